@@ -0,0 +1,25 @@
+//! Throughput of the sha256 backends, run with `cargo bench --features ring`
+use repro_env::hash::{Backend, Sha256};
+use std::hint::black_box;
+use std::time::Instant;
+
+const SIZE: usize = 256 * 1024 * 1024;
+const CHUNK: usize = 64 * 1024;
+
+fn main() {
+    let chunk = (0..CHUNK).map(|i| i as u8).collect::<Vec<_>>();
+    for backend in Backend::ALL {
+        let started = Instant::now();
+        let mut hasher = Sha256::with_backend(*backend);
+        for _ in 0..SIZE / CHUNK {
+            hasher.update(black_box(&chunk));
+        }
+        black_box(hasher.finalize());
+        let elapsed = started.elapsed();
+        let throughput = SIZE as f64 / elapsed.as_secs_f64() / 1024.0 / 1024.0;
+        println!(
+            "{backend}: {throughput:.0} MiB/s ({elapsed:.2?} for {} MiB)",
+            SIZE / 1024 / 1024
+        );
+    }
+}
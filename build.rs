@@ -0,0 +1,41 @@
+//! Embed how this binary was built, shown with `repro-env --print-build-info`
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+fn output(cmd: &str, args: &[&str]) -> Option<String> {
+    let out = Command::new(cmd).args(args).output().ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    let out = String::from_utf8(out.stdout).ok()?;
+    let out = out.trim();
+    (!out.is_empty()).then(|| out.to_string())
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=REPRO_ENV_GIT_COMMIT");
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    if let Some(version) = output(&rustc, &["--version"]) {
+        println!("cargo:rustc-env=REPRO_ENV_RUSTC_VERSION={version}");
+    }
+    if let Ok(target) = env::var("TARGET") {
+        println!("cargo:rustc-env=REPRO_ENV_TARGET={target}");
+    }
+
+    // source tarballs have no git repository, the commit can be passed in instead
+    let commit = match env::var("REPRO_ENV_GIT_COMMIT") {
+        Ok(commit) => Some(commit),
+        Err(_) if Path::new(".git").exists() => {
+            println!("cargo:rerun-if-changed=.git/HEAD");
+            println!("cargo:rerun-if-changed=.git/refs");
+            output("git", &["rev-parse", "HEAD"])
+        }
+        Err(_) => None,
+    };
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=REPRO_ENV_GIT_COMMIT={commit}");
+    }
+}
@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use repro_env::resolver::parsers;
+
+fuzz_target!(|data: &str| {
+    let _ = parsers::apk_simulate(data);
+    let (before, after) = data.split_once("\n\n").unwrap_or((data, ""));
+    let before = parsers::apk_info(before);
+    let after = parsers::apk_info(after);
+    for pkg in parsers::apk_info_diff(&before, &after) {
+        assert!(after.contains(&pkg) && !before.contains(&pkg));
+    }
+});
@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use repro_env::resolver::alpine::DatabaseCache;
+use std::sync::Arc;
+
+fuzz_target!(|data: &[u8]| {
+    let repo_url = Arc::new("https://dl-cdn.alpinelinux.org/alpine/v3.18/main".to_string());
+    let _ = DatabaseCache::default().read_apkindex_container(data, &repo_url);
+    let _ = DatabaseCache::default().read_apkindex_text(data, &repo_url);
+});
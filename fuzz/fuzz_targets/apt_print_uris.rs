@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use repro_env::resolver::parsers;
+
+fuzz_target!(|data: &str| {
+    if let Ok(uris) = parsers::apt_print_uris(data) {
+        for uri in uris {
+            assert!(!uri.url.is_empty());
+            let _ = uri.sha256();
+        }
+    }
+});
@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use repro_env::resolver::debian::PkgDatabase;
+use std::io::{BufRead, BufReader};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = PkgDatabase::default().import_lines_stream(BufReader::new(data).lines());
+});
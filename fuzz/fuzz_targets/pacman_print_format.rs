@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use repro_env::resolver::parsers;
+
+fuzz_target!(|data: &str| {
+    if let Ok(resolved) = parsers::pacman_print_format(data) {
+        for (repo, name, version) in resolved {
+            assert!(!repo.is_empty() && !name.is_empty() && !version.is_empty());
+        }
+    }
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use repro_env::pkgs;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = pkgs::debian::parse(data);
+});
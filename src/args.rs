@@ -1,16 +1,26 @@
+use crate::build;
+use crate::container;
 use crate::errors::*;
+use crate::fetch;
+use crate::init::Template;
+use crate::jobs::{Job, JobsFile};
+use crate::lock;
 use crate::lockfile::Lockfile;
-use crate::manifest::Manifest;
-use clap::{ArgAction, CommandFactory, Parser, Subcommand};
+use crate::manifest::{self, Manifest};
+use crate::paths;
+use crate::platform::Platform;
+use crate::rebuilderd;
+use clap::{ArgAction, CommandFactory, Parser, Subcommand, ValueEnum};
 use clap_complete::Shell;
+use serde::Serialize;
 use std::collections::HashSet;
 use std::env;
 use std::io;
-use std::path::Path;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Debug, Parser)]
-#[command(version)]
+#[command(version, arg_required_else_help = true)]
 pub struct Args {
     /// Increase logging output (can be used multiple times)
     #[arg(short, long, global = true, action(ArgAction::Count))]
@@ -18,16 +28,56 @@ pub struct Args {
     /// Change the current directory to this path before executing the subcommand
     #[arg(short = 'C', long)]
     pub context: Option<PathBuf>,
+    /// Write phase timings and podman invocation counts to this json file (before the subcommand)
+    #[arg(long, value_name = "PATH")]
+    pub profile: Option<PathBuf>,
+    /// Record every podman invocation (args, duration, exit code, truncated output) to this jsonl file
+    #[arg(long, global = true, value_name = "PATH")]
+    pub trace_runtime: Option<PathBuf>,
+    /// Seconds to wait for a connection to be established (default: 30)
+    #[arg(long, global = true, value_name = "SECONDS")]
+    pub connect_timeout: Option<u64>,
+    /// Seconds to wait for data on an established connection before aborting a download (default: 240)
+    #[arg(long, global = true, value_name = "SECONDS")]
+    pub read_timeout: Option<u64>,
+    /// Number of idle connections to keep open for each host (default: 32)
+    #[arg(long, global = true, value_name = "NUM")]
+    pub max_idle_connections: Option<usize>,
+    /// Print the version, target, compiler and git commit of this binary and exit
+    #[arg(long, exclusive = true)]
+    pub print_build_info: bool,
     #[command(subcommand)]
-    pub subcommand: SubCommand,
+    pub subcommand: Option<SubCommand>,
 }
 
 #[derive(Debug, Subcommand)]
 pub enum SubCommand {
-    Build(Build),
+    Init(Init),
+    Build(Box<Build>),
+    Prepare(Prepare),
     Update(Update),
     Fetch(Fetch),
+    Explain(Explain),
+    Audit(Audit),
+    Verify(Verify),
+    Sbom(Sbom),
+    #[command(subcommand)]
+    Attest(Attest),
+    #[command(subcommand)]
+    Lock(Lock),
+    Status(Status),
+    Exec(Exec),
+    Ps(Ps),
+    Gc(Gc),
+    #[command(subcommand)]
+    Cache(Cache),
+    Doctor(Doctor),
+    ListSystems(ListSystems),
+    Batch(Batch),
     Completions(Completions),
+    SelfUpdate(SelfUpdate),
+    #[command(hide = true)]
+    InspectPackage(InspectPackage),
 }
 
 /// Run a build in a reproducible environment
@@ -42,13 +92,124 @@ pub struct Build {
     /// Pass environment variables into the build container (FOO=bar or just FOO to lookup the value)
     #[arg(short, long)]
     pub env: Vec<String>,
-    /// The command to execute inside the build container
-    #[arg(required = true)]
+    /// Pass an environment variable from the host without exposing its value in process listings or logs
+    #[arg(long, value_name = "NAME")]
+    pub secret_env: Vec<String>,
+    /// Read environment variables for the build container from a file (KEY=value per line)
+    #[arg(long, value_name = "PATH")]
+    pub env_file: Vec<PathBuf>,
+    /// Copy the current directory into a temporary build context (honoring .reproenvignore or .dockerignore) instead of mounting it
+    #[arg(long)]
+    pub copy_context: bool,
+    /// Mount the current directory read-only at /build/src and a writable output directory at /build/out
+    #[arg(long)]
+    pub src_ro: bool,
+    /// Run the build with a read-only root filesystem, only the mounted directories and a tmpfs on /tmp are writable
+    #[arg(long)]
+    pub hardened: bool,
+    /// The output directory that is mounted to /build/out with --src-ro
+    #[arg(long, default_value = "out")]
+    pub out_dir: PathBuf,
+    /// Where the packages for /extra are staged (default: $REPRO_ENV_STAGING or the repro-env cache directory)
+    #[arg(long, value_name = "PATH")]
+    pub staging_dir: Option<PathBuf>,
+    /// Pass --allow-untrusted to apk, packages are still verified with the keys recorded in the lockfile (alpine only)
+    #[arg(long)]
+    pub allow_untrusted: bool,
+    /// Print the summary at the end as json
+    #[arg(long)]
+    pub json: bool,
+    /// Fail if repro-env.lock is out-of-sync with repro-env.toml
+    #[arg(long)]
+    pub frozen: bool,
+    /// Only use repro-env.lock from the current directory instead of searching parent directories
+    #[arg(long)]
+    pub no_search_parents: bool,
+    /// Use repro-env.<VARIANT>.lock of a [matrix] variant
+    #[arg(long, conflicts_with = "file")]
+    pub variant: Option<String>,
+    /// Pass an additional argument to `podman run` for the build container (--flag or --flag=value)
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true)]
+    pub podman_arg: Vec<String>,
+    /// Run the build container for this platform (os/arch[/variant]), e.g. linux/arm64 with qemu-user-static
+    #[arg(long, value_name = "PLATFORM")]
+    pub platform: Option<Platform>,
+    /// Ask the registry if a newer version of the base image has been published since the lockfile was resolved
+    #[arg(long)]
+    pub check_image: bool,
+    /// Don't access the network for the image and dependencies, everything needs to be fetched beforehand
+    #[arg(long, conflicts_with_all = ["check_image", "platform"])]
+    pub offline: bool,
+    /// Print what would be done without creating a container (text or json)
+    #[arg(long, value_name = "FORMAT", num_args = 0..=1, default_missing_value = "text")]
+    pub plan: Option<PlanFormat>,
+    /// Print the packages that are going to be installed as a table before starting the build
+    #[arg(long)]
+    pub list: bool,
+    /// Only install these packages of the lockfile (comma separated), e.g. to find a package that breaks the build
+    #[arg(long, value_name = "PKG", value_delimiter = ',')]
+    pub only: Vec<String>,
+    /// Don't install these packages of the lockfile (comma separated)
+    #[arg(long, value_name = "PKG", value_delimiter = ',')]
+    pub skip: Vec<String>,
+    /// Check the downloaded packages for files that are installed by more than one package, before creating the container
+    #[arg(long)]
+    pub check_conflicts: bool,
+    /// Run the jobs from this toml file concurrently in the build container instead of a single command
+    #[arg(long, value_name = "PATH", conflicts_with = "cmd")]
+    pub jobs_file: Option<PathBuf>,
+    /// How many jobs of --jobs-file (default: all) or independent --steps (default: 1) run at the same time
+    #[arg(short, long, value_name = "N")]
+    pub jobs: Option<usize>,
+    /// Also write stdout of the build command to this file, and stderr to PATH.stderr
+    #[arg(long, value_name = "PATH", conflicts_with = "jobs_file")]
+    pub capture: Option<PathBuf>,
+    /// Execute this shell script with `sh` instead of a command from the arguments
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["cmd", "jobs_file"])]
+    pub cmd_file: Option<PathBuf>,
+    /// Run the [[build.steps]] of the manifest instead of a command
+    #[arg(long, conflicts_with_all = ["cmd", "cmd_file", "jobs_file", "capture", "src_ro"])]
+    pub steps: bool,
+    /// Run all steps, even if their inputs didn't change since they were cached
+    #[arg(long, requires = "steps")]
+    pub no_step_cache: bool,
+    /// Also install the packages of this profile of the lockfile, see [profile.<NAME>] in repro-env.toml
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+    /// Do not sign the artifacts declared in [build.sign] after the build
+    #[arg(long)]
+    pub no_sign: bool,
+    /// Change the owner of the files in the writable build directory after the build, to UID:GID inside of the container or the invoking user by default
+    #[arg(
+        long,
+        value_name = "UID:GID",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "auto"
+    )]
+    pub chown: Option<build::Chown>,
+    /// Preload libfaketime with the clock set to SOURCE_DATE_EPOCH, also enabled by `faketime = true` in [packages]
+    #[arg(long)]
+    pub faketime: bool,
+    #[command(flatten)]
+    pub download: DownloadArgs,
+    /// The command to execute inside the build container, everything after the first argument is passed on verbatim
+    #[arg(
+        required_unless_present_any = ["jobs_file", "cmd_file", "steps"],
+        trailing_var_arg = true
+    )]
     pub cmd: Vec<String>,
 }
 
 impl Build {
     pub fn validate(&self) -> Result<()> {
+        if self.jobs.is_some() && self.jobs_file.is_none() && !self.steps {
+            bail!("--jobs can only be used with --jobs-file or --steps");
+        }
+        self.load_jobs()?;
+        self.command()?;
+        container::validate_podman_args(&self.podman_arg)?;
+        let env_file_vars = self.env_file_vars()?;
         let mut env_keys = HashSet::new();
         for env in &self.env {
             let key = if let Some((key, _value)) = env.split_once('=') {
@@ -63,21 +224,202 @@ impl Build {
                 bail!("Can not set environment multiple times: {key:?}");
             }
         }
+        for name in &self.secret_env {
+            if let Some((key, _value)) = name.split_once('=') {
+                bail!("Secret environment variables are passed by name only, found a value for {key:?}");
+            }
+            if env::var_os(name).is_none() {
+                bail!("Referenced secret environment variable does not exist: {name:?}");
+            }
+            if !env_keys.insert(name) {
+                bail!("Can not set environment multiple times: {name:?}");
+            }
+        }
+        for (key, _value) in &env_file_vars {
+            if !env_keys.insert(key) {
+                bail!("Can not set environment multiple times: {key:?}");
+            }
+        }
         Ok(())
     }
 
-    pub async fn load_files(&self) -> Result<(Option<Manifest>, Lockfile)> {
-        let path = self.file.as_deref().unwrap_or(Path::new("repro-env.lock"));
-        let lockfile = Lockfile::read_from_file(path).await?;
+    /// Read the jobs from --jobs-file, if configured
+    pub fn load_jobs(&self) -> Result<Option<Vec<Job>>> {
+        let Some(path) = &self.jobs_file else {
+            return Ok(None);
+        };
+        let file = JobsFile::read_from_file(path)?;
+        Ok(Some(file.jobs))
+    }
 
-        let manifest = if self.file.is_none() {
-            Some(Manifest::read_from_file("repro-env.toml").await?)
-        } else {
-            None
+    /// The argv of the build command, either as given or the --cmd-file that's mounted into the container
+    pub fn command(&self) -> Result<Vec<String>> {
+        let Some(path) = &self.cmd_file else {
+            return Ok(self.cmd.clone());
         };
+        let script = std::fs::read_to_string(path)
+            .with_context(|| anyhow!("Failed to read command file: {path:?}"))?;
+        if script.trim().is_empty() {
+            bail!("Command file is empty: {path:?}");
+        }
+        Ok(vec!["sh".to_string(), build::CMD_FILE.to_string()])
+    }
+
+    /// The files that stdout and stderr of the build command are copied to with --capture
+    pub fn capture_paths(&self) -> Option<(PathBuf, PathBuf)> {
+        let stdout = self.capture.clone()?;
+        let mut stderr = stdout.clone().into_os_string();
+        stderr.push(".stderr");
+        Some((stdout, stderr.into()))
+    }
+
+    /// Read all variables from the --env-file arguments
+    pub fn env_file_vars(&self) -> Result<Vec<(String, String)>> {
+        let mut vars = Vec::new();
+        for path in &self.env_file {
+            let buf = std::fs::read_to_string(path)
+                .with_context(|| anyhow!("Failed to read env file: {path:?}"))?;
+            let parsed = parse_env_file(&buf)
+                .with_context(|| anyhow!("Failed to parse env file: {path:?}"))?;
+            vars.extend(parsed);
+        }
+        Ok(vars)
+    }
 
-        Ok((manifest, lockfile))
+    /// Read the values of all --secret-env variables from the host
+    pub fn secrets(&self) -> Result<Vec<(String, String)>> {
+        self.secret_env
+            .iter()
+            .map(|name| {
+                let value = env::var(name).with_context(|| {
+                    anyhow!("Failed to read secret environment variable: {name:?}")
+                })?;
+                Ok((name.clone(), value))
+            })
+            .collect()
+    }
+
+    /// The directory inside of the container that the build writes its results to
+    pub fn output_dir(&self) -> &'static str {
+        if self.src_ro {
+            "/build/out"
+        } else {
+            "/build"
+        }
+    }
+
+    /// The directory the build command is executed in, `subdir` is the
+    /// current directory relative to the one that's mounted to /build
+    pub fn workdir(&self, subdir: &Path) -> String {
+        let root = if self.src_ro { "/build/src" } else { "/build" };
+        if subdir.as_os_str().is_empty() {
+            return root.to_string();
+        }
+        Path::new(root).join(subdir).to_string_lossy().into_owned()
     }
+
+    /// Load the lockfile and manifest of this build, also returns the path of the lockfile
+    pub async fn load_files(&self) -> Result<(Option<Manifest>, PathBuf, Lockfile)> {
+        load_files(
+            self.file.as_deref(),
+            self.variant.as_deref(),
+            !self.no_search_parents,
+        )
+        .await
+    }
+}
+
+/// Load the lockfile (and the manifest next to it, unless an explicit lockfile is used)
+pub async fn load_files(
+    file: Option<&Path>,
+    variant: Option<&str>,
+    search_parents: bool,
+) -> Result<(Option<Manifest>, PathBuf, Lockfile)> {
+    let path = match file {
+        Some(path) => path.to_path_buf(),
+        None => paths::find_project_file(&manifest::lockfile_name(variant), search_parents)?,
+    };
+    let lockfile = Lockfile::read_from_file(&path).await?;
+
+    let manifest = if file.is_none() {
+        let path = manifest::locate(&path.with_file_name(manifest::FILENAME));
+        let manifest = Manifest::read_from_file(path).await?;
+        match variant {
+            Some(variant) => Some(manifest.variant(variant)?),
+            None => Some(manifest),
+        }
+    } else {
+        None
+    };
+
+    Ok((manifest, path, lockfile))
+}
+
+/// Parse an env file, one KEY=value per line, empty lines and lines starting with # are ignored
+pub fn parse_env_file(buf: &str) -> Result<Vec<(String, String)>> {
+    let mut vars = Vec::new();
+    for (num, line) in buf.lines().enumerate() {
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            bail!("Line {} is missing a `=`", num + 1);
+        };
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            bail!("Invalid variable name in line {}: {key:?}", num + 1);
+        }
+        vars.push((key.to_string(), value.to_string()));
+    }
+    Ok(vars)
+}
+
+/// Set up the environment of the lockfile in a container and keep it running for later builds
+#[derive(Debug, Parser)]
+pub struct Prepare {
+    /// The dependency lockfile to use
+    #[arg(short, long)]
+    pub file: Option<PathBuf>,
+    /// Fail if repro-env.lock is out-of-sync with repro-env.toml
+    #[arg(long)]
+    pub frozen: bool,
+    /// Only use repro-env.lock from the current directory instead of searching parent directories
+    #[arg(long)]
+    pub no_search_parents: bool,
+    /// Use repro-env.<VARIANT>.lock of a [matrix] variant
+    #[arg(long, conflicts_with = "file")]
+    pub variant: Option<String>,
+    /// Pass --allow-untrusted to apk, packages are still verified with the keys recorded in the lockfile (alpine only)
+    #[arg(long)]
+    pub allow_untrusted: bool,
+    /// Where the packages for /extra are staged (default: $REPRO_ENV_STAGING or the repro-env cache directory)
+    #[arg(long, value_name = "PATH")]
+    pub staging_dir: Option<PathBuf>,
+    /// Also install the packages of this profile of the lockfile, see [profile.<NAME>] in repro-env.toml
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+    /// Check the downloaded packages for files that are installed by more than one package, before creating the container
+    #[arg(long)]
+    pub check_conflicts: bool,
+    #[command(flatten)]
+    pub download: DownloadArgs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum PlanFormat {
+    Text,
+    Json,
+}
+
+/// Write a repro-env.toml for a new project from a template
+#[derive(Debug, Parser)]
+pub struct Init {
+    /// The template for the manifest, it also determines the suggested build command
+    #[arg(short, long, value_enum, default_value = "minimal")]
+    pub template: Template,
+    /// Overwrite an existing repro-env.toml
+    #[arg(long)]
+    pub force: bool,
 }
 
 /// Update all dependencies of the reproducible environment
@@ -89,6 +431,55 @@ pub struct Update {
     /// Do not delete the build container, wait for ctrl-c
     #[arg(short, long)]
     pub keep: bool,
+    /// Do not show progress while resolving packages
+    #[arg(short, long)]
+    pub quiet: bool,
+    /// Print the summary at the end as json
+    #[arg(long)]
+    pub json: bool,
+    /// The dependency manifest to resolve
+    #[arg(long, default_value = "repro-env.toml")]
+    pub manifest: PathBuf,
+    /// The dependency lockfile to write
+    #[arg(long, default_value = "repro-env.lock")]
+    pub lockfile: PathBuf,
+    /// Print the resolved lockfile to stdout instead of writing it to a file
+    #[arg(long, conflicts_with_all = ["lockfile", "json"])]
+    pub write_stdout: bool,
+    /// Only resolve this [matrix] variant instead of the default environment and all variants
+    #[arg(long)]
+    pub variant: Option<String>,
+    /// Fail if a package would get an older version than in the previous lockfile
+    #[arg(long)]
+    pub deny_downgrades: bool,
+    /// Allow this package to be downgraded with --deny-downgrades (can be used multiple times)
+    #[arg(long, value_name = "PKG", requires = "deny_downgrades")]
+    pub allow_downgrade: Vec<String>,
+    /// Print a report of the changes to stdout, e.g. as body of a pull request
+    #[arg(long, value_name = "FORMAT", conflicts_with_all = ["json", "write_stdout"])]
+    pub report: Option<ReportFormat>,
+    /// Only resolve the container image and print the pinned reference, packages are skipped
+    #[arg(long, conflicts_with_all = ["lockfile", "json", "write_stdout", "report", "deny_downgrades"])]
+    pub print: bool,
+    /// Ask for every upgraded or downgraded package whether to accept it, rejected ones keep their previous version
+    #[arg(short, long, conflicts_with_all = ["write_stdout", "print"])]
+    pub interactive: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ReportFormat {
+    Markdown,
+}
+
+/// Ask before large downloads, for metered connections
+#[derive(Debug, Default, Clone, clap::Args)]
+pub struct DownloadArgs {
+    /// Ask before downloading more than this (e.g. 500MiB), fails if there's no terminal to ask on
+    #[arg(long, value_name = "SIZE", value_parser = fetch::parse_size)]
+    pub download_limit: Option<u64>,
+    /// Download without asking, even if --download-limit is exceeded
+    #[arg(short = 'y', long)]
+    pub assume_yes: bool,
 }
 
 /// Fetch dependencies into the local cache
@@ -100,6 +491,377 @@ pub struct Fetch {
     /// Do not attempt to pull the container tag from registry
     #[arg(long)]
     pub no_pull: bool,
+    /// Also export the pinned image into the cache, so `build --offline` doesn't need the registry
+    #[arg(long)]
+    pub export_image: bool,
+    /// Print the summary at the end as json
+    #[arg(long)]
+    pub json: bool,
+    /// Only use repro-env.lock from the current directory instead of searching parent directories
+    #[arg(long, alias = "frozen")]
+    pub no_search_parents: bool,
+    /// Use repro-env.<VARIANT>.lock of a [matrix] variant
+    #[arg(long, conflicts_with = "file")]
+    pub variant: Option<String>,
+    /// Print the packages that are going to be downloaded as a table before starting
+    #[arg(long)]
+    pub list: bool,
+    /// Also install the packages of this profile of the lockfile, see [profile.<NAME>] in repro-env.toml
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+    #[command(flatten)]
+    pub download: DownloadArgs,
+}
+
+/// Show everything known about a package in the dependency lockfile
+#[derive(Debug, Parser)]
+pub struct Explain {
+    /// The dependency lockfile to use
+    #[arg(short, long)]
+    pub file: Option<PathBuf>,
+    /// The name of the package (or a name it provides)
+    pub name: String,
+}
+
+/// Look up known vulnerabilities of the packages in the dependency lockfile on osv.dev
+#[derive(Debug, Parser)]
+pub struct Audit {
+    /// The dependency lockfile to use
+    #[arg(short, long)]
+    pub file: Option<PathBuf>,
+    /// Only use the osv.dev responses from the cache of a previous audit
+    #[arg(long)]
+    pub offline: bool,
+    /// Print the vulnerabilities as json
+    #[arg(long)]
+    pub json: bool,
+    /// Attach a VEX document to the project, its not_affected statements suppress findings of future audits
+    #[arg(long, value_name = "PATH")]
+    pub attach_vex: Vec<PathBuf>,
+    /// Stop using a VEX document that was attached before
+    #[arg(long, value_name = "PATH")]
+    pub detach_vex: Vec<PathBuf>,
+}
+
+/// Verify the cached packages and files of the dependency lockfile, optionally check with public rebuilders if they are reproducible
+#[derive(Debug, Parser)]
+pub struct Verify {
+    /// The dependency lockfile to use
+    #[arg(short, long)]
+    pub file: Option<PathBuf>,
+    /// Ask rebuilderd instances which of the pinned packages have been independently reproduced
+    #[arg(long)]
+    pub cross_check: bool,
+    /// Use this rebuilderd instance for a package system instead of the default
+    #[arg(long, value_name = "SYSTEM=URL", value_parser = rebuilderd::parse_rebuilder, requires = "cross_check")]
+    pub rebuilder: Vec<(String, String)>,
+    /// Print the results of the cross-check as json
+    #[arg(long, requires = "cross_check")]
+    pub json: bool,
+}
+
+/// Print a CycloneDX SBOM of the environment, merged with the SBOM of the base image if it has one
+#[derive(Debug, Parser)]
+pub struct Sbom {
+    /// The dependency lockfile to use
+    #[arg(short, long)]
+    pub file: Option<PathBuf>,
+    /// Do not look up the SBOM of the base image in its registry
+    #[arg(long)]
+    pub no_image_sbom: bool,
+}
+
+/// Bundle the lockfile, SBOM and artifact hashes of a build for auditors, and check such a bundle
+#[derive(Debug, Subcommand)]
+pub enum Attest {
+    Bundle(AttestBundle),
+    Verify(AttestVerify),
+}
+
+/// Write a single archive with the lockfile, the SBOM, the hashes of the build log and artifacts, and their signatures
+#[derive(Debug, Parser)]
+pub struct AttestBundle {
+    /// The file to write the bundle to (tar.gz)
+    #[arg(short, long)]
+    pub output: PathBuf,
+    /// The dependency lockfile to use
+    #[arg(short, long, default_value = "repro-env.lock")]
+    pub file: PathBuf,
+    /// The dependency manifest, the artifacts of [build.sign] are bundled unless --artifact is used
+    #[arg(long, default_value = "repro-env.toml")]
+    pub manifest: PathBuf,
+    /// An artifact of the build to bundle, signatures next to it are included (can be used multiple times)
+    #[arg(long, value_name = "PATH")]
+    pub artifact: Vec<PathBuf>,
+    /// Record the sha256 of the build log, e.g. the output captured with `build --capture`
+    #[arg(long, value_name = "PATH")]
+    pub build_log: Option<PathBuf>,
+    /// Do not look up the SBOM of the base image in its registry
+    #[arg(long)]
+    pub no_image_sbom: bool,
+}
+
+/// Check that a bundle is complete and consistent, without network access
+#[derive(Debug, Parser)]
+pub struct AttestVerify {
+    /// The bundle to check
+    pub bundle: PathBuf,
+    /// Compare the artifacts in this directory with the hashes of the bundle
+    #[arg(long, value_name = "PATH")]
+    pub artifacts_dir: Option<PathBuf>,
+    /// Verify the OpenPGP signatures of the artifacts with this public key (can be used multiple times)
+    #[arg(long, value_name = "PATH", requires = "artifacts_dir")]
+    pub key: Vec<PathBuf>,
+}
+
+/// Inspect the dependency lockfile
+#[derive(Debug, Subcommand)]
+pub enum Lock {
+    Digest(LockDigest),
+    Print(LockPrint),
+    Prune(LockPrune),
+    Check(LockCheck),
+    Purls(LockPurls),
+    DescribeImage(LockDescribeImage),
+    Set(LockSet),
+    Remove(LockRemove),
+}
+
+/// Print a single hash identifying the whole environment
+#[derive(Debug, Parser)]
+pub struct LockDigest {
+    /// The dependency lockfile to use
+    #[arg(short, long)]
+    pub file: Option<PathBuf>,
+}
+
+/// Print the dependency lockfile, or the values scripts usually need from it
+#[derive(Debug, Parser)]
+pub struct LockPrint {
+    /// The dependency lockfile to use
+    #[arg(short, long)]
+    pub file: Option<PathBuf>,
+    /// The output format
+    #[arg(long, value_enum, default_value_t = LockPrintFormat::Toml)]
+    pub format: LockPrintFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum LockPrintFormat {
+    /// The lockfile as toml, normalized
+    Toml,
+    /// The lockfile as json
+    Json,
+    /// Shell variables with the image digest, the lockfile hash and the number of packages
+    Env,
+}
+
+/// Remove packages that are no longer needed by any dependency in the manifest, without resolving again
+#[derive(Debug, Parser)]
+pub struct LockPrune {
+    /// The dependency manifest with the remaining dependencies
+    #[arg(long, default_value = "repro-env.toml")]
+    pub manifest: PathBuf,
+    /// The dependency lockfile to prune
+    #[arg(short, long, default_value = "repro-env.lock")]
+    pub file: PathBuf,
+    /// Only print the orphaned packages, don't modify the lockfile
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Print the package url (purl) of every package in the dependency lockfile
+#[derive(Debug, Parser)]
+pub struct LockPurls {
+    /// The dependency lockfile to use
+    #[arg(short, long)]
+    pub file: Option<PathBuf>,
+    /// Print a json object with the name, version and purl of each package
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Pin a package to a different file by hand, it's downloaded and verified before the lockfile is changed
+#[derive(Debug, Parser)]
+pub struct LockSet {
+    /// The name of the package
+    pub name: String,
+    /// The version of the package, it needs to match the metadata embedded in the package
+    #[arg(long)]
+    pub version: String,
+    /// The url to download the package from
+    #[arg(long)]
+    pub url: String,
+    /// The sha256 of the package file
+    #[arg(long)]
+    pub sha256: String,
+    /// The package system, only needed if the lockfile has no packages yet
+    #[arg(long)]
+    pub system: Option<String>,
+    /// The base64 encoded OpenPGP signature of the package file
+    #[arg(long)]
+    pub signature: Option<String>,
+    /// The dependency lockfile to change
+    #[arg(short, long, default_value = "repro-env.lock")]
+    pub file: PathBuf,
+}
+
+/// Remove a package from the dependency lockfile by hand
+#[derive(Debug, Parser)]
+pub struct LockRemove {
+    /// The name of the package
+    pub name: String,
+    /// The dependency lockfile to change
+    #[arg(short, long, default_value = "repro-env.lock")]
+    pub file: PathBuf,
+}
+
+/// Look up which tags of the registry point to the pinned container image
+#[derive(Debug, Parser)]
+pub struct LockDescribeImage {
+    /// The dependency lockfile to use
+    #[arg(short, long)]
+    pub file: Option<PathBuf>,
+    /// Only compare tags that start with this prefix (default: the tag the lockfile was resolved from)
+    #[arg(long, conflicts_with = "all_tags")]
+    pub prefix: Option<String>,
+    /// Compare all tags of the repository, not only the ones that start with the prefix
+    #[arg(long)]
+    pub all_tags: bool,
+    /// The maximum number of tags to compare with the pinned digest
+    #[arg(long, default_value_t = 200)]
+    pub limit: usize,
+    /// Print the image, its creation date and its tags as json
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Fail if the packages of the dependency lockfile are inconsistent, or if it's older than a given age
+#[derive(Debug, Parser)]
+pub struct LockCheck {
+    /// The dependency lockfile to check
+    #[arg(short, long, default_value = "repro-env.lock")]
+    pub file: PathBuf,
+    /// The maximum age of the lockfile, e.g. 90d, 12w or 48h
+    #[arg(long, value_name = "AGE", value_parser = lock::parse_age)]
+    pub max_age: Option<Duration>,
+}
+
+/// Show the last builds and the containers of the current project
+#[derive(Debug, Parser)]
+pub struct Status {
+    /// The dependency lockfile to use
+    #[arg(short, long)]
+    pub file: Option<PathBuf>,
+    /// Print the status as json
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Run a command in a container of the current project that is kept running (`prepare` or `build --keep`)
+#[derive(Debug, Parser)]
+pub struct Exec {
+    /// The dependency lockfile to use
+    #[arg(short, long)]
+    pub file: Option<PathBuf>,
+    /// The command to execute, defaults to an interactive shell
+    pub cmd: Vec<String>,
+}
+
+/// List containers and staging directories created by repro-env
+#[derive(Debug, Parser)]
+pub struct Ps {}
+
+/// Check if this machine is able to run builds (podman, user namespaces, catatonit, disk space, network)
+#[derive(Debug, Parser)]
+pub struct Doctor {
+    /// Print the report as json
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Replace this binary with the latest release, after verifying its signature
+#[derive(Debug, Parser)]
+pub struct SelfUpdate {
+    /// Only print if an update is available, don't download it
+    #[arg(long)]
+    pub check: bool,
+    /// Install this release instead of the latest one, this can also be used to downgrade
+    #[arg(long)]
+    pub version: Option<String>,
+    /// Reinstall even if this version is already installed
+    #[arg(long)]
+    pub force: bool,
+}
+
+/// Remove leftovers of interrupted builds
+#[derive(Debug, Parser)]
+pub struct Gc {
+    /// Also remove containers created by repro-env that are stopped or belong to a deleted project
+    #[arg(long)]
+    pub containers: bool,
+    /// Remove running containers too, requires --containers
+    #[arg(long, requires = "containers")]
+    pub force: bool,
+    /// Also remove packages from the cache that aren't referenced by any known lockfile
+    #[arg(long)]
+    pub packages: bool,
+    /// Also remove the containers recorded for the current project, including running ones
+    #[arg(long)]
+    pub project: bool,
+}
+
+/// Inspect the package cache
+#[derive(Debug, Subcommand)]
+pub enum Cache {
+    Stats(CacheStats),
+}
+
+/// Show how much of the package cache is referenced by lockfiles on this machine
+#[derive(Debug, Parser)]
+pub struct CacheStats {
+    /// Print the statistics as json
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Print the supported package systems, container runtimes and architectures as json
+#[derive(Debug, Parser)]
+pub struct ListSystems {}
+
+/// Run update, fetch and build across many project directories
+#[derive(Debug, Parser)]
+pub struct Batch {
+    /// The toml file with a [[project]] entry for each project directory
+    #[arg(long, value_name = "PATH", default_value = "projects.toml")]
+    pub projects: PathBuf,
+    /// How many projects are processed at the same time
+    #[arg(short, long, value_name = "N", default_value_t = 4)]
+    pub jobs: usize,
+    /// Print the report at the end as json
+    #[arg(long)]
+    pub json: bool,
+    /// The actions to run in each project, they always run in the order update, fetch, build
+    #[arg(required = true)]
+    pub actions: Vec<BatchAction>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchAction {
+    Update,
+    Fetch,
+    Build,
+}
+
+impl BatchAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BatchAction::Update => "update",
+            BatchAction::Fetch => "fetch",
+            BatchAction::Build => "build",
+        }
+    }
 }
 
 /// Generate shell completions
@@ -108,6 +870,15 @@ pub struct Completions {
     pub shell: Shell,
 }
 
+/// Parse a package from stdin, used to run the parser in a sandboxed child process
+#[derive(Debug, Parser)]
+pub struct InspectPackage {
+    pub system: String,
+    /// List the files of the package instead of its metadata
+    #[arg(long)]
+    pub list_files: bool,
+}
+
 impl Completions {
     pub fn generate<W: io::Write>(&self, mut w: W) -> Result<()> {
         clap_complete::generate(self.shell, &mut Args::command(), "repro-env", &mut w);
@@ -125,4 +896,138 @@ mod tests {
             .generate(io::sink())
             .unwrap();
     }
+
+    fn parse_build(args: &[&str]) -> Build {
+        let args = Args::try_parse_from(["repro-env", "build"].iter().chain(args)).unwrap();
+        match args.subcommand {
+            Some(SubCommand::Build(build)) => *build,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_print_build_info() {
+        let args = Args::try_parse_from(["repro-env", "--print-build-info"]).unwrap();
+        assert!(args.print_build_info);
+        assert!(args.subcommand.is_none());
+        assert!(Args::try_parse_from(["repro-env"]).is_err());
+    }
+
+    #[test]
+    fn test_update_print() {
+        let args = Args::try_parse_from(["repro-env", "update", "--print"]).unwrap();
+        assert!(matches!(
+            args.subcommand,
+            Some(SubCommand::Update(Update { print: true, .. }))
+        ));
+        assert!(
+            Args::try_parse_from(["repro-env", "update", "--print", "--write-stdout"]).is_err()
+        );
+        assert!(
+            Args::try_parse_from(["repro-env", "update", "--print", "--lockfile", "x"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_profile() {
+        // the timings are written to the path given before the subcommand,
+        // the profile of the lockfile is selected after it
+        let args = Args::try_parse_from([
+            "repro-env",
+            "--profile",
+            "timings.json",
+            "build",
+            "--profile",
+            "debug",
+            "make",
+        ])
+        .unwrap();
+        assert_eq!(args.profile, Some(PathBuf::from("timings.json")));
+        assert!(matches!(
+            args.subcommand,
+            Some(SubCommand::Build(build)) if build.profile.as_deref() == Some("debug")
+        ));
+    }
+
+    #[test]
+    fn test_build_cmd_verbatim() -> Result<()> {
+        let build = parse_build(&["--", "sh", "-c", "echo \"a  b\" 'c'", "--keep"]);
+        assert!(!build.keep);
+        assert_eq!(
+            build.command()?,
+            ["sh", "-c", "echo \"a  b\" 'c'", "--keep"]
+        );
+
+        let build = parse_build(&["--env", "FOO=1", "cargo", "build", "--release", "-v"]);
+        assert_eq!(build.env, ["FOO=1"]);
+        assert_eq!(build.command()?, ["cargo", "build", "--release", "-v"]);
+
+        let build = parse_build(&["printf", "%s\\n", "grüße 🦀", "", "$HOME"]);
+        assert_eq!(
+            build.command()?,
+            ["printf", "%s\\n", "grüße 🦀", "", "$HOME"]
+        );
+
+        assert!(Args::try_parse_from(["repro-env", "build"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_capture_paths() {
+        let build = parse_build(&["--capture", "out/sbom.json", "make", "sbom"]);
+        assert_eq!(
+            build.capture_paths(),
+            Some((
+                PathBuf::from("out/sbom.json"),
+                PathBuf::from("out/sbom.json.stderr")
+            ))
+        );
+        assert_eq!(parse_build(&["make"]).capture_paths(), None);
+    }
+
+    #[test]
+    fn test_build_cmd_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("build.sh");
+        std::fs::write(&path, "set -e\ncargo build --release\n")?;
+        let build = parse_build(&["--cmd-file", path.to_str().unwrap()]);
+        assert_eq!(build.command()?, ["sh", "/extra/cmd.sh"]);
+
+        std::fs::write(&path, "\n")?;
+        assert!(build.command().is_err());
+        assert!(Args::try_parse_from([
+            "repro-env",
+            "build",
+            "--cmd-file",
+            path.to_str().unwrap(),
+            "make"
+        ])
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_env_file() -> Result<()> {
+        let vars = parse_env_file(
+            "# comment
+FOO=bar
+
+  BAR=a=b # not a comment
+EMPTY=
+",
+        )?;
+        assert_eq!(
+            vars,
+            vec![
+                ("FOO".to_string(), "bar".to_string()),
+                ("BAR".to_string(), "a=b # not a comment".to_string()),
+                ("EMPTY".to_string(), String::new()),
+            ]
+        );
+
+        assert!(parse_env_file("FOO\n").is_err());
+        assert!(parse_env_file("=bar\n").is_err());
+        assert!(parse_env_file("FOO BAR=1\n").is_err());
+        Ok(())
+    }
 }
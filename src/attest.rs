@@ -0,0 +1,577 @@
+//! Bundle everything an auditor needs to review a build into a single archive
+//! (lockfile, SBOM, artifact hashes and signatures), and check such a bundle
+//! again without network access
+use crate::args;
+use crate::errors::*;
+use crate::hash;
+use crate::lockfile::Lockfile;
+use crate::manifest::{self, Manifest, SignMethod};
+use crate::pgp;
+use crate::sbom;
+use crate::sign;
+use crate::state;
+use crate::summary::{Origin, PackageSource};
+use crate::utils;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use sequoia_openpgp::parse::Parse;
+use sequoia_openpgp::Cert;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+const MANIFEST_PATH: &str = "manifest.json";
+const LOCKFILE_PATH: &str = "repro-env.lock";
+const SBOM_PATH: &str = "sbom.cdx.json";
+const SIGNATURES_DIR: &str = "signatures";
+const SIGN_METHODS: &[SignMethod] = &[
+    SignMethod::Openpgp,
+    SignMethod::Minisign,
+    SignMethod::Cosign,
+];
+
+/// The `manifest.json` of a bundle
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BundleManifest {
+    pub repro_env_version: String,
+    pub lockfile_digest: String,
+    pub image: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build_log: Option<FileHash>,
+    #[serde(default)]
+    pub artifacts: Vec<Artifact>,
+    /// Whether the packages of the last build were taken from the cache or downloaded
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub packages: Vec<PackageSource>,
+    /// The sha256 of every other file in the bundle
+    pub files: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileHash {
+    pub path: String,
+    pub sha256: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Artifact {
+    pub path: String,
+    pub sha256: String,
+    pub size: u64,
+    /// The signatures that were found next to the artifact, by their path in the bundle
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub signatures: Vec<BundledSignature>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BundledSignature {
+    pub method: SignMethod,
+    pub path: String,
+}
+
+/// Artifacts are recorded with the path they were given with, it needs to
+/// stay inside of the build directory so it can be checked again
+fn artifact_path(path: &Path) -> Result<String> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(
+                part.to_str()
+                    .with_context(|| anyhow!("Artifact path is not valid utf8: {path:?}"))?,
+            ),
+            Component::CurDir => (),
+            _ => bail!("Artifact path needs to be relative, without `..`: {path:?}"),
+        }
+    }
+    if parts.is_empty() {
+        bail!("Artifact path is empty: {path:?}");
+    }
+    Ok(parts.join("/"))
+}
+
+fn hash_file(path: &Path) -> Result<(String, u64)> {
+    let sha256 = utils::sha256_file(path)?;
+    let size = std::fs::metadata(path)
+        .with_context(|| anyhow!("Failed to read metadata of file: {path:?}"))?
+        .len();
+    Ok((sha256, size))
+}
+
+/// A gzip compressed tar of the files, sorted and without timestamps or
+/// owners, so the same inputs always produce the same archive
+pub fn write_archive(files: &BTreeMap<String, Vec<u8>>) -> Result<Vec<u8>> {
+    let mut tar = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for (path, data) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(0);
+        header.set_uid(0);
+        header.set_gid(0);
+        tar.append_data(&mut header, path, &data[..])
+            .with_context(|| anyhow!("Failed to add {path:?} to bundle"))?;
+    }
+    let gz = tar.into_inner().context("Failed to finish tar archive")?;
+    gz.finish().context("Failed to finish gzip stream")
+}
+
+/// Read the regular files of a bundle, anything else is rejected
+pub fn read_archive<R: Read>(reader: R) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut tar = tar::Archive::new(GzDecoder::new(reader));
+    let mut files = BTreeMap::new();
+    for entry in tar.entries().context("Failed to read bundle")? {
+        let mut entry = entry.context("Failed to read entry of bundle")?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            bail!("Bundle contains an entry that is not a regular file: {path:?}");
+        }
+        let mut data = Vec::new();
+        entry
+            .read_to_end(&mut data)
+            .with_context(|| anyhow!("Failed to read {path:?} from bundle"))?;
+        if files.insert(path.clone(), data).is_some() {
+            bail!("Bundle contains {path:?} multiple times");
+        }
+    }
+    Ok(files)
+}
+
+/// Collect the files of a bundle, artifact paths are relative to `dir` and
+/// their signatures are taken from next to them
+pub fn collect(
+    dir: &Path,
+    lockfile: &[u8],
+    sbom: &[u8],
+    artifacts: &[PathBuf],
+    build_log: Option<&Path>,
+    packages: Vec<PackageSource>,
+) -> Result<(BundleManifest, BTreeMap<String, Vec<u8>>)> {
+    let parsed = Lockfile::deserialize(std::str::from_utf8(lockfile)?)?;
+    let mut files = BTreeMap::new();
+    files.insert(LOCKFILE_PATH.to_string(), lockfile.to_vec());
+    files.insert(SBOM_PATH.to_string(), sbom.to_vec());
+
+    let mut recorded = Vec::new();
+    for path in artifacts {
+        let name = artifact_path(path)?;
+        let path = dir.join(&name);
+        if !path.is_file() {
+            bail!("Artifact doesn't exist or is not a file: {path:?}");
+        }
+        let (sha256, size) = hash_file(&path)?;
+        let mut signatures = Vec::new();
+        for method in SIGN_METHODS {
+            let sig = sign::signature_path(&path, *method);
+            if !sig.is_file() {
+                continue;
+            }
+            let bundled = format!("{SIGNATURES_DIR}/{name}.{}", method.extension());
+            let data = std::fs::read(&sig)
+                .with_context(|| anyhow!("Failed to read signature: {sig:?}"))?;
+            files.insert(bundled.clone(), data);
+            signatures.push(BundledSignature {
+                method: *method,
+                path: bundled,
+            });
+        }
+        if signatures.is_empty() {
+            warn!("No signature found for artifact: {path:?}");
+        }
+        recorded.push(Artifact {
+            path: name,
+            sha256,
+            size,
+            signatures,
+        });
+    }
+
+    let build_log = build_log
+        .map(|path| {
+            let (sha256, size) = hash_file(path)?;
+            Ok::<_, Error>(FileHash {
+                path: path.to_string_lossy().into_owned(),
+                sha256,
+                size,
+            })
+        })
+        .transpose()?;
+
+    let manifest = BundleManifest {
+        repro_env_version: env!("CARGO_PKG_VERSION").to_string(),
+        lockfile_digest: parsed.digest(),
+        image: parsed.container.image.clone(),
+        build_log,
+        artifacts: recorded,
+        packages,
+        files: files
+            .iter()
+            .map(|(path, data)| (path.clone(), hash::sha256(data)))
+            .collect(),
+    };
+    let mut buf = serde_json::to_vec_pretty(&manifest)?;
+    buf.push(b'\n');
+    files.insert(MANIFEST_PATH.to_string(), buf);
+    Ok((manifest, files))
+}
+
+/// Check that the bundle is complete and consistent, returns its manifest and
+/// a list of problems. Artifacts are compared with the files in `artifacts_dir`
+/// and their OpenPGP signatures are checked with `certs`.
+pub fn check(
+    files: &BTreeMap<String, Vec<u8>>,
+    artifacts_dir: Option<&Path>,
+    certs: &[Cert],
+) -> Result<(BundleManifest, Vec<String>)> {
+    let buf = files
+        .get(MANIFEST_PATH)
+        .with_context(|| anyhow!("Bundle has no {MANIFEST_PATH}"))?;
+    let manifest = serde_json::from_slice::<BundleManifest>(buf)
+        .with_context(|| anyhow!("Failed to parse {MANIFEST_PATH} of bundle"))?;
+
+    let mut problems = Vec::new();
+    for (path, expected) in &manifest.files {
+        match files.get(path) {
+            Some(data) if hash::sha256(data) == *expected => (),
+            Some(_) => problems.push(format!("{path}: sha256 doesn't match the manifest")),
+            None => problems.push(format!("{path}: missing from bundle")),
+        }
+    }
+    for path in files.keys() {
+        if path != MANIFEST_PATH && !manifest.files.contains_key(path) {
+            problems.push(format!("{path}: not listed in the manifest"));
+        }
+    }
+
+    match files.get(LOCKFILE_PATH).map(|buf| {
+        let lockfile = Lockfile::deserialize(std::str::from_utf8(buf)?)?;
+        Ok::<_, Error>(lockfile)
+    }) {
+        Some(Ok(lockfile)) => {
+            if lockfile.digest() != manifest.lockfile_digest {
+                problems.push(format!(
+                    "{LOCKFILE_PATH}: digest doesn't match the manifest"
+                ));
+            }
+            if lockfile.container.image != manifest.image {
+                problems.push(format!("{LOCKFILE_PATH}: image doesn't match the manifest"));
+            }
+            for package in &manifest.packages {
+                if !lockfile.packages.iter().any(|p| p.sha256 == package.sha256) {
+                    problems.push(format!(
+                        "{MANIFEST_PATH}: package {:?} is not in the lockfile",
+                        package.name
+                    ));
+                }
+            }
+        }
+        Some(Err(err)) => problems.push(format!("{LOCKFILE_PATH}: {err:#}")),
+        None => problems.push(format!("{LOCKFILE_PATH}: missing from bundle")),
+    }
+
+    let sbom = files
+        .get(SBOM_PATH)
+        .and_then(|buf| serde_json::from_slice::<serde_json::Value>(buf).ok());
+    if sbom.as_ref().and_then(|sbom| sbom.get("bomFormat")) != Some(&"CycloneDX".into()) {
+        problems.push(format!("{SBOM_PATH}: not a CycloneDX document"));
+    }
+
+    for artifact in &manifest.artifacts {
+        let Some(dir) = artifacts_dir else {
+            continue;
+        };
+        let path = dir.join(artifact_path(Path::new(&artifact.path))?);
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(err) => {
+                problems.push(format!("{}: failed to read {path:?}: {err}", artifact.path));
+                continue;
+            }
+        };
+        if hash::sha256(&data) != artifact.sha256 {
+            problems.push(format!(
+                "{}: sha256 doesn't match the manifest",
+                artifact.path
+            ));
+            continue;
+        }
+        if certs.is_empty() {
+            continue;
+        }
+        let openpgp = artifact
+            .signatures
+            .iter()
+            .filter(|sig| sig.method == SignMethod::Openpgp)
+            .collect::<Vec<_>>();
+        if openpgp.is_empty() {
+            problems.push(format!("{}: no OpenPGP signature in bundle", artifact.path));
+        }
+        for sig in openpgp {
+            let Some(buf) = files.get(&sig.path) else {
+                continue;
+            };
+            if let Err(err) = pgp::verify_detached(&data, buf, certs) {
+                problems.push(format!("{}: {err:#}", sig.path));
+            }
+        }
+    }
+
+    Ok((manifest, problems))
+}
+
+/// The package sources of the last successful build of this lockfile in the project
+fn last_build_packages(lockfile_path: &Path, lockfile_digest: &str) -> Result<Vec<PackageSource>> {
+    let project = state::project_dir(lockfile_path)?;
+    let state = state::load(&project)?;
+    let packages = state
+        .builds
+        .iter()
+        .rev()
+        .find(|build| build.success && build.lockfile_digest == lockfile_digest)
+        .map(|build| build.packages.clone())
+        .unwrap_or_default();
+    Ok(packages)
+}
+
+pub async fn bundle(bundle: &args::AttestBundle) -> Result<()> {
+    let lockfile = std::fs::read(&bundle.file)
+        .with_context(|| anyhow!("Failed to read dependency lockfile: {:?}", bundle.file))?;
+    let parsed = Lockfile::deserialize(std::str::from_utf8(&lockfile)?)?;
+
+    let mut artifacts = bundle.artifact.clone();
+    if artifacts.is_empty() {
+        let path = manifest::locate(&bundle.manifest);
+        if path.exists() {
+            let manifest = Manifest::read_from_file(&path).await?;
+            if let Some(sign) = manifest.build.sign {
+                debug!("Using the artifacts of [build.sign] in {path:?}");
+                artifacts = sign.artifacts;
+            }
+        }
+    }
+    if artifacts.is_empty() {
+        warn!("No artifacts are bundled, use --artifact or [build.sign] in repro-env.toml");
+    }
+
+    let sbom = sbom::document(&parsed, bundle.no_image_sbom).await?;
+    let mut sbom = serde_json::to_vec_pretty(&sbom)?;
+    sbom.push(b'\n');
+
+    let packages = match last_build_packages(&bundle.file, &parsed.digest()) {
+        Ok(packages) => packages,
+        Err(err) => {
+            warn!("Failed to read the build history of the project: {err:#}");
+            vec![]
+        }
+    };
+    if packages.is_empty() && !parsed.packages.is_empty() {
+        warn!("No build of this lockfile was recorded, the bundle doesn't say where the packages came from");
+    }
+
+    let (manifest, files) = collect(
+        Path::new("."),
+        &lockfile,
+        &sbom,
+        &artifacts,
+        bundle.build_log.as_deref(),
+        packages,
+    )?;
+    let archive = write_archive(&files)?;
+    utils::atomic_write(&bundle.output, &archive)
+        .with_context(|| anyhow!("Failed to write bundle: {:?}", bundle.output))?;
+    info!(
+        "Wrote bundle with {} artifacts to {:?} (sha256: {})",
+        manifest.artifacts.len(),
+        bundle.output,
+        hash::sha256(&archive)
+    );
+    Ok(())
+}
+
+pub async fn verify(verify: &args::AttestVerify) -> Result<()> {
+    let file = std::fs::File::open(&verify.bundle)
+        .with_context(|| anyhow!("Failed to open bundle: {:?}", verify.bundle))?;
+    let files = read_archive(file)?;
+
+    let mut certs = Vec::new();
+    for path in &verify.key {
+        let buf = std::fs::read(path).with_context(|| anyhow!("Failed to read key: {path:?}"))?;
+        let cert = Cert::from_bytes(&buf)
+            .with_context(|| anyhow!("Failed to parse OpenPGP key: {path:?}"))?;
+        certs.push(cert);
+    }
+    if !certs.is_empty() && verify.artifacts_dir.is_none() {
+        bail!("Checking signatures with --key needs the artifacts, use --artifacts-dir");
+    }
+
+    let (manifest, problems) = check(&files, verify.artifacts_dir.as_deref(), &certs)?;
+    if !problems.is_empty() {
+        bail!("Bundle verification failed:\n  {}", problems.join("\n  "));
+    }
+
+    println!("image: {}", manifest.image);
+    println!("lockfile digest: {}", manifest.lockfile_digest);
+    if let Some(log) = &manifest.build_log {
+        println!("build log: {} {}", log.sha256, log.path);
+    }
+    for package in &manifest.packages {
+        let origin = match package.origin {
+            Origin::Cache => "cache".to_string(),
+            Origin::Download => format!(
+                "downloaded from {}",
+                package.url.as_deref().unwrap_or("unknown url")
+            ),
+        };
+        println!("package: {} {} ({origin})", package.name, package.version);
+    }
+    for artifact in &manifest.artifacts {
+        let methods = artifact
+            .signatures
+            .iter()
+            .map(|sig| sig.method.as_str())
+            .collect::<Vec<_>>();
+        println!(
+            "artifact: {} {} (signatures: {})",
+            artifact.sha256,
+            artifact.path,
+            if methods.is_empty() {
+                "none".to_string()
+            } else {
+                methods.join(", ")
+            }
+        );
+    }
+    if verify.artifacts_dir.is_some() {
+        info!("Artifacts match the hashes of the bundle");
+    }
+    if !certs.is_empty() {
+        info!("OpenPGP signatures of the artifacts are valid");
+    }
+    info!("Bundle is complete and consistent: {:?}", verify.bundle);
+    Ok(())
+}
+
+pub async fn run(attest: &args::Attest) -> Result<()> {
+    match attest {
+        args::Attest::Bundle(bundle) => self::bundle(bundle).await,
+        args::Attest::Verify(verify) => self::verify(verify).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sequoia_openpgp::cert::CertBuilder;
+
+    const LOCKFILE: &str = r#"[container]
+image = "docker.io/library/archlinux@sha256:6568d3f1f278827a4a7d8537f80c2ae36982829a0c6bccff4cec081774025472"
+"#;
+
+    #[test]
+    fn test_bundle_roundtrip() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (cert, _) = CertBuilder::general_purpose(None, Some("release")).generate()?;
+        let artifact = dir.path().join("hello");
+        std::fs::write(&artifact, b"hello world")?;
+        let sig = pgp::sign_detached(&cert, None, b"hello world")?;
+        std::fs::write(dir.path().join("hello.asc"), &sig)?;
+
+        let sbom = br#"{"bomFormat":"CycloneDX"}"#;
+        let (manifest, files) = collect(
+            dir.path(),
+            LOCKFILE.as_bytes(),
+            sbom,
+            &[PathBuf::from("./hello")],
+            None,
+            vec![],
+        )?;
+        assert_eq!(
+            manifest.artifacts,
+            [Artifact {
+                path: "hello".to_string(),
+                sha256: hash::sha256(b"hello world"),
+                size: 11,
+                signatures: vec![BundledSignature {
+                    method: SignMethod::Openpgp,
+                    path: "signatures/hello.asc".to_string(),
+                }],
+            }]
+        );
+
+        let archive = write_archive(&files)?;
+        // the archive is reproducible
+        assert_eq!(archive, write_archive(&files)?);
+        let files = read_archive(&archive[..])?;
+        let (_, problems) = check(&files, Some(dir.path()), std::slice::from_ref(&cert))?;
+        assert_eq!(problems, Vec::<String>::new());
+
+        // a modified artifact is detected
+        std::fs::write(&artifact, b"hello world!")?;
+        let (_, problems) = check(&files, Some(dir.path()), &[])?;
+        assert_eq!(problems, ["hello: sha256 doesn't match the manifest"]);
+
+        // and so is a modified lockfile
+        let mut tampered = files.clone();
+        tampered.insert(
+            LOCKFILE_PATH.to_string(),
+            b"[container]\nimage = \"x\"\n".to_vec(),
+        );
+        let (_, problems) = check(&tampered, None, &[])?;
+        assert_eq!(
+            problems,
+            [
+                "repro-env.lock: sha256 doesn't match the manifest",
+                "repro-env.lock: digest doesn't match the manifest",
+                "repro-env.lock: image doesn't match the manifest",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_bundle_packages() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let sbom = br#"{"bomFormat":"CycloneDX"}"#;
+        let packages = vec![PackageSource {
+            name: "gcc".to_string(),
+            version: "13.2.1-3".to_string(),
+            sha256: "aa".to_string(),
+            origin: Origin::Download,
+            url: Some("https://mirror.example.com/gcc-13.2.1-3.pkg.tar.zst".to_string()),
+        }];
+        let (manifest, files) = collect(
+            dir.path(),
+            LOCKFILE.as_bytes(),
+            sbom,
+            &[],
+            None,
+            packages.clone(),
+        )?;
+        assert_eq!(manifest.packages, packages);
+        let json = serde_json::from_slice::<serde_json::Value>(&files[MANIFEST_PATH])?;
+        assert_eq!(json["packages"][0]["origin"], "download");
+
+        // the recorded packages need to be part of the lockfile
+        let (_, problems) = check(&files, None, &[])?;
+        assert_eq!(
+            problems,
+            ["manifest.json: package \"gcc\" is not in the lockfile"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_artifact_path() {
+        assert_eq!(
+            artifact_path(Path::new("target/release/hello")).unwrap(),
+            "target/release/hello"
+        );
+        assert_eq!(artifact_path(Path::new("./hello")).unwrap(), "hello");
+        assert!(artifact_path(Path::new("/etc/passwd")).is_err());
+        assert!(artifact_path(Path::new("../hello")).is_err());
+        assert!(artifact_path(Path::new("")).is_err());
+    }
+}
@@ -0,0 +1,264 @@
+//! Report known vulnerabilities of the locked packages, using the advisories on osv.dev
+use crate::args;
+use crate::errors::*;
+use crate::http;
+use crate::lockfile::{Lockfile, PackageLock};
+use crate::osv::{self, Vuln};
+use crate::purl;
+use crate::state::{self, VexRecord};
+use crate::utils;
+use crate::vex::{self, Statement};
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Finding {
+    pub name: String,
+    pub version: String,
+    pub purl: String,
+    pub vulns: Vec<Vuln>,
+}
+
+/// The packages that can be looked up on osv.dev, with their purl. `distro` is
+/// the debian release, e.g. `debian-12`.
+pub fn queryable<'a>(
+    packages: &'a [PackageLock],
+    distro: Option<&str>,
+) -> Vec<(&'a PackageLock, String)> {
+    packages
+        .iter()
+        .filter_map(|pkg| {
+            let purl = purl::advisory_purl(pkg, distro)?;
+            if !osv::is_supported(&purl) {
+                return None;
+            }
+            Some((pkg, purl))
+        })
+        .collect()
+}
+
+/// If a VEX statement says the package is not affected by this vulnerability
+pub fn is_suppressed(statements: &[Statement], purl: &str, vuln: &Vuln) -> bool {
+    let ids = [vuln.id.as_str()]
+        .into_iter()
+        .chain(vuln.aliases.iter().map(String::as_str))
+        .collect::<Vec<_>>();
+    statements.iter().any(|s| s.matches(&ids, purl))
+}
+
+/// Attach and detach VEX documents, then load the statements of all attached ones
+async fn load_vex(audit: &args::Audit, project: &Path) -> Result<Vec<Statement>> {
+    if !audit.attach_vex.is_empty() || !audit.detach_vex.is_empty() {
+        let mut attach = Vec::new();
+        for path in &audit.attach_vex {
+            // make sure it's valid before it's recorded
+            vex::read_from_file(path)?;
+            let path = std::path::absolute(path)?;
+            let sha256 = utils::sha256_file(&path)?;
+            attach.push(VexRecord { path, sha256 });
+        }
+        let mut detach = Vec::new();
+        for path in &audit.detach_vex {
+            detach.push(std::path::absolute(path)?);
+        }
+        state::update(project, |state| {
+            for path in detach {
+                if !state.detach_vex(&path) {
+                    warn!("VEX document was not attached: {path:?}");
+                }
+            }
+            for record in attach {
+                info!("Attaching VEX document: {:?}", record.path);
+                state.attach_vex(record);
+            }
+        })
+        .await?;
+    }
+
+    let mut statements = Vec::new();
+    for record in state::load(project)?.vex {
+        match utils::sha256_file(&record.path) {
+            Ok(sha256) if sha256 != record.sha256 => {
+                info!(
+                    "VEX document has changed since it was attached: {:?}",
+                    record.path
+                )
+            }
+            _ => (),
+        }
+        statements.extend(vex::read_from_file(&record.path)?);
+    }
+    Ok(statements)
+}
+
+pub fn format_text(findings: &[Finding]) -> String {
+    let mut out = String::new();
+    for finding in findings {
+        out.push_str(&format!(
+            "{} {} ({})\n",
+            finding.name, finding.version, finding.purl
+        ));
+        for vuln in &finding.vulns {
+            let mut line = format!("  {}", vuln.id);
+            if !vuln.aliases.is_empty() {
+                line.push_str(&format!(" ({})", vuln.aliases.join(", ")));
+            }
+            if let Some(summary) = &vuln.summary {
+                line.push_str(&format!(": {summary}"));
+            }
+            out.push_str(&line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+pub async fn audit(audit: &args::Audit) -> Result<()> {
+    let path = audit.file.as_deref().unwrap_or(Path::new("repro-env.lock"));
+    let lockfile = Lockfile::read_from_file(path).await?;
+    let statements = load_vex(audit, &state::project_dir(path)?).await?;
+
+    let distro = purl::debian_distro(&lockfile);
+    if distro.is_none() && lockfile.packages.iter().any(|pkg| pkg.system == "debian") {
+        warn!("Could not detect the debian release of the lockfile, advisories are matched without it");
+    }
+    let packages = queryable(&lockfile.packages, distro.as_deref());
+    let mut unsupported = lockfile
+        .packages
+        .iter()
+        .filter(|pkg| !purl::advisory_purl(pkg, None).is_some_and(|purl| osv::is_supported(&purl)))
+        .map(|pkg| pkg.system.as_str())
+        .collect::<Vec<_>>();
+    unsupported.sort();
+    unsupported.dedup();
+    if packages.is_empty() && !lockfile.packages.is_empty() {
+        bail!(
+            "osv.dev has no advisories for any of the locked packages (system: {}), they can't be audited",
+            unsupported.join(", ")
+        );
+    }
+    let skipped = lockfile.packages.len() - packages.len();
+    if skipped > 0 {
+        warn!(
+            "Skipping {skipped} packages that osv.dev has no advisories for (system: {}), they are not audited",
+            unsupported.join(", ")
+        );
+    }
+
+    let client = http::Client::new()?;
+    let purls = packages
+        .iter()
+        .map(|(_, purl)| purl.as_str())
+        .collect::<Vec<_>>();
+    let results = osv::query(&client, &purls, audit.offline).await?;
+
+    let mut findings = Vec::new();
+    let mut suppressed = 0;
+    for ((pkg, purl), result) in packages.iter().zip(results) {
+        let mut vulns = Vec::new();
+        for vuln in &result.vulns {
+            let vuln = osv::vuln(&client, vuln, audit.offline).await?;
+            if is_suppressed(&statements, purl, &vuln) {
+                debug!(
+                    "Vulnerability {:?} of {purl:?} is suppressed by VEX",
+                    vuln.id
+                );
+                suppressed += 1;
+            } else {
+                vulns.push(vuln);
+            }
+        }
+        if vulns.is_empty() {
+            continue;
+        }
+        findings.push(Finding {
+            name: pkg.name.clone(),
+            version: pkg.version.clone(),
+            purl: purl.clone(),
+            vulns,
+        });
+    }
+
+    if suppressed > 0 {
+        info!("Suppressed {suppressed} vulnerabilities that VEX statements marked as not affecting the package");
+    }
+    if audit.json {
+        println!("{}", serde_json::to_string(&findings)?);
+    } else {
+        print!("{}", format_text(&findings));
+    }
+
+    if !findings.is_empty() {
+        let count = findings.iter().map(|f| f.vulns.len()).sum::<usize>();
+        bail!(
+            "Found {count} known vulnerabilities in {} packages",
+            findings.len()
+        );
+    }
+    if skipped > 0 {
+        info!(
+            "No known vulnerabilities in {} packages, {skipped} packages were not audited",
+            packages.len()
+        );
+    } else {
+        info!("No known vulnerabilities in {} packages", packages.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_suppressed() {
+        let vuln = Vuln {
+            id: "DSA-5587-1".to_string(),
+            summary: None,
+            aliases: vec!["CVE-2023-46218".to_string()],
+            modified: None,
+        };
+        let statements = vec![Statement {
+            vulnerability: "CVE-2023-46218".to_string(),
+            products: vec!["pkg:deb/debian/curl".to_string()],
+        }];
+        let purl = "pkg:deb/debian/curl@7.88.1-10?arch=amd64";
+        assert!(is_suppressed(&statements, purl, &vuln));
+        assert!(!is_suppressed(&[], purl, &vuln));
+        assert!(!is_suppressed(
+            &statements,
+            "pkg:deb/debian/libcurl4@7.88.1-10?arch=amd64",
+            &vuln
+        ));
+    }
+
+    #[test]
+    fn test_format_text() {
+        let findings = vec![Finding {
+            name: "curl".to_string(),
+            version: "7.88.1-10".to_string(),
+            purl: "pkg:deb/debian/curl@7.88.1-10?arch=amd64".to_string(),
+            vulns: vec![
+                Vuln {
+                    id: "DSA-5587-1".to_string(),
+                    summary: Some("curl - security update".to_string()),
+                    aliases: vec!["CVE-2023-46218".to_string()],
+                    modified: None,
+                },
+                Vuln {
+                    id: "DLA-3000-1".to_string(),
+                    summary: None,
+                    aliases: vec![],
+                    modified: None,
+                },
+            ],
+        }];
+        assert_eq!(
+            format_text(&findings),
+            "curl 7.88.1-10 (pkg:deb/debian/curl@7.88.1-10?arch=amd64)
+  DSA-5587-1 (CVE-2023-46218): curl - security update
+  DLA-3000-1
+"
+        );
+    }
+}
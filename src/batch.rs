@@ -0,0 +1,372 @@
+//! Run update, fetch and build across many project directories, configured
+//! with `batch --projects`. Each action is a child process of repro-env, they
+//! share the cache directory like any other concurrent invocation. The output
+//! of each action is printed to stderr once it's done, the report to stdout.
+use crate::args::{self, BatchAction};
+use crate::errors::*;
+use crate::jobs::JobCommand;
+use crate::progress::format_duration;
+use futures_util::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectsFile {
+    #[serde(rename = "project", default)]
+    pub projects: Vec<Project>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Project {
+    /// The project directory, relative to the projects file
+    pub path: PathBuf,
+    /// Defaults to the path
+    pub name: Option<String>,
+    /// The command for the build action, the project is skipped if it's not set
+    pub build: Option<JobCommand>,
+}
+
+impl Project {
+    pub fn name(&self) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| self.path.to_string_lossy().into_owned())
+    }
+
+    /// The arguments of repro-env for this action, None if there's nothing to do
+    pub fn action_args(&self, action: BatchAction) -> Option<Vec<String>> {
+        let args = match action {
+            BatchAction::Update => vec!["update".to_string()],
+            BatchAction::Fetch => vec!["fetch".to_string()],
+            BatchAction::Build => {
+                let mut args = vec!["build".to_string(), "--".to_string()];
+                match self.build.as_ref()? {
+                    JobCommand::Shell(script) => {
+                        args.extend(["sh".to_string(), "-c".to_string(), script.clone()])
+                    }
+                    JobCommand::Args(cmd) => args.extend(cmd.iter().cloned()),
+                }
+                args
+            }
+        };
+        Some(args)
+    }
+}
+
+impl ProjectsFile {
+    pub fn deserialize(buf: &str) -> Result<Self> {
+        let file = toml::from_str::<Self>(buf).context("Failed to load projects from toml")?;
+
+        if file.projects.is_empty() {
+            bail!("Projects file doesn't define any [[project]]");
+        }
+        let mut names = HashSet::new();
+        for project in &file.projects {
+            let name = project.name();
+            if !names.insert(name.clone()) {
+                bail!("Project name is used multiple times: {name:?}");
+            }
+        }
+        Ok(file)
+    }
+
+    pub fn read_from_file(path: &Path) -> Result<Self> {
+        let buf = std::fs::read_to_string(path)
+            .with_context(|| anyhow!("Failed to read projects file: {path:?}"))?;
+        Self::deserialize(&buf).with_context(|| anyhow!("Failed to parse projects file: {path:?}"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Failed,
+    Skipped,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ActionResult {
+    pub action: BatchAction,
+    pub status: Status,
+    /// None if the action was skipped or the process got killed by a signal
+    pub code: Option<i32>,
+    #[serde(serialize_with = "serialize_secs")]
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ProjectResult {
+    pub name: String,
+    pub actions: Vec<ActionResult>,
+}
+
+impl ProjectResult {
+    pub fn success(&self) -> bool {
+        self.actions.iter().all(|a| a.status != Status::Failed)
+    }
+}
+
+fn serialize_secs<S: serde::Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_f64(d.as_secs_f64())
+}
+
+async fn run_action(
+    exe: &Path,
+    dir: &Path,
+    name: &str,
+    action: BatchAction,
+    args: &[String],
+) -> Result<ActionResult> {
+    let mut cmd = Command::new(exe);
+    cmd.arg("-C")
+        .arg(dir)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    debug!("Running {action:?} for project {name:?}: {cmd:?}");
+    let start = Instant::now();
+    let output = cmd
+        .output()
+        .await
+        .with_context(|| anyhow!("Failed to start repro-env for project {name:?}"))?;
+    let elapsed = start.elapsed();
+
+    let status = if output.status.success() {
+        Status::Ok
+    } else {
+        Status::Failed
+    };
+    eprintln!(
+        "==> {name} {} {} in {:.1}s <==",
+        action.as_str(),
+        status_text(status, output.status.code()),
+        elapsed.as_secs_f64()
+    );
+    eprint!("{}", String::from_utf8_lossy(&output.stderr));
+    eprint!("{}", String::from_utf8_lossy(&output.stdout));
+
+    Ok(ActionResult {
+        action,
+        status,
+        code: output.status.code(),
+        elapsed,
+    })
+}
+
+/// Run the actions of a project in order, the remaining ones are skipped after a failure
+async fn run_project(
+    exe: &Path,
+    base: &Path,
+    project: &Project,
+    actions: &[BatchAction],
+) -> Result<ProjectResult> {
+    let name = project.name();
+    let dir = base.join(&project.path);
+    let mut results = Vec::new();
+    let mut failed = false;
+    for action in actions {
+        let args = match project.action_args(*action) {
+            Some(args) if !failed => args,
+            _ => {
+                results.push(ActionResult {
+                    action: *action,
+                    status: Status::Skipped,
+                    code: None,
+                    elapsed: Duration::ZERO,
+                });
+                continue;
+            }
+        };
+        let result = run_action(exe, &dir, &name, *action, &args).await?;
+        failed |= result.status == Status::Failed;
+        results.push(result);
+    }
+    Ok(ProjectResult {
+        name,
+        actions: results,
+    })
+}
+
+fn status_text(status: Status, code: Option<i32>) -> String {
+    match (status, code) {
+        (Status::Ok, _) => "ok".to_string(),
+        (Status::Skipped, _) => "skipped".to_string(),
+        (Status::Failed, Some(code)) => format!("failed (exit code {code})"),
+        (Status::Failed, None) => "failed (killed)".to_string(),
+    }
+}
+
+pub fn format_report(results: &[ProjectResult]) -> String {
+    let width = results.iter().map(|r| r.name.len()).max().unwrap_or(0);
+    let mut out = String::new();
+    for result in results {
+        let actions = result
+            .actions
+            .iter()
+            .map(|a| {
+                let status = status_text(a.status, a.code);
+                if a.status == Status::Skipped {
+                    format!("{} {status}", a.action.as_str())
+                } else {
+                    format!(
+                        "{} {status} ({})",
+                        a.action.as_str(),
+                        format_duration(a.elapsed)
+                    )
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        out.push_str(&format!("{:<width$}  {actions}\n", result.name));
+    }
+    out
+}
+
+pub async fn batch(batch: &args::Batch) -> Result<()> {
+    let file = ProjectsFile::read_from_file(&batch.projects)?;
+    let base = batch.projects.parent().unwrap_or(Path::new(""));
+    let exe = std::env::current_exe().context("Failed to detect path of repro-env executable")?;
+
+    let mut actions = batch.actions.clone();
+    actions.sort();
+    actions.dedup();
+
+    info!(
+        "Running {} in {} projects ({} at a time)...",
+        actions
+            .iter()
+            .map(|a| a.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+        file.projects.len(),
+        batch.jobs.max(1).min(file.projects.len())
+    );
+    let mut running = stream::iter(&file.projects)
+        .map(|project| run_project(&exe, base, project, &actions))
+        .buffer_unordered(batch.jobs.max(1));
+
+    let mut results = Vec::new();
+    while let Some(result) = running.next().await {
+        results.push(result?);
+    }
+    // report in the order of the projects file
+    results.sort_by_key(|r| file.projects.iter().position(|p| p.name() == r.name));
+
+    if batch.json {
+        println!("{}", serde_json::to_string(&results)?);
+    } else {
+        print!("{}", format_report(&results));
+    }
+
+    let failed = results.iter().filter(|r| !r.success()).count();
+    if failed > 0 {
+        bail!("{failed} of {} projects failed", results.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_projects() -> Result<()> {
+        let file = ProjectsFile::deserialize(
+            r#"
+[[project]]
+path = "pkgs/foo"
+build = ["make", "install"]
+
+[[project]]
+path = "pkgs/bar"
+name = "bar"
+build = "make && make check"
+
+[[project]]
+path = "pkgs/baz"
+"#,
+        )?;
+        assert_eq!(file.projects.len(), 3);
+        assert_eq!(file.projects[0].name(), "pkgs/foo");
+        assert_eq!(file.projects[1].name(), "bar");
+
+        assert_eq!(
+            file.projects[0].action_args(BatchAction::Build).unwrap(),
+            ["build", "--", "make", "install"]
+        );
+        assert_eq!(
+            file.projects[1].action_args(BatchAction::Build).unwrap(),
+            ["build", "--", "sh", "-c", "make && make check"]
+        );
+        assert_eq!(file.projects[2].action_args(BatchAction::Build), None);
+        assert_eq!(
+            file.projects[2].action_args(BatchAction::Update).unwrap(),
+            ["update"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_projects() {
+        assert!(ProjectsFile::deserialize("").is_err());
+        assert!(ProjectsFile::deserialize(
+            r#"
+[[project]]
+path = "foo"
+
+[[project]]
+path = "bar"
+name = "foo"
+"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_format_report() {
+        let results = vec![
+            ProjectResult {
+                name: "foo".to_string(),
+                actions: vec![
+                    ActionResult {
+                        action: BatchAction::Update,
+                        status: Status::Ok,
+                        code: Some(0),
+                        elapsed: Duration::from_secs(3),
+                    },
+                    ActionResult {
+                        action: BatchAction::Build,
+                        status: Status::Failed,
+                        code: Some(2),
+                        elapsed: Duration::from_secs(65),
+                    },
+                ],
+            },
+            ProjectResult {
+                name: "barbaz".to_string(),
+                actions: vec![ActionResult {
+                    action: BatchAction::Update,
+                    status: Status::Skipped,
+                    code: None,
+                    elapsed: Duration::ZERO,
+                }],
+            },
+        ];
+        assert!(!results[0].success());
+        assert!(results[1].success());
+        let report = format_report(&results);
+        let lines = report.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("foo     update ok ("));
+        assert!(lines[0].contains("build failed (exit code 2) ("));
+        assert_eq!(lines[1], "barbaz  update skipped");
+    }
+}
@@ -0,0 +1,11 @@
+use repro_env::cargo;
+use repro_env::cli;
+use repro_env::errors::*;
+use repro_env::summary;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    summary::init();
+    let args = cargo::parse()?;
+    cli::run(args).await
+}
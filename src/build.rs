@@ -1,63 +1,313 @@
 use crate::args;
-use crate::container::{self, Container};
+use crate::consistency;
+use crate::container::{self, Container, Image, Mount};
+use crate::context::CopyContext;
 use crate::errors::*;
 use crate::fetch;
-use crate::lockfile::PackageLock;
+use crate::freshness;
+use crate::git;
+use crate::hash;
+use crate::haskell;
+use crate::install::{self, Install};
+use crate::java;
+use crate::jobs;
+use crate::local::LocalConfig;
+use crate::lockfile::{
+    ApkKeyLock, FileLock, GitLock, KeyLock, Lockfile, PackageLock, ToolchainLock,
+};
+use crate::manifest::{self, EnvironmentManifest, Manifest, FAKETIME_PACKAGE};
 use crate::paths;
-use crate::pgp;
-use crate::pkgs::archlinux;
-use data_encoding::BASE64;
+use crate::perl;
+use crate::php;
+use crate::pkgs;
+use crate::platform::{self, Platform};
+use crate::refs;
+use crate::ruby;
+use crate::sandbox;
+use crate::sign;
+use crate::staging::{self, StagingDir};
+use crate::state::{self, BuildRecord, ContainerRecord};
+use crate::steps::{self, Steps};
+use crate::summary;
+use crate::texlive;
+use crate::toolchain;
+use crate::utils;
+use nix::unistd::geteuid;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::path::Path;
-use std::time::Duration;
-use tempfile::TempDir;
-use time::format_description::well_known;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tempfile::NamedTempFile;
 use time::OffsetDateTime;
 use tokio::fs;
 
-#[derive(Debug, PartialEq, Default)]
-pub struct Install {
-    alpine: Vec<(PackageLock, String)>,
-    archlinux: Vec<(PackageLock, String)>,
-    debian: Vec<(PackageLock, String)>,
+/// Where --cmd-file is mounted, it's executed from there instead of being
+/// passed as argument, so its size isn't limited by the kernel
+pub const CMD_FILE: &str = "/extra/cmd.sh";
+
+/// The filename of a package in /extra, the last path segment of its url.
+/// It's percent-decoded, so `rust-1%3A1.70.0-1` and `rust-1:1.70.0-1` are
+/// staged as the same file.
+pub fn filename_from_url(url: &str) -> Result<String> {
+    let url = url
+        .parse::<reqwest::Url>()
+        .with_context(|| anyhow!("Failed to parse string as url: {url:?}"))?;
+    let segment = url
+        .path_segments()
+        .context("Failed to get path from url")?
+        .next_back()
+        .context("Failed to find filename from url")?;
+    let filename = urlencoding::decode(segment)
+        .with_context(|| anyhow!("Failed to url decode filename: {segment:?}"))?;
+    if filename.is_empty() {
+        bail!("Filename from url is empty");
+    }
+    if filename == "."
+        || filename == ".."
+        || filename.contains('/')
+        || filename.chars().any(char::is_control)
+    {
+        bail!("Invalid filename in url: {filename:?}");
+    }
+    Ok(filename.into_owned())
 }
 
-impl Install {
-    fn add_pkg(&mut self, pkg: PackageLock, filename: String) -> Result<()> {
-        let list = match pkg.system.as_str() {
-            "alpine" => &mut self.alpine,
-            "archlinux" => &mut self.archlinux,
-            "debian" => &mut self.debian,
-            system => bail!("Unknown package system: {system:?}"),
-        };
-        list.push((pkg, filename));
-        Ok(())
+/// The owner the files in the build directory are changed to with `--chown`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Chown {
+    /// The user that invoked repro-env, as seen from inside of the container
+    Auto,
+    Owner {
+        uid: u32,
+        gid: u32,
+    },
+}
+
+impl FromStr for Chown {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "auto" {
+            return Ok(Chown::Auto);
+        }
+        let (uid, gid) = s
+            .split_once(':')
+            .with_context(|| anyhow!("Invalid owner, expected UID:GID: {s:?}"))?;
+        Ok(Chown::Owner {
+            uid: uid
+                .parse()
+                .with_context(|| anyhow!("Invalid uid: {uid:?}"))?,
+            gid: gid
+                .parse()
+                .with_context(|| anyhow!("Invalid gid: {gid:?}"))?,
+        })
+    }
+}
+
+impl Chown {
+    /// Rootless podman maps root of the container to the user that started
+    /// it, with rootful podman the user that ran sudo is used instead
+    pub fn resolve(self, euid: u32, sudo: Option<(&str, &str)>) -> Result<(u32, u32)> {
+        match self {
+            Chown::Owner { uid, gid } => Ok((uid, gid)),
+            Chown::Auto if euid != 0 => Ok((0, 0)),
+            Chown::Auto => {
+                let (uid, gid) = sudo.context(
+                    "Running as root without sudo, select the owner with --chown=UID:GID",
+                )?;
+                Ok((
+                    uid.parse()
+                        .with_context(|| anyhow!("Invalid SUDO_UID: {uid:?}"))?,
+                    gid.parse()
+                        .with_context(|| anyhow!("Invalid SUDO_GID: {gid:?}"))?,
+                ))
+            }
+        }
+    }
+
+    /// Resolve the owner for the current process
+    pub fn owner(self) -> Result<(u32, u32)> {
+        let sudo_uid = env::var("SUDO_UID").ok();
+        let sudo_gid = env::var("SUDO_GID").ok();
+        let sudo = sudo_uid.as_deref().zip(sudo_gid.as_deref());
+        self.resolve(geteuid().as_raw(), sudo)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Plan {
+    pub image: String,
+    pub mounts: Vec<PlanMount>,
+    pub packages: Vec<PlanPackage>,
+    pub env: Vec<String>,
+    pub cmd: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlanMount {
+    pub src: String,
+    pub dest: String,
+    pub read_only: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlanPackage {
+    pub name: String,
+    pub version: String,
+    pub system: String,
+    pub path: String,
+    pub cache_path: PathBuf,
+    pub cached: bool,
+}
+
+impl Plan {
+    pub fn new(
+        build: &args::Build,
+        image: &str,
+        mounts: &[Mount],
+        dependencies: &[PackageLock],
+        env: &BuildEnv,
+    ) -> Result<Self> {
+        let pkgs_cache_dir = paths::pkgs_cache_dir()?;
+
+        let mut mounts = mounts
+            .iter()
+            .map(|mount| PlanMount {
+                src: mount.src.to_string_lossy().into_owned(),
+                dest: mount.dest.clone(),
+                read_only: mount.read_only,
+            })
+            .collect::<Vec<_>>();
+        if !dependencies.is_empty() {
+            let mut src = paths::staging_dir(build.staging_dir.as_deref())?;
+            src.push(format!("{}XXXXXX", staging::PREFIX));
+            mounts.push(PlanMount {
+                src: src.to_string_lossy().into_owned(),
+                dest: "/extra".to_string(),
+                read_only: false,
+            });
+        }
+
+        let mut packages = Vec::new();
+        for package in dependencies.iter().filter(|p| p.has_package_file()) {
+            let filename = filename_from_url(&package.url)?;
+            let cache_path = pkgs_cache_dir.sha256_path(&package.sha256)?;
+            packages.push(PlanPackage {
+                name: package.name.clone(),
+                version: package.version.clone(),
+                system: package.system.clone(),
+                path: format!("/extra/{filename}"),
+                cached: cache_path.exists(),
+                cache_path,
+            });
+        }
+
+        Ok(Plan {
+            image: image.to_string(),
+            mounts,
+            packages,
+            env: env
+                .vars
+                .iter()
+                .cloned()
+                .chain(
+                    build
+                        .secret_env
+                        .iter()
+                        .map(|name| format!("{name}=<redacted>")),
+                )
+                .collect(),
+            cmd: build.command()?,
+        })
     }
+
+    pub fn format_text(&self) -> String {
+        let mut out = String::new();
+        out.push_str(&format!("image: {}\n", self.image));
+        out.push_str("mounts:\n");
+        for mount in &self.mounts {
+            let ro = if mount.read_only { " (read-only)" } else { "" };
+            out.push_str(&format!("  {} -> {}{ro}\n", mount.src, mount.dest));
+        }
+        if !self.packages.is_empty() {
+            out.push_str("packages:\n");
+            for pkg in &self.packages {
+                let cached = if pkg.cached { "cached" } else { "download" };
+                out.push_str(&format!(
+                    "  {} {} ({}): {} -> {} ({cached})\n",
+                    pkg.name,
+                    pkg.version,
+                    pkg.system,
+                    pkg.cache_path.display(),
+                    pkg.path
+                ));
+            }
+        }
+        if !self.env.is_empty() {
+            out.push_str("env:\n");
+            for env in &self.env {
+                out.push_str(&format!("  {env}\n"));
+            }
+        }
+        out.push_str(&format!("command: {:?}\n", self.cmd));
+        out
+    }
+}
+
+/// Packages are usually reflinked from the cache if the staging directory is on
+/// the same filesystem, otherwise they are copied and need free space
+fn check_staging_space(path: &Path, dependencies: &[PackageLock]) -> Result<()> {
+    let cache_dir = paths::cache_dir()?;
+    let same_fs = std::fs::metadata(path)?.dev() == std::fs::metadata(&cache_dir)?.dev();
+    if !same_fs {
+        info!(
+            "Staging directory is on a different filesystem than the cache {cache_dir:?}, packages are copied instead of reflinked"
+        );
+        utils::check_free_space(path, utils::total_size(dependencies))?;
+    }
+    Ok(())
 }
 
-pub async fn setup_extra_folder(path: &Path, dependencies: Vec<PackageLock>) -> Result<Install> {
+pub async fn setup_extra_folder(
+    path: &Path,
+    dependencies: Vec<PackageLock>,
+    keys: Vec<KeyLock>,
+    apk_keys: Vec<ApkKeyLock>,
+) -> Result<Install> {
     let pkgs_cache_dir = paths::pkgs_cache_dir()?;
 
     let mut install = Install::default();
+    for key in keys {
+        let filename = format!("{}.asc", key.fingerprint);
+        debug!("Writing pinned OpenPGP certificate to {filename:?}...");
+        fs::write(path.join(&filename), &key.cert).await?;
+        install.keys.push((key, filename));
+    }
+    install.apk_keys = apk_keys;
+
+    let mut staged = Vec::new();
     for package in dependencies {
-        // determine filename
-        let url = package
-            .url
-            .parse::<reqwest::Url>()
-            .with_context(|| anyhow!("Failed to parse string as url: {:?}", package.url))?;
-        let filename = url
-            .path_segments()
-            .context("Failed to get path from url")?
-            .last()
-            .context("Failed to find filename from url")?;
-        if filename.is_empty() {
-            bail!("Filename from url is empty");
-        }
-
-        // setup /extra/ directory
-        let source = pkgs_cache_dir.sha256_path(&package.sha256)?;
+        if !package.has_package_file() {
+            install.add_pkg(package, String::new())?;
+            continue;
+        }
+        let filename = filename_from_url(&package.url)?;
+        staged.push((package, filename));
+    }
+
+    // setup /extra/ directory, the files are created in the same order every time
+    let mut copies = staged
+        .iter()
+        .map(|(package, filename)| (filename.as_str(), package.sha256.as_str()))
+        .collect::<Vec<_>>();
+    copies.sort();
+    for (filename, sha256) in copies {
+        let source = pkgs_cache_dir.sha256_path(sha256)?;
         let dest = path.join(filename);
-        let dest_sig = path.join(filename.to_owned() + ".sig");
 
         debug!("Trying to reflink {source:?} -> {dest:?}...");
         if let Err(err) = clone_file::clone_file(&source, &dest) {
@@ -66,141 +316,840 @@ pub async fn setup_extra_folder(path: &Path, dependencies: Vec<PackageLock>) ->
                 .await
                 .context("Failed to copy package from cache to temporary folder")?;
         }
+    }
 
-        // setup extra data
-        match package.system.as_str() {
-            "alpine" => (),
-            "archlinux" => {
-                let base64 = package
-                    .signature
-                    .as_ref()
-                    .context("Package in dependency lockfile is missing signature")?;
-                let signature = BASE64
-                    .decode(base64.as_bytes())
-                    .with_context(|| anyhow!("Failed to decode signature as base64: {base64:?}"))?;
-
-                debug!(
-                    "Writing signature ({} bytes) to {dest_sig:?}...",
-                    signature.len()
-                );
-                fs::write(dest_sig, signature).await?;
-            }
-            "debian" => (),
-            system => bail!("Unknown package system: {system:?}"),
+    // verification is the slow part, check all packages at the same time
+    let dir = path.to_path_buf();
+    let (mut install, staged, extra_files) = utils::spawn_blocking(move |cancel| {
+        let extra_files = staged
+            .par_iter()
+            .map(|(package, filename)| {
+                cancel.scope(|| {
+                    fetch::verify_package(&install, package, filename, &dir.join(filename))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok((install, staged, extra_files))
+    })
+    .await?;
+
+    for ((package, filename), extra_files) in staged.into_iter().zip(extra_files) {
+        for (name, content) in extra_files {
+            debug!("Writing {} bytes to {name:?}...", content.len());
+            fs::write(path.join(name), content).await?;
         }
+        install.add_pkg(package, filename)?;
+    }
 
-        // verify pkg content matches pin metadata
-        let pkg = fs::read(&dest).await?;
-        fetch::verify_pin_metadata(&pkg, &package)
-            .with_context(|| anyhow!("Failed to verify metadata for {filename:?}"))?;
+    Ok(install)
+}
 
-        install.add_pkg(package, filename.to_string())?;
+/// Install the dependencies in a temporary container and commit it, so the build
+/// itself can run with a read-only root filesystem
+async fn install_into_image(
+    image: &str,
+    config: container::Config<'_>,
+    install: &Install,
+    allow_untrusted: bool,
+) -> Result<String> {
+    info!("Installing dependencies into a temporary image for the hardened build...");
+    let container = Container::create(image, config).await?;
+    let mut committed = None;
+    let result = container
+        .run(
+            async {
+                install::run(&container, install, allow_untrusted).await?;
+                committed = Some(container.commit().await?);
+                Ok(())
+            },
+            false,
+        )
+        .await;
+    if let Err(err) = result {
+        if let Some(id) = committed {
+            remove_temporary_image(&id, false).await;
+        }
+        return Err(err);
     }
+    let id = committed.context("Container was not committed")?;
+    debug!("Committed container with dependencies as {id:?}");
+    Ok(id)
+}
 
-    Ok(install)
+/// Remove an image of `install_into_image`, unless the container using it is kept
+async fn remove_temporary_image(image: &str, keep: bool) {
+    if keep {
+        info!("Keeping temporary image of the kept container: {image:?}");
+        return;
+    }
+    if let Err(err) = container::remove_image(image).await {
+        warn!("Failed to remove temporary image {image:?}: {err:#}");
+    }
+}
+
+/// The environment variables of the build command, the preset of
+/// `[build.environment]` (if any) is overridden by the ones of the arguments
+#[derive(Debug, Default, PartialEq)]
+pub struct BuildEnv {
+    pub vars: Vec<String>,
+    /// The HOME of the preset, it's created before the build command runs
+    pub home: Option<String>,
+    /// The FAKETIME of libfaketime, it's preloaded into the build command if set
+    pub faketime: Option<String>,
+    /// The current directory relative to the project directory that's mounted to /build
+    pub subdir: PathBuf,
+}
+
+impl BuildEnv {
+    pub fn new(
+        environment: Option<&EnvironmentManifest>,
+        faketime: bool,
+        build: &args::Build,
+    ) -> Result<Self> {
+        let faketime = if faketime {
+            let epoch = source_date_epoch(build)?
+                .context("Running the build with faketime requires --env SOURCE_DATE_EPOCH")?;
+            Some(faketime_spec(&epoch)?)
+        } else {
+            None
+        };
+        let Some(environment) = environment else {
+            return Ok(BuildEnv {
+                vars: build.env.clone(),
+                home: None,
+                faketime,
+                subdir: PathBuf::new(),
+            });
+        };
+        let mut keys = build
+            .env
+            .iter()
+            .map(|env| env.split_once('=').map(|(key, _)| key).unwrap_or(env))
+            .chain(build.secret_env.iter().map(String::as_str))
+            .map(String::from)
+            .collect::<HashSet<_>>();
+        keys.extend(build.env_file_vars()?.into_iter().map(|(key, _)| key));
+
+        let mut vars = Vec::new();
+        let mut home = None;
+        for (key, value) in environment.vars() {
+            if keys.contains(key) {
+                continue;
+            }
+            if key == "HOME" {
+                home = Some(value.to_string());
+            }
+            vars.push(format!("{key}={value}"));
+        }
+        vars.extend(build.env.iter().cloned());
+        Ok(BuildEnv {
+            vars,
+            home,
+            faketime,
+            subdir: PathBuf::new(),
+        })
+    }
+}
+
+/// The value of SOURCE_DATE_EPOCH, as passed with --env or --env-file
+fn source_date_epoch(build: &args::Build) -> Result<Option<String>> {
+    for env in &build.env {
+        match env.split_once('=') {
+            Some(("SOURCE_DATE_EPOCH", value)) => return Ok(Some(value.to_string())),
+            None if env == "SOURCE_DATE_EPOCH" => return Ok(env::var(env).ok()),
+            _ => (),
+        }
+    }
+    let value = build
+        .env_file_vars()?
+        .into_iter()
+        .find(|(key, _)| key == "SOURCE_DATE_EPOCH")
+        .map(|(_, value)| value);
+    Ok(value)
+}
+
+/// The FAKETIME of SOURCE_DATE_EPOCH, the clock starts at this time and keeps ticking from there
+pub fn faketime_spec(epoch: &str) -> Result<String> {
+    let timestamp = epoch
+        .parse::<i64>()
+        .with_context(|| anyhow!("Invalid SOURCE_DATE_EPOCH: {epoch:?}"))?;
+    let time = OffsetDateTime::from_unix_timestamp(timestamp)
+        .with_context(|| anyhow!("SOURCE_DATE_EPOCH is out of range: {epoch:?}"))?;
+    Ok(format!(
+        "@{}-{:02}-{:02} {:02}:{:02}:{:02}",
+        time.year(),
+        u8::from(time.month()),
+        time.day(),
+        time.hour(),
+        time.minute(),
+        time.second()
+    ))
+}
+
+/// Find the libfaketime that has been installed into the container, the directory depends on the system
+async fn find_faketime(container: &Container) -> Result<String> {
+    let script = "for lib in /usr/lib/faketime/libfaketime.so.1 /usr/lib/*/faketime/libfaketime.so.1; do [ -e \"$lib\" ] && echo \"$lib\" && exit; done";
+    let out = container
+        .exec(
+            &["sh", "-c", script],
+            container::Exec {
+                capture_stdout: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .context("Failed to search for libfaketime in container")?;
+    let out = String::from_utf8(out)?;
+    let Some(lib) = out.lines().next() else {
+        bail!("libfaketime is not installed in the container, lock it with `faketime = true` in [packages]");
+    };
+    Ok(lib.to_string())
 }
 
 pub async fn run_build(
     container: &Container,
     build: &args::Build,
-    extra: Option<&(TempDir, Install)>,
+    steps: Option<&Steps<'_>>,
+    extra: Option<&(StagingDir, Install)>,
+    env: &BuildEnv,
+    env_file: Option<&Path>,
 ) -> Result<()> {
     if let Some((_, install)) = extra {
-        if !install.alpine.is_empty() {
-            let mut cmd = vec![
-                "apk".to_string(),
-                "add".to_string(),
-                "--no-network".to_string(),
-                "--".to_string(),
-            ];
-            for (_, filename) in &install.alpine {
-                cmd.push(format!("/extra/{filename}"));
+        install::run(container, install, build.allow_untrusted).await?;
+    }
+    exec_build(container, build, steps, env, env_file).await
+}
+
+/// Execute the build command in a container that has all dependencies
+/// installed, with --chown the build directory is handed over afterwards,
+/// even if the build failed
+async fn exec_build(
+    container: &Container,
+    build: &args::Build,
+    steps: Option<&Steps<'_>>,
+    env: &BuildEnv,
+    env_file: Option<&Path>,
+) -> Result<()> {
+    let owner = build.chown.map(Chown::owner).transpose()?;
+    if let Some(home) = &env.home {
+        debug!("Creating home directory in container: {home:?}");
+        container
+            .exec(&["mkdir", "-p", "--", home], container::Exec::default())
+            .await
+            .with_context(|| anyhow!("Failed to create home directory {home:?} in container"))?;
+    }
+    let mut vars = env.vars.clone();
+    if let Some(faketime) = &env.faketime {
+        let lib = find_faketime(container).await?;
+        info!("Running build with libfaketime, starting at {faketime:?}");
+        vars.extend([
+            format!("LD_PRELOAD={lib}"),
+            format!("FAKETIME={faketime}"),
+            // timeouts and benchmarks of build tools rely on the monotonic clock
+            "DONT_FAKE_MONOTONIC=1".to_string(),
+        ]);
+    }
+    let workdir = build.workdir(&env.subdir);
+    let result = exec_command(container, build, steps, &workdir, &vars, env_file).await;
+    if let Some((uid, gid)) = owner {
+        let dir = build.output_dir();
+        info!("Changing owner of {dir} to {uid}:{gid}...");
+        let cmd = ["chown", "-R", "-h", &format!("{uid}:{gid}"), "--", dir];
+        if let Err(err) = container.exec(&cmd, container::Exec::default()).await {
+            if result.is_ok() {
+                return Err(err.context("Failed to change owner of build directory"));
             }
+            warn!("Failed to change owner of build directory: {err:#}");
+        }
+    }
+    result
+}
+
+async fn exec_command(
+    container: &Container,
+    build: &args::Build,
+    steps: Option<&Steps<'_>>,
+    workdir: &str,
+    env: &[String],
+    env_file: Option<&Path>,
+) -> Result<()> {
+    let _phase = summary::phase("build");
+    if let Some(steps) = steps {
+        let parallel = build.jobs.unwrap_or(1);
+        return steps::run(container, steps, parallel, workdir, env, env_file).await;
+    }
+    if let Some(jobs) = build.load_jobs()? {
+        let parallel = build.jobs.unwrap_or(jobs.len());
+        let results = jobs::run(container, &jobs, parallel, workdir, env, env_file).await?;
+        eprint!("{}", jobs::format_summary(&results));
+        return jobs::check(&results);
+    }
+
+    info!("Running build...");
+    let capture = build.capture_paths();
+    container
+        .exec(
+            build.command()?,
+            container::Exec {
+                cwd: Some(workdir),
+                env,
+                env_file,
+                tee_stdout: capture.as_ref().map(|(stdout, _)| stdout.as_path()),
+                tee_stderr: capture.as_ref().map(|(_, stderr)| stderr.as_path()),
+                ..Default::default()
+            },
+        )
+        .await?;
+    if let Some((stdout, stderr)) = &capture {
+        info!("Captured output of build command in {stdout:?} and {stderr:?}");
+    }
+
+    Ok(())
+}
+
+/// Render variables in the format of `podman --env-file`
+fn format_env_file(vars: &[(String, String)]) -> Result<String> {
+    let mut out = String::new();
+    for (key, value) in vars {
+        if value.contains(['\n', '\r']) {
+            bail!("Value of environment variable {key:?} must not contain newlines");
+        }
+        out.push_str(&format!("{key}={value}\n"));
+    }
+    Ok(out)
+}
+
+/// Write variables into a private file so their values are not part of the podman arguments
+fn write_env_file(vars: &[(String, String)]) -> Result<NamedTempFile> {
+    let mut file = tempfile::Builder::new()
+        .prefix("repro-env-")
+        .suffix(".env")
+        .tempfile()
+        .context("Failed to create env file")?;
+    file.write_all(format_env_file(vars)?.as_bytes())
+        .context("Failed to write env file")?;
+    file.flush()?;
+    Ok(file)
+}
 
-            info!("Installing dependencies...");
-            container.exec(&cmd, container::Exec::default()).await?;
+/// Only keep the packages of --only and drop the ones of --skip, a warning is
+/// printed for each dependency that's not going to be installed anymore. The
+/// remaining packages are installed with the package manager's flag to ignore
+/// dependencies (e.g. `dpkg --force-depends`), see [`install::Options::ignore_depends`].
+pub fn select_packages(
+    dependencies: Vec<PackageLock>,
+    only: &[String],
+    skip: &[String],
+) -> Result<Vec<PackageLock>> {
+    if only.is_empty() && skip.is_empty() {
+        return Ok(dependencies);
+    }
+    for name in only.iter().chain(skip) {
+        if !dependencies.iter().any(|pkg| pkg.name == *name) {
+            bail!("Package {name:?} is not in the lockfile");
         }
+    }
 
-        if !install.archlinux.is_empty() {
-            // determine verification timestamp and add it to gpg.conf
-            let filename_iter = install.archlinux.iter().map(|(pkg, _)| pkg);
-            if let Some(time) = pgp::find_max_signature_time(filename_iter)? {
-                let time = time
-                    .checked_add(Duration::from_secs(1))
-                    .with_context(|| anyhow!("Failed to increase time by 1 second {time:?}"))?;
-                let datetime = OffsetDateTime::from(time).format(&well_known::Rfc3339)?;
+    let total = dependencies.len();
+    let (selected, left_out) = dependencies.into_iter().partition::<Vec<_>, _>(|pkg| {
+        (only.is_empty() || only.contains(&pkg.name)) && !skip.contains(&pkg.name)
+    });
+    info!(
+        "Installing {} of {total} locked packages, skipping: {:?}",
+        selected.len(),
+        left_out.iter().map(|pkg| &pkg.name).collect::<Vec<_>>()
+    );
 
-                info!("Derived signature verification timestamp: {datetime:?}");
-                archlinux::set_pacman_verification_datetime(container, time).await?;
+    for (pkg, dep) in skipped_dependencies(&selected, &left_out) {
+        warn!("Package {pkg:?} depends on {dep:?}, which is not going to be installed, installing it without its dependencies");
+    }
+    Ok(selected)
+}
+
+/// The (package, dependency) pairs of `selected` that are only satisfied by a package of `left_out`
+fn skipped_dependencies<'a>(
+    selected: &'a [PackageLock],
+    left_out: &[PackageLock],
+) -> Vec<(&'a str, &'a str)> {
+    let provided_names = |pkgs: &[PackageLock]| {
+        pkgs.iter()
+            .flat_map(|pkg| {
+                pkgs::dependency_names(&pkg.system, &pkg.name)
+                    .into_iter()
+                    .chain(
+                        pkg.provides
+                            .iter()
+                            .flat_map(|provide| pkgs::dependency_names(&pkg.system, provide)),
+                    )
+                    .map(String::from)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<HashSet<_>>()
+    };
+    let installed = provided_names(selected);
+    let skipped = provided_names(left_out);
+    let mut missing = Vec::new();
+    for pkg in selected {
+        for dep in &pkg.depends {
+            let names = pkgs::dependency_names(&pkg.system, dep);
+            // anything that's not in the lockfile is provided by the container image
+            if !names.iter().any(|name| installed.contains(*name))
+                && names.iter().any(|name| skipped.contains(*name))
+            {
+                missing.push((pkg.name.as_str(), dep.as_str()));
             }
+        }
+    }
+    missing
+}
 
-            // prepare and execute the install command
-            let mut cmd = vec![
-                "pacman".to_string(),
-                "-U".to_string(),
-                "--noconfirm".to_string(),
-                "--".to_string(),
-            ];
-            for (_, filename) in &install.archlinux {
-                cmd.push(format!("/extra/{filename}"));
+/// If two packages that both install `file` don't conflict. Debian packages
+/// may overwrite the files of packages they `Replaces:` and `Multi-Arch: same`
+/// packages share their files with the same package of other architectures.
+fn allows_shared_file(
+    (pkg, list): (&PackageLock, &pkgs::FileList),
+    (other, other_list): (&PackageLock, &pkgs::FileList),
+) -> bool {
+    let replaces = |list: &pkgs::FileList, name: &str| {
+        list.replaces
+            .iter()
+            .any(|dep| pkgs::dependency_names(&pkg.system, dep).contains(&name))
+    };
+    (pkg.name == other.name && list.multi_arch_same && other_list.multi_arch_same)
+        || replaces(list, &other.name)
+        || replaces(other_list, &pkg.name)
+}
+
+/// Find files that are installed by more than one package
+fn find_file_conflicts(packages: &[(PackageLock, pkgs::FileList)]) -> Vec<(&str, &str, &str)> {
+    let mut owners = HashMap::<&str, Vec<(&PackageLock, &pkgs::FileList)>>::new();
+    let mut conflicts = Vec::new();
+    for (pkg, list) in packages {
+        for file in &list.files {
+            let others = owners.entry(file).or_default();
+            for other in others.iter() {
+                if !allows_shared_file((pkg, list), *other) {
+                    conflicts.push((file.as_str(), other.0.name.as_str(), pkg.name.as_str()));
+                }
             }
+            others.push((pkg, list));
+        }
+    }
+    conflicts
+}
+
+/// Read the file lists of the downloaded packages and make sure no two packages
+/// install the same file. Files that are moved aside with dpkg-divert can't be
+/// detected from the package files, so conflicts of debian packages are only
+/// warned about and left for dpkg to report.
+pub async fn check_file_conflicts(
+    dependencies: &[PackageLock],
+    download: &args::DownloadArgs,
+) -> Result<()> {
+    let packages = dependencies
+        .iter()
+        .filter(|p| p.has_embedded_metadata())
+        .cloned()
+        .collect::<Vec<_>>();
+    if packages.is_empty() {
+        return Ok(());
+    }
+    {
+        let _phase = summary::phase("download");
+        fetch::download_dependencies(&packages, download).await?;
+    }
+
+    info!("Checking package file lists for conflicts...");
+    let pkgs_cache_dir = paths::pkgs_cache_dir()?;
+    let packages = utils::spawn_blocking(move |_cancel| {
+        packages
+            .into_iter()
+            .map(|pkg| {
+                let path = pkgs_cache_dir.sha256_path(&pkg.sha256)?;
+                let file = std::fs::File::open(&path)
+                    .with_context(|| anyhow!("Failed to open package from cache: {path:?}"))?;
+                let list = sandbox::list_files(&pkg.system, file)
+                    .with_context(|| anyhow!("Failed to list files of package: {:?}", pkg.name))?;
+                Ok((pkg, list))
+            })
+            .collect::<Result<Vec<_>>>()
+    })
+    .await?;
 
-            info!("Installing dependencies...");
-            container.exec(&cmd, container::Exec::default()).await?;
+    let conflicts = find_file_conflicts(&packages);
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    if packages.iter().all(|(pkg, _)| pkg.system == "debian") {
+        for (file, a, b) in &conflicts {
+            warn!("File {file:?} is installed by both {a:?} and {b:?}, unless it's diverted dpkg is going to fail");
         }
+        return Ok(());
+    }
+    for (file, a, b) in &conflicts {
+        error!("File conflict: {file:?} is installed by both {a:?} and {b:?}");
+    }
+    bail!(
+        "Found {} conflicting files in the packages that are going to be installed",
+        conflicts.len()
+    );
+}
 
-        if !install.debian.is_empty() {
-            let mut cmd = vec![
-                "apt-get".to_string(),
-                "install".to_string(),
-                "--".to_string(),
-            ];
-            for (_, filename) in &install.debian {
-                cmd.push(format!("/extra/{filename}"));
-            }
+/// Packages are parsed on the host in a sandbox that needs user namespaces.
+/// Without packages they're only needed by rootless podman, so a failed check
+/// is left for podman to report.
+pub async fn check_userns(dependencies: &[PackageLock]) -> Result<()> {
+    let result = container::test_for_unprivileged_userns_clone().await;
+    if dependencies.is_empty() {
+        if let Err(err) = result {
+            warn!("Creating the container is likely going to fail with rootless podman: {err:#}");
+        }
+        return Ok(());
+    }
+    result
+}
+
+/// The staging directory is named after the packages it contains
+fn staging_name(dependencies: &[PackageLock]) -> String {
+    let mut sha256s = dependencies
+        .iter()
+        .map(|p| p.sha256.as_str())
+        .collect::<Vec<_>>();
+    sha256s.sort();
+    let mut hash = hash::sha256(sha256s.join("\n").as_bytes());
+    hash.truncate(16);
+    hash
+}
+
+/// Download the dependencies and set up the directory that is mounted to /extra
+pub async fn stage_dependencies(
+    dependencies: Vec<PackageLock>,
+    keys: Vec<KeyLock>,
+    apk_keys: Vec<ApkKeyLock>,
+    install_order: Vec<String>,
+    staging_dir: &Path,
+    mounts: &mut Vec<Mount>,
+    download: &args::DownloadArgs,
+) -> Result<Option<(StagingDir, Install)>> {
+    if dependencies.is_empty() {
+        debug!("Lockfile doesn't pin any packages, nothing to stage");
+        return Ok(None);
+    }
 
-            info!("Installing dependencies...");
-            container.exec(&cmd, container::Exec::default()).await?;
+    {
+        let _phase = summary::phase("download");
+        fetch::download_dependencies(&dependencies, download).await?;
+    }
+
+    // remove staging directories of builds that got killed
+    if let Err(err) = staging::cleanup_stale(staging_dir) {
+        warn!("Failed to clean up stale staging directories: {err:#}");
+    }
+
+    let temp_dir = StagingDir::create(staging_dir, &staging_name(&dependencies))?;
+    check_staging_space(temp_dir.path(), &dependencies)?;
+    let mut pkgs = setup_extra_folder(temp_dir.path(), dependencies, keys, apk_keys).await?;
+    pkgs.install_order = install_order;
+    staging::normalize(temp_dir.path())?;
+
+    mounts.push(Mount::new(temp_dir.path(), "/extra"));
+
+    Ok(Some((temp_dir, pkgs)))
+}
+
+/// Download and verify the `[[file]]` pins of the lockfile, they are mounted
+/// read-only from the cache into /extra/files/
+pub async fn stage_files(files: Vec<FileLock>, mounts: &mut Vec<Mount>) -> Result<()> {
+    if files.is_empty() {
+        return Ok(());
+    }
+    for file in &files {
+        if !manifest::valid_file_name(&file.name) {
+            bail!("Invalid filename for file in lockfile: {:?}", file.name);
         }
     }
 
-    info!("Running build...");
-    container
-        .exec(
-            &build.cmd,
-            container::Exec {
-                cwd: Some("/build"),
-                env: &build.env,
-                ..Default::default()
-            },
-        )
-        .await?;
+    {
+        let _phase = summary::phase("download");
+        fetch::download_files(&files).await?;
+    }
+    let files = {
+        let _phase = summary::phase("verify");
+        utils::spawn_blocking(move |_| {
+            fetch::verify_files(&files)?;
+            Ok(files)
+        })
+        .await?
+    };
 
+    let pkgs_cache_dir = paths::pkgs_cache_dir()?;
+    for file in files {
+        let source = pkgs_cache_dir.sha256_path(&file.sha256)?;
+        mounts.push(Mount::read_only(
+            source,
+            format!("/extra/files/{}", file.name),
+        ));
+    }
     Ok(())
 }
 
-pub async fn build(build: &args::Build) -> Result<()> {
-    container::test_for_unprivileged_userns_clone().await?;
+/// Check out the `[[git]]` pins of the lockfile, they are mounted read-only
+/// from the cache into /extra/git/
+pub async fn stage_git(repos: Vec<GitLock>, mounts: &mut Vec<Mount>) -> Result<()> {
+    for repo in repos {
+        if !manifest::valid_file_name(&repo.name) {
+            bail!(
+                "Invalid name for git repository in lockfile: {:?}",
+                repo.name
+            );
+        }
+        let path = git::checkout(&repo)
+            .await
+            .with_context(|| anyhow!("Failed to check out git repository {:?}", repo.name))?;
+        mounts.push(Mount::read_only(path, format!("/extra/git/{}", repo.name)));
+    }
+    Ok(())
+}
+
+/// Extract the `[[toolchain]]` pins of the lockfile into the cache, they are
+/// mounted read-only at their path
+pub async fn stage_toolchains(
+    toolchains: Vec<ToolchainLock>,
+    mounts: &mut Vec<Mount>,
+) -> Result<()> {
+    if toolchains.is_empty() {
+        return Ok(());
+    }
+    let files = toolchains
+        .iter()
+        .map(ToolchainLock::file)
+        .collect::<Vec<_>>();
+    {
+        let _phase = summary::phase("download");
+        fetch::download_files(&files).await?;
+    }
+    {
+        let _phase = summary::phase("verify");
+        utils::spawn_blocking(move |_| fetch::verify_files(&files)).await?;
+    }
+
+    for toolchain in toolchains {
+        if !manifest::valid_toolchain_path(&toolchain.path) {
+            bail!(
+                "Invalid path for toolchain in lockfile: {:?}",
+                toolchain.path
+            );
+        }
+        let path = toolchain::extract(&toolchain).await?;
+        mounts.push(Mount::read_only(path, toolchain.path));
+    }
+    Ok(())
+}
+
+/// With --offline, everything needs to be in the cache already instead of being downloaded
+fn check_offline(
+    dependencies: &[PackageLock],
+    files: &[FileLock],
+    repos: &[GitLock],
+) -> Result<()> {
+    let pkgs_cache_dir = paths::pkgs_cache_dir()?;
+    let mut missing = Vec::new();
+    for package in dependencies.iter().filter(|p| p.has_package_file()) {
+        if !pkgs_cache_dir.sha256_path(&package.sha256)?.exists() {
+            missing.push(format!("package {:?} {:?}", package.name, package.version));
+        }
+    }
+    for file in files {
+        if !pkgs_cache_dir.sha256_path(&file.sha256)?.exists() {
+            missing.push(format!("file {:?}", file.name));
+        }
+    }
+    for repo in repos {
+        if !git::is_checked_out(repo)? {
+            missing.push(format!(
+                "git repository {:?} at {:?}",
+                repo.name, repo.commit
+            ));
+        }
+    }
+    if !missing.is_empty() {
+        bail!(
+            "Missing from cache, run `repro-env fetch` first (--offline):\n  {}",
+            missing.join("\n  ")
+        );
+    }
+    Ok(())
+}
+
+/// Fail with a precise error if the image can't run on this machine (or the
+/// packages of architecture `arch` can't run in the image), instead of an exec
+/// format error halfway through the build
+pub async fn check_platform(
+    image: &str,
+    requested: Option<&Platform>,
+    arch: Option<&str>,
+) -> Result<()> {
+    let mut inspect = container::inspect(image).await.ok();
+    let mismatch = |inspect: &Image| {
+        requested.is_some_and(|requested| {
+            inspect
+                .platform()
+                .is_none_or(|platform| !requested.matches(&platform))
+        })
+    };
+    if inspect.as_ref().is_none_or(mismatch) {
+        container::pull_platform(image, requested).await?;
+        inspect = Some(container::inspect(image).await?);
+    }
 
+    let Some(platform) = inspect.and_then(|inspect| inspect.platform()) else {
+        debug!("Podman did not report a platform for image {image:?}, skipping check");
+        return Ok(());
+    };
+    debug!("Platform of image {image:?}: {platform}");
+    if let Some(arch) = arch {
+        consistency::check_arch(&platform, arch)?;
+    }
+    platform::check(
+        &platform,
+        requested,
+        &platform::host(),
+        &platform::binfmt_handlers(),
+    )
+}
+
+/// Warn if the lockfile doesn't satisfy the manifest, or fail with --frozen
+pub fn check_manifest(
+    manifest: Option<&Manifest>,
+    lockfile: &Lockfile,
+    frozen: bool,
+) -> Result<()> {
+    if let Some(manifest) = manifest {
+        if let Err(err) = manifest.satisfied_by(lockfile) {
+            if frozen {
+                return Err(err.context("Lockfile is out-of-sync with manifest (--frozen)"));
+            }
+            warn!("Lockfile might be out-of-sync: {err:#}");
+        }
+    }
+    Ok(())
+}
+
+fn source_mounts(build: &args::Build, pwd: &Path, src: &Path) -> Vec<Mount> {
+    let mut mounts = if build.src_ro {
+        vec![
+            Mount::read_only(src, "/build/src"),
+            Mount::new(pwd.join(&build.out_dir), "/build/out"),
+        ]
+    } else {
+        vec![Mount::new(src, "/build")]
+    };
+    if let Some(path) = &build.cmd_file {
+        mounts.push(Mount::read_only(pwd.join(path), CMD_FILE));
+    }
+    mounts
+}
+
+/// The directory that's mounted to /build and the current directory relative
+/// to it. If the lockfile was found in a parent directory, that's the project
+/// directory and the build runs in the subdirectory it was started in
+pub fn source_root(file: Option<&Path>, pwd: &Path, project: &Path) -> (PathBuf, PathBuf) {
+    if file.is_none() {
+        if let Ok(subdir) = pwd.strip_prefix(project) {
+            if !subdir.as_os_str().is_empty() {
+                info!("Mounting project directory {project:?} to /build, running in {subdir:?}");
+            }
+            return (project.to_path_buf(), subdir.to_path_buf());
+        }
+    }
+    (pwd.to_path_buf(), PathBuf::new())
+}
+
+/// Sign the artifacts declared in `[build.sign]`, after the build succeeded
+async fn sign_artifacts(
+    manifest: Option<&Manifest>,
+    build: &args::Build,
+    pwd: &Path,
+) -> Result<()> {
+    let Some(config) = manifest.and_then(|m| m.build.sign.as_ref()) else {
+        return Ok(());
+    };
+    if build.no_sign {
+        info!("Skipping signing of build artifacts");
+        return Ok(());
+    }
+    sign::sign_artifacts(config, pwd).await
+}
+
+pub async fn build(build: &args::Build) -> Result<()> {
     // ensure arguments make sense
     build.validate()?;
 
+    let _cache_lock = utils::lock_shared(paths::cache_lock()?).await?;
+
     // load lockfile
-    let (manifest, lockfile) = build.load_files().await?;
-    if let Some(manifest) = &manifest {
-        if let Err(err) = manifest.satisfied_by(&lockfile) {
-            warn!("Lockfile might be out-of-sync: {err:#}");
-        }
+    let (manifest, lockfile_path, mut lockfile) = build.load_files().await?;
+    let local = LocalConfig::load_for_lockfile(&lockfile_path)?;
+    let keep = build.keep || local.build.keep;
+    check_manifest(manifest.as_ref(), &lockfile, build.frozen)?;
+    lockfile.select_profile(build.profile.as_deref())?;
+    let arch = consistency::check_packages(&lockfile.packages)?;
+    let environment = manifest.as_ref().and_then(|m| m.build.environment.as_ref());
+    let faketime = build.faketime
+        || manifest
+            .as_ref()
+            .and_then(|m| m.packages.as_ref())
+            .is_some_and(|p| p.faketime);
+    if faketime && !lockfile.packages.iter().any(|p| p.name == FAKETIME_PACKAGE) {
+        bail!("libfaketime is not in the lockfile, set `faketime = true` in [packages] and run `repro-env update`");
+    }
+    let mut env = BuildEnv::new(environment, faketime, build)?;
+    let mut podman_args = manifest
+        .iter()
+        .flat_map(|m| &m.build.podman_args)
+        .chain(&local.build.podman_args)
+        .chain(&build.podman_arg)
+        .cloned()
+        .collect::<Vec<_>>();
+    podman_args.extend(environment.map(|e| format!("--umask={}", e.umask())));
+    if build.check_image {
+        freshness::check(&lockfile).await;
     }
 
-    // mount current directory into container
+    let lockfile_digest = lockfile.digest();
+    let all_files = lockfile.all_files();
+    if let Err(err) = refs::record(&lockfile_path, &lockfile.packages, &all_files).await {
+        warn!("Failed to record package references of lockfile: {err:#}");
+    }
+    let project = state::project_dir(&lockfile_path)?;
+
+    // mount the project directory into container
     let pwd = env::current_dir()?;
-    let pwd = pwd
-        .into_os_string()
-        .into_string()
-        .map_err(|_| anyhow!("Failed to convert current path to utf-8"))?;
+    let (root, subdir) = source_root(build.file.as_deref(), &pwd, &project);
+    env.subdir = subdir;
+    let started = state::now();
+    let cmd = build.command()?;
+    let record_build = |container: Option<&str>, success: bool| {
+        let record = BuildRecord {
+            started,
+            lockfile_digest: lockfile_digest.clone(),
+            cmd: cmd.clone(),
+            success,
+            container: container.map(String::from),
+            packages: summary::package_sources(),
+        };
+        state::record(&project, |state| state.record_build(record))
+    };
 
-    let mut mounts = vec![(pwd, "/build".to_string())];
+    let build_steps = if build.steps {
+        let steps = manifest.as_ref().map(|m| &m.build.steps[..]).unwrap_or(&[]);
+        if steps.is_empty() {
+            bail!("The manifest doesn't define any [[build.steps]]");
+        }
+        Some(steps)
+    } else {
+        None
+    };
 
     // ignore packages that are already present in the container
     let dependencies = lockfile
@@ -208,36 +1157,601 @@ pub async fn build(build: &args::Build) -> Result<()> {
         .into_iter()
         .filter(|p| !p.installed)
         .collect::<Vec<_>>();
+    let locked = dependencies.len();
+    let dependencies = select_packages(dependencies, &build.only, &build.skip)?;
+    let ignore_depends = dependencies.len() < locked;
+
+    if build.list {
+        fetch::print_list(&dependencies)?;
+    }
+
+    if let Some(format) = &build.plan {
+        let mut mounts = source_mounts(build, &pwd, &root);
+        mounts.extend(local.mounts());
+        let plan = Plan::new(
+            build,
+            &lockfile.container.image,
+            &mounts,
+            &dependencies,
+            &env,
+        )?;
+        match format {
+            args::PlanFormat::Text => print!("{}", plan.format_text()),
+            args::PlanFormat::Json => {
+                let json = serde_json::to_string_pretty(&plan)?;
+                println!("{json}");
+            }
+        }
+        return Ok(());
+    }
 
-    let extra = if !dependencies.is_empty() {
-        fetch::download_dependencies(&dependencies).await?;
+    check_userns(&dependencies).await?;
 
-        let path = paths::repro_env_dir()?;
-        let temp_dir = tempfile::Builder::new().prefix("env.").tempdir_in(path)?;
-        let pkgs = setup_extra_folder(temp_dir.path(), dependencies).await?;
+    let mut private_env = build.env_file_vars()?;
+    private_env.extend(build.secrets()?);
+    let env_file = if !private_env.is_empty() {
+        Some(write_env_file(&private_env)?)
+    } else {
+        None
+    };
 
-        let path = temp_dir
-            .path()
-            .to_owned()
-            .into_os_string()
-            .into_string()
-            .map_err(|_| anyhow!("Failed to convert temporary path to utf-8"))?;
-        mounts.push((path, "/extra".to_string()));
+    // a container from `repro-env prepare` only has the default mounts (and all packages)
+    if !build.copy_context
+        && !build.src_ro
+        && build.cmd_file.is_none()
+        && !build.hardened
+        && build.only.is_empty()
+        && build.skip.is_empty()
+        && local.build.podman_args.is_empty()
+        && local.build.mounts.is_empty()
+    {
+        if let Some(id) = container::find_prepared(&lockfile_digest).await? {
+            info!("Using prepared container: {id:?}");
+            let container = Container { id };
+            let steps = build_steps
+                .map(|steps| Steps::new(steps, &root, &lockfile_digest, !build.no_step_cache))
+                .transpose()?;
+            let result = exec_build(
+                &container,
+                build,
+                steps.as_ref(),
+                &env,
+                env_file.as_ref().map(|f| f.path()),
+            )
+            .await;
+            record_build(Some(&container.id), result.is_ok()).await;
+            print_summary_on_error(result, build.json)?;
+            sign_artifacts(manifest.as_ref(), build, &root).await?;
+            return summary::print(build.json);
+        }
+    }
 
-        Some((temp_dir, pkgs))
+    let staging_dir = paths::staging_dir(build.staging_dir.as_deref())?;
+    let context = if build.copy_context {
+        let dir = StagingDir::create(&staging_dir, "context")?;
+        let ctx = CopyContext::copy_into(&root, dir.path())?;
+        Some((dir, ctx))
     } else {
         None
     };
 
-    let container = Container::create(
-        &lockfile.container.image,
-        container::Config {
-            mounts: &mounts,
-            expose_fuse: false,
-        },
+    let src = if let Some((dir, _)) = &context {
+        dir.path()
+    } else {
+        &root
+    };
+    let mut mounts = source_mounts(build, &pwd, src);
+    mounts.extend(local.mounts());
+    let steps = build_steps
+        .map(|steps| Steps::new(steps, src, &lockfile_digest, !build.no_step_cache))
+        .transpose()?;
+
+    if build.src_ro {
+        let out_dir = pwd.join(&build.out_dir);
+        fs::create_dir_all(&out_dir)
+            .await
+            .with_context(|| anyhow!("Failed to create output directory: {out_dir:?}"))?;
+    }
+
+    if build.offline {
+        check_offline(&dependencies, &all_files, &lockfile.git)?;
+    }
+    if build.check_conflicts {
+        check_file_conflicts(&dependencies, &build.download).await?;
+    }
+    let mut extra = stage_dependencies(
+        dependencies,
+        lockfile.keys,
+        lockfile.apk_keys,
+        lockfile.install_order,
+        &staging_dir,
+        &mut mounts,
+        &build.download,
     )
     .await?;
-    container
-        .run(run_build(&container, build, extra.as_ref()), build.keep)
-        .await
+    if let Some((_, install)) = &mut extra {
+        install.ignore_depends = ignore_depends;
+    }
+    stage_files(lockfile.files, &mut mounts).await?;
+    stage_git(lockfile.git, &mut mounts).await?;
+    stage_toolchains(lockfile.toolchains, &mut mounts).await?;
+    java::stage(lockfile.maven, &mut mounts).await?;
+    ruby::stage(lockfile.gems, &mut mounts).await?;
+    php::stage(lockfile.composer, &mut mounts).await?;
+    perl::stage(lockfile.cpan, &mut mounts).await?;
+    haskell::stage(lockfile.haskell, &mut mounts).await?;
+    texlive::stage(lockfile.texlive, &mut mounts).await?;
+
+    container::verify_init(&lockfile.container)?;
+    let image = if build.offline {
+        container::prepare_image_offline(&lockfile.container, &project, &paths::images_cache_dir()?)
+            .await?
+    } else {
+        container::prepare_image(&lockfile.container, &project).await?
+    };
+    check_platform(&image, build.platform.as_ref(), arch.as_deref()).await?;
+    let config = |read_only_root| container::Config {
+        mounts: &mounts,
+        expose_fuse: false,
+        lockfile_digest: Some(&lockfile_digest),
+        prepared: false,
+        platform: build.platform.as_ref(),
+        podman_args: &podman_args,
+        read_only_root,
+        init: lockfile.container.init,
+    };
+
+    // packages can't be installed on a read-only root filesystem
+    let committed = match &extra {
+        Some((_, install)) if build.hardened => {
+            Some(install_into_image(&image, config(false), install, build.allow_untrusted).await?)
+        }
+        _ => None,
+    };
+    let install = if committed.is_some() {
+        None
+    } else {
+        extra.as_ref()
+    };
+
+    // the temporary image is removed on every path, so nothing in here may return early
+    let result = async {
+        let container = Container::create(
+            committed.as_deref().unwrap_or(&image),
+            config(build.hardened),
+        )
+        .await?;
+        // remembered until it's removed, so `gc --project` can clean it up if we get killed
+        state::record(&project, |state| {
+            state.add_container(ContainerRecord {
+                id: container.id.clone(),
+                lockfile_digest: lockfile_digest.clone(),
+                created: state::now(),
+                kept: keep,
+            })
+        })
+        .await;
+        let result = container
+            .run(
+                run_build(
+                    &container,
+                    build,
+                    steps.as_ref(),
+                    install,
+                    &env,
+                    env_file.as_ref().map(|f| f.path()),
+                ),
+                keep,
+            )
+            .await;
+        state::record(&project, |state| state.remove_container(&container.id)).await;
+        record_build(Some(&container.id), result.is_ok()).await;
+        result
+    }
+    .await;
+
+    if let Some(image) = &committed {
+        remove_temporary_image(image, keep).await;
+    }
+
+    // a failed build may have left partial outputs, keep the current directory as it was
+    if let Some((dir, ctx)) = context.filter(|_| result.is_ok()) {
+        let count = ctx.copy_back(dir.path(), &root)?;
+        info!("Copied {count} files from build context back into current directory");
+    }
+
+    print_summary_on_error(result, build.json)?;
+    sign_artifacts(manifest.as_ref(), build, &root).await?;
+    summary::print(build.json)
+}
+
+/// A failed build still prints the summary, it shows which command failed
+/// and how long everything took until then
+fn print_summary_on_error(result: Result<()>, json: bool) -> Result<()> {
+    if result.is_err() {
+        if let Err(err) = summary::print(json) {
+            warn!("Failed to print summary: {err:#}");
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_runtime::{FakeRuntime, CONTAINER_ID};
+    use crate::manifest::{EnvironmentPreset, Init};
+    use clap::Parser;
+
+    #[tokio::test]
+    async fn test_install_into_image() -> Result<()> {
+        let runtime = FakeRuntime::new();
+        runtime.reply(&["container", "commit"], "3c1a0b2e\n");
+        let id = runtime
+            .run(install_into_image(
+                "alpine@sha256:00",
+                container::Config {
+                    mounts: &[],
+                    expose_fuse: false,
+                    lockfile_digest: None,
+                    prepared: false,
+                    platform: None,
+                    podman_args: &[],
+                    read_only_root: false,
+                    init: Init::Catatonit,
+                },
+                &Install::default(),
+                false,
+            ))
+            .await?;
+        assert_eq!(id, "3c1a0b2e");
+
+        let commands = runtime.commands();
+        let commit = commands
+            .iter()
+            .position(|cmd| *cmd == format!("container commit --quiet -- {CONTAINER_ID}"));
+        let kill = commands
+            .iter()
+            .position(|cmd| *cmd == format!("container kill {CONTAINER_ID}"));
+        assert!(commit.is_some());
+        assert!(commit < kill);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remove_temporary_image() {
+        let runtime = FakeRuntime::new();
+        runtime.run(remove_temporary_image("3c1a0b2e", true)).await;
+        assert!(runtime.commands().is_empty());
+
+        runtime.run(remove_temporary_image("3c1a0b2e", false)).await;
+        assert_eq!(runtime.commands(), ["image rm -- 3c1a0b2e"]);
+    }
+
+    #[test]
+    fn test_build_env() -> Result<()> {
+        let args = args::Args::try_parse_from([
+            "repro-env",
+            "build",
+            "--env",
+            "TZ=Europe/Berlin",
+            "--env",
+            "CARGO_HOME=/build/.cargo",
+            "make",
+        ])?;
+        let Some(args::SubCommand::Build(build)) = args.subcommand else {
+            bail!("Expected build subcommand");
+        };
+        let environment = EnvironmentManifest {
+            preset: EnvironmentPreset::Deterministic,
+            home: None,
+            locale: Some("en_US.UTF-8".to_string()),
+            tz: None,
+            umask: None,
+            path: Some("/usr/bin:/bin".to_string()),
+        };
+
+        assert_eq!(
+            BuildEnv::new(None, false, &build)?,
+            BuildEnv {
+                vars: vec![
+                    "TZ=Europe/Berlin".to_string(),
+                    "CARGO_HOME=/build/.cargo".to_string()
+                ],
+                home: None,
+                faketime: None,
+                subdir: PathBuf::new(),
+            }
+        );
+        assert_eq!(
+            BuildEnv::new(Some(&environment), false, &build)?,
+            BuildEnv {
+                vars: vec![
+                    "HOME=/build/.home".to_string(),
+                    "LANG=en_US.UTF-8".to_string(),
+                    "LC_ALL=en_US.UTF-8".to_string(),
+                    "PATH=/usr/bin:/bin".to_string(),
+                    "TZ=Europe/Berlin".to_string(),
+                    "CARGO_HOME=/build/.cargo".to_string()
+                ],
+                home: Some("/build/.home".to_string()),
+                faketime: None,
+                subdir: PathBuf::new(),
+            }
+        );
+        assert!(BuildEnv::new(None, true, &build).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_source_root() -> Result<()> {
+        let project = Path::new("/home/user/project");
+        assert_eq!(
+            source_root(None, Path::new("/home/user/project/crates/foo"), project),
+            (project.to_path_buf(), PathBuf::from("crates/foo"))
+        );
+        assert_eq!(
+            source_root(None, project, project),
+            (project.to_path_buf(), PathBuf::new())
+        );
+        // an explicit lockfile doesn't change what's mounted
+        assert_eq!(
+            source_root(
+                Some(Path::new("../../repro-env.lock")),
+                Path::new("/home/user/project/crates/foo"),
+                project
+            ),
+            (
+                PathBuf::from("/home/user/project/crates/foo"),
+                PathBuf::new()
+            )
+        );
+
+        let args = args::Args::try_parse_from(["repro-env", "build", "--src-ro", "make"])?;
+        let Some(args::SubCommand::Build(build)) = args.subcommand else {
+            bail!("Expected build subcommand");
+        };
+        assert_eq!(
+            build.workdir(Path::new("crates/foo")),
+            "/build/src/crates/foo"
+        );
+        assert_eq!(build.workdir(Path::new("")), "/build/src");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_build_faketime() -> Result<()> {
+        assert_eq!(faketime_spec("0")?, "@1970-01-01 00:00:00");
+        assert_eq!(faketime_spec("1700000000")?, "@2023-11-14 22:13:20");
+        assert!(faketime_spec("yesterday").is_err());
+
+        let args = args::Args::try_parse_from([
+            "repro-env",
+            "build",
+            "--faketime",
+            "--env",
+            "SOURCE_DATE_EPOCH=1700000000",
+            "make",
+        ])?;
+        let Some(args::SubCommand::Build(build)) = args.subcommand else {
+            bail!("Expected build subcommand");
+        };
+        let env = BuildEnv::new(None, build.faketime, &build)?;
+        assert_eq!(env.faketime.as_deref(), Some("@2023-11-14 22:13:20"));
+
+        let runtime = FakeRuntime::new();
+        runtime.reply(
+            &["sh", "-c"],
+            "/usr/lib/x86_64-linux-gnu/faketime/libfaketime.so.1\n",
+        );
+        let container = Container {
+            id: CONTAINER_ID.to_string(),
+        };
+        runtime
+            .run(exec_build(&container, &build, None, &env, None))
+            .await?;
+        let execs = runtime.execs();
+        assert_eq!(execs.last().map(String::as_str), Some("make"));
+        let commands = runtime.commands();
+        let cmd = commands.last().context("No command was executed")?;
+        assert!(cmd.contains(
+            "-e LD_PRELOAD=/usr/lib/x86_64-linux-gnu/faketime/libfaketime.so.1 -e FAKETIME=@2023-11-14 22:13:20 -e DONT_FAKE_MONOTONIC=1"
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_build_image_only() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let staging_dir = dir.path().join("staging");
+        let mut mounts = vec![Mount::new(dir.path(), "/build")];
+        let extra = stage_dependencies(
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            &staging_dir,
+            &mut mounts,
+            &args::DownloadArgs::default(),
+        )
+        .await?;
+        assert!(extra.is_none());
+        assert_eq!(mounts.len(), 1);
+        assert!(!staging_dir.exists());
+
+        let args = args::Args::try_parse_from(["repro-env", "build", "make"])?;
+        let Some(args::SubCommand::Build(build)) = args.subcommand else {
+            bail!("Expected build subcommand");
+        };
+        let env = BuildEnv::new(None, false, &build)?;
+        let runtime = FakeRuntime::new();
+        let container = Container {
+            id: CONTAINER_ID.to_string(),
+        };
+        runtime
+            .run(run_build(&container, &build, None, None, &env, None))
+            .await?;
+        assert_eq!(runtime.execs(), ["make"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chown() -> Result<()> {
+        assert_eq!("auto".parse::<Chown>()?, Chown::Auto);
+        assert_eq!(
+            "1000:100".parse::<Chown>()?,
+            Chown::Owner {
+                uid: 1000,
+                gid: 100
+            }
+        );
+        assert!("1000".parse::<Chown>().is_err());
+        assert!("user:group".parse::<Chown>().is_err());
+
+        assert_eq!(Chown::Auto.resolve(1000, None)?, (0, 0));
+        assert_eq!(Chown::Auto.resolve(0, Some(("1000", "100")))?, (1000, 100));
+        assert!(Chown::Auto.resolve(0, None).is_err());
+        assert_eq!(
+            Chown::Owner { uid: 33, gid: 33 }.resolve(0, Some(("1000", "100")))?,
+            (33, 33)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_filename_from_url() -> Result<()> {
+        let filename = filename_from_url(
+            "https://archive.archlinux.org/packages/r/rust/rust-1%3A1.69.0-3-x86_64.pkg.tar.zst",
+        )?;
+        assert_eq!(filename, "rust-1:1.69.0-3-x86_64.pkg.tar.zst");
+        let filename = filename_from_url(
+            "https://archive.archlinux.org/packages/r/rust/rust-1:1.69.0-3-x86_64.pkg.tar.zst",
+        )?;
+        assert_eq!(filename, "rust-1:1.69.0-3-x86_64.pkg.tar.zst");
+        let filename = filename_from_url(
+            "https://deb.debian.org/debian/pool/main/b/binutils/binutils_2.40-2%2Bdeb12u1_amd64.deb",
+        )?;
+        assert_eq!(filename, "binutils_2.40-2+deb12u1_amd64.deb");
+        assert!(filename_from_url("https://example.com/packages/..%2Fetc%2Fpasswd").is_err());
+        assert!(filename_from_url("https://example.com/packages/%2E%2E").is_err());
+        assert!(filename_from_url("https://example.com/packages/a%0Ab.deb").is_err());
+        assert!(filename_from_url("https://archive.archlinux.org/packages/").is_err());
+        assert!(filename_from_url("not a url").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_file_conflicts() {
+        let pkg = |name: &str, files: &[&str]| {
+            let pkg = PackageLock {
+                name: name.to_string(),
+                version: "1.0-1".to_string(),
+                system: "debian".to_string(),
+                url: format!("https://example.com/{name}.deb"),
+                sha256: "00".to_string(),
+                ..Default::default()
+            };
+            let list = pkgs::FileList {
+                files: files.iter().map(|f| f.to_string()).collect(),
+                ..Default::default()
+            };
+            (pkg, list)
+        };
+
+        assert!(
+            find_file_conflicts(&[pkg("a", &["/usr/bin/a"]), pkg("b", &["/usr/bin/b"])]).is_empty()
+        );
+        assert!(find_file_conflicts(&[pkg("a", &[]), pkg("b", &[])]).is_empty());
+        assert_eq!(
+            find_file_conflicts(&[
+                pkg("a", &["/usr/bin/a", "/usr/bin/cc"]),
+                pkg("b", &["/usr/bin/cc"]),
+            ]),
+            [("/usr/bin/cc", "a", "b")]
+        );
+
+        let mut replacing = pkg("b", &["/usr/bin/cc"]);
+        replacing.1.replaces = vec!["a (<< 2.0)".to_string()];
+        assert!(find_file_conflicts(&[pkg("a", &["/usr/bin/cc"]), replacing]).is_empty());
+
+        let multi_arch = |arch: &str| {
+            let (mut pkg, mut list) = pkg("libc6", &["/usr/share/doc/libc6/copyright"]);
+            pkg.url = format!("https://example.com/libc6_{arch}.deb");
+            list.multi_arch_same = true;
+            (pkg, list)
+        };
+        assert!(find_file_conflicts(&[multi_arch("amd64"), multi_arch("i386")]).is_empty());
+    }
+
+    #[test]
+    fn test_select_packages() -> Result<()> {
+        let pkg = |name: &str, depends: &[&str], provides: &[&str]| PackageLock {
+            name: name.to_string(),
+            version: "1.0-1".to_string(),
+            system: "debian".to_string(),
+            url: format!("https://example.com/{name}.deb"),
+            provides: provides.iter().map(|p| p.to_string()).collect(),
+            sha256: "00".to_string(),
+            depends: depends.iter().map(|d| d.to_string()).collect(),
+            ..Default::default()
+        };
+        let names = |pkgs: &[PackageLock]| pkgs.iter().map(|p| p.name.clone()).collect::<Vec<_>>();
+        let pkgs = vec![
+            pkg("gcc", &["cpp (= 12.2.0-14)", "libc6-dev | libc-dev"], &[]),
+            pkg("cpp", &["libc6"], &[]),
+            pkg("libc6-dev", &[], &["libc-dev"]),
+            pkg("musl-dev", &[], &["libc-dev"]),
+        ];
+
+        assert_eq!(
+            names(&select_packages(pkgs.clone(), &[], &[])?),
+            names(&pkgs)
+        );
+        let only = ["gcc".to_string(), "cpp".to_string()];
+        assert_eq!(
+            names(&select_packages(pkgs.clone(), &only, &[])?),
+            ["gcc", "cpp"]
+        );
+        let skip = ["cpp".to_string()];
+        assert_eq!(
+            names(&select_packages(pkgs.clone(), &[], &skip)?),
+            ["gcc", "libc6-dev", "musl-dev"]
+        );
+        assert_eq!(
+            names(&select_packages(pkgs.clone(), &only, &skip)?),
+            ["gcc"]
+        );
+        assert!(select_packages(pkgs.clone(), &["clang".to_string()], &[]).is_err());
+        assert!(select_packages(pkgs.clone(), &[], &["clang".to_string()]).is_err());
+
+        // libc6 isn't in the lockfile, it's provided by the image
+        let (selected, left_out) = pkgs.split_at(2);
+        assert_eq!(
+            skipped_dependencies(selected, left_out),
+            [("gcc", "libc6-dev | libc-dev")]
+        );
+        // another provider of libc-dev is still installed
+        assert_eq!(
+            skipped_dependencies(&[&pkgs[..2], &pkgs[3..]].concat(), &pkgs[2..3]),
+            []
+        );
+        assert_eq!(
+            skipped_dependencies(&pkgs[..1], &pkgs[1..2]),
+            [("gcc", "cpp (= 12.2.0-14)")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_env_file() -> Result<()> {
+        let vars = vec![
+            ("TOKEN".to_string(), "abc=def".to_string()),
+            ("EMPTY".to_string(), String::new()),
+        ];
+        assert_eq!(format_env_file(&vars)?, "TOKEN=abc=def\nEMPTY=\n");
+
+        let vars = vec![("KEY".to_string(), "line1\nline2".to_string())];
+        assert!(format_env_file(&vars).is_err());
+        Ok(())
+    }
 }
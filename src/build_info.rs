@@ -0,0 +1,78 @@
+//! How this build of repro-env was compiled, recorded by the build script
+use crate::errors::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs::File;
+use std::io;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub target: Option<&'static str>,
+    pub rustc: Option<&'static str>,
+    pub git_commit: Option<&'static str>,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        BuildInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            target: option_env!("REPRO_ENV_TARGET"),
+            rustc: option_env!("REPRO_ENV_RUSTC_VERSION"),
+            git_commit: option_env!("REPRO_ENV_GIT_COMMIT"),
+        }
+    }
+
+    /// One `key: value` per line, `sha256` is the hash of the running binary (if known)
+    pub fn format_text(&self, sha256: Option<&str>) -> String {
+        let fields = [
+            ("version", Some(self.version)),
+            ("target", self.target),
+            ("rustc", self.rustc),
+            ("git commit", self.git_commit),
+            ("sha256", sha256),
+        ];
+        let mut out = String::new();
+        for (key, value) in fields {
+            out.push_str(&format!("{key}: {}\n", value.unwrap_or("unknown")));
+        }
+        out
+    }
+}
+
+/// The sha256 of the running executable, so rebuilders can pin the exact binary
+pub fn current_exe_sha256() -> Result<String> {
+    let path = env::current_exe().context("Failed to locate the running executable")?;
+    let mut file = File::open(&path).with_context(|| anyhow!("Failed to open file: {path:?}"))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).with_context(|| anyhow!("Failed to read file: {path:?}"))?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_build_info() {
+        let info = BuildInfo {
+            version: "0.4.1",
+            target: Some("x86_64-unknown-linux-musl"),
+            rustc: Some("rustc 1.83.0 (90b35a623 2024-11-26)"),
+            git_commit: None,
+        };
+        assert_eq!(
+            info.format_text(Some("00ff")),
+            "\
+version: 0.4.1
+target: x86_64-unknown-linux-musl
+rustc: rustc 1.83.0 (90b35a623 2024-11-26)
+git commit: unknown
+sha256: 00ff
+"
+        );
+        assert_eq!(BuildInfo::current().version, env!("CARGO_PKG_VERSION"));
+        assert!(BuildInfo::current().target.is_some());
+    }
+}
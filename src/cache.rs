@@ -0,0 +1,67 @@
+use crate::args;
+use crate::errors::*;
+use crate::paths;
+use crate::progress::format_bytes;
+use crate::refs;
+use serde::Serialize;
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Stats {
+    pub lockfiles: usize,
+    pub packages: usize,
+    pub bytes: u64,
+    pub referenced_packages: usize,
+    pub referenced_bytes: u64,
+    pub unreferenced_packages: usize,
+    pub unreferenced_bytes: u64,
+}
+
+impl Stats {
+    pub fn format_text(&self) -> String {
+        format!(
+            "packages:     {} ({})\nreferenced:   {} ({}) by {} lockfiles\nunreferenced: {} ({})\n",
+            self.packages,
+            format_bytes(self.bytes),
+            self.referenced_packages,
+            format_bytes(self.referenced_bytes),
+            self.lockfiles,
+            self.unreferenced_packages,
+            format_bytes(self.unreferenced_bytes),
+        )
+    }
+}
+
+pub async fn stats(stats: &args::CacheStats) -> Result<()> {
+    let mut index = refs::load().await?;
+    index.refresh();
+    let referenced = index.referenced();
+
+    let mut out = Stats {
+        lockfiles: index.lockfiles.len(),
+        ..Default::default()
+    };
+    for (sha256, _path, size) in paths::pkgs_cache_dir()?.list()? {
+        out.packages += 1;
+        out.bytes += size;
+        if referenced.contains(&sha256) {
+            out.referenced_packages += 1;
+            out.referenced_bytes += size;
+        } else {
+            out.unreferenced_packages += 1;
+            out.unreferenced_bytes += size;
+        }
+    }
+
+    if stats.json {
+        println!("{}", serde_json::to_string(&out)?);
+    } else {
+        print!("{}", out.format_text());
+    }
+    Ok(())
+}
+
+pub async fn run(cache: &args::Cache) -> Result<()> {
+    match cache {
+        args::Cache::Stats(stats) => self::stats(stats).await,
+    }
+}
@@ -0,0 +1,138 @@
+//! Defaults for `cargo repro-env`, the commands run in the root of the cargo
+//! workspace and `build` runs `cargo build --release` if no command is given
+use crate::args::{Args, SubCommand};
+use crate::errors::*;
+use clap::{Arg, CommandFactory, FromArgMatches};
+use std::env;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::Command;
+
+pub const DEFAULT_BUILD_CMD: &[&str] = &["cargo", "build", "--release"];
+
+pub fn command() -> clap::Command {
+    Args::command()
+        .name("cargo-repro-env")
+        .bin_name("cargo repro-env")
+        .mut_subcommand("build", |build| {
+            build.mut_arg("cmd", |_| {
+                Arg::new("cmd")
+                    .value_name("CMD")
+                    .num_args(1..)
+                    .trailing_var_arg(true)
+                    .default_values(DEFAULT_BUILD_CMD)
+                    .help("The command to execute, defaults to `cargo build --release`")
+            })
+        })
+}
+
+/// Parse the arguments cargo passes to the subcommand, `cargo repro-env build` runs `cargo-repro-env repro-env build`
+pub fn try_parse<I, T>(args: I) -> Result<Args, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let mut args = args.into_iter().map(Into::into).collect::<Vec<OsString>>();
+    if args.get(1).is_some_and(|arg| arg == "repro-env") {
+        args.remove(1);
+    }
+    let matches = command().try_get_matches_from(args)?;
+    Args::from_arg_matches(&matches)
+}
+
+/// The directory of the workspace root as reported by `cargo locate-project`
+pub fn workspace_root() -> Result<PathBuf> {
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let out = Command::new(&cargo)
+        .args(["locate-project", "--workspace", "--message-format", "plain"])
+        .output()
+        .with_context(|| anyhow!("Failed to execute {cargo:?}"))?;
+    if !out.status.success() {
+        bail!(
+            "Failed to locate cargo workspace: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+    let path = String::from_utf8(out.stdout).context("Cargo output is not valid utf8")?;
+    let path = PathBuf::from(path.trim_end());
+    let root = path
+        .parent()
+        .with_context(|| anyhow!("Cargo.toml has no parent directory: {path:?}"))?;
+    Ok(root.to_path_buf())
+}
+
+/// The sandboxed package parser re-executes this binary for every package, it
+/// doesn't need the workspace and might not have one
+fn needs_workspace(args: &Args) -> bool {
+    args.context.is_none() && !matches!(args.subcommand, Some(SubCommand::InspectPackage(_)))
+}
+
+pub fn parse() -> Result<Args> {
+    let mut args = try_parse(env::args_os()).unwrap_or_else(|err| err.exit());
+    if needs_workspace(&args) {
+        args.context = Some(workspace_root()?);
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_cmd(args: &[&str]) -> Vec<String> {
+        let args = try_parse(args).unwrap();
+        let Some(SubCommand::Build(build)) = args.subcommand else {
+            panic!("Expected build subcommand: {:?}", args.subcommand);
+        };
+        build.cmd
+    }
+
+    #[test]
+    fn test_default_build_cmd() {
+        assert_eq!(
+            build_cmd(&["cargo-repro-env", "repro-env", "build"]),
+            DEFAULT_BUILD_CMD
+        );
+        assert_eq!(
+            build_cmd(&["cargo-repro-env", "build", "--", "cargo", "test"]),
+            ["cargo", "test"]
+        );
+        assert_eq!(
+            build_cmd(&[
+                "cargo-repro-env",
+                "repro-env",
+                "build",
+                "-e",
+                "FOO=1",
+                "make"
+            ]),
+            ["make"]
+        );
+    }
+
+    #[test]
+    fn test_other_subcommands() {
+        let args = try_parse(["cargo-repro-env", "repro-env", "-C", "/tmp", "update"]).unwrap();
+        assert!(matches!(args.subcommand, Some(SubCommand::Update(_))));
+        assert_eq!(args.context, Some(PathBuf::from("/tmp")));
+        assert!(try_parse(["cargo-repro-env", "repro-env"]).is_err());
+    }
+
+    #[test]
+    fn test_needs_workspace() {
+        let args = try_parse(["cargo-repro-env", "repro-env", "build"]).unwrap();
+        assert!(needs_workspace(&args));
+        let args = try_parse(["cargo-repro-env", "repro-env", "-C", "/tmp", "build"]).unwrap();
+        assert!(!needs_workspace(&args));
+        // the sandbox runs `cargo-repro-env inspect-package <system>`
+        let args = try_parse(["cargo-repro-env", "inspect-package", "debian"]).unwrap();
+        assert!(!needs_workspace(&args));
+    }
+
+    #[test]
+    fn test_workspace_root() -> Result<()> {
+        let root = workspace_root()?;
+        assert!(root.join("Cargo.toml").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+//! Run the parsed command line, shared by `repro-env` and `cargo repro-env`
+use crate::args::{Args, SubCommand};
+use crate::attest;
+use crate::audit;
+use crate::batch;
+use crate::build;
+use crate::build_info::{self, BuildInfo};
+use crate::cache;
+use crate::doctor;
+use crate::errors::*;
+use crate::explain;
+use crate::fetch;
+use crate::gc;
+use crate::http;
+use crate::init;
+use crate::lock;
+use crate::prepare;
+use crate::ps;
+use crate::sandbox;
+use crate::sbom;
+use crate::self_update;
+use crate::status;
+use crate::summary;
+use crate::systems;
+use crate::trace;
+use crate::update;
+use crate::verify;
+use env_logger::Env;
+use std::env;
+use std::io;
+use std::path;
+
+pub async fn run(args: Args) -> Result<()> {
+    let log_level = match args.verbose {
+        0 => "info",
+        1 => "debug",
+        _ => "trace",
+    };
+    env_logger::init_from_env(Env::default().default_filter_or(log_level));
+
+    // resolve before changing directories
+    let profile = args.profile.as_deref().map(path::absolute).transpose()?;
+    if let Some(path) = &args.trace_runtime {
+        trace::init(path)?;
+    }
+
+    http::init(http::Settings {
+        connect_timeout: args.connect_timeout,
+        read_timeout: args.read_timeout,
+        max_idle_connections: args.max_idle_connections,
+    })?;
+
+    if let Some(path) = args.context {
+        debug!("Changing current directory to {path:?}...");
+        env::set_current_dir(&path)
+            .with_context(|| anyhow!("Failed to switch to directory {path:?}"))?;
+    }
+
+    if args.print_build_info {
+        let sha256 = build_info::current_exe_sha256()
+            .map_err(|err| debug!("Failed to hash repro-env binary: {err:#}"))
+            .ok();
+        print!("{}", BuildInfo::current().format_text(sha256.as_deref()));
+        return Ok(());
+    }
+    let Some(subcommand) = args.subcommand else {
+        bail!("No subcommand given, see --help");
+    };
+
+    let result = match subcommand {
+        SubCommand::Init(init) => init::init(&init),
+        SubCommand::Build(build) => build::build(&build).await,
+        SubCommand::Prepare(prepare) => prepare::prepare(&prepare).await,
+        SubCommand::Update(update) => update::update(&update).await,
+        SubCommand::Fetch(fetch) => fetch::fetch(&fetch).await,
+        SubCommand::Explain(explain) => explain::explain(&explain).await,
+        SubCommand::Audit(audit) => audit::audit(&audit).await,
+        SubCommand::Verify(verify) => verify::verify(&verify).await,
+        SubCommand::Sbom(sbom) => sbom::sbom(&sbom).await,
+        SubCommand::Attest(attest) => attest::run(&attest).await,
+        SubCommand::Lock(lock) => lock::run(&lock).await,
+        SubCommand::Status(status) => status::status(&status).await,
+        SubCommand::Exec(exec) => status::exec(&exec).await,
+        SubCommand::Ps(ps) => ps::ps(&ps).await,
+        SubCommand::Gc(gc) => gc::gc(&gc).await,
+        SubCommand::Cache(cache) => cache::run(&cache).await,
+        SubCommand::Doctor(doctor) => doctor::doctor(&doctor).await,
+        SubCommand::ListSystems(list) => systems::run(&list),
+        SubCommand::Batch(batch) => batch::batch(&batch).await,
+        SubCommand::Completions(completions) => completions.generate(io::stdout()),
+        SubCommand::SelfUpdate(update) => self_update::self_update(&update).await,
+        SubCommand::InspectPackage(inspect) => sandbox::child(&inspect),
+    };
+
+    if let Some(path) = profile {
+        if let Err(err) = summary::write_profile(&path) {
+            warn!("{err:#}");
+        }
+    }
+
+    result
+}
@@ -0,0 +1,176 @@
+//! Make sure the packages of a lockfile belong together and fit the container
+//! image, so a lockfile that was edited or merged by hand fails before
+//! anything is downloaded or installed instead of halfway through the install
+use crate::errors::*;
+use crate::lockfile::PackageLock;
+use crate::platform::Platform;
+use crate::purl;
+use std::collections::BTreeMap;
+
+/// Translate the architecture names of the package systems to the ones used
+/// by OCI images, `None` for packages that run on any architecture
+pub fn oci_arch(arch: &str) -> Option<&str> {
+    let arch = match arch {
+        "all" | "any" | "noarch" => return None,
+        "x86_64" => "amd64",
+        "i386" | "i686" | "x86" => "386",
+        "aarch64" => "arm64",
+        "armel" | "armhf" | "armv6" | "armv7" | "armv7h" => "arm",
+        "ppc64el" => "ppc64le",
+        "loongarch64" => "loong64",
+        other => other,
+    };
+    Some(arch)
+}
+
+/// Join the values of a map as `amd64 (e.g. "gcc"), arm64 (e.g. "libc6")`
+fn examples(map: &BTreeMap<String, &str>) -> String {
+    map.iter()
+        .map(|(key, name)| format!("{key} (e.g. {name:?})"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Fail if the packages are of different systems or architectures, returns
+/// the architecture of the packages (in OCI terms) if any of them has one
+pub fn check_packages<'a, I: IntoIterator<Item = &'a PackageLock>>(
+    packages: I,
+) -> Result<Option<String>> {
+    let mut systems = BTreeMap::new();
+    let mut archs = BTreeMap::new();
+    for pkg in packages {
+        systems
+            .entry(pkg.system.clone())
+            .or_insert(pkg.name.as_str());
+        let arch = purl::arch(pkg);
+        if let Some(arch) = arch.as_deref().and_then(oci_arch) {
+            archs.entry(arch.to_string()).or_insert(pkg.name.as_str());
+        }
+    }
+    if systems.len() > 1 {
+        bail!(
+            "Lockfile mixes packages of different systems: {}",
+            examples(&systems)
+        );
+    }
+    if archs.len() > 1 {
+        bail!(
+            "Lockfile mixes packages of different architectures: {}",
+            examples(&archs)
+        );
+    }
+    Ok(archs.into_keys().next())
+}
+
+/// Fail if the packages are built for a different architecture than the image
+pub fn check_arch(image: &Platform, arch: &str) -> Result<()> {
+    if image.arch != arch {
+        bail!("Lockfile has packages for {arch}, but the container image is {image}");
+    }
+    Ok(())
+}
+
+/// The `ID` and `ID_LIKE` of an os-release file, e.g. `["ubuntu", "debian"]`
+pub fn parse_os_ids(os_release: &str) -> Vec<&str> {
+    let mut ids = Vec::new();
+    for line in os_release.lines() {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        if key == "ID" || key == "ID_LIKE" {
+            ids.extend(value.trim_matches(['"', '\'']).split_whitespace());
+        }
+    }
+    ids
+}
+
+/// Fail if the distribution of the image (as described by its os-release)
+/// can't install packages of this system
+pub fn check_distro(os_release: &str, system: &str) -> Result<()> {
+    let expected = match system {
+        "alpine" => "alpine",
+        "archlinux" => "arch",
+        "debian" => "debian",
+        _ => return Ok(()),
+    };
+    let ids = parse_os_ids(os_release);
+    if ids.is_empty() {
+        debug!("os-release of image has no ID, skipping distribution check");
+        return Ok(());
+    }
+    if !ids.contains(&expected) {
+        bail!(
+            "Lockfile has {system} packages, but the container image is {:?}",
+            ids[0]
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::install;
+
+    fn pkg(system: &str, name: &str, filename: &str) -> PackageLock {
+        install::test_pkg(system, name, filename).0
+    }
+
+    #[test]
+    fn test_check_packages() -> Result<()> {
+        let debian = [
+            pkg("debian", "gcc", "gcc_12.2.0-14_amd64.deb"),
+            pkg("debian", "libc6", "libc6_2.36-9_amd64.deb"),
+            pkg("debian", "tzdata", "tzdata_2024a-0_all.deb"),
+        ];
+        assert_eq!(check_packages(&debian)?.as_deref(), Some("amd64"));
+        assert_eq!(check_packages(&debian[2..])?, None);
+        assert_eq!(check_packages([])?, None);
+
+        let archlinux = pkg("archlinux", "gcc", "gcc-13.2.1-3-x86_64.pkg.tar.zst");
+        let err = check_packages(debian.iter().chain([&archlinux])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Lockfile mixes packages of different systems: archlinux (e.g. \"gcc\"), debian (e.g. \"gcc\")"
+        );
+
+        let arm64 = pkg("debian", "zlib1g", "zlib1g_1.2.13.dfsg-1_arm64.deb");
+        let err = check_packages(debian.iter().chain([&arm64])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Lockfile mixes packages of different architectures: amd64 (e.g. \"gcc\"), arm64 (e.g. \"zlib1g\")"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_arch() -> Result<()> {
+        let image = "linux/amd64".parse::<Platform>()?;
+        check_arch(&image, "amd64")?;
+        let err = check_arch(&image, "arm64").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Lockfile has packages for arm64, but the container image is linux/amd64"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_distro() {
+        let debian = "PRETTY_NAME=\"Debian GNU/Linux 12 (bookworm)\"\nNAME=\"Debian GNU/Linux\"\nID=debian\n";
+        let ubuntu = "NAME=\"Ubuntu\"\nID=ubuntu\nID_LIKE=debian\n";
+        let arch = "NAME=\"Arch Linux\"\nID=arch\n";
+        assert_eq!(parse_os_ids(ubuntu), ["ubuntu", "debian"]);
+
+        assert!(check_distro(debian, "debian").is_ok());
+        assert!(check_distro(ubuntu, "debian").is_ok());
+        assert!(check_distro(arch, "archlinux").is_ok());
+        assert!(check_distro("", "archlinux").is_ok());
+        assert!(check_distro(debian, "gentoo").is_ok());
+        assert_eq!(
+            check_distro(debian, "archlinux").unwrap_err().to_string(),
+            "Lockfile has archlinux packages, but the container image is \"debian\""
+        );
+        assert!(check_distro(arch, "alpine").is_err());
+    }
+}
@@ -1,14 +1,29 @@
 use crate::errors::*;
+use crate::lockfile::{ContainerLock, ContainerfileLock};
+pub use crate::manifest::validate_podman_args;
+use crate::manifest::Init;
+use crate::platform::Platform;
+use crate::summary;
+use crate::trace;
+use crate::utils;
 use serde::{Deserialize, Serialize};
-use std::ffi::OsStr;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::future::{self, Future};
-use std::io::Read;
+use std::io::{IsTerminal, Read};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::process::Stdio;
 use std::str::FromStr;
-use tokio::io::AsyncWriteExt;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::process::Command;
-use tokio::signal;
+use tokio::signal::unix::{self, SignalKind};
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct ImageRef {
@@ -59,8 +74,188 @@ impl fmt::Display for ImageRef {
 #[derive(Debug, Default)]
 pub struct ExecConfig {
     pub capture_stdout: bool,
+    /// Keep stderr for [`CommandError`], it's forwarded if the command succeeds
+    pub capture_stderr: bool,
     pub silence_stderr: bool,
     pub stdin: Option<Vec<u8>>,
+    /// Copy stdout to this file while still forwarding it to the terminal
+    pub tee_stdout: Option<PathBuf>,
+    /// Copy stderr to this file while still forwarding it to the terminal
+    pub tee_stderr: Option<PathBuf>,
+    /// Don't receive the ^C of the terminal, [`Container::run`] forwards signals itself
+    pub own_process_group: bool,
+}
+
+/// A podman command exited with an error
+#[derive(Debug)]
+pub struct CommandError {
+    pub args: Vec<OsString>,
+    pub code: Option<i32>,
+    /// Only set if stderr was captured
+    pub stderr: String,
+    /// Only set if stdout was captured
+    pub stdout: Vec<u8>,
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        write!(w, "Podman command ({:?}) failed to execute", self.args)?;
+        if let Some(code) = self.code {
+            write!(w, ": exit code {code}")?;
+        }
+        if !self.stderr.is_empty() {
+            write!(w, "\n{}", self.stderr.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// Executes podman commands, the default is the podman binary but tests can
+/// replace it with [`with_runtime`]
+pub trait ContainerRuntime: Send + Sync {
+    fn podman<'a>(
+        &'a self,
+        args: &'a [OsString],
+        config: &'a ExecConfig,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>>;
+}
+
+/// Forward the output of a child process and write a copy of it to `file`
+async fn tee<R, W>(mut reader: R, mut terminal: W, mut file: tokio::fs::File) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = [0; 8192];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        terminal.write_all(&buf[..n]).await?;
+        terminal.flush().await?;
+        file.write_all(&buf[..n]).await?;
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+async fn create_tee_file(path: Option<&Path>) -> Result<Option<tokio::fs::File>> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    let file = tokio::fs::File::create(path)
+        .await
+        .with_context(|| anyhow!("Failed to create capture file: {path:?}"))?;
+    Ok(Some(file))
+}
+
+/// The podman binary of `repro-env.local.toml`, `podman` from PATH otherwise
+static PODMAN_BINARY: OnceLock<PathBuf> = OnceLock::new();
+
+/// Use this podman binary for every command, needs to be set before podman is used
+pub fn set_podman_binary(path: PathBuf) -> Result<()> {
+    if PODMAN_BINARY.set(path).is_err() {
+        bail!("Podman binary has already been configured");
+    }
+    Ok(())
+}
+
+/// Spawn the podman binary
+pub struct Podman;
+
+impl ContainerRuntime for Podman {
+    fn podman<'a>(
+        &'a self,
+        args: &'a [OsString],
+        config: &'a ExecConfig,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>> {
+        Box::pin(async move {
+            let mut cmd = Command::new(
+                PODMAN_BINARY
+                    .get()
+                    .map(|path| path.as_os_str())
+                    .unwrap_or(OsStr::new("podman")),
+            );
+            cmd.args(args);
+            if config.own_process_group {
+                cmd.process_group(0);
+            }
+            if config.stdin.is_some() {
+                cmd.stdin(Stdio::piped());
+            }
+            let stdout_file = create_tee_file(config.tee_stdout.as_deref()).await?;
+            let stderr_file = create_tee_file(config.tee_stderr.as_deref()).await?;
+            if config.capture_stdout || stdout_file.is_some() {
+                cmd.stdout(Stdio::piped());
+            }
+            if config.silence_stderr {
+                cmd.stderr(Stdio::null());
+            } else if config.capture_stderr || stderr_file.is_some() {
+                cmd.stderr(Stdio::piped());
+            }
+            debug!("Spawning child process: podman {:?}", args);
+            let mut child = cmd.spawn().context("Failed to execute podman binary")?;
+
+            // the pipes of teed output are taken, so they don't end up in `out`
+            let tee_stdout =
+                stdout_file.and_then(|file| child.stdout.take().map(|stdout| (file, stdout)));
+            let tee_stderr =
+                stderr_file.and_then(|file| child.stderr.take().map(|stderr| (file, stderr)));
+
+            // write to stdin (if configured)
+            if let Some(buf) = &config.stdin {
+                if let Some(mut stdin) = child.stdin.take() {
+                    stdin.write_all(buf).await?;
+                }
+            }
+
+            // wait for the process to exit
+            let (out, tee_stdout, tee_stderr) = tokio::join!(
+                child.wait_with_output(),
+                async {
+                    match tee_stdout {
+                        Some((file, stdout)) => tee(stdout, tokio::io::stdout(), file).await,
+                        None => Ok(()),
+                    }
+                },
+                async {
+                    match tee_stderr {
+                        Some((file, stderr)) => tee(stderr, tokio::io::stderr(), file).await,
+                        None => Ok(()),
+                    }
+                },
+            );
+            let out = out?;
+            tee_stdout.context("Failed to capture stdout")?;
+            tee_stderr.context("Failed to capture stderr")?;
+            debug!("Podman command exited: {:?}", out.status);
+            if !out.status.success() {
+                return Err(CommandError {
+                    args: args.to_vec(),
+                    code: out.status.code(),
+                    stderr: String::from_utf8_lossy(&out.stderr).into_owned(),
+                    stdout: out.stdout,
+                }
+                .into());
+            }
+            if config.capture_stderr {
+                std::io::Write::write_all(&mut std::io::stderr(), &out.stderr)?;
+            }
+            Ok(out.stdout)
+        })
+    }
+}
+
+tokio::task_local! {
+    static RUNTIME: Arc<dyn ContainerRuntime>;
+}
+
+/// Run a future with all podman commands going to `runtime` instead of the podman binary
+pub async fn with_runtime<F: Future>(runtime: Arc<dyn ContainerRuntime>, fut: F) -> F::Output {
+    RUNTIME.scope(runtime, fut).await
 }
 
 pub async fn podman<I, S>(args: I, config: &ExecConfig) -> Result<Vec<u8>>
@@ -68,50 +263,414 @@ where
     I: IntoIterator<Item = S>,
     S: AsRef<OsStr> + fmt::Debug,
 {
-    let mut cmd = Command::new("podman");
-    let args = args.into_iter().collect::<Vec<_>>();
-    cmd.args(&args);
-    if config.stdin.is_some() {
-        cmd.stdin(Stdio::piped());
+    let args = args
+        .into_iter()
+        .map(|arg| arg.as_ref().to_owned())
+        .collect::<Vec<_>>();
+    let started = Instant::now();
+    let out = match RUNTIME.try_with(Arc::clone) {
+        Ok(runtime) => runtime.podman(&args, config).await,
+        Err(_) => Podman.podman(&args, config).await,
+    };
+    let elapsed = started.elapsed();
+    summary::record_podman(elapsed);
+    trace::record(&args, elapsed, &out);
+    out
+}
+
+pub const MIN_PODMAN_VERSION: PodmanVersion = PodmanVersion {
+    major: 2,
+    minor: 0,
+    patch: 0,
+};
+
+static PODMAN_VERSION: OnceLock<PodmanVersion> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PodmanVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl PodmanVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        PodmanVersion {
+            major,
+            minor,
+            patch,
+        }
     }
-    if config.capture_stdout {
-        cmd.stdout(Stdio::piped());
+
+    /// `podman cp` can read/write tar archives from stdin/stdout
+    pub fn supports_cp_stdio(&self) -> bool {
+        *self >= Self::new(3, 0, 0)
     }
-    if config.silence_stderr {
-        cmd.stderr(Stdio::null());
+
+    /// `podman run --platform`
+    pub fn supports_platform(&self) -> bool {
+        *self >= Self::new(3, 0, 0)
     }
-    debug!("Spawning child process: podman {:?}", args);
-    let mut child = cmd.spawn().context("Failed to execute podman binary")?;
+}
 
-    // write to stdin (if configured)
-    if let Some(buf) = &config.stdin {
-        if let Some(mut stdin) = child.stdin.take() {
-            stdin.write_all(buf).await?;
-        }
+impl FromStr for PodmanVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let version = s.trim();
+        let version = version.strip_prefix("podman version ").unwrap_or(version);
+        // strip suffixes like -dev or -rc1
+        let version = version
+            .split_once(['-', '+', '~'])
+            .map(|(version, _)| version)
+            .unwrap_or(version);
+
+        let mut parts = version.split('.').map(|x| {
+            x.parse::<u32>()
+                .with_context(|| anyhow!("Failed to parse podman version: {s:?}"))
+        });
+        let major = parts
+            .next()
+            .with_context(|| anyhow!("Podman version is empty: {s:?}"))??;
+        let minor = parts.next().transpose()?.unwrap_or(0);
+        let patch = parts.next().transpose()?.unwrap_or(0);
+        Ok(PodmanVersion::new(major, minor, patch))
+    }
+}
+
+impl fmt::Display for PodmanVersion {
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        write!(w, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Detect the version of the podman binary, this is only done once per process
+pub async fn podman_version() -> Result<PodmanVersion> {
+    if let Some(version) = PODMAN_VERSION.get() {
+        return Ok(*version);
+    }
+
+    let out = podman(
+        &["--version"],
+        &ExecConfig {
+            capture_stdout: true,
+            ..Default::default()
+        },
+    )
+    .await
+    .context("Failed to detect podman version, is podman installed?")?;
+    let out = String::from_utf8(out).context("Podman version output is invalid utf-8")?;
+    let version = out.parse::<PodmanVersion>()?;
+    debug!("Detected podman version: {version}");
+
+    if version < MIN_PODMAN_VERSION {
+        bail!("Podman {version} is too old, at least {MIN_PODMAN_VERSION} is required");
+    }
+
+    Ok(*PODMAN_VERSION.get_or_init(|| version))
+}
+
+pub async fn pull(image: &str) -> Result<()> {
+    pull_platform(image, None).await
+}
+
+/// Pull the image for a specific platform (like `linux/arm64`) instead of the host's
+pub async fn pull_platform(image: &str, platform: Option<&Platform>) -> Result<()> {
+    let mut args = vec!["image".to_string(), "pull".to_string()];
+    if let Some(platform) = platform {
+        args.push(format!("--platform={platform}"));
+    }
+    args.extend(["--".to_string(), image.to_string()]);
+    podman(&args, &ExecConfig::default()).await?;
+    Ok(())
+}
+
+pub async fn remove_image(image: &str) -> Result<()> {
+    podman(
+        &["image", "rm", "--", image],
+        &ExecConfig {
+            capture_stdout: true,
+            ..Default::default()
+        },
+    )
+    .await
+    .with_context(|| anyhow!("Failed to remove image: {image:?}"))?;
+    Ok(())
+}
+
+/// Image transports that refer to a file instead of a registry
+pub const ARCHIVE_TRANSPORTS: &[&str] = &["oci-archive", "docker-archive"];
+
+/// Detect image references like `oci-archive:./builder.tar` and return the path
+pub fn archive_path(image: &str) -> Option<&str> {
+    let (transport, path) = image.split_once(':')?;
+    ARCHIVE_TRANSPORTS.contains(&transport).then_some(path)
+}
+
+/// The file of an image archive relative to `project` and the reference to load it with podman
+pub fn project_archive(image: &str, project: &Path) -> Option<(PathBuf, String)> {
+    let (transport, path) = image.split_once(':')?;
+    if !ARCHIVE_TRANSPORTS.contains(&transport) {
+        return None;
     }
+    let file = project.join(path);
+    let image = format!("{transport}:{}", file.display());
+    Some((file, image))
+}
 
-    // wait for the process to exit
-    let out = child.wait_with_output().await?;
-    debug!("Podman command exited: {:?}", out.status);
-    if !out.status.success() {
+/// Load an image from an archive file, returns the id of the image
+pub async fn pull_archive(image: &str) -> Result<String> {
+    let out = podman(
+        &["image", "pull", "--quiet", "--", image],
+        &ExecConfig {
+            capture_stdout: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+    let out = String::from_utf8(out).context("Podman output is invalid utf-8")?;
+    let id = out
+        .lines()
+        .last()
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .with_context(|| anyhow!("Failed to determine image id after loading {image:?}"))?;
+    Ok(id.to_string())
+}
+
+/// Build an image from a containerfile relative to `project`, which is also used as
+/// build context. `base` replaces the image of its first stage. Timestamps are set to
+/// the epoch, so the image id only changes if the content does. Returns the id of the image.
+pub async fn build_image(base: &str, project: &Path, containerfile: &str) -> Result<String> {
+    let context = if project.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        project
+    };
+    let out = podman(
+        &[
+            "image".to_string(),
+            "build".to_string(),
+            "--quiet".to_string(),
+            "--timestamp=0".to_string(),
+            format!("--from={base}"),
+            format!("--file={}", context.join(containerfile).display()),
+            "--".to_string(),
+            context.display().to_string(),
+        ],
+        &ExecConfig {
+            capture_stdout: true,
+            ..Default::default()
+        },
+    )
+    .await
+    .with_context(|| anyhow!("Failed to build image from containerfile {containerfile:?}"))?;
+    let out = String::from_utf8(out).context("Podman output is invalid utf-8")?;
+    let id = out
+        .lines()
+        .last()
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .with_context(|| {
+            anyhow!("Failed to determine image id after building {containerfile:?}")
+        })?;
+    Ok(id.to_string())
+}
+
+/// Use the image that was built from the containerfile if it's still in local
+/// storage, otherwise build it again on top of `base`. The image id is not
+/// reproducible across machines, a different id is only reported.
+async fn prepare_built_image(
+    containerfile: &ContainerfileLock,
+    base: &str,
+    project: &Path,
+) -> Result<String> {
+    let id = &containerfile.image_id;
+    if inspect(id).await.is_ok() {
+        debug!("Found image built from containerfile in local storage: {id:?}");
+        return Ok(id.clone());
+    }
+
+    let path = &containerfile.path;
+    let sha256 = utils::sha256_file(&project.join(path))?;
+    if sha256 != containerfile.sha256 {
         bail!(
-            "Podman command ({:?}) failed to execute: {:?}",
-            args,
-            out.status
+            "Mismatch of sha256 for containerfile {path:?}, expected={:?}, found={sha256:?}, run `repro-env update` if the change is intended",
+            containerfile.sha256
         );
     }
-    Ok(out.stdout)
+    info!("Building image from containerfile {path:?}...");
+    let built = build_image(base, project, path).await?;
+    if built != *id {
+        warn!("Image built from containerfile {path:?} has a different id than the lockfile (expected={id:?}, found={built:?}), podman builds are not reproducible across machines. The base image and containerfile match, using it anyway");
+    }
+    Ok(built)
 }
 
-pub async fn pull(image: &str) -> Result<()> {
-    podman(&["image", "pull", "--", image], &ExecConfig::default()).await?;
+/// Ensure the pinned image is available, returns the reference to use with podman run.
+/// Images from archives are verified against the pinned sha256 and loaded first,
+/// archives and a containerfile are relative to `project`, the directory of the lockfile.
+pub async fn prepare_image(lock: &ContainerLock, project: &Path) -> Result<String> {
+    if let Some(containerfile) = &lock.containerfile {
+        return prepare_built_image(containerfile, &lock.image, project).await;
+    }
+    let Some((path, image)) = project_archive(&lock.image, project) else {
+        return Ok(lock.image.clone());
+    };
+
+    let expected = lock
+        .sha256
+        .as_ref()
+        .context("Dependency lockfile is missing sha256 for image archive")?;
+    let sha256 = utils::sha256_file(&path)?;
+    if *expected != sha256 {
+        bail!("Mismatch of sha256 for image archive {path:?}, expected={expected:?}, found={sha256:?}");
+    }
+
+    info!("Loading container image from archive: {path:?}");
+    let id = pull_archive(&image).await?;
+    debug!("Loaded image archive {path:?} as {id:?}");
+    Ok(id)
+}
+
+/// The reference of a pinned image in the OCI layout of the cache, like `sha256-<hex>`
+pub fn layout_tag(image: &str) -> Result<String> {
+    let image_ref = image.parse::<ImageRef>()?;
+    let digest = image_ref
+        .digest
+        .with_context(|| anyhow!("Image is not pinned by digest: {image:?}"))?;
+    let (algo, hex) = digest
+        .split_once(':')
+        .with_context(|| anyhow!("Invalid digest of image: {digest:?}"))?;
+    if !algo.chars().all(|c| c.is_ascii_alphanumeric())
+        || !hex.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        bail!("Invalid digest of image: {digest:?}");
+    }
+    Ok(format!("{algo}-{hex}"))
+}
+
+fn layout_ref(layout: &Path, tag: &str) -> Result<String> {
+    let path = layout
+        .to_str()
+        .with_context(|| anyhow!("Path of image cache is not valid utf-8: {layout:?}"))?;
+    Ok(format!("oci:{path}:{tag}"))
+}
+
+#[derive(Debug, Deserialize)]
+struct LayoutIndex {
+    #[serde(default)]
+    manifests: Vec<LayoutDescriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LayoutDescriptor {
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+/// If the image has been exported into the OCI layout with `fetch --export-image`
+pub fn is_exported(image: &str, layout: &Path) -> Result<bool> {
+    let tag = layout_tag(image)?;
+    let path = layout.join("index.json");
+    let buf = match std::fs::read(&path) {
+        Ok(buf) => buf,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err.into()),
+    };
+    let index = serde_json::from_slice::<LayoutIndex>(&buf)
+        .with_context(|| anyhow!("Failed to parse index of image cache: {path:?}"))?;
+    Ok(index.manifests.iter().any(|manifest| {
+        manifest
+            .annotations
+            .get("org.opencontainers.image.ref.name")
+            .is_some_and(|name| *name == tag)
+    }))
+}
+
+/// Copy a pinned image from local storage into the OCI layout of the cache,
+/// layers that are shared with other images are only stored once
+pub async fn export_image(image: &str, layout: &Path) -> Result<()> {
+    let tag = layout_tag(image)?;
+    std::fs::create_dir_all(layout)
+        .with_context(|| anyhow!("Failed to create image cache: {layout:?}"))?;
+    info!("Exporting container image into cache: {image:?}");
+    podman(
+        &[
+            "image",
+            "push",
+            "--quiet",
+            "--",
+            image,
+            &layout_ref(layout, &tag)?,
+        ],
+        &ExecConfig {
+            capture_stdout: true,
+            ..Default::default()
+        },
+    )
+    .await
+    .with_context(|| anyhow!("Failed to export image: {image:?}"))?;
     Ok(())
 }
 
+/// Like [`prepare_image`], but the image is never pulled from a registry. If it's
+/// not in local storage, it's loaded from the OCI layout of the cache instead.
+pub async fn prepare_image_offline(
+    lock: &ContainerLock,
+    project: &Path,
+    layout: &Path,
+) -> Result<String> {
+    if let Some(containerfile) = &lock.containerfile {
+        if inspect(&containerfile.image_id).await.is_ok() {
+            return Ok(containerfile.image_id.clone());
+        }
+        let base = load_image_offline(lock, project, layout).await?;
+        return prepare_built_image(containerfile, &base, project).await;
+    }
+    load_image_offline(lock, project, layout).await
+}
+
+/// Make the pinned image (not the one built from a containerfile) available without network access
+async fn load_image_offline(lock: &ContainerLock, project: &Path, layout: &Path) -> Result<String> {
+    if archive_path(&lock.image).is_some() {
+        return prepare_image(lock, project).await;
+    }
+    if inspect(&lock.image).await.is_ok() {
+        return Ok(lock.image.clone());
+    }
+    if !is_exported(&lock.image, layout)? {
+        bail!(
+            "Image is neither in local storage nor in the cache, run `repro-env fetch --export-image` first: {:?}",
+            lock.image
+        );
+    }
+    info!("Loading container image from cache: {:?}", lock.image);
+    let id = pull_archive(&layout_ref(layout, &layout_tag(&lock.image)?)?).await?;
+    debug!("Loaded image {:?} from cache as {id:?}", lock.image);
+    Ok(id)
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct Image {
     pub digest: String,
+    #[serde(default)]
+    pub os: Option<String>,
+    #[serde(default)]
+    pub architecture: Option<String>,
+    #[serde(default)]
+    pub variant: Option<String>,
+}
+
+impl Image {
+    pub fn platform(&self) -> Option<Platform> {
+        Some(Platform {
+            os: self.os.clone()?,
+            arch: self.architecture.clone()?,
+            variant: self.variant.clone().filter(|v| !v.is_empty()),
+        })
+    }
 }
 
 pub async fn inspect(image: &str) -> Result<Image> {
@@ -140,18 +699,261 @@ pub async fn inspect(image: &str) -> Result<Image> {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ContainerInfo {
+    #[serde(rename = "Id")]
+    pub id: String,
+    #[serde(rename = "Image")]
+    pub image: String,
+    #[serde(rename = "State", default)]
+    pub state: String,
+    #[serde(rename = "Labels", default)]
+    pub labels: Option<HashMap<String, String>>,
+}
+
+impl ContainerInfo {
+    pub fn label(&self, key: &str) -> Option<&str> {
+        self.labels.as_ref()?.get(key).map(String::as_str)
+    }
+}
+
+/// List all containers that have been created by repro-env
+pub async fn list_containers() -> Result<Vec<ContainerInfo>> {
+    let filter = format!("--filter=label={LABEL_VERSION}");
+    let out = podman(
+        ["container", "ps", "--all", "--format=json", &filter],
+        &ExecConfig {
+            capture_stdout: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+    let list = serde_json::from_slice(&out).context("Failed to parse podman container list")?;
+    Ok(list)
+}
+
+/// Find a running container created by `repro-env prepare` for the current
+/// directory and lockfile
+pub async fn find_prepared(lockfile_digest: &str) -> Result<Option<String>> {
+    let project = env::current_dir()?;
+    let project = project.to_string_lossy();
+    let container = list_containers().await?.into_iter().find(|c| {
+        c.state == "running"
+            && c.label(LABEL_PREPARED) == Some("1")
+            && c.label(LABEL_PROJECT) == Some(&project)
+            && c.label(LABEL_LOCKFILE_DIGEST) == Some(lockfile_digest)
+    });
+    Ok(container.map(|c| c.id))
+}
+
+pub async fn remove(id: &str) -> Result<()> {
+    podman(
+        ["container", "rm", "--force", id],
+        &ExecConfig {
+            capture_stdout: true,
+            ..Default::default()
+        },
+    )
+    .await
+    .with_context(|| anyhow!("Failed to remove container: {id:?}"))?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mount {
+    pub src: PathBuf,
+    pub dest: String,
+    pub read_only: bool,
+}
+
+impl Mount {
+    pub fn new<S: Into<PathBuf>, D: Into<String>>(src: S, dest: D) -> Self {
+        Mount {
+            src: src.into(),
+            dest: dest.into(),
+            read_only: false,
+        }
+    }
+
+    pub fn read_only<S: Into<PathBuf>, D: Into<String>>(src: S, dest: D) -> Self {
+        Mount {
+            read_only: true,
+            ..Self::new(src, dest)
+        }
+    }
+
+    /// Use `--mount` instead of `-v`, the paths may contain `:`. The option is
+    /// parsed as csv by podman, so fields with `,` or `"` need to be quoted.
+    /// The source path doesn't need to be valid utf-8.
+    pub fn to_podman_arg(&self) -> OsString {
+        let mut src = b"src=".to_vec();
+        src.extend(self.src.as_os_str().as_bytes());
+        let dst = format!("dst={}", self.dest);
+
+        let mut arg = b"--mount=type=bind,".to_vec();
+        arg.extend(csv_field(&src));
+        arg.push(b',');
+        arg.extend(csv_field(dst.as_bytes()));
+        if self.read_only {
+            arg.extend(b",ro");
+        }
+        OsString::from_vec(arg)
+    }
+}
+
+fn csv_field(field: &[u8]) -> Vec<u8> {
+    if !field
+        .iter()
+        .any(|b| matches!(b, b',' | b'"' | b'\n' | b'\r'))
+    {
+        return field.to_vec();
+    }
+    let mut out = vec![b'"'];
+    for &b in field {
+        if b == b'"' {
+            out.push(b'"');
+        }
+        out.push(b);
+    }
+    out.push(b'"');
+    out
+}
+
+pub const LABEL_VERSION: &str = "io.repro-env.version";
+pub const LABEL_PROJECT: &str = "io.repro-env.project";
+pub const LABEL_LOCKFILE_DIGEST: &str = "io.repro-env.lockfile-digest";
+pub const LABEL_PREPARED: &str = "io.repro-env.prepared";
+
+/// The init of the container, mounted from the host
+pub const CATATONIT: &str = "/usr/bin/catatonit";
+/// The argument for `sleep` if the container isn't kept running by catatonit (~68 years)
+const SLEEP_FOREVER: &str = "2147483647";
+
+/// The sha256 of the init on this system
+pub fn init_sha256() -> Result<String> {
+    utils::sha256_file(Path::new(CATATONIT))
+        .with_context(|| anyhow!("Failed to hash init {CATATONIT:?} (install catatonit)"))
+}
+
+fn check_init(path: &Path, expected: &str) -> Result<()> {
+    let found = utils::sha256_file(path)
+        .with_context(|| anyhow!("Failed to hash init {path:?} (install catatonit)"))?;
+    if found != expected {
+        bail!(
+            "The init {path:?} doesn't match the lockfile (expected={expected}, found={found}), run `repro-env update` if the change is intended"
+        );
+    }
+    Ok(())
+}
+
+/// Make sure the init that's mounted into the container is the one recorded in the lockfile
+pub fn verify_init(lock: &ContainerLock) -> Result<()> {
+    if let Some(expected) = &lock.init_sha256 {
+        check_init(Path::new(CATATONIT), expected)?;
+        debug!("Verified init {CATATONIT:?} has sha256 {expected:?}");
+    }
+    Ok(())
+}
+
+/// How long an interrupted build gets to clean up before the container is killed
+pub const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The labels attached to containers (and staging directories) so leftovers can be traced back
+pub fn labels(lockfile_digest: Option<&str>) -> Vec<(&'static str, String)> {
+    let mut labels = vec![(LABEL_VERSION, env!("CARGO_PKG_VERSION").to_string())];
+    if let Ok(pwd) = env::current_dir() {
+        labels.push((LABEL_PROJECT, pwd.to_string_lossy().into_owned()));
+    }
+    if let Some(digest) = lockfile_digest {
+        labels.push((LABEL_LOCKFILE_DIGEST, digest.to_string()));
+    }
+    labels
+}
+
+#[derive(Debug, Deserialize)]
+struct ContainerInspect {
+    #[serde(rename = "State")]
+    state: ContainerState,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerState {
+    pub status: String,
+    pub running: bool,
+    /// The pid of the init on the host, 0 if it's not running
+    #[serde(default)]
+    pub pid: u32,
+    #[serde(default)]
+    pub exit_code: i32,
+    #[serde(default)]
+    pub error: String,
+}
+
+impl ContainerState {
+    pub fn is_running(&self) -> bool {
+        self.running && self.pid != 0
+    }
+}
+
+/// Likely reasons why a container didn't start
+pub fn start_hints(state: Option<&ContainerState>, catatonit: bool) -> Vec<String> {
+    let mut hints = Vec::new();
+    if !catatonit {
+        hints.push(format!(
+            "{CATATONIT} does not exist, it's used as init of the container (install catatonit)"
+        ));
+    }
+    if let Some(state) = state {
+        if !state.error.is_empty() {
+            hints.push(format!("Podman reported: {}", state.error));
+        }
+        if matches!(state.exit_code, 126 | 127) {
+            hints.push(
+                "The init could not be executed in the container, the image may be for a different architecture".to_string(),
+            );
+        }
+    }
+    hints
+}
+
+fn log_hints(hints: &[String]) {
+    for hint in hints {
+        warn!("{hint}");
+    }
+}
+
 #[derive(Debug)]
 pub struct Config<'a> {
-    pub mounts: &'a [(String, String)],
+    pub mounts: &'a [Mount],
     pub expose_fuse: bool,
+    pub lockfile_digest: Option<&'a str>,
+    /// Mark the container as ready for builds, see `repro-env prepare`
+    pub prepared: bool,
+    /// Passed to `podman run --platform`
+    pub platform: Option<&'a Platform>,
+    /// Additional arguments for `podman run`, checked with [`validate_podman_args`]
+    pub podman_args: &'a [String],
+    /// Only the mounts and a tmpfs on /tmp are writable
+    pub read_only_root: bool,
+    /// What runs as pid 1 of the container
+    pub init: Init,
 }
 
 #[derive(Debug, Default)]
 pub struct Exec<'a> {
     pub capture_stdout: bool,
+    pub capture_stderr: bool,
     pub cwd: Option<&'a str>,
     pub user: Option<&'a str>,
     pub env: &'a [String],
+    pub env_file: Option<&'a Path>,
+    /// Keep stdin open and allocate a tty if stdin is a terminal
+    pub interactive: bool,
+    /// Also write stdout to this file on the host
+    pub tee_stdout: Option<&'a Path>,
+    /// Also write stderr to this file on the host
+    pub tee_stderr: Option<&'a Path>,
 }
 
 #[derive(Debug)]
@@ -161,28 +963,67 @@ pub struct Container {
 
 impl Container {
     pub async fn create(image: &str, config: Config<'_>) -> Result<Container> {
+        let version = podman_version().await?;
+
         let mut podman_args = vec![
-            "container".to_string(),
-            "run".to_string(),
-            "--detach".to_string(),
-            "--rm".to_string(),
-            "--network=host".to_string(),
-            "-v=/usr/bin/catatonit:/__:ro".to_string(),
-            "--entrypoint=/__".to_string(),
+            OsString::from("container"),
+            OsString::from("run"),
+            OsString::from("--detach"),
+            OsString::from("--rm"),
+            OsString::from("--network=host"),
         ];
+        match config.init {
+            Init::Catatonit => podman_args.extend([
+                Mount::read_only(CATATONIT, "/__").to_podman_arg(),
+                OsString::from("--entrypoint=/__"),
+            ]),
+            Init::Podman => podman_args.extend([
+                OsString::from("--init"),
+                OsString::from("--entrypoint=sleep"),
+            ]),
+            Init::Image => (),
+        }
+
+        for (key, value) in labels(config.lockfile_digest) {
+            podman_args.push(format!("--label={key}={value}").into());
+        }
+        if config.prepared {
+            podman_args.push(format!("--label={LABEL_PREPARED}=1").into());
+        }
+
+        for mount in config.mounts {
+            podman_args.push(mount.to_podman_arg());
+        }
 
-        for (src, dest) in config.mounts {
-            podman_args.push(format!("-v={src}:{dest}"));
+        if let Some(platform) = config.platform {
+            if !version.supports_platform() {
+                bail!("Podman {version} does not support --platform, at least 3.0.0 is required");
+            }
+            podman_args.push(format!("--platform={platform}").into());
         }
 
         if config.expose_fuse {
             debug!("Mapping /dev/fuse into the container");
-            podman_args.push("--device=/dev/fuse".to_string());
+            podman_args.push("--device=/dev/fuse".into());
         }
 
-        podman_args.extend(["--".to_string(), image.to_string(), "-P".to_string()]);
+        if config.read_only_root {
+            podman_args.extend(["--read-only".into(), "--tmpfs=/tmp".into()]);
+        }
+
+        validate_podman_args(config.podman_args)?;
+        podman_args.extend(config.podman_args.iter().map(OsString::from));
+
+        podman_args.extend(["--".into(), image.into()]);
+        match config.init {
+            Init::Catatonit => podman_args.push("-P".into()),
+            Init::Podman => podman_args.push(SLEEP_FOREVER.into()),
+            Init::Image => podman_args.extend(["sleep".into(), SLEEP_FOREVER.into()]),
+        }
 
         debug!("Creating container...");
+        // the hint about a missing catatonit is only relevant if it's used
+        let catatonit = config.init != Init::Catatonit || Path::new(CATATONIT).exists();
         let mut out = podman(
             &podman_args,
             &ExecConfig {
@@ -190,12 +1031,61 @@ impl Container {
                 ..Default::default()
             },
         )
-        .await?;
+        .await
+        .inspect_err(|_| log_hints(&start_hints(None, catatonit)))?;
         if let Some(idx) = memchr::memchr(b'\n', &out) {
             out.truncate(idx);
         }
         let id = String::from_utf8(out)?;
-        Ok(Container { id })
+        let container = Container { id };
+        container.check_started(catatonit).await?;
+        Ok(container)
+    }
+
+    /// `run --detach` succeeds even if the init exits right away, make sure it's
+    /// still running before executing anything in the container
+    async fn check_started(&self, catatonit: bool) -> Result<()> {
+        let state = match self.state().await {
+            Ok(state) if state.is_running() => {
+                debug!("Container is running with init pid {}", state.pid);
+                return Ok(());
+            }
+            Ok(state) => Some(state),
+            Err(err) => {
+                // with --rm the container is usually gone already
+                debug!("Failed to inspect container: {err:#}");
+                None
+            }
+        };
+        log_hints(&start_hints(state.as_ref(), catatonit));
+        match state {
+            Some(state) => bail!(
+                "Container {:?} did not start (status {:?}, exit code {})",
+                self.id,
+                state.status,
+                state.exit_code
+            ),
+            None => bail!("Container {:?} exited right after it was created", self.id),
+        }
+    }
+
+    pub async fn state(&self) -> Result<ContainerState> {
+        let out = podman(
+            &["container", "inspect", "--", &self.id],
+            &ExecConfig {
+                capture_stdout: true,
+                silence_stderr: true,
+                ..Default::default()
+            },
+        )
+        .await?;
+        let list = serde_json::from_slice::<Vec<ContainerInspect>>(&out)
+            .context("Failed to parse container inspect output")?;
+        let inspect = list
+            .into_iter()
+            .next()
+            .with_context(|| anyhow!("Could not find container: {:?}", self.id))?;
+        Ok(inspect.state)
     }
 
     pub async fn exec<I, S>(&self, args: I, options: Exec<'_>) -> Result<Vec<u8>>
@@ -218,21 +1108,53 @@ impl Container {
             a.extend(["-e".to_string(), env.to_string()]);
         }
 
+        if options.interactive {
+            a.push("--interactive".to_string());
+            if std::io::stdin().is_terminal() {
+                a.push("--tty".to_string());
+            }
+        }
+
+        if let Some(path) = options.env_file {
+            let path = path
+                .to_str()
+                .with_context(|| anyhow!("Path to env file is not valid utf-8: {path:?}"))?;
+            a.push(format!("--env-file={path}"));
+        }
+
         a.extend(["--".to_string(), self.id.to_string()]);
-        a.extend(args.iter().map(|x| x.as_ref().to_string()));
-        let buf = podman(
+        let cmd = args
+            .iter()
+            .map(|x| x.as_ref().to_string())
+            .collect::<Vec<_>>();
+        a.extend(cmd.iter().cloned());
+        let started = Instant::now();
+        let result = podman(
             &a,
             &ExecConfig {
                 capture_stdout: options.capture_stdout,
+                capture_stderr: options.capture_stderr,
+                tee_stdout: options.tee_stdout.map(PathBuf::from),
+                tee_stderr: options.tee_stderr.map(PathBuf::from),
+                own_process_group: !options.interactive,
                 ..Default::default()
             },
         )
-        .await
-        .with_context(|| anyhow!("Failed to execute in container: {:?}", args))?;
+        .await;
+        let exit_code = match &result {
+            Ok(_) => Some(0),
+            Err(err) => err.downcast_ref::<CommandError>().and_then(|err| err.code),
+        };
+        summary::record_command(cmd, started.elapsed(), exit_code);
+        let buf = result.with_context(|| anyhow!("Failed to execute in container: {:?}", args))?;
         Ok(buf)
     }
 
     pub async fn tar(&self, path: &str) -> Result<Vec<u8>> {
+        if !podman_version().await?.supports_cp_stdio() {
+            return self.tar_with_exec(path).await;
+        }
+
         let a = vec![
             "container".to_string(),
             "cp".to_string(),
@@ -253,6 +1175,23 @@ impl Container {
         Ok(buf)
     }
 
+    /// Fallback for podman versions that can't write a tar archive to stdout,
+    /// this requires a tar binary inside of the container
+    async fn tar_with_exec(&self, path: &str) -> Result<Vec<u8>> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let parent = if parent.is_empty() { "/" } else { parent };
+        self.exec(
+            &["tar", "-c", "-f", "-", "-C", parent, "--", name],
+            Exec {
+                capture_stdout: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .with_context(|| anyhow!("Failed to read from container: {:?}", path))
+    }
+
     pub async fn cat(&self, path: &str) -> Result<Vec<u8>> {
         let buf = self.tar(path).await?;
 
@@ -275,6 +1214,12 @@ impl Container {
     }
 
     pub async fn write_file(&self, directory: &str, filename: &str, content: &[u8]) -> Result<()> {
+        if !podman_version().await?.supports_cp_stdio() {
+            return self
+                .write_file_with_exec(directory, filename, content)
+                .await;
+        }
+
         // generate tar file
         let mut tar = tar::Builder::new(Vec::new());
 
@@ -313,6 +1258,58 @@ impl Container {
         Ok(())
     }
 
+    /// Fallback for podman versions that can't read a tar archive from stdin
+    async fn write_file_with_exec(
+        &self,
+        directory: &str,
+        filename: &str,
+        content: &[u8],
+    ) -> Result<()> {
+        let path = format!("{}/{}", directory.trim_end_matches('/'), filename);
+        let a = vec![
+            "container".to_string(),
+            "exec".to_string(),
+            "-i".to_string(),
+            "--".to_string(),
+            self.id.to_string(),
+            "tee".to_string(),
+            "--".to_string(),
+            path.clone(),
+        ];
+        podman(
+            &a,
+            &ExecConfig {
+                capture_stdout: true,
+                stdin: Some(content.to_vec()),
+                ..Default::default()
+            },
+        )
+        .await
+        .with_context(|| anyhow!("Failed to write container file: {path:?}"))?;
+        Ok(())
+    }
+
+    /// Save the filesystem of the container as a new image, returns the image id
+    pub async fn commit(&self) -> Result<String> {
+        let out = podman(
+            &["container", "commit", "--quiet", "--", &self.id],
+            &ExecConfig {
+                capture_stdout: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .context("Failed to commit container")?;
+        let out = String::from_utf8(out).context("Podman output is invalid utf-8")?;
+        let id = out
+            .lines()
+            .last()
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .with_context(|| anyhow!("Failed to determine image id of committed container"))?;
+        Ok(id.to_string())
+    }
+
     pub async fn kill(&self) -> Result<()> {
         podman(
             &["container", "kill", &self.id],
@@ -326,9 +1323,36 @@ impl Container {
         Ok(())
     }
 
+    /// Send a signal to every process in the container except for the init
+    pub async fn signal_processes(&self, signal: &str) -> Result<()> {
+        self.exec(
+            ["sh", "-c", "kill -s \"$1\" -1", "sh", signal],
+            Exec {
+                capture_stdout: true,
+                capture_stderr: true,
+                ..Default::default()
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Forward a signal to the processes in the container and wait for `fut` to
+    /// finish, returns false if it didn't within `timeout`
+    pub async fn stop<F: Future>(&self, fut: Pin<&mut F>, signal: &str, timeout: Duration) -> bool {
+        if let Err(err) = self.signal_processes(signal).await {
+            warn!("Failed to forward SIG{signal} into container: {err:#}");
+            return false;
+        }
+        tokio::time::timeout(timeout, fut).await.is_ok()
+    }
+
     pub async fn run<F: Future<Output = Result<()>>>(&self, fut: F, keep: bool) -> Result<()> {
+        let _interrupts = utils::handle_interrupts();
+        let running = Cell::new(true);
         let fut = async {
             fut.await?;
+            running.set(false);
             if keep {
                 info!("Keeping container around until ^C...");
                 future::pending().await
@@ -336,10 +1360,34 @@ impl Container {
                 Ok(())
             }
         };
-        let result = tokio::select! {
-            result = fut => result,
-            _ = signal::ctrl_c() => Err(anyhow!("Ctrl-c received")),
+        tokio::pin!(fut);
+        let mut sigint = unix::signal(SignalKind::interrupt())?;
+        let mut sigterm = unix::signal(SignalKind::terminate())?;
+        let (signal, result) = tokio::select! {
+            result = &mut fut => (None, result),
+            _ = sigint.recv() => (Some("INT"), Err(anyhow!("Ctrl-c received"))),
+            _ = sigterm.recv() => (Some("TERM"), Err(anyhow!("SIGTERM received"))),
         };
+
+        if signal.is_some() {
+            // abort in-flight downloads of the resolver right away, they'd
+            // otherwise hold up the teardown until their read timeout
+            utils::shutdown().cancel();
+        }
+        if let Some(signal) = signal.filter(|_| running.get()) {
+            info!(
+                "Stopping build, waiting up to {}s for it to exit (^C again to kill it)...",
+                STOP_TIMEOUT.as_secs()
+            );
+            let stopped = tokio::select! {
+                stopped = self.stop(fut, signal, STOP_TIMEOUT) => stopped,
+                _ = sigint.recv() => false,
+                _ = sigterm.recv() => false,
+            };
+            if !stopped {
+                warn!("Build did not exit in time, killing container");
+            }
+        }
         debug!("Removing container...");
         if let Err(err) = self.kill().await {
             warn!("Failed to kill container {:?}: {:#}", self.id, err);
@@ -409,6 +1457,292 @@ pub async fn test_for_unprivileged_userns_clone() -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fake_runtime::FakeRuntime;
+
+    #[test]
+    fn test_start_hints() {
+        let state = ContainerState {
+            status: "exited".to_string(),
+            running: false,
+            pid: 0,
+            exit_code: 127,
+            error: "crun: executable file `/__` not found".to_string(),
+        };
+        assert!(!state.is_running());
+        assert_eq!(
+            start_hints(Some(&state), true),
+            [
+                "Podman reported: crun: executable file `/__` not found",
+                "The init could not be executed in the container, the image may be for a different architecture",
+            ]
+        );
+        assert_eq!(
+            start_hints(None, false),
+            ["/usr/bin/catatonit does not exist, it's used as init of the container (install catatonit)"]
+        );
+    }
+
+    #[test]
+    fn test_check_init() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("catatonit");
+        std::fs::write(&path, b"init")?;
+        let sha256 = utils::sha256_file(&path)?;
+        check_init(&path, &sha256)?;
+
+        let err = check_init(&path, "00").unwrap_err();
+        assert!(format!("{err:#}").contains(&format!("expected=00, found={sha256}")));
+        assert!(check_init(&dir.path().join("missing"), &sha256).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prepare_image_offline() -> Result<()> {
+        let image = "docker.io/library/debian@sha256:3d868b5eb908155f3784317b3dda2941df87bbbbaa4608f84881de66d9bb297b";
+        let tag = "sha256-3d868b5eb908155f3784317b3dda2941df87bbbbaa4608f84881de66d9bb297b";
+        assert_eq!(layout_tag(image)?, tag);
+        assert!(layout_tag("debian:bookworm").is_err());
+
+        let dir = tempfile::tempdir()?;
+        let lock = ContainerLock {
+            image: image.to_string(),
+            sha256: None,
+            init_sha256: None,
+            init: Init::Catatonit,
+            containerfile: None,
+        };
+        let runtime = FakeRuntime::new();
+        runtime.fail(&["image", "inspect"]);
+        runtime.reply(&["image", "pull"], "5f2c3e1a\n");
+        assert!(runtime
+            .run(prepare_image_offline(&lock, Path::new(""), dir.path()))
+            .await
+            .is_err());
+
+        std::fs::write(
+            dir.path().join("index.json"),
+            format!(
+                r#"{{"schemaVersion":2,"manifests":[{{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"sha256:aa","size":1,"annotations":{{"org.opencontainers.image.ref.name":"{tag}"}}}}]}}"#
+            ),
+        )?;
+        let id = runtime
+            .run(prepare_image_offline(&lock, Path::new(""), dir.path()))
+            .await?;
+        assert_eq!(id, "5f2c3e1a");
+        let layout = format!("oci:{}:{tag}", dir.path().display());
+        assert!(runtime
+            .commands()
+            .contains(&format!("image pull --quiet -- {layout}")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prepare_built_image() -> Result<()> {
+        // the lockfile is in a subdirectory of the working directory, the
+        // containerfile and the build context are relative to the project
+        let dir = tempfile::tempdir()?;
+        let project = dir.path().join("sub");
+        std::fs::create_dir_all(project.join("ci"))?;
+        let path = "ci/Containerfile".to_string();
+        std::fs::write(project.join(&path), "FROM scratch\nRUN true\n")?;
+        let base = "docker.io/library/debian@sha256:3d868b5eb908155f3784317b3dda2941df87bbbbaa4608f84881de66d9bb297b";
+        let mut lock = ContainerLock {
+            image: base.to_string(),
+            sha256: None,
+            init_sha256: None,
+            init: Init::Catatonit,
+            containerfile: Some(ContainerfileLock {
+                path: path.clone(),
+                sha256: utils::sha256_file(&project.join(&path))?,
+                image_id: "7a1c9e2b".to_string(),
+            }),
+        };
+
+        // the image is still in local storage
+        let runtime = FakeRuntime::new();
+        runtime.reply(&["image", "inspect"], r#"[{"Digest": "sha256:aa"}]"#);
+        assert_eq!(
+            runtime.run(prepare_image(&lock, &project)).await?,
+            "7a1c9e2b"
+        );
+        assert!(!runtime.commands().iter().any(|cmd| cmd.contains("build")));
+
+        // or it's built again, with the pinned image as base
+        let runtime = FakeRuntime::new();
+        runtime.fail(&["image", "inspect"]);
+        runtime.reply(&["image", "build"], "STEP 1/2: FROM scratch\n7a1c9e2b\n");
+        assert_eq!(
+            runtime.run(prepare_image(&lock, &project)).await?,
+            "7a1c9e2b"
+        );
+        let commands = runtime.commands();
+        assert!(commands.contains(&format!(
+            "image build --quiet --timestamp=0 --from={base} --file={} -- {}",
+            project.join(&path).display(),
+            project.display(),
+        )));
+
+        // a build on another machine results in a different id, that's only a warning
+        let runtime = FakeRuntime::new();
+        runtime.fail(&["image", "inspect"]);
+        runtime.reply(&["image", "build"], "0b5d3f88\n");
+        assert_eq!(
+            runtime.run(prepare_image(&lock, &project)).await?,
+            "0b5d3f88"
+        );
+
+        // and so does the containerfile
+        lock.containerfile.as_mut().unwrap().sha256 = "00".to_string();
+        let runtime = FakeRuntime::new();
+        runtime.fail(&["image", "inspect"]);
+        let err = runtime
+            .run(prepare_image(&lock, &project))
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Mismatch of sha256 for containerfile"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prepare_image_archive() -> Result<()> {
+        // the lockfile is in a subdirectory of the working directory, the
+        // archive is relative to the project
+        let dir = tempfile::tempdir()?;
+        let project = dir.path().join("sub");
+        std::fs::create_dir_all(&project)?;
+        std::fs::write(project.join("builder.tar"), "image")?;
+        let mut lock = ContainerLock {
+            image: "oci-archive:./builder.tar".to_string(),
+            sha256: Some(utils::sha256_file(&project.join("builder.tar"))?),
+            init_sha256: None,
+            init: Init::Catatonit,
+            containerfile: None,
+        };
+
+        let runtime = FakeRuntime::new();
+        runtime.reply(&["image", "pull"], "5f2c3e1a\n");
+        assert_eq!(
+            runtime.run(prepare_image(&lock, &project)).await?,
+            "5f2c3e1a"
+        );
+        assert!(runtime.commands().contains(&format!(
+            "image pull --quiet -- oci-archive:{}",
+            project.join("./builder.tar").display()
+        )));
+
+        lock.sha256 = Some("00".to_string());
+        let runtime = FakeRuntime::new();
+        let err = runtime
+            .run(prepare_image(&lock, &project))
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Mismatch of sha256 for image archive"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tee() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("stdout");
+        let file = create_tee_file(Some(&path)).await?.unwrap();
+        let input = vec![b'x'; 20_000];
+        let mut terminal = Vec::new();
+        tee(&input[..], &mut terminal, file).await?;
+        assert_eq!(terminal, input);
+        assert_eq!(std::fs::read(&path)?, input);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_container_list() -> Result<()> {
+        let list = serde_json::from_str::<Vec<ContainerInfo>>(
+            r#"[{"AutoRemove":true,"Id":"0b5e5a1f4cc5","Image":"docker.io/library/debian@sha256:3d868b5eb908155f3784317b3dda2941df87bbbbaa4608f84881de66d9bb297b","Labels":{"io.repro-env.project":"/home/user/project","io.repro-env.version":"0.4.1"},"State":"running"},{"Id":"1c6f6b2e5dd6","Image":"alpine","Labels":null}]"#,
+        )?;
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].label(LABEL_PROJECT), Some("/home/user/project"));
+        assert_eq!(list[0].state, "running");
+        assert_eq!(list[1].label(LABEL_PROJECT), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mount_podman_arg() {
+        assert_eq!(
+            Mount::new("/home/user/project", "/build").to_podman_arg(),
+            "--mount=type=bind,src=/home/user/project,dst=/build"
+        );
+        assert_eq!(
+            Mount::read_only("/home/user/project", "/build/src").to_podman_arg(),
+            "--mount=type=bind,src=/home/user/project,dst=/build/src,ro"
+        );
+        assert_eq!(
+            Mount::new("/home/user/my project: v2", "/build").to_podman_arg(),
+            "--mount=type=bind,src=/home/user/my project: v2,dst=/build"
+        );
+        assert_eq!(
+            Mount::new("/home/user/a,b", "/build").to_podman_arg(),
+            "--mount=type=bind,\"src=/home/user/a,b\",dst=/build"
+        );
+        assert_eq!(
+            Mount::read_only("/home/user/\"quoted\"", "/build").to_podman_arg(),
+            "--mount=type=bind,\"src=/home/user/\"\"quoted\"\"\",dst=/build,ro"
+        );
+        assert_eq!(
+            Mount::new("/home/user/projekt-übersicht", "/build").to_podman_arg(),
+            "--mount=type=bind,src=/home/user/projekt-übersicht,dst=/build"
+        );
+        let invalid = OsStr::from_bytes(b"/home/user/\xff,\xfe");
+        assert_eq!(
+            Mount::new(invalid, "/build").to_podman_arg().as_bytes(),
+            b"--mount=type=bind,\"src=/home/user/\xff,\xfe\",dst=/build"
+        );
+    }
+
+    #[test]
+    fn test_archive_path() {
+        assert_eq!(
+            archive_path("oci-archive:./builder.tar"),
+            Some("./builder.tar")
+        );
+        assert_eq!(
+            archive_path("docker-archive:/tmp/builder.tar"),
+            Some("/tmp/builder.tar")
+        );
+        assert_eq!(archive_path("rust:1-alpine3.18"), None);
+        assert_eq!(archive_path("docker.io/library/rust"), None);
+
+        let (path, image) = project_archive("oci-archive:./builder.tar", Path::new("sub")).unwrap();
+        assert_eq!(path, Path::new("sub/./builder.tar"));
+        assert_eq!(image, "oci-archive:sub/./builder.tar");
+        let (path, image) =
+            project_archive("docker-archive:/tmp/builder.tar", Path::new("sub")).unwrap();
+        assert_eq!(path, Path::new("/tmp/builder.tar"));
+        assert_eq!(image, "docker-archive:/tmp/builder.tar");
+        assert_eq!(project_archive("rust:1-alpine3.18", Path::new("sub")), None);
+    }
+
+    #[test]
+    fn test_parse_podman_version() -> Result<()> {
+        let version = "podman version 4.3.1\n".parse::<PodmanVersion>()?;
+        assert_eq!(version, PodmanVersion::new(4, 3, 1));
+        assert!(version.supports_cp_stdio());
+
+        let version = "podman version 5.0.0-rc1".parse::<PodmanVersion>()?;
+        assert_eq!(version, PodmanVersion::new(5, 0, 0));
+
+        let version = "podman version 2.1".parse::<PodmanVersion>()?;
+        assert_eq!(version, PodmanVersion::new(2, 1, 0));
+        assert!(!version.supports_cp_stdio());
+        assert!(version >= MIN_PODMAN_VERSION);
+
+        assert!("podman version".parse::<PodmanVersion>().is_err());
+        assert!("podman version foo".parse::<PodmanVersion>().is_err());
+        Ok(())
+    }
 
     #[test]
     fn test_parse_image_ref() -> Result<()> {
@@ -0,0 +1,157 @@
+use crate::errors::*;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Files that list paths that should not be copied into the build context,
+/// the first one that exists is used
+pub const IGNORE_FILES: &[&str] = &[".reproenvignore", ".dockerignore"];
+
+/// A copy of the source tree that is mounted instead of the current directory,
+/// so files listed in an ignore file never become visible to the build.
+#[derive(Debug, Default)]
+pub struct CopyContext {
+    copied: HashMap<PathBuf, (u64, SystemTime)>,
+}
+
+fn fingerprint(metadata: &fs::Metadata) -> Result<(u64, SystemTime)> {
+    Ok((metadata.len(), metadata.modified()?))
+}
+
+pub fn copy_entry(src: &Path, dest: &Path, file_type: fs::FileType) -> Result<()> {
+    if file_type.is_dir() {
+        fs::create_dir_all(dest)?;
+    } else if file_type.is_symlink() {
+        let target = fs::read_link(src)?;
+        std::os::unix::fs::symlink(target, dest)?;
+    } else if file_type.is_file() {
+        fs::copy(src, dest)?;
+    } else {
+        debug!("Skipping special file: {src:?}");
+    }
+    Ok(())
+}
+
+impl CopyContext {
+    pub fn copy_into(src: &Path, dest: &Path) -> Result<Self> {
+        let mut walk = ignore::WalkBuilder::new(src);
+        walk.standard_filters(false).current_dir(src);
+        if let Some(path) = IGNORE_FILES
+            .iter()
+            .map(|name| src.join(name))
+            .find(|path| path.exists())
+        {
+            info!("Using ignore file for build context: {path:?}");
+            if let Some(err) = walk.add_ignore(&path) {
+                return Err(err).context("Failed to load ignore file");
+            }
+        }
+
+        let mut ctx = CopyContext::default();
+        for entry in walk.build() {
+            let entry = entry.context("Failed to walk build context")?;
+            let path = entry.path();
+            let rel = path.strip_prefix(src)?;
+            if rel.as_os_str().is_empty() {
+                continue;
+            }
+            let file_type = entry.file_type().context("Failed to detect file type")?;
+
+            let target = dest.join(rel);
+            trace!("Copying into build context: {rel:?}");
+            copy_entry(path, &target, file_type)
+                .with_context(|| anyhow!("Failed to copy {path:?} to {target:?}"))?;
+
+            if file_type.is_file() {
+                let metadata = fs::metadata(&target)?;
+                ctx.copied.insert(rel.to_owned(), fingerprint(&metadata)?);
+            }
+        }
+
+        debug!("Copied {} files into build context", ctx.copied.len());
+        Ok(ctx)
+    }
+
+    /// Copy files that have been created or modified by the build back to
+    /// the source tree, returns the number of copied files
+    pub fn copy_back(&self, staged: &Path, dest: &Path) -> Result<usize> {
+        let mut walk = ignore::WalkBuilder::new(staged);
+        walk.standard_filters(false);
+
+        let mut count = 0;
+        for entry in walk.build() {
+            let entry = entry.context("Failed to walk build context")?;
+            let path = entry.path();
+            let rel = path.strip_prefix(staged)?;
+            if rel.as_os_str().is_empty() {
+                continue;
+            }
+            let file_type = entry.file_type().context("Failed to detect file type")?;
+
+            if file_type.is_file() {
+                let metadata = fs::symlink_metadata(path)?;
+                if self.copied.get(rel) == Some(&fingerprint(&metadata)?) {
+                    continue;
+                }
+            } else if !file_type.is_dir() || dest.join(rel).is_dir() {
+                continue;
+            }
+
+            let target = dest.join(rel);
+            trace!("Copying from build context: {rel:?}");
+            if file_type.is_file() && target.is_symlink() {
+                fs::remove_file(&target)?;
+            }
+            copy_entry(path, &target, file_type)
+                .with_context(|| anyhow!("Failed to copy {path:?} to {target:?}"))?;
+            if file_type.is_file() {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_context() -> Result<()> {
+        let src = tempfile::tempdir()?;
+        let staged = tempfile::tempdir()?;
+
+        fs::write(src.path().join(".reproenvignore"), "/target\n*.secret\n")?;
+        fs::write(src.path().join("main.c"), "int main() {}\n")?;
+        fs::write(src.path().join("token.secret"), "hunter2\n")?;
+        fs::create_dir_all(src.path().join("src/target"))?;
+        fs::write(src.path().join("src/target/lib.c"), "\n")?;
+        fs::create_dir_all(src.path().join("target/release"))?;
+        fs::write(src.path().join("target/release/main"), "old\n")?;
+
+        let ctx = CopyContext::copy_into(src.path(), staged.path())?;
+        assert!(staged.path().join("main.c").exists());
+        assert!(staged.path().join("src/target/lib.c").exists());
+        assert!(!staged.path().join("token.secret").exists());
+        assert!(!staged.path().join("target").exists());
+
+        // simulate the build
+        fs::create_dir_all(staged.path().join("target/release"))?;
+        fs::write(staged.path().join("target/release/main"), "new\n")?;
+
+        let count = ctx.copy_back(staged.path(), src.path())?;
+        assert_eq!(count, 1);
+        assert_eq!(
+            fs::read_to_string(src.path().join("target/release/main"))?,
+            "new\n"
+        );
+        assert_eq!(
+            fs::read_to_string(src.path().join("token.secret"))?,
+            "hunter2\n"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,240 @@
+//! HTTP basic auth for package mirrors that are hosted behind authentication,
+//! configured in `credentials.toml` instead of the manifest so they never end
+//! up in a lockfile
+use crate::errors::*;
+use crate::paths;
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::path::Path;
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Credentials {
+    #[serde(rename = "mirror", default)]
+    pub mirrors: Vec<MirrorCredential>,
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorCredential {
+    /// Requests to urls starting with this prefix are authenticated
+    pub url: String,
+    pub username: String,
+    pub password: Option<String>,
+    /// Read the password from this environment variable instead
+    pub password_env: Option<String>,
+}
+
+impl fmt::Debug for MirrorCredential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MirrorCredential")
+            .field("url", &self.url)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("password_env", &self.password_env)
+            .finish()
+    }
+}
+
+/// If `url` starts with `prefix`, without matching a longer path segment
+pub fn url_matches(prefix: &str, url: &str) -> bool {
+    let Some(rest) = url.strip_prefix(prefix) else {
+        return false;
+    };
+    // https://example.com/repo must not match https://example.com/repository
+    prefix.ends_with('/') || rest.is_empty() || rest.starts_with(['/', '?'])
+}
+
+impl MirrorCredential {
+    fn matches(&self, url: &str) -> bool {
+        url_matches(&self.url, url)
+    }
+
+    pub fn password(&self) -> Result<String> {
+        match (&self.password, &self.password_env) {
+            (Some(password), None) => Ok(password.clone()),
+            (None, Some(name)) => env::var(name).with_context(|| {
+                anyhow!(
+                    "Failed to read password for mirror {:?} from environment variable {name:?}",
+                    self.url
+                )
+            }),
+            _ => bail!(
+                "Credentials for mirror {:?} need exactly one of password or password_env",
+                self.url
+            ),
+        }
+    }
+}
+
+impl Credentials {
+    pub fn deserialize(buf: &str) -> Result<Self> {
+        let credentials =
+            toml::from_str::<Self>(buf).context("Failed to load credentials from toml")?;
+        for mirror in &credentials.mirrors {
+            if !mirror.url.starts_with("http://") && !mirror.url.starts_with("https://") {
+                bail!(
+                    "Mirror url must start with http:// or https://: {:?}",
+                    mirror.url
+                );
+            }
+            if mirror.password.is_some() == mirror.password_env.is_some() {
+                bail!(
+                    "Credentials for mirror {:?} need exactly one of password or password_env",
+                    mirror.url
+                );
+            }
+        }
+        Ok(credentials)
+    }
+
+    pub fn read_from_file(path: &Path) -> Result<Self> {
+        let buf = std::fs::read_to_string(path)
+            .with_context(|| anyhow!("Failed to read credentials file: {path:?}"))?;
+        Self::deserialize(&buf).with_context(|| anyhow!("Invalid credentials file: {path:?}"))
+    }
+
+    /// Load `credentials.toml`, it's fine if it doesn't exist
+    pub fn load() -> Result<Self> {
+        let path = paths::credentials_path()?;
+        if !path.exists() {
+            return Ok(Credentials::default());
+        }
+        debug!("Loading mirror credentials from {path:?}");
+        Self::read_from_file(&path)
+    }
+
+    /// The credentials with the longest matching prefix for this url
+    pub fn lookup(&self, url: &str) -> Option<&MirrorCredential> {
+        self.mirrors
+            .iter()
+            .filter(|mirror| mirror.matches(url))
+            .max_by_key(|mirror| mirror.url.len())
+    }
+
+    /// Render the credentials as file for `/etc/apt/auth.conf.d/`, so apt in
+    /// the resolver container can access the same mirrors as repro-env
+    pub fn apt_auth_conf(&self) -> Result<String> {
+        let mut out = String::new();
+        for mirror in &self.mirrors {
+            let password = mirror.password()?;
+            // the format is whitespace separated, without any quoting
+            if mirror.username.contains(char::is_whitespace)
+                || password.contains(char::is_whitespace)
+            {
+                bail!(
+                    "Credentials for mirror {:?} can't contain whitespace to be used by apt",
+                    mirror.url
+                );
+            }
+            out.push_str(&format!(
+                "machine {} login {} password {password}\n",
+                mirror.url, mirror.username
+            ));
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() -> Result<()> {
+        let credentials = Credentials::deserialize(
+            r#"
+[[mirror]]
+url = "https://mirror.example.com/archlinux"
+username = "ci"
+password = "hunter2"
+
+[[mirror]]
+url = "https://mirror.example.com/archlinux/core/"
+username = "core"
+password_env = "REPRO_ENV_TEST_MIRROR_PASSWORD"
+"#,
+        )?;
+
+        let url = "https://mirror.example.com/archlinux/extra/os/x86_64/extra.db";
+        let mirror = credentials.lookup(url).unwrap();
+        assert_eq!(mirror.username, "ci");
+        assert_eq!(mirror.password()?, "hunter2");
+
+        let url = "https://mirror.example.com/archlinux/core/os/x86_64/core.db";
+        assert_eq!(credentials.lookup(url).unwrap().username, "core");
+
+        assert!(credentials
+            .lookup("https://mirror.example.com/archlinux-arm/core.db")
+            .is_none());
+        assert!(credentials
+            .lookup("https://geo.mirror.pkgbuild.com/core/os/x86_64/core.db")
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_apt_auth_conf() -> Result<()> {
+        let credentials = Credentials::deserialize(
+            r#"
+[[mirror]]
+url = "https://mirror.example.com/debian/"
+username = "ci"
+password = "hunter2"
+"#,
+        )?;
+        assert_eq!(
+            credentials.apt_auth_conf()?,
+            "machine https://mirror.example.com/debian/ login ci password hunter2\n"
+        );
+
+        let credentials = Credentials::deserialize(
+            r#"
+[[mirror]]
+url = "https://mirror.example.com/debian/"
+username = "ci"
+password = "correct horse"
+"#,
+        )?;
+        assert!(credentials.apt_auth_conf().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Credentials::deserialize(
+            r#"
+[[mirror]]
+url = "https://mirror.example.com/"
+username = "ci"
+"#
+        )
+        .is_err());
+        assert!(Credentials::deserialize(
+            r#"
+[[mirror]]
+url = "mirror.example.com"
+username = "ci"
+password = "hunter2"
+"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_debug_redacted() -> Result<()> {
+        let credentials = Credentials::deserialize(
+            r#"
+[[mirror]]
+url = "https://mirror.example.com/"
+username = "ci"
+password = "hunter2"
+"#,
+        )?;
+        let debug = format!("{credentials:?}");
+        assert!(!debug.contains("hunter2"));
+        assert!(debug.contains("<redacted>"));
+        Ok(())
+    }
+}
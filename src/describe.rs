@@ -0,0 +1,366 @@
+//! Map the pinned image digest of a lockfile back to the tags that currently
+//! point to it, so reviewers can tell which release a bare sha256 refers to
+use crate::args;
+use crate::container::{self, ImageRef};
+use crate::errors::*;
+use crate::freshness::{self, Repository};
+use crate::http;
+use crate::lockfile::Lockfile;
+use crate::platform::{self, Platform};
+use crate::sbom;
+use futures_util::stream::{self, StreamExt};
+use reqwest::header::LINK;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// How many tags are compared with the pinned digest at the same time
+const CONCURRENT_LOOKUPS: usize = 8;
+/// The number of tags requested per page of the tag list
+const TAGS_PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+struct TagList {
+    #[serde(default)]
+    tags: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageManifest {
+    #[serde(default)]
+    manifests: Vec<PlatformDescriptor>,
+    #[serde(default)]
+    config: Option<ConfigDescriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PlatformDescriptor {
+    digest: String,
+    #[serde(default)]
+    platform: Option<DescriptorPlatform>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DescriptorPlatform {
+    os: String,
+    architecture: String,
+    #[serde(default)]
+    variant: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfigDescriptor {
+    digest: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageConfig {
+    #[serde(default)]
+    created: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ImageDescription {
+    pub image: String,
+    /// When the image was built, according to its config
+    pub created: Option<String>,
+    /// The tags that currently point to the pinned digest
+    pub tags: Vec<String>,
+    /// How many tags have been compared with the pinned digest
+    pub checked: usize,
+}
+
+/// The url of the next page of a paginated registry response, from a
+/// `Link: </v2/library/debian/tags/list?last=bookworm&n=1000>; rel="next"` header
+pub fn parse_next_link(host: &str, header: &str) -> Option<String> {
+    header.split(',').find_map(|link| {
+        let (url, params) = link.split_once(';')?;
+        let is_next = params
+            .split(';')
+            .any(|param| matches!(param.trim(), "rel=\"next\"" | "rel=next"));
+        if !is_next {
+            return None;
+        }
+        let url = url.trim().strip_prefix('<')?.strip_suffix('>')?;
+        if url.starts_with('/') {
+            Some(format!("https://{host}{url}"))
+        } else {
+            Some(url.to_string())
+        }
+    })
+}
+
+/// List all tags of a repository, following the pagination of the registry
+pub async fn list_tags(client: &http::Client, repo: &Repository) -> Result<Vec<String>> {
+    let mut url = Some(format!(
+        "https://{}/v2/{}/tags/list?n={TAGS_PAGE_SIZE}",
+        repo.host, repo.name
+    ));
+    let mut tags = Vec::new();
+    while let Some(current) = url.take() {
+        debug!("Requesting tags of image: {current:?}");
+        let response = freshness::send(client, |client| client.get(&current))
+            .await?
+            .error_for_status()
+            .context("Received http error")?;
+        url = response
+            .headers()
+            .get(LINK)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|header| parse_next_link(&repo.host, header));
+        let buf = response.bytes().await.context("Failed to read http body")?;
+        let page = serde_json::from_slice::<TagList>(&buf)
+            .context("Failed to parse tag list of registry")?;
+        tags.extend(page.tags.unwrap_or_default());
+    }
+    Ok(tags)
+}
+
+/// The tags that are worth comparing, tags that look like they belong to the
+/// original tag (like `bookworm-20240701` for `bookworm`) if it's known
+pub fn candidate_tags(tags: Vec<String>, prefix: Option<&str>) -> Vec<String> {
+    let Some(prefix) = prefix else {
+        return tags;
+    };
+    tags.into_iter()
+        .filter(|tag| {
+            tag == prefix
+                || tag
+                    .strip_prefix(prefix)
+                    .is_some_and(|rest| rest.starts_with(['-', '.', '_']))
+        })
+        .collect()
+}
+
+/// Select the manifest of the host platform from an image index, or the only one if there's no choice
+fn select_manifest<'a>(
+    manifests: &'a [PlatformDescriptor],
+    host: &Platform,
+) -> Option<&'a PlatformDescriptor> {
+    manifests
+        .iter()
+        .find(|descriptor| {
+            descriptor.platform.as_ref().is_some_and(|p| {
+                host.matches(&Platform {
+                    os: p.os.clone(),
+                    arch: p.architecture.clone(),
+                    variant: p.variant.clone(),
+                })
+            })
+        })
+        .or(match manifests {
+            [single] => Some(single),
+            _ => None,
+        })
+}
+
+/// Read the build date from the image config, image indexes are resolved to
+/// the manifest of the host platform
+async fn image_created(
+    client: &http::Client,
+    repo: &Repository,
+    digest: &str,
+) -> Result<Option<String>> {
+    let mut digest = digest.to_string();
+    let host = platform::host();
+    loop {
+        let url = format!("https://{}/v2/{}/manifests/{digest}", repo.host, repo.name);
+        let manifest = sbom::fetch_json::<ImageManifest>(client, &url, freshness::MANIFEST_TYPES)
+            .await?
+            .with_context(|| anyhow!("Image manifest not found in registry: {digest:?}"))?;
+        if let Some(config) = manifest.config {
+            let buf = sbom::fetch_blob(client, repo, &config.digest).await?;
+            let config = serde_json::from_slice::<ImageConfig>(&buf)
+                .context("Failed to parse image config")?;
+            return Ok(config.created);
+        }
+        let Some(selected) = select_manifest(&manifest.manifests, &host) else {
+            debug!("Image index has no manifest for {host}, can't tell when it was created");
+            return Ok(None);
+        };
+        if selected.digest == digest {
+            bail!("Image index references itself: {digest:?}");
+        }
+        digest = selected.digest.clone();
+    }
+}
+
+/// Look up the tags and the build date of the pinned image in its registry
+pub async fn describe(
+    client: &http::Client,
+    image: &str,
+    prefix: Option<&str>,
+    limit: usize,
+) -> Result<ImageDescription> {
+    let image_ref = image.parse::<ImageRef>()?;
+    let digest = image_ref
+        .digest
+        .as_deref()
+        .with_context(|| anyhow!("Image in lockfile is not pinned by digest: {image:?}"))?;
+    let repo = Repository::parse(&image_ref.repo);
+
+    let created = match image_created(client, &repo, digest).await {
+        Ok(created) => created,
+        Err(err) => {
+            warn!("Failed to read creation date of image: {err:#}");
+            None
+        }
+    };
+
+    let mut candidates = candidate_tags(list_tags(client, &repo).await?, prefix);
+    if candidates.len() > limit {
+        warn!(
+            "Only comparing {limit} of {} tags with the pinned digest, use --limit to check more",
+            candidates.len()
+        );
+        // newer dated tags sort last, prefer them
+        candidates.drain(..candidates.len() - limit);
+    }
+    info!(
+        "Comparing {} tags of {:?} with the pinned digest...",
+        candidates.len(),
+        image_ref.repo
+    );
+
+    let checked = candidates.len();
+    let repo = &repo;
+    let mut tags = stream::iter(candidates)
+        .map(|tag| async move {
+            let current = freshness::remote_digest(client, repo, &tag).await;
+            (tag, current)
+        })
+        .buffer_unordered(CONCURRENT_LOOKUPS)
+        .filter_map(|(tag, current)| async move {
+            match current {
+                Ok(current) => (current == digest).then_some(tag),
+                Err(err) => {
+                    debug!("Failed to request digest of tag {tag:?}: {err:#}");
+                    None
+                }
+            }
+        })
+        .collect::<Vec<_>>()
+        .await;
+    tags.sort();
+
+    Ok(ImageDescription {
+        image: image.to_string(),
+        created,
+        tags,
+        checked,
+    })
+}
+
+pub async fn describe_image(describe_image: &args::LockDescribeImage) -> Result<()> {
+    let path = describe_image
+        .file
+        .as_deref()
+        .unwrap_or(Path::new("repro-env.lock"));
+    let lockfile = Lockfile::read_from_file(path).await?;
+    let image = &lockfile.container.image;
+    if container::archive_path(image).is_some() {
+        bail!("Image is loaded from an archive, it has no tags in a registry: {image:?}");
+    }
+
+    // the tag the lockfile was resolved from, dated tags usually start with it
+    let original_tag = lockfile
+        .meta
+        .as_ref()
+        .and_then(|meta| meta.image.parse::<ImageRef>().ok())
+        .and_then(|image| image.tag);
+    let prefix = if describe_image.all_tags {
+        None
+    } else {
+        describe_image.prefix.as_deref().or(original_tag.as_deref())
+    };
+
+    let client = http::Client::new()?;
+    let description = describe(&client, image, prefix, describe_image.limit).await?;
+
+    if describe_image.json {
+        println!("{}", serde_json::to_string(&description)?);
+        return Ok(());
+    }
+    println!("Image: {}", description.image);
+    if let Some(created) = &description.created {
+        println!("Created: {created}");
+    }
+    if description.tags.is_empty() {
+        println!(
+            "Tags: none of the {} checked tags point to this digest anymore",
+            description.checked
+        );
+    } else {
+        println!("Tags: {}", description.tags.join(", "));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_next_link() {
+        assert_eq!(
+            parse_next_link(
+                "registry-1.docker.io",
+                "</v2/library/debian/tags/list?last=bookworm&n=1000>; rel=\"next\""
+            ),
+            Some(
+                "https://registry-1.docker.io/v2/library/debian/tags/list?last=bookworm&n=1000"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            parse_next_link(
+                "ghcr.io",
+                "<https://ghcr.io/v2/foo/bar/tags/list?last=v1&n=1000>; rel=next"
+            ),
+            Some("https://ghcr.io/v2/foo/bar/tags/list?last=v1&n=1000".to_string())
+        );
+        assert_eq!(
+            parse_next_link("ghcr.io", "</v2/foo/bar/tags/list>; rel=\"prev\""),
+            None
+        );
+    }
+
+    #[test]
+    fn test_candidate_tags() {
+        let tags = [
+            "bookworm",
+            "bookworm-20240701",
+            "bookworm-slim",
+            "bookworm2",
+            "bullseye",
+            "latest",
+        ]
+        .map(String::from)
+        .to_vec();
+        assert_eq!(
+            candidate_tags(tags.clone(), Some("bookworm")),
+            ["bookworm", "bookworm-20240701", "bookworm-slim"]
+        );
+        assert_eq!(candidate_tags(tags.clone(), None), tags);
+    }
+
+    #[test]
+    fn test_select_manifest() -> Result<()> {
+        let index = serde_json::from_str::<ImageManifest>(
+            r#"{
+  "manifests": [
+    {"digest": "sha256:aaaa", "platform": {"architecture": "amd64", "os": "linux"}},
+    {"digest": "sha256:bbbb", "platform": {"architecture": "arm", "os": "linux", "variant": "v7"}},
+    {"digest": "sha256:cccc", "platform": {"architecture": "arm64", "os": "linux", "variant": "v8"}}
+  ]
+}"#,
+        )?;
+        let select = |platform: &str| -> Result<Option<String>> {
+            let platform = platform.parse::<Platform>()?;
+            Ok(select_manifest(&index.manifests, &platform).map(|d| d.digest.clone()))
+        };
+        assert_eq!(select("linux/amd64")?.as_deref(), Some("sha256:aaaa"));
+        assert_eq!(select("linux/arm64")?.as_deref(), Some("sha256:cccc"));
+        assert_eq!(select("linux/riscv64")?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,475 @@
+//! Compare two lockfiles, this is also used by tools that display lockfile
+//! changes (it doesn't need the `cli` feature)
+use crate::errors::*;
+use crate::lockfile::{Lockfile, PackageLock};
+use crate::version;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Package {
+    pub name: String,
+    /// The profile of the lockfile the package belongs to, if any
+    pub profile: Option<String>,
+    pub version: String,
+}
+
+impl Package {
+    /// The name of the package, with the profile if it's not part of the environment
+    pub fn label(&self) -> String {
+        label(self.profile.as_deref(), &self.name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Changed {
+    pub name: String,
+    /// The profile of the lockfile the package belongs to, if any
+    pub profile: Option<String>,
+    pub old: String,
+    pub new: String,
+}
+
+impl Changed {
+    /// The name of the package, with the profile if it's not part of the environment
+    pub fn label(&self) -> String {
+        label(self.profile.as_deref(), &self.name)
+    }
+}
+
+fn label(profile: Option<&str>, name: &str) -> String {
+    match profile {
+        Some(profile) => format!("{name} (profile {profile})"),
+        None => name.to_string(),
+    }
+}
+
+/// How a lockfile changed compared to the previous one. Packages that are
+/// already installed in the container image are not listed, they change
+/// together with the image.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Changes {
+    /// The container image, if it changed
+    pub container: Option<Changed>,
+    pub upgraded: Vec<Changed>,
+    pub downgraded: Vec<Changed>,
+    pub added: Vec<Package>,
+    pub removed: Vec<Package>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        *self == Changes::default()
+    }
+
+    /// Fail if a package has been downgraded, unless it's in `allowed`
+    pub fn deny_downgrades(&self, allowed: &[String]) -> Result<()> {
+        let denied = self
+            .downgraded
+            .iter()
+            .filter(|pkg| !allowed.contains(&pkg.name))
+            .map(|pkg| format!("{} {} -> {}", pkg.label(), pkg.old, pkg.new))
+            .collect::<Vec<_>>();
+        if !denied.is_empty() {
+            bail!(
+                "Refusing to downgrade {} (use --allow-downgrade if this is intended): {}",
+                count(denied.len()),
+                denied.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// A table of the changed packages, downgrades are flagged because they can be a rollback attack
+    pub fn format_markdown(&self) -> String {
+        if self.is_empty() {
+            return "No changes\n".to_string();
+        }
+        let mut out = String::new();
+        if let Some(container) = &self.container {
+            out.push_str(&format!(
+                "Container image: `{}` → `{}`\n\n",
+                container.old, container.new
+            ));
+        }
+        if self.upgraded.len() + self.downgraded.len() + self.added.len() + self.removed.len() == 0
+        {
+            return out;
+        }
+        out.push_str("| Package | Old version | New version | Change |\n");
+        out.push_str("| ------- | ----------- | ----------- | ------ |\n");
+        let mut row = |name: &str, old: &str, new: &str, change: &str| {
+            out.push_str(&format!("| {name} | {old} | {new} | {change} |\n"));
+        };
+        for pkg in &self.upgraded {
+            row(&pkg.label(), &pkg.old, &pkg.new, "upgraded");
+        }
+        for pkg in &self.downgraded {
+            row(&pkg.label(), &pkg.old, &pkg.new, "⚠️ **downgraded**");
+        }
+        for pkg in &self.added {
+            row(&pkg.label(), "", &pkg.version, "added");
+        }
+        for pkg in &self.removed {
+            row(&pkg.label(), &pkg.version, "", "removed");
+        }
+        out
+    }
+
+    pub fn format_text(&self) -> String {
+        if self.is_empty() {
+            return "No changes\n".to_string();
+        }
+        let mut out = String::new();
+        if let Some(container) = &self.container {
+            out.push_str(&format!(
+                "Container image: {} -> {}\n",
+                container.old, container.new
+            ));
+        }
+        let sections = [
+            ("Upgraded", &self.upgraded),
+            ("Downgraded", &self.downgraded),
+        ];
+        for (title, changed) in sections {
+            if !changed.is_empty() {
+                out.push_str(&format!("{title} {}:\n", count(changed.len())));
+            }
+            for pkg in changed {
+                out.push_str(&format!("    {} {} -> {}\n", pkg.label(), pkg.old, pkg.new));
+            }
+        }
+        for (title, list) in [("Added", &self.added), ("Removed", &self.removed)] {
+            if !list.is_empty() {
+                out.push_str(&format!("{title} {}:\n", count(list.len())));
+            }
+            for pkg in list {
+                out.push_str(&format!("    {} {}\n", pkg.label(), pkg.version));
+            }
+        }
+        out
+    }
+}
+
+/// A markdown report of the changes to `path`, `old` is None if the lockfile is new
+pub fn markdown_report(path: &str, old: Option<&Lockfile>, new: &Lockfile) -> String {
+    let mut out = format!("## `{path}`\n\n");
+    let changes = match old {
+        Some(old) => {
+            out.push_str(&format!(
+                "Lockfile digest: `{}` → `{}`\n\n",
+                old.digest(),
+                new.digest()
+            ));
+            changes(old, new)
+        }
+        None => {
+            out.push_str(&format!("New lockfile, digest: `{}`\n\n", new.digest()));
+            Changes {
+                added: packages(new)
+                    .iter()
+                    .map(|((profile, name), pkg)| Package {
+                        name: name.to_string(),
+                        profile: profile.map(String::from),
+                        version: pkg.version.clone(),
+                    })
+                    .collect(),
+                ..Default::default()
+            }
+        }
+    };
+    out.push_str(&changes.format_markdown());
+    out
+}
+
+fn count(n: usize) -> String {
+    if n == 1 {
+        "1 package".to_string()
+    } else {
+        format!("{n} packages")
+    }
+}
+
+/// The packages of the environment and of every profile, keyed by profile and name
+fn packages(lockfile: &Lockfile) -> BTreeMap<(Option<&str>, &str), &PackageLock> {
+    let profiles = lockfile.profiles.iter().flat_map(|(profile, lock)| {
+        lock.packages
+            .iter()
+            .map(move |p| (Some(profile.as_str()), p))
+    });
+    lockfile
+        .packages
+        .iter()
+        .map(|p| (None, p))
+        .chain(profiles)
+        .filter(|(_, p)| !p.installed)
+        .map(|(profile, p)| ((profile, p.name.as_str()), p))
+        .collect()
+}
+
+/// Pin the package `name` (of `profile`, if set) in `new` to the version of `old`
+/// again, e.g. because the change was rejected during an interactive update
+pub fn keep_previous(
+    new: &mut Lockfile,
+    old: &Lockfile,
+    profile: Option<&str>,
+    name: &str,
+) -> Result<()> {
+    let label = label(profile, name);
+    let previous = packages(old)
+        .get(&(profile, name))
+        .map(|pkg| (*pkg).clone())
+        .with_context(|| anyhow!("Package is not in the previous lockfile: {label:?}"))?;
+    let packages = match profile {
+        Some(profile) => new.profiles.get_mut(profile).map(|lock| &mut lock.packages),
+        None => Some(&mut new.packages),
+    };
+    let pkg = packages
+        .and_then(|packages| packages.iter_mut().find(|p| !p.installed && p.name == name))
+        .with_context(|| anyhow!("Package is not in the lockfile: {label:?}"))?;
+    *pkg = previous;
+    Ok(())
+}
+
+/// Compare the lockfile `new` with the previous lockfile `old`
+pub fn changes(old: &Lockfile, new: &Lockfile) -> Changes {
+    let old_pkgs = packages(old);
+    let new_pkgs = packages(new);
+
+    let mut changes = Changes::default();
+    if old.container.image != new.container.image {
+        changes.container = Some(Changed {
+            name: "container".to_string(),
+            profile: None,
+            old: old.container.image.clone(),
+            new: new.container.image.clone(),
+        });
+    }
+    for (key @ (profile, name), pkg) in &new_pkgs {
+        let Some(old) = old_pkgs.get(key) else {
+            changes.added.push(Package {
+                name: name.to_string(),
+                profile: profile.map(String::from),
+                version: pkg.version.clone(),
+            });
+            continue;
+        };
+        let changed = Changed {
+            name: name.to_string(),
+            profile: profile.map(String::from),
+            old: old.version.clone(),
+            new: pkg.version.clone(),
+        };
+        match version::compare(&pkg.system, &pkg.version, &old.version) {
+            Ordering::Greater => changes.upgraded.push(changed),
+            Ordering::Less => changes.downgraded.push(changed),
+            // only the notation changed, e.g. an explicit `0:` epoch
+            Ordering::Equal if old.version != pkg.version => changes.upgraded.push(changed),
+            Ordering::Equal => (),
+        }
+    }
+    for (key @ (profile, name), pkg) in &old_pkgs {
+        if !new_pkgs.contains_key(key) {
+            changes.removed.push(Package {
+                name: name.to_string(),
+                profile: profile.map(String::from),
+                version: pkg.version.clone(),
+            });
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lockfile(image: &str, packages: &[(&str, &str, bool)]) -> Result<Lockfile> {
+        let mut toml = format!("[container]\nimage = \"archlinux@sha256:{image}\"\n");
+        for (name, version, installed) in packages {
+            toml += &format!(
+                "\n[[package]]\nname = \"{name}\"\nversion = \"{version}\"\nsystem = \"archlinux\"\nurl = \"https://example.com/{name}\"\nsha256 = \"00\"\ninstalled = {installed}\n"
+            );
+        }
+        Lockfile::deserialize(&toml)
+    }
+
+    #[test]
+    fn test_changes() -> Result<()> {
+        let old = lockfile(
+            "00",
+            &[
+                ("glibc", "2.37-3", true),
+                ("rust", "1:1.70.0-1", false),
+                ("nodejs", "20.4.0-1", false),
+                ("gcc", "13.2.1-3", false),
+                ("make", "4.4.1-2", false),
+            ],
+        )?;
+        let new = lockfile(
+            "11",
+            &[
+                ("glibc", "2.38-1", true),
+                ("rust", "1:1.71.0-1", false),
+                ("zstd", "1.5.5-1", false),
+                ("gcc", "13.2.1-1", false),
+                ("make", "4.4.1-2", false),
+            ],
+        )?;
+        let changes = changes(&old, &new);
+        assert_eq!(
+            changes.format_text(),
+            "\
+Container image: archlinux@sha256:00 -> archlinux@sha256:11
+Upgraded 1 package:
+    rust 1:1.70.0-1 -> 1:1.71.0-1
+Downgraded 1 package:
+    gcc 13.2.1-3 -> 13.2.1-1
+Added 1 package:
+    zstd 1.5.5-1
+Removed 1 package:
+    nodejs 20.4.0-1
+"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_previous() -> Result<()> {
+        let old = lockfile(
+            "00",
+            &[("rust", "1:1.70.0-1", false), ("gcc", "13.2.1-3", false)],
+        )?;
+        let mut new = lockfile(
+            "00",
+            &[
+                ("rust", "1:1.71.0-1", false),
+                ("gcc", "13.2.1-4", false),
+                ("zstd", "1.5.5-1", false),
+            ],
+        )?;
+        keep_previous(&mut new, &old, None, "rust")?;
+        let changes = changes(&old, &new);
+        assert_eq!(
+            changes.upgraded,
+            [Changed {
+                name: "gcc".to_string(),
+                profile: None,
+                old: "13.2.1-3".to_string(),
+                new: "13.2.1-4".to_string(),
+            }]
+        );
+        // the package order of the new lockfile is kept
+        assert_eq!(new.packages[0], old.packages[0]);
+
+        assert!(keep_previous(&mut new, &old, None, "zstd").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_profile_changes() -> Result<()> {
+        let profile = |gdb: &str| -> Result<Lockfile> {
+            let mut lockfile = lockfile("00", &[("gdb", "14.1-1", false)])?;
+            let toml = format!(
+                "[container]\nimage = \"archlinux@sha256:00\"\n\n[[profile.debug.package]]\nname = \"gdb\"\nversion = \"{gdb}\"\nsystem = \"archlinux\"\nurl = \"https://example.com/gdb\"\nsha256 = \"00\"\n"
+            );
+            lockfile.profiles = Lockfile::deserialize(&toml)?.profiles;
+            Ok(lockfile)
+        };
+        let old = profile("14.1-1")?;
+        let mut new = profile("13.2-1")?;
+        let changes = changes(&old, &new);
+        assert_eq!(
+            changes.format_text(),
+            "\
+Downgraded 1 package:
+    gdb (profile debug) 14.1-1 -> 13.2-1
+"
+        );
+        assert_ne!(old.digest(), new.digest());
+        assert!(changes.deny_downgrades(&[]).is_err());
+
+        // only the package of the profile is pinned to the previous version again
+        keep_previous(&mut new, &old, Some("debug"), "gdb")?;
+        assert!(super::changes(&old, &new).is_empty());
+        assert!(keep_previous(&mut new, &old, Some("release"), "gdb").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_changes() -> Result<()> {
+        let old = lockfile("00", &[("rust", "1:1.70.0-1", false)])?;
+        let changes = changes(&old, &old);
+        assert!(changes.is_empty());
+        assert_eq!(changes.format_text(), "No changes\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_markdown_report() -> Result<()> {
+        let old = lockfile(
+            "00",
+            &[
+                ("gcc", "13.2.1-3", false),
+                ("nodejs", "20.4.0-1", false),
+                ("rust", "1:1.70.0-1", false),
+            ],
+        )?;
+        let new = lockfile(
+            "11",
+            &[
+                ("gcc", "13.2.1-1", false),
+                ("rust", "1:1.71.0-1", false),
+                ("zstd", "1.5.5-1", false),
+            ],
+        )?;
+        assert_eq!(
+            markdown_report("repro-env.lock", Some(&old), &new),
+            format!(
+                "\
+## `repro-env.lock`
+
+Lockfile digest: `{}` → `{}`
+
+Container image: `archlinux@sha256:00` → `archlinux@sha256:11`
+
+| Package | Old version | New version | Change |
+| ------- | ----------- | ----------- | ------ |
+| rust | 1:1.70.0-1 | 1:1.71.0-1 | upgraded |
+| gcc | 13.2.1-3 | 13.2.1-1 | ⚠️ **downgraded** |
+| zstd |  | 1.5.5-1 | added |
+| nodejs | 20.4.0-1 |  | removed |
+",
+                old.digest(),
+                new.digest()
+            )
+        );
+
+        let report = markdown_report("repro-env.lock", None, &new);
+        assert!(report.contains("\nNew lockfile, digest: `sha256:"));
+        assert!(report.ends_with("| zstd |  | 1.5.5-1 | added |\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_deny_downgrades() -> Result<()> {
+        let old = lockfile(
+            "00",
+            &[("gcc", "13.2.1-3", false), ("rust", "1:1.70.0-1", false)],
+        )?;
+        let new = lockfile(
+            "00",
+            &[("gcc", "13.2.1-1", false), ("rust", "1:1.71.0-1", false)],
+        )?;
+        let changes = changes(&old, &new);
+        let err = changes.deny_downgrades(&[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Refusing to downgrade 1 package (use --allow-downgrade if this is intended): gcc 13.2.1-3 -> 13.2.1-1"
+        );
+        assert!(changes.deny_downgrades(&["gcc".to_string()]).is_ok());
+        // the other way around rust is downgraded
+        assert!(super::changes(&new, &old).deny_downgrades(&[]).is_err());
+        Ok(())
+    }
+}
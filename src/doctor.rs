@@ -0,0 +1,201 @@
+//! Check if this machine is able to run builds, wrappers can call
+//! [`check_host`] to validate the host before invoking repro-env
+use crate::args;
+use crate::container::{self, MIN_PODMAN_VERSION};
+use crate::errors::*;
+use crate::http;
+use crate::paths;
+use crate::progress::format_bytes;
+use crate::utils;
+use serde::Serialize;
+use std::path::Path;
+use std::time::Duration;
+
+/// Less free space than this in the cache directory is reported as a warning
+pub const MIN_FREE_SPACE: u64 = 2 * 1024 * 1024 * 1024;
+/// Used to test if container registries are reachable
+const REGISTRY_URL: &str = "https://registry-1.docker.io/v2/";
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    /// Builds may work, but some features are not available
+    Warning,
+    /// Builds are not going to work
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub message: String,
+}
+
+impl Check {
+    fn new(name: &'static str, result: Result<String>, failed: Status) -> Self {
+        match result {
+            Ok(message) => Check {
+                name,
+                status: Status::Ok,
+                message,
+            },
+            Err(err) => Check {
+                name,
+                status: failed,
+                message: format!("{err:#}"),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    /// If none of the checks failed with an error
+    pub fn is_ok(&self) -> bool {
+        self.checks.iter().all(|c| c.status != Status::Error)
+    }
+
+    pub fn format_text(&self) -> String {
+        let width = self.checks.iter().map(|c| c.name.len()).max().unwrap_or(0);
+        let mut out = String::new();
+        for check in &self.checks {
+            let status = match check.status {
+                Status::Ok => "ok",
+                Status::Warning => "warning",
+                Status::Error => "error",
+            };
+            out.push_str(&format!(
+                "{:<width$}  {status:<7}  {}\n",
+                check.name, check.message
+            ));
+        }
+        out
+    }
+}
+
+async fn check_podman() -> Result<String> {
+    let version = container::podman_version().await?;
+    Ok(format!(
+        "podman {version} (at least {MIN_PODMAN_VERSION} is required)"
+    ))
+}
+
+async fn check_userns() -> Result<String> {
+    container::test_for_unprivileged_userns_clone().await?;
+    Ok("unprivileged user namespaces are available".to_string())
+}
+
+fn check_catatonit(path: &Path) -> Result<String> {
+    if !path.exists() {
+        bail!("{path:?} does not exist, it's used as init of the containers (install catatonit)");
+    }
+    Ok(format!("{path:?} exists"))
+}
+
+/// The cache directory may not exist yet, check the filesystem it's going to be created on
+fn check_disk(path: &Path, min_free: u64) -> Result<String> {
+    let existing = path
+        .ancestors()
+        .find(|p| p.exists())
+        .with_context(|| anyhow!("Failed to find existing parent of {path:?}"))?;
+    let available = utils::available_space(existing)?;
+    let msg = format!("{} available in {path:?}", format_bytes(available));
+    if available < min_free {
+        bail!("only {msg}, big environments need several gigabytes");
+    }
+    Ok(msg)
+}
+
+async fn check_network() -> Result<String> {
+    let client = http::Client::new()?;
+    let response = tokio::time::timeout(NETWORK_TIMEOUT, client.head(REGISTRY_URL).send())
+        .await
+        .with_context(|| anyhow!("Timeout while connecting to {REGISTRY_URL:?}"))?
+        .with_context(|| anyhow!("Failed to connect to {REGISTRY_URL:?}"))?;
+    // the registry requires authentication, any response means it's reachable
+    Ok(format!(
+        "{REGISTRY_URL} is reachable (http status {})",
+        response.status().as_u16()
+    ))
+}
+
+/// Run all checks, this doesn't fail but reports the problems it found
+pub async fn check_host() -> Report {
+    let disk = paths::cache_dir().and_then(|dir| check_disk(&dir, MIN_FREE_SPACE));
+    let checks = vec![
+        Check::new("podman", check_podman().await, Status::Error),
+        Check::new("userns", check_userns().await, Status::Error),
+        Check::new(
+            "catatonit",
+            check_catatonit(Path::new(container::CATATONIT)),
+            Status::Error,
+        ),
+        // builds may work with packages that are already cached
+        Check::new("disk", disk, Status::Warning),
+        Check::new("network", check_network().await, Status::Warning),
+    ];
+    Report { checks }
+}
+
+pub async fn doctor(doctor: &args::Doctor) -> Result<()> {
+    let report = check_host().await;
+    if doctor.json {
+        let json = serde_json::to_string_pretty(&report)?;
+        println!("{json}");
+    } else {
+        print!("{}", report.format_text());
+    }
+    if !report.is_ok() {
+        bail!("This machine is not able to run builds");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_disk() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("not/created/yet");
+        assert!(check_disk(&path, 0)?.ends_with(&format!(" available in {path:?}")));
+        assert!(check_disk(&path, u64::MAX).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_report() {
+        let report = Report {
+            checks: vec![
+                Check::new("podman", Ok("podman 4.9.3".to_string()), Status::Error),
+                Check::new(
+                    "catatonit",
+                    check_catatonit(Path::new("/nonexistent/catatonit")),
+                    Status::Error,
+                ),
+                Check::new("network", Err(anyhow!("offline")), Status::Warning),
+            ],
+        };
+        assert!(!report.is_ok());
+        assert_eq!(
+            report.format_text(),
+            "\
+podman     ok       podman 4.9.3
+catatonit  error    \"/nonexistent/catatonit\" does not exist, it's used as init of the containers (install catatonit)
+network    warning  offline
+"
+        );
+        assert!(Report {
+            checks: report.checks[2..].to_vec()
+        }
+        .is_ok());
+    }
+}
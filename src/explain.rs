@@ -0,0 +1,201 @@
+use crate::args;
+use crate::errors::*;
+use crate::lockfile::{Lockfile, PackageLock, SignatureLock};
+use crate::paths;
+use crate::pgp;
+use crate::progress::format_bytes;
+use data_encoding::BASE64;
+use std::path::Path;
+use time::format_description::well_known;
+use time::OffsetDateTime;
+
+pub fn origin(pkg: &PackageLock) -> Option<String> {
+    let url = pkg.url.parse::<reqwest::Url>().ok()?;
+    match pkg.system.as_str() {
+        "alpine" => {
+            // https://dl-cdn.alpinelinux.org/alpine/v3.18/main/x86_64/binutils-2.40-r7.apk
+            let (repo, _) = pkg.url.rsplit_once('/')?;
+            let (repo, _arch) = repo.rsplit_once('/')?;
+            Some(repo.to_string())
+        }
+        "debian" if url.host_str() == Some("snapshot.debian.org") => {
+            // https://snapshot.debian.org/archive/debian/20230115T211934Z/pool/...
+            let mut segments = url.path_segments()?.skip(1);
+            let archive = segments.next()?;
+            let timestamp = segments.next()?;
+            Some(format!("{archive} (snapshot {timestamp})"))
+        }
+        _ => url.host_str().map(String::from),
+    }
+}
+
+fn explain_signature(sig: &SignatureLock) -> Result<String> {
+    if sig.algorithm != SignatureLock::OPENPGP {
+        let issuer = sig.issuer.as_deref().unwrap_or("unknown");
+        return Ok(format!(
+            "algorithm={} issuer={issuer} (not verified)",
+            sig.algorithm
+        ));
+    }
+
+    let base64 = &sig.value;
+    let signature = BASE64
+        .decode(base64.as_bytes())
+        .with_context(|| anyhow!("Failed to decode signature as base64: {base64:?}"))?;
+    let info = pgp::inspect_signature(&signature)?;
+
+    let issuer = info.issuer.as_deref().unwrap_or("unknown");
+    let created = if let Some(time) = info.created {
+        OffsetDateTime::from(time).format(&well_known::Rfc3339)?
+    } else {
+        "unknown".to_string()
+    };
+
+    Ok(format!("issuer={issuer} created={created}"))
+}
+
+pub fn explain_package(pkg: &PackageLock) -> Result<String> {
+    let mut out = String::new();
+    let mut field = |key: &str, value: &str| {
+        out.push_str(&format!("{:<12}{value}\n", format!("{key}:")));
+    };
+
+    field("name", &pkg.name);
+    field("version", &pkg.version);
+    field("system", &pkg.system);
+    if let Some(purl) = pkg.purl() {
+        field("purl", &purl);
+    }
+    if let Some(origin) = origin(pkg) {
+        field("origin", &origin);
+    }
+    field("url", &pkg.url);
+    for url in &pkg.fallback_urls {
+        field("fallback", url);
+    }
+    if let Some(commit) = &pkg.commit {
+        field("commit", commit);
+    } else {
+        field("sha256", &pkg.sha256);
+    }
+    if let Some(size) = pkg.size {
+        field("size", &format_bytes(size));
+    }
+    if !pkg.provides.is_empty() {
+        field("provides", &pkg.provides.join(", "));
+    }
+
+    if pkg.signatures.is_empty() {
+        field("signature", "none");
+    }
+    for sig in &pkg.signatures {
+        match explain_signature(sig) {
+            Ok(signature) => field("signature", &signature),
+            Err(err) => field("signature", &format!("failed to parse ({err:#})")),
+        }
+    }
+
+    if pkg.has_package_file() {
+        let path = paths::pkgs_cache_dir()?.sha256_path(&pkg.sha256)?;
+        if path.exists() {
+            field("cached", &format!("yes ({})", path.display()));
+        } else {
+            field("cached", "no");
+        }
+    }
+
+    field(
+        "installed",
+        if pkg.installed {
+            "yes (already present in the container image)"
+        } else {
+            "no (installed during build)"
+        },
+    );
+
+    Ok(out)
+}
+
+pub async fn explain(explain: &args::Explain) -> Result<()> {
+    let path = explain
+        .file
+        .as_deref()
+        .unwrap_or(Path::new("repro-env.lock"));
+    let lockfile = Lockfile::read_from_file(path).await?;
+
+    let mut matches = lockfile
+        .packages
+        .iter()
+        .filter(|pkg| pkg.name == explain.name)
+        .collect::<Vec<_>>();
+
+    if matches.is_empty() {
+        matches = lockfile
+            .packages
+            .iter()
+            .filter(|pkg| pkg.provides.contains(&explain.name))
+            .collect();
+    }
+
+    if matches.is_empty() {
+        bail!(
+            "Could not find package in dependency lockfile: {:?}",
+            explain.name
+        );
+    }
+
+    for (i, pkg) in matches.into_iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        print!("{}", explain_package(pkg)?);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pkg(system: &str, url: &str) -> PackageLock {
+        PackageLock {
+            name: "binutils".to_string(),
+            version: "2.40-2".to_string(),
+            system: system.to_string(),
+            url: url.to_string(),
+            sha256: "83c3e20b53e1fbd84d764c3ba27d26a0376e361ae5d7fb37120196934dd87424".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_origin_debian() {
+        let pkg = pkg("debian", "https://snapshot.debian.org/archive/debian/20230115T211934Z/pool/main/b/binutils/binutils_2.40-2_amd64.deb");
+        assert_eq!(
+            origin(&pkg).as_deref(),
+            Some("debian (snapshot 20230115T211934Z)")
+        );
+    }
+
+    #[test]
+    fn test_origin_alpine() {
+        let pkg = pkg(
+            "alpine",
+            "https://dl-cdn.alpinelinux.org/alpine/v3.18/main/x86_64/binutils-2.40-r7.apk",
+        );
+        assert_eq!(
+            origin(&pkg).as_deref(),
+            Some("https://dl-cdn.alpinelinux.org/alpine/v3.18/main")
+        );
+    }
+
+    #[test]
+    fn test_origin_archlinux() {
+        let pkg = pkg(
+            "archlinux",
+            "https://archive.archlinux.org/packages/b/binutils/binutils-2.40-6-x86_64.pkg.tar.zst",
+        );
+        assert_eq!(origin(&pkg).as_deref(), Some("archive.archlinux.org"));
+    }
+}
@@ -0,0 +1,574 @@
+//! A container runtime for tests that records podman commands and replies with
+//! canned outputs, so the orchestration code can be tested without podman
+use crate::container::{self, ContainerRuntime, ExecConfig};
+use crate::errors::*;
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+pub const CONTAINER_ID: &str = "8f1c3b5e9a2d4f6071829304a5b6c7d8e9f0a1b2c3d4e5f60718293a4b5c6d7e";
+pub const PODMAN_VERSION: &str = "podman version 4.9.3\n";
+pub const CONTAINER_RUNNING: &str = r#"[{"State": {"Status": "running", "Running": true, "Pid": 4242, "ExitCode": 0, "Error": ""}}]"#;
+
+/// A podman invocation that was received by the fake runtime
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    pub args: Vec<String>,
+    pub stdin: Option<Vec<u8>>,
+}
+
+impl Call {
+    pub fn contains(&self, pattern: &[&str]) -> bool {
+        pattern.is_empty()
+            || self
+                .args
+                .windows(pattern.len())
+                .any(|window| window.iter().zip(pattern).all(|(a, b)| a == b))
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Reply {
+    Output(Vec<u8>),
+    Fail(String),
+}
+
+#[derive(Debug)]
+struct Rule {
+    pattern: Vec<String>,
+    /// Replies are used in order, the last one is repeated
+    replies: VecDeque<Reply>,
+}
+
+#[derive(Debug, Default)]
+pub struct FakeRuntime {
+    rules: Mutex<Vec<Rule>>,
+    calls: Mutex<Vec<Call>>,
+}
+
+impl FakeRuntime {
+    /// A runtime that knows how to report its version and create containers,
+    /// every other command succeeds with empty output
+    pub fn new() -> Arc<Self> {
+        let runtime = Self::default();
+        runtime.reply(&["--version"], PODMAN_VERSION);
+        runtime.reply(&["container", "run"], format!("{CONTAINER_ID}\n"));
+        runtime.reply(&["container", "inspect"], CONTAINER_RUNNING);
+        Arc::new(runtime)
+    }
+
+    fn push(&self, pattern: &[&str], reply: Reply) {
+        let pattern = pattern.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let mut rules = self.rules.lock().unwrap();
+        if let Some(rule) = rules.iter_mut().find(|r| r.pattern == pattern) {
+            rule.replies.push_back(reply);
+        } else {
+            rules.push(Rule {
+                pattern,
+                replies: VecDeque::from([reply]),
+            });
+        }
+    }
+
+    /// Reply to commands containing `pattern` with `out`. Registering the same
+    /// pattern again queues another reply for the next invocation.
+    pub fn reply<B: Into<Vec<u8>>>(&self, pattern: &[&str], out: B) {
+        self.push(pattern, Reply::Output(out.into()));
+    }
+
+    /// Commands containing `pattern` exit with an error
+    pub fn fail(&self, pattern: &[&str]) {
+        self.fail_with(pattern, "");
+    }
+
+    /// Commands containing `pattern` exit with an error and print `stderr`
+    pub fn fail_with(&self, pattern: &[&str], stderr: &str) {
+        self.push(pattern, Reply::Fail(stderr.to_string()));
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// The recorded commands, with arguments joined by spaces
+    pub fn commands(&self) -> Vec<String> {
+        self.calls()
+            .into_iter()
+            .map(|call| call.args.join(" "))
+            .collect()
+    }
+
+    /// The commands that have been executed inside of a container
+    pub fn execs(&self) -> Vec<String> {
+        let prefix = format!("{CONTAINER_ID} ");
+        self.commands()
+            .into_iter()
+            .filter_map(|cmd| {
+                let (_, cmd) = cmd.split_once(&prefix)?;
+                Some(cmd.to_string())
+            })
+            .collect()
+    }
+
+    /// Run a future with all podman commands going to this runtime
+    pub async fn run<F: Future>(self: &Arc<Self>, fut: F) -> F::Output {
+        container::with_runtime(self.clone(), fut).await
+    }
+
+    fn handle(&self, call: Call, config: &ExecConfig) -> Result<Vec<u8>> {
+        let reply = {
+            let mut rules = self.rules.lock().unwrap();
+            // rules registered later take precedence
+            rules
+                .iter_mut()
+                .rev()
+                .find(|rule| {
+                    let pattern = rule.pattern.iter().map(String::as_str).collect::<Vec<_>>();
+                    call.contains(&pattern)
+                })
+                .map(|rule| {
+                    if rule.replies.len() > 1 {
+                        rule.replies.pop_front().unwrap()
+                    } else {
+                        rule.replies[0].clone()
+                    }
+                })
+        };
+
+        let args = call.args.iter().map(OsString::from).collect();
+        self.calls.lock().unwrap().push(call);
+
+        let (stdout, stderr) = match &reply {
+            Some(Reply::Output(out)) => (&out[..], ""),
+            Some(Reply::Fail(stderr)) => (&[][..], stderr.as_str()),
+            None => (&[][..], ""),
+        };
+        if let Some(path) = &config.tee_stdout {
+            std::fs::write(path, stdout)?;
+        }
+        if let Some(path) = &config.tee_stderr {
+            std::fs::write(path, stderr)?;
+        }
+
+        match reply {
+            // teed output goes to the terminal, it's not returned
+            Some(Reply::Output(_)) if config.tee_stdout.is_some() => Ok(vec![]),
+            Some(Reply::Output(out)) => Ok(out),
+            Some(Reply::Fail(stderr)) => Err(container::CommandError {
+                args,
+                code: Some(1),
+                stderr,
+                stdout: vec![],
+            }
+            .into()),
+            None => Ok(vec![]),
+        }
+    }
+}
+
+impl ContainerRuntime for FakeRuntime {
+    fn podman<'a>(
+        &'a self,
+        args: &'a [OsString],
+        config: &'a ExecConfig,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>> {
+        let call = Call {
+            args: args
+                .iter()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            stdin: config.stdin.clone(),
+        };
+        Box::pin(async move { self.handle(call, config) })
+    }
+}
+
+/// A tar archive with a single file, like `podman container cp` writes to stdout
+pub fn tar_file(path: &str, content: &[u8]) -> Vec<u8> {
+    let mut tar = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    tar.append_data(&mut header, path, content).unwrap();
+    tar.into_inner().unwrap()
+}
+
+/// Read the files of a tar archive, like one passed to `podman container cp` on stdin
+pub fn untar(buf: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    use std::io::Read;
+
+    let mut files = Vec::new();
+    let mut tar = tar::Archive::new(buf);
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        files.push((path, content));
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container::{Container, Mount};
+    use crate::manifest::Init;
+    use crate::summary::{self, Summary};
+
+    #[tokio::test]
+    async fn test_create_container() -> Result<()> {
+        let runtime = FakeRuntime::new();
+        let mounts = [Mount::read_only("/src", "/build")];
+        let container = runtime
+            .run(Container::create(
+                "alpine@sha256:00",
+                container::Config {
+                    mounts: &mounts,
+                    expose_fuse: false,
+                    lockfile_digest: Some("1234"),
+                    prepared: true,
+                    platform: None,
+                    podman_args: &["--ulimit=nofile=1024".to_string()],
+                    read_only_root: false,
+                    init: Init::Catatonit,
+                },
+            ))
+            .await?;
+        assert_eq!(container.id, CONTAINER_ID);
+
+        let calls = runtime.calls();
+        let run = calls
+            .iter()
+            .find(|call| call.contains(&["container", "run"]))
+            .context("No container was created")?;
+        assert!(run.contains(&["--label=io.repro-env.lockfile-digest=1234"]));
+        assert!(run.contains(&["--label=io.repro-env.prepared=1"]));
+        assert!(run.contains(&[&mounts[0].to_podman_arg().to_string_lossy()]));
+        assert!(run.contains(&["--ulimit=nofile=1024", "--", "alpine@sha256:00", "-P"]));
+        assert!(calls
+            .last()
+            .unwrap()
+            .contains(&["container", "inspect", "--", CONTAINER_ID]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_container_exited() {
+        let runtime = FakeRuntime::new();
+        let inspect = ["container", "inspect", "--", CONTAINER_ID];
+        runtime.reply(
+            &inspect,
+            r#"[{"State": {"Status": "exited", "Running": false, "Pid": 0, "ExitCode": 127, "Error": ""}}]"#,
+        );
+        runtime.fail(&inspect);
+        let config = || container::Config {
+            mounts: &[],
+            expose_fuse: false,
+            lockfile_digest: None,
+            prepared: false,
+            platform: None,
+            podman_args: &[],
+            read_only_root: false,
+            init: Init::Catatonit,
+        };
+        let err = runtime
+            .run(Container::create("alpine@sha256:00", config()))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Container {CONTAINER_ID:?} did not start (status \"exited\", exit code 127)")
+        );
+
+        // removed by --rm before it could be inspected
+        let err = runtime
+            .run(Container::create("alpine@sha256:00", config()))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Container {CONTAINER_ID:?} exited right after it was created")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_read_only_container() -> Result<()> {
+        let runtime = FakeRuntime::new();
+        runtime
+            .run(Container::create(
+                "alpine@sha256:00",
+                container::Config {
+                    mounts: &[],
+                    expose_fuse: false,
+                    lockfile_digest: None,
+                    prepared: false,
+                    platform: None,
+                    podman_args: &[],
+                    read_only_root: true,
+                    init: Init::Catatonit,
+                },
+            ))
+            .await?;
+        let calls = runtime.calls();
+        let run = calls
+            .iter()
+            .find(|call| call.contains(&["container", "run"]))
+            .context("No container was created")?;
+        assert!(run.contains(&["--read-only", "--tmpfs=/tmp"]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_container_without_catatonit() -> Result<()> {
+        for (init, entrypoint, cmd) in [
+            (
+                Init::Podman,
+                &["--init", "--entrypoint=sleep"][..],
+                &["alpine@sha256:00", "2147483647"][..],
+            ),
+            (
+                Init::Image,
+                &[][..],
+                &["alpine@sha256:00", "sleep", "2147483647"][..],
+            ),
+        ] {
+            let runtime = FakeRuntime::new();
+            runtime
+                .run(Container::create(
+                    "alpine@sha256:00",
+                    container::Config {
+                        mounts: &[],
+                        expose_fuse: false,
+                        lockfile_digest: None,
+                        prepared: false,
+                        platform: None,
+                        podman_args: &[],
+                        read_only_root: false,
+                        init,
+                    },
+                ))
+                .await?;
+            let calls = runtime.calls();
+            let run = calls
+                .iter()
+                .find(|call| call.contains(&["container", "run"]))
+                .context("No container was created")?;
+            assert!(!run.contains(&["--entrypoint=/__"]));
+            assert_eq!(run.contains(&["--init"]), init == Init::Podman);
+            assert!(run.contains(entrypoint));
+            assert_eq!(run.args[run.args.len() - cmd.len()..], *cmd);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_exec_recorded_in_summary() {
+        let runtime = FakeRuntime::new();
+        runtime.fail(&["summary-test-fail"]);
+        let container = Container {
+            id: CONTAINER_ID.to_string(),
+        };
+        {
+            let _phase = summary::phase("install");
+            runtime
+                .run(container.exec(&["summary-test-ok"], container::Exec::default()))
+                .await
+                .unwrap();
+        }
+        runtime
+            .run(container.exec(&["summary-test-fail", "x"], container::Exec::default()))
+            .await
+            .unwrap_err();
+
+        // other tests record commands (and start phases) concurrently
+        let commands = Summary::collect()
+            .commands
+            .into_iter()
+            .filter(|cmd| cmd.cmd[0].starts_with("summary-test-"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            commands
+                .iter()
+                .map(|cmd| (cmd.cmd.join(" "), cmd.exit_code))
+                .collect::<Vec<_>>(),
+            [
+                ("summary-test-ok".to_string(), Some(0)),
+                ("summary-test-fail x".to_string(), Some(1)),
+            ]
+        );
+        assert!(commands[0].phase.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_write_and_read_file() -> Result<()> {
+        let runtime = FakeRuntime::new();
+        runtime.reply(
+            &[
+                "container",
+                "cp",
+                "--",
+                &format!("{CONTAINER_ID}:/etc/os-release"),
+                "-",
+            ],
+            tar_file("os-release", b"ID=alpine\n"),
+        );
+        let container = Container {
+            id: CONTAINER_ID.to_string(),
+        };
+
+        let content = runtime
+            .run(async {
+                let content = container.cat("/etc/os-release").await?;
+                container
+                    .write_file("/etc/apk/keys/", "test.rsa.pub", b"key")
+                    .await?;
+                container.kill().await?;
+                Ok::<_, Error>(content)
+            })
+            .await?;
+        assert_eq!(content, b"ID=alpine\n");
+
+        let calls = runtime.calls();
+        let stdin = calls
+            .iter()
+            .find_map(|call| call.stdin.as_ref())
+            .context("No file was written")?;
+        assert_eq!(
+            untar(stdin)?,
+            [("test.rsa.pub".to_string(), b"key".to_vec())]
+        );
+        assert!(calls
+            .last()
+            .unwrap()
+            .contains(&["container", "kill", CONTAINER_ID]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_exec_args_verbatim() -> Result<()> {
+        let runtime = FakeRuntime::new();
+        let container = Container {
+            id: CONTAINER_ID.to_string(),
+        };
+        let cmd = ["sh", "-c", "echo \"$1\" 'x  y'", "--", "grüße 🦀", ""];
+        runtime
+            .run(container.exec(&cmd, container::Exec::default()))
+            .await?;
+
+        let calls = runtime.calls();
+        let args = &calls.last().unwrap().args;
+        assert_eq!(args[args.len() - cmd.len() - 1..][0], CONTAINER_ID);
+        assert_eq!(args[args.len() - cmd.len()..], cmd);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_exec_tee() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let stdout = dir.path().join("sbom.json");
+        let stderr = dir.path().join("sbom.json.stderr");
+        let runtime = FakeRuntime::new();
+        runtime.reply(&["cargo", "sbom"], "{}\n");
+
+        let container = Container {
+            id: CONTAINER_ID.to_string(),
+        };
+        let out = runtime
+            .run(container.exec(
+                ["cargo", "sbom"],
+                container::Exec {
+                    tee_stdout: Some(&stdout),
+                    tee_stderr: Some(&stderr),
+                    ..Default::default()
+                },
+            ))
+            .await?;
+        assert_eq!(out, b"");
+        assert_eq!(std::fs::read(&stdout)?, b"{}\n");
+        assert_eq!(std::fs::read(&stderr)?, b"");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stop_forwards_signal() -> Result<()> {
+        let runtime = FakeRuntime::new();
+        let container = Container {
+            id: CONTAINER_ID.to_string(),
+        };
+        let timeout = std::time::Duration::from_millis(10);
+
+        let stopped = runtime
+            .run(container.stop(std::pin::pin!(async {}), "INT", timeout))
+            .await;
+        assert!(stopped);
+        let stopped = runtime
+            .run(container.stop(
+                std::pin::pin!(std::future::pending::<()>()),
+                "TERM",
+                timeout,
+            ))
+            .await;
+        assert!(!stopped);
+        assert_eq!(
+            runtime.execs(),
+            [
+                "sh -c kill -s \"$1\" -1 sh INT",
+                "sh -c kill -s \"$1\" -1 sh TERM"
+            ]
+        );
+
+        runtime.fail(&["INT"]);
+        let stopped = runtime
+            .run(container.stop(std::pin::pin!(async {}), "INT", timeout))
+            .await;
+        assert!(!stopped);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replies_in_order() -> Result<()> {
+        let runtime = FakeRuntime::new();
+        runtime.reply(&["apk", "info"], "musl-1.2.4-r1\n");
+        runtime.reply(&["apk", "info"], "musl-1.2.4-r1\nzlib-1.2.13-r1\n");
+        runtime.fail(&["apk", "upgrade"]);
+
+        let container = Container {
+            id: CONTAINER_ID.to_string(),
+        };
+        let exec = |args: &'static [&'static str]| {
+            let container = &container;
+            async move {
+                container
+                    .exec(args, container::Exec::default())
+                    .await
+                    .map(|out| String::from_utf8(out).unwrap())
+            }
+        };
+        runtime
+            .run(async {
+                assert_eq!(exec(&["apk", "info"]).await?, "musl-1.2.4-r1\n");
+                assert_eq!(
+                    exec(&["apk", "info"]).await?,
+                    "musl-1.2.4-r1\nzlib-1.2.13-r1\n"
+                );
+                assert_eq!(
+                    exec(&["apk", "info"]).await?,
+                    "musl-1.2.4-r1\nzlib-1.2.13-r1\n"
+                );
+                assert!(exec(&["apk", "upgrade"]).await.is_err());
+                assert_eq!(exec(&["true"]).await?, "");
+                Ok::<_, Error>(())
+            })
+            .await?;
+
+        assert_eq!(
+            runtime.execs(),
+            ["apk info", "apk info", "apk info", "apk upgrade", "true"]
+        );
+        Ok(())
+    }
+}
@@ -1,114 +1,577 @@
 use crate::args;
+use crate::build;
 use crate::container;
 use crate::errors::*;
+use crate::git;
+use crate::hash;
 use crate::http;
-use crate::lockfile::{Lockfile, PackageLock};
-use crate::paths;
+use crate::install::Install;
+use crate::limits::Cancel;
+use crate::local::LocalConfig;
+use crate::lockfile::{ApkKeyLock, FileLock, FileSignatureLock, Lockfile, PackageLock};
+use crate::manifest;
+use crate::paths::{self, PkgsCacheDir};
+use crate::pgp;
 use crate::pkgs;
-use sha2::{Digest, Sha256};
+use crate::progress::{self, Progress};
+use crate::refs;
+use crate::sandbox;
+use crate::state;
+use crate::summary::{self, Origin, PackageSource};
+use crate::utils;
+use data_encoding::BASE64;
+use nix::errno::Errno;
+use nix::fcntl;
+use rayon::prelude::*;
+use sequoia_openpgp::parse::Parse;
+use sequoia_openpgp::Cert;
+use std::fmt;
+use std::io::Read;
+use std::iter;
+use std::os::fd::AsRawFd;
 use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
 use tokio::fs;
 use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::time;
 
-pub async fn download_dependencies(dependencies: &[PackageLock]) -> Result<()> {
+const DOWNLOAD_ATTEMPTS: u32 = 3;
+
+/// Reserve the disk space for a download upfront, so we don't run out of space
+/// halfway through (and the file isn't fragmented)
+fn preallocate<F: AsRawFd>(file: &F, size: u64) -> Result<()> {
+    let len = i64::try_from(size).context("Package size is too large")?;
+    match fcntl::posix_fallocate(file.as_raw_fd(), 0, len) {
+        Ok(()) => Ok(()),
+        Err(Errno::ENOSPC) => bail!("Not enough free disk space to download package"),
+        Err(err) => {
+            debug!("Failed to pre-allocate file, ignoring: {err:#}");
+            Ok(())
+        }
+    }
+}
+
+/// Parse a size like `500MiB` or `2G`, units are powers of 1024
+pub fn parse_size(s: &str) -> Result<u64> {
+    let (num, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let num = num
+        .parse::<u64>()
+        .with_context(|| anyhow!("Invalid size, expected a number with unit: {s:?}"))?;
+    let factor = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KiB" => 1 << 10,
+        "M" | "MiB" => 1 << 20,
+        "G" | "GiB" => 1 << 30,
+        "T" | "TiB" => 1 << 40,
+        _ => bail!("Invalid unit of size, expected B, KiB, MiB, GiB or TiB: {s:?}"),
+    };
+    num.checked_mul(factor)
+        .with_context(|| anyhow!("Size is too large: {s:?}"))
+}
+
+/// How much is going to be downloaded, printed before fetching
+#[derive(Debug, Default, PartialEq)]
+pub struct DownloadEstimate {
+    pub packages: usize,
+    pub download_bytes: u64,
+    pub cached_bytes: u64,
+    /// Packages that need to be downloaded, but their size is unknown
+    pub unknown: usize,
+}
+
+impl fmt::Display for DownloadEstimate {
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            w,
+            "{} packages, {} to download, {} cached",
+            self.packages,
+            progress::format_bytes(self.download_bytes),
+            progress::format_bytes(self.cached_bytes)
+        )?;
+        if self.unknown > 0 {
+            write!(w, " (size of {} packages unknown)", self.unknown)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sizes are taken from the lockfile, a HEAD request is sent for packages
+/// that were locked without one
+async fn estimate(
+    client: &http::Client,
+    pkgs_cache_dir: &PkgsCacheDir,
+    dependencies: &[PackageLock],
+) -> Result<DownloadEstimate> {
+    let mut estimate = DownloadEstimate {
+        packages: dependencies.len(),
+        ..Default::default()
+    };
+    for package in dependencies {
+        let path = pkgs_cache_dir.sha256_path(&package.sha256)?;
+        if let Ok(metadata) = fs::metadata(&path).await {
+            estimate.cached_bytes += metadata.len();
+            continue;
+        }
+        let size = match package.size {
+            Some(size) => Some(size),
+            None => client
+                .content_length(&package.url)
+                .await
+                .map_err(|err| debug!("Failed to determine size of {:?}: {err:#}", package.url))
+                .ok()
+                .flatten(),
+        };
+        match size {
+            Some(size) => estimate.download_bytes += size,
+            None => estimate.unknown += 1,
+        }
+    }
+    Ok(estimate)
+}
+
+/// Ask before exceeding `--download-limit`, unless `--assume-yes` was given
+fn confirm_download(estimate: &DownloadEstimate, download: &args::DownloadArgs) -> Result<()> {
+    let Some(limit) = download.download_limit else {
+        return Ok(());
+    };
+    if estimate.download_bytes <= limit || download.assume_yes {
+        return Ok(());
+    }
+    let size = progress::format_bytes(estimate.download_bytes);
+    let limit = progress::format_bytes(limit);
+    if !utils::confirm(&format!(
+        "Downloading {size} exceeds the limit of {limit}, continue?"
+    ))? {
+        bail!("Downloading {size} exceeds --download-limit of {limit}, use --assume-yes to download anyway");
+    }
+    Ok(())
+}
+
+/// What a download is checked against, for packages and `[[file]]` pins alike
+struct Pin<'a> {
+    name: &'a str,
+    sha256: &'a str,
+    size: Option<u64>,
+}
+
+impl<'a> From<&'a PackageLock> for Pin<'a> {
+    fn from(package: &'a PackageLock) -> Self {
+        Pin {
+            name: &package.name,
+            sha256: &package.sha256,
+            size: package.size,
+        }
+    }
+}
+
+impl<'a> From<&'a FileLock> for Pin<'a> {
+    fn from(file: &'a FileLock) -> Self {
+        Pin {
+            name: &file.name,
+            sha256: &file.sha256,
+            size: Some(file.size),
+        }
+    }
+}
+
+pub async fn download_dependencies(
+    dependencies: &[PackageLock],
+    download: &args::DownloadArgs,
+) -> Result<()> {
+    // flatpak runtimes are pulled while installing
+    let dependencies = &dependencies
+        .iter()
+        .filter(|p| p.has_package_file())
+        .cloned()
+        .collect::<Vec<_>>();
     let client = http::Client::new()?;
     let pkgs_cache_dir = paths::pkgs_cache_dir()?;
 
+    let mut missing = Vec::new();
+    for package in dependencies {
+        if !pkgs_cache_dir.sha256_path(&package.sha256)?.exists() {
+            missing.push(package);
+        }
+    }
+    let total_bytes = utils::total_size(missing.iter().copied());
+    if !missing.is_empty() {
+        cleanup_partial_downloads(&pkgs_cache_dir);
+        let estimate = estimate(&client, &pkgs_cache_dir, dependencies).await?;
+        info!("{estimate}");
+        confirm_download(&estimate, download)?;
+        utils::check_free_space(&paths::cache_dir()?, total_bytes)?;
+    }
+
+    let mut failures = Vec::new();
+    let mut progress = Progress::new("Fetched", missing.len(), false);
+    if missing.iter().all(|p| p.size.is_some()) {
+        progress = progress.with_total_bytes(total_bytes);
+    }
+
     for package in dependencies {
         trace!("Found dependencies: {package:?}");
         let path = pkgs_cache_dir.sha256_path(&package.sha256)?;
+        summary::record_cache(path.exists());
+        let source = |origin, url| PackageSource {
+            name: package.name.clone(),
+            version: package.version.clone(),
+            sha256: package.sha256.clone(),
+            origin,
+            url,
+        };
         if path.exists() {
             debug!(
                 "Package already in cache: {:?} {:?}",
                 package.name, package.version
             );
+            summary::record_package_source(source(Origin::Cache, None));
         } else {
-            let parent = path
-                .parent()
-                .context("Failed to determine parent directory")?;
-            fs::create_dir_all(parent).await.with_context(|| {
-                anyhow!("Failed to create parent directories for file: {path:?}")
-            })?;
-
-            let mut dl_path = path.clone();
-            dl_path.as_mut_os_string().push(".tmp");
-
-            let file = fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(false)
-                .open(&dl_path)
-                .await?;
-
-            let mut lock = fd_lock::RwLock::new(file);
-            debug!("Trying to acquire write lock for file: {path:?}");
-            let mut lock = lock
-                .write()
-                .with_context(|| anyhow!("Failed to acquire lock for {dl_path:?}"))?;
-
-            // check if file became available in meantime
-            if path.exists() {
-                debug!("File became available in the meantime, nothing to do");
-            } else {
-                debug!(
-                    "Downloading package into cache: {:?} {:?}",
-                    package.name, package.version
+            match download_with_fallbacks(&client, &path, package, &mut progress).await {
+                Ok(url) => summary::record_package_source(source(Origin::Download, Some(url))),
+                Err(err) => failures.push((package, err)),
+            }
+            progress.inc();
+        }
+    }
+
+    if !failures.is_empty() {
+        error!(
+            "Failed to download {} of {} packages:",
+            failures.len(),
+            missing.len()
+        );
+        for (package, err) in &failures {
+            error!(
+                "  {} {} from {:?}: {err:#}",
+                package.name, package.version, package.url
+            );
+        }
+        bail!("Failed to download {} packages", failures.len());
+    }
+
+    Ok(())
+}
+
+/// Download from the url of the package, the fallback urls are tried if that fails.
+/// The checksum is verified regardless of where the package came from, returns
+/// the url that was used.
+async fn download_with_fallbacks(
+    client: &http::Client,
+    path: &Path,
+    package: &PackageLock,
+    progress: &mut Progress,
+) -> Result<String> {
+    let pin = Pin::from(package);
+    let mut urls = iter::once(&package.url)
+        .chain(&package.fallback_urls)
+        .peekable();
+    while let Some(url) = urls.next() {
+        match download_with_retries(client, path, &pin, url, progress).await {
+            Ok(()) => return Ok(url.clone()),
+            Err(err) => {
+                let Some(next) = urls.peek() else {
+                    return Err(err);
+                };
+                warn!(
+                    "Failed to download {:?} from {url:?}, trying {next:?}: {err:#}",
+                    package.name
                 );
-                lock.set_len(0).await.context("Failed to truncate file")?;
-                lock.rewind()
-                    .await
-                    .context("Failed to rewind file to beginning")?;
+            }
+        }
+    }
+    bail!("Package has no url to download from: {:?}", package.name)
+}
 
-                let mut response = client.request(&package.url).await.with_context(|| {
-                    anyhow!("Failed to download package from url: {:?}", package.url)
-                })?;
+/// Retry failed downloads a few times before giving up on a url
+async fn download_with_retries(
+    client: &http::Client,
+    path: &Path,
+    pin: &Pin<'_>,
+    url: &str,
+    progress: &mut Progress,
+) -> Result<()> {
+    let mut attempt = 1;
+    loop {
+        match download_package(client, path, pin, url, progress).await {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt < DOWNLOAD_ATTEMPTS => {
+                warn!(
+                    "Failed to download {:?} (attempt {attempt}/{DOWNLOAD_ATTEMPTS}), retrying: {err:#}",
+                    pin.name
+                );
+                time::sleep(Duration::from_secs(attempt.into())).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
 
-                let mut hasher = Sha256::new();
-                while let Some(chunk) = response
-                    .chunk()
-                    .await
-                    .context("Failed to read from download stream")?
-                {
-                    lock.write_all(&chunk)
-                        .await
-                        .context("Failed to write to downloaded data to disk")?;
-                    hasher.update(&chunk);
-                }
-                let result = hex::encode(hasher.finalize());
-
-                if package.sha256 != result {
-                    lock.set_len(0)
-                        .await
-                        .context("Mismatch of sha256, failed to truncate file")?;
-                    bail!(
-                        "Mismatch of sha256, expected={:?}, downloaded={:?}",
-                        package.sha256,
-                        result
-                    );
-                }
-
-                lock.sync_all()
-                    .await
-                    .context("Failed to sync downloaded data to disk")?;
-                fs::rename(&dl_path, &path)
+/// A `.tmp` file this recent might belong to a download that didn't lock it yet
+const PARTIAL_DOWNLOAD_MIN_AGE: Duration = Duration::from_secs(60);
+
+/// Remove the `.tmp` files of failed or interrupted downloads before starting new ones
+fn cleanup_partial_downloads(pkgs_cache_dir: &PkgsCacheDir) {
+    match pkgs_cache_dir.remove_partial(PARTIAL_DOWNLOAD_MIN_AGE) {
+        Ok((0, _)) => (),
+        Ok((removed, freed)) => info!(
+            "Removed {removed} stale partial downloads ({})",
+            progress::format_bytes(freed)
+        ),
+        Err(err) => warn!("Failed to remove stale partial downloads: {err:#}"),
+    }
+}
+
+async fn download_package(
+    client: &http::Client,
+    path: &Path,
+    package: &Pin<'_>,
+    url: &str,
+    progress: &mut Progress,
+) -> Result<()> {
+    let parent = path
+        .parent()
+        .context("Failed to determine parent directory")?;
+    fs::create_dir_all(parent)
+        .await
+        .with_context(|| anyhow!("Failed to create parent directories for file: {path:?}"))?;
+
+    let mut dl_path = path.to_path_buf();
+    dl_path.as_mut_os_string().push(".tmp");
+
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&dl_path)
+        .await?;
+
+    let mut lock = fd_lock::RwLock::new(file);
+    debug!("Trying to acquire write lock for file: {path:?}");
+    let mut lock = lock
+        .write()
+        .with_context(|| anyhow!("Failed to acquire lock for {dl_path:?}"))?;
+
+    // check if file became available in meantime
+    if path.exists() {
+        debug!("File became available in the meantime, nothing to do");
+    } else {
+        debug!("Downloading into cache: {:?}", package.name);
+        lock.set_len(0).await.context("Failed to truncate file")?;
+        lock.rewind()
+            .await
+            .context("Failed to rewind file to beginning")?;
+        if let Some(size) = package.size {
+            preallocate(&*lock, size)?;
+        }
+
+        let mut response = client
+            .request(url)
+            .await
+            .with_context(|| anyhow!("Failed to download package from url: {url:?}"))?;
+
+        let mut hasher = hash::Sha256::new();
+        let mut written = 0;
+        while let Some(chunk) = client.chunk(&mut response).await? {
+            written += chunk.len() as u64;
+            if package.size.is_some_and(|size| written > size) {
+                lock.set_len(0)
                     .await
-                    .with_context(|| anyhow!("Failed to rename {dl_path:?} to {path:?}"))?;
+                    .context("Download too large, failed to truncate file")?;
+                bail!(
+                    "Download is larger than the size in the lockfile ({:?} bytes)",
+                    package.size
+                );
             }
+            lock.write_all(&chunk)
+                .await
+                .context("Failed to write to downloaded data to disk")?;
+            hasher.update(&chunk);
+            summary::record_download(chunk.len() as u64);
+            progress.add_bytes(chunk.len() as u64);
+        }
+        let result = hasher.finalize();
+
+        // the file was pre-allocated, make sure it doesn't end with zeroes
+        if package.size.is_some_and(|size| written != size) {
+            lock.set_len(0)
+                .await
+                .context("Mismatch of size, failed to truncate file")?;
+            bail!(
+                "Mismatch of size, expected={:?}, downloaded={written}",
+                package.size
+            );
         }
+
+        if package.sha256 != result {
+            lock.set_len(0)
+                .await
+                .context("Mismatch of sha256, failed to truncate file")?;
+            bail!(
+                "Mismatch of sha256, expected={:?}, downloaded={:?}",
+                package.sha256,
+                result
+            );
+        }
+
+        lock.sync_all()
+            .await
+            .context("Failed to sync downloaded data to disk")?;
+        fs::rename(&dl_path, path)
+            .await
+            .with_context(|| anyhow!("Failed to rename {dl_path:?} to {path:?}"))?;
     }
 
     Ok(())
 }
 
-pub fn verify_pin_metadata(pkg: &[u8], pin: &PackageLock) -> Result<()> {
-    let pkg = match pin.system.as_str() {
-        "alpine" => pkgs::alpine::parse(pkg).context("Failed to parse data as alpine package")?,
-        "archlinux" => {
-            pkgs::archlinux::parse(pkg).context("Failed to parse data as archlinux package")?
+/// Download a url of `[[files]]` into the cache and pin it by its sha256, the
+/// download is skipped if `expected` is given and already in the cache
+pub async fn pin_file(
+    client: &http::Client,
+    name: &str,
+    url: &str,
+    expected: Option<&str>,
+) -> Result<FileLock> {
+    let pkgs_cache_dir = paths::pkgs_cache_dir()?;
+    if let Some(sha256) = expected {
+        let path = pkgs_cache_dir.sha256_path(sha256)?;
+        if let Ok(metadata) = fs::metadata(&path).await {
+            debug!("File already in cache: {name:?}");
+            summary::record_cache(true);
+            return Ok(FileLock {
+                name: name.to_string(),
+                url: url.to_string(),
+                sha256: sha256.to_string(),
+                size: metadata.len(),
+                signature: None,
+            });
         }
-        "debian" => pkgs::debian::parse(pkg).context("Failed to parse data as debian package")?,
-        system => bail!("Unknown package system: {system:?}"),
-    };
+    }
+
+    info!("Downloading {name:?} from {url:?}...");
+    let cache_dir = paths::cache_dir()?;
+    fs::create_dir_all(&cache_dir)
+        .await
+        .with_context(|| anyhow!("Failed to create cache directory: {cache_dir:?}"))?;
+    let temp = tempfile::NamedTempFile::new_in(&cache_dir)?;
+    let mut file = fs::File::from_std(temp.reopen()?);
+
+    let mut response = client
+        .request(url)
+        .await
+        .with_context(|| anyhow!("Failed to download file from url: {url:?}"))?;
+    let mut hasher = hash::Sha256::new();
+    let mut size = 0;
+    while let Some(chunk) = client.chunk(&mut response).await? {
+        size += chunk.len() as u64;
+        file.write_all(&chunk)
+            .await
+            .context("Failed to write downloaded data to disk")?;
+        hasher.update(&chunk);
+        summary::record_download(chunk.len() as u64);
+    }
+    file.sync_all()
+        .await
+        .context("Failed to sync downloaded data to disk")?;
+    let sha256 = hasher.finalize();
+    if let Some(expected) = expected {
+        if expected != sha256 {
+            bail!("Mismatch of sha256 for {name:?}, expected={expected:?}, downloaded={sha256:?}");
+        }
+    }
+
+    let path = pkgs_cache_dir.sha256_path(&sha256)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .await
+            .with_context(|| anyhow!("Failed to create parent directories for file: {path:?}"))?;
+    }
+    temp.persist(&path)
+        .with_context(|| anyhow!("Failed to move download into cache: {path:?}"))?;
+
+    Ok(FileLock {
+        name: name.to_string(),
+        url: url.to_string(),
+        sha256,
+        size,
+        signature: None,
+    })
+}
+
+/// Download the `[[file]]` pins of the lockfile that are not in the cache yet
+pub async fn download_files(files: &[FileLock]) -> Result<()> {
+    let pkgs_cache_dir = paths::pkgs_cache_dir()?;
+    let mut missing = Vec::new();
+    for file in files {
+        let path = pkgs_cache_dir.sha256_path(&file.sha256)?;
+        summary::record_cache(path.exists());
+        if !path.exists() {
+            missing.push((file, path));
+        }
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let total_bytes = missing.iter().map(|(file, _)| file.size).sum();
+    utils::check_free_space(&paths::cache_dir()?, total_bytes)?;
+
+    let client = http::Client::new()?;
+    let mut progress = Progress::new("Fetched", missing.len(), false).with_total_bytes(total_bytes);
+    for (file, path) in missing {
+        download_with_retries(&client, &path, &Pin::from(file), &file.url, &mut progress)
+            .await
+            .with_context(|| anyhow!("Failed to download {:?} from {:?}", file.name, file.url))?;
+        progress.inc();
+    }
+    Ok(())
+}
+
+/// Check the cached `[[file]]` pins against their sha256, they have no signatures or metadata
+pub fn verify_files(files: &[FileLock]) -> Result<()> {
+    let pkgs_cache_dir = paths::pkgs_cache_dir()?;
+    for file in files {
+        let path = pkgs_cache_dir.sha256_path(&file.sha256)?;
+        let sha256 = utils::sha256_file(&path)?;
+        if sha256 != file.sha256 {
+            bail!(
+                "Mismatch of sha256 for {:?} in cache, expected={:?}, found={sha256:?}",
+                file.name,
+                file.sha256
+            );
+        }
+        if let Some(signature) = &file.signature {
+            verify_file_signature(signature, &path)
+                .with_context(|| anyhow!("Failed to verify signature of {:?}", file.name))?;
+        }
+    }
+    Ok(())
+}
+
+/// Verify the pinned signature of a `[[file]]` with the pinned certificate
+fn verify_file_signature(signature: &FileSignatureLock, path: &Path) -> Result<()> {
+    let cert = Cert::from_bytes(signature.cert.as_bytes())
+        .context("Failed to parse OpenPGP certificate of signature")?;
+    if cert.fingerprint().to_hex() != signature.fingerprint {
+        bail!(
+            "Certificate of signature doesn't match its fingerprint: {:?}",
+            signature.fingerprint
+        );
+    }
+    let sig = BASE64
+        .decode(signature.value.as_bytes())
+        .context("Failed to decode signature")?;
+    let data = std::fs::read(path).with_context(|| anyhow!("Failed to read file: {path:?}"))?;
+    pgp::verify_detached(&data, &sig, &[cert])
+}
+
+pub fn verify_pin_metadata<R: Read + Send>(pkg: R, pin: &PackageLock) -> Result<()> {
+    if !pin.has_embedded_metadata() {
+        return Ok(());
+    }
+    let pkg = sandbox::inspect(&pin.system, pkg)?;
 
     debug!("Parsed embedded metadata from package: {pkg:?}");
 
@@ -128,27 +591,174 @@ pub fn verify_pin_metadata(pkg: &[u8], pin: &PackageLock) -> Result<()> {
         );
     }
 
+    if let Some(arch) = &pkg.arch {
+        if !pkgs::is_native_arch(&pin.system, arch) {
+            bail!(
+                "Package architecture doesn't match this system: expected={:?}, embedded={arch:?}",
+                pkgs::native_arch(&pin.system).unwrap_or(std::env::consts::ARCH)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// The checks a package has to pass before it's used for a build (signatures,
+/// embedded metadata), returns the files that need to be placed next to it in /extra
+pub fn verify_package(
+    install: &Install,
+    package: &PackageLock,
+    filename: &str,
+    path: &Path,
+) -> Result<Vec<(String, Vec<u8>)>> {
+    let extra_files = install.verify(package, filename, path)?;
+    let file =
+        std::fs::File::open(path).with_context(|| anyhow!("Failed to open package: {path:?}"))?;
+    verify_pin_metadata(file, package)
+        .with_context(|| anyhow!("Failed to verify metadata for {filename:?}"))?;
+    Ok(extra_files)
+}
+
+/// Verify the cached packages the same way a build does
+pub fn verify_dependencies(
+    dependencies: &[PackageLock],
+    apk_keys: Vec<ApkKeyLock>,
+    cancel: &Cancel,
+) -> Result<()> {
+    let pkgs_cache_dir = paths::pkgs_cache_dir()?;
+    let install = Install {
+        apk_keys,
+        ..Default::default()
+    };
+
+    let dependencies = dependencies
+        .iter()
+        .filter(|p| p.has_package_file())
+        .collect::<Vec<_>>();
+    let progress = Mutex::new(Progress::new("Verified", dependencies.len(), false));
+    dependencies.par_iter().try_for_each(|package| {
+        let filename = build::filename_from_url(&package.url)?;
+        let path = pkgs_cache_dir.sha256_path(&package.sha256)?;
+        cancel
+            .scope(|| verify_package(&install, package, &filename, &path))
+            .with_context(|| anyhow!("Failed to verify package {:?}", package.name))?;
+        if let Ok(mut progress) = progress.lock() {
+            progress.inc();
+        }
+        Ok(())
+    })
+}
+
+/// Format packages as an aligned table, `is_cached` decides the last column
+pub fn format_list<F: Fn(&PackageLock) -> bool>(packages: &[PackageLock], is_cached: F) -> String {
+    let mut rows = vec![[
+        "NAME".to_string(),
+        "VERSION".to_string(),
+        "SYSTEM".to_string(),
+        "SIZE".to_string(),
+        "CACHED".to_string(),
+    ]];
+    for pkg in packages {
+        rows.push([
+            pkg.name.clone(),
+            pkg.version.clone(),
+            pkg.system.clone(),
+            pkg.size
+                .map(progress::format_bytes)
+                .unwrap_or_else(|| "-".to_string()),
+            if is_cached(pkg) { "yes" } else { "no" }.to_string(),
+        ]);
+    }
+
+    let mut widths = [0; 5];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut out = String::new();
+    for row in &rows {
+        let mut line = String::new();
+        for (i, (cell, width)) in row.iter().zip(widths).enumerate() {
+            if i > 0 {
+                line.push_str("  ");
+            }
+            line.push_str(&format!("{cell:<width$}"));
+        }
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/// Print the packages of the locked environment and whether they are already in the cache
+pub fn print_list(packages: &[PackageLock]) -> Result<()> {
+    let pkgs_cache_dir = paths::pkgs_cache_dir()?;
+    print!(
+        "{}",
+        format_list(packages, |pkg| {
+            pkgs_cache_dir
+                .sha256_path(&pkg.sha256)
+                .map(|path| path.exists())
+                .unwrap_or(false)
+        })
+    );
     Ok(())
 }
 
 pub async fn fetch(fetch: &args::Fetch) -> Result<()> {
+    let _cache_lock = utils::lock_shared(paths::cache_lock()?).await?;
+
     // load lockfile
-    let path = fetch.file.as_deref().unwrap_or(Path::new("repro-env.lock"));
-    let buf = fs::read_to_string(path)
+    let path = match &fetch.file {
+        Some(path) => path.clone(),
+        None => paths::find_project_file(
+            &manifest::lockfile_name(fetch.variant.as_deref()),
+            !fetch.no_search_parents,
+        )?,
+    };
+    LocalConfig::load_for_lockfile(&path)?;
+    let buf = fs::read_to_string(&path)
         .await
         .with_context(|| anyhow!("Failed to read dependency lockfile: {path:?}"))?;
 
-    let lockfile = Lockfile::deserialize(&buf)?;
+    let mut lockfile = Lockfile::deserialize(&buf)?;
     trace!("Loaded dependency lockfile from file: {lockfile:?}");
+    lockfile.select_profile(fetch.profile.as_deref())?;
+    let files = lockfile.all_files();
+    if let Err(err) = refs::record(&path, &lockfile.packages, &files).await {
+        warn!("Failed to record package references of lockfile: {err:#}");
+    }
 
     if !fetch.no_pull {
+        let _phase = summary::phase("pull");
+        let project = state::project_dir(&path)?;
         let image = &lockfile.container.image;
-        if let Err(err) = container::inspect(image).await {
+        if container::archive_path(image).is_some() {
+            container::prepare_image(&lockfile.container, &project).await?;
+        } else if let Err(err) = container::inspect(image).await {
             debug!("Could not find image in cache: {err:#}");
             container::pull(image).await?;
         } else {
             info!("Found container image in local cache: {image:?}");
         }
+        if lockfile.container.containerfile.is_some() {
+            container::prepare_image(&lockfile.container, &project).await?;
+        }
+    }
+
+    if fetch.export_image {
+        let image = &lockfile.container.image;
+        let layout = paths::images_cache_dir()?;
+        if container::archive_path(image).is_some() {
+            info!("Image is loaded from an archive, nothing to export: {image:?}");
+        } else if container::is_exported(image, &layout)? {
+            info!("Container image is already exported into the cache: {image:?}");
+        } else {
+            let _phase = summary::phase("export");
+            container::export_image(image, &layout).await?;
+        }
     }
 
     // ignore packages that are already present in the container
@@ -158,9 +768,138 @@ pub async fn fetch(fetch: &args::Fetch) -> Result<()> {
         .filter(|p| !p.installed)
         .collect::<Vec<_>>();
 
+    if fetch.list {
+        print_list(&dependencies)?;
+    }
+
     if !dependencies.is_empty() {
-        download_dependencies(&dependencies).await?;
+        let _phase = summary::phase("download");
+        download_dependencies(&dependencies, &fetch.download).await?;
+
+        let _phase = summary::phase("verify");
+        let apk_keys = lockfile.apk_keys;
+        utils::spawn_blocking(move |cancel| verify_dependencies(&dependencies, apk_keys, cancel))
+            .await?;
     }
 
-    Ok(())
+    if !files.is_empty() {
+        let _phase = summary::phase("download");
+        download_files(&files).await?;
+
+        let _phase = summary::phase("verify");
+        utils::spawn_blocking(move |_| verify_files(&files)).await?;
+    }
+
+    if !lockfile.git.is_empty() {
+        let _phase = summary::phase("download");
+        for repo in &lockfile.git {
+            git::checkout(repo)
+                .await
+                .with_context(|| anyhow!("Failed to check out git repository {:?}", repo.name))?;
+        }
+    }
+
+    summary::print(fetch.json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(name: &str, version: &str, size: Option<u64>) -> PackageLock {
+        PackageLock {
+            name: name.to_string(),
+            version: version.to_string(),
+            system: "debian".to_string(),
+            url: format!("https://example.com/{name}.deb"),
+            sha256: "00".repeat(32),
+            size,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_format_list() {
+        let packages = [
+            package("binutils", "2.40-2", Some(2 * 1024 * 1024)),
+            package("gcc-12", "12.2.0-14", None),
+        ];
+        let out = format_list(&packages, |pkg| pkg.name == "gcc-12");
+        assert_eq!(
+            out,
+            "\
+NAME      VERSION    SYSTEM  SIZE     CACHED
+binutils  2.40-2     debian  2.0 MiB  no
+gcc-12    12.2.0-14  debian  -        yes
+"
+        );
+    }
+
+    #[test]
+    fn test_parse_size() -> Result<()> {
+        assert_eq!(parse_size("4096")?, 4096);
+        assert_eq!(parse_size("500MiB")?, 500 * 1024 * 1024);
+        assert_eq!(parse_size("2G")?, 2 * 1024 * 1024 * 1024);
+        assert!(parse_size("1.5GiB").is_err());
+        assert!(parse_size("10MB").is_err());
+        assert!(parse_size("99999999999T").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_download_estimate() -> Result<()> {
+        let mut estimate = DownloadEstimate {
+            packages: 42,
+            download_bytes: 1395864371,
+            cached_bytes: 300 * 1024 * 1024,
+            unknown: 0,
+        };
+        assert_eq!(
+            estimate.to_string(),
+            "42 packages, 1.3 GiB to download, 300.0 MiB cached"
+        );
+
+        let mut download = args::DownloadArgs {
+            download_limit: Some(2 << 30),
+            assume_yes: false,
+        };
+        confirm_download(&estimate, &download)?;
+        estimate.download_bytes = 3 << 30;
+        download.assume_yes = true;
+        confirm_download(&estimate, &download)?;
+
+        estimate.unknown = 2;
+        assert_eq!(
+            estimate.to_string(),
+            "42 packages, 3.0 GiB to download, 300.0 MiB cached (size of 2 packages unknown)"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_package() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let apk = dir.path().join("alpine-base-3.18.3-r0.apk");
+        std::fs::write(&apk, crate::test_data::ALPINE_APK_EXAMPLE)?;
+
+        let install = Install::default();
+        let mut pin = package("alpine-base", "3.18.3-r0", None);
+        pin.system = "alpine".to_string();
+        verify_package(&install, &pin, "alpine-base-3.18.3-r0.apk", &apk)?;
+
+        pin.version = "3.18.4-r0".to_string();
+        let err = verify_package(&install, &pin, "alpine-base-3.18.3-r0.apk", &apk).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "Failed to verify metadata for \"alpine-base-3.18.3-r0.apk\": Package version in metadata doesn't match lockfile: expected=\"3.18.4-r0\", embedded=\"3.18.3-r0\""
+        );
+
+        // archlinux packages are installed with their detached signature
+        let mut pin = package("rust", "1:1.74.0-1", None);
+        pin.system = "archlinux".to_string();
+        let path = dir.path().join("rust.pkg.tar.zst");
+        std::fs::write(&path, b"")?;
+        assert!(verify_package(&install, &pin, "rust.pkg.tar.zst", &path).is_err());
+        Ok(())
+    }
 }
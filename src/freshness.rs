@@ -0,0 +1,310 @@
+//! Compare the pinned container image with the current digest of the tag it
+//! was resolved from, to tell users when `repro-env update` would pick up a
+//! newer base image
+use crate::container::{self, ImageRef};
+use crate::errors::*;
+use crate::http;
+use crate::lockfile::Lockfile;
+use reqwest::header::{ACCEPT, AUTHORIZATION, WWW_AUTHENTICATE};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use time::format_description::well_known;
+use time::OffsetDateTime;
+
+/// A newer image is only reported as a warning once the lockfile is this old
+pub const STALE_AFTER_DAYS: i64 = 14;
+
+pub const MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+
+#[derive(Debug, PartialEq)]
+pub struct Repository {
+    /// The host serving the registry api
+    pub host: String,
+    pub name: String,
+}
+
+impl Repository {
+    /// Split `docker.io/library/debian` into registry and repository name,
+    /// references without a registry are assumed to be on docker hub
+    pub fn parse(repo: &str) -> Self {
+        let (registry, name) = match repo.split_once('/') {
+            Some((first, rest))
+                if first.contains('.') || first.contains(':') || first == "localhost" =>
+            {
+                (first, rest.to_string())
+            }
+            _ => ("docker.io", repo.to_string()),
+        };
+
+        if registry == "docker.io" {
+            let name = if name.contains('/') {
+                name
+            } else {
+                format!("library/{name}")
+            };
+            Repository {
+                host: "registry-1.docker.io".to_string(),
+                name,
+            }
+        } else {
+            Repository {
+                host: registry.to_string(),
+                name,
+            }
+        }
+    }
+}
+
+/// The parameters of a `WWW-Authenticate: Bearer realm="...",service="...",scope="..."` challenge
+pub fn parse_bearer_challenge(header: &str) -> Option<Vec<(String, String)>> {
+    let params = header.strip_prefix("Bearer ")?;
+    let mut out = Vec::new();
+    let mut rest = params.trim();
+    while !rest.is_empty() {
+        let (key, value) = rest.split_once('=')?;
+        let value = value.strip_prefix('"')?;
+        let (value, remaining) = value.split_once('"')?;
+        out.push((key.trim().to_string(), value.to_string()));
+        rest = remaining.trim_start_matches(',').trim();
+    }
+    Some(out)
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+/// Request an anonymous pull token for the challenge of a registry
+async fn fetch_token(client: &http::Client, challenge: &str) -> Result<String> {
+    let params = parse_bearer_challenge(challenge)
+        .with_context(|| anyhow!("Unsupported authentication challenge: {challenge:?}"))?;
+    let realm = params
+        .iter()
+        .find(|(key, _)| key == "realm")
+        .map(|(_, value)| value.as_str())
+        .context("Authentication challenge is missing realm")?;
+    let query = params
+        .iter()
+        .filter(|(key, _)| key != "realm")
+        .collect::<Vec<_>>();
+
+    let response = client
+        .get(realm)
+        .query(&query)
+        .send()
+        .await
+        .context("Failed to request registry token")?
+        .error_for_status()
+        .context("Received http error")?;
+    let buf = response.bytes().await.context("Failed to read http body")?;
+    let token = serde_json::from_slice::<TokenResponse>(&buf)
+        .context("Failed to parse registry token response")?;
+    token
+        .token
+        .or(token.access_token)
+        .context("Registry token response did not contain a token")
+}
+
+/// Send a request to a registry, an anonymous pull token is requested if the
+/// registry asks for authentication. `build` is called again for the retry.
+pub async fn send<F>(client: &http::Client, build: F) -> Result<reqwest::Response>
+where
+    F: Fn(&http::Client) -> reqwest::RequestBuilder,
+{
+    let mut token = None;
+    loop {
+        let mut req = build(client);
+        if let Some(token) = &token {
+            req = req.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let response = client
+            .cancellable(async { req.send().await.context("Failed to send http request") })
+            .await?;
+
+        if response.status() == StatusCode::UNAUTHORIZED && token.is_none() {
+            let challenge = response
+                .headers()
+                .get(WWW_AUTHENTICATE)
+                .and_then(|v| v.to_str().ok())
+                .context("Registry requires authentication, but sent no challenge")?;
+            token = Some(fetch_token(client, challenge).await?);
+            continue;
+        }
+        return Ok(response);
+    }
+}
+
+/// Ask the registry which digest a tag currently points to, without pulling it
+pub async fn remote_digest(client: &http::Client, repo: &Repository, tag: &str) -> Result<String> {
+    let url = format!("https://{}/v2/{}/manifests/{}", repo.host, repo.name, tag);
+    debug!("Requesting current digest of image: {url:?}");
+
+    let response = send(client, |client| {
+        client.head(&url).header(ACCEPT, MANIFEST_TYPES)
+    })
+    .await?
+    .error_for_status()
+    .context("Received http error")?;
+    let digest = response
+        .headers()
+        .get("docker-content-digest")
+        .and_then(|v| v.to_str().ok())
+        .context("Registry response is missing Docker-Content-Digest header")?;
+    Ok(digest.to_string())
+}
+
+/// Describe how the pinned image compares to the current one, returns whether this is worth a warning
+pub fn advisory(
+    image: &str,
+    pinned: &str,
+    current: &str,
+    age_days: Option<i64>,
+) -> Option<(bool, String)> {
+    if pinned == current {
+        return None;
+    }
+    let age = match age_days {
+        Some(days) => format!(" {days} days ago"),
+        None => String::new(),
+    };
+    let stale = age_days.is_none_or(|days| days >= STALE_AFTER_DAYS);
+    Some((
+        stale,
+        format!(
+            "A newer version of {image:?} has been published since the lockfile was resolved{age} (pinned {pinned}, current {current}), run `repro-env update` to use it"
+        ),
+    ))
+}
+
+fn days_since(rfc3339: &str) -> Result<i64> {
+    let resolved_at = OffsetDateTime::parse(rfc3339, &well_known::Rfc3339)?;
+    Ok((OffsetDateTime::now_utc() - resolved_at).whole_days())
+}
+
+/// Query the registry and log an advisory if the base image is outdated, this never fails the build
+pub async fn check(lockfile: &Lockfile) {
+    if let Err(err) = try_check(lockfile).await {
+        warn!("Failed to check if the base image is up-to-date: {err:#}");
+    }
+}
+
+async fn try_check(lockfile: &Lockfile) -> Result<()> {
+    let pinned = &lockfile.container.image;
+    if container::archive_path(pinned).is_some() {
+        debug!("Image is loaded from an archive, skipping freshness check");
+        return Ok(());
+    }
+    let Some(meta) = &lockfile.meta else {
+        bail!("Lockfile has no [meta] section with the original image reference (enable it with `meta = true` in the [lockfile] section of repro-env.toml)");
+    };
+
+    let pinned_ref = pinned.parse::<ImageRef>()?;
+    let pinned_digest = pinned_ref
+        .digest
+        .as_deref()
+        .with_context(|| anyhow!("Image in lockfile is not pinned by digest: {pinned:?}"))?;
+    let original = meta.image.parse::<ImageRef>()?;
+    let tag = original.tag.as_deref().unwrap_or("latest");
+
+    let repo = Repository::parse(&pinned_ref.repo);
+    let client = http::Client::new()?;
+    let current = remote_digest(&client, &repo, tag).await?;
+
+    let age_days = match days_since(&meta.resolved_at) {
+        Ok(days) => Some(days),
+        Err(err) => {
+            debug!("Failed to parse resolved_at of lockfile: {err:#}");
+            None
+        }
+    };
+    match advisory(&meta.image, pinned_digest, &current, age_days) {
+        Some((true, msg)) => warn!("{msg}"),
+        Some((false, msg)) => info!("{msg}"),
+        None => info!("Base image {:?} is up-to-date", meta.image),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_repository() {
+        assert_eq!(
+            Repository::parse("debian"),
+            Repository {
+                host: "registry-1.docker.io".to_string(),
+                name: "library/debian".to_string(),
+            }
+        );
+        assert_eq!(
+            Repository::parse("docker.io/library/rust"),
+            Repository {
+                host: "registry-1.docker.io".to_string(),
+                name: "library/rust".to_string(),
+            }
+        );
+        assert_eq!(
+            Repository::parse("archlinux/archlinux"),
+            Repository {
+                host: "registry-1.docker.io".to_string(),
+                name: "archlinux/archlinux".to_string(),
+            }
+        );
+        assert_eq!(
+            Repository::parse("ghcr.io/kpcyrd/repro-env"),
+            Repository {
+                host: "ghcr.io".to_string(),
+                name: "kpcyrd/repro-env".to_string(),
+            }
+        );
+        assert_eq!(
+            Repository::parse("localhost:5000/builder"),
+            Repository {
+                host: "localhost:5000".to_string(),
+                name: "builder".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_bearer_challenge() {
+        let params = parse_bearer_challenge(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/debian:pull""#,
+        );
+        assert_eq!(
+            params,
+            Some(vec![
+                (
+                    "realm".to_string(),
+                    "https://auth.docker.io/token".to_string()
+                ),
+                ("service".to_string(), "registry.docker.io".to_string()),
+                (
+                    "scope".to_string(),
+                    "repository:library/debian:pull".to_string()
+                ),
+            ])
+        );
+        assert_eq!(parse_bearer_challenge(r#"Basic realm="x""#), None);
+    }
+
+    #[test]
+    fn test_advisory() {
+        assert_eq!(
+            advisory("debian:bookworm", "sha256:aa", "sha256:aa", Some(90)),
+            None
+        );
+        let (stale, msg) = advisory("debian:bookworm", "sha256:aa", "sha256:bb", Some(90)).unwrap();
+        assert!(stale);
+        assert_eq!(msg, "A newer version of \"debian:bookworm\" has been published since the lockfile was resolved 90 days ago (pinned sha256:aa, current sha256:bb), run `repro-env update` to use it");
+        let (stale, _) = advisory("debian:bookworm", "sha256:aa", "sha256:bb", Some(2)).unwrap();
+        assert!(!stale);
+    }
+}
@@ -0,0 +1,185 @@
+use crate::args;
+use crate::container::{self, ContainerInfo, LABEL_PROJECT};
+use crate::errors::*;
+use crate::paths;
+use crate::progress::format_bytes;
+use crate::refs;
+use crate::staging;
+use crate::state;
+use crate::utils;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// Remove packages from the cache that are not referenced by any lockfile we know of
+async fn prune_packages() -> Result<()> {
+    let _cache_lock = utils::lock_exclusive(paths::cache_lock()?).await?;
+
+    let referenced = refs::update(|index| {
+        index.refresh();
+        Ok(index.referenced())
+    })
+    .await?;
+
+    let mut removed = 0;
+    let mut freed = 0;
+    for (sha256, path, size) in paths::pkgs_cache_dir()?.list()? {
+        if referenced.contains(&sha256) {
+            continue;
+        }
+        debug!("Removing unreferenced package: {path:?}");
+        fs::remove_file(&path).with_context(|| anyhow!("Failed to remove file: {path:?}"))?;
+        removed += 1;
+        freed += size;
+    }
+    info!(
+        "Removed {removed} unreferenced packages ({})",
+        format_bytes(freed)
+    );
+    Ok(())
+}
+
+/// Remove the `.tmp` files of downloads that failed or have been interrupted
+async fn cleanup_partial_downloads() -> Result<()> {
+    let _cache_lock = utils::lock_exclusive(paths::cache_lock()?).await?;
+
+    // nothing can be downloading while we hold the exclusive lock
+    let (removed, freed) = paths::pkgs_cache_dir()?.remove_partial(Duration::ZERO)?;
+    info!(
+        "Removed {removed} stale partial downloads ({})",
+        format_bytes(freed)
+    );
+    Ok(())
+}
+
+/// Remove the containers recorded in the state of the current project and
+/// forget the ones that don't exist anymore
+async fn cleanup_project() -> Result<()> {
+    let lockfile = paths::find_project_file("repro-env.lock", true)?;
+    let project = state::project_dir(&lockfile)?;
+
+    let recorded = state::load(&project)?.containers;
+    let existing = container::list_containers().await?;
+    let existing = existing
+        .iter()
+        .map(|c| c.id.as_str())
+        .collect::<HashSet<_>>();
+
+    let mut removed = 0;
+    for c in &recorded {
+        if existing.contains(c.id.as_str()) {
+            info!("Removing container of project: {:?}", c.id);
+            container::remove(&c.id).await?;
+            removed += 1;
+        }
+    }
+    info!("Removed {removed} containers of project {project:?}");
+
+    let recorded = recorded.iter().map(|c| &c.id).collect::<HashSet<_>>();
+    state::update(&project, |state| {
+        state.containers.retain(|c| !recorded.contains(&c.id))
+    })
+    .await
+}
+
+/// Containers that can be removed without interrupting anything: the ones that
+/// aren't running anymore and the ones of projects that have been deleted
+fn is_leftover(c: &ContainerInfo) -> bool {
+    c.state != "running"
+        || c.label(LABEL_PROJECT)
+            .is_some_and(|project| !Path::new(project).exists())
+}
+
+/// Remove the containers created by repro-env, running ones only with `force`
+async fn cleanup_containers(force: bool) -> Result<()> {
+    let mut removed = 0;
+    let mut running = 0;
+    for c in container::list_containers().await? {
+        if !force && !is_leftover(&c) {
+            debug!("Keeping running container: {:?} ({})", c.id, c.image);
+            running += 1;
+            continue;
+        }
+        info!("Removing container: {:?} ({})", c.id, c.image);
+        container::remove(&c.id).await?;
+        removed += 1;
+    }
+    info!("Removed {removed} containers");
+    if running > 0 {
+        info!("Kept {running} running containers, use --force to remove them too");
+    }
+    Ok(())
+}
+
+pub async fn gc(gc: &args::Gc) -> Result<()> {
+    if gc.project {
+        cleanup_project().await?;
+    }
+
+    if gc.containers {
+        cleanup_containers(gc.force).await?;
+    }
+
+    let staging_dir = paths::staging_dir(None)?;
+    let removed = staging::cleanup_stale(&staging_dir)?;
+    info!("Removed {removed} stale staging directories");
+    let containers = container::list_containers().await?;
+    let containers = containers
+        .iter()
+        .map(|c| c.id.as_str())
+        .collect::<HashSet<_>>();
+    let removed = staging::cleanup_orphaned(&staging_dir, &containers)?;
+    info!("Removed {removed} staging directories of removed containers");
+
+    cleanup_partial_downloads().await?;
+
+    if gc.packages {
+        prune_packages().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_runtime::FakeRuntime;
+
+    #[tokio::test]
+    async fn test_cleanup_containers() -> Result<()> {
+        let project = tempfile::tempdir()?;
+        let list = format!(
+            r#"[
+    {{"Id": "aaaa", "Image": "debian", "State": "running", "Labels": {{"{LABEL_PROJECT}": "{}"}}}},
+    {{"Id": "bbbb", "Image": "debian", "State": "exited", "Labels": {{"{LABEL_PROJECT}": "{}"}}}},
+    {{"Id": "cccc", "Image": "debian", "State": "running", "Labels": {{"{LABEL_PROJECT}": "/nonexistent/project"}}}}
+]"#,
+            project.path().display(),
+            project.path().display(),
+        );
+
+        // containers that are still in use are kept
+        let runtime = FakeRuntime::new();
+        runtime.reply(&["container", "ps"], list.as_str());
+        runtime.run(cleanup_containers(false)).await?;
+        let removed = runtime
+            .calls()
+            .iter()
+            .filter(|call| call.contains(&["container", "rm"]))
+            .map(|call| call.args.last().cloned().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(removed, ["bbbb", "cccc"]);
+
+        // unless forced
+        let runtime = FakeRuntime::new();
+        runtime.reply(&["container", "ps"], list.as_str());
+        runtime.run(cleanup_containers(true)).await?;
+        let removed = runtime
+            .calls()
+            .iter()
+            .filter(|call| call.contains(&["container", "rm"]))
+            .count();
+        assert_eq!(removed, 3);
+        Ok(())
+    }
+}
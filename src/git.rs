@@ -0,0 +1,283 @@
+//! Pin the `[[git]]` repositories of the manifest to exact commits and check
+//! them out into the cache, so builds can use them without network access
+use crate::errors::*;
+use crate::hash;
+use crate::lockfile::GitLock;
+use crate::manifest::GitManifest;
+use crate::paths;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::fs;
+use tokio::process::Command;
+
+/// Run git and return its stdout, it's never allowed to ask for credentials
+async fn git<I, S>(args: I) -> Result<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut cmd = Command::new("git");
+    cmd.args(args)
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::null());
+    debug!("Spawning child process: {cmd:?}");
+    let out = cmd
+        .output()
+        .await
+        .context("Failed to execute git, is it installed?")?;
+    if !out.status.success() {
+        bail!(
+            "Git exited with {}: {}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+    String::from_utf8(out.stdout).context("Git output is not valid utf8")
+}
+
+/// If the rev is already a full commit hash (sha1 or sha256)
+pub fn is_commit(rev: &str) -> bool {
+    matches!(rev.len(), 40 | 64) && rev.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+/// Find the commit of `rev` in the output of `git ls-remote`, tags take
+/// precedence over branches and annotated tags are resolved to their commit
+pub fn parse_ls_remote(out: &str, rev: &str) -> Option<String> {
+    let refs = out
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .collect::<Vec<_>>();
+    let candidates = [
+        format!("refs/tags/{rev}^{{}}"),
+        format!("refs/tags/{rev}"),
+        format!("refs/heads/{rev}"),
+        rev.to_string(),
+    ];
+    candidates.iter().find_map(|candidate| {
+        refs.iter()
+            .find(|(_, name)| name == candidate)
+            .map(|(commit, _)| commit.to_string())
+    })
+}
+
+/// Resolve the rev of a `[[git]]` entry to the commit it currently points to
+pub async fn resolve(entry: &GitManifest) -> Result<GitLock> {
+    let name = entry
+        .name()
+        .with_context(|| anyhow!("Failed to determine name of repository: {:?}", entry.url))?;
+    let commit = if is_commit(&entry.rev) {
+        entry.rev.clone()
+    } else {
+        info!("Resolving {:?} of {:?}...", entry.rev, entry.url);
+        let out = git(["ls-remote", "--", &entry.url]).await?;
+        parse_ls_remote(&out, &entry.rev).with_context(|| {
+            anyhow!(
+                "Git repository {:?} has no branch or tag {:?}",
+                entry.url,
+                entry.rev
+            )
+        })?
+    };
+    debug!("Pinned git repository {name:?} to commit {commit:?}");
+    Ok(GitLock {
+        name: name.to_string(),
+        url: entry.url.clone(),
+        rev: entry.rev.clone(),
+        commit,
+    })
+}
+
+/// The bare repository the commits of an url are fetched into
+fn mirror_path(dir: &Path, url: &str) -> PathBuf {
+    let id = hash::sha256(url.as_bytes());
+    dir.join("mirrors").join(format!("{}.git", &id[..32]))
+}
+
+async fn fetch(git_dir: &OsStr, url: &str, refspec: &str) -> Result<()> {
+    git([
+        OsStr::new("--git-dir"),
+        git_dir,
+        OsStr::new("fetch"),
+        OsStr::new("--quiet"),
+        OsStr::new("--"),
+        OsStr::new(url),
+        OsStr::new(refspec),
+    ])
+    .await?;
+    Ok(())
+}
+
+/// Make sure the commit is available in the mirror of the repository
+async fn fetch_commit(mirror: &Path, lock: &GitLock) -> Result<()> {
+    if !mirror.exists() {
+        fs::create_dir_all(mirror)
+            .await
+            .with_context(|| anyhow!("Failed to create directory: {mirror:?}"))?;
+        git([
+            OsStr::new("init"),
+            OsStr::new("--quiet"),
+            OsStr::new("--bare"),
+            mirror.as_os_str(),
+        ])
+        .await?;
+    }
+
+    let object = format!("{}^{{commit}}", lock.commit);
+    let git_dir = mirror.as_os_str();
+    let has_commit = || {
+        git([
+            OsStr::new("--git-dir"),
+            git_dir,
+            OsStr::new("cat-file"),
+            OsStr::new("-e"),
+            OsStr::new(&object),
+        ])
+    };
+    if has_commit().await.is_ok() {
+        debug!("Commit {:?} already in git cache", lock.commit);
+        return Ok(());
+    }
+
+    info!("Fetching {:?} from {:?}...", lock.commit, lock.url);
+    // most servers allow fetching a commit directly, otherwise fetch all refs
+    if let Err(err) = fetch(git_dir, &lock.url, &lock.commit).await {
+        debug!("Failed to fetch commit directly, fetching all refs: {err:#}");
+        fetch(git_dir, &lock.url, "+refs/*:refs/*").await?;
+    }
+    has_commit().await.with_context(|| {
+        anyhow!(
+            "Git repository {:?} has no commit {:?}",
+            lock.url,
+            lock.commit
+        )
+    })?;
+    Ok(())
+}
+
+/// If the pinned commit has already been checked out into the cache
+pub fn is_checked_out(lock: &GitLock) -> Result<bool> {
+    let path = paths::git_cache_dir()?.join("checkouts").join(&lock.commit);
+    Ok(is_commit(&lock.commit) && path.exists())
+}
+
+/// Check out the pinned commit into the cache, the checkout is shared by all
+/// builds that use this commit and is never modified afterwards
+pub async fn checkout(lock: &GitLock) -> Result<PathBuf> {
+    if !is_commit(&lock.commit) {
+        bail!(
+            "Invalid commit for git repository {:?}: {:?}",
+            lock.name,
+            lock.commit
+        );
+    }
+    let dir = paths::git_cache_dir()?;
+    let path = dir.join("checkouts").join(&lock.commit);
+    if !path.exists() {
+        let mirror = mirror_path(&dir, &lock.url);
+        fetch_commit(&mirror, lock).await?;
+
+        let temp =
+            dir.join("checkouts")
+                .join(format!("{}.tmp-{}", lock.commit, std::process::id()));
+        if temp.exists() {
+            fs::remove_dir_all(&temp).await?;
+        }
+        fs::create_dir_all(&temp)
+            .await
+            .with_context(|| anyhow!("Failed to create directory: {temp:?}"))?;
+        debug!("Checking out {:?} into {temp:?}...", lock.commit);
+        let temp_dir = temp.as_os_str();
+        git([
+            OsStr::new("clone"),
+            OsStr::new("--quiet"),
+            OsStr::new("--local"),
+            OsStr::new("--no-checkout"),
+            OsStr::new("--"),
+            mirror.as_os_str(),
+            temp_dir,
+        ])
+        .await?;
+        git([
+            OsStr::new("-C"),
+            temp_dir,
+            OsStr::new("checkout"),
+            OsStr::new("--quiet"),
+            OsStr::new("--detach"),
+            OsStr::new(&lock.commit),
+        ])
+        .await?;
+        // the mirror doesn't exist inside of the container
+        git([
+            OsStr::new("-C"),
+            temp_dir,
+            OsStr::new("remote"),
+            OsStr::new("set-url"),
+            OsStr::new("origin"),
+            OsStr::new(&lock.url),
+        ])
+        .await?;
+        fs::rename(&temp, &path)
+            .await
+            .with_context(|| anyhow!("Failed to rename {temp:?} to {path:?}"))?;
+    }
+
+    let head = git([
+        OsStr::new("-C"),
+        path.as_os_str(),
+        OsStr::new("rev-parse"),
+        OsStr::new("HEAD"),
+    ])
+    .await?;
+    if head.trim() != lock.commit {
+        bail!(
+            "Checkout of {:?} in cache is at {:?} instead of {:?}: {path:?}",
+            lock.name,
+            head.trim(),
+            lock.commit
+        );
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ls_remote() {
+        let out = "\
+5b6f7dcb2b1b4ba3b1d6b4a59a7ac4e1f1c1f6a1\tHEAD
+5b6f7dcb2b1b4ba3b1d6b4a59a7ac4e1f1c1f6a1\trefs/heads/main
+0d2c6e8f55d9e3c2c7d2f6f4e1a9e1b3c4d5e6f7\trefs/heads/v1.0
+a1c8e4b0c2b14cbe1e48f1e0e4f3cd1b2a7b8c9d\trefs/tags/v1.0
+e3f1c2d4b5a6978877665544332211ffeeddccbb\trefs/tags/v1.0^{}
+9e8d7c6b5a4f3e2d1c0b9a8f7e6d5c4b3a2f1e0d\trefs/tags/v0.9
+";
+        assert_eq!(
+            parse_ls_remote(out, "v1.0").as_deref(),
+            Some("e3f1c2d4b5a6978877665544332211ffeeddccbb")
+        );
+        assert_eq!(
+            parse_ls_remote(out, "v0.9").as_deref(),
+            Some("9e8d7c6b5a4f3e2d1c0b9a8f7e6d5c4b3a2f1e0d")
+        );
+        assert_eq!(
+            parse_ls_remote(out, "main").as_deref(),
+            Some("5b6f7dcb2b1b4ba3b1d6b4a59a7ac4e1f1c1f6a1")
+        );
+        assert_eq!(
+            parse_ls_remote(out, "HEAD").as_deref(),
+            Some("5b6f7dcb2b1b4ba3b1d6b4a59a7ac4e1f1c1f6a1")
+        );
+        assert_eq!(parse_ls_remote(out, "v2.0"), None);
+        assert_eq!(parse_ls_remote(out, "1.0"), None);
+    }
+
+    #[test]
+    fn test_is_commit() {
+        assert!(is_commit("5b6f7dcb2b1b4ba3b1d6b4a59a7ac4e1f1c1f6a1"));
+        assert!(!is_commit("5b6f7dcb"));
+        assert!(!is_commit("main"));
+    }
+}
@@ -0,0 +1,171 @@
+//! sha256 of downloaded and cached files, this dominates `fetch` on fast
+//! networks. The sha2 crate uses SHA-NI on x86 if the cpu supports it, the
+//! optional `ring` feature adds ring's implementation which is also
+//! accelerated on aarch64. The backend is selected at runtime and can be
+//! overridden with `REPRO_ENV_HASH_BACKEND`.
+use crate::errors::*;
+use sha2::Digest;
+use std::env;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+const BACKEND_ENV: &str = "REPRO_ENV_HASH_BACKEND";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Sha2,
+    #[cfg(feature = "ring")]
+    Ring,
+}
+
+impl Backend {
+    /// All backends that are available in this build
+    pub const ALL: &'static [Backend] = &[
+        Backend::Sha2,
+        #[cfg(feature = "ring")]
+        Backend::Ring,
+    ];
+
+    /// The backend selected with `REPRO_ENV_HASH_BACKEND`, otherwise the fastest available one
+    pub fn current() -> Self {
+        static CURRENT: OnceLock<Backend> = OnceLock::new();
+        *CURRENT.get_or_init(|| match env::var(BACKEND_ENV) {
+            Ok(value) => value.parse().unwrap_or_else(|err| {
+                warn!("Ignoring {BACKEND_ENV}: {err:#}");
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        })
+    }
+}
+
+impl Default for Backend {
+    #[cfg(feature = "ring")]
+    fn default() -> Self {
+        Backend::Ring
+    }
+
+    #[cfg(not(feature = "ring"))]
+    fn default() -> Self {
+        Backend::Sha2
+    }
+}
+
+impl FromStr for Backend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sha2" => Ok(Backend::Sha2),
+            #[cfg(feature = "ring")]
+            "ring" => Ok(Backend::Ring),
+            _ => bail!("Unknown or unavailable hash backend: {s:?}"),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Backend::Sha2 => write!(w, "sha2"),
+            #[cfg(feature = "ring")]
+            Backend::Ring => write!(w, "ring"),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Inner {
+    Sha2(sha2::Sha256),
+    #[cfg(feature = "ring")]
+    Ring(ring::digest::Context),
+}
+
+/// An incremental sha256 hasher, using the [`Backend::current`] backend
+#[derive(Clone)]
+pub struct Sha256 {
+    inner: Inner,
+}
+
+impl Sha256 {
+    pub fn new() -> Self {
+        Self::with_backend(Backend::current())
+    }
+
+    pub fn with_backend(backend: Backend) -> Self {
+        let inner = match backend {
+            Backend::Sha2 => Inner::Sha2(sha2::Sha256::new()),
+            #[cfg(feature = "ring")]
+            Backend::Ring => Inner::Ring(ring::digest::Context::new(&ring::digest::SHA256)),
+        };
+        Sha256 { inner }
+    }
+
+    pub fn update(&mut self, buf: &[u8]) {
+        match &mut self.inner {
+            Inner::Sha2(hasher) => hasher.update(buf),
+            #[cfg(feature = "ring")]
+            Inner::Ring(ctx) => ctx.update(buf),
+        }
+    }
+
+    /// The hex encoded digest
+    pub fn finalize(self) -> String {
+        match self.inner {
+            Inner::Sha2(hasher) => hex::encode(hasher.finalize()),
+            #[cfg(feature = "ring")]
+            Inner::Ring(ctx) => hex::encode(ctx.finish()),
+        }
+    }
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl io::Write for Sha256 {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The hex encoded sha256 of `buf`
+pub fn sha256(buf: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(buf);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backends() -> Result<()> {
+        let data = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let expected = hex::encode(sha2::Sha256::digest(&data));
+        for backend in Backend::ALL {
+            let mut hasher = Sha256::with_backend(*backend);
+            for chunk in data.chunks(4096) {
+                hasher.update(chunk);
+            }
+            assert_eq!(hasher.finalize(), expected, "backend={backend}");
+            assert_eq!(backend.to_string().parse::<Backend>()?, *backend);
+        }
+        assert_eq!(
+            sha256(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert!("md5".parse::<Backend>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+//! The `[haskell]` section. The GHC bindist is pinned like a
+//! `[[toolchain.external]]` and checked against the SHA256SUMS of the release,
+//! the stackage snapshot is pinned as snapshot definition (for stack) and as
+//! constraints (for cabal), so both resolve the same package versions.
+use crate::container::Mount;
+use crate::errors::*;
+use crate::fetch;
+use crate::http;
+use crate::lockfile::{HaskellLock, Lockfile, ToolchainLock};
+use crate::manifest::{self, HaskellManifest, GHC_PATH};
+use crate::paths;
+use crate::summary;
+use crate::utils;
+
+const GHC_DOWNLOADS: &str = "https://downloads.haskell.org/~ghc";
+const STACKAGE_SNAPSHOTS: &str =
+    "https://raw.githubusercontent.com/commercialhaskell/stackage-snapshots/master";
+const STACKAGE: &str = "https://www.stackage.org";
+/// Where the files of the snapshot are mounted during builds
+pub const HASKELL_DIR: &str = "/extra/haskell";
+
+/// The checksum of a file in a `SHA256SUMS` file of a GHC release
+pub fn parse_sha256sums<'a>(buf: &'a str, filename: &str) -> Option<&'a str> {
+    buf.lines().find_map(|line| {
+        let (sha256, name) = line.split_once(char::is_whitespace)?;
+        let name = name.trim_start().trim_start_matches('*');
+        (name.strip_prefix("./").unwrap_or(name) == filename).then_some(sha256)
+    })
+}
+
+/// The definition of the snapshot in the stackage-snapshots repository,
+/// `lts-22.33` is at `lts/22/33.yaml` and `nightly-2024-09-01` at `nightly/2024/9/1.yaml`
+pub fn snapshot_url(snapshot: &str) -> Result<String> {
+    if !manifest::valid_stackage_snapshot(snapshot) {
+        bail!("Invalid stackage snapshot: {snapshot:?}");
+    }
+    let path = if let Some(version) = snapshot.strip_prefix("lts-") {
+        let (major, minor) = version
+            .split_once('.')
+            .with_context(|| anyhow!("Invalid lts snapshot: {snapshot:?}"))?;
+        format!("lts/{major}/{minor}.yaml")
+    } else {
+        let date = snapshot.trim_start_matches("nightly-");
+        let parts = date
+            .split('-')
+            .map(|part| part.trim_start_matches('0'))
+            .collect::<Vec<_>>();
+        format!("nightly/{}.yaml", parts.join("/"))
+    };
+    Ok(format!("{STACKAGE_SNAPSHOTS}/{path}"))
+}
+
+/// Pin the bindist of a GHC release
+pub async fn resolve_ghc(
+    client: &http::Client,
+    version: &str,
+    platform: &str,
+) -> Result<ToolchainLock> {
+    let filename = format!("ghc-{version}-{platform}.tar.xz");
+    let sums_url = format!("{GHC_DOWNLOADS}/{version}/SHA256SUMS");
+    debug!("Fetching checksums of ghc release: {sums_url:?}");
+    let buf = client.fetch(&sums_url).await?;
+    let buf = String::from_utf8(buf.to_vec()).context("SHA256SUMS of ghc release is not utf8")?;
+    let sha256 = parse_sha256sums(&buf, &filename)
+        .with_context(|| anyhow!("Ghc {version} has no bindist {filename:?}"))?;
+
+    let url = format!("{GHC_DOWNLOADS}/{version}/{filename}");
+    let pin = fetch::pin_file(client, &filename, &url, Some(sha256))
+        .await
+        .with_context(|| anyhow!("Failed to pin ghc {version:?}"))?;
+    Ok(ToolchainLock {
+        name: pin.name,
+        url: pin.url,
+        sha256: pin.sha256,
+        size: pin.size,
+        path: GHC_PATH.to_string(),
+        // the tarball contains a single directory like ghc-9.6.6-x86_64-unknown-linux/
+        strip_components: 1,
+    })
+}
+
+/// Pin the snapshot definition and the cabal constraints of a stackage snapshot
+pub async fn resolve_snapshot(client: &http::Client, snapshot: &str) -> Result<HaskellLock> {
+    let sources = [
+        (format!("{snapshot}.yaml"), snapshot_url(snapshot)?),
+        (
+            format!("{snapshot}.cabal.config"),
+            format!("{STACKAGE}/{snapshot}/cabal.config"),
+        ),
+    ];
+    let mut files = Vec::new();
+    for (name, url) in sources {
+        let pin = fetch::pin_file(client, &name, &url, None)
+            .await
+            .with_context(|| anyhow!("Failed to pin {name:?} of stackage snapshot"))?;
+        debug!("Pinned {name:?} with sha256 {:?}", pin.sha256);
+        files.push(pin);
+    }
+    Ok(HaskellLock {
+        snapshot: snapshot.to_string(),
+        files,
+    })
+}
+
+/// Pin the ghc and the stackage snapshot of `[haskell]`
+pub async fn pin(haskell: &HaskellManifest, lockfile: &mut Lockfile) -> Result<()> {
+    let _phase = summary::phase("download");
+    let client = http::Client::new()?;
+    if let Some(ghc) = &haskell.ghc {
+        let pin = resolve_ghc(&client, ghc, &haskell.ghc_platform).await?;
+        lockfile.toolchains.push(pin);
+    }
+    if let Some(snapshot) = &haskell.snapshot {
+        lockfile.haskell = Some(resolve_snapshot(&client, snapshot).await?);
+    }
+    Ok(())
+}
+
+/// Download and verify the files of the stackage snapshot, they are mounted
+/// read-only into /extra/haskell/
+pub async fn stage(haskell: Option<HaskellLock>, mounts: &mut Vec<Mount>) -> Result<()> {
+    let Some(haskell) = haskell else {
+        return Ok(());
+    };
+    let files = haskell.files;
+    for file in &files {
+        if !manifest::valid_file_name(&file.name) {
+            bail!(
+                "Invalid filename for stackage snapshot in lockfile: {:?}",
+                file.name
+            );
+        }
+    }
+
+    {
+        let _phase = summary::phase("download");
+        fetch::download_files(&files).await?;
+    }
+    let files = {
+        let _phase = summary::phase("verify");
+        utils::spawn_blocking(move |_| {
+            fetch::verify_files(&files)?;
+            Ok(files)
+        })
+        .await?
+    };
+
+    let pkgs_cache_dir = paths::pkgs_cache_dir()?;
+    for file in files {
+        let source = pkgs_cache_dir.sha256_path(&file.sha256)?;
+        mounts.push(Mount::read_only(
+            source,
+            format!("{HASKELL_DIR}/{}", file.name),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sha256sums() {
+        let buf = "\
+3a4b5c6d7e8f90a1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f6071829  ./ghc-9.6.6-aarch64-deb12-linux.tar.xz
+1f2e3d4c5b6a79880f1e2d3c4b5a69788f1e2d3c4b5a69788f1e2d3c4b5a6978  ./ghc-9.6.6-x86_64-deb12-linux.tar.xz
+0a1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f60718293a4b5c6d7e8f9  ./ghc-9.6.6-x86_64-deb12-linux.tar.xz.sig
+";
+        assert_eq!(
+            parse_sha256sums(buf, "ghc-9.6.6-x86_64-deb12-linux.tar.xz"),
+            Some("1f2e3d4c5b6a79880f1e2d3c4b5a69788f1e2d3c4b5a69788f1e2d3c4b5a6978")
+        );
+        assert_eq!(
+            parse_sha256sums(buf, "ghc-9.6.6-x86_64-deb11-linux.tar.xz"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_snapshot_url() -> Result<()> {
+        assert_eq!(
+            snapshot_url("lts-22.33")?,
+            "https://raw.githubusercontent.com/commercialhaskell/stackage-snapshots/master/lts/22/33.yaml"
+        );
+        assert_eq!(
+            snapshot_url("nightly-2024-09-01")?,
+            "https://raw.githubusercontent.com/commercialhaskell/stackage-snapshots/master/nightly/2024/9/1.yaml"
+        );
+        assert!(snapshot_url("lts-22").is_err());
+        assert!(snapshot_url("../lts-22.33").is_err());
+        Ok(())
+    }
+}
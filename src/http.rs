@@ -1,35 +1,524 @@
+use crate::credentials::{self, Credentials};
 use crate::errors::*;
+use crate::paths;
+use crate::summary;
+use crate::utils;
+use reqwest::header::{
+    CONTENT_LENGTH, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RETRY_AFTER,
+};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
 
 static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+/// Settings given on the command line, they take precedence over `http.toml`
+static CLI_SETTINGS: OnceLock<Settings> = OnceLock::new();
+/// The proxy of `repro-env.local.toml`, used for every client
+static PROXY: OnceLock<String> = OnceLock::new();
+
+/// How long establishing a connection may take, unless configured otherwise
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long we wait for data on an established connection, the download as a
+/// whole may take longer than this
+pub const READ_TIMEOUT: Duration = Duration::from_secs(240);
+/// How many idle connections are kept open for each host
+pub const MAX_IDLE_CONNECTIONS: usize = 32;
+
+/// Timeouts (in seconds) and connection pool size, unset values fall back to the defaults
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    pub connect_timeout: Option<u64>,
+    pub read_timeout: Option<u64>,
+    pub max_idle_connections: Option<usize>,
+}
+
+impl Settings {
+    /// Values that are unset in `self` are taken from `other`
+    pub fn or(self, other: Settings) -> Settings {
+        Settings {
+            connect_timeout: self.connect_timeout.or(other.connect_timeout),
+            read_timeout: self.read_timeout.or(other.read_timeout),
+            max_idle_connections: self.max_idle_connections.or(other.max_idle_connections),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.connect_timeout == Some(0) || self.read_timeout == Some(0) {
+            bail!("Timeouts need to be at least 1 second");
+        }
+        Ok(())
+    }
+
+    fn build(&self) -> Result<reqwest::Client> {
+        let connect_timeout = self
+            .connect_timeout
+            .map(Duration::from_secs)
+            .unwrap_or(CONNECT_TIMEOUT);
+        let read_timeout = self
+            .read_timeout
+            .map(Duration::from_secs)
+            .unwrap_or(READ_TIMEOUT);
+        let mut builder = reqwest::Client::builder()
+            .user_agent(APP_USER_AGENT)
+            .connect_timeout(connect_timeout)
+            .read_timeout(read_timeout)
+            .pool_max_idle_per_host(self.max_idle_connections.unwrap_or(MAX_IDLE_CONNECTIONS));
+        if let Some(proxy) = PROXY.get() {
+            builder = builder.proxy(
+                reqwest::Proxy::all(proxy)
+                    .with_context(|| anyhow!("Invalid proxy url: {proxy:?}"))?,
+            );
+        }
+        let http = builder.build()?;
+        Ok(http)
+    }
+}
+
+/// Use these settings for every client, they take precedence over `http.toml`
+pub fn init(settings: Settings) -> Result<()> {
+    settings.validate()?;
+    if CLI_SETTINGS.set(settings).is_err() {
+        bail!("HTTP settings have already been initialized");
+    }
+    Ok(())
+}
+
+/// Send all requests through this proxy, needs to be set before the first client is created
+pub fn set_proxy(proxy: String) -> Result<()> {
+    if PROXY.set(proxy).is_err() {
+        bail!("HTTP proxy has already been configured");
+    }
+    Ok(())
+}
+
+/// The contents of `http.toml`
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpConfig {
+    #[serde(default)]
+    pub defaults: Settings,
+    /// Overrides for urls starting with a prefix, e.g. a slow mirror
+    #[serde(default)]
+    pub hosts: BTreeMap<String, Settings>,
+}
+
+impl HttpConfig {
+    pub fn deserialize(buf: &str) -> Result<Self> {
+        let config = toml::from_str::<Self>(buf).context("Failed to load http config from toml")?;
+        config.defaults.validate()?;
+        for (url, settings) in &config.hosts {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                bail!("Host url must start with http:// or https://: {url:?}");
+            }
+            settings
+                .validate()
+                .with_context(|| anyhow!("Invalid settings for {url:?}"))?;
+        }
+        Ok(config)
+    }
+
+    /// Load `http.toml`, it's fine if it doesn't exist
+    pub fn load() -> Result<Self> {
+        let path = paths::http_config_path()?;
+        if !path.exists() {
+            return Ok(HttpConfig::default());
+        }
+        debug!("Loading http config from {path:?}");
+        let buf = std::fs::read_to_string(&path)
+            .with_context(|| anyhow!("Failed to read http config file: {path:?}"))?;
+        Self::deserialize(&buf).with_context(|| anyhow!("Invalid http config file: {path:?}"))
+    }
+
+    /// The host override with the longest matching prefix for this url
+    pub fn host(&self, url: &str) -> Option<&str> {
+        self.hosts
+            .keys()
+            .filter(|prefix| credentials::url_matches(prefix, url))
+            .max_by_key(|prefix| prefix.len())
+            .map(String::as_str)
+    }
+
+    /// The effective settings for a url, `cli` takes precedence over the config
+    pub fn settings(&self, url: &str, cli: Settings) -> Settings {
+        let host = self
+            .host(url)
+            .and_then(|host| self.hosts.get(host))
+            .copied()
+            .unwrap_or_default();
+        cli.or(host).or(self.defaults)
+    }
+}
+
+/// The server responded with HTTP 429, the request can be retried later
+#[derive(Debug, PartialEq)]
+pub struct RateLimited {
+    pub url: String,
+    /// The delay requested by the server, if it sent a Retry-After in seconds
+    pub retry_after: Option<Duration>,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        write!(w, "Rate limit exceeded for {:?}", self.url)?;
+        if let Some(retry_after) = self.retry_after {
+            write!(w, ", retry after {}s", retry_after.as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+impl RateLimited {
+    fn check(url: &str, response: &reqwest::Response) -> Result<()> {
+        if response.status() != StatusCode::TOO_MANY_REQUESTS {
+            return Ok(());
+        }
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_retry_after);
+        Err(RateLimited {
+            url: url.to_string(),
+            retry_after,
+        }
+        .into())
+    }
+}
+
+/// Only the delay-seconds form is supported, an http-date falls back to our own backoff
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse().ok().map(Duration::from_secs)
+}
 
 pub struct Client {
     http: reqwest::Client,
+    /// Clients for the host overrides of `http.toml`, by url prefix
+    hosts: BTreeMap<String, reqwest::Client>,
+    config: HttpConfig,
+    credentials: Credentials,
+    /// In-flight requests are aborted once this is cancelled
+    shutdown: &'static utils::Shutdown,
 }
 
 impl Client {
     pub fn new() -> Result<Self> {
-        let http = reqwest::Client::builder()
-            .user_agent(APP_USER_AGENT)
-            .build()?;
-        Ok(Client { http })
+        let config = HttpConfig::load()?;
+        let cli = CLI_SETTINGS.get().copied().unwrap_or_default();
+        let http = cli.or(config.defaults).build()?;
+        let hosts = config
+            .hosts
+            .keys()
+            .map(|url| Ok((url.clone(), config.settings(url, cli).build()?)))
+            .collect::<Result<_>>()?;
+        let credentials = Credentials::load()?;
+        Ok(Client {
+            http,
+            hosts,
+            config,
+            credentials,
+            shutdown: utils::shutdown(),
+        })
+    }
+
+    /// Abort `fut` on ctrl-c or container teardown, instead of waiting for it to time out
+    pub async fn cancellable<T, F: Future<Output = Result<T>>>(&self, fut: F) -> Result<T> {
+        self.shutdown.run(fut).await
+    }
+
+    /// Read the next chunk of a streamed download
+    pub async fn chunk(&self, response: &mut reqwest::Response) -> Result<Option<bytes::Bytes>> {
+        self.cancellable(async {
+            let chunk = response
+                .chunk()
+                .await
+                .context("Failed to read from download stream")?;
+            Ok(chunk)
+        })
+        .await
+    }
+
+    /// The client with the timeouts and pool settings for this url
+    fn http(&self, url: &str) -> &reqwest::Client {
+        self.config
+            .host(url)
+            .and_then(|host| self.hosts.get(host))
+            .unwrap_or(&self.http)
+    }
+
+    /// A GET request, with basic auth if credentials are configured for this mirror
+    fn mirror_get(&self, url: &str) -> Result<reqwest::RequestBuilder> {
+        self.authenticate(url, self.http(url).get(url))
+    }
+
+    fn authenticate(
+        &self,
+        url: &str,
+        mut req: reqwest::RequestBuilder,
+    ) -> Result<reqwest::RequestBuilder> {
+        if let Some(mirror) = self.credentials.lookup(url) {
+            debug!(
+                "Using credentials of user {:?} for {url:?}",
+                mirror.username
+            );
+            req = req.basic_auth(&mirror.username, Some(mirror.password()?));
+        }
+        Ok(req)
+    }
+
+    /// Build a request with custom headers, for apis like container registries
+    pub fn get(&self, url: &str) -> reqwest::RequestBuilder {
+        self.http(url).get(url)
+    }
+
+    pub fn head(&self, url: &str) -> reqwest::RequestBuilder {
+        self.http(url).head(url)
+    }
+
+    pub fn post(&self, url: &str) -> reqwest::RequestBuilder {
+        self.http(url).post(url)
     }
 
     pub async fn request(&self, url: &str) -> Result<reqwest::Response> {
         info!("Downloading {url:?}...");
+        let req = self.mirror_get(url)?;
         let response = self
-            .http
-            .get(url)
-            .send()
-            .await
-            .context("Failed to send http request")?
-            .error_for_status()
-            .context("Received http error")?;
+            .cancellable(async { req.send().await.context("Failed to send http request") })
+            .await?;
+        RateLimited::check(url, &response)?;
+        let response = response.error_for_status().context("Received http error")?;
         Ok(response)
     }
 
+    /// The size of a download according to a HEAD request, if the server sends it
+    pub async fn content_length(&self, url: &str) -> Result<Option<u64>> {
+        let req = self.authenticate(url, self.http(url).head(url))?;
+        let response = self
+            .cancellable(async { req.send().await.context("Failed to send http request") })
+            .await?;
+        RateLimited::check(url, &response)?;
+        let response = response.error_for_status().context("Received http error")?;
+        // reqwest reports the (empty) body of a HEAD response, not the header
+        let length = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+        Ok(length)
+    }
+
     pub async fn fetch(&self, url: &str) -> Result<bytes::Bytes> {
         let response = self.request(url).await?;
-        let buf = response.bytes().await.context("Failed to read http body")?;
+        let buf = self
+            .cancellable(async { response.bytes().await.context("Failed to read http body") })
+            .await?;
+        summary::record_download(buf.len() as u64);
+        Ok(buf)
+    }
+
+    /// Like fetch, also returns the `Last-Modified` header of the response
+    pub async fn fetch_modified(&self, url: &str) -> Result<(bytes::Bytes, Option<String>)> {
+        let response = self.request(url).await?;
+        let last_modified = response
+            .headers()
+            .get(LAST_MODIFIED)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        let buf = self
+            .cancellable(async { response.bytes().await.context("Failed to read http body") })
+            .await?;
+        summary::record_download(buf.len() as u64);
+        Ok((buf, last_modified))
+    }
+
+    /// Like fetch, but keep the response on disk and revalidate it with
+    /// ETag/Last-Modified next time, for metadata that is requested repeatedly
+    pub async fn fetch_cached(&self, url: &str) -> Result<bytes::Bytes> {
+        let path = paths::http_cache_dir()?.join(hex::encode(Sha256::digest(url)));
+        let cached = match CachedResponse::read(&path) {
+            Ok(cached) => cached.filter(|cached| cached.meta.url == url),
+            Err(err) => {
+                debug!("Ignoring invalid http cache entry {path:?}: {err:#}");
+                None
+            }
+        };
+
+        let mut req = self.mirror_get(url)?;
+        if let Some(cached) = &cached {
+            if let Some(etag) = &cached.meta.etag {
+                req = req.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &cached.meta.last_modified {
+                req = req.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        info!("Downloading {url:?}...");
+        let response = self
+            .cancellable(async { req.send().await.context("Failed to send http request") })
+            .await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            if let Some(cached) = cached {
+                debug!("Response has not been modified, using cache: {path:?}");
+                return Ok(cached.body.into());
+            }
+        }
+        RateLimited::check(url, &response)?;
+        let response = response.error_for_status().context("Received http error")?;
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        };
+        let meta = CacheMeta {
+            url: url.to_string(),
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        };
+
+        let buf = self
+            .cancellable(async { response.bytes().await.context("Failed to read http body") })
+            .await?;
+        summary::record_download(buf.len() as u64);
+
+        if meta.etag.is_some() || meta.last_modified.is_some() {
+            let cached = CachedResponse {
+                meta,
+                body: buf.to_vec(),
+            };
+            if let Err(err) = cached.write(&path) {
+                warn!("Failed to write http cache entry {path:?}: {err:#}");
+            }
+        }
+
+        Ok(buf)
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct CacheMeta {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// A json line with the metadata, followed by the body. Both are kept in a
+/// single file so they are always replaced together.
+#[derive(Debug, PartialEq)]
+struct CachedResponse {
+    meta: CacheMeta,
+    body: Vec<u8>,
+}
+
+impl CachedResponse {
+    fn parse(buf: &[u8]) -> Result<Self> {
+        let idx = memchr::memchr(b'\n', buf).context("Missing metadata line")?;
+        let meta = serde_json::from_slice(&buf[..idx])?;
+        let body = buf[idx + 1..].to_vec();
+        Ok(CachedResponse { meta, body })
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>> {
+        let mut buf = serde_json::to_vec(&self.meta)?;
+        buf.push(b'\n');
+        buf.extend(&self.body);
         Ok(buf)
     }
+
+    fn read(path: &Path) -> Result<Option<Self>> {
+        match std::fs::read(path) {
+            Ok(buf) => Ok(Some(Self::parse(&buf)?)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        utils::atomic_write(path, &self.serialize()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cached_response_roundtrip() -> Result<()> {
+        let cached = CachedResponse {
+            meta: CacheMeta {
+                url: "https://snapshot.debian.org/mr/file/abc/info".to_string(),
+                etag: Some("\"1234\"".to_string()),
+                last_modified: None,
+            },
+            body: b"{\"result\": []}\n\n".to_vec(),
+        };
+        let buf = cached.serialize()?;
+        assert_eq!(CachedResponse::parse(&buf)?, cached);
+        assert!(CachedResponse::parse(b"no metadata").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_http_config() -> Result<()> {
+        let config = HttpConfig::deserialize(
+            r#"
+[defaults]
+connect_timeout = 10
+read_timeout = 60
+
+[hosts."https://snapshot.debian.org"]
+read_timeout = 900
+max_idle_connections = 4
+"#,
+        )?;
+
+        let url = "https://snapshot.debian.org/archive/debian/20230801T000000Z/";
+        assert_eq!(config.host(url), Some("https://snapshot.debian.org"));
+        assert_eq!(
+            config.settings(url, Settings::default()),
+            Settings {
+                connect_timeout: Some(10),
+                read_timeout: Some(900),
+                max_idle_connections: Some(4),
+            }
+        );
+
+        let cli = Settings {
+            read_timeout: Some(30),
+            ..Default::default()
+        };
+        assert_eq!(config.settings(url, cli).read_timeout, Some(30));
+
+        let url = "https://snapshot.debian.org.example.com/";
+        assert_eq!(config.host(url), None);
+        assert_eq!(
+            config.settings(url, Settings::default()).read_timeout,
+            Some(60)
+        );
+
+        assert!(HttpConfig::deserialize("[defaults]\nread_timeout = 0\n").is_err());
+        assert!(HttpConfig::deserialize("[hosts.\"snapshot.debian.org\"]\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+    }
 }
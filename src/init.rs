@@ -0,0 +1,174 @@
+//! Write a repro-env.toml for a new project, from one of the built-in templates
+use crate::args;
+use crate::errors::*;
+use crate::manifest;
+use clap::ValueEnum;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Template {
+    /// Only pin a debian image, packages can be added later
+    Minimal,
+    /// Static binaries with cargo on alpine (musl)
+    RustMuslStatic,
+    /// A binary package from a debian/ directory with dpkg-buildpackage
+    DebianDebPackage,
+    /// A PKGBUILD built with makepkg on Arch Linux
+    ArchPkgbuild,
+    /// Static binaries without cgo
+    GoStatic,
+    /// A python wheel built with pip
+    PythonWheel,
+}
+
+impl Template {
+    pub fn name(&self) -> String {
+        self.to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default()
+    }
+
+    /// The repro-env.toml of this template
+    pub fn manifest(&self) -> &'static str {
+        match self {
+            Template::Minimal => {
+                r#"[container]
+image = "docker.io/library/debian:bookworm"
+"#
+            }
+            Template::RustMuslStatic => {
+                r#"[container]
+image = "docker.io/library/rust:1-alpine"
+
+[packages]
+system = "alpine"
+dependencies = ["musl-dev"]
+
+[build.environment]
+preset = "deterministic"
+# the toolchain of the image is in /usr/local/cargo/bin
+path = "/usr/local/cargo/bin:/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin"
+"#
+            }
+            Template::DebianDebPackage => {
+                r#"[container]
+image = "docker.io/library/debian:bookworm"
+
+[packages]
+system = "debian"
+# add the Build-Depends of debian/control
+dependencies = ["build-essential", "debhelper", "dpkg-dev"]
+
+[build.environment]
+preset = "deterministic"
+"#
+            }
+            Template::ArchPkgbuild => {
+                r#"[container]
+image = "docker.io/library/archlinux"
+
+[packages]
+system = "archlinux"
+# add the depends and makedepends of the PKGBUILD
+dependencies = ["base-devel"]
+
+[build.environment]
+preset = "deterministic"
+"#
+            }
+            Template::GoStatic => {
+                r#"[container]
+image = "docker.io/library/golang:1-alpine"
+
+[build.environment]
+preset = "deterministic"
+# the toolchain of the image is in /usr/local/go/bin
+path = "/usr/local/go/bin:/go/bin:/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin"
+"#
+            }
+            Template::PythonWheel => {
+                r#"[container]
+image = "docker.io/library/python:3-slim"
+
+[build.environment]
+preset = "deterministic"
+"#
+            }
+        }
+    }
+
+    /// The suggested build command, printed after the manifest was written
+    pub fn build_command(&self) -> &'static str {
+        match self {
+            Template::Minimal => "repro-env build -- make",
+            Template::RustMuslStatic => "repro-env build -- cargo build --release --locked",
+            Template::DebianDebPackage => {
+                "repro-env build -- sh -c 'dpkg-buildpackage -us -uc -b && mkdir -p out && mv ../*.deb ../*.buildinfo ../*.changes out/'"
+            }
+            // makepkg refuses to run as root, --chown hands the build directory back afterwards
+            Template::ArchPkgbuild => {
+                "repro-env build --chown --env SOURCE_DATE_EPOCH=\"$(git log -1 --format=%ct)\" -- sh -c 'useradd -m builder && chown -R builder: /build && runuser -u builder -- makepkg --nodeps'"
+            }
+            Template::GoStatic => {
+                "repro-env build -- env CGO_ENABLED=0 go build -trimpath -ldflags=-buildid= ."
+            }
+            Template::PythonWheel => {
+                "repro-env build --env SOURCE_DATE_EPOCH=\"$(git log -1 --format=%ct)\" -- python3 -m pip wheel --no-deps --wheel-dir dist ."
+            }
+        }
+    }
+}
+
+/// Write the manifest of the template to `path`, an existing file is only replaced with `force`
+pub fn write(path: &Path, template: Template, force: bool) -> Result<()> {
+    if path.exists() && !force {
+        bail!("{path:?} already exists, use --force to overwrite it");
+    }
+    fs::write(path, template.manifest())
+        .with_context(|| anyhow!("Failed to write manifest: {path:?}"))?;
+    Ok(())
+}
+
+pub fn init(init: &args::Init) -> Result<()> {
+    let path = Path::new(manifest::FILENAME);
+    write(path, init.template, init.force)?;
+    info!(
+        "Wrote {path:?} from template {:?}, run `repro-env update` to create the lockfile",
+        init.template.name()
+    );
+    info!("Suggested build command: {}", init.template.build_command());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::Manifest;
+
+    #[test]
+    fn test_templates() -> Result<()> {
+        for template in Template::value_variants() {
+            let manifest = Manifest::deserialize(template.manifest())
+                .with_context(|| anyhow!("Invalid template: {:?}", template.name()))?;
+            assert!(!manifest.container.image.is_empty());
+            assert!(template.build_command().starts_with("repro-env build "));
+        }
+        assert_eq!(Template::RustMuslStatic.name(), "rust-musl-static");
+        Ok(())
+    }
+
+    #[test]
+    fn test_write() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(manifest::FILENAME);
+        write(&path, Template::GoStatic, false)?;
+        assert_eq!(fs::read_to_string(&path)?, Template::GoStatic.manifest());
+
+        assert!(write(&path, Template::Minimal, false).is_err());
+        assert_eq!(fs::read_to_string(&path)?, Template::GoStatic.manifest());
+        write(&path, Template::Minimal, true)?;
+        assert_eq!(fs::read_to_string(&path)?, Template::Minimal.manifest());
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+use super::{Installer, Options, Step};
+use crate::errors::*;
+use crate::install;
+use crate::lockfile::{ApkKeyLock, PackageLock};
+use crate::resolver;
+use std::collections::HashMap;
+use std::io::Read;
+
+pub struct Alpine<'a> {
+    pub apk_keys: &'a [ApkKeyLock],
+}
+
+impl Installer for Alpine<'_> {
+    fn query_installed(&self) -> &'static [&'static str] {
+        &["apk", "info", "-v"]
+    }
+
+    fn parse_installed(&self, buf: &str) -> Result<HashMap<String, String>> {
+        buf.lines()
+            .filter(|line| !line.starts_with("WARNING:"))
+            .map(|line| {
+                // busybox-1.36.1-r2, neither the version nor the release contain a dash
+                let (name, version) = line
+                    .rsplit_once('-')
+                    .and_then(|(rest, release)| {
+                        let (name, version) = rest.rsplit_once('-')?;
+                        Some((name, format!("{version}-{release}")))
+                    })
+                    .with_context(|| anyhow!("Unexpected output from apk: {line:?}"))?;
+                Ok((name.to_string(), version))
+            })
+            .collect()
+    }
+
+    fn verify(&self, _pkg: &PackageLock, filename: &str, reader: &mut dyn Read) -> Result<()> {
+        if self.apk_keys.is_empty() {
+            warn!("Lockfile has no apk keys, can't verify signature of {filename:?}");
+        } else {
+            let key = resolver::alpine::verify_signature(reader, self.apk_keys)
+                .with_context(|| anyhow!("Failed to verify signature of {filename:?}"))?;
+            debug!("Verified signature of {filename:?} with key {key:?}");
+        }
+        Ok(())
+    }
+
+    fn steps(&self, pkgs: &[(PackageLock, String)], options: &Options) -> Result<Vec<Step>> {
+        let mut steps = self
+            .apk_keys
+            .iter()
+            .map(|key| Step::WriteFile {
+                dir: "/etc/apk/keys/",
+                filename: key.name.clone(),
+                content: key.key.as_bytes().to_vec(),
+            })
+            .collect::<Vec<_>>();
+
+        let mut others = pkgs.to_vec();
+        let first = install::take_install_order(&mut others, &options.install_order);
+        for pkg in first {
+            steps.push(Step::Info(format!("Installing {:?} first...", pkg.0.name)));
+            steps.push(apk_add(&[pkg], options));
+        }
+        if !others.is_empty() {
+            steps.push(Step::Info("Installing dependencies...".to_string()));
+            steps.push(apk_add(&others, options));
+        }
+        Ok(steps)
+    }
+}
+
+fn apk_add(pkgs: &[(PackageLock, String)], options: &Options) -> Step {
+    let mut cmd = vec![
+        "apk".to_string(),
+        "add".to_string(),
+        "--no-network".to_string(),
+    ];
+    if options.allow_untrusted {
+        cmd.push("--allow-untrusted".to_string());
+    }
+    if options.ignore_depends {
+        cmd.push("--force-broken-world".to_string());
+    }
+    cmd.push("--".to_string());
+    for (_, filename) in pkgs {
+        cmd.push(format!("/extra/{filename}"));
+    }
+    Step::Exec(cmd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::install::test_pkg;
+
+    #[test]
+    fn test_steps() -> Result<()> {
+        let apk_keys = [ApkKeyLock {
+            name: "alpine-devel@lists.alpinelinux.org-6165ee59.rsa.pub".to_string(),
+            key: "-----BEGIN PUBLIC KEY-----\n".to_string(),
+        }];
+        let installer = Alpine {
+            apk_keys: &apk_keys,
+        };
+        let pkgs = [test_pkg("alpine", "rust", "rust-1.71.1-r0.apk")];
+        let steps = installer.steps(
+            &pkgs,
+            &Options {
+                allow_untrusted: true,
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(
+            steps,
+            [
+                Step::WriteFile {
+                    dir: "/etc/apk/keys/",
+                    filename: "alpine-devel@lists.alpinelinux.org-6165ee59.rsa.pub".to_string(),
+                    content: b"-----BEGIN PUBLIC KEY-----\n".to_vec(),
+                },
+                Step::Info("Installing dependencies...".to_string()),
+                Step::Exec(
+                    [
+                        "apk",
+                        "add",
+                        "--no-network",
+                        "--allow-untrusted",
+                        "--",
+                        "/extra/rust-1.71.1-r0.apk"
+                    ]
+                    .map(String::from)
+                    .to_vec()
+                ),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+use super::{Installer, Options, Step};
+use crate::errors::*;
+use crate::install;
+use crate::lockfile::{KeyLock, PackageLock};
+use crate::pgp;
+use data_encoding::BASE64;
+use std::collections::HashMap;
+use std::time::Duration;
+use time::format_description::well_known;
+use time::OffsetDateTime;
+
+const KEYRING: &str = "archlinux-keyring";
+
+pub struct Archlinux<'a> {
+    pub keys: &'a [(KeyLock, String)],
+}
+
+/// Separate the keyring package from the other archlinux packages
+fn split_keyring(pkgs: &[(PackageLock, String)]) -> (Option<&str>, Vec<&str>) {
+    let mut keyring = None;
+    let mut others = Vec::new();
+    for (pkg, filename) in pkgs {
+        if pkg.name == KEYRING {
+            keyring = Some(filename.as_str());
+        } else {
+            others.push(filename.as_str());
+        }
+    }
+    (keyring, others)
+}
+
+fn pacman_install(filenames: &[&str], options: &Options) -> Step {
+    let mut cmd = vec![
+        "pacman".to_string(),
+        "-U".to_string(),
+        "--noconfirm".to_string(),
+    ];
+    if options.ignore_depends {
+        // skip both the dependency version and the dependency checks
+        cmd.push("-dd".to_string());
+    }
+    cmd.push("--".to_string());
+    for filename in filenames {
+        cmd.push(format!("/extra/{filename}"));
+    }
+    Step::Exec(cmd)
+}
+
+fn exec(cmd: &[&str]) -> Step {
+    Step::Exec(cmd.iter().map(|s| s.to_string()).collect())
+}
+
+impl Installer for Archlinux<'_> {
+    fn query_installed(&self) -> &'static [&'static str] {
+        &["pacman", "-Q"]
+    }
+
+    fn parse_installed(&self, buf: &str) -> Result<HashMap<String, String>> {
+        buf.lines()
+            .map(|line| {
+                let (name, version) = line
+                    .split_once(' ')
+                    .with_context(|| anyhow!("Unexpected output from pacman: {line:?}"))?;
+                Ok((name.to_string(), version.to_string()))
+            })
+            .collect()
+    }
+
+    fn extra_files(&self, pkg: &PackageLock, filename: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let base64 = pkg
+            .openpgp_signature()
+            .context("Package in dependency lockfile is missing OpenPGP signature")?;
+        let signature = BASE64
+            .decode(base64.as_bytes())
+            .with_context(|| anyhow!("Failed to decode signature as base64: {base64:?}"))?;
+        Ok(vec![(format!("{filename}.sig"), signature)])
+    }
+
+    fn steps(&self, pkgs: &[(PackageLock, String)], options: &Options) -> Result<Vec<Step>> {
+        let mut steps = Vec::new();
+
+        // determine verification timestamp and add it to gpg.conf
+        if let Some(time) = pgp::find_max_signature_time(pkgs.iter().map(|(pkg, _)| pkg))? {
+            let time = time
+                .checked_add(Duration::from_secs(1))
+                .with_context(|| anyhow!("Failed to increase time by 1 second {time:?}"))?;
+            let datetime = OffsetDateTime::from(time).format(&well_known::Rfc3339)?;
+
+            steps.push(Step::Info(format!(
+                "Derived signature verification timestamp: {datetime:?}"
+            )));
+            steps.push(Step::PacmanVerificationTime(time));
+        }
+
+        for (key, filename) in self.keys {
+            steps.push(Step::Info(format!(
+                "Importing pinned key: {:?}",
+                key.fingerprint
+            )));
+            steps.push(exec(&[
+                "pacman-key",
+                "--add",
+                &format!("/extra/{filename}"),
+            ]));
+            steps.push(exec(&["pacman-key", "--lsign-key", &key.fingerprint]));
+        }
+
+        // the keyring is always installed first, even if it's listed in install_order
+        let install_order = options
+            .install_order
+            .iter()
+            .filter(|name| *name != KEYRING)
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut others = pkgs.to_vec();
+        let first = install::take_install_order(&mut others, &install_order);
+
+        // old snapshots may need their keyring before the other packages can be verified
+        let (keyring, others) = split_keyring(&others);
+        if let Some(keyring) = keyring {
+            steps.push(Step::Info("Installing keyring...".to_string()));
+            steps.push(pacman_install(&[keyring], options));
+            steps.push(exec(&["pacman-key", "--populate", "archlinux"]));
+        }
+
+        for (pkg, filename) in &first {
+            steps.push(Step::Info(format!("Installing {:?} first...", pkg.name)));
+            steps.push(pacman_install(&[filename], options));
+        }
+
+        if !others.is_empty() {
+            steps.push(Step::Info("Installing dependencies...".to_string()));
+            steps.push(pacman_install(&others, options));
+        }
+
+        Ok(steps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::install::test_pkg;
+    use crate::lockfile::SignatureLock;
+
+    #[test]
+    fn test_split_keyring() {
+        let pkgs = [
+            test_pkg("archlinux", "rust", "rust.pkg.tar.zst"),
+            test_pkg(
+                "archlinux",
+                "archlinux-keyring",
+                "archlinux-keyring.pkg.tar.zst",
+            ),
+            test_pkg("archlinux", "zstd", "zstd.pkg.tar.zst"),
+        ];
+        assert_eq!(
+            split_keyring(&pkgs),
+            (
+                Some("archlinux-keyring.pkg.tar.zst"),
+                vec!["rust.pkg.tar.zst", "zstd.pkg.tar.zst"]
+            )
+        );
+        assert_eq!(split_keyring(&pkgs[..1]), (None, vec!["rust.pkg.tar.zst"]));
+    }
+
+    #[test]
+    fn test_steps_keyring_first() -> Result<()> {
+        let mut pkgs = [
+            test_pkg("archlinux", "rust", "rust.pkg.tar.zst"),
+            test_pkg(
+                "archlinux",
+                "archlinux-keyring",
+                "archlinux-keyring.pkg.tar.zst",
+            ),
+        ];
+        for (pkg, _) in &mut pkgs {
+            pkg.signatures = vec![SignatureLock::openpgp("iHUEABYIAB0WIQQEKYl95fO9rFN6MGltQr3RFuAGjwUCZKPPXgAKCRBtQr3RFuAGj9oXAP94RQ1sKD53/RxVYlVEEOjKHvOmrWvDkt1veMYygnlnIgD+MLg/TT6d71kE8F08+JH+EcnG7wQow5Xr/qBo1VPLdgQ=")];
+        }
+        let steps = Archlinux { keys: &[] }.steps(&pkgs, &Options::default())?;
+
+        let expected = std::time::UNIX_EPOCH + Duration::from_secs(1688457054 + 1);
+        assert_eq!(steps[1], Step::PacmanVerificationTime(expected));
+
+        let cmds = steps
+            .into_iter()
+            .filter_map(|step| match step {
+                Step::Exec(cmd) => Some(cmd.join(" ")),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            cmds,
+            [
+                "pacman -U --noconfirm -- /extra/archlinux-keyring.pkg.tar.zst",
+                "pacman-key --populate archlinux",
+                "pacman -U --noconfirm -- /extra/rust.pkg.tar.zst",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_steps_install_order() -> Result<()> {
+        let mut pkgs = [
+            test_pkg("archlinux", "rust", "rust.pkg.tar.zst"),
+            test_pkg(
+                "archlinux",
+                "ca-certificates",
+                "ca-certificates.pkg.tar.zst",
+            ),
+            test_pkg(
+                "archlinux",
+                "archlinux-keyring",
+                "archlinux-keyring.pkg.tar.zst",
+            ),
+        ];
+        for (pkg, _) in &mut pkgs {
+            pkg.signatures = vec![SignatureLock::openpgp("iHUEABYIAB0WIQQEKYl95fO9rFN6MGltQr3RFuAGjwUCZKPPXgAKCRBtQr3RFuAGj9oXAP94RQ1sKD53/RxVYlVEEOjKHvOmrWvDkt1veMYygnlnIgD+MLg/TT6d71kE8F08+JH+EcnG7wQow5Xr/qBo1VPLdgQ=")];
+        }
+        let options = Options {
+            install_order: ["ca-certificates", "archlinux-keyring"]
+                .map(String::from)
+                .to_vec(),
+            ..Default::default()
+        };
+        let steps = Archlinux { keys: &[] }.steps(&pkgs, &options)?;
+        let cmds = steps
+            .into_iter()
+            .filter_map(|step| match step {
+                Step::Exec(cmd) => Some(cmd.join(" ")),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            cmds,
+            [
+                "pacman -U --noconfirm -- /extra/archlinux-keyring.pkg.tar.zst",
+                "pacman-key --populate archlinux",
+                "pacman -U --noconfirm -- /extra/ca-certificates.pkg.tar.zst",
+                "pacman -U --noconfirm -- /extra/rust.pkg.tar.zst",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_extra_files_requires_signature() {
+        let (pkg, filename) = test_pkg("archlinux", "rust", "rust.pkg.tar.zst");
+        assert!(Archlinux { keys: &[] }
+            .extra_files(&pkg, &filename)
+            .is_err());
+    }
+}
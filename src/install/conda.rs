@@ -0,0 +1,131 @@
+use super::{Installer, Options, Step};
+use crate::errors::*;
+use crate::lockfile::PackageLock;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// The prefix the environment is created in
+pub const CONDA_PREFIX: &str = "/opt/conda";
+
+pub struct Conda;
+
+#[derive(Debug, Deserialize)]
+struct Installed {
+    name: String,
+    version: String,
+}
+
+/// An explicit spec file, micromamba installs exactly these files and checks
+/// their sha256 instead of solving the environment again
+pub fn explicit_spec(pkgs: &[(PackageLock, String)]) -> String {
+    let mut spec = "@EXPLICIT\n".to_string();
+    for (pkg, filename) in pkgs {
+        spec.push_str(&format!("file:///extra/{filename}#{}\n", pkg.sha256));
+    }
+    spec
+}
+
+impl Installer for Conda {
+    fn query_installed(&self) -> &'static [&'static str] {
+        &["micromamba", "list", "--json", "--prefix", CONDA_PREFIX]
+    }
+
+    fn parse_installed(&self, buf: &str) -> Result<HashMap<String, String>> {
+        let installed = serde_json::from_str::<Vec<Installed>>(buf)
+            .context("Failed to parse json output of micromamba list")?;
+        Ok(installed
+            .into_iter()
+            .map(|pkg| (pkg.name, pkg.version))
+            .collect())
+    }
+
+    fn steps(&self, pkgs: &[(PackageLock, String)], _options: &Options) -> Result<Vec<Step>> {
+        let cmd = [
+            "micromamba",
+            "create",
+            "--yes",
+            "--offline",
+            "--prefix",
+            CONDA_PREFIX,
+            "--file",
+            "/tmp/repro-env-conda.txt",
+        ]
+        .map(String::from)
+        .to_vec();
+        Ok(vec![
+            Step::Info("Installing conda packages...".to_string()),
+            Step::WriteFile {
+                dir: "/tmp/",
+                filename: "repro-env-conda.txt".to_string(),
+                content: explicit_spec(pkgs).into_bytes(),
+            },
+            Step::Exec(cmd),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::install::test_pkg;
+
+    #[test]
+    fn test_parse_installed() -> Result<()> {
+        let installed = Conda.parse_installed(
+            r#"[
+  {
+    "base_url": "https://conda.anaconda.org/conda-forge",
+    "build_number": 7,
+    "build_string": "h4bc722e_7",
+    "channel": "conda-forge",
+    "dist_name": "bzip2-1.0.8-h4bc722e_7",
+    "name": "bzip2",
+    "platform": "linux-64",
+    "version": "1.0.8"
+  }
+]"#,
+        )?;
+        assert_eq!(installed.len(), 1);
+        assert_eq!(installed.get("bzip2").map(String::as_str), Some("1.0.8"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_steps() -> Result<()> {
+        let pkgs = [
+            test_pkg("conda", "bzip2", "bzip2-1.0.8-h4bc722e_7.conda"),
+            test_pkg("conda", "python", "python-3.12.5-h2ad013b_0_cpython.conda"),
+        ];
+        let steps = Conda.steps(&pkgs, &Options::default())?;
+        assert_eq!(
+            steps[1],
+            Step::WriteFile {
+                dir: "/tmp/",
+                filename: "repro-env-conda.txt".to_string(),
+                content: b"@EXPLICIT
+file:///extra/bzip2-1.0.8-h4bc722e_7.conda#00
+file:///extra/python-3.12.5-h2ad013b_0_cpython.conda#00
+"
+                .to_vec(),
+            }
+        );
+        assert_eq!(
+            steps[2],
+            Step::Exec(
+                [
+                    "micromamba",
+                    "create",
+                    "--yes",
+                    "--offline",
+                    "--prefix",
+                    "/opt/conda",
+                    "--file",
+                    "/tmp/repro-env-conda.txt",
+                ]
+                .map(String::from)
+                .to_vec()
+            )
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+use super::{Installer, Options, Step};
+use crate::errors::*;
+use crate::install;
+use crate::lockfile::PackageLock;
+use std::collections::{HashMap, HashSet};
+
+pub struct Debian;
+
+/// Sort debian packages into batches that dpkg can install one after another,
+/// using the `depends` recorded in the lockfile
+fn dpkg_install_order(pkgs: &[(PackageLock, String)]) -> Vec<Vec<String>> {
+    let mut pending = pkgs.iter().collect::<Vec<_>>();
+    let mut installed = HashSet::new();
+    let mut batches = Vec::new();
+
+    while !pending.is_empty() {
+        let (ready, blocked): (Vec<_>, Vec<_>) = pending.into_iter().partition(|(pkg, _)| {
+            pkg.depends.iter().all(|dep| {
+                installed.contains(dep.as_str()) || !pkgs.iter().any(|(p, _)| &p.name == dep)
+            })
+        });
+
+        if ready.is_empty() {
+            // dependency cycle, dpkg can configure these in a single invocation
+            batches.push(blocked.into_iter().map(|(_, f)| f.clone()).collect());
+            break;
+        }
+
+        installed.extend(ready.iter().map(|(pkg, _)| pkg.name.as_str()));
+        batches.push(ready.into_iter().map(|(_, f)| f.clone()).collect());
+        pending = blocked;
+    }
+
+    batches
+}
+
+fn apt_get_install(filenames: &[&str]) -> Step {
+    let mut cmd = vec![
+        "apt-get".to_string(),
+        "install".to_string(),
+        "--".to_string(),
+    ];
+    for filename in filenames {
+        cmd.push(format!("/extra/{filename}"));
+    }
+    Step::Exec(cmd)
+}
+
+impl Installer for Debian {
+    fn query_installed(&self) -> &'static [&'static str] {
+        &[
+            "dpkg-query",
+            "-W",
+            "-f=${db:Status-Abbrev}\t${Package}\t${Version}\n",
+        ]
+    }
+
+    fn parse_installed(&self, buf: &str) -> Result<HashMap<String, String>> {
+        let mut installed = HashMap::new();
+        for line in buf.lines() {
+            let mut parts = line.split('\t');
+            let (Some(status), Some(name), Some(version)) =
+                (parts.next(), parts.next(), parts.next())
+            else {
+                bail!("Unexpected output from dpkg-query: {line:?}");
+            };
+            // only packages that are unpacked and configured, not just known to dpkg
+            if status.chars().nth(1) == Some('i') {
+                installed.insert(name.to_string(), version.to_string());
+            }
+        }
+        Ok(installed)
+    }
+
+    fn steps(&self, pkgs: &[(PackageLock, String)], options: &Options) -> Result<Vec<Step>> {
+        let mut steps = Vec::new();
+        let mut pkgs = pkgs.to_vec();
+        let first = install::take_install_order(&mut pkgs, &options.install_order);
+        if options.has_apt && !options.ignore_depends {
+            for (pkg, filename) in &first {
+                steps.push(Step::Info(format!("Installing {:?} first...", pkg.name)));
+                steps.push(apt_get_install(&[filename]));
+            }
+            if !pkgs.is_empty() {
+                let filenames = pkgs.iter().map(|(_, f)| f.as_str()).collect::<Vec<_>>();
+                steps.push(Step::Info("Installing dependencies...".to_string()));
+                steps.push(apt_get_install(&filenames));
+            }
+        } else {
+            steps.push(Step::Info(if options.ignore_depends {
+                // apt-get can't leave out dependencies
+                "Some packages are left out, installing dependencies with dpkg --force-depends..."
+                    .to_string()
+            } else {
+                "No apt-get in container, installing dependencies with dpkg...".to_string()
+            }));
+            let dpkg = |filenames: &[String]| {
+                let mut cmd = vec!["dpkg".to_string(), "-i".to_string()];
+                if options.ignore_depends {
+                    cmd.push("--force-depends".to_string());
+                }
+                cmd.push("--".to_string());
+                for filename in filenames {
+                    cmd.push(format!("/extra/{filename}"));
+                }
+                Step::Exec(cmd)
+            };
+            for (_, filename) in &first {
+                steps.push(dpkg(std::slice::from_ref(filename)));
+            }
+            for batch in dpkg_install_order(&pkgs) {
+                steps.push(dpkg(&batch));
+            }
+        }
+        Ok(steps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::install::test_pkg;
+
+    fn pkg(name: &str, depends: &[&str]) -> (PackageLock, String) {
+        let (mut lock, filename) = test_pkg("debian", name, &format!("{name}.deb"));
+        lock.depends = depends.iter().map(|d| d.to_string()).collect();
+        (lock, filename)
+    }
+
+    #[test]
+    fn test_dpkg_install_order() {
+        let order = dpkg_install_order(&[
+            pkg("rustc", &["libstd-rust-dev", "libc6"]),
+            pkg("libstd-rust-dev", &["libc6"]),
+            pkg("cycle-a", &["cycle-b"]),
+            pkg("cycle-b", &["cycle-a", "rustc"]),
+        ]);
+        assert_eq!(
+            order,
+            vec![
+                vec!["libstd-rust-dev.deb".to_string()],
+                vec!["rustc.deb".to_string()],
+                vec!["cycle-a.deb".to_string(), "cycle-b.deb".to_string()],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_steps_without_apt() -> Result<()> {
+        let pkgs = [
+            pkg("rustc", &["libstd-rust-dev"]),
+            pkg("libstd-rust-dev", &[]),
+        ];
+        let steps = Debian.steps(&pkgs, &Options::default())?;
+        assert_eq!(
+            steps[1..],
+            [
+                Step::Exec(
+                    ["dpkg", "-i", "--", "/extra/libstd-rust-dev.deb"]
+                        .map(String::from)
+                        .to_vec()
+                ),
+                Step::Exec(
+                    ["dpkg", "-i", "--", "/extra/rustc.deb"]
+                        .map(String::from)
+                        .to_vec()
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_steps_ignore_depends() -> Result<()> {
+        let pkgs = [pkg("rustc", &["libstd-rust-dev"])];
+        let options = Options {
+            has_apt: true,
+            ignore_depends: true,
+            ..Default::default()
+        };
+        let cmds = Debian
+            .steps(&pkgs, &options)?
+            .into_iter()
+            .filter_map(|step| match step {
+                Step::Exec(cmd) => Some(cmd.join(" ")),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(cmds, ["dpkg -i --force-depends -- /extra/rustc.deb"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_steps_install_order() -> Result<()> {
+        let pkgs = [
+            pkg("rustc", &[]),
+            pkg("ca-certificates", &[]),
+            pkg("openssl", &[]),
+        ];
+        let options = Options {
+            has_apt: true,
+            install_order: ["openssl", "ca-certificates"].map(String::from).to_vec(),
+            ..Default::default()
+        };
+        let cmds = Debian
+            .steps(&pkgs, &options)?
+            .into_iter()
+            .filter_map(|step| match step {
+                Step::Exec(cmd) => Some(cmd.join(" ")),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            cmds,
+            [
+                "apt-get install -- /extra/openssl.deb",
+                "apt-get install -- /extra/ca-certificates.deb",
+                "apt-get install -- /extra/rustc.deb",
+            ]
+        );
+        Ok(())
+    }
+}
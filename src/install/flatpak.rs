@@ -0,0 +1,195 @@
+use super::{Installer, Options, Step};
+use crate::container::{self, Container};
+use crate::errors::*;
+use crate::hash;
+use crate::lockfile::PackageLock;
+use std::collections::HashMap;
+
+/// The remote of flathub, used if the manifest doesn't configure one
+pub const DEFAULT_REMOTE: &str = "https://dl.flathub.org/repo/flathub.flatpakrepo";
+
+pub struct Flatpak;
+
+/// The name the remote is added with, derived from its url so it's stable
+pub fn remote_name(url: &str) -> String {
+    format!("repro-env-{}", &hash::sha256(url.as_bytes())[..12])
+}
+
+/// Add the remote (a .flatpakrepo file, with the signing key of the repository)
+pub fn remote_add(url: &str) -> Vec<String> {
+    [
+        "flatpak",
+        "remote-add",
+        "--system",
+        "--if-not-exists",
+        "--",
+        &remote_name(url),
+        url,
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+impl Installer for Flatpak {
+    fn query_installed(&self) -> &'static [&'static str] {
+        &[
+            "flatpak",
+            "list",
+            "--system",
+            "--runtime",
+            "--columns=ref:f,active:f",
+        ]
+    }
+
+    /// The ref of each runtime (without `runtime/`) and the commit that's checked out
+    fn parse_installed(&self, buf: &str) -> Result<HashMap<String, String>> {
+        let mut installed = HashMap::new();
+        for line in buf.lines().filter(|line| !line.trim().is_empty()) {
+            let mut parts = line.split_whitespace();
+            let (Some(name), Some(commit)) = (parts.next(), parts.next()) else {
+                bail!("Unexpected output from flatpak list: {line:?}");
+            };
+            installed.insert(name.to_string(), commit.to_string());
+        }
+        Ok(installed)
+    }
+
+    fn steps(&self, pkgs: &[(PackageLock, String)], _options: &Options) -> Result<Vec<Step>> {
+        let mut steps = vec![Step::Info("Installing flatpak runtimes...".to_string())];
+        let mut remotes = Vec::new();
+        for (pkg, _) in pkgs {
+            if !remotes.contains(&pkg.url) {
+                steps.push(Step::Exec(remote_add(&pkg.url)));
+                remotes.push(pkg.url.clone());
+            }
+        }
+        for (pkg, _) in pkgs {
+            let commit = pkg
+                .commit
+                .clone()
+                .with_context(|| anyhow!("Flatpak runtime has no commit: {:?}", pkg.name))?;
+            let flatpak_ref = format!("runtime/{}", pkg.name);
+            let flags = ["--system", "--noninteractive", "--no-deps", "--no-related"];
+            // `flatpak install` has no option to select a commit, the pinned
+            // one is deployed with `flatpak update --commit` right after
+            let mut install = vec!["flatpak".to_string(), "install".to_string()];
+            install.extend(flags.map(String::from));
+            install.extend(["--".to_string(), remote_name(&pkg.url), flatpak_ref.clone()]);
+            steps.push(Step::Exec(install));
+
+            let mut update = vec!["flatpak".to_string(), "update".to_string()];
+            update.extend(flags.map(String::from));
+            update.extend([
+                format!("--commit={commit}"),
+                "--".to_string(),
+                flatpak_ref.clone(),
+            ]);
+            steps.push(Step::Exec(update));
+            steps.push(Step::VerifyFlatpakCommit {
+                flatpak_ref,
+                commit,
+            });
+        }
+        Ok(steps)
+    }
+}
+
+/// Make sure the pinned commit of the ref is the one that's deployed
+pub async fn verify_commit(container: &Container, flatpak_ref: &str, commit: &str) -> Result<()> {
+    debug!("Verifying commit of flatpak runtime {flatpak_ref:?}...");
+    let buf = container
+        .exec(
+            &[
+                "flatpak",
+                "info",
+                "--system",
+                "--show-commit",
+                "--",
+                flatpak_ref,
+            ],
+            container::Exec {
+                capture_stdout: true,
+                ..Default::default()
+            },
+        )
+        .await?;
+    let deployed = String::from_utf8(buf).context("Failed to decode flatpak output as utf8")?;
+    let deployed = deployed.trim();
+    if deployed != commit {
+        bail!("Flatpak runtime {flatpak_ref:?} is at commit {deployed:?}, but the lockfile pins {commit:?}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::install::test_pkg;
+
+    #[test]
+    fn test_steps() -> Result<()> {
+        let (mut pkg, filename) = test_pkg("flatpak", "org.freedesktop.Sdk/x86_64/23.08", "");
+        pkg.url = DEFAULT_REMOTE.to_string();
+        pkg.version = "a".repeat(64);
+        pkg.sha256 = String::new();
+        pkg.commit = Some("a".repeat(64));
+        let remote = remote_name(DEFAULT_REMOTE);
+        let steps = Flatpak.steps(&[(pkg, filename)], &Options::default())?;
+        assert_eq!(
+            steps[1..],
+            [
+                Step::Exec(remote_add(DEFAULT_REMOTE)),
+                Step::Exec(
+                    [
+                        "flatpak",
+                        "install",
+                        "--system",
+                        "--noninteractive",
+                        "--no-deps",
+                        "--no-related",
+                        "--",
+                        &remote,
+                        "runtime/org.freedesktop.Sdk/x86_64/23.08",
+                    ]
+                    .map(String::from)
+                    .to_vec()
+                ),
+                Step::Exec(
+                    [
+                        "flatpak",
+                        "update",
+                        "--system",
+                        "--noninteractive",
+                        "--no-deps",
+                        "--no-related",
+                        &format!("--commit={}", "a".repeat(64)),
+                        "--",
+                        "runtime/org.freedesktop.Sdk/x86_64/23.08",
+                    ]
+                    .map(String::from)
+                    .to_vec()
+                ),
+                Step::VerifyFlatpakCommit {
+                    flatpak_ref: "runtime/org.freedesktop.Sdk/x86_64/23.08".to_string(),
+                    commit: "a".repeat(64),
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_installed() -> Result<()> {
+        let installed = Flatpak.parse_installed(
+            "org.freedesktop.Platform/x86_64/23.08\t1f2e3d\norg.freedesktop.Sdk/x86_64/23.08\t4c5b6a\n",
+        )?;
+        assert_eq!(
+            installed
+                .get("org.freedesktop.Sdk/x86_64/23.08")
+                .map(String::as_str),
+            Some("4c5b6a")
+        );
+        assert_eq!(installed.len(), 2);
+        Ok(())
+    }
+}
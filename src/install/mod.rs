@@ -0,0 +1,592 @@
+//! Installing the packages from /extra into the build container. Installers
+//! only describe the steps, so ordering and command construction can be tested
+//! without podman.
+pub mod alpine;
+pub mod archlinux;
+pub mod conda;
+pub mod debian;
+pub mod flatpak;
+pub mod openwrt;
+pub mod snap;
+
+use crate::consistency;
+use crate::container::{self, CommandError, Container};
+use crate::errors::*;
+use crate::lockfile::{ApkKeyLock, KeyLock, PackageLock};
+use crate::pkgs;
+use crate::summary;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::time::SystemTime;
+
+const VERIFY_SUMS_FILENAME: &str = "repro-env.sha256sums";
+
+/// A single action of the install phase
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    Info(String),
+    /// Write a file into a directory of the container (with trailing slash)
+    WriteFile {
+        dir: &'static str,
+        filename: String,
+        content: Vec<u8>,
+    },
+    Exec(Vec<String>),
+    /// Configure the time pacman uses to verify signatures
+    PacmanVerificationTime(SystemTime),
+    /// Check the files in /extra with `sha256sum -c` against these checksums
+    VerifyExtra(Vec<u8>),
+    /// Check with `flatpak info --show-commit` that this commit of the ref is deployed
+    VerifyFlatpakCommit {
+        flatpak_ref: String,
+        commit: String,
+    },
+}
+
+/// What's known about the container and the build before installing
+#[derive(Debug, Default)]
+pub struct Options {
+    pub allow_untrusted: bool,
+    pub has_apt: bool,
+    /// Packages that are installed before the others, one at a time in this order
+    pub install_order: Vec<String>,
+    /// Some locked packages are left out (`--only`/`--skip`), so the package
+    /// manager must not insist on the dependencies being installed
+    pub ignore_depends: bool,
+}
+
+/// Remove the packages of `install_order` from `pkgs` and return them in that
+/// order, names that aren't in `pkgs` are skipped
+pub fn take_install_order(
+    pkgs: &mut Vec<(PackageLock, String)>,
+    install_order: &[String],
+) -> Vec<(PackageLock, String)> {
+    let mut first = Vec::new();
+    for name in install_order {
+        if let Some(idx) = pkgs.iter().position(|(pkg, _)| pkg.name == *name) {
+            first.push(pkgs.remove(idx));
+        }
+    }
+    first
+}
+
+pub trait Installer {
+    /// Check a package file before it's made available in /extra
+    fn verify(&self, _pkg: &PackageLock, _filename: &str, _reader: &mut dyn Read) -> Result<()> {
+        Ok(())
+    }
+
+    /// Files that need to be placed next to the package in /extra
+    fn extra_files(&self, _pkg: &PackageLock, _filename: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        Ok(vec![])
+    }
+
+    /// The steps to install these packages (with their filenames in /extra)
+    fn steps(&self, pkgs: &[(PackageLock, String)], options: &Options) -> Result<Vec<Step>>;
+
+    /// The command that lists the installed packages and their versions
+    fn query_installed(&self) -> &'static [&'static str];
+
+    /// Parse the output of the query into package name => version
+    fn parse_installed(&self, buf: &str) -> Result<HashMap<String, String>>;
+}
+
+/// Describe how the installed packages differ from the locked ones, if they do
+pub fn compare_installed(
+    pkgs: &[(PackageLock, String)],
+    installed: &HashMap<String, String>,
+) -> Option<String> {
+    let mut problems = Vec::new();
+    for (pkg, _) in pkgs {
+        match installed.get(&pkg.name) {
+            Some(version) if *version == pkg.version => (),
+            Some(version) => problems.push(format!(
+                "{:?}: {version:?} is installed, but {:?} is locked",
+                pkg.name, pkg.version
+            )),
+            None => problems.push(format!("{:?}: not installed", pkg.name)),
+        }
+    }
+    (!problems.is_empty()).then(|| problems.join("\n"))
+}
+
+/// The packages (and keys) that have been set up in /extra
+#[derive(Debug, PartialEq, Default)]
+pub struct Install {
+    pub alpine: Vec<(PackageLock, String)>,
+    pub archlinux: Vec<(PackageLock, String)>,
+    /// Packages for an environment in /opt/conda
+    pub conda: Vec<(PackageLock, String)>,
+    pub debian: Vec<(PackageLock, String)>,
+    /// Runtimes that are pulled by flatpak itself, they have no file in /extra
+    pub flatpak: Vec<(PackageLock, String)>,
+    /// Packages for the SDK or ImageBuilder, they are staged but not installed
+    pub openwrt: Vec<(PackageLock, String)>,
+    pub snap: Vec<(PackageLock, String)>,
+    pub keys: Vec<(KeyLock, String)>,
+    pub apk_keys: Vec<ApkKeyLock>,
+    /// The `install_order` of the lockfile
+    pub install_order: Vec<String>,
+    /// Install without checking dependencies, see [`Options::ignore_depends`]
+    pub ignore_depends: bool,
+}
+
+impl Install {
+    pub fn add_pkg(&mut self, pkg: PackageLock, filename: String) -> Result<()> {
+        let list = match pkg.system.as_str() {
+            "alpine" => &mut self.alpine,
+            "archlinux" => &mut self.archlinux,
+            "conda" => &mut self.conda,
+            "debian" => &mut self.debian,
+            "flatpak" => &mut self.flatpak,
+            "openwrt" => &mut self.openwrt,
+            "snap" => &mut self.snap,
+            system => bail!("Unknown package system: {system:?}"),
+        };
+        list.push((pkg, filename));
+        Ok(())
+    }
+
+    pub fn installer(&self, system: &str) -> Result<Box<dyn Installer + '_>> {
+        match system {
+            "alpine" => Ok(Box::new(alpine::Alpine {
+                apk_keys: &self.apk_keys,
+            })),
+            "archlinux" => Ok(Box::new(archlinux::Archlinux { keys: &self.keys })),
+            "conda" => Ok(Box::new(conda::Conda)),
+            "debian" => Ok(Box::new(debian::Debian)),
+            "flatpak" => Ok(Box::new(flatpak::Flatpak)),
+            "openwrt" => Ok(Box::new(openwrt::Openwrt)),
+            "snap" => Ok(Box::new(snap::Snap)),
+            system => bail!("Unknown package system: {system:?}"),
+        }
+    }
+
+    /// Check a package file, returns the files that need to be placed next to it in /extra
+    pub fn verify(
+        &self,
+        pkg: &PackageLock,
+        filename: &str,
+        path: &Path,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        for sig in pkg.signatures.iter().filter(|sig| !sig.is_supported()) {
+            warn!(
+                "Ignoring {:?} signature of {filename:?}, this algorithm is not supported",
+                sig.algorithm
+            );
+        }
+        let installer = self.installer(&pkg.system)?;
+        let mut file =
+            File::open(path).with_context(|| anyhow!("Failed to open package: {path:?}"))?;
+        installer.verify(pkg, filename, &mut file)?;
+        installer.extra_files(pkg, filename)
+    }
+
+    /// Verify the packages from inside of the container before they are
+    /// installed, this detects tampering after staging and broken mounts
+    pub fn verify_step(&self) -> Option<Step> {
+        let mut sums = String::new();
+        for (pkg, filename) in self
+            .alpine
+            .iter()
+            .chain(&self.archlinux)
+            .chain(&self.conda)
+            .chain(&self.debian)
+            .chain(&self.openwrt)
+            .chain(&self.snap)
+        {
+            sums.push_str(&format!("{}  /extra/{filename}\n", pkg.sha256));
+        }
+        (!sums.is_empty()).then(|| Step::VerifyExtra(sums.into_bytes()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.alpine.len()
+            + self.archlinux.len()
+            + self.conda.len()
+            + self.debian.len()
+            + self.flatpak.len()
+            + self.openwrt.len()
+            + self.snap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn steps(&self, options: &Options) -> Result<Vec<Step>> {
+        let mut steps = Vec::new();
+        for (system, pkgs) in [
+            ("alpine", &self.alpine),
+            ("archlinux", &self.archlinux),
+            ("conda", &self.conda),
+            ("debian", &self.debian),
+            ("flatpak", &self.flatpak),
+            ("openwrt", &self.openwrt),
+            ("snap", &self.snap),
+        ] {
+            if !pkgs.is_empty() {
+                steps.extend(self.installer(system)?.steps(pkgs, options)?);
+            }
+        }
+        Ok(steps)
+    }
+}
+
+async fn has_apt(container: &Container) -> bool {
+    let cmd = ["sh", "-c", "command -v apt-get"];
+    let exec = container::Exec {
+        capture_stdout: true,
+        ..Default::default()
+    };
+    container.exec(&cmd, exec).await.is_ok()
+}
+
+/// Make sure the image is a distribution that can install these packages,
+/// images without a readable os-release are not checked
+async fn check_distro(container: &Container, install: &Install) -> Result<()> {
+    let os_release = match container.cat("/etc/os-release").await {
+        Ok(buf) => String::from_utf8_lossy(&buf).into_owned(),
+        Err(err) => {
+            debug!("Failed to read os-release of container, skipping distribution check: {err:#}");
+            return Ok(());
+        }
+    };
+    for (system, pkgs) in [
+        ("alpine", &install.alpine),
+        ("archlinux", &install.archlinux),
+        ("debian", &install.debian),
+    ] {
+        if !pkgs.is_empty() {
+            consistency::check_distro(&os_release, system)?;
+        }
+    }
+    Ok(())
+}
+
+async fn execute(container: &Container, step: Step) -> Result<()> {
+    match step {
+        Step::Info(msg) => info!("{msg}"),
+        Step::WriteFile {
+            dir,
+            filename,
+            content,
+        } => {
+            debug!("Writing file to container: {dir}{filename}");
+            container.write_file(dir, &filename, &content).await?;
+        }
+        Step::Exec(cmd) => {
+            debug!("Executing in container: {cmd:?}");
+            container.exec(&cmd, container::Exec::default()).await?;
+        }
+        Step::PacmanVerificationTime(time) => {
+            pkgs::archlinux::set_pacman_verification_datetime(container, time).await?;
+        }
+        Step::VerifyExtra(sums) => verify_extra(container, &sums).await?,
+        Step::VerifyFlatpakCommit {
+            flatpak_ref,
+            commit,
+        } => flatpak::verify_commit(container, &flatpak_ref, &commit).await?,
+    }
+    Ok(())
+}
+
+async fn verify_extra(container: &Container, sums: &[u8]) -> Result<()> {
+    info!("Verifying packages in /extra...");
+    container
+        .write_file("/tmp/", VERIFY_SUMS_FILENAME, sums)
+        .await?;
+    let path = format!("/tmp/{VERIFY_SUMS_FILENAME}");
+    let exec = container::Exec {
+        capture_stdout: true,
+        capture_stderr: true,
+        ..Default::default()
+    };
+    let Err(err) = container.exec(&["sha256sum", "-c", &path], exec).await else {
+        return Ok(());
+    };
+    let Some(err) = err.downcast_ref::<CommandError>() else {
+        return Err(err);
+    };
+    let stdout = String::from_utf8_lossy(&err.stdout);
+    let mut details = stdout
+        .lines()
+        .filter(|line| !line.ends_with(": OK"))
+        .collect::<Vec<_>>();
+    details.extend(err.stderr.lines());
+    bail!(
+        "Packages in /extra don't match the lockfile, the staging directory was modified or isn't mounted correctly:\n{}",
+        details.join("\n")
+    )
+}
+
+/// Query the package database of the container and make sure the locked
+/// versions have been installed, a package manager may skip or replace
+/// packages if they conflict with the base image
+async fn verify_installed(
+    container: &Container,
+    system: &str,
+    installer: &dyn Installer,
+    pkgs: &[(PackageLock, String)],
+) -> Result<()> {
+    debug!("Verifying installed {system} packages...");
+    let exec = container::Exec {
+        capture_stdout: true,
+        ..Default::default()
+    };
+    let buf = container.exec(installer.query_installed(), exec).await?;
+    let buf = String::from_utf8(buf).context("Failed to decode package database query as utf8")?;
+    let installed = installer.parse_installed(&buf)?;
+    if let Some(problems) = compare_installed(pkgs, &installed) {
+        bail!("Installed packages don't match the lockfile:\n{problems}");
+    }
+    Ok(())
+}
+
+/// Install the packages from /extra into the container
+pub async fn run(container: &Container, install: &Install, allow_untrusted: bool) -> Result<()> {
+    let _phase = summary::phase("install");
+    summary::record_installed(install.len());
+
+    if !install.is_empty() {
+        check_distro(container, install).await?;
+    }
+    let options = Options {
+        allow_untrusted,
+        has_apt: !install.debian.is_empty() && has_apt(container).await,
+        install_order: install.install_order.clone(),
+        ignore_depends: install.ignore_depends,
+    };
+    if let Some(step) = install.verify_step() {
+        execute(container, step).await?;
+    }
+    for step in install.steps(&options)? {
+        execute(container, step).await?;
+    }
+    for (system, pkgs) in [
+        ("alpine", &install.alpine),
+        ("archlinux", &install.archlinux),
+        ("conda", &install.conda),
+        ("debian", &install.debian),
+        ("flatpak", &install.flatpak),
+        // openwrt packages are only staged, there's nothing installed to verify
+        ("snap", &install.snap),
+    ] {
+        if !pkgs.is_empty() {
+            verify_installed(container, system, &*install.installer(system)?, pkgs).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn test_pkg(system: &str, name: &str, filename: &str) -> (PackageLock, String) {
+    let lock = PackageLock {
+        name: name.to_string(),
+        version: "1.0-1".to_string(),
+        system: system.to_string(),
+        url: format!("https://example.com/{filename}"),
+        sha256: "00".to_string(),
+        ..Default::default()
+    };
+    (lock, filename.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_runtime::{self, FakeRuntime};
+
+    #[test]
+    fn test_steps_order_by_system() -> Result<()> {
+        let mut install = Install::default();
+        let (pkg, filename) = test_pkg("debian", "rustc", "rustc.deb");
+        install.add_pkg(pkg, filename)?;
+        let (pkg, filename) = test_pkg("alpine", "rust", "rust.apk");
+        install.add_pkg(pkg, filename)?;
+        assert_eq!(install.len(), 2);
+
+        let steps = install.steps(&Options {
+            has_apt: true,
+            ..Default::default()
+        })?;
+        let cmds = steps
+            .into_iter()
+            .filter_map(|step| match step {
+                Step::Exec(cmd) => Some(cmd.join(" ")),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            cmds,
+            [
+                "apk add --no-network -- /extra/rust.apk",
+                "apt-get install -- /extra/rustc.deb"
+            ]
+        );
+
+        let (pkg, filename) = test_pkg("gentoo", "rust", "rust.tbz2");
+        assert!(install.add_pkg(pkg, filename).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_take_install_order() {
+        let pkgs = [
+            test_pkg("debian", "rustc", "rustc.deb"),
+            test_pkg("debian", "ca-certificates", "ca-certificates.deb"),
+            test_pkg("debian", "openssl", "openssl.deb"),
+        ];
+        let order = ["openssl", "ca-certificates", "gnupg"].map(String::from);
+        let mut others = pkgs.to_vec();
+        let first = take_install_order(&mut others, &order);
+        assert_eq!(first, [pkgs[2].clone(), pkgs[1].clone()]);
+        assert_eq!(others, [pkgs[0].clone()]);
+    }
+
+    #[tokio::test]
+    async fn test_run_without_apt() -> Result<()> {
+        let mut install = Install::default();
+        let (mut pkg, filename) = test_pkg("debian", "rustc", "rustc.deb");
+        pkg.depends = vec!["libc6".to_string()];
+        install.add_pkg(pkg, filename)?;
+        let (pkg, filename) = test_pkg("debian", "libc6", "libc6.deb");
+        install.add_pkg(pkg, filename)?;
+
+        let runtime = FakeRuntime::new();
+        runtime.fail(&["command -v apt-get"]);
+        runtime.reply(&["dpkg-query"], "ii \tlibc6\t1.0-1\nii \trustc\t1.0-1\n");
+        let container = Container {
+            id: fake_runtime::CONTAINER_ID.to_string(),
+        };
+        runtime.run(run(&container, &install, false)).await?;
+
+        assert_eq!(
+            runtime.execs(),
+            [
+                "sh -c command -v apt-get",
+                "sha256sum -c /tmp/repro-env.sha256sums",
+                "dpkg -i -- /extra/libc6.deb",
+                "dpkg -i -- /extra/rustc.deb",
+                "dpkg-query -W -f=${db:Status-Abbrev}\t${Package}\t${Version}\n",
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_verify_installed() -> Result<()> {
+        let mut install = Install::default();
+        let (pkg, filename) = test_pkg("debian", "rustc", "rustc.deb");
+        install.add_pkg(pkg, filename)?;
+        let (pkg, filename) = test_pkg("debian", "lua5.4", "lua5.4.deb");
+        install.add_pkg(pkg, filename)?;
+
+        let runtime = FakeRuntime::new();
+        runtime.reply(
+            &["dpkg-query"],
+            "ii \trustc\t2.0-1\nrc \tlua5.4\t1.0-1\nii \tzstd\t1.5.4+dfsg2-5\n",
+        );
+        let container = Container {
+            id: fake_runtime::CONTAINER_ID.to_string(),
+        };
+        let err = runtime
+            .run(run(&container, &install, false))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "Installed packages don't match the lockfile:
+\"rustc\": \"2.0-1\" is installed, but \"1.0-1\" is locked
+\"lua5.4\": not installed"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_installed() -> Result<()> {
+        let installed = debian::Debian.parse_installed(
+            "ii \tlibc6\t2.36-9+deb12u4\nrc \told-pkg\t1.0-1\nii \tperl-base\t5.36.0-7+deb12u1\n",
+        )?;
+        assert_eq!(installed.len(), 2);
+        assert_eq!(installed["libc6"], "2.36-9+deb12u4");
+
+        let installed = archlinux::Archlinux { keys: &[] }
+            .parse_installed("rust 1:1.70.0-1\nzstd 1.5.5-1\n")?;
+        assert_eq!(installed["rust"], "1:1.70.0-1");
+
+        let installed = alpine::Alpine { apk_keys: &[] }
+            .parse_installed("busybox-1.36.1-r2\nca-certificates-bundle-20230506-r0\n")?;
+        assert_eq!(installed["busybox"], "1.36.1-r2");
+        assert_eq!(installed["ca-certificates-bundle"], "20230506-r0");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify_extra() -> Result<()> {
+        let mut install = Install::default();
+        let (mut pkg, filename) = test_pkg("debian", "rustc", "rustc.deb");
+        pkg.sha256 = "aa".to_string();
+        install.add_pkg(pkg, filename)?;
+        let (pkg, filename) = test_pkg("alpine", "rust", "rust.apk");
+        install.add_pkg(pkg, filename)?;
+        assert_eq!(
+            install.verify_step(),
+            Some(Step::VerifyExtra(
+                b"00  /extra/rust.apk\naa  /extra/rustc.deb\n".to_vec()
+            ))
+        );
+        assert_eq!(Install::default().verify_step(), None);
+
+        let runtime = FakeRuntime::new();
+        runtime.fail_with(
+            &["sha256sum"],
+            "sha256sum: WARNING: 1 computed checksum did NOT match",
+        );
+        let container = Container {
+            id: fake_runtime::CONTAINER_ID.to_string(),
+        };
+        let err = runtime
+            .run(run(&container, &install, false))
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("1 computed checksum did NOT match"));
+        // nothing is installed
+        assert_eq!(
+            runtime.execs(),
+            [
+                "sh -c command -v apt-get",
+                "sha256sum -c /tmp/repro-env.sha256sums"
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_wrong_distro() -> Result<()> {
+        let mut install = Install::default();
+        let (pkg, filename) = test_pkg("debian", "rustc", "rustc.deb");
+        install.add_pkg(pkg, filename)?;
+
+        let runtime = FakeRuntime::new();
+        runtime.reply(
+            &[&format!("{}:/etc/os-release", fake_runtime::CONTAINER_ID)],
+            fake_runtime::tar_file("os-release", b"NAME=\"Arch Linux\"\nID=arch\n"),
+        );
+        let container = Container {
+            id: fake_runtime::CONTAINER_ID.to_string(),
+        };
+        let err = runtime
+            .run(run(&container, &install, false))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Lockfile has debian packages, but the container image is \"arch\""
+        );
+        assert!(runtime.execs().is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+use super::{Installer, Options, Step};
+use crate::errors::*;
+use crate::lockfile::PackageLock;
+use std::collections::HashMap;
+
+/// OpenWrt packages are for the firmware, not the build container. They are
+/// only staged in /extra, so the SDK or ImageBuilder can use them as local feed.
+pub struct Openwrt;
+
+impl Installer for Openwrt {
+    fn query_installed(&self) -> &'static [&'static str] {
+        &["opkg", "list-installed"]
+    }
+
+    /// `busybox - 1.36.1-1`
+    fn parse_installed(&self, buf: &str) -> Result<HashMap<String, String>> {
+        let mut installed = HashMap::new();
+        for line in buf.lines().filter(|line| !line.trim().is_empty()) {
+            let Some((name, version)) = line.split_once(" - ") else {
+                bail!("Unexpected output from opkg: {line:?}");
+            };
+            installed.insert(name.to_string(), version.trim().to_string());
+        }
+        Ok(installed)
+    }
+
+    fn steps(&self, pkgs: &[(PackageLock, String)], _options: &Options) -> Result<Vec<Step>> {
+        Ok(vec![Step::Info(format!(
+            "Staged {} openwrt packages in /extra, they are not installed into the container",
+            pkgs.len()
+        ))])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_installed() -> Result<()> {
+        let installed = Openwrt.parse_installed("busybox - 1.36.1-1\nlibc - 1.2.4-4\n")?;
+        assert_eq!(
+            installed.get("busybox").map(String::as_str),
+            Some("1.36.1-1")
+        );
+        assert_eq!(installed.len(), 2);
+        Ok(())
+    }
+}
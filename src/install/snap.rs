@@ -0,0 +1,101 @@
+use super::{Installer, Options, Step};
+use crate::errors::*;
+use crate::lockfile::PackageLock;
+use std::collections::HashMap;
+
+pub struct Snap;
+
+impl Installer for Snap {
+    fn query_installed(&self) -> &'static [&'static str] {
+        &["snap", "list"]
+    }
+
+    fn parse_installed(&self, buf: &str) -> Result<HashMap<String, String>> {
+        let mut installed = HashMap::new();
+        for line in buf.lines().skip(1).filter(|line| !line.trim().is_empty()) {
+            let mut parts = line.split_whitespace();
+            let (Some(name), Some(version)) = (parts.next(), parts.next()) else {
+                bail!("Unexpected output from snap list: {line:?}");
+            };
+            installed.insert(name.to_string(), version.to_string());
+        }
+        Ok(installed)
+    }
+
+    fn steps(&self, pkgs: &[(PackageLock, String)], _options: &Options) -> Result<Vec<Step>> {
+        // without the store assertions the snaps can only be installed as
+        // local snaps, snapd installs their bases first if they're in the same change
+        let mut cmd = vec![
+            "snap".to_string(),
+            "install".to_string(),
+            "--dangerous".to_string(),
+            "--".to_string(),
+        ];
+        for (_, filename) in pkgs {
+            cmd.push(format!("/extra/{filename}"));
+        }
+        Ok(vec![
+            Step::Info("Installing snaps...".to_string()),
+            Step::Exec(cmd),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::install::test_pkg;
+
+    #[test]
+    fn test_parse_installed() -> Result<()> {
+        let installed = Snap.parse_installed(
+            "Name       Version   Rev    Tracking       Publisher   Notes
+core22     20240111  1122   latest/stable  canonical✓  base
+snapcraft  8.2.5     x1     -              -           classic
+",
+        )?;
+        assert_eq!(installed.len(), 2);
+        assert_eq!(
+            installed.get("core22").map(String::as_str),
+            Some("20240111")
+        );
+        assert_eq!(
+            installed.get("snapcraft").map(String::as_str),
+            Some("8.2.5")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_steps() -> Result<()> {
+        let pkgs = [
+            test_pkg(
+                "snap",
+                "core22",
+                "amcUKQILKXHHTlmSa7NMdnXSx02dNeeT_1122.snap",
+            ),
+            test_pkg(
+                "snap",
+                "snapcraft",
+                "vMTKRaLjnOJQetI78HjntT37VuoyssFE_11040.snap",
+            ),
+        ];
+        let steps = Snap.steps(&pkgs, &Options::default())?;
+        assert_eq!(
+            steps[1],
+            Step::Exec(
+                [
+                    "snap",
+                    "install",
+                    "--dangerous",
+                    "--",
+                    "/extra/amcUKQILKXHHTlmSa7NMdnXSx02dNeeT_1122.snap",
+                    "/extra/vMTKRaLjnOJQetI78HjntT37VuoyssFE_11040.snap",
+                ]
+                .map(String::from)
+                .to_vec()
+            )
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,554 @@
+//! The `[java]` section. The JDK is a Temurin release that's pinned like a
+//! `[[toolchain.external]]`, maven dependencies are resolved by running maven
+//! in the image and pinned as the files of a local repository.
+use crate::args;
+use crate::container::{self, Container, Mount};
+use crate::errors::*;
+use crate::fetch;
+use crate::hash;
+use crate::http;
+use crate::lockfile::{Lockfile, MavenLock, ToolchainLock};
+use crate::manifest::{JavaManifest, MavenManifest, JDK_PATH};
+use crate::paths;
+use crate::summary;
+use crate::toolchain;
+use crate::utils;
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+const ADOPTIUM_API: &str = "https://api.adoptium.net/v3/assets/latest";
+/// The local repository maven writes to while resolving
+const RESOLVE_REPO: &str = "/tmp/m2";
+/// Where the project is mounted read-only while resolving, it's copied to /build
+/// so goals that write into the project directory (like `package`) work too
+const RESOLVE_SRC: &str = "/src";
+/// Where the pinned local repository is mounted during builds
+pub const MAVEN_REPO: &str = "/extra/maven";
+
+#[derive(Debug, Deserialize)]
+pub struct Asset {
+    pub binary: Binary,
+    pub release_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Binary {
+    pub package: Package,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Package {
+    pub name: String,
+    pub link: String,
+    pub checksum: String,
+    pub size: u64,
+}
+
+/// The architecture as it's named by the adoptium api
+fn adoptium_arch() -> Option<&'static str> {
+    let arch = match std::env::consts::ARCH {
+        "x86_64" => "x64",
+        "aarch64" => "aarch64",
+        "powerpc64" => "ppc64le",
+        "s390x" => "s390x",
+        "riscv64" => "riscv64",
+        "arm" => "arm",
+        _ => return None,
+    };
+    Some(arch)
+}
+
+/// Pin the latest GA build of a Temurin feature release, like `21`
+pub async fn resolve_jdk(client: &http::Client, feature: &str) -> Result<ToolchainLock> {
+    let arch = adoptium_arch().context("Unsupported architecture for Temurin")?;
+    let url = format!(
+        "{ADOPTIUM_API}/{feature}/hotspot?architecture={arch}&image_type=jdk&os=linux&vendor=eclipse"
+    );
+    debug!("Querying adoptium api: {url:?}");
+    let buf = client.fetch(&url).await?;
+    let assets = serde_json::from_slice::<Vec<Asset>>(&buf)
+        .context("Failed to parse response of adoptium api")?;
+    let asset = assets
+        .into_iter()
+        .next()
+        .with_context(|| anyhow!("Temurin has no jdk {feature:?} for {arch}"))?;
+    debug!("Detected jdk: {:?}", asset.release_name);
+
+    let package = asset.binary.package;
+    let pin = fetch::pin_file(
+        client,
+        &package.name,
+        &package.link,
+        Some(&package.checksum),
+    )
+    .await
+    .with_context(|| anyhow!("Failed to pin jdk {:?}", asset.release_name))?;
+    if pin.size != package.size {
+        bail!(
+            "Mismatch of size for jdk {:?}, expected={}, downloaded={}",
+            package.name,
+            package.size,
+            pin.size
+        );
+    }
+    Ok(ToolchainLock {
+        name: pin.name,
+        url: pin.url,
+        sha256: pin.sha256,
+        size: pin.size,
+        path: JDK_PATH.to_string(),
+        // the tarball contains a single directory like jdk-21.0.4+7/
+        strip_components: 1,
+    })
+}
+
+/// Files maven writes for its own bookkeeping, they are not needed for offline builds
+fn is_bookkeeping(filename: &str) -> bool {
+    filename == "_remote.repositories"
+        || filename == "resolver-status.properties"
+        || filename.ends_with(".lastUpdated")
+        || filename.ends_with(".md5")
+        || (filename.starts_with("maven-metadata-") && filename.ends_with(".xml"))
+}
+
+/// The `_remote.repositories` of a directory, filename => repository id
+fn parse_remote_repositories(buf: &str) -> HashMap<String, String> {
+    buf.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let (filename, id) = line.strip_suffix('=')?.split_once('>')?;
+            Some((filename.to_string(), id.to_string()))
+        })
+        .filter(|(_, id)| !id.is_empty())
+        .collect()
+}
+
+/// A path in the local repository, relative and without any `..`
+pub fn valid_repo_path(path: &str) -> bool {
+    !path.is_empty()
+        && !path.starts_with('/')
+        && path
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..")
+}
+
+/// The checksum of a `.sha1` file, some repositories append the filename
+fn parse_sha1_file(buf: &[u8]) -> Option<String> {
+    let buf = std::str::from_utf8(buf).ok()?;
+    let sha1 = buf.split_whitespace().next()?;
+    if sha1.len() != 40 || !sha1.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(sha1.to_ascii_lowercase())
+}
+
+/// Read the local repository maven wrote during resolve (as tar archive of
+/// its directory), every artifact is pinned with the url of the repository
+/// it was downloaded from and verified with the `.sha1` the repository provided
+pub fn parse_local_repository(
+    buf: &[u8],
+    repositories: &BTreeMap<String, String>,
+) -> Result<Vec<(MavenLock, Vec<u8>)>> {
+    let mut files = BTreeMap::new();
+    let mut remotes = HashMap::new();
+    let mut checksums = HashMap::new();
+
+    let mut tar = tar::Archive::new(buf);
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?;
+        let path = path
+            .to_str()
+            .with_context(|| anyhow!("Path in local repository is not utf8: {path:?}"))?;
+        // skip the name of the directory itself
+        let Some((_, path)) = path.split_once('/') else {
+            continue;
+        };
+        let path = path.to_string();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+
+        let (dir, filename) = path.rsplit_once('/').unwrap_or(("", &path));
+        if filename == "_remote.repositories" {
+            let buf = String::from_utf8(data).context("Failed to decode _remote.repositories")?;
+            remotes.insert(dir.to_string(), parse_remote_repositories(&buf));
+        } else if let Some(artifact) = path.strip_suffix(".sha1") {
+            let sha1 = parse_sha1_file(&data)
+                .with_context(|| anyhow!("Invalid checksum file in local repository: {path:?}"))?;
+            checksums.insert(artifact.to_string(), sha1);
+        } else if !is_bookkeeping(filename) {
+            files.insert(path, data);
+        }
+    }
+
+    let mut artifacts = Vec::new();
+    for (path, data) in files {
+        if !valid_repo_path(&path) {
+            bail!("Invalid path in local repository: {path:?}");
+        }
+        let (dir, filename) = path.rsplit_once('/').unwrap_or(("", &path));
+        let Some(id) = remotes.get(dir).and_then(|remotes| remotes.get(filename)) else {
+            warn!("Skipping {path:?}, maven did not record which repository it's from");
+            continue;
+        };
+        let Some(url) = repositories.get(id) else {
+            bail!("Artifact {path:?} is from maven repository {id:?}, add its url to [java.maven.repositories]");
+        };
+        match checksums.get(&path) {
+            Some(expected) => {
+                let sha1 = hex::encode(Sha1::digest(&data));
+                if sha1 != *expected {
+                    bail!("Mismatch of sha1 for maven artifact {path:?}, expected={expected:?}, downloaded={sha1:?}");
+                }
+            }
+            None => warn!("Maven repository {id:?} did not provide a sha1 checksum for {path:?}"),
+        }
+        let lock = MavenLock {
+            url: format!("{}/{path}", url.trim_end_matches('/')),
+            sha256: hash::sha256(&data),
+            size: data.len() as u64,
+            path,
+        };
+        artifacts.push((lock, data));
+    }
+    Ok(artifacts)
+}
+
+async fn resolve_maven_in_container(
+    container: &Container,
+    maven: &MavenManifest,
+    java_home: bool,
+) -> Result<Vec<u8>> {
+    // goals like `package` write into the project, run them in a copy
+    container
+        .exec(&["mkdir", "-p", "/build"], container::Exec::default())
+        .await?;
+    container
+        .exec(
+            &["cp", "-a", "--", &format!("{RESOLVE_SRC}/."), "/build/"],
+            container::Exec::default(),
+        )
+        .await
+        .context("Failed to copy project into container")?;
+
+    info!("Resolving maven dependencies...");
+    let mut args = vec![
+        "mvn".to_string(),
+        "-B".to_string(),
+        // fail instead of warn if a download doesn't match the repository checksum
+        "--strict-checksums".to_string(),
+        format!("-Dmaven.repo.local={RESOLVE_REPO}"),
+    ];
+    args.extend(maven.goals.iter().cloned());
+    let env = if java_home {
+        vec![format!("JAVA_HOME={JDK_PATH}")]
+    } else {
+        vec![]
+    };
+    container
+        .exec(
+            &args,
+            container::Exec {
+                cwd: Some("/build"),
+                env: &env,
+                ..Default::default()
+            },
+        )
+        .await
+        .context("Failed to resolve maven dependencies, does the image contain mvn?")?;
+    container.tar(RESOLVE_REPO).await
+}
+
+/// Run the goals of `[java.maven]` in the image, in a copy of the project,
+/// and pin everything maven downloaded
+pub async fn resolve_maven(
+    update: &args::Update,
+    maven: &MavenManifest,
+    manifest_dir: &Path,
+    lockfile: &Lockfile,
+) -> Result<Vec<MavenLock>> {
+    let project = if manifest_dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        manifest_dir
+    };
+    let project = fs::canonicalize(project)
+        .with_context(|| anyhow!("Failed to resolve project directory: {project:?}"))?;
+    let mut mounts = vec![Mount::read_only(project, RESOLVE_SRC)];
+    let jdk = lockfile.toolchains.iter().find(|pin| pin.path == JDK_PATH);
+    if let Some(jdk) = jdk {
+        mounts.push(Mount::read_only(toolchain::extract(jdk).await?, JDK_PATH));
+    }
+
+    let image = container::prepare_image(&lockfile.container, manifest_dir).await?;
+    let container = Container::create(
+        &image,
+        container::Config {
+            mounts: &mounts,
+            expose_fuse: false,
+            lockfile_digest: None,
+            prepared: false,
+            platform: None,
+            podman_args: &[],
+            read_only_root: false,
+            init: lockfile.container.init,
+        },
+    )
+    .await?;
+    let mut buf = None;
+    container
+        .run(
+            async {
+                buf = Some(resolve_maven_in_container(&container, maven, jdk.is_some()).await?);
+                Ok(())
+            },
+            update.keep,
+        )
+        .await?;
+    let buf = buf.context("Maven resolve did not return a local repository")?;
+
+    let artifacts = parse_local_repository(&buf, &maven.repositories)?;
+    let pkgs_cache_dir = paths::pkgs_cache_dir()?;
+    let mut pins = Vec::new();
+    for (lock, data) in artifacts {
+        let path = pkgs_cache_dir.sha256_path(&lock.sha256)?;
+        if !path.exists() {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).with_context(|| {
+                    anyhow!("Failed to create parent directories for file: {path:?}")
+                })?;
+            }
+            utils::atomic_write(&path, &data)?;
+        }
+        debug!(
+            "Pinned maven artifact {:?} with sha256 {:?}",
+            lock.path, lock.sha256
+        );
+        pins.push(lock);
+    }
+    info!("Pinned {} maven artifacts", pins.len());
+    Ok(pins)
+}
+
+/// Pin the jdk and the maven dependencies of `[java]`
+pub async fn pin(
+    update: &args::Update,
+    java: &JavaManifest,
+    manifest_dir: &Path,
+    lockfile: &mut Lockfile,
+) -> Result<()> {
+    if let Some(jdk) = &java.jdk {
+        let _phase = summary::phase("download");
+        let client = http::Client::new()?;
+        let pin = resolve_jdk(&client, jdk)
+            .await
+            .with_context(|| anyhow!("Failed to pin jdk {jdk:?}"))?;
+        lockfile.toolchains.push(pin);
+    }
+    if let Some(maven) = &java.maven {
+        lockfile.maven = resolve_maven(update, maven, manifest_dir, lockfile).await?;
+    }
+    Ok(())
+}
+
+/// Assemble the local repository from the cache, the files are hardlinked if possible
+fn build_repository(artifacts: &[MavenLock], dest: &Path) -> Result<()> {
+    let pkgs_cache_dir = paths::pkgs_cache_dir()?;
+    for artifact in artifacts {
+        let source = pkgs_cache_dir.sha256_path(&artifact.sha256)?;
+        let target = dest.join(&artifact.path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| anyhow!("Failed to create directory: {parent:?}"))?;
+        }
+        if fs::hard_link(&source, &target).is_err() {
+            fs::copy(&source, &target)
+                .with_context(|| anyhow!("Failed to copy {source:?} to {target:?}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Download and verify the `[[maven]]` pins of the lockfile, the local
+/// repository is mounted read-only at /extra/maven
+pub async fn stage(artifacts: Vec<MavenLock>, mounts: &mut Vec<Mount>) -> Result<()> {
+    if artifacts.is_empty() {
+        return Ok(());
+    }
+    for artifact in &artifacts {
+        if !valid_repo_path(&artifact.path) {
+            bail!(
+                "Invalid path for maven artifact in lockfile: {:?}",
+                artifact.path
+            );
+        }
+    }
+    let files = artifacts.iter().map(MavenLock::file).collect::<Vec<_>>();
+    {
+        let _phase = summary::phase("download");
+        fetch::download_files(&files).await?;
+    }
+    {
+        let _phase = summary::phase("verify");
+        utils::spawn_blocking(move |_| fetch::verify_files(&files)).await?;
+    }
+
+    // the same set of artifacts is only assembled once
+    let mut digest = hash::Sha256::new();
+    for artifact in &artifacts {
+        digest.update(format!("{} {}\n", artifact.path, artifact.sha256).as_bytes());
+    }
+    let dir = paths::maven_cache_dir()?;
+    let path = dir.join(digest.finalize());
+    if !path.exists() {
+        let temp = dir.join(format!(
+            "{}.tmp-{}",
+            path.file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or_default(),
+            std::process::id()
+        ));
+        let target: PathBuf = path.clone();
+        utils::spawn_blocking(move |_| {
+            if temp.exists() {
+                fs::remove_dir_all(&temp)?;
+            }
+            build_repository(&artifacts, &temp)?;
+            fs::rename(&temp, &target).with_context(|| {
+                anyhow!("Failed to move maven repository into cache: {target:?}")
+            })?;
+            Ok(())
+        })
+        .await
+        .context("Failed to assemble local maven repository")?;
+    }
+    mounts.push(Mount::read_only(path, MAVEN_REPO));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn append(builder: &mut tar::Builder<Vec<u8>>, path: &str, data: &[u8]) -> Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, path, data)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_local_repository() -> Result<()> {
+        let dir = "m2/org/slf4j/slf4j-api/2.0.13";
+        let mut builder = tar::Builder::new(Vec::new());
+        append(
+            &mut builder,
+            &format!("{dir}/_remote.repositories"),
+            b"#NOTE: This is a Maven Resolver internal implementation file, its format can be changed without prior notice.
+#Thu Oct 17 12:00:00 UTC 2024
+slf4j-api-2.0.13.jar>central=
+slf4j-api-2.0.13.pom>central=
+",
+        )?;
+        append(
+            &mut builder,
+            &format!("{dir}/slf4j-api-2.0.13.jar"),
+            b"PK\x03\x04",
+        )?;
+        append(
+            &mut builder,
+            &format!("{dir}/slf4j-api-2.0.13.pom"),
+            b"<project/>",
+        )?;
+        append(
+            &mut builder,
+            &format!("{dir}/slf4j-api-2.0.13.jar.sha1"),
+            format!(
+                "{}  slf4j-api-2.0.13.jar\n",
+                hex::encode(Sha1::digest(b"PK\x03\x04"))
+            )
+            .as_bytes(),
+        )?;
+        append(
+            &mut builder,
+            &format!("{dir}/slf4j-api-2.0.13.jar.lastUpdated"),
+            b"",
+        )?;
+        append(
+            &mut builder,
+            "m2/com/example/app/1.0/app-1.0.jar",
+            b"installed",
+        )?;
+        let buf = builder.into_inner()?;
+
+        let repositories = BTreeMap::from([(
+            "central".to_string(),
+            "https://repo.maven.apache.org/maven2/".to_string(),
+        )]);
+        let artifacts = parse_local_repository(&buf, &repositories)?;
+        let locks = artifacts
+            .into_iter()
+            .map(|(lock, _)| lock)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            locks,
+            [
+                MavenLock {
+                    path: "org/slf4j/slf4j-api/2.0.13/slf4j-api-2.0.13.jar".to_string(),
+                    url: "https://repo.maven.apache.org/maven2/org/slf4j/slf4j-api/2.0.13/slf4j-api-2.0.13.jar".to_string(),
+                    sha256: hash::sha256(b"PK\x03\x04"),
+                    size: 4,
+                },
+                MavenLock {
+                    path: "org/slf4j/slf4j-api/2.0.13/slf4j-api-2.0.13.pom".to_string(),
+                    url: "https://repo.maven.apache.org/maven2/org/slf4j/slf4j-api/2.0.13/slf4j-api-2.0.13.pom".to_string(),
+                    sha256: hash::sha256(b"<project/>"),
+                    size: 10,
+                },
+            ]
+        );
+
+        assert!(parse_local_repository(&buf, &BTreeMap::new()).is_err());
+
+        // the artifact doesn't match the checksum of the repository
+        let mut builder = tar::Builder::new(Vec::new());
+        append(
+            &mut builder,
+            &format!("{dir}/_remote.repositories"),
+            b"slf4j-api-2.0.13.jar>central=\n",
+        )?;
+        append(
+            &mut builder,
+            &format!("{dir}/slf4j-api-2.0.13.jar"),
+            b"PK\x03\x04",
+        )?;
+        append(
+            &mut builder,
+            &format!("{dir}/slf4j-api-2.0.13.jar.sha1"),
+            b"da39a3ee5e6b4b0d3255bfef95601890afd80709",
+        )?;
+        let buf = builder.into_inner()?;
+        assert!(parse_local_repository(&buf, &repositories).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_valid_repo_path() {
+        assert!(valid_repo_path(
+            "org/slf4j/slf4j-api/2.0.13/slf4j-api-2.0.13.jar"
+        ));
+        assert!(!valid_repo_path("/etc/passwd"));
+        assert!(!valid_repo_path("org/../../etc/passwd"));
+        assert!(!valid_repo_path("org//slf4j"));
+        assert!(!valid_repo_path(""));
+    }
+}
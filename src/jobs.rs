@@ -0,0 +1,338 @@
+//! Run several independent commands concurrently in the same build container,
+//! configured with `build --jobs-file`
+use crate::container::{self, CommandError, Container};
+use crate::errors::*;
+use futures_util::stream::{self, StreamExt};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobsFile {
+    #[serde(rename = "job", default)]
+    pub jobs: Vec<Job>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Job {
+    pub name: String,
+    pub cmd: JobCommand,
+    /// Additional environment variables, same format as `build --env`
+    #[serde(default)]
+    pub env: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum JobCommand {
+    /// Executed with `sh -c`
+    Shell(String),
+    Args(Vec<String>),
+}
+
+impl JobsFile {
+    pub fn deserialize(buf: &str) -> Result<Self> {
+        let file = toml::from_str::<Self>(buf).context("Failed to load jobs from toml")?;
+
+        if file.jobs.is_empty() {
+            bail!("Jobs file doesn't define any [[job]]");
+        }
+        let mut names = HashSet::new();
+        for job in &file.jobs {
+            if !names.insert(&job.name) {
+                bail!("Job name is used multiple times: {:?}", job.name);
+            }
+            let empty = match &job.cmd {
+                JobCommand::Shell(script) => script.trim().is_empty(),
+                JobCommand::Args(args) => args.is_empty(),
+            };
+            if empty {
+                bail!("Command of job {:?} is empty", job.name);
+            }
+        }
+        Ok(file)
+    }
+
+    pub fn read_from_file(path: &Path) -> Result<Self> {
+        let buf = std::fs::read_to_string(path)
+            .with_context(|| anyhow!("Failed to read jobs file: {path:?}"))?;
+        Self::deserialize(&buf).with_context(|| anyhow!("Failed to parse jobs file: {path:?}"))
+    }
+}
+
+impl Job {
+    /// The command line in the container, stderr is merged into stdout so the log keeps the order of both
+    pub fn argv(&self) -> Vec<String> {
+        match &self.cmd {
+            JobCommand::Shell(script) => {
+                vec![
+                    "sh".to_string(),
+                    "-c".to_string(),
+                    format!("exec 2>&1\n{script}"),
+                ]
+            }
+            JobCommand::Args(args) => ["sh", "-c", "exec \"$@\" 2>&1", "sh"]
+                .into_iter()
+                .map(String::from)
+                .chain(args.iter().cloned())
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct JobResult {
+    pub name: String,
+    /// None if the command got killed by a signal
+    pub code: Option<i32>,
+    pub log: Vec<u8>,
+    pub elapsed: Duration,
+}
+
+impl JobResult {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+
+    fn status(&self) -> String {
+        match self.code {
+            Some(0) => "ok".to_string(),
+            Some(code) => format!("failed (exit code {code})"),
+            None => "failed (killed)".to_string(),
+        }
+    }
+}
+
+pub async fn run_job(
+    container: &Container,
+    job: &Job,
+    workdir: &str,
+    env: &[String],
+    env_file: Option<&Path>,
+) -> Result<JobResult> {
+    let env = env.iter().chain(&job.env).cloned().collect::<Vec<_>>();
+    let start = Instant::now();
+    let result = container
+        .exec(
+            job.argv(),
+            container::Exec {
+                capture_stdout: true,
+                cwd: Some(workdir),
+                env: &env,
+                env_file,
+                ..Default::default()
+            },
+        )
+        .await;
+
+    let (code, log) = match result {
+        Ok(log) => (Some(0), log),
+        Err(err) => match err.downcast::<CommandError>() {
+            Ok(err) => (err.code, err.stdout),
+            Err(err) => return Err(err),
+        },
+    };
+    Ok(JobResult {
+        name: job.name.clone(),
+        code,
+        log,
+        elapsed: start.elapsed(),
+    })
+}
+
+/// Run the jobs with at most `parallel` at the same time, each log is printed once its job is done
+pub async fn run(
+    container: &Container,
+    jobs: &[Job],
+    parallel: usize,
+    workdir: &str,
+    env: &[String],
+    env_file: Option<&Path>,
+) -> Result<Vec<JobResult>> {
+    info!(
+        "Running {} jobs ({} at a time)...",
+        jobs.len(),
+        parallel.min(jobs.len())
+    );
+    let mut running = stream::iter(jobs)
+        .map(|job| run_job(container, job, workdir, env, env_file))
+        .buffer_unordered(parallel.max(1));
+
+    let mut results = Vec::new();
+    while let Some(result) = running.next().await {
+        let result = result?;
+        print_log(&result);
+        results.push(result);
+    }
+
+    // report in the order of the jobs file
+    results.sort_by_key(|r| jobs.iter().position(|j| j.name == r.name));
+    Ok(results)
+}
+
+/// Print the output of a job that has finished, with a header
+pub fn print_log(result: &JobResult) {
+    println!(
+        "==> {} {} in {:.1}s <==",
+        result.name,
+        result.status(),
+        result.elapsed.as_secs_f64()
+    );
+    print!("{}", String::from_utf8_lossy(&result.log));
+}
+
+pub fn format_summary(results: &[JobResult]) -> String {
+    let width = results.iter().map(|r| r.name.len()).max().unwrap_or(0);
+    let mut out = String::new();
+    for result in results {
+        out.push_str(&format!(
+            "{:<width$}  {:>6.1}s  {}\n",
+            result.name,
+            result.elapsed.as_secs_f64(),
+            result.status(),
+        ));
+    }
+    out
+}
+
+/// Fail if any of the jobs failed
+pub fn check(results: &[JobResult]) -> Result<()> {
+    let failed = results
+        .iter()
+        .filter(|r| !r.success())
+        .map(|r| r.name.as_str())
+        .collect::<Vec<_>>();
+    if !failed.is_empty() {
+        bail!(
+            "{} of {} jobs failed: {}",
+            failed.len(),
+            results.len(),
+            failed.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_runtime::{FakeRuntime, CONTAINER_ID};
+
+    const JOBS: &str = r#"
+[[job]]
+name = "test"
+cmd = ["cargo", "test", "--all"]
+env = ["RUST_BACKTRACE=1"]
+
+[[job]]
+name = "lint"
+cmd = "cargo fmt --check && cargo clippy"
+"#;
+
+    #[test]
+    fn test_parse_jobs() -> Result<()> {
+        let file = JobsFile::deserialize(JOBS)?;
+        assert_eq!(
+            file.jobs,
+            [
+                Job {
+                    name: "test".to_string(),
+                    cmd: JobCommand::Args(vec![
+                        "cargo".to_string(),
+                        "test".to_string(),
+                        "--all".to_string()
+                    ]),
+                    env: vec!["RUST_BACKTRACE=1".to_string()],
+                },
+                Job {
+                    name: "lint".to_string(),
+                    cmd: JobCommand::Shell("cargo fmt --check && cargo clippy".to_string()),
+                    env: vec![],
+                },
+            ]
+        );
+        assert_eq!(
+            file.jobs[0].argv(),
+            [
+                "sh",
+                "-c",
+                "exec \"$@\" 2>&1",
+                "sh",
+                "cargo",
+                "test",
+                "--all"
+            ]
+        );
+        assert_eq!(
+            file.jobs[1].argv(),
+            ["sh", "-c", "exec 2>&1\ncargo fmt --check && cargo clippy"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_jobs() {
+        assert!(JobsFile::deserialize("").is_err());
+        assert!(JobsFile::deserialize("[[job]]\nname = \"a\"\ncmd = []\n").is_err());
+        assert!(JobsFile::deserialize(
+            "[[job]]\nname = \"a\"\ncmd = \"true\"\n[[job]]\nname = \"a\"\ncmd = \"false\"\n"
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run_jobs() -> Result<()> {
+        let jobs = JobsFile::deserialize(JOBS)?.jobs;
+        let runtime = FakeRuntime::new();
+        runtime.reply(&["cargo", "test"], "test result: ok\n");
+        runtime.fail(&["exec 2>&1\ncargo fmt --check && cargo clippy"]);
+
+        let container = Container {
+            id: CONTAINER_ID.to_string(),
+        };
+        let results = runtime
+            .run(run(&container, &jobs, 2, "/build", &[], None))
+            .await?;
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].name, "test");
+        assert_eq!(results[0].code, Some(0));
+        assert_eq!(results[0].log, b"test result: ok\n");
+        assert_eq!(results[1].name, "lint");
+        assert_eq!(results[1].code, Some(1));
+        assert!(runtime
+            .commands()
+            .iter()
+            .any(|cmd| cmd.contains("-e RUST_BACKTRACE=1")));
+
+        let err = check(&results).unwrap_err();
+        assert_eq!(err.to_string(), "1 of 2 jobs failed: lint");
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_summary() {
+        let result = |name: &str, code, secs| JobResult {
+            name: name.to_string(),
+            code,
+            log: vec![],
+            elapsed: Duration::from_millis(secs),
+        };
+        let results = [
+            result("test", Some(0), 12_340),
+            result("clippy", Some(101), 3_000),
+            result("doc", None, 500),
+        ];
+        assert_eq!(
+            format_summary(&results),
+            "\
+test      12.3s  ok
+clippy     3.0s  failed (exit code 101)
+doc        0.5s  failed (killed)
+"
+        );
+        assert!(check(&results[..1]).is_ok());
+    }
+}
@@ -1,16 +1,131 @@
+#[cfg(feature = "cli")]
 pub mod args;
+#[cfg(feature = "cli")]
+pub mod attest;
+#[cfg(feature = "cli")]
+pub mod audit;
+#[cfg(feature = "cli")]
+pub mod batch;
+#[cfg(feature = "cli")]
 pub mod build;
+pub mod build_info;
+#[cfg(feature = "cli")]
+pub mod cache;
+#[cfg(feature = "cli")]
+pub mod cargo;
+#[cfg(feature = "cli")]
+pub mod cli;
+#[cfg(feature = "cli")]
+pub mod consistency;
+#[cfg(feature = "cli")]
 pub mod container;
+#[cfg(feature = "cli")]
+pub mod context;
+#[cfg(feature = "cli")]
+pub mod credentials;
+#[cfg(feature = "cli")]
+pub mod describe;
+pub mod diff;
+#[cfg(feature = "cli")]
+pub mod doctor;
 pub mod errors;
+#[cfg(feature = "cli")]
+pub mod explain;
+#[cfg(all(test, feature = "cli"))]
+pub mod fake_runtime;
+#[cfg(feature = "cli")]
 pub mod fetch;
+#[cfg(feature = "cli")]
+pub mod freshness;
+#[cfg(feature = "cli")]
+pub mod gc;
+#[cfg(feature = "cli")]
+pub mod git;
+pub mod hash;
+#[cfg(feature = "cli")]
+pub mod haskell;
+#[cfg(feature = "cli")]
 pub mod http;
+#[cfg(feature = "cli")]
+pub mod init;
+#[cfg(feature = "cli")]
+pub mod install;
+#[cfg(feature = "cli")]
+pub mod java;
+#[cfg(feature = "cli")]
+pub mod jobs;
+#[cfg(feature = "pkgs")]
+pub mod limits;
+#[cfg(feature = "cli")]
+pub mod local;
+#[cfg(feature = "cli")]
+pub mod lock;
 pub mod lockfile;
 pub mod manifest;
+#[cfg(feature = "cli")]
+pub mod osv;
+#[cfg(feature = "cli")]
 pub mod paths;
+#[cfg(feature = "cli")]
+pub mod perl;
+#[cfg(feature = "cli")]
 pub mod pgp;
+#[cfg(feature = "cli")]
+pub mod php;
+#[cfg(feature = "pkgs")]
 pub mod pkgs;
+#[cfg(feature = "cli")]
+pub mod platform;
+#[cfg(feature = "cli")]
+pub mod prepare;
+#[cfg(feature = "cli")]
+pub mod progress;
+#[cfg(feature = "cli")]
+pub mod ps;
+pub mod purl;
+#[cfg(feature = "cli")]
+pub mod rebuilderd;
+#[cfg(feature = "cli")]
+pub mod refs;
+#[cfg(feature = "cli")]
 pub mod resolver;
+#[cfg(feature = "cli")]
+pub mod ruby;
+#[cfg(feature = "cli")]
+pub mod sandbox;
+#[cfg(feature = "cli")]
+pub mod sbom;
+#[cfg(feature = "cli")]
+pub mod self_update;
+#[cfg(feature = "cli")]
+pub mod sign;
+#[cfg(feature = "cli")]
+pub mod staging;
+#[cfg(feature = "cli")]
+pub mod state;
+#[cfg(feature = "cli")]
+pub mod status;
+#[cfg(feature = "cli")]
+pub mod steps;
+#[cfg(feature = "cli")]
+pub mod summary;
+pub mod systems;
 #[cfg(test)]
 pub mod test_data;
+#[cfg(feature = "cli")]
+pub mod texlive;
+#[cfg(feature = "cli")]
+pub mod toolchain;
+#[cfg(feature = "cli")]
+pub mod trace;
+#[cfg(feature = "cli")]
+pub mod transform;
+#[cfg(feature = "cli")]
 pub mod update;
+#[cfg(feature = "cli")]
 pub mod utils;
+#[cfg(feature = "cli")]
+pub mod verify;
+pub mod version;
+#[cfg(feature = "cli")]
+pub mod vex;
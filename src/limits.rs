@@ -0,0 +1,200 @@
+//! Guard rails for parsing untrusted archives on the host, packages and
+//! indexes are attacker-influenced and could be decompression bombs
+use crate::errors::*;
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+thread_local! {
+    /// The token of the work that is running on this thread, see [`Cancel::scope`]
+    static CURRENT: RefCell<Option<Cancel>> = const { RefCell::new(None) };
+}
+
+/// Stop parsing archives, for example because ctrl-c was received
+#[derive(Debug, Clone, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Run `f` with this token on the current thread, archives that are read
+    /// in `f` fail at the next read or tar entry once it's cancelled
+    pub fn scope<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        let previous = CURRENT.with(|current| current.replace(Some(self.clone())));
+        let result = f();
+        CURRENT.with(|current| current.replace(previous));
+        result
+    }
+}
+
+fn is_cancelled() -> bool {
+    CURRENT.with(|current| current.borrow().as_ref().is_some_and(Cancel::is_cancelled))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// Maximum number of bytes after decompression
+    pub max_size: u64,
+    /// Maximum number of entries in a tar
+    pub max_entries: usize,
+    /// Maximum time spent reading the archive
+    pub timeout: Duration,
+}
+
+/// Limits for package files (.deb, .apk, .pkg.tar.*)
+pub const PACKAGE: Limits = Limits {
+    max_size: 16 * 1024 * 1024 * 1024,
+    max_entries: 1_000_000,
+    timeout: Duration::from_secs(15 * 60),
+};
+
+/// Limits for package indexes (APKINDEX, sync databases, apt lists)
+pub const INDEX: Limits = Limits {
+    max_size: 4 * 1024 * 1024 * 1024,
+    max_entries: 1_000_000,
+    timeout: Duration::from_secs(5 * 60),
+};
+
+impl Limits {
+    /// Wrap a decompressed stream (reader or writer) to enforce size and timeout
+    pub fn wrap<T>(&self, inner: T) -> Limited<T> {
+        Limited {
+            inner,
+            limits: *self,
+            written: 0,
+            deadline: Instant::now() + self.timeout,
+        }
+    }
+
+    /// Call with the index of each tar entry that is read
+    pub fn check_entry(&self, idx: usize) -> Result<()> {
+        if is_cancelled() {
+            bail!("Parsing archive was cancelled");
+        }
+        if idx >= self.max_entries {
+            bail!(
+                "Archive has more than {} entries, refusing to continue",
+                self.max_entries
+            );
+        }
+        Ok(())
+    }
+}
+
+pub struct Limited<T> {
+    inner: T,
+    limits: Limits,
+    written: u64,
+    deadline: Instant,
+}
+
+impl<T> Limited<T> {
+    fn account(&mut self, n: usize) -> io::Result<()> {
+        if is_cancelled() {
+            return Err(io::Error::other("Parsing archive was cancelled"));
+        }
+        if Instant::now() > self.deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "Parsing archive took longer than {:?}, refusing to continue",
+                    self.limits.timeout
+                ),
+            ));
+        }
+        self.written += n as u64;
+        if self.written > self.limits.max_size {
+            return Err(io::Error::other(format!(
+                "Decompressed data exceeds {} bytes, refusing to continue",
+                self.limits.max_size
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Read> Read for Limited<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.account(n)?;
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Limited<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.account(buf.len())?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TINY: Limits = Limits {
+        max_size: 16,
+        max_entries: 2,
+        timeout: Duration::from_secs(60),
+    };
+
+    #[test]
+    fn test_max_size() {
+        let mut buf = Vec::new();
+        assert!(TINY.wrap(&[0u8; 16][..]).read_to_end(&mut buf).is_ok());
+        assert!(TINY.wrap(&[0u8; 17][..]).read_to_end(&mut buf).is_err());
+
+        let mut w = TINY.wrap(Vec::new());
+        assert!(w.write_all(&[0u8; 16]).is_ok());
+        assert!(w.write_all(&[0u8; 1]).is_err());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let limits = Limits {
+            timeout: Duration::ZERO,
+            ..TINY
+        };
+        let mut r = limits.wrap(&b"abc"[..]);
+        std::thread::sleep(Duration::from_millis(1));
+        let err = r.read(&mut [0u8; 3]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_max_entries() {
+        assert!(TINY.check_entry(1).is_ok());
+        assert!(TINY.check_entry(2).is_err());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let cancel = Cancel::default();
+        cancel.scope(|| {
+            let mut buf = Vec::new();
+            assert!(TINY.wrap(&b"abc"[..]).read_to_end(&mut buf).is_ok());
+            assert!(TINY.check_entry(0).is_ok());
+            cancel.cancel();
+            assert!(TINY.wrap(&b"abc"[..]).read_to_end(&mut buf).is_err());
+            assert!(TINY.check_entry(0).is_err());
+        });
+        // only the work in the scope is affected
+        assert!(TINY.check_entry(0).is_ok());
+    }
+}
@@ -0,0 +1,219 @@
+//! `repro-env.local.toml`, machine specific settings next to the lockfile that
+//! aren't meant to be committed. It's only read to fetch and build, never while
+//! resolving, so nothing in it can end up in the shared lockfile.
+use crate::container::{self, Mount};
+use crate::errors::*;
+use crate::http;
+use crate::manifest;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const FILENAME: &str = "repro-env.local.toml";
+
+/// The sections that may be used, everything else belongs into repro-env.toml
+const SECTIONS: &[&str] = &["build", "runtime", "proxy"];
+
+/// Directories in the container that are managed by repro-env
+const RESERVED_MOUNTS: &[&str] = &["/build", "/extra", "/__"];
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LocalConfig {
+    #[serde(default)]
+    pub build: LocalBuild,
+    #[serde(default)]
+    pub runtime: LocalRuntime,
+    /// Used for downloads of repro-env, e.g. `http://proxy.example.com:3128`
+    pub proxy: Option<String>,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LocalBuild {
+    /// Keep the build container, like `build --keep`
+    #[serde(default)]
+    pub keep: bool,
+    /// Appended to the podman arguments of the manifest
+    #[serde(default)]
+    pub podman_args: Vec<String>,
+    /// Additional directories of this machine, e.g. a compiler cache
+    #[serde(default)]
+    pub mounts: Vec<LocalMount>,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LocalMount {
+    /// Relative to the directory of repro-env.local.toml
+    pub src: PathBuf,
+    pub dest: String,
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LocalRuntime {
+    /// The podman binary to use instead of `podman` from PATH
+    pub podman: Option<PathBuf>,
+}
+
+impl LocalConfig {
+    pub fn deserialize(buf: &str) -> Result<Self> {
+        let table = toml::from_str::<toml::Table>(&manifest::normalize_text(buf))
+            .with_context(|| anyhow!("Failed to load {FILENAME} from toml"))?;
+        if let Some(key) = table.keys().find(|key| !SECTIONS.contains(&key.as_str())) {
+            bail!("{FILENAME} can only override [build], [runtime] and proxy, {key:?} belongs into the manifest");
+        }
+        let config = table
+            .try_into::<Self>()
+            .with_context(|| anyhow!("Failed to load {FILENAME} from toml"))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        manifest::validate_podman_args(&self.build.podman_args)?;
+        for mount in &self.build.mounts {
+            if !mount.dest.starts_with('/') {
+                bail!(
+                    "Mount destination needs to be an absolute path: {:?}",
+                    mount.dest
+                );
+            }
+            let dest = Path::new(&mount.dest);
+            if dest == Path::new("/") || RESERVED_MOUNTS.iter().any(|dir| dest.starts_with(dir)) {
+                bail!(
+                    "Mount destination is managed by repro-env: {:?}",
+                    mount.dest
+                );
+            }
+        }
+        if let Some(proxy) = &self.proxy {
+            if !["http://", "https://", "socks5://"]
+                .iter()
+                .any(|scheme| proxy.starts_with(scheme))
+            {
+                bail!("Proxy url must start with http://, https:// or socks5://: {proxy:?}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the file from `dir` if it exists, relative paths are resolved from there
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(FILENAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        debug!("Loading machine specific settings from {path:?}");
+        let buf = fs::read_to_string(&path)
+            .with_context(|| anyhow!("Failed to read local settings: {path:?}"))?;
+        let mut config =
+            Self::deserialize(&buf).with_context(|| anyhow!("Invalid local settings: {path:?}"))?;
+        for mount in &mut config.build.mounts {
+            mount.src = dir.join(&mount.src);
+        }
+        Ok(config)
+    }
+
+    /// Load the file next to the lockfile and configure the podman binary and
+    /// the proxy, this needs to happen before either of them is used
+    pub fn load_for_lockfile(lockfile_path: &Path) -> Result<Self> {
+        let dir = lockfile_path.parent().unwrap_or(Path::new(""));
+        let config = Self::load(dir)?;
+        if let Some(podman) = &config.runtime.podman {
+            container::set_podman_binary(podman.clone())?;
+        }
+        if let Some(proxy) = &config.proxy {
+            http::set_proxy(proxy.clone())?;
+        }
+        Ok(config)
+    }
+
+    pub fn mounts(&self) -> impl Iterator<Item = Mount> + '_ {
+        self.build.mounts.iter().map(|mount| Mount {
+            src: mount.src.clone(),
+            dest: mount.dest.clone(),
+            read_only: mount.read_only,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_config() -> Result<()> {
+        let config = LocalConfig::deserialize(
+            r#"proxy = "http://proxy.example.com:3128"
+
+[build]
+keep = true
+podman_args = ["--memory=8g"]
+
+[[build.mounts]]
+src = "../sccache"
+dest = "/sccache"
+
+[runtime]
+podman = "/opt/podman/bin/podman"
+"#,
+        )?;
+        assert_eq!(
+            config,
+            LocalConfig {
+                build: LocalBuild {
+                    keep: true,
+                    podman_args: vec!["--memory=8g".to_string()],
+                    mounts: vec![LocalMount {
+                        src: PathBuf::from("../sccache"),
+                        dest: "/sccache".to_string(),
+                        read_only: false,
+                    }],
+                },
+                runtime: LocalRuntime {
+                    podman: Some(PathBuf::from("/opt/podman/bin/podman")),
+                },
+                proxy: Some("http://proxy.example.com:3128".to_string()),
+            }
+        );
+        assert_eq!(LocalConfig::deserialize("")?, LocalConfig::default());
+        Ok(())
+    }
+
+    #[test]
+    fn test_local_config_invalid() {
+        // anything that could change the resolved lockfile is rejected
+        for buf in [
+            "[container]\nimage = \"debian\"\n",
+            "[packages]\nsystem = \"debian\"\ndependencies = [\"gcc\"]\n",
+            "[build]\nsteps = []\n",
+            "[build]\npodman_args = [\"--volume=/:/host\"]\n",
+            "[[build.mounts]]\nsrc = \"/tmp\"\ndest = \"/extra/x\"\n",
+            "[[build.mounts]]\nsrc = \"/tmp\"\ndest = \"tmp\"\n",
+            "proxy = \"proxy.example.com:3128\"\n",
+        ] {
+            assert!(LocalConfig::deserialize(buf).is_err(), "{buf:?}");
+        }
+    }
+
+    #[test]
+    fn test_load_local_config() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        assert_eq!(LocalConfig::load(dir.path())?, LocalConfig::default());
+
+        fs::write(
+            dir.path().join(FILENAME),
+            "[[build.mounts]]\nsrc = \"cache\"\ndest = \"/cache\"\nread_only = true\n",
+        )?;
+        let config = LocalConfig::load(dir.path())?;
+        assert_eq!(
+            config.mounts().collect::<Vec<_>>(),
+            [Mount::read_only(dir.path().join("cache"), "/cache")]
+        );
+        Ok(())
+    }
+}
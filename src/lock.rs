@@ -0,0 +1,690 @@
+use crate::args;
+use crate::consistency;
+use crate::describe;
+use crate::errors::*;
+use crate::fetch;
+use crate::hash;
+use crate::lockfile::{Lockfile, PackageLock, SignatureLock};
+use crate::manifest::{self, Manifest};
+use crate::paths;
+use crate::pgp;
+use crate::pkgs::{self, Pkg};
+use crate::sandbox;
+use crate::systems;
+use crate::utils;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use time::format_description::well_known;
+use time::OffsetDateTime;
+use tokio::fs;
+
+pub async fn digest(digest: &args::LockDigest) -> Result<()> {
+    let path = digest
+        .file
+        .as_deref()
+        .unwrap_or(Path::new("repro-env.lock"));
+    let lockfile = Lockfile::read_from_file(path).await?;
+    println!("{}", lockfile.digest());
+    Ok(())
+}
+
+/// Quote a value for a shell, values that don't need quotes are left alone so
+/// the output can also be used with `include` in a Makefile
+fn env_value(value: &str) -> String {
+    if !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c))
+    {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
+
+/// `KEY=value` lines describing the lockfile, `buf` is the file it was loaded from
+pub fn env_vars(lockfile: &Lockfile, buf: &[u8]) -> String {
+    let image_digest = lockfile
+        .container
+        .image
+        .split_once('@')
+        .map(|(_, digest)| digest)
+        .unwrap_or_default();
+    let vars = [
+        ("IMAGE", lockfile.container.image.clone()),
+        ("IMAGE_DIGEST", image_digest.to_string()),
+        ("LOCKFILE_SHA256", hash::sha256(buf)),
+        ("LOCKFILE_DIGEST", lockfile.digest()),
+        ("PACKAGE_COUNT", lockfile.packages.len().to_string()),
+    ];
+    let mut out = String::new();
+    for (key, value) in vars {
+        out.push_str(&format!("{key}={}\n", env_value(&value)));
+    }
+    out
+}
+
+pub async fn print(print: &args::LockPrint) -> Result<()> {
+    let path = print.file.as_deref().unwrap_or(Path::new("repro-env.lock"));
+    let buf = fs::read_to_string(path)
+        .await
+        .with_context(|| anyhow!("Failed to read dependency lockfile: {path:?}"))?;
+    let lockfile = Lockfile::deserialize(&buf)?;
+    match print.format {
+        args::LockPrintFormat::Toml => print!("{}", lockfile.serialize()?),
+        args::LockPrintFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&lockfile)?)
+        }
+        args::LockPrintFormat::Env => print!("{}", env_vars(&lockfile, buf.as_bytes())),
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct PackagePurl<'a> {
+    pub name: &'a str,
+    pub version: &'a str,
+    pub purl: String,
+}
+
+/// The purl of every package in the lockfile, packages of systems without a purl type are skipped
+pub fn package_purls(lockfile: &Lockfile) -> Vec<PackagePurl<'_>> {
+    lockfile
+        .packages
+        .iter()
+        .filter_map(|pkg| {
+            Some(PackagePurl {
+                name: &pkg.name,
+                version: &pkg.version,
+                purl: pkg.purl()?,
+            })
+        })
+        .collect()
+}
+
+pub async fn purls(purls: &args::LockPurls) -> Result<()> {
+    let path = purls.file.as_deref().unwrap_or(Path::new("repro-env.lock"));
+    let lockfile = Lockfile::read_from_file(path).await?;
+    let packages = package_purls(&lockfile);
+    if purls.json {
+        println!("{}", serde_json::to_string(&packages)?);
+    } else {
+        for package in packages {
+            println!("{}", package.purl);
+        }
+    }
+    Ok(())
+}
+
+/// The indexes of packages that can't be reached from the requested
+/// dependencies. Packages that are already installed in the image are never
+/// orphans, `metadata` has the parsed package for every other one.
+pub fn find_orphans(
+    system: &str,
+    requested: &[String],
+    packages: &[PackageLock],
+    metadata: &HashMap<usize, Pkg>,
+) -> BTreeSet<usize> {
+    let mut providers = HashMap::<&str, Vec<usize>>::new();
+    for (idx, package) in packages.iter().enumerate() {
+        providers.entry(&package.name).or_default().push(idx);
+        let provides = package
+            .provides
+            .iter()
+            .chain(metadata.get(&idx).into_iter().flat_map(|pkg| &pkg.provides));
+        for provide in provides {
+            for name in pkgs::dependency_names(&package.system, provide) {
+                providers.entry(name).or_default().push(idx);
+            }
+        }
+    }
+
+    let mut queue = requested
+        .iter()
+        .flat_map(|dep| pkgs::dependency_names(system, dep))
+        .collect::<Vec<_>>();
+    let mut reachable = BTreeSet::new();
+    while let Some(name) = queue.pop() {
+        // anything else is provided by the container image
+        let Some(idxs) = providers.get(name) else {
+            continue;
+        };
+        for idx in idxs {
+            if !reachable.insert(*idx) {
+                continue;
+            }
+            let package = &packages[*idx];
+            let depends = package
+                .depends
+                .iter()
+                .chain(metadata.get(idx).into_iter().flat_map(|pkg| &pkg.depends));
+            for dep in depends {
+                queue.extend(pkgs::dependency_names(&package.system, dep));
+            }
+        }
+    }
+
+    packages
+        .iter()
+        .enumerate()
+        .filter(|(idx, package)| {
+            !package.installed && package.has_package_file() && !reachable.contains(idx)
+        })
+        .map(|(idx, _)| idx)
+        .collect()
+}
+
+pub async fn prune(prune: &args::LockPrune) -> Result<()> {
+    let _cache_lock = utils::lock_shared(paths::cache_lock()?).await?;
+
+    let manifest = Manifest::read_from_file(manifest::locate(&prune.manifest)).await?;
+    let mut lockfile = Lockfile::read_from_file(&prune.file).await?;
+
+    // the dependencies of each package are read from the package files
+    let pending = lockfile
+        .packages
+        .iter()
+        .filter(|p| !p.installed && p.has_embedded_metadata())
+        .cloned()
+        .collect::<Vec<_>>();
+    fetch::download_dependencies(&pending, &args::DownloadArgs::default()).await?;
+
+    let pkgs_cache_dir = paths::pkgs_cache_dir()?;
+    let packages = lockfile.packages.clone();
+    let metadata = utils::spawn_blocking(move |_| {
+        let mut metadata = HashMap::new();
+        for (idx, package) in packages.iter().enumerate() {
+            if package.installed || !package.has_embedded_metadata() {
+                continue;
+            }
+            let path = pkgs_cache_dir.sha256_path(&package.sha256)?;
+            let file = std::fs::File::open(&path)
+                .with_context(|| anyhow!("Failed to open package from cache: {path:?}"))?;
+            let pkg = sandbox::inspect(&package.system, file)
+                .with_context(|| anyhow!("Failed to parse package: {:?}", package.name))?;
+            metadata.insert(idx, pkg);
+        }
+        Ok(metadata)
+    })
+    .await?;
+
+    let (system, requested) = match &manifest.packages {
+        Some(packages) => (
+            packages.system.as_str(),
+            packages.dependencies.iter().cloned().collect(),
+        ),
+        None => ("", vec![]),
+    };
+    let orphans = find_orphans(system, &requested, &lockfile.packages, &metadata);
+    if orphans.is_empty() {
+        info!("No orphaned packages in {:?}", prune.file);
+        return Ok(());
+    }
+    for idx in &orphans {
+        let package = &lockfile.packages[*idx];
+        info!(
+            "Orphaned package: {} {} ({})",
+            package.name, package.version, package.system
+        );
+    }
+    if prune.dry_run {
+        return Ok(());
+    }
+
+    let mut idx = 0;
+    lockfile.packages.retain(|_| {
+        let keep = !orphans.contains(&idx);
+        idx += 1;
+        keep
+    });
+    lockfile.write_to_file(&prune.file)?;
+    info!(
+        "Removed {} orphaned packages from {:?}",
+        orphans.len(),
+        prune.file
+    );
+    Ok(())
+}
+
+/// Parse an age like `90d`, `12w` or `48h`
+pub fn parse_age(s: &str) -> Result<Duration> {
+    let (num, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let num = num
+        .parse::<u64>()
+        .with_context(|| anyhow!("Invalid age, expected a number with unit: {s:?}"))?;
+    let secs = match unit {
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => bail!("Invalid unit of age, expected h, d or w: {s:?}"),
+    };
+    Ok(Duration::from_secs(num * secs))
+}
+
+/// When the lockfile was resolved, or if there's no `[meta]` section, the
+/// time the newest package signature was made
+pub fn resolved_at(lockfile: &Lockfile) -> Result<Option<SystemTime>> {
+    if let Some(meta) = &lockfile.meta {
+        let time = OffsetDateTime::parse(&meta.resolved_at, &well_known::Rfc3339)
+            .with_context(|| anyhow!("Failed to parse resolved_at: {:?}", meta.resolved_at))?;
+        return Ok(Some(time.into()));
+    }
+    let signed = lockfile
+        .packages
+        .iter()
+        .filter(|p| p.openpgp_signature().is_some());
+    pgp::find_max_signature_time(signed)
+}
+
+pub fn check_age(resolved_at: SystemTime, now: SystemTime, max_age: Duration) -> Result<Duration> {
+    let age = now.duration_since(resolved_at).unwrap_or_default();
+    if age > max_age {
+        bail!(
+            "Lockfile is {} days old, the maximum age is {} days, run `repro-env update` to refresh it",
+            age.as_secs() / 86400,
+            max_age.as_secs() / 86400
+        );
+    }
+    Ok(age)
+}
+
+pub async fn check(check: &args::LockCheck) -> Result<()> {
+    let lockfile = Lockfile::read_from_file(&check.file).await?;
+    consistency::check_packages(lockfile.all_packages())
+        .with_context(|| anyhow!("Lockfile check failed: {:?}", check.file))?;
+    let Some(max_age) = check.max_age else {
+        info!("Packages of lockfile {:?} are consistent", check.file);
+        return Ok(());
+    };
+    let resolved_at = resolved_at(&lockfile)?.with_context(|| {
+        anyhow!("Can't tell when {:?} was resolved, enable `meta = true` in the [lockfile] section of repro-env.toml", check.file)
+    })?;
+    let age = check_age(resolved_at, SystemTime::now(), max_age)
+        .with_context(|| anyhow!("Lockfile check failed: {:?}", check.file))?;
+    info!(
+        "Lockfile {:?} is {} days old",
+        check.file,
+        age.as_secs() / 86400
+    );
+    Ok(())
+}
+
+/// Replace the pin of the package with this name (or add it, if it's not in
+/// the lockfile yet), returns its index and the previous pin. The signatures,
+/// size and fallback urls of the previous pin don't apply to the new file.
+pub fn set_package(
+    lockfile: &mut Lockfile,
+    set: &args::LockSet,
+) -> Result<(usize, Option<PackageLock>)> {
+    if set.sha256.len() != 64 || !set.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!(
+            "Invalid sha256, expected 64 hex characters: {:?}",
+            set.sha256
+        );
+    }
+    if !set.url.starts_with("https://") && !set.url.starts_with("http://") {
+        bail!("Package url needs to be http(s): {:?}", set.url);
+    }
+
+    let mut matches = lockfile
+        .packages
+        .iter()
+        .enumerate()
+        .filter(|(_, p)| p.name == set.name);
+    let idx = match (matches.next(), matches.next()) {
+        (Some((idx, _)), None) => Some(idx),
+        (None, _) => None,
+        (Some(_), Some(_)) => bail!(
+            "Package {:?} is in the lockfile multiple times, edit it by hand",
+            set.name
+        ),
+    };
+
+    let system = match (&set.system, idx) {
+        (Some(system), _) => system.clone(),
+        (None, Some(idx)) => lockfile.packages[idx].system.clone(),
+        (None, None) => {
+            let systems = lockfile
+                .packages
+                .iter()
+                .map(|p| p.system.as_str())
+                .collect::<BTreeSet<_>>();
+            let mut systems = systems.into_iter();
+            match (systems.next(), systems.next()) {
+                (Some(system), None) => system.to_string(),
+                _ => bail!(
+                    "Package {:?} is not in the lockfile yet, use --system to add it",
+                    set.name
+                ),
+            }
+        }
+    };
+    if !systems::package_systems()
+        .iter()
+        .any(|known| known.name == system)
+    {
+        bail!("Unknown package system: {system:?}");
+    }
+    if system == "flatpak" {
+        bail!("Pinning flatpak runtimes by hand is not supported, use `repro-env update`");
+    }
+
+    let signatures = set
+        .signature
+        .iter()
+        .map(|value| SignatureLock {
+            algorithm: SignatureLock::OPENPGP.to_string(),
+            issuer: None,
+            value: value.clone(),
+        })
+        .collect();
+    let previous = idx.map(|idx| lockfile.packages[idx].clone());
+    let package = PackageLock {
+        name: set.name.clone(),
+        version: set.version.clone(),
+        system,
+        url: set.url.clone(),
+        provides: previous
+            .as_ref()
+            .map(|p| p.provides.clone())
+            .unwrap_or_default(),
+        sha256: set.sha256.to_ascii_lowercase(),
+        signatures,
+        depends: previous
+            .as_ref()
+            .map(|p| p.depends.clone())
+            .unwrap_or_default(),
+        ..Default::default()
+    };
+    let idx = match idx {
+        Some(idx) => {
+            lockfile.packages[idx] = package;
+            idx
+        }
+        None => {
+            lockfile.packages.push(package);
+            lockfile.packages.len() - 1
+        }
+    };
+    Ok((idx, previous))
+}
+
+pub async fn set(set: &args::LockSet) -> Result<()> {
+    let _cache_lock = utils::lock_shared(paths::cache_lock()?).await?;
+
+    let mut lockfile = Lockfile::read_from_file(&set.file).await?;
+    let (idx, previous) = set_package(&mut lockfile, set)?;
+    let package = &lockfile.packages[idx];
+    if previous.as_ref().is_some_and(|p| !p.signatures.is_empty()) && package.signatures.is_empty()
+    {
+        warn!(
+            "Package {:?} had a signature that doesn't apply to the new file, use --signature to pin one",
+            set.name
+        );
+    }
+
+    consistency::check_packages(lockfile.all_packages())
+        .with_context(|| anyhow!("Refusing to pin {:?}", set.name))?;
+
+    // only write the lockfile if the new pin can be used for a build
+    fetch::download_dependencies(
+        std::slice::from_ref(package),
+        &args::DownloadArgs::default(),
+    )
+    .await?;
+    let dependencies = vec![package.clone()];
+    let apk_keys = lockfile.apk_keys.clone();
+    utils::spawn_blocking(move |cancel| {
+        fetch::verify_dependencies(&dependencies, apk_keys, cancel)
+    })
+    .await?;
+    let path = paths::pkgs_cache_dir()?.sha256_path(&lockfile.packages[idx].sha256)?;
+    lockfile.packages[idx].size = Some(std::fs::metadata(&path)?.len());
+    lockfile.write_to_file(&set.file)?;
+    match previous {
+        Some(previous) => info!(
+            "Changed pin of {:?} from {:?} to {:?} in {:?}",
+            set.name, previous.version, set.version, set.file
+        ),
+        None => info!(
+            "Added pin of {:?} {:?} to {:?}",
+            set.name, set.version, set.file
+        ),
+    }
+    Ok(())
+}
+
+/// Remove every pin of the package with this name, returns the removed pins
+pub fn remove_package(lockfile: &mut Lockfile, name: &str) -> Result<Vec<PackageLock>> {
+    let (removed, kept) = lockfile
+        .packages
+        .drain(..)
+        .partition::<Vec<_>, _>(|p| p.name == name);
+    lockfile.packages = kept;
+    if removed.is_empty() {
+        bail!("Package {name:?} is not in the lockfile");
+    }
+    Ok(removed)
+}
+
+pub async fn remove(remove: &args::LockRemove) -> Result<()> {
+    let mut lockfile = Lockfile::read_from_file(&remove.file).await?;
+    let removed = remove_package(&mut lockfile, &remove.name)?;
+    for package in lockfile.all_packages() {
+        if package.depends.contains(&remove.name) {
+            warn!(
+                "Package {:?} still depends on {:?}",
+                package.name, remove.name
+            );
+        }
+    }
+    lockfile.write_to_file(&remove.file)?;
+    for package in removed {
+        info!(
+            "Removed pin of {:?} {:?} from {:?}",
+            package.name, package.version, remove.file
+        );
+    }
+    Ok(())
+}
+
+pub async fn run(lock: &args::Lock) -> Result<()> {
+    match lock {
+        args::Lock::Digest(digest) => self::digest(digest).await,
+        args::Lock::Print(print) => self::print(print).await,
+        args::Lock::Prune(prune) => self::prune(prune).await,
+        args::Lock::Check(check) => self::check(check).await,
+        args::Lock::Purls(purls) => self::purls(purls).await,
+        args::Lock::Set(set) => self::set(set).await,
+        args::Lock::Remove(remove) => self::remove(remove).await,
+        args::Lock::DescribeImage(describe) => describe::describe_image(describe).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(name: &str, installed: bool) -> PackageLock {
+        PackageLock {
+            name: name.to_string(),
+            version: "1.0-1".to_string(),
+            system: "archlinux".to_string(),
+            url: format!("https://example.com/{name}.pkg.tar.zst"),
+            sha256: "00".repeat(32),
+            installed,
+            ..Default::default()
+        }
+    }
+
+    fn pkg(name: &str, depends: &[&str], provides: &[&str]) -> Pkg {
+        Pkg {
+            name: name.to_string(),
+            version: "1.0-1".to_string(),
+            arch: None,
+            depends: depends.iter().map(|s| s.to_string()).collect(),
+            provides: provides.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_find_orphans() {
+        let packages = vec![
+            package("glibc", true),
+            package("rust", false),
+            package("libssh2", false),
+            package("openssl", false),
+            package("nodejs", false),
+            package("libuv", false),
+        ];
+        let metadata = HashMap::from([
+            (1, pkg("rust", &["glibc", "libssh2.so=1-64"], &["cargo"])),
+            (2, pkg("libssh2", &["openssl>=3"], &["libssh2.so=1-64"])),
+            (3, pkg("openssl", &["glibc"], &[])),
+            (4, pkg("nodejs", &["libuv"], &[])),
+            (5, pkg("libuv", &[], &[])),
+        ]);
+        let orphans = find_orphans("archlinux", &["cargo".to_string()], &packages, &metadata);
+        assert_eq!(orphans, BTreeSet::from([4, 5]));
+    }
+
+    #[test]
+    fn test_package_purls() -> Result<()> {
+        let mut lockfile = Lockfile::deserialize(
+            r#"
+[container]
+image = "docker.io/library/archlinux@sha256:6568d3f1f278827a4a7d8537f80c2ae36982829a0c6bccff4cec081774025472"
+"#,
+        )?;
+        let mut other = package("rust", false);
+        other.system = "gentoo".to_string();
+        lockfile.packages = vec![package("glibc", true), other];
+        assert_eq!(
+            package_purls(&lockfile),
+            [PackagePurl {
+                name: "glibc",
+                version: "1.0-1",
+                purl: "pkg:alpm/arch/glibc@1.0-1".to_string(),
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_env_vars() -> Result<()> {
+        let lockfile = lockfile(vec![package("glibc", true), package("gcc", false)])?;
+        let env = env_vars(&lockfile, b"");
+        assert_eq!(
+            env,
+            format!(
+                "IMAGE=docker.io/library/archlinux@sha256:6568d3f1f278827a4a7d8537f80c2ae36982829a0c6bccff4cec081774025472
+IMAGE_DIGEST=sha256:6568d3f1f278827a4a7d8537f80c2ae36982829a0c6bccff4cec081774025472
+LOCKFILE_SHA256=e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855
+LOCKFILE_DIGEST={}
+PACKAGE_COUNT=2
+",
+                lockfile.digest()
+            )
+        );
+
+        assert_eq!(env_value(""), "''");
+        assert_eq!(env_value("it's $HOME"), "'it'\\''s $HOME'");
+        Ok(())
+    }
+
+    fn lockfile(packages: Vec<PackageLock>) -> Result<Lockfile> {
+        let mut lockfile = Lockfile::deserialize(
+            r#"
+[container]
+image = "docker.io/library/archlinux@sha256:6568d3f1f278827a4a7d8537f80c2ae36982829a0c6bccff4cec081774025472"
+"#,
+        )?;
+        lockfile.packages = packages;
+        Ok(lockfile)
+    }
+
+    fn set(name: &str, version: &str) -> args::LockSet {
+        args::LockSet {
+            name: name.to_string(),
+            version: version.to_string(),
+            url: format!("https://example.com/{name}-{version}.pkg.tar.zst"),
+            sha256: "AB".repeat(32),
+            system: None,
+            signature: None,
+            file: "repro-env.lock".into(),
+        }
+    }
+
+    #[test]
+    fn test_set_package() -> Result<()> {
+        let mut old = package("rust", false);
+        old.depends = vec!["glibc".to_string()];
+        old.fallback_urls = vec!["https://mirror.example.com/rust.pkg.tar.zst".to_string()];
+        old.signatures = vec![SignatureLock {
+            algorithm: SignatureLock::OPENPGP.to_string(),
+            issuer: None,
+            value: "AAAA".to_string(),
+        }];
+        old.size = Some(1234);
+        let mut lockfile = lockfile(vec![package("glibc", true), old.clone()])?;
+
+        let (idx, previous) = set_package(&mut lockfile, &set("rust", "1.1-1"))?;
+        assert_eq!((idx, previous), (1, Some(old)));
+        let package = &lockfile.packages[1];
+        assert_eq!(package.version, "1.1-1");
+        assert_eq!(package.system, "archlinux");
+        assert_eq!(package.sha256, "ab".repeat(32));
+        assert_eq!(package.depends, ["glibc"]);
+        assert!(package.fallback_urls.is_empty());
+        assert!(package.signatures.is_empty());
+        assert_eq!(package.size, None);
+
+        // new packages use the system of the other packages
+        let (idx, previous) = set_package(&mut lockfile, &set("nodejs", "20.0-1"))?;
+        assert_eq!((idx, previous), (2, None));
+        assert_eq!(lockfile.packages[2].system, "archlinux");
+
+        let mut invalid = set("rust", "1.1-1");
+        invalid.sha256 = "abc".to_string();
+        assert!(set_package(&mut lockfile, &invalid).is_err());
+        let mut invalid = set("rust", "1.1-1");
+        invalid.system = Some("gentoo".to_string());
+        assert!(set_package(&mut lockfile, &invalid).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_package() -> Result<()> {
+        let mut lockfile = lockfile(vec![package("glibc", true), package("rust", false)])?;
+        let removed = remove_package(&mut lockfile, "rust")?;
+        assert_eq!(removed, [package("rust", false)]);
+        assert_eq!(lockfile.packages, [package("glibc", true)]);
+        assert!(remove_package(&mut lockfile, "rust").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_age() {
+        assert_eq!(parse_age("90d").unwrap(), Duration::from_secs(90 * 86400));
+        assert_eq!(parse_age("2w").unwrap(), Duration::from_secs(14 * 86400));
+        assert_eq!(parse_age("48h").unwrap(), Duration::from_secs(2 * 86400));
+        assert!(parse_age("90").is_err());
+        assert!(parse_age("d").is_err());
+        assert!(parse_age("3m").is_err());
+    }
+
+    #[test]
+    fn test_check_age() {
+        let day = Duration::from_secs(86400);
+        let resolved_at = SystemTime::UNIX_EPOCH + 1000 * day;
+        let now = resolved_at + 30 * day;
+        assert_eq!(check_age(resolved_at, now, 90 * day).unwrap(), 30 * day);
+        assert_eq!(check_age(resolved_at, now, 30 * day).unwrap(), 30 * day);
+        let err = check_age(resolved_at, now, 7 * day).unwrap_err();
+        assert!(err.to_string().contains("30 days old"));
+        // clock skew doesn't make the lockfile fail the check
+        assert!(check_age(now, resolved_at, day).is_ok());
+    }
+}
@@ -1,26 +1,268 @@
+#[cfg(feature = "cli")]
+use crate::build_info;
 use crate::errors::*;
+#[cfg(feature = "cli")]
+use crate::manifest::Manifest;
+use crate::manifest::{self, Init};
+use crate::purl;
+#[cfg(feature = "cli")]
+use crate::resolver::provenance::Provenance;
+use crate::systems;
+#[cfg(feature = "cli")]
+use crate::utils;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+#[cfg(feature = "cli")]
 use std::path::Path;
+#[cfg(feature = "cli")]
+use time::format_description::well_known;
+#[cfg(feature = "cli")]
+use time::OffsetDateTime;
+#[cfg(feature = "cli")]
 use tokio::fs;
 
+/// The first version of repro-env that checks `min_repro_env_version`, older
+/// versions ignore it. Features that already existed are recorded with it.
+const VERSION_CHECK_SINCE: &str = "0.4.1";
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Lockfile {
+    /// Older versions of repro-env refuse to use the lockfile instead of
+    /// ignoring parts of it, this is set when the lockfile is written
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_repro_env_version: Option<String>,
+    /// Packages that are installed before the others, one at a time in this order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub install_order: Vec<String>,
     pub container: ContainerLock,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<MetaLock>,
+    /// Organization specific fields, usually added by a lockfile transform.
+    /// They are kept as-is but not interpreted by repro-env.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub policy: BTreeMap<String, toml::Value>,
+    #[serde(default, rename = "key", skip_serializing_if = "Vec::is_empty")]
+    pub keys: Vec<KeyLock>,
+    #[serde(default, rename = "apk_key", skip_serializing_if = "Vec::is_empty")]
+    pub apk_keys: Vec<ApkKeyLock>,
+    #[serde(default, rename = "package", skip_serializing_if = "Vec::is_empty")]
+    pub packages: Vec<PackageLock>,
+    /// Packages that are only installed if the profile is selected, in
+    /// addition to the packages above
+    #[serde(
+        default,
+        rename = "profile",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub profiles: BTreeMap<String, ProfileLock>,
+    /// Files of `[[files]]` in the manifest, mounted into /extra/files/
+    #[serde(default, rename = "file", skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<FileLock>,
+    /// Repositories of `[[git]]` in the manifest, checked out into /extra/git/
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub git: Vec<GitLock>,
+    /// Tarballs of `[[toolchain.external]]` in the manifest, extracted and mounted at their path
+    #[serde(default, rename = "toolchain", skip_serializing_if = "Vec::is_empty")]
+    pub toolchains: Vec<ToolchainLock>,
+    /// Artifacts of `[java.maven]`, they form the local repository in /extra/maven/
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub maven: Vec<MavenLock>,
+    /// Gem archives of the `Gemfile.lock` of `[ruby]`, provided as bundler cache in /extra/gems/
+    #[serde(default, rename = "gem", skip_serializing_if = "Vec::is_empty")]
+    pub gems: Vec<GemLock>,
+    /// Dist archives of the `composer.lock` of `[php]`, provided as composer cache in /extra/composer/
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub composer: Vec<ComposerLock>,
+    /// Distributions of the `cpanfile` of `[perl]`, provided as CPAN mirror in /extra/cpan/
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cpan: Vec<CpanLock>,
+    /// The stackage snapshot of `[haskell]`, provided in /extra/haskell/
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub haskell: Option<HaskellLock>,
+    /// The tlnet snapshot of `[texlive]`, provided as local repository in /extra/texlive/
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub texlive: Option<TexliveLock>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProfileLock {
     #[serde(default, rename = "package", skip_serializing_if = "Vec::is_empty")]
     pub packages: Vec<PackageLock>,
 }
 
 impl Lockfile {
     pub fn deserialize(buf: &str) -> Result<Self> {
-        let lockfile = toml::from_str(buf)?;
+        let mut lockfile = toml::from_str::<Self>(&manifest::normalize_text(buf))?;
+        lockfile.check_compatible(env!("CARGO_PKG_VERSION"))?;
+        lockfile.check_packages()?;
         Ok(lockfile)
     }
 
     pub fn serialize(&self) -> Result<String> {
-        let toml = toml::to_string_pretty(self)?;
+        let mut lockfile = self.clone();
+        lockfile.min_repro_env_version = self.required_version().map(String::from);
+        let toml = toml::to_string_pretty(&lockfile)?;
         Ok(toml)
     }
 
+    /// The oldest version of repro-env that understands everything this lockfile uses
+    pub fn required_version(&self) -> Option<&'static str> {
+        let features = [
+            ("key", !self.keys.is_empty(), VERSION_CHECK_SINCE),
+            ("apk_key", !self.apk_keys.is_empty(), VERSION_CHECK_SINCE),
+            (
+                "container.sha256",
+                self.container.sha256.is_some(),
+                VERSION_CHECK_SINCE,
+            ),
+            (
+                "container.init_sha256",
+                self.container.init_sha256.is_some(),
+                VERSION_CHECK_SINCE,
+            ),
+            (
+                "container.init",
+                !self.container.init.is_default(),
+                VERSION_CHECK_SINCE,
+            ),
+            (
+                "container.containerfile",
+                self.container.containerfile.is_some(),
+                VERSION_CHECK_SINCE,
+            ),
+            (
+                "install_order",
+                !self.install_order.is_empty(),
+                VERSION_CHECK_SINCE,
+            ),
+            ("file", !self.files.is_empty(), VERSION_CHECK_SINCE),
+            (
+                "file.signature",
+                self.files.iter().any(|f| f.signature.is_some()),
+                VERSION_CHECK_SINCE,
+            ),
+            ("git", !self.git.is_empty(), VERSION_CHECK_SINCE),
+            (
+                "toolchain",
+                !self.toolchains.is_empty(),
+                VERSION_CHECK_SINCE,
+            ),
+            ("maven", !self.maven.is_empty(), VERSION_CHECK_SINCE),
+            ("gem", !self.gems.is_empty(), VERSION_CHECK_SINCE),
+            ("composer", !self.composer.is_empty(), VERSION_CHECK_SINCE),
+            ("cpan", !self.cpan.is_empty(), VERSION_CHECK_SINCE),
+            ("haskell", self.haskell.is_some(), VERSION_CHECK_SINCE),
+            ("texlive", self.texlive.is_some(), VERSION_CHECK_SINCE),
+            (
+                "package.depends",
+                self.packages.iter().any(|p| !p.depends.is_empty()),
+                VERSION_CHECK_SINCE,
+            ),
+            (
+                "package.signature",
+                self.packages.iter().any(|p| match &p.signatures[..] {
+                    [] => false,
+                    [sig] => sig.algorithm != SignatureLock::OPENPGP || sig.issuer.is_some(),
+                    _ => true,
+                }),
+                VERSION_CHECK_SINCE,
+            ),
+        ];
+        features
+            .into_iter()
+            .filter(|(_, used, _)| *used)
+            .map(|(name, _, version)| {
+                trace!("Lockfile uses {name}, supported since repro-env {version}");
+                version
+            })
+            .max_by_key(|version| parse_version(version))
+    }
+
+    /// The `[[file]]` pins, the tarballs of the toolchains, the maven artifacts,
+    /// gems, composer dists, perl distributions, the stackage snapshot and the
+    /// TeX Live packages, everything that's
+    /// downloaded into the cache from an arbitrary url
+    pub fn all_files(&self) -> Vec<FileLock> {
+        self.files
+            .iter()
+            .cloned()
+            .chain(self.toolchains.iter().map(ToolchainLock::file))
+            .chain(self.maven.iter().map(MavenLock::file))
+            .chain(self.gems.iter().map(GemLock::file))
+            .chain(self.composer.iter().map(ComposerLock::file))
+            .chain(self.cpan.iter().map(CpanLock::file))
+            .chain(self.haskell.iter().flat_map(|h| h.files.iter().cloned()))
+            .chain(self.texlive.iter().flat_map(|t| t.files.iter().cloned()))
+            .collect()
+    }
+
+    /// The packages of the environment and of every profile
+    pub fn all_packages(&self) -> impl Iterator<Item = &PackageLock> {
+        self.packages
+            .iter()
+            .chain(self.profiles.values().flat_map(|profile| &profile.packages))
+    }
+
+    /// Add the packages of a profile to the environment, the other profiles are discarded
+    pub fn select_profile(&mut self, name: Option<&str>) -> Result<()> {
+        let profiles = std::mem::take(&mut self.profiles);
+        let Some(name) = name else {
+            return Ok(());
+        };
+        let Some(profile) = profiles.get(name) else {
+            if profiles.is_empty() {
+                bail!("Lockfile has no profiles, unknown profile: {name:?}");
+            }
+            let known = profiles.keys().map(String::as_str).collect::<Vec<_>>();
+            bail!("Unknown profile {name:?}, available: {}", known.join(", "));
+        };
+        self.packages.extend(profile.packages.iter().cloned());
+        Ok(())
+    }
+
+    /// Fail if the lockfile needs a newer version of repro-env than `current`
+    pub fn check_compatible(&self, current: &str) -> Result<()> {
+        if let Some(required) = &self.min_repro_env_version {
+            if parse_version(required) > parse_version(current) {
+                bail!("Lockfile requires repro-env {required} or newer, but this is repro-env {current}, please upgrade");
+            }
+        }
+        let systems = systems::package_systems();
+        for pkg in &self.packages {
+            if !systems.iter().any(|s| s.name == pkg.system) {
+                bail!(
+                    "Lockfile uses package system {:?} which is not supported by repro-env {current}, please upgrade",
+                    pkg.system
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Every package needs to be pinned, either by sha256 or (flatpak) by commit
+    fn check_packages(&mut self) -> Result<()> {
+        let profiles = self
+            .profiles
+            .values_mut()
+            .flat_map(|p| p.packages.iter_mut());
+        for pkg in self.packages.iter_mut().chain(profiles) {
+            if pkg.system == "flatpak" {
+                // older lockfiles recorded the commit as sha256
+                if pkg.commit.is_none() && !pkg.sha256.is_empty() {
+                    pkg.commit = Some(std::mem::take(&mut pkg.sha256));
+                }
+                if pkg.commit.is_none() {
+                    bail!("Flatpak runtime in lockfile has no commit: {:?}", pkg.name);
+                }
+            } else if pkg.sha256.is_empty() {
+                bail!("Package in lockfile has no sha256: {:?}", pkg.name);
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "cli")]
     pub async fn read_from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
         let path = path.as_ref();
         let buf = fs::read_to_string(&path)
@@ -30,29 +272,644 @@ impl Lockfile {
         trace!("Loaded dependency lockfile from file: {lockfile:?}");
         Ok(lockfile)
     }
+
+    /// A stable hash over everything that defines the environment (the
+    /// container and the set of packages). Metadata, urls and signatures are
+    /// ignored, as is the order of the packages, but not the install order.
+    pub fn digest(&self) -> String {
+        let mut lines = vec![format!("container {}", self.container.image)];
+        if let Some(sha256) = &self.container.sha256 {
+            lines.push(format!("container-archive {sha256}"));
+        }
+        if let Some(sha256) = &self.container.init_sha256 {
+            lines.push(format!("container-init {sha256}"));
+        }
+        if let Some(containerfile) = &self.container.containerfile {
+            lines.push(format!(
+                "container-build {} {}",
+                containerfile.sha256, containerfile.image_id
+            ));
+        }
+        if !self.container.init.is_default() {
+            lines.push(format!(
+                "container-init-mode {}",
+                self.container.init.as_str()
+            ));
+        }
+
+        let mut packages = self
+            .packages
+            .iter()
+            .map(|pkg| {
+                format!(
+                    "package {} {} {} {} {}",
+                    pkg.system,
+                    pkg.name,
+                    pkg.version,
+                    pkg.commit.as_deref().unwrap_or(&pkg.sha256),
+                    pkg.installed
+                )
+            })
+            .collect::<Vec<_>>();
+        packages.sort();
+        lines.extend(packages);
+
+        for (name, profile) in &self.profiles {
+            let mut packages = profile
+                .packages
+                .iter()
+                .map(|pkg| {
+                    format!(
+                        "profile {name} package {} {} {} {} {}",
+                        pkg.system,
+                        pkg.name,
+                        pkg.version,
+                        pkg.commit.as_deref().unwrap_or(&pkg.sha256),
+                        pkg.installed
+                    )
+                })
+                .collect::<Vec<_>>();
+            packages.sort();
+            lines.extend(packages);
+        }
+
+        lines.extend(
+            self.install_order
+                .iter()
+                .map(|name| format!("install-order {name}")),
+        );
+
+        let mut keys = self
+            .keys
+            .iter()
+            .map(|key| format!("key {}", key.fingerprint))
+            .collect::<Vec<_>>();
+        keys.sort();
+        lines.extend(keys);
+
+        let mut apk_keys = self
+            .apk_keys
+            .iter()
+            .map(|key| {
+                let mut hasher = Sha256::new();
+                hasher.update(key.key.as_bytes());
+                format!("apk-key {} {}", key.name, hex::encode(hasher.finalize()))
+            })
+            .collect::<Vec<_>>();
+        apk_keys.sort();
+        lines.extend(apk_keys);
+
+        let mut files = self
+            .files
+            .iter()
+            .map(|file| format!("file {} {}", file.name, file.sha256))
+            .collect::<Vec<_>>();
+        files.sort();
+        lines.extend(files);
+
+        let mut git = self
+            .git
+            .iter()
+            .map(|git| format!("git {} {}", git.name, git.commit))
+            .collect::<Vec<_>>();
+        git.sort();
+        lines.extend(git);
+
+        let mut toolchains = self
+            .toolchains
+            .iter()
+            .map(|toolchain| {
+                format!(
+                    "toolchain {} {} {}",
+                    toolchain.path, toolchain.sha256, toolchain.strip_components
+                )
+            })
+            .collect::<Vec<_>>();
+        toolchains.sort();
+        lines.extend(toolchains);
+
+        let mut maven = self
+            .maven
+            .iter()
+            .map(|artifact| format!("maven {} {}", artifact.path, artifact.sha256))
+            .collect::<Vec<_>>();
+        maven.sort();
+        lines.extend(maven);
+
+        let mut gems = self
+            .gems
+            .iter()
+            .map(|gem| format!("gem {} {}", gem.filename(), gem.sha256))
+            .collect::<Vec<_>>();
+        gems.sort();
+        lines.extend(gems);
+
+        let mut composer = self
+            .composer
+            .iter()
+            .map(|package| {
+                format!(
+                    "composer {} {} {}",
+                    package.name, package.url, package.sha256
+                )
+            })
+            .collect::<Vec<_>>();
+        composer.sort();
+        lines.extend(composer);
+
+        let mut cpan = self
+            .cpan
+            .iter()
+            .map(|dist| format!("cpan {} {}", dist.path, dist.sha256))
+            .collect::<Vec<_>>();
+        cpan.sort();
+        lines.extend(cpan);
+
+        if let Some(haskell) = &self.haskell {
+            lines.push(format!("haskell {}", haskell.snapshot));
+            let mut files = haskell
+                .files
+                .iter()
+                .map(|file| format!("haskell.file {} {}", file.name, file.sha256))
+                .collect::<Vec<_>>();
+            files.sort();
+            lines.extend(files);
+        }
+
+        if let Some(texlive) = &self.texlive {
+            lines.push(format!("texlive {}", texlive.date));
+            let mut files = texlive
+                .files
+                .iter()
+                .map(|file| format!("texlive.file {} {}", file.name, file.sha256))
+                .collect::<Vec<_>>();
+            files.sort();
+            lines.extend(files);
+        }
+
+        let mut hasher = Sha256::new();
+        for line in lines {
+            hasher.update(line.as_bytes());
+            hasher.update(b"\n");
+        }
+        format!("sha256:{}", hex::encode(hasher.finalize()))
+    }
+
+    #[cfg(feature = "cli")]
+    pub fn write_to_file(&self, path: &Path) -> Result<()> {
+        let buf = self.serialize()?;
+        utils::atomic_write(path, buf.as_bytes())
+            .with_context(|| anyhow!("Failed to write dependency lockfile: {path:?}"))
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ContainerLock {
     pub image: String,
+    /// The sha256 of the image archive, if the image is loaded from a file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// The sha256 of the init that is mounted into the container, if it's pinned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub init_sha256: Option<String>,
+    /// What runs as pid 1 of the containers, catatonit of the host by default
+    #[serde(default, skip_serializing_if = "Init::is_default")]
+    pub init: Init,
+    /// If set, the image is built locally on top of `image`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub containerfile: Option<ContainerfileLock>,
+}
+
+/// An image that's built locally with `podman build` from a containerfile
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContainerfileLock {
+    /// The path of the containerfile, relative to the project directory
+    pub path: String,
+    /// The sha256 of the containerfile
+    pub sha256: String,
+    /// The id of the image that was built from it
+    pub image_id: String,
+}
+
+/// An OpenPGP certificate that is imported into the pacman keyring before
+/// installing packages (archlinux only)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyLock {
+    pub fingerprint: String,
+    /// The ascii-armored public key
+    pub cert: String,
+}
+
+/// A file that was downloaded from an arbitrary url, it's verified with its sha256
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileLock {
+    /// The filename in /extra/files/
+    pub name: String,
+    pub url: String,
+    pub sha256: String,
+    pub size: u64,
+    /// Verified during `update` and again before the file is used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<FileSignatureLock>,
+}
+
+/// A detached OpenPGP signature of a `[[file]]` and the certificate that made it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileSignatureLock {
+    pub url: String,
+    /// The signature, base64
+    pub value: String,
+    pub fingerprint: String,
+    /// The ascii-armored public key
+    pub cert: String,
+}
+
+/// A git repository, pinned to the commit its rev pointed to during `update`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GitLock {
+    /// The directory in /extra/git/
+    pub name: String,
+    pub url: String,
+    /// The branch, tag or commit of the manifest
+    pub rev: String,
+    pub commit: String,
+}
+
+/// A prebuilt toolchain, the tarball is verified with its sha256 and extracted into the cache
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolchainLock {
+    /// The filename of the tarball
+    pub name: String,
+    pub url: String,
+    pub sha256: String,
+    pub size: u64,
+    /// Where the extracted toolchain is mounted in the container
+    pub path: String,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub strip_components: u32,
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+impl ToolchainLock {
+    /// The tarball, it's downloaded and verified like `[[file]]`
+    pub fn file(&self) -> FileLock {
+        FileLock {
+            name: self.name.clone(),
+            url: self.url.clone(),
+            sha256: self.sha256.clone(),
+            size: self.size,
+            signature: None,
+        }
+    }
+}
+
+/// A file of the local maven repository, verified with its sha256
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MavenLock {
+    /// The path in the repository, like `org/slf4j/slf4j-api/2.0.13/slf4j-api-2.0.13.jar`
+    pub path: String,
+    pub url: String,
+    pub sha256: String,
+    pub size: u64,
+}
+
+impl MavenLock {
+    /// The artifact is downloaded and verified like `[[file]]`
+    pub fn file(&self) -> FileLock {
+        FileLock {
+            name: self.path.clone(),
+            url: self.url.clone(),
+            sha256: self.sha256.clone(),
+            size: self.size,
+            signature: None,
+        }
+    }
+}
+
+/// A gem archive from a `GEM` section of `Gemfile.lock`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GemLock {
+    pub name: String,
+    pub version: String,
+    /// Set for gems with precompiled extensions, like `x86_64-linux`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    pub url: String,
+    pub sha256: String,
+    pub size: u64,
+}
+
+impl GemLock {
+    /// The filename of the archive, like `nokogiri-1.16.2-x86_64-linux.gem`
+    pub fn filename(&self) -> String {
+        match &self.platform {
+            Some(platform) => format!("{}-{}-{platform}.gem", self.name, self.version),
+            None => format!("{}-{}.gem", self.name, self.version),
+        }
+    }
+
+    /// The archive is downloaded and verified like `[[file]]`
+    pub fn file(&self) -> FileLock {
+        FileLock {
+            name: self.filename(),
+            url: self.url.clone(),
+            sha256: self.sha256.clone(),
+            size: self.size,
+            signature: None,
+        }
+    }
+}
+
+/// A dist archive of a package in `composer.lock`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ComposerLock {
+    /// The name of the package, like `symfony/console`
+    pub name: String,
+    pub version: String,
+    /// The archive format, usually `zip`
+    pub dist_type: String,
+    pub url: String,
+    pub sha256: String,
+    pub size: u64,
+}
+
+impl ComposerLock {
+    /// The archive is downloaded and verified like `[[file]]`
+    pub fn file(&self) -> FileLock {
+        FileLock {
+            name: format!(
+                "{}-{}.{}",
+                self.name.replace('/', "-"),
+                self.version,
+                self.dist_type
+            ),
+            url: self.url.clone(),
+            sha256: self.sha256.clone(),
+            size: self.size,
+            signature: None,
+        }
+    }
+}
+
+/// A distribution tarball from CPAN, pinned for the requirements of a `cpanfile`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CpanLock {
+    /// The name of the distribution, like `Plack`
+    pub name: String,
+    pub version: String,
+    /// The path below `authors/id/`, like `M/MI/MIYAGAWA/Plack-1.0050.tar.gz`
+    pub path: String,
+    pub url: String,
+    pub sha256: String,
+    pub size: u64,
+    /// The modules that were resolved to this distribution, with their version
+    #[serde(default)]
+    pub modules: BTreeMap<String, String>,
 }
 
+impl CpanLock {
+    /// The tarball is downloaded and verified like `[[file]]`
+    pub fn file(&self) -> FileLock {
+        FileLock {
+            name: self.path.rsplit('/').next().unwrap_or_default().to_string(),
+            url: self.url.clone(),
+            sha256: self.sha256.clone(),
+            size: self.size,
+            signature: None,
+        }
+    }
+}
+
+/// A stackage snapshot, the files are mounted into /extra/haskell/
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HaskellLock {
+    /// The name of the snapshot, like `lts-22.33`
+    pub snapshot: String,
+    /// The snapshot definition for stack and the constraints for cabal
+    #[serde(default, rename = "file", skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<FileLock>,
+}
+
+/// A tlnet snapshot, the files are mounted as local repository into /extra/texlive/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TexliveLock {
+    /// The day of the snapshot, like `2024-06-01`
+    pub date: String,
+    /// The packages of the manifest, the files also contain their dependencies
+    pub packages: Vec<String>,
+    /// The `texlive.tlpdb` and a container for every package
+    #[serde(default, rename = "file", skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<FileLock>,
+}
+
+/// A public key from /etc/apk/keys, used to verify alpine packages
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApkKeyLock {
+    /// The filename, this is what the package signatures refer to
+    pub name: String,
+    /// The PEM encoded RSA public key
+    pub key: String,
+}
+
+/// Records how and when the lockfile was generated. This is opt-in because
+/// the timestamp changes on every update and would otherwise clutter diffs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MetaLock {
+    /// The version of repro-env that resolved this lockfile
+    pub repro_env_version: String,
+    /// The sha256 of the repro-env binary that resolved this lockfile
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repro_env_sha256: Option<String>,
+    /// When the resolution was done (rfc3339)
+    pub resolved_at: String,
+    /// The sha256 of the repro-env.toml that was resolved
+    pub manifest_sha256: String,
+    /// The container image reference before it was pinned
+    pub image: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    /// Versions of the package manager in the resolution container, e.g. `apt = "2.6.1"`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tools: BTreeMap<String, String>,
+    /// When the package indexes used for resolution were generated (rfc3339)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub indexes: BTreeMap<String, String>,
+    /// The repository configuration files in the resolution container, path => content
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sources: BTreeMap<String, String>,
+    /// The lockfile transforms that rewrote the resolved lockfile, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transforms: Vec<String>,
+}
+
+impl MetaLock {
+    #[cfg(feature = "cli")]
+    pub fn new(manifest: &Manifest, manifest_buf: &[u8], provenance: Provenance) -> Result<Self> {
+        let mut hasher = Sha256::new();
+        hasher.update(manifest_buf);
+        let manifest_sha256 = hex::encode(hasher.finalize());
+
+        let resolved_at = OffsetDateTime::now_utc().format(&well_known::Rfc3339)?;
+        let repro_env_sha256 = build_info::current_exe_sha256()
+            .map_err(|err| warn!("Failed to hash repro-env binary: {err:#}"))
+            .ok();
+
+        Ok(MetaLock {
+            repro_env_version: env!("CARGO_PKG_VERSION").to_string(),
+            repro_env_sha256,
+            resolved_at,
+            manifest_sha256,
+            image: manifest.container.image.clone(),
+            system: manifest.packages.as_ref().map(|p| p.system.clone()),
+            tools: provenance.tools,
+            indexes: provenance.indexes,
+            sources: provenance.sources,
+            transforms: vec![],
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PackageLock {
     pub name: String,
     pub version: String,
     pub system: String,
     pub url: String,
+    /// Tried in order if the download from `url` fails, for example a mirror
+    /// of a package that didn't reach the archive yet
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallback_urls: Vec<String>,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub provides: Vec<String>,
+    /// The sha256 of the package file, empty for flatpak runtimes (see `commit`)
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub sha256: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub signature: Option<String>,
+    /// The OSTree commit a flatpak runtime is pinned to, flatpak verifies the
+    /// download against it while installing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    /// Detached signatures over the package file. A single OpenPGP signature
+    /// is written as a plain base64 string, like older versions did.
+    #[serde(
+        default,
+        rename = "signature",
+        with = "signatures",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub signatures: Vec<SignatureLock>,
     /// If true, this package is already present in the container and does not
     /// need to be installed. It's only in the lockfile to make the
     /// repro-env.lock diff easier to read and help git's delta-compression.
     #[serde(default, skip_serializing_if = "is_false")]
     pub installed: bool,
+    /// Other packages in the lockfile that need to be installed before this
+    /// one, this is only recorded for debian (for installing with dpkg)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends: Vec<String>,
+    /// Size of the package file in bytes, used to check for free disk space
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+impl PackageLock {
+    /// The base64 encoded OpenPGP signature of this package, if any
+    pub fn openpgp_signature(&self) -> Option<&str> {
+        self.signatures
+            .iter()
+            .find(|sig| sig.algorithm == SignatureLock::OPENPGP)
+            .map(|sig| sig.value.as_str())
+    }
+
+    /// The package url (purl) of this package, e.g. `pkg:apk/alpine/curl@8.4.0-r0?arch=x86_64`
+    pub fn purl(&self) -> Option<String> {
+        purl::purl(self)
+    }
+
+    /// If the package is a file that's downloaded into the cache and staged in
+    /// /extra. Flatpak runtimes are pulled by flatpak itself while installing,
+    /// they are pinned by commit (in both `version` and `commit`).
+    pub fn has_package_file(&self) -> bool {
+        self.system != "flatpak"
+    }
+
+    /// If the package file has metadata that can be parsed, like its name,
+    /// version and the files it contains. Snaps are squashfs images and conda
+    /// packages are only installed by micromamba, they are only verified by their sha256.
+    pub fn has_embedded_metadata(&self) -> bool {
+        self.has_package_file() && !["conda", "snap"].contains(&self.system.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SignatureLock {
+    /// The signature scheme, e.g. `openpgp` or `sigstore-bundle`
+    pub algorithm: String,
+    /// Fingerprint or identity of the signer, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    /// The signature, base64 encoded
+    pub value: String,
+}
+
+impl SignatureLock {
+    pub const OPENPGP: &'static str = "openpgp";
+    /// Algorithms repro-env knows how to verify, others are kept but ignored
+    pub const SUPPORTED: &'static [&'static str] = &[Self::OPENPGP];
+
+    pub fn openpgp<S: Into<String>>(value: S) -> Self {
+        SignatureLock {
+            algorithm: Self::OPENPGP.to_string(),
+            issuer: None,
+            value: value.into(),
+        }
+    }
+
+    pub fn is_supported(&self) -> bool {
+        Self::SUPPORTED.contains(&self.algorithm.as_str())
+    }
+}
+
+/// Read both the legacy `signature = "<base64>"` and a list of signature tables
+mod signatures {
+    use super::SignatureLock;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Signatures {
+        Legacy(String),
+        List(Vec<SignatureLock>),
+    }
+
+    pub fn serialize<S: Serializer>(sigs: &[SignatureLock], s: S) -> Result<S::Ok, S::Error> {
+        match sigs {
+            [sig] if sig.algorithm == SignatureLock::OPENPGP && sig.issuer.is_none() => {
+                s.serialize_str(&sig.value)
+            }
+            _ => sigs.serialize(s),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<SignatureLock>, D::Error> {
+        Ok(match Signatures::deserialize(d)? {
+            Signatures::Legacy(value) => vec![SignatureLock::openpgp(value)],
+            Signatures::List(list) => list,
+        })
+    }
+}
+
+/// Split a version like `0.4.1` into numbers for comparison, suffixes are ignored
+fn parse_version(version: &str) -> Vec<u64> {
+    version
+        .split(['-', '+'])
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .map(|n| n.parse().unwrap_or(0))
+        .collect()
 }
 
 fn is_false(value: &bool) -> bool {
@@ -66,35 +923,53 @@ mod tests {
     #[test]
     pub fn test_serialize_archlinux() -> Result<()> {
         let lockfile = Lockfile {
+            min_repro_env_version: None,
+            install_order: vec![],
             container: ContainerLock {
                 image:
                     "docker.io/library/archlinux@sha256:6568d3f1f278827a4a7d8537f80c2ae36982829a0c6bccff4cec081774025472"
                         .to_string(),
+                sha256: None,
+                init_sha256: None,
+                init: Init::Catatonit,
+                containerfile: None,
             },
+            meta: None,
+            policy: BTreeMap::new(),
+            keys: vec![],
+            apk_keys: vec![],
             packages: vec![
                 PackageLock {
                     name: "archlinux-keyring".to_string(),
                     version: "20230704-1".to_string(),
                     system: "archlinux".to_string(),
                     url: "https://archive.archlinux.org/packages/a/archlinux-keyring/archlinux-keyring-20230704-1-any.pkg.tar.zst".to_string(),
-                    provides: vec![],
                     sha256: "6a3d2acaa396c4bd72fe3f61a3256d881e3fc2cf326113cf331f168e36dd9a3c".to_string(),
-                    signature: Some(
-"iHUEABYIAB0WIQQEKYl95fO9rFN6MGltQr3RFuAGjwUCZKPPXgAKCRBtQr3RFuAGj9oXAP94RQ1sKD53/RxVYlVEEOjKHvOmrWvDkt1veMYygnlnIgD+MLg/TT6d71kE8F08+JH+EcnG7wQow5Xr/qBo1VPLdgQ=".to_string()),
-                    installed: false,
+                    signatures: vec![SignatureLock::openpgp(
+"iHUEABYIAB0WIQQEKYl95fO9rFN6MGltQr3RFuAGjwUCZKPPXgAKCRBtQr3RFuAGj9oXAP94RQ1sKD53/RxVYlVEEOjKHvOmrWvDkt1veMYygnlnIgD+MLg/TT6d71kE8F08+JH+EcnG7wQow5Xr/qBo1VPLdgQ=".to_string())],
+                    ..Default::default()
                 },
                 PackageLock {
                     name: "binutils".to_string(),
                     version: "2.40-6".to_string(),
                     system: "archlinux".to_string(),
                     url: "https://archive.archlinux.org/packages/b/binutils/binutils-2.40-6-x86_64.pkg.tar.zst".to_string(),
-                    provides: vec![],
                     sha256: "b65fd16001578e10b602e577a8031cbfffc1164caf47ed9ba00c60d804519430".to_string(),
-                    signature: Some(
-"iNUEABYKAH0WIQQFx3danouXdAf+COadTFqhVCbaCgUCZG6Rg18UgAAAAAAuAChpc3N1ZXItZnByQG5vdGF0aW9ucy5vcGVucGdwLmZpZnRoaG9yc2VtYW4ubmV0MDVDNzc3NUE5RThCOTc3NDA3RkUwOEU2OUQ0QzVBQTE1NDI2REEwQQAKCRCdTFqhVCbaCge2AQD/LGBeHRaeO8xh4E/bAYfqd1O/OFqk2DrQBJ73cdKl2gD9EC8p4U/cXQK8V774m6LSS50usH5pxcQWEq/H0SF+FgM=".to_string()),
-                    installed: false,
+                    signatures: vec![SignatureLock::openpgp(
+"iNUEABYKAH0WIQQFx3danouXdAf+COadTFqhVCbaCgUCZG6Rg18UgAAAAAAuAChpc3N1ZXItZnByQG5vdGF0aW9ucy5vcGVucGdwLmZpZnRoaG9yc2VtYW4ubmV0MDVDNzc3NUE5RThCOTc3NDA3RkUwOEU2OUQ0QzVBQTE1NDI2REEwQQAKCRCdTFqhVCbaCge2AQD/LGBeHRaeO8xh4E/bAYfqd1O/OFqk2DrQBJ73cdKl2gD9EC8p4U/cXQK8V774m6LSS50usH5pxcQWEq/H0SF+FgM=".to_string())],
+                    ..Default::default()
                 }
             ],
+            profiles: BTreeMap::new(),
+            files: vec![],
+            git: vec![],
+            toolchains: vec![],
+            maven: vec![],
+            gems: vec![],
+            composer: vec![],
+            cpan: vec![],
+            haskell: None,
+            texlive: None,
         };
 
         let toml = lockfile.serialize()?;
@@ -131,33 +1006,49 @@ signature = "iNUEABYKAH0WIQQFx3danouXdAf+COadTFqhVCbaCgUCZG6Rg18UgAAAAAAuAChpc3N
     #[test]
     pub fn test_serialize_debian() -> Result<()> {
         let lockfile = Lockfile {
+            min_repro_env_version: None,
+            install_order: vec![],
             container: ContainerLock {
                 image:
                     "debian@sha256:3d868b5eb908155f3784317b3dda2941df87bbbbaa4608f84881de66d9bb297b"
                         .to_string(),
+                sha256: None,
+                init_sha256: None,
+                init: Init::Catatonit,
+                containerfile: None,
             },
+            meta: None,
+            policy: BTreeMap::new(),
+            keys: vec![],
+            apk_keys: vec![],
             packages: vec![
                 PackageLock {
                     name: "binutils".to_string(),
                     version: "2.40-2".to_string(),
                     system: "debian".to_string(),
                     url: "https://snapshot.debian.org/archive/debian/20230115T211934Z/pool/main/b/binutils/binutils_2.40-2_amd64.deb".to_string(),
-                    provides: vec![],
                     sha256: "83c3e20b53e1fbd84d764c3ba27d26a0376e361ae5d7fb37120196934dd87424".to_string(),
-                    signature: None,
-                    installed: false,
+                    ..Default::default()
                 },
                 PackageLock {
                     name: "binutils-common".to_string(),
                     version: "2.40-2".to_string(),
                     system: "debian".to_string(),
                     url: "https://snapshot.debian.org/archive/debian/20230115T211934Z/pool/main/b/binutils/binutils-common_2.40-2_amd64.deb".to_string(),
-                    provides: vec![],
                     sha256: "ab314134f43a0891a48f69a9bc33d825da748fa5e0ba2bebb7a5c491b026f1a0".to_string(),
-                    signature: None,
-                    installed: false,
+                    ..Default::default()
                 }
             ],
+            profiles: BTreeMap::new(),
+            files: vec![],
+            git: vec![],
+            toolchains: vec![],
+            maven: vec![],
+            gems: vec![],
+            composer: vec![],
+            cpan: vec![],
+            haskell: None,
+            texlive: None,
         };
 
         let toml = lockfile.serialize()?;
@@ -188,4 +1079,384 @@ sha256 = "ab314134f43a0891a48f69a9bc33d825da748fa5e0ba2bebb7a5c491b026f1a0"
 
         Ok(())
     }
+
+    #[test]
+    pub fn test_serialize_meta() -> Result<()> {
+        let lockfile = Lockfile {
+            min_repro_env_version: None,
+            install_order: vec![],
+            container: ContainerLock {
+                image:
+                    "docker.io/library/rust@sha256:22760a18d52be83a74f5df8b190b8e9baa1e6ce7d9bda40630acc8ba5328a2fd"
+                        .to_string(),
+                sha256: None,
+                init_sha256: None,
+                init: Init::Catatonit,
+                containerfile: None,
+            },
+            meta: Some(MetaLock {
+                repro_env_version: "0.4.1".to_string(),
+                repro_env_sha256: Some(
+                    "5f2b6f1d6a1b5e0c6a1c4e5d1f0c8b0e3a6c1b7d9e2f4a6b8c0d2e4f6a8b0c2d".to_string(),
+                ),
+                resolved_at: "2023-07-20T10:08:19Z".to_string(),
+                manifest_sha256: "9a1b4e1b8b0dee0e8c0fa6a2d6ba4cf3f0deac4d3c8e0bd15ec4be4b11b3d5b1"
+                    .to_string(),
+                image: "docker.io/library/rust:1-alpine3.18".to_string(),
+                system: Some("alpine".to_string()),
+                tools: BTreeMap::from([("apk".to_string(), "2.14.0".to_string())]),
+                indexes: BTreeMap::from([(
+                    "https://dl-cdn.alpinelinux.org/alpine/v3.18/main".to_string(),
+                    "2023-07-19T21:33:52Z".to_string(),
+                )]),
+                sources: BTreeMap::from([(
+                    "/etc/apk/repositories".to_string(),
+                    "https://dl-cdn.alpinelinux.org/alpine/v3.18/main\nhttps://dl-cdn.alpinelinux.org/alpine/v3.18/community\n".to_string(),
+                )]),
+                transforms: vec![],
+            }),
+            policy: BTreeMap::new(),
+            keys: vec![],
+            apk_keys: vec![],
+            packages: vec![],
+            profiles: BTreeMap::new(),
+            files: vec![],
+            git: vec![],
+            toolchains: vec![],
+            maven: vec![],
+            gems: vec![],
+            composer: vec![],
+            cpan: vec![],
+            haskell: None,
+            texlive: None,
+        };
+
+        let toml = lockfile.serialize()?;
+
+        assert_eq!(
+            toml,
+            r#"[container]
+image = "docker.io/library/rust@sha256:22760a18d52be83a74f5df8b190b8e9baa1e6ce7d9bda40630acc8ba5328a2fd"
+
+[meta]
+repro_env_version = "0.4.1"
+repro_env_sha256 = "5f2b6f1d6a1b5e0c6a1c4e5d1f0c8b0e3a6c1b7d9e2f4a6b8c0d2e4f6a8b0c2d"
+resolved_at = "2023-07-20T10:08:19Z"
+manifest_sha256 = "9a1b4e1b8b0dee0e8c0fa6a2d6ba4cf3f0deac4d3c8e0bd15ec4be4b11b3d5b1"
+image = "docker.io/library/rust:1-alpine3.18"
+system = "alpine"
+
+[meta.tools]
+apk = "2.14.0"
+
+[meta.indexes]
+"https://dl-cdn.alpinelinux.org/alpine/v3.18/main" = "2023-07-19T21:33:52Z"
+
+[meta.sources]
+"/etc/apk/repositories" = """
+https://dl-cdn.alpinelinux.org/alpine/v3.18/main
+https://dl-cdn.alpinelinux.org/alpine/v3.18/community
+"""
+"#
+        );
+
+        let deserialized = Lockfile::deserialize(&toml)?;
+        assert_eq!(deserialized, lockfile);
+
+        // a lockfile touched by an editor on windows is written back with LF line endings
+        let windows = format!("\u{feff}{}", toml.replace('\n', "\r\n"));
+        let deserialized = Lockfile::deserialize(&windows)?;
+        assert_eq!(deserialized, lockfile);
+        assert_eq!(deserialized.serialize()?, toml);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_signature_list() -> Result<()> {
+        let toml = r#"min_repro_env_version = "0.4.1"
+
+[container]
+image = "docker.io/library/archlinux@sha256:6568d3f1f278827a4a7d8537f80c2ae36982829a0c6bccff4cec081774025472"
+
+[[package]]
+name = "binutils"
+version = "2.40-6"
+system = "archlinux"
+url = "https://archive.archlinux.org/packages/b/binutils/binutils-2.40-6-x86_64.pkg.tar.zst"
+sha256 = "b65fd16001578e10b602e577a8031cbfffc1164caf47ed9ba00c60d804519430"
+
+[[package.signature]]
+algorithm = "openpgp"
+issuer = "05C7775A9E8B977407FE08E69D4C5AA15426DA0A"
+value = "aWdub3JlZA=="
+
+[[package.signature]]
+algorithm = "sigstore-bundle"
+value = "e30="
+"#;
+        let lockfile = Lockfile::deserialize(toml)?;
+        let pkg = &lockfile.packages[0];
+        assert_eq!(pkg.signatures.len(), 2);
+        assert_eq!(pkg.openpgp_signature(), Some("aWdub3JlZA=="));
+        assert!(pkg.signatures[0].is_supported());
+        assert!(!pkg.signatures[1].is_supported());
+        assert_eq!(lockfile.serialize()?, toml);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_compatible() -> Result<()> {
+        let toml = r#"min_repro_env_version = "0.9.0"
+
+[container]
+image = "docker.io/library/debian@sha256:00"
+"#;
+        let lockfile = toml::from_str::<Lockfile>(toml)?;
+        assert!(lockfile.check_compatible("0.9.0").is_ok());
+        assert!(lockfile.check_compatible("0.10.0").is_ok());
+        let err = lockfile.check_compatible("0.4.1").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Lockfile requires repro-env 0.9.0 or newer, but this is repro-env 0.4.1, please upgrade"
+        );
+        assert!(Lockfile::deserialize(toml).is_err());
+
+        let toml = r#"[container]
+image = "docker.io/library/debian@sha256:00"
+
+[[package]]
+name = "hello"
+version = "1.0"
+system = "nixos"
+url = "https://example.com/hello"
+sha256 = "00"
+"#;
+        let err = Lockfile::deserialize(toml).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Lockfile uses package system \"nixos\" which is not supported"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_packages() -> Result<()> {
+        let commit = "a".repeat(64);
+        // older lockfiles recorded the commit of flatpak runtimes as sha256
+        let toml = format!(
+            r#"[container]
+image = "docker.io/library/debian@sha256:00"
+
+[[package]]
+name = "org.freedesktop.Sdk/x86_64/23.08"
+version = "{commit}"
+system = "flatpak"
+url = "https://dl.flathub.org/repo/flathub.flatpakrepo"
+sha256 = "{commit}"
+"#
+        );
+        let lockfile = Lockfile::deserialize(&toml)?;
+        assert_eq!(lockfile.packages[0].commit.as_ref(), Some(&commit));
+        assert_eq!(lockfile.packages[0].sha256, "");
+        let serialized = lockfile.serialize()?;
+        assert!(serialized.contains(&format!("commit = \"{commit}\"")));
+        assert!(!serialized.contains("sha256 = "));
+        assert_eq!(Lockfile::deserialize(&serialized)?, lockfile);
+
+        let toml = r#"[container]
+image = "docker.io/library/debian@sha256:00"
+
+[[package]]
+name = "hello"
+version = "1.0"
+system = "debian"
+url = "https://example.com/hello.deb"
+"#;
+        let err = Lockfile::deserialize(toml).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Package in lockfile has no sha256: \"hello\""
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("0.4.1"), [0, 4, 1]);
+        assert_eq!(parse_version("1.0.0-rc.1"), [1, 0, 0]);
+        assert!(parse_version("0.10.0") > parse_version("0.9.3"));
+    }
+
+    #[test]
+    fn test_profiles() -> Result<()> {
+        let buf = r#"[container]
+image = "docker.io/library/debian@sha256:3d868b5eb908155f3784317b3dda2941df87bbbbaa4608f84881de66d9bb297b"
+
+[[package]]
+name = "binutils"
+version = "2.40-2"
+system = "debian"
+url = "https://snapshot.debian.org/archive/debian/20230115T211934Z/pool/main/b/binutils/binutils_2.40-2_amd64.deb"
+sha256 = "83c3e20b53e1fbd84d764c3ba27d26a0376e361ae5d7fb37120196934dd87424"
+
+[[profile.debug.package]]
+name = "binutils-dbgsym"
+version = "2.40-2"
+system = "debian"
+url = "https://snapshot.debian.org/archive/debian-debug/20230115T211934Z/pool/main/b/binutils/binutils-dbgsym_2.40-2_amd64.deb"
+sha256 = "0e6fa1a4b5c7d2ab0e09e9d4e5d1b6d4e6c4b4d2a3e9d08f0f2b6d8e7c1a9b30"
+"#;
+        let lockfile = Lockfile::deserialize(buf)?;
+        assert_eq!(lockfile.serialize()?, buf);
+        assert_eq!(lockfile.all_packages().count(), 2);
+
+        let mut base = lockfile.clone();
+        base.select_profile(None)?;
+        assert_eq!(base.packages.len(), 1);
+        assert!(base.profiles.is_empty());
+
+        let mut debug = lockfile.clone();
+        debug.select_profile(Some("debug"))?;
+        assert_eq!(
+            debug.packages.iter().map(|p| &p.name).collect::<Vec<_>>(),
+            ["binutils", "binutils-dbgsym"]
+        );
+        assert_ne!(debug.digest(), base.digest());
+
+        let err = lockfile
+            .clone()
+            .select_profile(Some("release"))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown profile \"release\", available: debug"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_digest_stable() -> Result<()> {
+        let pkg = |name: &str, sha256: &str| PackageLock {
+            name: name.to_string(),
+            version: "1.0-1".to_string(),
+            system: "archlinux".to_string(),
+            url: format!("https://example.com/{name}.pkg.tar.zst"),
+            sha256: sha256.to_string(),
+            ..Default::default()
+        };
+        let mut lockfile = Lockfile {
+            min_repro_env_version: None,
+            install_order: vec![],
+            container: ContainerLock {
+                image: "docker.io/library/archlinux@sha256:6568d3f1f278827a4a7d8537f80c2ae36982829a0c6bccff4cec081774025472".to_string(),
+                sha256: None,
+                init_sha256: None,
+                init: Init::Catatonit,
+                containerfile: None,
+            },
+            meta: None,
+            policy: BTreeMap::new(),
+            keys: vec![],
+            apk_keys: vec![],
+            packages: vec![pkg("a", "aa"), pkg("b", "bb")],
+            profiles: BTreeMap::new(),
+            files: vec![],
+            git: vec![],
+            toolchains: vec![],
+            maven: vec![],
+            gems: vec![],
+            composer: vec![],
+            cpan: vec![],
+            haskell: None,
+            texlive: None,
+        };
+        let digest = lockfile.digest();
+        assert!(digest.starts_with("sha256:"));
+
+        // order, urls and metadata don't matter
+        lockfile.packages.reverse();
+        lockfile.packages[0].url = "https://mirror.example.com/b.pkg.tar.zst".to_string();
+        lockfile.meta = Some(MetaLock {
+            repro_env_version: "0.4.1".to_string(),
+            repro_env_sha256: None,
+            resolved_at: "2023-07-20T10:08:19Z".to_string(),
+            manifest_sha256: "00".to_string(),
+            image: "archlinux".to_string(),
+            system: None,
+            tools: BTreeMap::new(),
+            indexes: BTreeMap::new(),
+            sources: BTreeMap::new(),
+            transforms: vec![],
+        });
+        assert_eq!(lockfile.digest(), digest);
+
+        // the package content does
+        lockfile.packages[0].sha256 = "cc".to_string();
+        assert_ne!(lockfile.digest(), digest);
+
+        // and so does a pinned init
+        let digest = lockfile.digest();
+        lockfile.container.init_sha256 = Some("dd".to_string());
+        assert_ne!(lockfile.digest(), digest);
+        assert!(lockfile.serialize()?.contains("init_sha256 = \"dd\"\n"));
+        assert_eq!(lockfile.required_version(), Some(VERSION_CHECK_SINCE));
+
+        // and an image built from a containerfile
+        let digest = lockfile.digest();
+        lockfile.container.containerfile = Some(ContainerfileLock {
+            path: "ci/Containerfile".to_string(),
+            sha256: "ff".to_string(),
+            image_id: "7a1c9e2b".to_string(),
+        });
+        assert_ne!(lockfile.digest(), digest);
+        assert!(lockfile
+            .serialize()?
+            .contains("[container.containerfile]\npath = \"ci/Containerfile\"\n"));
+
+        // and the mode of the init
+        let digest = lockfile.digest();
+        lockfile.container.init = Init::Podman;
+        assert_ne!(lockfile.digest(), digest);
+        assert!(lockfile.serialize()?.contains("init = \"podman\"\n"));
+
+        // and pinned files
+        let digest = lockfile.digest();
+        lockfile.files.push(FileLock {
+            name: "firmware.bin".to_string(),
+            url: "https://example.com/firmware.bin".to_string(),
+            sha256: "ee".to_string(),
+            size: 1024,
+            signature: None,
+        });
+        assert_ne!(lockfile.digest(), digest);
+        assert!(lockfile
+            .serialize()?
+            .contains("[[file]]\nname = \"firmware.bin\"\n"));
+
+        // and the install order, including the order of its entries
+        let digest = lockfile.digest();
+        lockfile.install_order = vec!["a".to_string(), "b".to_string()];
+        let ordered = lockfile.digest();
+        assert_ne!(ordered, digest);
+        lockfile.install_order.reverse();
+        assert_ne!(lockfile.digest(), ordered);
+        assert_ne!(lockfile.digest(), digest);
+
+        // and the packages of a profile
+        let digest = lockfile.digest();
+        let mut profile = ProfileLock {
+            packages: vec![pkg("gdb", "gg"), pkg("valgrind", "vv")],
+        };
+        lockfile
+            .profiles
+            .insert("debug".to_string(), profile.clone());
+        let with_profile = lockfile.digest();
+        assert_ne!(with_profile, digest);
+        profile.packages.reverse();
+        lockfile.profiles.insert("debug".to_string(), profile);
+        assert_eq!(lockfile.digest(), with_profile);
+        lockfile.profiles.get_mut("debug").unwrap().packages[0].version = "2.0-1".to_string();
+        assert_ne!(lockfile.digest(), with_profile);
+        Ok(())
+    }
 }
@@ -1,87 +1,2431 @@
 use crate::errors::*;
-use crate::lockfile::Lockfile;
-use indexmap::IndexSet;
+use crate::lockfile::{Lockfile, PackageLock};
+use crate::version;
+use indexmap::{IndexMap, IndexSet};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use std::path::Path;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+#[cfg(feature = "cli")]
 use tokio::fs;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Manifest {
     pub container: ContainerManifest,
     pub packages: Option<PackagesManifest>,
+    #[serde(default)]
+    pub lockfile: LockfileManifest,
+    #[serde(default)]
+    pub build: BuildManifest,
+    /// Variants of the environment, each one is resolved into its own lockfile
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub matrix: IndexMap<String, VariantManifest>,
+    /// Extra packages on top of [packages], resolved into the same lockfile
+    #[serde(
+        default,
+        rename = "profile",
+        skip_serializing_if = "IndexMap::is_empty"
+    )]
+    pub profiles: IndexMap<String, ProfileManifest>,
+    /// Files that aren't part of any package, pinned by sha256 and exposed to the build in /extra/files/
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<FileManifest>,
+    /// Git repositories, pinned to a commit and exposed to the build in /extra/git/
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub git: Vec<GitManifest>,
+    #[serde(default, skip_serializing_if = "ToolchainManifest::is_empty")]
+    pub toolchain: ToolchainManifest,
+    /// A JDK and the maven dependencies of the project
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub java: Option<JavaManifest>,
+    /// The gems of a `Gemfile.lock`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ruby: Option<RubyManifest>,
+    /// The packages of a `composer.lock`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub php: Option<PhpManifest>,
+    /// The requirements of a `cpanfile`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub perl: Option<PerlManifest>,
+    /// A GHC bindist and a stackage snapshot
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub haskell: Option<HaskellManifest>,
+    /// A tlnet snapshot and the TeX Live packages to pin from it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub texlive: Option<TexliveManifest>,
+}
+
+/// The default name of the manifest
+pub const FILENAME: &str = "repro-env.toml";
+/// Small rust projects can declare the manifest in `[package.metadata.repro-env]` instead
+pub const CARGO_FILENAME: &str = "Cargo.toml";
+/// Locked with `faketime = true`, the package has this name on all supported systems
+pub const FAKETIME_PACKAGE: &str = "libfaketime";
+/// The JDK of `[java]` is pinned as toolchain that's mounted here
+pub const JDK_PATH: &str = "/opt/jdk";
+/// The GHC bindist of `[haskell]` is pinned as toolchain that's mounted here
+pub const GHC_PATH: &str = "/opt/ghc";
+
+/// Editors on Windows may write a byte order mark and CRLF line endings, the
+/// file is parsed as if it had been written with LF line endings instead
+pub fn normalize_text(buf: &str) -> Cow<'_, str> {
+    let buf = buf.strip_prefix('\u{feff}').unwrap_or(buf);
+    if buf.contains("\r\n") {
+        Cow::Owned(buf.replace("\r\n", "\n"))
+    } else {
+        Cow::Borrowed(buf)
+    }
 }
 
 impl Manifest {
     pub fn deserialize(buf: &str) -> Result<Self> {
-        let manifest = toml::from_str(buf).context("Failed to load manifest from toml")?;
+        let manifest = toml::from_str::<Self>(&normalize_text(buf))
+            .context("Failed to load manifest from toml")?;
+        manifest.validate()
+    }
+
+    /// Read the manifest from the `[package.metadata.repro-env]` section of a Cargo.toml
+    pub fn deserialize_cargo(buf: &str) -> Result<Self> {
+        let cargo = toml::from_str::<toml::Table>(&normalize_text(buf))
+            .context("Failed to parse Cargo.toml")?;
+        let section = cargo_metadata(&cargo)
+            .context("Cargo.toml has no [package.metadata.repro-env] section")?;
+        let manifest = section
+            .clone()
+            .try_into::<Self>()
+            .context("Failed to load manifest from [package.metadata.repro-env]")?;
+        manifest.validate()
+    }
+
+    fn validate(self) -> Result<Self> {
+        let manifest = self;
+        validate_podman_args(&manifest.build.podman_args)
+            .context("Invalid podman_args in [build]")?;
+        if let Some(environment) = &manifest.build.environment {
+            environment
+                .validate()
+                .context("Invalid [build.environment]")?;
+        }
+        let mut transforms = HashSet::new();
+        for transform in &manifest.lockfile.transforms {
+            if !transforms.insert(&transform.name) {
+                bail!(
+                    "Lockfile transform name is used multiple times: {:?}",
+                    transform.name
+                );
+            }
+            if transform.cmd.is_empty() {
+                bail!(
+                    "Command of lockfile transform {:?} is empty",
+                    transform.name
+                );
+            }
+        }
+        let mut steps = HashSet::new();
+        for step in &manifest.build.steps {
+            if !steps.insert(&step.name) {
+                bail!("Build step name is used multiple times: {:?}", step.name);
+            }
+            if step.cmd.is_empty() {
+                bail!("Command of build step {:?} is empty", step.name);
+            }
+            // only earlier steps can be referenced, so there can't be a cycle
+            for need in &step.needs {
+                if need == &step.name || !steps.contains(need) {
+                    bail!(
+                        "Build step {:?} needs {need:?}, which is not defined before it",
+                        step.name
+                    );
+                }
+            }
+            if step.inputs.is_empty() != step.outputs.is_empty() {
+                bail!(
+                    "Build step {:?} needs both inputs and outputs to be cached",
+                    step.name
+                );
+            }
+            for output in &step.outputs {
+                let path = Path::new(output);
+                if !path
+                    .components()
+                    .all(|c| matches!(c, std::path::Component::Normal(_)))
+                {
+                    bail!(
+                        "Output of build step {:?} needs to be a relative path inside the build directory: {output:?}",
+                        step.name
+                    );
+                }
+            }
+        }
+        if let Some(sign) = &manifest.build.sign {
+            if sign.key.is_none() && sign.method != SignMethod::Cosign {
+                bail!(
+                    "Signing with {} needs a key in [build.sign]",
+                    sign.method.as_str()
+                );
+            }
+            if sign.artifacts.is_empty() {
+                bail!("No artifacts to sign are declared in [build.sign]");
+            }
+        }
+        for name in manifest.matrix.keys() {
+            if !valid_variant_name(name) {
+                bail!("Invalid name for matrix variant, only letters, digits, `-` and `_` are allowed: {name:?}");
+            }
+        }
+        for (name, profile) in &manifest.profiles {
+            if !valid_variant_name(name) {
+                bail!("Invalid name for profile, only letters, digits, `-` and `_` are allowed: {name:?}");
+            }
+            if manifest.packages.is_none() {
+                bail!("Profile {name:?} needs a [packages] section to add packages to");
+            }
+            if profile.dependencies.is_empty() {
+                bail!("Profile {name:?} has no dependencies");
+            }
+        }
+        let mut files = HashSet::new();
+        for file in &manifest.files {
+            if !file.url.starts_with("https://") && !file.url.starts_with("http://") {
+                bail!(
+                    "Url of [[files]] entry needs to be http or https: {:?}",
+                    file.url
+                );
+            }
+            let name = file.name().with_context(|| {
+                anyhow!(
+                    "Failed to determine filename of url, set a name for it in [[files]]: {:?}",
+                    file.url
+                )
+            })?;
+            if !valid_file_name(name) {
+                bail!("Invalid filename in [[files]], it can't contain `/`: {name:?}");
+            }
+            if !files.insert(name) {
+                bail!("Filename is used multiple times in [[files]]: {name:?}");
+            }
+            if let Some(sha256) = &file.sha256 {
+                if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                    bail!("Invalid sha256 for {name:?} in [[files]]: {sha256:?}");
+                }
+            }
+            match (&file.signature, file.keys.is_empty()) {
+                (Some(url), false) => {
+                    if !url.starts_with("https://") && !url.starts_with("http://") {
+                        bail!("Url of signature for {name:?} needs to be http or https: {url:?}");
+                    }
+                }
+                (Some(_), true) => bail!("The signature of {name:?} in [[files]] needs keys"),
+                (None, false) => bail!("The keys of {name:?} in [[files]] need a signature"),
+                (None, true) if manifest.lockfile.signed_files => {
+                    bail!("Unsigned file in [[files]], but [lockfile] requires signed files: {name:?}")
+                }
+                (None, true) => (),
+            }
+            if let Some(path) = file.keys.iter().find(|path| !valid_project_path(path)) {
+                bail!(
+                    "The keys of [[files]] need to be relative paths inside the project: {path:?}"
+                );
+            }
+        }
+        let mut toolchains = HashSet::new();
+        for toolchain in &manifest.toolchain.external {
+            if !toolchain.url.starts_with("https://") && !toolchain.url.starts_with("http://") {
+                bail!(
+                    "Url of [[toolchain.external]] entry needs to be http or https: {:?}",
+                    toolchain.url
+                );
+            }
+            if toolchain.name().is_none() {
+                bail!(
+                    "Failed to determine filename of url in [[toolchain.external]]: {:?}",
+                    toolchain.url
+                );
+            }
+            if !valid_toolchain_path(&toolchain.path) {
+                bail!(
+                    "Invalid path in [[toolchain.external]], it needs to be absolute and can't be in /build or /extra: {:?}",
+                    toolchain.path
+                );
+            }
+            if !toolchains.insert(&toolchain.path) {
+                bail!(
+                    "Path is used multiple times in [[toolchain.external]]: {:?}",
+                    toolchain.path
+                );
+            }
+            if let Some(sha256) = &toolchain.sha256 {
+                if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                    bail!(
+                        "Invalid sha256 for {:?} in [[toolchain.external]]: {sha256:?}",
+                        toolchain.path
+                    );
+                }
+            }
+        }
+        if let Some(java) = &manifest.java {
+            if let Some(jdk) = &java.jdk {
+                if manifest
+                    .toolchain
+                    .external
+                    .iter()
+                    .any(|t| t.path == JDK_PATH)
+                {
+                    bail!("The jdk of [java] is mounted at {JDK_PATH:?}, it can't be used for [[toolchain.external]]");
+                }
+                if jdk.is_empty() || !jdk.chars().all(|c| c.is_ascii_digit()) {
+                    bail!("The jdk in [java] needs to be a feature release, like \"21\": {jdk:?}");
+                }
+            }
+            if let Some(maven) = &java.maven {
+                if maven.goals.is_empty() {
+                    bail!("[java.maven] needs at least one goal");
+                }
+                for (id, url) in &maven.repositories {
+                    if !url.starts_with("https://") && !url.starts_with("http://") {
+                        bail!("Url of maven repository {id:?} needs to be http or https: {url:?}");
+                    }
+                }
+            }
+        }
+        if let Some(containerfile) = &manifest.container.containerfile {
+            if !valid_project_path(containerfile) {
+                bail!(
+                    "The containerfile of [container] needs to be a relative path inside the project: {containerfile:?}"
+                );
+            }
+        }
+        if let Some(ruby) = &manifest.ruby {
+            if !valid_project_path(&ruby.gemfile_lock) {
+                bail!(
+                    "The gemfile_lock of [ruby] needs to be a relative path inside the project: {:?}",
+                    ruby.gemfile_lock
+                );
+            }
+        }
+        if let Some(php) = &manifest.php {
+            if !valid_project_path(&php.composer_lock) {
+                bail!(
+                    "The composer_lock of [php] needs to be a relative path inside the project: {:?}",
+                    php.composer_lock
+                );
+            }
+        }
+        if let Some(perl) = &manifest.perl {
+            if !valid_project_path(&perl.cpanfile) {
+                bail!(
+                    "The cpanfile of [perl] needs to be a relative path inside the project: {:?}",
+                    perl.cpanfile
+                );
+            }
+        }
+        if let Some(haskell) = &manifest.haskell {
+            if let Some(ghc) = &haskell.ghc {
+                if manifest
+                    .toolchain
+                    .external
+                    .iter()
+                    .any(|t| t.path == GHC_PATH)
+                {
+                    bail!("The ghc of [haskell] is mounted at {GHC_PATH:?}, it can't be used for [[toolchain.external]]");
+                }
+                if !valid_version_str(ghc) {
+                    bail!("The ghc in [haskell] needs to be a version, like \"9.6.6\": {ghc:?}");
+                }
+            }
+            if !haskell.ghc_platform.is_empty()
+                && !haskell
+                    .ghc_platform
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+            {
+                bail!(
+                    "Invalid ghc_platform in [haskell]: {:?}",
+                    haskell.ghc_platform
+                );
+            }
+            if let Some(snapshot) = &haskell.snapshot {
+                if !valid_stackage_snapshot(snapshot) {
+                    bail!("The snapshot in [haskell] needs to be like \"lts-22.33\" or \"nightly-2024-09-01\": {snapshot:?}");
+                }
+            }
+        }
+        if let Some(texlive) = &manifest.texlive {
+            if !valid_date(&texlive.date) {
+                bail!(
+                    "The date in [texlive] needs to be like \"2024-06-01\": {:?}",
+                    texlive.date
+                );
+            }
+            if texlive.packages.is_empty() {
+                bail!("The [texlive] section has no packages");
+            }
+            if !valid_file_name(&texlive.platform) {
+                bail!("Invalid platform in [texlive]: {:?}", texlive.platform);
+            }
+        }
+        let mut checkouts = HashSet::new();
+        for git in &manifest.git {
+            if git.url.is_empty() || git.url.starts_with('-') {
+                bail!("Invalid url of [[git]] entry: {:?}", git.url);
+            }
+            if git.rev.is_empty() || git.rev.starts_with('-') {
+                bail!(
+                    "Invalid rev of [[git]] entry for {:?}: {:?}",
+                    git.url,
+                    git.rev
+                );
+            }
+            let name = git.name().with_context(|| {
+                anyhow!(
+                    "Failed to determine name of repository, set a name for it in [[git]]: {:?}",
+                    git.url
+                )
+            })?;
+            if !valid_file_name(name) {
+                bail!("Invalid name in [[git]], it can't contain `/`: {name:?}");
+            }
+            if !checkouts.insert(name) {
+                bail!("Name is used multiple times in [[git]]: {name:?}");
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// The manifest with the overrides of a matrix variant applied
+    pub fn variant(&self, name: &str) -> Result<Manifest> {
+        let Some(variant) = self.matrix.get(name) else {
+            let known = self.matrix.keys().map(String::as_str).collect::<Vec<_>>();
+            if known.is_empty() {
+                bail!("Manifest has no [matrix], unknown variant: {name:?}");
+            }
+            bail!(
+                "Unknown matrix variant {name:?}, available: {}",
+                known.join(", ")
+            );
+        };
+
+        let mut manifest = self.clone();
+        manifest.matrix.clear();
+        if let Some(image) = &variant.image {
+            manifest.container.image = image.clone();
+        }
+        if let Some(dependencies) = &variant.dependencies {
+            let packages = manifest.packages.as_mut().with_context(|| {
+                anyhow!(
+                    "Matrix variant {name:?} sets dependencies, but the manifest has no [packages]"
+                )
+            })?;
+            packages.dependencies = dependencies.clone();
+        }
         Ok(manifest)
     }
 
+    #[cfg(feature = "cli")]
     pub async fn read_from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
         let path = path.as_ref();
         let buf = fs::read_to_string(&path)
             .await
             .with_context(|| anyhow!("Failed to read dependency manifest: {path:?}"))?;
-        let manifest = Self::deserialize(&buf)?;
+        let manifest = if path.file_name() == Some(CARGO_FILENAME.as_ref()) {
+            Self::deserialize_cargo(&buf)?
+        } else {
+            Self::deserialize(&buf)?
+        };
         debug!("Loaded manifest from file: {manifest:?}");
         Ok(manifest)
     }
 
     pub fn satisfied_by(&self, lockfile: &Lockfile) -> Result<()> {
         if let Some(packages) = &self.packages {
-            let mut provided = HashSet::new();
-            for package in &lockfile.packages {
-                provided.insert(package.name.clone());
-                provided.extend(package.provides.iter().cloned());
+            let mut dependencies = packages.all_dependencies();
+            // conda match specs have their own syntax, only the names are checked
+            if packages.system == "conda" {
+                dependencies = dependencies
+                    .iter()
+                    .map(|spec| conda_spec_name(spec).to_string())
+                    .collect();
             }
-
-            for dependency in &packages.dependencies {
-                let (name, _) = dependency.split_once('=').unwrap_or((dependency, ""));
-                if !provided.contains(name) {
-                    bail!("Lockfile does not satisify dependency: {dependency:?}");
+            satisfies(&dependencies, &lockfile.packages)?;
+        }
+        for (name, profile) in &self.profiles {
+            let locked = lockfile
+                .profiles
+                .get(name)
+                .with_context(|| anyhow!("Lockfile is missing profile: {name:?}"))?;
+            let packages = lockfile
+                .packages
+                .iter()
+                .chain(&locked.packages)
+                .cloned()
+                .collect::<Vec<_>>();
+            satisfies(&profile.dependencies, &packages)
+                .with_context(|| anyhow!("Profile {name:?} is out-of-date"))?;
+        }
+        for file in &self.files {
+            let name = file.name().unwrap_or_default();
+            let pinned = lockfile.files.iter().any(|pin| {
+                pin.name == name
+                    && pin.url == file.url
+                    && file
+                        .sha256
+                        .as_ref()
+                        .is_none_or(|sha256| *sha256 == pin.sha256)
+                    && file.signature.as_ref()
+                        == pin.signature.as_ref().map(|signature| &signature.url)
+            });
+            if !pinned {
+                bail!("Lockfile does not pin file: {name:?} from {:?}", file.url);
+            }
+        }
+        for pin in &lockfile.files {
+            if !self.files.iter().any(|f| f.name() == Some(&pin.name)) {
+                bail!(
+                    "Lockfile pins file that's not in the manifest: {:?}",
+                    pin.name
+                );
+            }
+        }
+        for git in &self.git {
+            let name = git.name().unwrap_or_default();
+            let pinned = lockfile
+                .git
+                .iter()
+                .any(|pin| pin.name == name && pin.url == git.url && pin.rev == git.rev);
+            if !pinned {
+                bail!(
+                    "Lockfile does not pin git repository: {name:?} from {:?} at {:?}",
+                    git.url,
+                    git.rev
+                );
+            }
+        }
+        for pin in &lockfile.git {
+            if !self.git.iter().any(|g| g.name() == Some(&pin.name)) {
+                bail!(
+                    "Lockfile pins git repository that's not in the manifest: {:?}",
+                    pin.name
+                );
+            }
+        }
+        for toolchain in &self.toolchain.external {
+            let pinned = lockfile.toolchains.iter().any(|pin| {
+                pin.path == toolchain.path
+                    && pin.url == toolchain.url
+                    && pin.strip_components == toolchain.strip_components
+                    && toolchain
+                        .sha256
+                        .as_ref()
+                        .is_none_or(|sha256| *sha256 == pin.sha256)
+            });
+            if !pinned {
+                bail!(
+                    "Lockfile does not pin toolchain for {:?} from {:?}",
+                    toolchain.path,
+                    toolchain.url
+                );
+            }
+        }
+        let jdk = self.java.as_ref().and_then(|java| java.jdk.as_ref());
+        if jdk.is_some() && !lockfile.toolchains.iter().any(|pin| pin.path == JDK_PATH) {
+            bail!("Lockfile does not pin a jdk");
+        }
+        let maven = self.java.as_ref().is_some_and(|java| java.maven.is_some());
+        if maven && lockfile.maven.is_empty() {
+            bail!("Lockfile does not pin maven dependencies");
+        }
+        if !maven && !lockfile.maven.is_empty() {
+            bail!("Lockfile pins maven dependencies, but the manifest has no [java.maven]");
+        }
+        if self.ruby.is_none() && !lockfile.gems.is_empty() {
+            bail!("Lockfile pins gems, but the manifest has no [ruby]");
+        }
+        if self.php.is_none() && !lockfile.composer.is_empty() {
+            bail!("Lockfile pins composer packages, but the manifest has no [php]");
+        }
+        if self.perl.is_none() && !lockfile.cpan.is_empty() {
+            bail!("Lockfile pins perl distributions, but the manifest has no [perl]");
+        }
+        let ghc = self.haskell.as_ref().and_then(|haskell| {
+            let ghc = haskell.ghc.as_ref()?;
+            Some(format!("ghc-{ghc}-{}.tar.xz", haskell.ghc_platform))
+        });
+        if let Some(ghc) = &ghc {
+            if !lockfile
+                .toolchains
+                .iter()
+                .any(|pin| pin.path == GHC_PATH && pin.name == *ghc)
+            {
+                bail!("Lockfile does not pin ghc: {ghc:?}");
+            }
+        }
+        let snapshot = self.haskell.as_ref().and_then(|h| h.snapshot.as_ref());
+        let pinned = lockfile.haskell.as_ref().map(|h| &h.snapshot);
+        if snapshot != pinned {
+            bail!(
+                "Lockfile pins stackage snapshot {pinned:?}, but the manifest wants {snapshot:?}"
+            );
+        }
+        match (&self.texlive, &lockfile.texlive) {
+            (Some(texlive), Some(pinned)) => {
+                if texlive.date != pinned.date {
+                    bail!(
+                        "Lockfile pins tlnet of {:?}, but the manifest wants {:?}",
+                        pinned.date,
+                        texlive.date
+                    );
                 }
+                if texlive.packages != pinned.packages {
+                    bail!("Lockfile pins different TeX Live packages than the manifest wants");
+                }
+            }
+            (Some(_), None) => bail!("Lockfile does not pin the packages of [texlive]"),
+            (None, Some(_)) => {
+                bail!("Lockfile pins TeX Live packages, but the manifest has no [texlive]")
+            }
+            (None, None) => (),
+        }
+        for pin in &lockfile.toolchains {
+            if jdk.is_some() && pin.path == JDK_PATH {
+                continue;
+            }
+            if ghc.is_some() && pin.path == GHC_PATH {
+                continue;
+            }
+            if !self.toolchain.external.iter().any(|t| t.path == pin.path) {
+                bail!(
+                    "Lockfile pins toolchain that's not in the manifest: {:?}",
+                    pin.path
+                );
             }
         }
-
         Ok(())
     }
 }
 
+/// Filenames in /extra/files/ can't escape the directory
+pub fn valid_file_name(name: &str) -> bool {
+    !matches!(name, "" | "." | "..") && !name.contains(['/', '\0'])
+}
+
+/// The package name of a conda match spec, like `numpy` of `numpy>=1.26` or `python 3.12.*`
+pub fn conda_spec_name(spec: &str) -> &str {
+    let spec = spec.trim();
+    let end = spec
+        .find([' ', '=', '<', '>', '!', '~', '['])
+        .unwrap_or(spec.len());
+    // a channel can be given as prefix, like `conda-forge::numpy`
+    let name = &spec[..end];
+    name.rsplit_once("::").map_or(name, |(_, name)| name)
+}
+
+/// Fail if a dependency isn't satisfied by any of the packages
+fn satisfies(dependencies: &IndexSet<String>, packages: &[PackageLock]) -> Result<()> {
+    for dependency in dependencies {
+        let (name, constraint) = version::split_constraint(dependency)?;
+        let satisfied = packages.iter().any(|package| {
+            if package.name == name {
+                constraint.is_none_or(|(relation, expected)| {
+                    relation.matches(version::compare(
+                        &package.system,
+                        &package.version,
+                        expected,
+                    ))
+                })
+            } else {
+                // provides are recorded without version
+                constraint.is_none() && package.provides.iter().any(|p| p == name)
+            }
+        });
+        if !satisfied {
+            bail!("Lockfile does not satisify dependency: {dependency:?}");
+        }
+    }
+    Ok(())
+}
+
+/// `[package.metadata.repro-env]`, or `[workspace.metadata.repro-env]` in the root of a workspace
+fn cargo_metadata(cargo: &toml::Table) -> Option<&toml::Value> {
+    ["package", "workspace"]
+        .iter()
+        .find_map(|key| cargo.get(*key)?.get("metadata")?.get("repro-env"))
+}
+
+/// Use the Cargo.toml next to `path` if the default manifest doesn't exist, but Cargo.toml declares one
+#[cfg(feature = "cli")]
+pub fn locate(path: &Path) -> PathBuf {
+    if path.exists() || path.file_name() != Some(FILENAME.as_ref()) {
+        return path.to_path_buf();
+    }
+    let cargo = path.with_file_name(CARGO_FILENAME);
+    let declared = std::fs::read_to_string(&cargo)
+        .ok()
+        .and_then(|buf| toml::from_str::<toml::Table>(&normalize_text(&buf)).ok())
+        .is_some_and(|cargo| cargo_metadata(&cargo).is_some());
+    if declared {
+        debug!("Using manifest from [package.metadata.repro-env] in {cargo:?}");
+        cargo
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// The directory that paths in the manifest are relative to, `.` if the
+/// manifest path has no parent (e.g. just `repro-env.toml`)
+pub fn manifest_dir(manifest_path: &Path) -> &Path {
+    match manifest_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ContainerManifest {
     pub image: String,
+    /// Record the sha256 of the init that's mounted from the host (catatonit)
+    #[serde(default)]
+    pub pin_init: bool,
+    /// What runs as pid 1 of the containers
+    #[serde(default, skip_serializing_if = "Init::is_default")]
+    pub init: Init,
+    /// Build the image from this containerfile with `podman build`, `image` is used as base.
+    /// The path is relative to the manifest, its directory is used as build context.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub containerfile: Option<String>,
 }
 
+/// A `[[files]]` entry, e.g. a source tarball or firmware blob the build needs
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub struct PackagesManifest {
-    pub system: String,
+#[serde(deny_unknown_fields)]
+pub struct FileManifest {
+    pub url: String,
+    /// The filename in /extra/files/, the last path segment of the url by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// If set, the download needs to match this checksum
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// The url of a detached OpenPGP signature of the file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    /// OpenPGP certificates that are trusted to sign the file, relative to the manifest
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keys: Vec<String>,
+}
+
+impl FileManifest {
+    pub fn name(&self) -> Option<&str> {
+        if let Some(name) = &self.name {
+            return Some(name);
+        }
+        url_filename(&self.url)
+    }
+}
+
+/// The last path segment of a url, without query or fragment
+fn url_filename(url: &str) -> Option<&str> {
+    let (_, rest) = url.split_once("://")?;
+    let rest = rest.split(['?', '#']).next()?;
+    let (_, path) = rest.split_once('/')?;
+    path.rsplit('/').next().filter(|name| !name.is_empty())
+}
+
+/// A `[[git]]` entry, an external source that's checked out at a fixed commit
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GitManifest {
+    pub url: String,
+    /// A branch, tag or commit, resolved to a commit by `repro-env update`
+    pub rev: String,
+    /// The directory in /extra/git/, the name of the repository by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl GitManifest {
+    pub fn name(&self) -> Option<&str> {
+        if let Some(name) = &self.name {
+            return Some(name);
+        }
+        let name = self.url.trim_end_matches('/').rsplit(['/', ':']).next()?;
+        let name = name.strip_suffix(".git").unwrap_or(name);
+        Some(name).filter(|name| !name.is_empty())
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ToolchainManifest {
+    /// Prebuilt cross-toolchains, like the ones of Bootlin or ARM GNU
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub external: Vec<ExternalToolchainManifest>,
+}
+
+impl ToolchainManifest {
+    pub fn is_empty(&self) -> bool {
+        self.external.is_empty()
+    }
+}
+
+/// A `[[toolchain.external]]` entry, a tarball that's extracted to `path` in the container
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExternalToolchainManifest {
+    pub url: String,
+    /// If set, the download needs to match this checksum
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// The absolute path the toolchain is mounted at (read-only)
+    pub path: String,
+    /// Remove this many leading directories, like `tar --strip-components`
+    #[serde(default)]
+    pub strip_components: u32,
+}
+
+impl ExternalToolchainManifest {
+    /// The filename of the tarball
+    pub fn name(&self) -> Option<&str> {
+        url_filename(&self.url)
+    }
+}
+
+/// The `[java]` section
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JavaManifest {
+    /// The feature release of Temurin, like `21`, pinned to its latest GA build and mounted at /opt/jdk
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jdk: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maven: Option<MavenManifest>,
+}
+
+/// The `[java.maven]` section, the dependencies are resolved by running maven in the image
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MavenManifest {
+    /// The goals that download everything the build needs
+    #[serde(default = "default_maven_goals")]
+    pub goals: Vec<String>,
+    /// Repository id => url, for the ids maven records in `_remote.repositories`
+    #[serde(default = "default_maven_repositories")]
+    pub repositories: BTreeMap<String, String>,
+}
+
+pub const MAVEN_CENTRAL: &str = "https://repo.maven.apache.org/maven2";
+
+fn default_maven_goals() -> Vec<String> {
+    vec!["dependency:go-offline".to_string()]
+}
+
+fn default_maven_repositories() -> BTreeMap<String, String> {
+    BTreeMap::from([("central".to_string(), MAVEN_CENTRAL.to_string())])
+}
+
+/// The `[ruby]` section, the gems are read from `Gemfile.lock` during `update`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RubyManifest {
+    /// Relative to the directory of the manifest
+    #[serde(default = "default_gemfile_lock")]
+    pub gemfile_lock: String,
+}
+
+fn default_gemfile_lock() -> String {
+    "Gemfile.lock".to_string()
+}
+
+/// The `[php]` section, the packages are read from `composer.lock` during `update`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PhpManifest {
+    /// Relative to the directory of the manifest
+    #[serde(default = "default_composer_lock")]
+    pub composer_lock: String,
+    /// Also pin `packages-dev`
+    #[serde(default)]
+    pub dev: bool,
+}
+
+fn default_composer_lock() -> String {
+    "composer.lock".to_string()
+}
+
+/// The `[perl]` section, the requirements are read from `cpanfile` during `update`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PerlManifest {
+    /// Relative to the directory of the manifest
+    #[serde(default = "default_cpanfile")]
+    pub cpanfile: String,
+    /// Also pin the requirements of the `test` phase
     #[serde(default)]
+    pub test: bool,
+}
+
+fn default_cpanfile() -> String {
+    "cpanfile".to_string()
+}
+
+/// The `[haskell]` section
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HaskellManifest {
+    /// The version of the GHC bindist from downloads.haskell.org, mounted at /opt/ghc
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ghc: Option<String>,
+    /// The platform of the bindist, like `x86_64-deb12-linux`
+    #[serde(default = "default_ghc_platform")]
+    pub ghc_platform: String,
+    /// The stackage snapshot, like `lts-22.33`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<String>,
+}
+
+fn default_ghc_platform() -> String {
+    format!("{}-deb12-linux", std::env::consts::ARCH)
+}
+
+/// The `[texlive]` section
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TexliveManifest {
+    /// The day of the tlnet snapshot on texlive.info, like `2024-06-01`
+    pub date: String,
+    /// Packages, collections or schemes, their dependencies are pinned too
+    pub packages: Vec<String>,
+    /// The platform of binary packages, like `x86_64-linux`
+    #[serde(default = "default_texlive_platform")]
+    pub platform: String,
+}
+
+fn default_texlive_platform() -> String {
+    format!("{}-linux", std::env::consts::ARCH)
+}
+
+fn valid_version_str(version: &str) -> bool {
+    !version.is_empty()
+        && version
+            .split('.')
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Either `lts-<major>.<minor>` or `nightly-<yyyy>-<mm>-<dd>`
+pub fn valid_stackage_snapshot(snapshot: &str) -> bool {
+    if let Some(version) = snapshot.strip_prefix("lts-") {
+        version.split('.').count() == 2 && valid_version_str(version)
+    } else if let Some(date) = snapshot.strip_prefix("nightly-") {
+        valid_date(date)
+    } else {
+        false
+    }
+}
+
+/// A date like `2024-09-01`
+pub fn valid_date(date: &str) -> bool {
+    let parts = date.split('-').collect::<Vec<_>>();
+    matches!(&parts[..], [y, m, d] if y.len() == 4 && m.len() == 2 && d.len() == 2)
+        && parts
+            .iter()
+            .all(|part| part.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// A file next to the manifest, the path can't leave the project
+fn valid_project_path(path: &str) -> bool {
+    !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)))
+}
+
+/// Toolchains can't be mounted over the directories repro-env uses itself
+pub fn valid_toolchain_path(path: &str) -> bool {
+    let Some(rest) = path.strip_prefix('/') else {
+        return false;
+    };
+    let mut components = rest.split('/');
+    let valid = components
+        .clone()
+        .all(|c| !matches!(c, "" | "." | "..") && !c.contains('\0'));
+    valid
+        && !matches!(
+            components.next(),
+            Some("build" | "extra" | "proc" | "dev" | "sys" | "tmp")
+        )
+}
+
+/// A `[profile.<name>]` entry, e.g. debug symbols that are only needed sometimes
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileManifest {
+    /// Installed in addition to the dependencies of [packages]
     pub dependencies: IndexSet<String>,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Overrides of a `[matrix.<name>]` entry, everything else is inherited
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VariantManifest {
+    /// Use a different container image
+    pub image: Option<String>,
+    /// Replace the dependencies of [packages]
+    pub dependencies: Option<IndexSet<String>>,
+}
 
-    #[test]
-    fn test_parse_manifest() -> Result<()> {
-        let manifest = Manifest::deserialize(
-            r#"[container]
-image = "docker.io/library/rust:1-alpine"
-"#,
-        )?;
+fn valid_variant_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
 
-        assert_eq!(
-            manifest,
-            Manifest {
-                container: ContainerManifest {
-                    image: "docker.io/library/rust:1-alpine".to_string(),
-                },
-                packages: None
-            }
-        );
+/// The default name of the lockfile, `repro-env.<variant>.lock` for matrix variants
+pub fn lockfile_name(variant: Option<&str>) -> String {
+    match variant {
+        Some(variant) => format!("repro-env.{variant}.lock"),
+        None => "repro-env.lock".to_string(),
+    }
+}
 
-        Ok(())
+/// The lockfile of a variant next to `path`, `foo.lock` becomes `foo.<variant>.lock`
+pub fn variant_lockfile_path(path: &Path, variant: &str) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name = match name.rsplit_once('.') {
+        Some((stem, ext)) => format!("{stem}.{variant}.{ext}"),
+        None => format!("{name}.{variant}"),
+    };
+    path.with_file_name(name)
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct LockfileManifest {
+    /// Record a [meta] section with provenance information in the lockfile
+    #[serde(default)]
+    pub meta: bool,
+    /// Every `[[files]]` entry needs a signature
+    #[serde(default)]
+    pub signed_files: bool,
+    /// Commands that rewrite the resolved lockfile before it's written
+    #[serde(default, rename = "transform", skip_serializing_if = "Vec::is_empty")]
+    pub transforms: Vec<TransformManifest>,
+}
+
+/// A `[[lockfile.transform]]` command, it receives the lockfile on stdin and prints the new one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransformManifest {
+    /// Recorded in the [meta] section of the lockfile
+    pub name: String,
+    /// Executed on the host in the directory of repro-env.toml
+    pub cmd: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BuildManifest {
+    /// Additional arguments for `podman run`, for flags repro-env doesn't have an option for
+    #[serde(default)]
+    pub podman_args: Vec<String>,
+    /// Executed in order with `build --steps`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<StepManifest>,
+    /// Sign the artifacts of a successful build
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sign: Option<SignManifest>,
+    /// A fixed environment for the build command, instead of the one of the image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub environment: Option<EnvironmentManifest>,
+}
+
+/// The `[build.environment]` section, a preset with optional overrides
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnvironmentManifest {
+    pub preset: EnvironmentPreset,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub home: Option<String>,
+    /// Used for both LANG and LC_ALL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tz: Option<String>,
+    /// In octal, e.g. `0022`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub umask: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EnvironmentPreset {
+    /// HOME=/build/.home, LANG=C.UTF-8, TZ=UTC, umask 0022 and a standard PATH
+    Deterministic,
+}
+
+impl EnvironmentManifest {
+    pub fn home(&self) -> &str {
+        self.home.as_deref().unwrap_or("/build/.home")
+    }
+
+    pub fn umask(&self) -> &str {
+        self.umask.as_deref().unwrap_or("0022")
+    }
+
+    /// The environment variables of the preset, with the overrides applied
+    pub fn vars(&self) -> Vec<(&'static str, &str)> {
+        let locale = self.locale.as_deref().unwrap_or("C.UTF-8");
+        vec![
+            ("HOME", self.home()),
+            ("LANG", locale),
+            ("LC_ALL", locale),
+            ("TZ", self.tz.as_deref().unwrap_or("UTC")),
+            (
+                "PATH",
+                self.path
+                    .as_deref()
+                    .unwrap_or("/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin"),
+            ),
+        ]
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !self.home().starts_with('/') {
+            bail!("HOME needs to be an absolute path: {:?}", self.home());
+        }
+        let umask = self.umask();
+        if !(3..=4).contains(&umask.len()) || !umask.chars().all(|c| ('0'..='7').contains(&c)) {
+            bail!("Invalid umask, expected an octal number like 0022: {umask:?}");
+        }
+        for (key, value) in self.vars() {
+            if value.contains(['\n', '\r']) {
+                bail!("Value of {key} must not contain newlines");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The `[build.sign]` section, the signatures are written next to the artifacts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SignManifest {
+    pub method: SignMethod,
+    /// The secret key, relative to the build directory (optional for cosign, which signs keyless without it)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<PathBuf>,
+    /// The files to sign, relative to the build directory
+    pub artifacts: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignMethod {
+    /// An ascii-armored OpenPGP signature (`.asc`)
+    Openpgp,
+    /// A minisign signature (`.minisig`), created with the minisign binary
+    Minisign,
+    /// A sigstore bundle (`.sigstore.json`), created with the cosign binary
+    Cosign,
+}
+
+impl SignMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SignMethod::Openpgp => "openpgp",
+            SignMethod::Minisign => "minisign",
+            SignMethod::Cosign => "cosign",
+        }
+    }
+
+    /// The extension that's appended to the filename of the artifact
+    pub fn extension(&self) -> &'static str {
+        match self {
+            SignMethod::Openpgp => "asc",
+            SignMethod::Minisign => "minisig",
+            SignMethod::Cosign => "sigstore.json",
+        }
+    }
+}
+
+/// A `[[build.steps]]` command, it's cached if it declares both inputs and outputs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StepManifest {
+    pub name: String,
+    pub cmd: Vec<String>,
+    /// Globs (gitignore syntax) of the files the step reads, relative to the build directory
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<String>,
+    /// Files and directories the step creates, they are restored if the step is skipped
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<String>,
+    /// Steps that need to finish first, independent steps can run concurrently with `build --jobs`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub needs: Vec<String>,
+}
+
+impl StepManifest {
+    pub fn is_cached(&self) -> bool {
+        !self.inputs.is_empty() && !self.outputs.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PackagesManifest {
+    pub system: String,
+    #[serde(default)]
+    pub dependencies: IndexSet<String>,
+    /// How the dependencies should be resolved
+    #[serde(default)]
+    pub solver: Solver,
+    /// Also install recommended packages (debian only)
+    #[serde(default)]
+    pub install_recommends: bool,
+    /// Also install suggested packages (debian only)
+    #[serde(default)]
+    pub install_suggests: bool,
+    /// Also lock the debug symbol packages of the resolved packages, if available (debian and archlinux only)
+    #[serde(default)]
+    pub debug_symbols: bool,
+    /// Also lock libfaketime, `repro-env build` runs the build with its clock set to SOURCE_DATE_EPOCH
+    #[serde(default)]
+    pub faketime: bool,
+    /// Dependencies that are considered satisfied without installing anything, like `pacman --assume-installed` (archlinux only)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub assume_installed: Vec<String>,
+    /// Never install packages of these groups, like `pacman --ignoregroup` (archlinux only)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ignore_groups: Vec<String>,
+    /// apt preferences that are written to /etc/apt/preferences.d/ before resolving (debian only)
+    #[serde(default, rename = "preference", skip_serializing_if = "Vec::is_empty")]
+    pub preferences: Vec<AptPreference>,
+    /// OpenPGP certificates to pin in the lockfile and import into the pacman keyring (archlinux only)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keys: Vec<PathBuf>,
+    /// A dated snapshot mirror that is recorded as fallback url of each package,
+    /// `{date}` and `{timestamp}` are replaced with when the index was generated (alpine only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<String>,
+    /// Resolve with the security suite, e.g. bookworm-security (debian only, setting this or `updates` replaces the apt sources of the image, default: true)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub security: Option<bool>,
+    /// Resolve with the updates suite, e.g. bookworm-updates (debian only, setting this or `security` replaces the apt sources of the image, default: true)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updates: Option<bool>,
+    /// Resolve from a snapshot of the archive at this point release, e.g. `12.5` (debian only, replaces the apt sources of the image)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub point_release: Option<String>,
+    /// The .flatpakrepo url of the remote to pin runtimes from (flatpak only, default: flathub)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote: Option<String>,
+    /// The urls of the opkg feeds to resolve from, each with a `Packages.gz` (openwrt only)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub feeds: Vec<String>,
+    /// An environment.yml to resolve, relative to the manifest (conda only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub environment: Option<String>,
+    /// The channels to resolve the dependencies from (conda only, default: conda-forge)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub channels: Vec<String>,
+    /// Packages that are installed before the others, one at a time in this order (alpine, archlinux and debian only)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub install_order: Vec<String>,
+}
+
+impl PackagesManifest {
+    /// The dependencies, plus the packages that are needed by other options
+    pub fn all_dependencies(&self) -> IndexSet<String> {
+        let mut dependencies = self.dependencies.clone();
+        if self.faketime {
+            dependencies.insert(FAKETIME_PACKAGE.to_string());
+        }
+        dependencies
+    }
+
+    /// If the apt sources of the image are replaced with the configured suites
+    pub fn configures_suites(&self) -> bool {
+        self.security.is_some() || self.updates.is_some()
+    }
+}
+
+/// A pin in the format of apt_preferences(5)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AptPreference {
+    /// The package names (or glob/regex) this applies to
+    pub package: String,
+    /// For example `release n=bookworm-backports` or `version 1.63*`
+    pub pin: String,
+    pub priority: i32,
+}
+
+/// Render the preferences as a file for /etc/apt/preferences.d/
+pub fn apt_preferences(preferences: &[AptPreference]) -> Result<String> {
+    let mut out = String::new();
+    for pref in preferences {
+        if pref.package.contains('\n') || pref.pin.contains('\n') {
+            bail!("apt preference must not contain newlines: {pref:?}");
+        }
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        out.push_str(&format!(
+            "Package: {}\nPin: {}\nPin-Priority: {}\n",
+            pref.package, pref.pin, pref.priority
+        ));
+    }
+    Ok(out)
+}
+
+/// The init of the containers, it keeps the container running and reaps zombies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Init {
+    /// catatonit of the host, mounted into the container
+    #[default]
+    Catatonit,
+    /// The init of podman (`podman run --init`), with `sleep` as command
+    Podman,
+    /// The entrypoint of the image, with `sleep` as command
+    Image,
+}
+
+impl Init {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Init::Catatonit => "catatonit",
+            Init::Podman => "podman",
+            Init::Image => "image",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Solver {
+    /// Install the packages in a container and detect what changed
+    #[default]
+    Native,
+    /// Ask the package manager what it would install, without installing anything (alpine only)
+    Simulate,
+    /// Experimental: resolve from the package indexes with repro-env's own solver (archlinux and debian only)
+    Builtin,
+}
+
+/// Podman flags that can't be set with `podman_args`, they'd break the pinned environment or repro-env itself
+const DENIED_PODMAN_ARGS: &[(&str, &str)] = &[
+    ("--entrypoint", "the container runs the init of repro-env"),
+    ("--init", "the container runs the init of repro-env"),
+    ("--init-path", "the container runs the init of repro-env"),
+    ("--rm", "containers are managed by repro-env"),
+    ("--detach", "containers are managed by repro-env"),
+    ("--name", "containers are managed by repro-env"),
+    ("--label", "labels are used to keep track of containers"),
+    ("--pull", "the image is pinned by the lockfile"),
+    ("--rootfs", "the image is pinned by the lockfile"),
+    ("--arch", "use --platform"),
+    ("--os", "use --platform"),
+    ("--variant", "use --platform"),
+    ("--platform", "use --platform"),
+    ("--volume", "directories are mounted by repro-env"),
+    ("--volumes-from", "directories are mounted by repro-env"),
+    ("--mount", "directories are mounted by repro-env"),
+    ("--env", "use --env or --env-file of repro-env"),
+    ("--env-file", "use --env or --env-file of repro-env"),
+    ("--env-host", "use --env or --env-file of repro-env"),
+];
+
+/// Check additional podman arguments, each one has to be a single `--flag` or `--flag=value`
+pub fn validate_podman_args(args: &[String]) -> Result<()> {
+    for arg in args {
+        let Some(flag) = arg.strip_prefix("--") else {
+            bail!("Podman arguments need to be passed as --flag or --flag=value: {arg:?}");
+        };
+        let name = flag.split_once('=').map(|(name, _)| name).unwrap_or(flag);
+        if name.is_empty() {
+            bail!("Podman arguments need to be passed as --flag or --flag=value: {arg:?}");
+        }
+        if let Some((flag, reason)) = DENIED_PODMAN_ARGS
+            .iter()
+            .find(|(flag, _)| flag.strip_prefix("--") == Some(name))
+        {
+            bail!("Podman argument {flag} is not allowed, {reason}: {arg:?}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lockfile::ProfileLock;
+
+    #[test]
+    fn test_parse_manifest() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "docker.io/library/rust:1-alpine"
+"#,
+        )?;
+
+        assert_eq!(
+            manifest,
+            Manifest {
+                container: ContainerManifest {
+                    image: "docker.io/library/rust:1-alpine".to_string(),
+                    pin_init: false,
+                    init: Init::Catatonit,
+                    containerfile: None,
+                },
+                packages: None,
+                lockfile: LockfileManifest::default(),
+                build: BuildManifest::default(),
+                matrix: IndexMap::new(),
+                profiles: IndexMap::new(),
+                files: vec![],
+                git: vec![],
+                toolchain: ToolchainManifest::default(),
+                java: None,
+                ruby: None,
+                php: None,
+                perl: None,
+                haskell: None,
+                texlive: None,
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_manifest_windows() -> Result<()> {
+        let buf = "[container]\nimage = \"docker.io/library/rust:1-alpine\"\n\n[packages]\nsystem = \"alpine\"\ndependencies = [\n    \"gcc\",\n]\n";
+        let manifest = Manifest::deserialize(buf)?;
+        let windows = format!("\u{feff}{}", buf.replace('\n', "\r\n"));
+        assert_eq!(Manifest::deserialize(&windows)?, manifest);
+
+        assert_eq!(normalize_text("a\r\nb\n"), "a\nb\n");
+        assert!(matches!(normalize_text("a\nb\n"), Cow::Borrowed(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_manifest_podman_args() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "docker.io/library/rust:1-alpine"
+
+[build]
+podman_args = ["--security-opt=seccomp=unconfined", "--ulimit=nofile=4096"]
+"#,
+        )?;
+        assert_eq!(
+            manifest.build.podman_args,
+            ["--security-opt=seccomp=unconfined", "--ulimit=nofile=4096"]
+        );
+
+        let err = Manifest::deserialize(
+            r#"[container]
+image = "docker.io/library/rust:1-alpine"
+
+[build]
+podman_args = ["--volume=/:/host"]
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "Invalid podman_args in [build]: Podman argument --volume is not allowed, directories are mounted by repro-env: \"--volume=/:/host\""
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_manifest_lockfile_meta() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "docker.io/library/rust:1-alpine"
+
+[lockfile]
+meta = true
+"#,
+        )?;
+        assert!(manifest.lockfile.meta);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_cargo_manifest() -> Result<()> {
+        let manifest = Manifest::deserialize_cargo(
+            r#"[package]
+name = "hello"
+version = "0.1.0"
+edition = "2021"
+
+[package.metadata.repro-env.container]
+image = "docker.io/library/rust:1-alpine3.18"
+
+[package.metadata.repro-env.packages]
+system = "alpine"
+dependencies = ["musl-dev"]
+
+[dependencies]
+"#,
+        )?;
+        assert_eq!(
+            manifest,
+            Manifest::deserialize(
+                r#"[container]
+image = "docker.io/library/rust:1-alpine3.18"
+
+[packages]
+system = "alpine"
+dependencies = ["musl-dev"]
+"#
+            )?
+        );
+
+        let workspace = Manifest::deserialize_cargo(
+            "[workspace]\nmembers = [\"hello\"]\n\n[workspace.metadata.repro-env.container]\nimage = \"rust\"\n",
+        )?;
+        assert_eq!(workspace.container.image, "rust");
+
+        let err = Manifest::deserialize_cargo("[package]\nname = \"hello\"\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cargo.toml has no [package.metadata.repro-env] section"
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn test_locate_manifest() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(FILENAME);
+        assert_eq!(locate(&path), path);
+
+        let cargo = dir.path().join(CARGO_FILENAME);
+        std::fs::write(&cargo, "[package]\nname = \"hello\"\n")?;
+        assert_eq!(locate(&path), path);
+
+        std::fs::write(
+            &cargo,
+            "[package]\nname = \"hello\"\n\n[package.metadata.repro-env.container]\nimage = \"rust\"\n",
+        )?;
+        assert_eq!(locate(&path), cargo);
+
+        // an existing repro-env.toml always takes precedence
+        std::fs::write(&path, "[container]\nimage = \"rust\"\n")?;
+        assert_eq!(locate(&path), path);
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_dir() {
+        assert_eq!(manifest_dir(Path::new("repro-env.toml")), Path::new("."));
+        assert_eq!(
+            manifest_dir(Path::new("a/b/repro-env.toml")),
+            Path::new("a/b")
+        );
+        assert_eq!(manifest_dir(Path::new("/repro-env.toml")), Path::new("/"));
+        assert_eq!(
+            manifest_dir(Path::new("/src/Cargo.toml")).join("keys/a.asc"),
+            Path::new("/src/keys/a.asc")
+        );
+    }
+
+    #[test]
+    fn test_parse_manifest_lockfile_transform() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "docker.io/library/archlinux"
+
+[[lockfile.transform]]
+name = "mirror"
+cmd = ["./contrib/use-mirror.sh", "https://mirror.example.com"]
+"#,
+        )?;
+        assert_eq!(
+            manifest.lockfile.transforms,
+            [TransformManifest {
+                name: "mirror".to_string(),
+                cmd: vec![
+                    "./contrib/use-mirror.sh".to_string(),
+                    "https://mirror.example.com".to_string()
+                ],
+            }]
+        );
+
+        let err = Manifest::deserialize(
+            r#"[container]
+image = "docker.io/library/archlinux"
+
+[[lockfile.transform]]
+name = "mirror"
+cmd = []
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Command of lockfile transform \"mirror\" is empty"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_manifest_build_steps() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "docker.io/library/rust"
+
+[[build.steps]]
+name = "compile"
+cmd = ["cargo", "build", "--release"]
+inputs = ["src/**", "Cargo.*"]
+outputs = ["target/release/hello"]
+
+[[build.steps]]
+name = "test"
+cmd = ["cargo", "test"]
+needs = ["compile"]
+"#,
+        )?;
+        assert_eq!(manifest.build.steps.len(), 2);
+        assert_eq!(manifest.build.steps[1].needs, ["compile"]);
+        assert!(manifest.build.steps[0].is_cached());
+        assert_eq!(manifest.build.steps[0].outputs, ["target/release/hello"]);
+        assert!(!manifest.build.steps[1].is_cached());
+
+        for (step, err) in [
+            (
+                "name = \"a\"\ncmd = [\"make\"]\nneeds = [\"b\"]\n[[build.steps]]\nname = \"b\"\ncmd = [\"make\"]\n",
+                "Build step \"a\" needs \"b\", which is not defined before it",
+            ),
+            (
+                "name = \"a\"\ncmd = [\"make\"]\ninputs = [\"*.c\"]\n",
+                "Build step \"a\" needs both inputs and outputs to be cached",
+            ),
+            (
+                "name = \"a\"\ncmd = [\"make\"]\ninputs = [\"*.c\"]\noutputs = [\"../out\"]\n",
+                "Output of build step \"a\" needs to be a relative path inside the build directory: \"../out\"",
+            ),
+        ] {
+            let toml = format!("[container]\nimage = \"rust\"\n\n[[build.steps]]\n{step}");
+            assert_eq!(Manifest::deserialize(&toml).unwrap_err().to_string(), err);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_manifest_build_sign() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "docker.io/library/rust"
+
+[build.sign]
+method = "openpgp"
+key = "release.key"
+artifacts = ["target/release/hello"]
+"#,
+        )?;
+        assert_eq!(
+            manifest.build.sign,
+            Some(SignManifest {
+                method: SignMethod::Openpgp,
+                key: Some(PathBuf::from("release.key")),
+                artifacts: vec![PathBuf::from("target/release/hello")],
+            })
+        );
+
+        for (sign, err) in [
+            (
+                "method = \"minisign\"\nartifacts = [\"a\"]\n",
+                "Signing with minisign needs a key in [build.sign]",
+            ),
+            (
+                "method = \"cosign\"\nartifacts = []\n",
+                "No artifacts to sign are declared in [build.sign]",
+            ),
+        ] {
+            let toml = format!("[container]\nimage = \"rust\"\n\n[build.sign]\n{sign}");
+            assert_eq!(Manifest::deserialize(&toml).unwrap_err().to_string(), err);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_manifest_build_environment() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "docker.io/library/rust"
+
+[build.environment]
+preset = "deterministic"
+tz = "Europe/Berlin"
+"#,
+        )?;
+        let environment = manifest.build.environment.context("Missing environment")?;
+        assert_eq!(environment.preset, EnvironmentPreset::Deterministic);
+        assert_eq!(environment.umask(), "0022");
+        assert_eq!(
+            environment.vars(),
+            [
+                ("HOME", "/build/.home"),
+                ("LANG", "C.UTF-8"),
+                ("LC_ALL", "C.UTF-8"),
+                ("TZ", "Europe/Berlin"),
+                (
+                    "PATH",
+                    "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin"
+                ),
+            ]
+        );
+
+        for (environment, err) in [
+            (
+                "home = \"build\"",
+                "HOME needs to be an absolute path: \"build\"",
+            ),
+            (
+                "umask = \"0999\"",
+                "Invalid umask, expected an octal number like 0022: \"0999\"",
+            ),
+        ] {
+            let toml = format!("[container]\nimage = \"rust\"\n\n[build.environment]\npreset = \"deterministic\"\n{environment}\n");
+            assert_eq!(
+                format!("{:#}", Manifest::deserialize(&toml).unwrap_err()),
+                format!("Invalid [build.environment]: {err}")
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_manifest_solver() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "docker.io/library/alpine:3.18"
+
+[packages]
+system = "alpine"
+dependencies = ["gcc"]
+solver = "simulate"
+"#,
+        )?;
+        assert_eq!(manifest.packages.unwrap().solver, Solver::Simulate);
+        Ok(())
+    }
+
+    #[test]
+    fn test_apt_preferences() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "debian:bookworm"
+
+[packages]
+system = "debian"
+dependencies = ["rustc"]
+
+[[packages.preference]]
+package = "rustc cargo"
+pin = "release n=bookworm-backports"
+priority = 990
+
+[[packages.preference]]
+package = "*"
+pin = "release n=bookworm"
+priority = 500
+"#,
+        )?;
+        let packages = manifest.packages.unwrap();
+        assert_eq!(
+            apt_preferences(&packages.preferences)?,
+            "Package: rustc cargo
+Pin: release n=bookworm-backports
+Pin-Priority: 990
+
+Package: *
+Pin: release n=bookworm
+Pin-Priority: 500
+"
+        );
+
+        let invalid = AptPreference {
+            package: "rustc".to_string(),
+            pin: "release n=bookworm\nPin-Priority: 1001".to_string(),
+            priority: 500,
+        };
+        assert!(apt_preferences(&[invalid]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_manifest_install_recommends() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "debian:bookworm"
+
+[packages]
+system = "debian"
+dependencies = ["gcc"]
+install_recommends = true
+"#,
+        )?;
+        let packages = manifest.packages.unwrap();
+        assert!(packages.install_recommends);
+        assert!(!packages.install_suggests);
+        Ok(())
+    }
+
+    #[test]
+    fn test_satisfied_by_version() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "debian:bookworm"
+
+[packages]
+system = "debian"
+dependencies = ["binutils>=2.40-10", "awk"]
+"#,
+        )?;
+        let lockfile = Lockfile::deserialize(
+            r#"[container]
+image = "debian@sha256:3d868b5eb908155f3784317b3dda2941df87bbbbaa4608f84881de66d9bb297b"
+
+[[package]]
+name = "binutils"
+version = "2.40-9"
+system = "debian"
+url = "https://snapshot.debian.org/archive/debian/20230115T211934Z/pool/main/b/binutils/binutils_2.40-9_amd64.deb"
+sha256 = "83c3e20b53e1fbd84d764c3ba27d26a0376e361ae5d7fb37120196934dd87424"
+
+[[package]]
+name = "mawk"
+version = "1.3.4.20200120-3.1"
+system = "debian"
+url = "https://snapshot.debian.org/archive/debian/20230115T211934Z/pool/main/m/mawk/mawk_1.3.4.20200120-3.1_amd64.deb"
+provides = ["awk"]
+sha256 = "ab314134f43a0891a48f69a9bc33d825da748fa5e0ba2bebb7a5c491b026f1a0"
+"#,
+        )?;
+        assert!(manifest.satisfied_by(&lockfile).is_err());
+
+        let mut lockfile = lockfile;
+        lockfile.packages[0].version = "2.40-10".to_string();
+        manifest.satisfied_by(&lockfile)?;
+
+        // libfaketime needs to be locked too
+        let mut manifest = manifest;
+        manifest.packages.as_mut().unwrap().faketime = true;
+        assert!(manifest.satisfied_by(&lockfile).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_files() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "debian:bookworm"
+
+[[files]]
+url = "https://example.com/releases/firmware-1.2.bin?download=1"
+
+[[files]]
+url = "https://example.com/src/"
+name = "src.tar.gz"
+sha256 = "83c3e20b53e1fbd84d764c3ba27d26a0376e361ae5d7fb37120196934dd87424"
+"#,
+        )?;
+        let names = manifest
+            .files
+            .iter()
+            .map(FileManifest::name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, [Some("firmware-1.2.bin"), Some("src.tar.gz")]);
+
+        let mut lockfile = Lockfile::deserialize(
+            r#"[container]
+image = "debian@sha256:3d868b5eb908155f3784317b3dda2941df87bbbbaa4608f84881de66d9bb297b"
+
+[[file]]
+name = "firmware-1.2.bin"
+url = "https://example.com/releases/firmware-1.2.bin?download=1"
+sha256 = "ab314134f43a0891a48f69a9bc33d825da748fa5e0ba2bebb7a5c491b026f1a0"
+size = 4096
+
+[[file]]
+name = "src.tar.gz"
+url = "https://example.com/src/"
+sha256 = "83c3e20b53e1fbd84d764c3ba27d26a0376e361ae5d7fb37120196934dd87424"
+size = 1024
+"#,
+        )?;
+        manifest.satisfied_by(&lockfile)?;
+
+        lockfile.files[1].sha256 = "00".repeat(32);
+        assert!(manifest.satisfied_by(&lockfile).is_err());
+        lockfile.files.pop();
+        assert!(manifest.satisfied_by(&lockfile).is_err());
+
+        for files in [
+            "[[files]]\nurl = \"https://example.com/\"\n",
+            "[[files]]\nurl = \"file:///etc/passwd\"\n",
+            "[[files]]\nurl = \"https://example.com/a\"\nname = \"../a\"\n",
+            "[[files]]\nurl = \"https://example.com/a\"\n[[files]]\nurl = \"https://example.org/a\"\n",
+            "[[files]]\nurl = \"https://example.com/a\"\nsha256 = \"abc\"\n",
+            "[[files]]\nurl = \"https://example.com/a\"\nsignature = \"https://example.com/a.asc\"\n",
+            "[[files]]\nurl = \"https://example.com/a\"\nkeys = [\"keys/vendor.asc\"]\n",
+            "[[files]]\nurl = \"https://example.com/a\"\nsignature = \"https://example.com/a.asc\"\nkeys = [\"/etc/vendor.asc\"]\n",
+            "[lockfile]\nsigned_files = true\n\n[[files]]\nurl = \"https://example.com/a\"\n",
+        ] {
+            let manifest = format!("[container]\nimage = \"debian:bookworm\"\n\n{files}");
+            assert!(Manifest::deserialize(&manifest).is_err(), "{files}");
+        }
+
+        // the signature is pinned too, a lockfile without it is outdated
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "debian:bookworm"
+
+[lockfile]
+signed_files = true
+
+[[files]]
+url = "https://example.com/releases/firmware-1.2.bin?download=1"
+signature = "https://example.com/releases/firmware-1.2.bin.asc"
+keys = ["keys/vendor.asc"]
+"#,
+        )?;
+        assert!(manifest.satisfied_by(&lockfile).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_git() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "debian:bookworm"
+
+[[git]]
+url = "https://github.com/kpcyrd/repro-env.git"
+rev = "v0.4.1"
+
+[[git]]
+url = "git@example.com:vendor/firmware"
+rev = "main"
+
+[[git]]
+url = "https://example.com/scm/"
+rev = "5b6f7dcb2b1b4ba3b1d6b4a59a7ac4e1f1c1f6a1"
+name = "scm"
+"#,
+        )?;
+        let names = manifest
+            .git
+            .iter()
+            .map(GitManifest::name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, [Some("repro-env"), Some("firmware"), Some("scm")]);
+
+        let mut lockfile = Lockfile::deserialize(
+            r#"[container]
+image = "debian@sha256:3d868b5eb908155f3784317b3dda2941df87bbbbaa4608f84881de66d9bb297b"
+
+[[git]]
+name = "repro-env"
+url = "https://github.com/kpcyrd/repro-env.git"
+rev = "v0.4.1"
+commit = "0d2c6e8f55d9e3c2c7d2f6f4e1a9e1b3c4d5e6f7"
+
+[[git]]
+name = "firmware"
+url = "git@example.com:vendor/firmware"
+rev = "main"
+commit = "a1c8e4b0c2b14cbe1e48f1e0e4f3cd1b2a7b8c9d"
+
+[[git]]
+name = "scm"
+url = "https://example.com/scm/"
+rev = "5b6f7dcb2b1b4ba3b1d6b4a59a7ac4e1f1c1f6a1"
+commit = "5b6f7dcb2b1b4ba3b1d6b4a59a7ac4e1f1c1f6a1"
+"#,
+        )?;
+        manifest.satisfied_by(&lockfile)?;
+
+        lockfile.git[1].rev = "develop".to_string();
+        assert!(manifest.satisfied_by(&lockfile).is_err());
+
+        let err = Manifest::deserialize(
+            r#"[container]
+image = "debian:bookworm"
+
+[[git]]
+url = "https://example.com/a/src.git"
+rev = "main"
+
+[[git]]
+url = "https://example.com/b/src"
+rev = "main"
+"#,
+        );
+        assert!(err.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_toolchains() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "debian:bookworm"
+
+[[toolchain.external]]
+url = "https://toolchains.bootlin.com/downloads/releases/toolchains/armv7-eabihf/tarballs/armv7-eabihf--glibc--stable-2024.02-1.tar.xz"
+path = "/opt/armv7-eabihf"
+strip_components = 1
+"#,
+        )?;
+        assert_eq!(
+            manifest.toolchain.external[0].name(),
+            Some("armv7-eabihf--glibc--stable-2024.02-1.tar.xz")
+        );
+
+        let mut lockfile = Lockfile::deserialize(
+            r#"[container]
+image = "debian@sha256:3d868b5eb908155f3784317b3dda2941df87bbbbaa4608f84881de66d9bb297b"
+
+[[toolchain]]
+name = "armv7-eabihf--glibc--stable-2024.02-1.tar.xz"
+url = "https://toolchains.bootlin.com/downloads/releases/toolchains/armv7-eabihf/tarballs/armv7-eabihf--glibc--stable-2024.02-1.tar.xz"
+sha256 = "8fd0f7ac9ae6e4b1ac2c8bd7a0d7d3f4a6c4b3c2e1f0a9b8c7d6e5f4a3b2c1d0"
+size = 123456789
+path = "/opt/armv7-eabihf"
+strip_components = 1
+"#,
+        )?;
+        manifest.satisfied_by(&lockfile)?;
+        lockfile.toolchains[0].strip_components = 0;
+        assert!(manifest.satisfied_by(&lockfile).is_err());
+
+        for path in [
+            "opt/toolchain",
+            "/build/toolchain",
+            "/extra",
+            "/opt/../build",
+            "/",
+        ] {
+            assert!(!valid_toolchain_path(path), "{path:?}");
+        }
+        assert!(valid_toolchain_path("/opt/toolchain"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_ruby() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "docker.io/library/ruby:3.3"
+
+[ruby]
+"#,
+        )?;
+        assert_eq!(
+            manifest.ruby,
+            Some(RubyManifest {
+                gemfile_lock: "Gemfile.lock".to_string()
+            })
+        );
+        for path in ["", "/src/Gemfile.lock", "../Gemfile.lock"] {
+            let buf = format!(
+                "[container]\nimage = \"debian:bookworm\"\n\n[ruby]\ngemfile_lock = {path:?}\n"
+            );
+            assert!(Manifest::deserialize(&buf).is_err(), "{path:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_containerfile() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            "[container]\nimage = \"debian:bookworm\"\ncontainerfile = \"ci/Containerfile\"\n",
+        )?;
+        assert_eq!(
+            manifest.container.containerfile.as_deref(),
+            Some("ci/Containerfile")
+        );
+        for path in ["../Containerfile", "/etc/Containerfile", ""] {
+            let buf =
+                format!("[container]\nimage = \"debian:bookworm\"\ncontainerfile = {path:?}\n");
+            assert!(Manifest::deserialize(&buf).is_err(), "{path:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_perl() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "docker.io/library/perl:5.40"
+
+[perl]
+test = true
+"#,
+        )?;
+        assert_eq!(
+            manifest.perl,
+            Some(PerlManifest {
+                cpanfile: "cpanfile".to_string(),
+                test: true,
+            })
+        );
+        let buf =
+            "[container]\nimage = \"debian:bookworm\"\n\n[perl]\ncpanfile = \"../cpanfile\"\n";
+        assert!(Manifest::deserialize(buf).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_php() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "docker.io/library/composer:2"
+
+[php]
+dev = true
+"#,
+        )?;
+        assert_eq!(
+            manifest.php,
+            Some(PhpManifest {
+                composer_lock: "composer.lock".to_string(),
+                dev: true,
+            })
+        );
+
+        let lockfile = Lockfile::deserialize(
+            r#"[container]
+image = "docker.io/library/composer@sha256:3d868b5eb908155f3784317b3dda2941df87bbbbaa4608f84881de66d9bb297b"
+
+[[composer]]
+name = "psr/log"
+version = "3.0.0"
+dist_type = "zip"
+url = "https://api.github.com/repos/php-fig/log/zipball/fe5ea303b0887d5caefd3d431c3e61ad47037001"
+sha256 = "0b9f2e8e8d6ad4b5d4d2f9bc0a6b5c1f0e2c5a9b3e8f1d7c6a5b4e3d2c1f0a9b"
+size = 7245
+"#,
+        )?;
+        manifest.satisfied_by(&lockfile)?;
+        let mut no_php = manifest.clone();
+        no_php.php = None;
+        assert!(no_php.satisfied_by(&lockfile).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_haskell() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "debian:bookworm"
+
+[haskell]
+ghc = "9.6.6"
+ghc_platform = "x86_64-deb12-linux"
+snapshot = "lts-22.33"
+"#,
+        )?;
+        let mut lockfile = Lockfile::deserialize(
+            r#"[container]
+image = "debian@sha256:3d868b5eb908155f3784317b3dda2941df87bbbbaa4608f84881de66d9bb297b"
+
+[[toolchain]]
+name = "ghc-9.6.6-x86_64-deb12-linux.tar.xz"
+url = "https://downloads.haskell.org/~ghc/9.6.6/ghc-9.6.6-x86_64-deb12-linux.tar.xz"
+sha256 = "1f2e3d4c5b6a79880f1e2d3c4b5a69788f1e2d3c4b5a69788f1e2d3c4b5a6978"
+size = 250880228
+path = "/opt/ghc"
+strip_components = 1
+
+[haskell]
+snapshot = "lts-22.33"
+
+[[haskell.file]]
+name = "lts-22.33.yaml"
+url = "https://raw.githubusercontent.com/commercialhaskell/stackage-snapshots/master/lts/22/33.yaml"
+sha256 = "4b2a1e6fdb4ab4d7e6d1b93e8a8c6de9c1e4ab2b7f6d4f1e7b3b1a6c2d2e5f90"
+size = 91234
+"#,
+        )?;
+        manifest.satisfied_by(&lockfile)?;
+        lockfile.haskell.as_mut().unwrap().snapshot = "lts-22.32".to_string();
+        assert!(manifest.satisfied_by(&lockfile).is_err());
+        lockfile.haskell = None;
+        assert!(manifest.satisfied_by(&lockfile).is_err());
+
+        for snapshot in ["lts-22", "lts-22.33.1", "nightly-2024-9-1", "lts/22.33"] {
+            assert!(!valid_stackage_snapshot(snapshot), "{snapshot:?}");
+        }
+        assert!(valid_stackage_snapshot("nightly-2024-09-01"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_java() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "docker.io/library/maven:3-eclipse-temurin-21"
+
+[java]
+jdk = "21"
+
+[java.maven]
+"#,
+        )?;
+        let maven = manifest
+            .java
+            .as_ref()
+            .and_then(|j| j.maven.as_ref())
+            .unwrap();
+        assert_eq!(maven.goals, ["dependency:go-offline"]);
+        assert_eq!(maven.repositories["central"], MAVEN_CENTRAL);
+
+        let mut lockfile = Lockfile::deserialize(
+            r#"[container]
+image = "docker.io/library/maven@sha256:3d868b5eb908155f3784317b3dda2941df87bbbbaa4608f84881de66d9bb297b"
+
+[[toolchain]]
+name = "OpenJDK21U-jdk_x64_linux_hotspot_21.0.4_7.tar.gz"
+url = "https://github.com/adoptium/temurin21-binaries/releases/download/jdk-21.0.4%2B7/OpenJDK21U-jdk_x64_linux_hotspot_21.0.4_7.tar.gz"
+sha256 = "51fb4d03a4429c39d397d3a03a779077159317616550e4e71624c9843083e7b9"
+size = 206777035
+path = "/opt/jdk"
+strip_components = 1
+
+[[maven]]
+path = "org/slf4j/slf4j-api/2.0.13/slf4j-api-2.0.13.jar"
+url = "https://repo.maven.apache.org/maven2/org/slf4j/slf4j-api/2.0.13/slf4j-api-2.0.13.jar"
+sha256 = "e7c2a48e8515ba1f49fa637d57b4e2f590b3f5bd97407ac699c3aa5efb1204a9"
+size = 68614
+"#,
+        )?;
+        manifest.satisfied_by(&lockfile)?;
+        lockfile.maven.clear();
+        assert!(manifest.satisfied_by(&lockfile).is_err());
+        lockfile.toolchains.clear();
+        let mut no_maven = manifest.clone();
+        no_maven.java.as_mut().unwrap().maven = None;
+        assert!(no_maven.satisfied_by(&lockfile).is_err());
+
+        for java in ["[java]\njdk = \"latest\"", "[java.maven]\ngoals = []"] {
+            let buf = format!("[container]\nimage = \"debian:bookworm\"\n\n{java}\n");
+            assert!(Manifest::deserialize(&buf).is_err(), "{java:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_profiles() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "debian:bookworm"
+
+[packages]
+system = "debian"
+dependencies = ["binutils"]
+
+[profile.debug]
+dependencies = ["binutils-dbgsym"]
+"#,
+        )?;
+        assert_eq!(
+            manifest.profiles["debug"].dependencies,
+            IndexSet::from(["binutils-dbgsym".to_string()])
+        );
+
+        let mut lockfile = Lockfile::deserialize(
+            r#"[container]
+image = "debian@sha256:3d868b5eb908155f3784317b3dda2941df87bbbbaa4608f84881de66d9bb297b"
+
+[[package]]
+name = "binutils"
+version = "2.40-2"
+system = "debian"
+url = "https://snapshot.debian.org/archive/debian/20230115T211934Z/pool/main/b/binutils/binutils_2.40-2_amd64.deb"
+sha256 = "83c3e20b53e1fbd84d764c3ba27d26a0376e361ae5d7fb37120196934dd87424"
+"#,
+        )?;
+        let err = manifest.satisfied_by(&lockfile).unwrap_err();
+        assert_eq!(err.to_string(), "Lockfile is missing profile: \"debug\"");
+
+        let mut dbgsym = lockfile.packages[0].clone();
+        dbgsym.name = "binutils-dbgsym".to_string();
+        lockfile.profiles.insert(
+            "debug".to_string(),
+            ProfileLock {
+                packages: vec![dbgsym],
+            },
+        );
+        manifest.satisfied_by(&lockfile)?;
+
+        // profiles only add packages
+        assert!(Manifest::deserialize(
+            "[container]\nimage = \"debian:bookworm\"\n[profile.debug]\ndependencies = [\"gdb\"]\n"
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_matrix_variants() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "debian:bookworm"
+
+[packages]
+system = "debian"
+dependencies = ["gcc", "libssl-dev"]
+
+[matrix.trixie]
+image = "debian:trixie"
+
+[matrix.minimal]
+dependencies = ["gcc"]
+"#,
+        )?;
+        assert_eq!(
+            manifest.matrix.keys().collect::<Vec<_>>(),
+            ["trixie", "minimal"]
+        );
+
+        let trixie = manifest.variant("trixie")?;
+        assert_eq!(trixie.container.image, "debian:trixie");
+        assert_eq!(
+            trixie.packages.as_ref().unwrap().dependencies,
+            manifest.packages.as_ref().unwrap().dependencies
+        );
+        assert!(trixie.matrix.is_empty());
+
+        let minimal = manifest.variant("minimal")?;
+        assert_eq!(minimal.container.image, "debian:bookworm");
+        assert_eq!(
+            minimal.packages.unwrap().dependencies,
+            IndexSet::from(["gcc".to_string()])
+        );
+
+        let err = manifest.variant("sid").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown matrix variant \"sid\", available: trixie, minimal"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_matrix() {
+        assert!(Manifest::deserialize(
+            "[container]\nimage = \"debian:bookworm\"\n[matrix.\"a/b\"]\nimage = \"x\"\n"
+        )
+        .is_err());
+        // dependencies can't be replaced without [packages]
+        let manifest = Manifest::deserialize(
+            "[container]\nimage = \"debian:bookworm\"\n[matrix.a]\ndependencies = [\"gcc\"]\n",
+        )
+        .unwrap();
+        assert!(manifest.variant("a").is_err());
+    }
+
+    #[test]
+    fn test_lockfile_names() {
+        assert_eq!(lockfile_name(None), "repro-env.lock");
+        assert_eq!(lockfile_name(Some("trixie")), "repro-env.trixie.lock");
+        assert_eq!(
+            variant_lockfile_path(Path::new("/src/repro-env.lock"), "trixie"),
+            Path::new("/src/repro-env.trixie.lock")
+        );
+        assert_eq!(
+            variant_lockfile_path(Path::new("deps"), "trixie"),
+            Path::new("deps.trixie")
+        );
+    }
+
+    #[test]
+    fn test_texlive() -> Result<()> {
+        let manifest = Manifest::deserialize(
+            r#"[container]
+image = "docker.io/texlive/texlive:TL2023-historic"
+
+[texlive]
+date = "2024-01-15"
+packages = ["collection-latex", "biblatex"]
+"#,
+        )?;
+        let mut lockfile = Lockfile::deserialize(
+            r#"[container]
+image = "docker.io/texlive/texlive@sha256:3d868b5eb908155f3784317b3dda2941df87bbbbaa4608f84881de66d9bb297b"
+
+[texlive]
+date = "2024-01-15"
+packages = ["collection-latex", "biblatex"]
+
+[[texlive.file]]
+name = "texlive.tlpdb"
+url = "https://texlive.info/tlnet-archive/2024/01/15/tlnet/tlpkg/texlive.tlpdb"
+sha256 = "4b2a1e6fdb4ab4d7e6d1b93e8a8c6de9c1e4ab2b7f6d4f1e7b3b1a6c2d2e5f90"
+size = 14563412
+"#,
+        )?;
+        manifest.satisfied_by(&lockfile)?;
+        lockfile.texlive.as_mut().unwrap().packages.pop();
+        assert!(manifest.satisfied_by(&lockfile).is_err());
+        lockfile.texlive = None;
+        assert!(manifest.satisfied_by(&lockfile).is_err());
+
+        assert!(Manifest::deserialize(
+            r#"[container]
+image = "debian:bookworm"
+
+[texlive]
+date = "2024/01/15"
+packages = ["biblatex"]
+"#
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_conda_spec_name() {
+        assert_eq!(conda_spec_name("numpy>=1.26"), "numpy");
+        assert_eq!(conda_spec_name("python 3.12.*"), "python");
+        assert_eq!(conda_spec_name("python=3.12"), "python");
+        assert_eq!(conda_spec_name("conda-forge::libgcc-ng"), "libgcc-ng");
+        assert_eq!(conda_spec_name("pytorch[build=cuda*]"), "pytorch");
+    }
+
+    #[test]
+    fn test_validate_podman_args() {
+        let args = |args: &[&str]| args.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert!(validate_podman_args(&args(&[
+            "--security-opt=label=disable",
+            "--ulimit=nofile=1024:2048",
+            "--cap-add=SYS_PTRACE",
+            "--read-only",
+        ]))
+        .is_ok());
+        assert!(validate_podman_args(&args(&["--ulimit", "nofile=1024"])).is_err());
+        assert!(validate_podman_args(&args(&["-v=/:/host"])).is_err());
+        assert!(validate_podman_args(&args(&["--"])).is_err());
+        assert!(validate_podman_args(&args(&["--=x"])).is_err());
+        assert!(validate_podman_args(&args(&["--entrypoint=/bin/sh"])).is_err());
+        assert!(validate_podman_args(&args(&["--env"])).is_err());
+        assert!(validate_podman_args(&args(&["--platform=linux/arm64"])).is_err());
     }
 }
@@ -0,0 +1,235 @@
+//! Look up known vulnerabilities of packages on osv.dev by their purl. The
+//! responses are kept in the cache, so they can be used without network access.
+use crate::errors::*;
+use crate::http;
+use crate::paths;
+use crate::utils;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+const QUERY_BATCH_URL: &str = "https://api.osv.dev/v1/querybatch";
+const VULNS_URL: &str = "https://api.osv.dev/v1/vulns/";
+/// The maximum number of queries in a single batch request
+const BATCH_SIZE: usize = 1000;
+/// The purl types that osv.dev has advisories for, queries for others are skipped
+const SUPPORTED_TYPES: &[&str] = &["pkg:apk/", "pkg:deb/"];
+
+#[derive(Debug, Serialize)]
+struct BatchQuery<'a> {
+    queries: Vec<Query<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct Query<'a> {
+    package: QueryPackage<'a>,
+}
+
+#[derive(Debug, Serialize)]
+struct QueryPackage<'a> {
+    purl: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchResponse {
+    results: Vec<QueryResult>,
+}
+
+/// The vulnerabilities that affect a purl, as returned by osv.dev
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct QueryResult {
+    #[serde(default)]
+    pub vulns: Vec<VulnRef>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VulnRef {
+    pub id: String,
+    #[serde(default)]
+    pub modified: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Vuln {
+    pub id: String,
+    #[serde(default)]
+    pub summary: Option<String>,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    #[serde(default)]
+    pub modified: Option<String>,
+}
+
+pub fn is_supported(purl: &str) -> bool {
+    SUPPORTED_TYPES.iter().any(|ty| purl.starts_with(ty))
+}
+
+fn query_cache_path(dir: &Path, purl: &str) -> PathBuf {
+    dir.join("queries")
+        .join(format!("{}.json", hex::encode(Sha256::digest(purl))))
+}
+
+fn vuln_cache_path(dir: &Path, id: &str) -> Result<PathBuf> {
+    if id.is_empty()
+        || !id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+    {
+        bail!("Unexpected vulnerability id from osv.dev: {id:?}");
+    }
+    Ok(dir.join("vulns").join(format!("{id}.json")))
+}
+
+fn read_cached<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<Option<T>> {
+    match std::fs::read(path) {
+        Ok(buf) => Ok(Some(serde_json::from_slice(&buf).with_context(|| {
+            anyhow!("Failed to parse cached osv.dev response: {path:?}")
+        })?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn write_cached<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    utils::atomic_write(path, &serde_json::to_vec(value)?)
+}
+
+async fn query_batch(client: &http::Client, purls: &[&str]) -> Result<Vec<QueryResult>> {
+    let query = BatchQuery {
+        queries: purls
+            .iter()
+            .map(|purl| Query {
+                package: QueryPackage { purl },
+            })
+            .collect(),
+    };
+    info!("Querying osv.dev for {} packages...", purls.len());
+    let response = client
+        .post(QUERY_BATCH_URL)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&query)?)
+        .send()
+        .await
+        .context("Failed to send http request")?
+        .error_for_status()
+        .context("Received http error from osv.dev")?;
+    let buf = response.bytes().await.context("Failed to read http body")?;
+    let response = serde_json::from_slice::<BatchResponse>(&buf)
+        .context("Failed to parse osv.dev query response")?;
+    if response.results.len() != purls.len() {
+        bail!(
+            "osv.dev returned {} results for {} queries",
+            response.results.len(),
+            purls.len()
+        );
+    }
+    Ok(response.results)
+}
+
+/// The vulnerabilities for each purl, with `offline` only the cache is used
+pub async fn query(
+    client: &http::Client,
+    purls: &[&str],
+    offline: bool,
+) -> Result<Vec<QueryResult>> {
+    let dir = paths::osv_cache_dir()?;
+    if offline {
+        return purls
+            .iter()
+            .map(|purl| {
+                read_cached(&query_cache_path(&dir, purl))?.with_context(|| {
+                    anyhow!("No cached osv.dev results for {purl:?}, run without --offline first")
+                })
+            })
+            .collect();
+    }
+
+    let mut results = Vec::new();
+    for chunk in purls.chunks(BATCH_SIZE) {
+        let chunk_results = query_batch(client, chunk).await?;
+        for (purl, result) in chunk.iter().zip(&chunk_results) {
+            write_cached(&query_cache_path(&dir, purl), result)?;
+        }
+        results.extend(chunk_results);
+    }
+    Ok(results)
+}
+
+/// The details of a vulnerability, fetched again if it was modified since it was cached
+pub async fn vuln(client: &http::Client, vuln: &VulnRef, offline: bool) -> Result<Vuln> {
+    let path = vuln_cache_path(&paths::osv_cache_dir()?, &vuln.id)?;
+    if let Some(cached) = read_cached::<Vuln>(&path)? {
+        if offline || vuln.modified.is_none() || cached.modified == vuln.modified {
+            return Ok(cached);
+        }
+    } else if offline {
+        bail!(
+            "No cached osv.dev details for {:?}, run without --offline first",
+            vuln.id
+        );
+    }
+
+    let buf = client.fetch(&format!("{VULNS_URL}{}", vuln.id)).await?;
+    let details = serde_json::from_slice::<Vuln>(&buf)
+        .with_context(|| anyhow!("Failed to parse osv.dev details of {:?}", vuln.id))?;
+    write_cached(&path, &details)?;
+    Ok(details)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialize_query() -> Result<()> {
+        let query = BatchQuery {
+            queries: vec![Query {
+                package: QueryPackage {
+                    purl: "pkg:deb/debian/curl@7.88.1-10?arch=amd64",
+                },
+            }],
+        };
+        assert_eq!(
+            serde_json::to_string(&query)?,
+            r#"{"queries":[{"package":{"purl":"pkg:deb/debian/curl@7.88.1-10?arch=amd64"}}]}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_response() -> Result<()> {
+        let response = serde_json::from_str::<BatchResponse>(
+            r#"{"results":[{},{"vulns":[{"id":"DSA-5587-1","modified":"2024-01-02T03:04:05Z"}]}]}"#,
+        )?;
+        assert_eq!(response.results[0], QueryResult::default());
+        assert_eq!(
+            response.results[1].vulns,
+            [VulnRef {
+                id: "DSA-5587-1".to_string(),
+                modified: Some("2024-01-02T03:04:05Z".to_string()),
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_supported() {
+        assert!(is_supported("pkg:deb/debian/curl@7.88.1-10"));
+        assert!(is_supported("pkg:apk/alpine/curl@8.4.0-r0"));
+        assert!(!is_supported("pkg:alpm/arch/curl@8.4.0-1"));
+    }
+
+    #[test]
+    fn test_vuln_cache_path() {
+        let dir = Path::new("/cache/osv");
+        assert_eq!(
+            vuln_cache_path(dir, "CVE-2023-38545").unwrap(),
+            Path::new("/cache/osv/vulns/CVE-2023-38545.json")
+        );
+        assert!(vuln_cache_path(dir, "../../etc/passwd").is_err());
+        assert!(vuln_cache_path(dir, "").is_err());
+    }
+}
@@ -1,7 +1,9 @@
 use crate::errors::*;
 use std::env;
+use std::fs::File;
 use std::io::ErrorKind;
 use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
 use tokio::fs;
 
 static SHARD_SIZE: usize = 2;
@@ -24,6 +26,59 @@ pub fn cache_dir() -> Result<PathBuf> {
     }
 }
 
+/// Timeouts and connection pool settings, `REPRO_ENV_HTTP_CONFIG` takes precedence
+pub fn http_config_path() -> Result<PathBuf> {
+    if let Some(path) = env::var_os("REPRO_ENV_HTTP_CONFIG") {
+        Ok(path.into())
+    } else {
+        let mut path = dirs::config_dir().context("Failed to detect config directory")?;
+        path.push("repro-env");
+        path.push("http.toml");
+        Ok(path)
+    }
+}
+
+/// Credentials for package mirrors, `REPRO_ENV_CREDENTIALS` takes precedence
+/// over `credentials.toml` in the config directory
+pub fn credentials_path() -> Result<PathBuf> {
+    if let Some(path) = env::var_os("REPRO_ENV_CREDENTIALS") {
+        Ok(path.into())
+    } else {
+        let mut path = dirs::config_dir().context("Failed to detect config directory")?;
+        path.push("repro-env");
+        path.push("credentials.toml");
+        Ok(path)
+    }
+}
+
+/// Where the directories that are mounted to /extra are set up, a directory
+/// passed with `--staging-dir` takes precedence over `REPRO_ENV_STAGING`
+pub fn staging_dir(arg: Option<&Path>) -> Result<PathBuf> {
+    if let Some(path) = arg {
+        Ok(path.to_path_buf())
+    } else if let Some(path) = env::var_os("REPRO_ENV_STAGING") {
+        Ok(path.into())
+    } else {
+        repro_env_dir()
+    }
+}
+
+/// Open the lock that guards the cache as a whole. Regular operations take a
+/// shared lock, operations that delete from the cache take an exclusive one.
+pub fn cache_lock() -> Result<File> {
+    let path = cache_dir()?;
+    std::fs::create_dir_all(&path)
+        .with_context(|| anyhow!("Failed to create cache directory: {path:?}"))?;
+    let path = path.join(".lock");
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| anyhow!("Failed to open cache lock: {path:?}"))?;
+    Ok(file)
+}
+
 pub fn pkgs_cache_dir() -> Result<PkgsCacheDir> {
     let mut path = cache_dir()?;
     path.push("pkgs");
@@ -36,6 +91,69 @@ pub fn alpine_cache_dir() -> Result<PkgsCacheDir> {
     Ok(PkgsCacheDir { path })
 }
 
+/// Metadata responses that can be revalidated with ETag/Last-Modified
+pub fn http_cache_dir() -> Result<PathBuf> {
+    let mut path = cache_dir()?;
+    path.push("http");
+    Ok(path)
+}
+
+/// snapshot.debian.org urls of packages that were already looked up, by sha256
+pub fn snapshot_cache_dir() -> Result<PathBuf> {
+    let mut path = cache_dir()?;
+    path.push("snapshot");
+    Ok(path)
+}
+
+/// Responses of osv.dev, for `audit --offline`
+pub fn osv_cache_dir() -> Result<PathBuf> {
+    let mut path = cache_dir()?;
+    path.push("osv");
+    Ok(path)
+}
+
+/// Outputs of cached `[[build.steps]]`, by the digest of their inputs
+pub fn steps_cache_dir() -> Result<PathBuf> {
+    let mut path = cache_dir()?;
+    path.push("steps");
+    Ok(path)
+}
+
+/// OCI layout with the images exported by `fetch --export-image`
+pub fn images_cache_dir() -> Result<PathBuf> {
+    let mut path = cache_dir()?;
+    path.push("images");
+    Ok(path)
+}
+
+/// Mirrors and checkouts of `[[git]]` repositories
+pub fn git_cache_dir() -> Result<PathBuf> {
+    let mut path = cache_dir()?;
+    path.push("git");
+    Ok(path)
+}
+
+/// Extracted tarballs of `[[toolchain.external]]`, by sha256
+pub fn toolchains_cache_dir() -> Result<PathBuf> {
+    let mut path = cache_dir()?;
+    path.push("toolchains");
+    Ok(path)
+}
+
+/// Indexes of the CPAN mirrors of the pinned perl distributions, by sha256
+pub fn cpan_cache_dir() -> Result<PathBuf> {
+    let mut path = cache_dir()?;
+    path.push("cpan");
+    Ok(path)
+}
+
+/// Local maven repositories assembled from the pinned artifacts, by digest of the artifact list
+pub fn maven_cache_dir() -> Result<PathBuf> {
+    let mut path = cache_dir()?;
+    path.push("maven");
+    Ok(path)
+}
+
 #[derive(Debug)]
 pub struct PkgsCacheDir {
     path: PathBuf,
@@ -73,6 +191,111 @@ impl PkgsCacheDir {
         Ok(path)
     }
 
+    /// All packages in the cache as (sha256, path, size), unfinished downloads are skipped
+    pub fn list(&self) -> Result<Vec<(String, PathBuf, u64)>> {
+        let mut pkgs = self
+            .files()?
+            .into_iter()
+            .filter(|(sha256, _, _)| Self::shard_sha256(sha256).is_ok())
+            .collect::<Vec<_>>();
+        pkgs.sort();
+        Ok(pkgs)
+    }
+
+    /// The `.tmp` files of downloads that are in progress or have been interrupted, as (path, size)
+    pub fn list_partial(&self) -> Result<Vec<(PathBuf, u64)>> {
+        let mut partial = self
+            .files()?
+            .into_iter()
+            .filter(|(name, _, _)| {
+                name.strip_suffix(".tmp")
+                    .is_some_and(|sha256| Self::shard_sha256(sha256).is_ok())
+            })
+            .map(|(_, path, size)| (path, size))
+            .collect::<Vec<_>>();
+        partial.sort();
+        Ok(partial)
+    }
+
+    /// Remove `.tmp` files that are at least `min_age` old and not locked by a
+    /// download in progress, returns how many were removed and their size
+    pub fn remove_partial(&self, min_age: Duration) -> Result<(usize, u64)> {
+        let mut removed = 0;
+        let mut freed = 0;
+        for (path, size) in self.list_partial()? {
+            let file = match std::fs::OpenOptions::new().write(true).open(&path) {
+                Ok(file) => file,
+                // the download finished in the meantime
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => {
+                    return Err(err).with_context(|| anyhow!("Failed to open file: {path:?}"))
+                }
+            };
+            // the file may have been created by a download that didn't get to lock it yet
+            let age = file.metadata()?.modified()?.elapsed().unwrap_or_default();
+            if age < min_age {
+                debug!("Keeping recent partial download: {path:?}");
+                continue;
+            }
+            let mut lock = fd_lock::RwLock::new(file);
+            let _guard = match lock.try_write() {
+                Ok(guard) => guard,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    debug!("Partial download is still in progress: {path:?}");
+                    continue;
+                }
+                Err(err) => {
+                    return Err(err).with_context(|| anyhow!("Failed to lock file: {path:?}"))
+                }
+            };
+            debug!("Removing stale partial download: {path:?}");
+            match std::fs::remove_file(&path) {
+                Ok(()) => (),
+                // the download finished between opening and locking the file
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => {
+                    return Err(err).with_context(|| anyhow!("Failed to remove file: {path:?}"))
+                }
+            }
+            removed += 1;
+            freed += size;
+        }
+        Ok((removed, freed))
+    }
+
+    /// All regular files in the shards as (shard + filename, path, size)
+    fn files(&self) -> Result<Vec<(String, PathBuf, u64)>> {
+        let mut files = Vec::new();
+        let shards = match std::fs::read_dir(&self.path) {
+            Ok(shards) => shards,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(files),
+            Err(err) => return Err(err.into()),
+        };
+        for shard in shards {
+            let shard = shard?;
+            if !shard.file_type()?.is_dir() {
+                continue;
+            }
+            let shard_name = shard.file_name();
+            let Some(prefix) = shard_name.to_str() else {
+                continue;
+            };
+            for entry in std::fs::read_dir(shard.path())? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if !metadata.is_file() {
+                    continue;
+                }
+                let name = entry.file_name();
+                let Some(suffix) = name.to_str() else {
+                    continue;
+                };
+                files.push((format!("{prefix}{suffix}"), entry.path(), metadata.len()));
+            }
+        }
+        Ok(files)
+    }
+
     fn sha1_path(&self, sha1: &str) -> Result<PathBuf> {
         let (shard, suffix) = Self::shard_sha1(sha1)?;
 
@@ -133,6 +356,33 @@ impl PkgsCacheDir {
     }
 }
 
+/// Search `start` and its parent directories for a file with this name
+pub fn find_in_ancestors(start: &Path, name: &str) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .map(|dir| dir.join(name))
+        .find(|path| path.exists())
+}
+
+/// Locate a repro-env file like cargo does, falling back to parent directories
+/// if it's not in the current one (if `search_parents` is set)
+pub fn find_project_file(name: &str, search_parents: bool) -> Result<PathBuf> {
+    if !search_parents {
+        return Ok(PathBuf::from(name));
+    }
+    let cwd = env::current_dir()?;
+    match find_in_ancestors(&cwd, name) {
+        Some(path) => {
+            if path.parent() != Some(cwd.as_path()) {
+                info!("Using {name} from parent directory: {path:?}");
+            }
+            Ok(path)
+        }
+        // report the usual error for the current directory
+        None => Ok(PathBuf::from(name)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -158,6 +408,85 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_list() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = PkgsCacheDir {
+            path: dir.path().to_path_buf(),
+        };
+        assert!(cache.list()?.is_empty());
+
+        let sha256 = "ff7951b5950a3a0319e86988041db4438b31a6ee4c7a36c64bd6c0c4607e40c9";
+        let path = cache.sha256_path(sha256)?;
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, b"pkg")?;
+        let mut tmp = path.clone();
+        tmp.as_mut_os_string().push(".tmp");
+        std::fs::write(&tmp, b"p")?;
+
+        assert_eq!(cache.list()?, [(sha256.to_string(), path, 3)]);
+        assert_eq!(cache.list_partial()?, [(tmp, 1)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_partial() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = PkgsCacheDir {
+            path: dir.path().to_path_buf(),
+        };
+        assert_eq!(cache.remove_partial(Duration::ZERO)?, (0, 0));
+
+        let mut paths = Vec::new();
+        for sha256 in [
+            "ff7951b5950a3a0319e86988041db4438b31a6ee4c7a36c64bd6c0c4607e40c9",
+            "0e28d9b8c3b8a5c6a8c6f1a2c1c0ff7951b5950a3a0319e86988041db4438b31",
+        ] {
+            let mut path = cache.sha256_path(sha256)?;
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            path.as_mut_os_string().push(".tmp");
+            std::fs::write(&path, b"partial")?;
+            paths.push(path);
+        }
+
+        // too recent, a download might be about to lock it
+        assert_eq!(cache.remove_partial(Duration::from_secs(3600))?, (0, 0));
+
+        // a download in progress holds a write lock
+        let file = std::fs::OpenOptions::new().write(true).open(&paths[0])?;
+        let mut lock = fd_lock::RwLock::new(file);
+        let guard = lock.write()?;
+        assert_eq!(cache.remove_partial(Duration::ZERO)?, (1, 7));
+        assert!(paths[0].exists());
+        assert!(!paths[1].exists());
+
+        drop(guard);
+        assert_eq!(cache.remove_partial(Duration::ZERO)?, (1, 7));
+        assert!(cache.list_partial()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_in_ancestors() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let subcrate = dir.path().join("crates/foo");
+        std::fs::create_dir_all(&subcrate)?;
+        assert_eq!(find_in_ancestors(&subcrate, "repro-env.lock"), None);
+
+        std::fs::write(dir.path().join("repro-env.lock"), "")?;
+        assert_eq!(
+            find_in_ancestors(&subcrate, "repro-env.lock"),
+            Some(dir.path().join("repro-env.lock"))
+        );
+
+        std::fs::write(subcrate.join("repro-env.lock"), "")?;
+        assert_eq!(
+            find_in_ancestors(&subcrate, "repro-env.lock"),
+            Some(subcrate.join("repro-env.lock"))
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_sha1_read_link() -> Result<()> {
         let path = PkgsCacheDir::link_to_sha256(Path::new(
@@ -0,0 +1,624 @@
+//! The `[perl]` section. The requirements of a `cpanfile` are resolved with
+//! the metacpan api to distribution tarballs, they are pinned by sha256 with
+//! their BackPAN permalink and provided as a CPAN mirror for `cpanm --mirror-only`.
+use crate::container::Mount;
+use crate::errors::*;
+use crate::fetch;
+use crate::hash;
+use crate::http;
+use crate::lockfile::{CpanLock, Lockfile};
+use crate::manifest::{self, PerlManifest};
+use crate::paths;
+use crate::summary;
+use crate::utils;
+use flate2::write::GzEncoder;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::Path;
+use tokio::fs;
+
+const METACPAN_API: &str = "https://fastapi.metacpan.org/v1";
+const BACKPAN: &str = "https://backpan.perl.org/authors/id";
+/// Where the mirror is mounted during builds
+pub const CPAN_MIRROR: &str = "/extra/cpan";
+
+#[derive(Debug, Deserialize)]
+pub struct DownloadUrl {
+    pub download_url: String,
+    pub version: String,
+    pub checksum_sha256: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ReleaseResponse {
+    Wrapped { release: Release },
+    Plain(Release),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Release {
+    pub distribution: String,
+    pub version: String,
+    #[serde(default)]
+    pub dependency: Vec<Dependency>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Dependency {
+    pub module: String,
+    pub phase: String,
+    pub relationship: String,
+    #[serde(default)]
+    pub version: Option<String>,
+}
+
+/// The phases whose requirements are needed to install, `test` only if enabled
+fn wanted_phase(phase: &str, test: bool) -> bool {
+    matches!(phase, "runtime" | "configure" | "build") || (test && phase == "test")
+}
+
+fn quoted_strings(stmt: &str) -> Vec<&str> {
+    let mut strings = Vec::new();
+    let mut rest = stmt;
+    while let Some(start) = rest.find(['\'', '"']) {
+        let quote = &rest[start..=start];
+        let Some(len) = rest[start + 1..].find(quote) else {
+            break;
+        };
+        strings.push(&rest[start + 1..start + 1 + len]);
+        rest = &rest[start + 2 + len..];
+    }
+    strings
+}
+
+/// Split a cpanfile into statements, `{` and `}` are statements of their own
+fn statements(buf: &str) -> Vec<String> {
+    let mut stmts = Vec::new();
+    let mut current = String::new();
+    let mut quote = None;
+    let mut comment = false;
+    for c in buf.chars() {
+        if comment {
+            comment = c != '\n';
+            continue;
+        }
+        match (c, quote) {
+            ('\'' | '"', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            (_, Some(_)) => (),
+            ('#', None) => {
+                comment = true;
+                continue;
+            }
+            (';' | '{' | '}', None) => {
+                stmts.push(current.trim().to_string());
+                current.clear();
+                if c != ';' {
+                    stmts.push(c.to_string());
+                }
+                continue;
+            }
+            _ => (),
+        }
+        current.push(c);
+    }
+    stmts.push(current.trim().to_string());
+    stmts.retain(|stmt| !stmt.is_empty());
+    stmts
+}
+
+/// An unquoted version after the module, like `requires 'Plack', 1.0;`
+fn bare_version<'a>(stmt: &'a str, module: &str) -> Option<&'a str> {
+    // the quoted module argument, the name may also be part of the keyword like `requires 're'`
+    let end = ['\'', '"']
+        .into_iter()
+        .filter_map(|quote| {
+            let quoted = format!("{quote}{module}{quote}");
+            stmt.find(&quoted).map(|idx| idx + quoted.len())
+        })
+        .min()?;
+    let rest = stmt.get(end..)?.trim_start();
+    let rest = rest
+        .strip_prefix("=>")
+        .or_else(|| rest.strip_prefix(','))?
+        .trim_start();
+    let len = rest
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '.' || c == '_'))
+        .unwrap_or(rest.len());
+    let version = &rest[..len];
+    version_parts(version).is_some().then_some(version)
+}
+
+/// The required modules of a `cpanfile` with their version requirement
+/// (`0` for any), recommendations and optional features are ignored
+pub fn parse_cpanfile(buf: &str, test: bool) -> Result<Vec<(String, String)>> {
+    let mut requirements = Vec::new();
+    let mut blocks = Vec::new();
+    let mut pending = None;
+    for stmt in statements(buf) {
+        match stmt.as_str() {
+            "{" => {
+                blocks.push(pending.take().unwrap_or_default());
+                continue;
+            }
+            "}" => {
+                blocks.pop().context("Unbalanced braces in cpanfile")?;
+                continue;
+            }
+            _ => (),
+        }
+        let keyword = stmt.split_whitespace().next().unwrap_or_default();
+        let phase = match keyword {
+            "on" => {
+                // the phase may also be a bareword, like `on test => sub`
+                let phase = quoted_strings(&stmt)
+                    .first()
+                    .copied()
+                    .or_else(|| stmt.split_whitespace().nth(1))
+                    .with_context(|| anyhow!("Invalid phase in cpanfile: {stmt:?}"))?;
+                let phase = phase.to_string();
+                pending = Some(phase);
+                continue;
+            }
+            "feature" => {
+                pending = Some("feature".to_string());
+                continue;
+            }
+            "requires" => blocks.last().map_or("runtime", String::as_str),
+            "test_requires" => "test",
+            "build_requires" => "build",
+            "configure_requires" => "configure",
+            "author_requires" => "develop",
+            _ => {
+                pending = None;
+                continue;
+            }
+        };
+        if !wanted_phase(phase, test) || blocks.iter().any(|block| block == "feature") {
+            continue;
+        }
+        let strings = quoted_strings(&stmt);
+        let module = strings
+            .first()
+            .with_context(|| anyhow!("Invalid requirement in cpanfile: {stmt:?}"))?;
+        let version = strings
+            .get(1)
+            .copied()
+            .or_else(|| bare_version(&stmt, module))
+            .unwrap_or("0");
+        requirements.push((module.to_string(), version.to_string()));
+    }
+    Ok(requirements)
+}
+
+/// The path of a distribution below `authors/id/`, like `M/MI/MIYAGAWA/Plack-1.0050.tar.gz`
+pub fn author_path(url: &str) -> Result<&str> {
+    let (_, path) = url
+        .split_once("/authors/id/")
+        .with_context(|| anyhow!("Download url is not on a CPAN mirror: {url:?}"))?;
+    if !valid_author_path(path) {
+        bail!("Invalid path of CPAN distribution: {path:?}");
+    }
+    Ok(path)
+}
+
+pub fn valid_author_path(path: &str) -> bool {
+    let parts = path.split('/').collect::<Vec<_>>();
+    matches!(&parts[..], [a, ab, author, filename]
+        if a.len() == 1
+            && ab.len() == 2
+            && ab.starts_with(a)
+            && author.starts_with(ab)
+            && author.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            && manifest::valid_file_name(filename))
+}
+
+/// Core modules resolve to a release of perl itself, they're never installed from CPAN
+fn is_perl_release(path: &str) -> bool {
+    path.rsplit('/')
+        .next()
+        .is_some_and(|filename| filename.starts_with("perl-5"))
+}
+
+/// The clauses of a version requirement like `>= 1.0, < 2.0`, a plain version means `>=`
+fn requirement_clauses(version: &str) -> Vec<String> {
+    version
+        .split(',')
+        .map(str::trim)
+        .filter(|clause| !matches!(*clause, "" | "0"))
+        .map(|clause| {
+            if clause.starts_with(['<', '>', '=', '!']) {
+                clause.to_string()
+            } else {
+                format!(">= {clause}")
+            }
+        })
+        .collect()
+}
+
+/// A version requirement of a cpanfile as query of the metacpan api
+fn version_query(version: &str) -> Option<String> {
+    let clauses = requirement_clauses(version);
+    if clauses.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "?version={}",
+        urlencoding::encode(&clauses.join(", "))
+    ))
+}
+
+/// The components of a perl version, decimal versions are split into groups of
+/// three digits like version.pm does, so `1.0050` is `v1.5.0`
+fn version_parts(version: &str) -> Option<Vec<u64>> {
+    let version = version.trim().replace('_', "");
+    let dotted = version
+        .strip_prefix('v')
+        .or_else(|| (version.matches('.').count() > 1).then_some(version.as_str()));
+    if let Some(dotted) = dotted {
+        return dotted.split('.').map(|part| part.parse().ok()).collect();
+    }
+    let (int, frac) = version.split_once('.').unwrap_or((&version, ""));
+    if !frac.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let mut parts = vec![int.parse().ok()?];
+    let frac = format!("{frac:0<width$}", width = frac.len().div_ceil(3) * 3);
+    for group in frac.as_bytes().chunks(3) {
+        parts.push(std::str::from_utf8(group).ok()?.parse().ok()?);
+    }
+    Some(parts)
+}
+
+fn compare_versions(a: &[u64], b: &[u64]) -> Ordering {
+    let len = a.len().max(b.len());
+    let part = |v: &[u64], i| v.get(i).copied().unwrap_or(0);
+    (0..len)
+        .map(|i| part(a, i).cmp(&part(b, i)))
+        .find(|ord| ord.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+/// Whether a resolved version satisfies a version requirement of a cpanfile
+fn satisfies(version: &str, requirement: &str) -> Result<bool> {
+    let parse = |version: &str| {
+        version_parts(version).with_context(|| anyhow!("Invalid perl version: {version:?}"))
+    };
+    let resolved = parse(version)?;
+    for clause in requirement_clauses(requirement) {
+        let (op, required) = clause
+            .find(|c: char| !matches!(c, '<' | '>' | '=' | '!'))
+            .map(|idx| clause.split_at(idx))
+            .with_context(|| anyhow!("Invalid version requirement: {clause:?}"))?;
+        let ord = compare_versions(&resolved, &parse(required)?);
+        let ok = match op.trim() {
+            ">=" => ord.is_ge(),
+            ">" => ord.is_gt(),
+            "<=" => ord.is_le(),
+            "<" => ord.is_lt(),
+            "==" => ord.is_eq(),
+            "!=" => ord.is_ne(),
+            op => bail!("Invalid operator in version requirement: {op:?}"),
+        };
+        if !ok {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// The `02packages.details.txt` index of the mirror, only the modules that
+/// were resolved are listed
+pub fn packages_index(dists: &[CpanLock]) -> String {
+    let mut modules = dists
+        .iter()
+        .flat_map(|dist| {
+            dist.modules
+                .iter()
+                .map(move |(module, version)| (module, version, &dist.path))
+        })
+        .collect::<Vec<_>>();
+    modules.sort();
+
+    let mut index = format!(
+        "File:         02packages.details.txt
+Description:  Package names found in directory $CPAN/authors/id/
+Columns:      package name, version, path
+Written-By:   repro-env
+Line-Count:   {}
+
+",
+        modules.len()
+    );
+    for (module, version, path) in modules {
+        index.push_str(&format!("{module:<40} {version:>10}  {path}\n"));
+    }
+    index
+}
+
+async fn fetch_json<T: DeserializeOwned>(client: &http::Client, url: &str) -> Result<T> {
+    let buf = client.fetch(url).await?;
+    serde_json::from_slice(&buf).with_context(|| anyhow!("Failed to parse response of {url:?}"))
+}
+
+/// Pin the distributions of the requirements of the `cpanfile` of `[perl]`
+pub async fn pin(perl: &PerlManifest, manifest_dir: &Path, lockfile: &mut Lockfile) -> Result<()> {
+    let path = manifest_dir.join(&perl.cpanfile);
+    let buf = fs::read_to_string(&path)
+        .await
+        .with_context(|| anyhow!("Failed to read cpanfile: {path:?}"))?;
+    let mut queue =
+        parse_cpanfile(&buf, perl.test).with_context(|| anyhow!("Failed to parse {path:?}"))?;
+
+    // the requirements of the cpanfile apply no matter which dependency asks for the module first
+    let mut cpanfile = HashMap::<_, Vec<_>>::new();
+    for (module, version) in &queue {
+        cpanfile
+            .entry(module.clone())
+            .or_default()
+            .extend(requirement_clauses(version));
+    }
+    queue.reverse();
+
+    let _phase = summary::phase("download");
+    let client = http::Client::new()?;
+    let mut resolved = HashMap::<String, (String, String)>::new();
+    let mut dists = BTreeMap::<String, CpanLock>::new();
+    while let Some((module, version)) = queue.pop() {
+        if module == "perl" {
+            continue;
+        }
+        if let Some((requirement, resolved)) = resolved.get(&module) {
+            match satisfies(resolved, &version) {
+                Ok(true) => (),
+                Ok(false) => bail!("Perl module {module:?} was resolved to {resolved:?} ({requirement:?}), but {version:?} is also required, add the stricter requirement to the cpanfile"),
+                Err(err) => warn!("Failed to check if perl module {module:?} {resolved:?} matches {version:?}: {err:#}"),
+            }
+            continue;
+        }
+        let mut clauses = cpanfile.get(&module).cloned().unwrap_or_default();
+        for clause in requirement_clauses(&version) {
+            if !clauses.contains(&clause) {
+                clauses.push(clause);
+            }
+        }
+        let requirement = clauses.join(", ");
+        let query = version_query(&requirement).unwrap_or_default();
+        let url = format!("{METACPAN_API}/download_url/{module}{query}");
+        let download = fetch_json::<DownloadUrl>(&client, &url)
+            .await
+            .with_context(|| {
+                anyhow!("Failed to resolve perl module {module:?} ({requirement:?})")
+            })?;
+        resolved.insert(
+            module.clone(),
+            (requirement.clone(), download.version.clone()),
+        );
+        let path = author_path(&download.download_url)?;
+        if is_perl_release(path) {
+            debug!("Perl module {module:?} is a core module");
+            continue;
+        }
+        if let Some(dist) = dists.get_mut(path) {
+            dist.modules.insert(module, download.version);
+            continue;
+        }
+
+        let (dir, filename) = path.rsplit_once('/').unwrap_or_default();
+        let author = dir.rsplit('/').next().unwrap_or_default();
+        let release = filename
+            .trim_end_matches(".gz")
+            .trim_end_matches(".bz2")
+            .trim_end_matches(".tar")
+            .trim_end_matches(".tgz")
+            .trim_end_matches(".zip");
+        let url = format!("{METACPAN_API}/release/{author}/{release}");
+        let release = match fetch_json::<ReleaseResponse>(&client, &url).await? {
+            ReleaseResponse::Wrapped { release } | ReleaseResponse::Plain(release) => release,
+        };
+        for dep in release.dependency.into_iter().rev() {
+            if dep.relationship == "requires" && wanted_phase(&dep.phase, perl.test) {
+                queue.push((dep.module, dep.version.unwrap_or_default()));
+            }
+        }
+
+        let url = format!("{BACKPAN}/{path}");
+        let pin = fetch::pin_file(&client, filename, &url, Some(&download.checksum_sha256))
+            .await
+            .with_context(|| anyhow!("Failed to pin perl distribution {filename:?}"))?;
+        debug!("Pinned {filename:?} with sha256 {:?}", pin.sha256);
+        dists.insert(
+            path.to_string(),
+            CpanLock {
+                name: release.distribution,
+                version: release.version,
+                path: path.to_string(),
+                url: pin.url,
+                sha256: pin.sha256,
+                size: pin.size,
+                modules: BTreeMap::from([(module, download.version)]),
+            },
+        );
+    }
+    lockfile.cpan = dists.into_values().collect();
+    Ok(())
+}
+
+/// Download and verify the `[[cpan]]` pins of the lockfile, they are mounted
+/// read-only into a CPAN mirror at /extra/cpan/ with an index of the resolved modules
+pub async fn stage(dists: Vec<CpanLock>, mounts: &mut Vec<Mount>) -> Result<()> {
+    if dists.is_empty() {
+        return Ok(());
+    }
+    for dist in &dists {
+        if !valid_author_path(&dist.path) {
+            bail!(
+                "Invalid path for perl distribution in lockfile: {:?}",
+                dist.path
+            );
+        }
+    }
+
+    let files = dists.iter().map(CpanLock::file).collect::<Vec<_>>();
+    {
+        let _phase = summary::phase("download");
+        fetch::download_files(&files).await?;
+    }
+    {
+        let _phase = summary::phase("verify");
+        utils::spawn_blocking(move |_| fetch::verify_files(&files)).await?;
+    }
+
+    // gzip is deterministic for the same input, the index only depends on the pins
+    let index = packages_index(&dists);
+    let dir = paths::cpan_cache_dir()?;
+    let index_path = dir.join(format!("{}.gz", hash::sha256(index.as_bytes())));
+    if !index_path.exists() {
+        let target = index_path.clone();
+        utils::spawn_blocking(move |_| {
+            std::fs::create_dir_all(&dir)
+                .with_context(|| anyhow!("Failed to create directory: {dir:?}"))?;
+            let mut gz = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            gz.write_all(index.as_bytes())?;
+            utils::atomic_write(&target, &gz.finish()?)
+        })
+        .await
+        .context("Failed to write index of CPAN mirror")?;
+    }
+    mounts.push(Mount::read_only(
+        index_path,
+        format!("{CPAN_MIRROR}/modules/02packages.details.txt.gz"),
+    ));
+
+    let pkgs_cache_dir = paths::pkgs_cache_dir()?;
+    for dist in &dists {
+        let source = pkgs_cache_dir.sha256_path(&dist.sha256)?;
+        mounts.push(Mount::read_only(
+            source,
+            format!("{CPAN_MIRROR}/authors/id/{}", dist.path),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpanfile() -> Result<()> {
+        let buf = r#"# dependencies of the documentation build
+requires 'perl', '5.010';
+requires 'Plack', '1.0';
+requires "JSON::PP" => "== 4.16"; # pinned for stable output
+recommends 'JSON::XS';
+
+on 'configure' => sub {
+    requires 'Module::Build::Tiny', '0.034';
+};
+
+on test => sub {
+    requires 'Test::More', '0.98';
+};
+
+on 'develop' => sub { requires 'Perl::Tidy'; };
+
+feature 'sqlite', 'SQLite support' => sub {
+    requires 'DBD::SQLite';
+};
+build_requires 'ExtUtils::MakeMaker';
+requires 'Try::Tiny', 0.30;
+requires 'Moo' => v2.4.0;
+requires 'Plack', '== 1.0050';
+requires 're', 0.23;
+requires "Carp" => 1.50;
+"#;
+        assert_eq!(
+            parse_cpanfile(buf, false)?,
+            [
+                ("perl", "5.010"),
+                ("Plack", "1.0"),
+                ("JSON::PP", "== 4.16"),
+                ("Module::Build::Tiny", "0.034"),
+                ("ExtUtils::MakeMaker", "0"),
+                ("Try::Tiny", "0.30"),
+                ("Moo", "v2.4.0"),
+                ("Plack", "== 1.0050"),
+                ("re", "0.23"),
+                ("Carp", "1.50"),
+            ]
+            .map(|(m, v)| (m.to_string(), v.to_string()))
+        );
+        let requirements = parse_cpanfile(buf, true)?;
+        assert!(requirements.contains(&("Test::More".to_string(), "0.98".to_string())));
+        assert!(parse_cpanfile("on 'test' => sub { requires 'A'; }; };", false).is_err());
+
+        // a module that is required more than once needs to match every requirement
+        assert!(satisfies("1.0050", "1.0")?);
+        assert!(satisfies("1.0050", ">= 1.0, == 1.0050")?);
+        assert!(!satisfies("1.0051", "== 1.0050")?);
+        assert!(!satisfies("0.30", "v2.4.0")?);
+        assert!(satisfies("2.004004", "v2.4.0")?);
+        // decimal versions compare as numbers
+        assert!(!satisfies("1.10", "> 1.9")?);
+        assert!(satisfies("1.0_01", "< 1.1")?);
+        assert!(satisfies("1.0", "0")?);
+        assert!(satisfies("garbage", "1.0").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_author_path() -> Result<()> {
+        assert_eq!(
+            author_path("https://cpan.metacpan.org/authors/id/M/MI/MIYAGAWA/Plack-1.0050.tar.gz")?,
+            "M/MI/MIYAGAWA/Plack-1.0050.tar.gz"
+        );
+        assert!(author_path("https://cpan.metacpan.org/authors/id/M/MI/../../etc/passwd").is_err());
+        assert!(author_path("https://example.com/Plack-1.0050.tar.gz").is_err());
+        assert!(is_perl_release("S/SH/SHAY/perl-5.38.2.tar.gz"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_query() {
+        assert_eq!(version_query("0"), None);
+        assert_eq!(
+            version_query("1.0").as_deref(),
+            Some("?version=%3E%3D%201.0")
+        );
+        assert_eq!(
+            version_query("== 4.16").as_deref(),
+            Some("?version=%3D%3D%204.16")
+        );
+        assert_eq!(
+            version_query("1.0, < 2").as_deref(),
+            Some("?version=%3E%3D%201.0%2C%20%3C%202")
+        );
+    }
+
+    #[test]
+    fn test_packages_index() {
+        let dist = CpanLock {
+            name: "Plack".to_string(),
+            version: "1.0050".to_string(),
+            path: "M/MI/MIYAGAWA/Plack-1.0050.tar.gz".to_string(),
+            url: "https://backpan.perl.org/authors/id/M/MI/MIYAGAWA/Plack-1.0050.tar.gz"
+                .to_string(),
+            sha256: "0".repeat(64),
+            size: 193_000,
+            modules: BTreeMap::from([
+                ("Plack::Request".to_string(), "1.0050".to_string()),
+                ("Plack".to_string(), "1.0050".to_string()),
+            ]),
+        };
+        let index = packages_index(&[dist]);
+        assert!(index.contains("Line-Count:   2\n\n"));
+        assert!(index.ends_with(
+            "Plack::Request                               1.0050  M/MI/MIYAGAWA/Plack-1.0050.tar.gz\n"
+        ));
+    }
+}
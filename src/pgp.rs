@@ -1,9 +1,16 @@
 use crate::errors::*;
 use crate::lockfile::PackageLock;
 use data_encoding::BASE64;
+use sequoia_openpgp::parse::stream::{
+    DetachedVerifierBuilder, MessageLayer, MessageStructure, VerificationHelper,
+};
 use sequoia_openpgp::parse::{PacketParser, PacketParserResult, Parse};
-use sequoia_openpgp::Packet;
+use sequoia_openpgp::policy::StandardPolicy;
+use sequoia_openpgp::serialize::stream::{Armorer, Message, Signer};
+use sequoia_openpgp::serialize::SerializeInto;
+use sequoia_openpgp::{armor, crypto::mpi, Cert, KeyHandle, Packet};
 use std::cmp;
+use std::io::Write;
 use std::time;
 use std::time::SystemTime;
 
@@ -26,6 +33,118 @@ pub fn parse_timestamp_from_sig(buf: &[u8]) -> Result<Option<time::SystemTime>>
     Ok(None)
 }
 
+#[derive(Debug, Default, PartialEq)]
+pub struct SignatureInfo {
+    pub issuer: Option<String>,
+    pub created: Option<SystemTime>,
+}
+
+pub fn inspect_signature(buf: &[u8]) -> Result<SignatureInfo> {
+    let mut ppr = PacketParser::from_bytes(buf)?;
+
+    while let PacketParserResult::Some(pp) = ppr {
+        let (packet, next_ppr) = pp.recurse()?;
+        ppr = next_ppr;
+        let Packet::Signature(sig) = &packet else {
+            continue;
+        };
+        let issuer = sig.get_issuers().first().map(|issuer| issuer.to_hex());
+        return Ok(SignatureInfo {
+            issuer,
+            created: sig.signature_creation_time(),
+        });
+    }
+
+    bail!("Could not find any signature in pgp data")
+}
+
+/// Parse an OpenPGP certificate, returns the fingerprint and the ascii-armored public key
+pub fn normalize_cert(buf: &[u8]) -> Result<(String, String)> {
+    let cert = Cert::from_bytes(buf).context("Failed to parse OpenPGP certificate")?;
+    let fingerprint = cert.fingerprint().to_hex();
+    let armored = String::from_utf8(cert.armored().to_vec()?)?;
+    Ok((fingerprint, armored))
+}
+
+/// Accept a message if any of its signatures is valid and made by one of `certs`
+pub struct TrustedCerts<'a> {
+    pub certs: &'a [Cert],
+}
+
+impl VerificationHelper for TrustedCerts<'_> {
+    fn get_certs(&mut self, _ids: &[KeyHandle]) -> sequoia_openpgp::Result<Vec<Cert>> {
+        Ok(self.certs.to_vec())
+    }
+
+    fn check(&mut self, structure: MessageStructure) -> sequoia_openpgp::Result<()> {
+        for layer in structure {
+            if let MessageLayer::SignatureGroup { results } = layer {
+                if results.iter().any(|r| r.is_ok()) {
+                    return Ok(());
+                }
+            }
+        }
+        Err(anyhow!("No valid signature by a trusted key"))
+    }
+}
+
+/// Verify a detached signature (binary or armored) of `data`
+pub fn verify_detached(data: &[u8], sig: &[u8], certs: &[Cert]) -> Result<()> {
+    let policy = StandardPolicy::new();
+    let mut verifier = DetachedVerifierBuilder::from_bytes(sig)?.with_policy(
+        &policy,
+        None,
+        TrustedCerts { certs },
+    )?;
+    verifier.verify_bytes(data)?;
+    Ok(())
+}
+
+/// The certificate that made a valid detached signature of `data`
+pub fn find_signer<'a>(data: &[u8], sig: &[u8], certs: &'a [Cert]) -> Result<&'a Cert> {
+    certs
+        .iter()
+        .find(|cert| verify_detached(data, sig, std::slice::from_ref(*cert)).is_ok())
+        .context("No valid signature by any of the trusted keys")
+}
+
+/// Create an ascii-armored detached signature of `data` with the signing key
+/// of a secret key, encrypted keys are decrypted with `password`
+pub fn sign_detached(cert: &Cert, password: Option<&str>, data: &[u8]) -> Result<Vec<u8>> {
+    let policy = StandardPolicy::new();
+    let mut key = cert
+        .keys()
+        .with_policy(&policy, None)
+        .alive()
+        .revoked(false)
+        .secret()
+        .for_signing()
+        .next()
+        .context("Secret key has no usable signing key")?
+        .key()
+        .clone();
+    // the RustCrypto backend of sequoia signs with RSA in variable time
+    if matches!(key.mpis(), mpi::PublicKey::RSA { .. }) {
+        warn!("Signing with an RSA key, the implementation is not constant-time and may leak the key through timing, consider an ed25519 key");
+    }
+    if key.secret().is_encrypted() {
+        let password = password.context("Signing key is encrypted, but no password was given")?;
+        key = key
+            .decrypt_secret(&password.into())
+            .context("Failed to decrypt signing key")?;
+    }
+    let keypair = key.into_keypair()?;
+
+    let mut sig = Vec::new();
+    let message = Armorer::new(Message::new(&mut sig))
+        .kind(armor::Kind::Signature)
+        .build()?;
+    let mut signer = Signer::new(message, keypair).detached().build()?;
+    signer.write_all(data)?;
+    signer.finalize()?;
+    Ok(sig)
+}
+
 pub fn find_max_signature_time<'a, I: Iterator<Item = &'a PackageLock>>(
     pkgs: I,
 ) -> Result<Option<SystemTime>> {
@@ -33,8 +152,7 @@ pub fn find_max_signature_time<'a, I: Iterator<Item = &'a PackageLock>>(
 
     for pkg in pkgs {
         let base64 = pkg
-            .signature
-            .as_ref()
+            .openpgp_signature()
             .context("Package in dependency lockfile is missing signature")?;
         let signature = BASE64
             .decode(base64.as_bytes())
@@ -58,6 +176,7 @@ pub fn find_max_signature_time<'a, I: Iterator<Item = &'a PackageLock>>(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::lockfile::SignatureLock;
     use data_encoding::BASE64;
 
     #[test]
@@ -70,6 +189,22 @@ mod tests {
         assert_eq!(time, expected);
     }
 
+    #[test]
+    fn test_inspect_sig() {
+        let buf = BASE64.decode(b"iHUEABYKAB0WIQQEKYl95fO9rFN6MGltQr3RFuAGjwUCZcU7FAAKCRBtQr3RFuAGj4Y4AQCKsihdyJWyNGBwQ9Kd5AmenehuvR4xfFOCjIOndQCYhwD+NFzEjbwraHHVtEjQh4HtrnZPc0JplQvM5zRT3gDCawE=").unwrap();
+        let info = inspect_signature(&buf).unwrap();
+        let expected = time::UNIX_EPOCH
+            .checked_add(time::Duration::from_secs(1707424532))
+            .unwrap();
+        assert_eq!(
+            info,
+            SignatureInfo {
+                issuer: Some("0429897DE5F3BDAC537A30696D42BDD116E0068F".to_string()),
+                created: Some(expected),
+            }
+        );
+    }
+
     #[test]
     fn test_max_signature_time() {
         let pkgs = [
@@ -78,22 +213,20 @@ mod tests {
                 version: "20230704-1".to_string(),
                 system: "archlinux".to_string(),
                 url: "https://archive.archlinux.org/packages/a/archlinux-keyring/archlinux-keyring-20230704-1-any.pkg.tar.zst".to_string(),
-                provides: vec![],
                 sha256: "6a3d2acaa396c4bd72fe3f61a3256d881e3fc2cf326113cf331f168e36dd9a3c".to_string(),
-                signature: Some(
-"iHUEABYIAB0WIQQEKYl95fO9rFN6MGltQr3RFuAGjwUCZKPPXgAKCRBtQr3RFuAGj9oXAP94RQ1sKD53/RxVYlVEEOjKHvOmrWvDkt1veMYygnlnIgD+MLg/TT6d71kE8F08+JH+EcnG7wQow5Xr/qBo1VPLdgQ=".to_string()),
-                installed: false,
+                signatures: vec![SignatureLock::openpgp(
+"iHUEABYIAB0WIQQEKYl95fO9rFN6MGltQr3RFuAGjwUCZKPPXgAKCRBtQr3RFuAGj9oXAP94RQ1sKD53/RxVYlVEEOjKHvOmrWvDkt1veMYygnlnIgD+MLg/TT6d71kE8F08+JH+EcnG7wQow5Xr/qBo1VPLdgQ=".to_string())],
+                ..Default::default()
             },
             PackageLock {
                 name: "binutils".to_string(),
                 version: "2.40-6".to_string(),
                 system: "archlinux".to_string(),
                 url: "https://archive.archlinux.org/packages/b/binutils/binutils-2.40-6-x86_64.pkg.tar.zst".to_string(),
-                provides: vec![],
                 sha256: "b65fd16001578e10b602e577a8031cbfffc1164caf47ed9ba00c60d804519430".to_string(),
-                signature: Some(
-"iNUEABYKAH0WIQQFx3danouXdAf+COadTFqhVCbaCgUCZG6Rg18UgAAAAAAuAChpc3N1ZXItZnByQG5vdGF0aW9ucy5vcGVucGdwLmZpZnRoaG9yc2VtYW4ubmV0MDVDNzc3NUE5RThCOTc3NDA3RkUwOEU2OUQ0QzVBQTE1NDI2REEwQQAKCRCdTFqhVCbaCge2AQD/LGBeHRaeO8xh4E/bAYfqd1O/OFqk2DrQBJ73cdKl2gD9EC8p4U/cXQK8V774m6LSS50usH5pxcQWEq/H0SF+FgM=".to_string()),
-                installed: false,
+                signatures: vec![SignatureLock::openpgp(
+"iNUEABYKAH0WIQQFx3danouXdAf+COadTFqhVCbaCgUCZG6Rg18UgAAAAAAuAChpc3N1ZXItZnByQG5vdGF0aW9ucy5vcGVucGdwLmZpZnRoaG9yc2VtYW4ubmV0MDVDNzc3NUE5RThCOTc3NDA3RkUwOEU2OUQ0QzVBQTE1NDI2REEwQQAKCRCdTFqhVCbaCge2AQD/LGBeHRaeO8xh4E/bAYfqd1O/OFqk2DrQBJ73cdKl2gD9EC8p4U/cXQK8V774m6LSS50usH5pxcQWEq/H0SF+FgM=".to_string())],
+                ..Default::default()
             }
         ];
 
@@ -103,4 +236,67 @@ mod tests {
             .unwrap();
         assert_eq!(time, Some(expected));
     }
+
+    #[test]
+    fn test_normalize_cert() -> Result<()> {
+        let (cert, _) =
+            sequoia_openpgp::cert::CertBuilder::general_purpose(None, Some("repro-env"))
+                .generate()?;
+        let expected = cert.fingerprint().to_hex();
+
+        // binary and armored input, secret key material is not exported
+        let (fingerprint, armored) = normalize_cert(&cert.as_tsk().to_vec()?)?;
+        assert_eq!(fingerprint, expected);
+        assert!(armored.starts_with("-----BEGIN PGP PUBLIC KEY BLOCK-----"));
+        assert_eq!(normalize_cert(armored.as_bytes())?, (fingerprint, armored));
+
+        assert!(normalize_cert(b"not a key").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_detached() -> Result<()> {
+        let (trusted, _) =
+            sequoia_openpgp::cert::CertBuilder::general_purpose(None, Some("release"))
+                .generate()?;
+        let (other, _) =
+            sequoia_openpgp::cert::CertBuilder::general_purpose(None, Some("other")).generate()?;
+        let certs = [trusted.clone()];
+
+        let sig = sign_detached(&trusted, None, b"hello world")?;
+        assert!(sig.starts_with(b"-----BEGIN PGP SIGNATURE-----"));
+        verify_detached(b"hello world", &sig, &certs)?;
+        assert!(verify_detached(b"hello world!", &sig, &certs).is_err());
+
+        let sig = sign_detached(&other, None, b"hello world")?;
+        assert!(verify_detached(b"hello world", &sig, &certs).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_signer() -> Result<()> {
+        let (first, _) =
+            sequoia_openpgp::cert::CertBuilder::general_purpose(None, Some("first")).generate()?;
+        let (second, _) =
+            sequoia_openpgp::cert::CertBuilder::general_purpose(None, Some("second")).generate()?;
+        let certs = [first, second.clone()];
+
+        let sig = sign_detached(&second, None, b"firmware")?;
+        let signer = find_signer(b"firmware", &sig, &certs)?;
+        assert_eq!(signer.fingerprint(), second.fingerprint());
+        assert!(find_signer(b"firmware", &sig, &certs[..1]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sign_detached_encrypted() -> Result<()> {
+        let (cert, _) = sequoia_openpgp::cert::CertBuilder::general_purpose(None, Some("release"))
+            .set_password(Some("hunter2".into()))
+            .generate()?;
+        assert!(sign_detached(&cert, None, b"hello world").is_err());
+        assert!(sign_detached(&cert, Some("wrong"), b"hello world").is_err());
+        let sig = sign_detached(&cert, Some("hunter2"), b"hello world")?;
+        verify_detached(b"hello world", &sig, &[cert])?;
+        Ok(())
+    }
 }
@@ -0,0 +1,252 @@
+//! The `[php]` section. The dist archives of `composer.lock` are pinned by
+//! sha256 and mounted as composer cache, so `composer install` works without network.
+use crate::container::Mount;
+use crate::errors::*;
+use crate::fetch;
+use crate::http;
+use crate::lockfile::{ComposerLock, Lockfile};
+use crate::manifest::PhpManifest;
+use crate::paths;
+use crate::summary;
+use crate::utils;
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use std::path::Path;
+use tokio::fs;
+
+/// Where the composer cache is mounted during builds
+pub const COMPOSER_CACHE: &str = "/extra/composer";
+
+#[derive(Debug, Deserialize)]
+pub struct ComposerLockfile {
+    #[serde(default)]
+    pub packages: Vec<Package>,
+    #[serde(default, rename = "packages-dev")]
+    pub packages_dev: Vec<Package>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Package {
+    pub name: String,
+    pub version: String,
+    pub dist: Option<Dist>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Dist {
+    #[serde(rename = "type")]
+    pub dist_type: String,
+    pub url: String,
+    /// A sha1 of the archive, usually empty for archives from github
+    #[serde(default)]
+    pub shasum: Option<String>,
+}
+
+impl ComposerLockfile {
+    pub fn deserialize(buf: &str) -> Result<Self> {
+        serde_json::from_str(buf).context("Failed to parse composer.lock")
+    }
+}
+
+/// Package names are `vendor/name`, each part becomes a directory of the cache
+pub fn valid_package_name(name: &str) -> bool {
+    let Some((vendor, package)) = name.split_once('/') else {
+        return false;
+    };
+    [vendor, package].iter().all(|part| {
+        !matches!(*part, "" | "." | "..")
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    })
+}
+
+/// The path of an archive in the composer cache, composer 2 uses the sha1 of its url
+pub fn cache_path(package: &ComposerLock) -> String {
+    let key = hex::encode(Sha1::digest(package.url.as_bytes()));
+    format!("files/{}/{key}.{}", package.name, package.dist_type)
+}
+
+/// Pin the dist archives of the `composer.lock` of `[php]`
+pub async fn pin(php: &PhpManifest, manifest_dir: &Path, lockfile: &mut Lockfile) -> Result<()> {
+    let path = manifest_dir.join(&php.composer_lock);
+    let buf = fs::read_to_string(&path)
+        .await
+        .with_context(|| anyhow!("Failed to read composer lockfile: {path:?}"))?;
+    let composer =
+        ComposerLockfile::deserialize(&buf).with_context(|| anyhow!("Failed to parse {path:?}"))?;
+    let mut packages = composer.packages;
+    if php.dev {
+        packages.extend(composer.packages_dev);
+    }
+
+    let _phase = summary::phase("download");
+    let client = http::Client::new()?;
+    let pkgs_cache_dir = paths::pkgs_cache_dir()?;
+    for package in packages {
+        let Some(dist) = package.dist else {
+            warn!(
+                "Composer package {:?} has no dist archive, it can't be installed offline",
+                package.name
+            );
+            continue;
+        };
+        if dist.dist_type == "path" {
+            continue;
+        }
+        if !valid_package_name(&package.name) {
+            bail!(
+                "Invalid name for package in composer.lock: {:?}",
+                package.name
+            );
+        }
+        if !dist.dist_type.chars().all(|c| c.is_ascii_alphanumeric()) {
+            bail!("Invalid dist type in composer.lock: {:?}", dist.dist_type);
+        }
+
+        let filename = format!(
+            "{}-{}.{}",
+            package.name.replace('/', "-"),
+            package.version,
+            dist.dist_type
+        );
+        let pin = fetch::pin_file(&client, &filename, &dist.url, None)
+            .await
+            .with_context(|| anyhow!("Failed to pin composer package {:?}", package.name))?;
+        if let Some(shasum) = dist.shasum.as_deref().filter(|s| !s.is_empty()) {
+            let path = pkgs_cache_dir.sha256_path(&pin.sha256)?;
+            let buf = fs::read(&path).await?;
+            let sha1 = hex::encode(Sha1::digest(&buf));
+            if sha1 != shasum.to_ascii_lowercase() {
+                bail!(
+                    "Mismatch of sha1 for composer package {:?}, expected={shasum:?}, downloaded={sha1:?}",
+                    package.name
+                );
+            }
+        }
+        debug!(
+            "Pinned composer package {:?} with sha256 {:?}",
+            package.name, pin.sha256
+        );
+        lockfile.composer.push(ComposerLock {
+            name: package.name,
+            version: package.version,
+            dist_type: dist.dist_type,
+            url: pin.url,
+            sha256: pin.sha256,
+            size: pin.size,
+        });
+    }
+    Ok(())
+}
+
+/// Download and verify the `[[composer]]` pins of the lockfile, they are
+/// mounted read-only into the composer cache at /extra/composer/
+pub async fn stage(packages: Vec<ComposerLock>, mounts: &mut Vec<Mount>) -> Result<()> {
+    if packages.is_empty() {
+        return Ok(());
+    }
+    for package in &packages {
+        if !valid_package_name(&package.name)
+            || !package.dist_type.chars().all(|c| c.is_ascii_alphanumeric())
+        {
+            bail!(
+                "Invalid composer package in lockfile: {:?} ({:?})",
+                package.name,
+                package.dist_type
+            );
+        }
+    }
+
+    let files = packages.iter().map(ComposerLock::file).collect::<Vec<_>>();
+    {
+        let _phase = summary::phase("download");
+        fetch::download_files(&files).await?;
+    }
+    {
+        let _phase = summary::phase("verify");
+        utils::spawn_blocking(move |_| fetch::verify_files(&files)).await?;
+    }
+
+    let pkgs_cache_dir = paths::pkgs_cache_dir()?;
+    for package in &packages {
+        let source = pkgs_cache_dir.sha256_path(&package.sha256)?;
+        mounts.push(Mount::read_only(
+            source,
+            format!("{COMPOSER_CACHE}/{}", cache_path(package)),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_composer_lock() -> Result<()> {
+        let lockfile = ComposerLockfile::deserialize(
+            r#"{
+    "_readme": ["This file locks the dependencies of your project to a known state"],
+    "content-hash": "5f0bd1d4fe7f2e3a1a71a6cd0f7fe9e3",
+    "packages": [
+        {
+            "name": "psr/log",
+            "version": "3.0.0",
+            "source": {
+                "type": "git",
+                "url": "https://github.com/php-fig/log.git",
+                "reference": "fe5ea303b0887d5caefd3d431c3e61ad47037001"
+            },
+            "dist": {
+                "type": "zip",
+                "url": "https://api.github.com/repos/php-fig/log/zipball/fe5ea303b0887d5caefd3d431c3e61ad47037001",
+                "reference": "fe5ea303b0887d5caefd3d431c3e61ad47037001",
+                "shasum": ""
+            },
+            "type": "library"
+        }
+    ],
+    "packages-dev": [
+        {
+            "name": "acme/local",
+            "version": "dev-main",
+            "dist": {
+                "type": "path",
+                "url": "../local",
+                "reference": "abc"
+            }
+        }
+    ],
+    "minimum-stability": "stable"
+}"#,
+        )?;
+        assert_eq!(lockfile.packages.len(), 1);
+        let dist = lockfile.packages[0].dist.as_ref().unwrap();
+        assert_eq!(dist.dist_type, "zip");
+        assert_eq!(dist.shasum.as_deref(), Some(""));
+        assert_eq!(lockfile.packages_dev[0].name, "acme/local");
+
+        let lock = ComposerLock {
+            name: "psr/log".to_string(),
+            version: "3.0.0".to_string(),
+            dist_type: "zip".to_string(),
+            url: dist.url.clone(),
+            sha256: "0".repeat(64),
+            size: 7_245,
+        };
+        let key = hex::encode(Sha1::digest(dist.url.as_bytes()));
+        assert_eq!(cache_path(&lock), format!("files/psr/log/{key}.zip"));
+        assert_eq!(lock.file().name, "psr-log-3.0.0.zip");
+        Ok(())
+    }
+
+    #[test]
+    fn test_valid_package_name() {
+        assert!(valid_package_name("symfony/polyfill-php80"));
+        assert!(!valid_package_name("symfony"));
+        assert!(!valid_package_name("../etc"));
+        assert!(!valid_package_name("symfony/../../etc"));
+        assert!(!valid_package_name("a/b/c"));
+    }
+}
@@ -1,13 +1,60 @@
 use crate::errors::*;
+use crate::limits::{self, Limits};
 use crate::pkgs::Pkg;
-use crate::utils;
-use flate2::read::GzDecoder;
+use flate2::bufread::GzDecoder;
 use std::io::{BufRead, BufReader, Read};
 
+#[derive(Debug, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+/// apks are a concatenation of compressed streams, newer abuild releases can use zstd for them
+pub fn detect_compression(buf: &[u8]) -> Result<Compression> {
+    if buf.starts_with(&[0x1F, 0x8B]) {
+        Ok(Compression::Gzip)
+    } else if buf.starts_with(&[0x28, 0xB5, 0x2F, 0xFD]) {
+        Ok(Compression::Zstd)
+    } else {
+        bail!(
+            "Unknown compression in apk section: {:02x?}",
+            &buf[..buf.len().min(4)]
+        )
+    }
+}
+
+/// Decompress the next section of an apk, the reader is left at the start of the following one
+pub fn read_section<R: BufRead>(reader: &mut R, limits: Limits) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    match detect_compression(reader.fill_buf()?)? {
+        Compression::Gzip => limits.wrap(GzDecoder::new(reader)).read_to_end(&mut buf)?,
+        Compression::Zstd => limits
+            .wrap(ruzstd::StreamingDecoder::new(reader)?)
+            .read_to_end(&mut buf)?,
+    };
+    Ok(buf)
+}
+
+/// Decompress the data section (the last one in the file)
+fn data_section<'a, R: BufRead + 'a>(mut reader: R) -> Result<Box<dyn Read + 'a>> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(Box::new(reader));
+    }
+    let limits = limits::PACKAGE;
+    Ok(match detect_compression(reader.fill_buf()?)? {
+        Compression::Gzip => Box::new(limits.wrap(GzDecoder::new(reader))),
+        Compression::Zstd => Box::new(limits.wrap(ruzstd::StreamingDecoder::new(reader)?)),
+    })
+}
+
 pub fn parse_pkginfo<R: Read>(reader: R) -> Result<Pkg> {
     let reader = BufReader::new(reader);
     let mut name = None;
     let mut version = None;
+    let mut arch = None;
+    let mut depends = Vec::new();
+    let mut provides = Vec::new();
 
     for line in reader.lines() {
         let line = line?;
@@ -16,21 +63,31 @@ pub fn parse_pkginfo<R: Read>(reader: R) -> Result<Pkg> {
             name = Some(value.to_string());
         } else if let Some(value) = line.strip_prefix("pkgver = ") {
             version = Some(value.to_string());
+        } else if let Some(value) = line.strip_prefix("arch = ") {
+            arch = Some(value.to_string());
+        } else if let Some(value) = line.strip_prefix("depend = ") {
+            depends.push(value.to_string());
+        } else if let Some(value) = line.strip_prefix("provides = ") {
+            provides.push(value.to_string());
         }
     }
 
     Ok(Pkg {
         name: name.context("Could not find pkgname in .PKGINFO")?,
         version: version.context("Could not find pkgver in .PKGINFO")?,
+        arch,
+        depends,
+        provides,
     })
 }
 
 pub fn parse<R: Read>(reader: R) -> Result<Pkg> {
     let mut r = BufReader::new(reader);
-    utils::read_gzip_to_end(&mut r).context("Failed to strip signature")?;
+    read_section(&mut r, limits::PACKAGE).context("Failed to strip signature")?;
+    let control =
+        read_section(&mut r, limits::PACKAGE).context("Failed to read control section")?;
 
-    let gz = GzDecoder::new(r);
-    let mut tar = tar::Archive::new(gz);
+    let mut tar = tar::Archive::new(&control[..]);
 
     for entry in tar.entries()? {
         let entry = entry?;
@@ -42,6 +99,17 @@ pub fn parse<R: Read>(reader: R) -> Result<Pkg> {
     bail!("Failed to find .PKGINFO in package file")
 }
 
+pub fn list_files<R: Read>(reader: R) -> Result<Vec<String>> {
+    let mut r = BufReader::new(reader);
+    read_section(&mut r, limits::PACKAGE).context("Failed to strip signature")?;
+    let control =
+        read_section(&mut r, limits::PACKAGE).context("Failed to read control section")?;
+
+    // the control and data section are separate streams that form a single tar
+    let data = data_section(r)?;
+    super::list_tar_files((&control[..]).chain(data), true)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -77,6 +145,15 @@ datahash = a2c44c6b313ca65980d7f610026a71e6119d119de6cf2b78e52464d9d80bff45
             Pkg {
                 name: "mpfr4".to_string(),
                 version: "4.2.0_p12-r0".to_string(),
+                arch: Some("x86_64".to_string()),
+                depends: vec![
+                    "so:libc.musl-x86_64.so.1".to_string(),
+                    "so:libgmp.so.10".to_string(),
+                ],
+                provides: vec![
+                    "mpfr=4.2.0_p12-r0".to_string(),
+                    "so:libmpfr.so.6=6.2.0".to_string(),
+                ],
             }
         );
         Ok(())
@@ -90,8 +167,72 @@ datahash = a2c44c6b313ca65980d7f610026a71e6119d119de6cf2b78e52464d9d80bff45
             Pkg {
                 name: "alpine-base".to_string(),
                 version: "3.18.3-r0".to_string(),
+                arch: Some("noarch".to_string()),
+                depends: [
+                    "alpine-baselayout",
+                    "alpine-conf",
+                    "alpine-release",
+                    "apk-tools",
+                    "busybox",
+                    "busybox-mdev-openrc",
+                    "busybox-openrc",
+                    "busybox-suid",
+                    "libc-utils",
+                    "openrc",
+                ]
+                .map(String::from)
+                .to_vec(),
+                provides: vec![],
             }
         );
         Ok(())
     }
+
+    /// Wrap data in a zstd frame made of uncompressed blocks
+    fn zstd_raw(data: &[u8]) -> Vec<u8> {
+        // magic, no flags, 128KiB window
+        let mut out = vec![0x28, 0xB5, 0x2F, 0xFD, 0x00, 0x38];
+        let mut chunks = data.chunks(128 * 1024).peekable();
+        while let Some(chunk) = chunks.next() {
+            let last = chunks.peek().is_none() as u32;
+            let header = ((chunk.len() as u32) << 3) | last;
+            out.extend(&header.to_le_bytes()[..3]);
+            out.extend(chunk);
+        }
+        out
+    }
+
+    #[test]
+    fn test_zstd_data_section() -> Result<()> {
+        let apk = crate::test_data::ALPINE_APK_EXAMPLE;
+        let mut r = apk;
+        read_section(&mut r, limits::PACKAGE)?;
+        read_section(&mut r, limits::PACKAGE)?;
+        let data_start = apk.len() - r.len();
+        let data = read_section(&mut r, limits::PACKAGE)?;
+        assert!(r.is_empty());
+
+        let mut zstd_apk = apk[..data_start].to_vec();
+        zstd_apk.extend(zstd_raw(&data));
+
+        assert_eq!(parse(&zstd_apk[..])?, parse(apk)?);
+        assert_eq!(list_files(&zstd_apk[..])?, list_files(apk)?);
+        #[cfg(feature = "cli")]
+        assert_eq!(
+            crate::resolver::alpine::calculate_checksum_for_apk(&zstd_apk)?,
+            crate::resolver::alpine::calculate_checksum_for_apk(apk)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_detect_compression() -> Result<()> {
+        assert_eq!(detect_compression(&[0x1F, 0x8B, 0x08])?, Compression::Gzip);
+        assert_eq!(
+            detect_compression(&[0x28, 0xB5, 0x2F, 0xFD])?,
+            Compression::Zstd
+        );
+        assert!(detect_compression(b"!<arch>\n").is_err());
+        Ok(())
+    }
 }
@@ -1,16 +1,25 @@
+#[cfg(feature = "cli")]
 use crate::container::Container;
 use crate::errors::*;
+use crate::limits::{self, Limits};
 use crate::pkgs::Pkg;
+use flate2::read::MultiGzDecoder;
 use peekread::{BufPeekReader, PeekRead};
+#[cfg(feature = "cli")]
 use std::fmt::Write;
-use std::io::{BufRead, BufReader, Read};
+use std::io::{BufRead, BufReader, Cursor, Read};
+#[cfg(feature = "cli")]
 use std::time::SystemTime;
+#[cfg(feature = "cli")]
 use std::time::UNIX_EPOCH;
 
 pub const GPG_CONF_DIR: &str = "/etc/pacman.d/gnupg/";
 pub const GPG_CONF_FILENAME: &str = "gpg.conf";
 
+#[derive(Debug, PartialEq)]
 pub enum Compression {
+    Gzip,
+    Lz4,
     Xz,
     Zstd,
     None,
@@ -27,16 +36,39 @@ pub fn detect_compression<R: Read>(mut reader: R) -> Result<Compression> {
         Ok(Compression::Zstd)
     } else if buf.starts_with(&[0xFD, 0x37, 0x7A, 0x58, 0x5A, 0x00]) {
         Ok(Compression::Xz)
+    } else if buf.starts_with(&[0x1F, 0x8B]) {
+        Ok(Compression::Gzip)
+    } else if buf.starts_with(&[0x04, 0x22, 0x4D, 0x18]) {
+        Ok(Compression::Lz4)
     } else {
         Ok(Compression::None)
     }
 }
 
+/// Detect the compression of a package or sync database and return the decompressed tar
+pub fn decompress<'a, R: Read + 'a>(reader: R, limits: Limits) -> Result<Box<dyn Read + 'a>> {
+    let mut reader = BufPeekReader::new(reader);
+    Ok(match detect_compression(reader.peek())? {
+        Compression::Gzip => Box::new(limits.wrap(MultiGzDecoder::new(reader))),
+        Compression::Lz4 => Box::new(limits.wrap(lz4_flex::frame::FrameDecoder::new(reader))),
+        Compression::Xz => {
+            let mut buf = limits.wrap(Vec::new());
+            lzma_rs::xz_decompress(&mut reader, &mut buf)?;
+            Box::new(Cursor::new(buf.into_inner()))
+        }
+        Compression::Zstd => Box::new(limits.wrap(ruzstd::StreamingDecoder::new(reader)?)),
+        Compression::None => Box::new(limits.wrap(reader)),
+    })
+}
+
 pub fn parse_pkginfo<R: Read>(reader: R) -> Result<Pkg> {
     let reader = BufReader::new(reader);
 
     let mut name = None;
     let mut version = None;
+    let mut arch = None;
+    let mut depends = Vec::new();
+    let mut provides = Vec::new();
 
     for line in reader.lines() {
         let line = line?;
@@ -45,18 +77,28 @@ pub fn parse_pkginfo<R: Read>(reader: R) -> Result<Pkg> {
             name = Some(value.to_string());
         } else if let Some(value) = line.strip_prefix("pkgver = ") {
             version = Some(value.to_string());
+        } else if let Some(value) = line.strip_prefix("arch = ") {
+            arch = Some(value.to_string());
+        } else if let Some(value) = line.strip_prefix("depend = ") {
+            depends.push(value.to_string());
+        } else if let Some(value) = line.strip_prefix("provides = ") {
+            provides.push(value.to_string());
         }
     }
 
     Ok(Pkg {
         name: name.context("Could not find pkgname in .PKGINFO")?,
         version: version.context("Could not find pkgver in .PKGINFO")?,
+        arch,
+        depends,
+        provides,
     })
 }
 
 pub fn parse_tar<R: Read>(reader: R) -> Result<Pkg> {
     let mut tar = tar::Archive::new(reader);
-    for entry in tar.entries()? {
+    for (idx, entry) in tar.entries()?.enumerate() {
+        limits::PACKAGE.check_entry(idx)?;
         let entry = entry?;
         let path = entry.path()?;
         if path.to_str() == Some(".PKGINFO") {
@@ -67,21 +109,14 @@ pub fn parse_tar<R: Read>(reader: R) -> Result<Pkg> {
 }
 
 pub fn parse<R: Read>(reader: R) -> Result<Pkg> {
-    let mut reader = BufPeekReader::new(reader);
-    match detect_compression(reader.peek())? {
-        Compression::Xz => {
-            let mut buf = Vec::new();
-            lzma_rs::xz_decompress(&mut reader, &mut buf)?;
-            parse_tar(&buf[..])
-        }
-        Compression::Zstd => {
-            let decoder = ruzstd::StreamingDecoder::new(reader)?;
-            parse_tar(decoder)
-        }
-        Compression::None => parse_tar(reader),
-    }
+    parse_tar(decompress(reader, limits::PACKAGE)?)
 }
 
+pub fn list_files<R: Read>(reader: R) -> Result<Vec<String>> {
+    super::list_tar_files(decompress(reader, limits::PACKAGE)?, true)
+}
+
+#[cfg(feature = "cli")]
 pub async fn set_pacman_verification_datetime(
     container: &Container,
     time: SystemTime,
@@ -117,6 +152,10 @@ pub async fn set_pacman_verification_datetime(
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[cfg(feature = "cli")]
+    use crate::fake_runtime::{self, FakeRuntime};
+    use flate2::write::GzEncoder;
+    use std::io::Write as _;
 
     #[test]
     fn test_parse_pkg() -> Result<()> {
@@ -180,9 +219,64 @@ checkdepend = tcl
             Pkg {
                 name: "gcc".to_string(),
                 version: "13.1.1-1".to_string(),
+                arch: Some("x86_64".to_string()),
+                depends: vec![
+                    "gcc-libs=13.1.1-1".to_string(),
+                    "binutils>=2.28".to_string(),
+                    "libmpc".to_string(),
+                    "zstd".to_string(),
+                    "libisl.so=23-64".to_string(),
+                ],
+                provides: vec!["gcc-multilib".to_string()],
             }
         );
 
+        let mut lz4 = lz4_flex::frame::FrameEncoder::new(Vec::new());
+        lz4.write_all(&archive)?;
+        let lz4 = lz4.finish()?;
+        assert_eq!(detect_compression(&lz4[..])?, Compression::Lz4);
+        assert_eq!(parse(&lz4[..])?, pkg);
+
+        let mut gz = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&archive)?;
+        let gz = gz.finish()?;
+        assert_eq!(detect_compression(&gz[..])?, Compression::Gzip);
+        assert_eq!(parse(&gz[..])?, pkg);
+
+        assert_eq!(detect_compression(&archive[..])?, Compression::None);
+        assert_eq!(parse(&archive[..])?, pkg);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "cli")]
+    #[tokio::test]
+    async fn test_set_pacman_verification_datetime() -> Result<()> {
+        let runtime = FakeRuntime::new();
+        runtime.reply(
+            &["container", "cp"],
+            fake_runtime::tar_file(GPG_CONF_FILENAME, b"no-greeting"),
+        );
+        let container = Container {
+            id: fake_runtime::CONTAINER_ID.to_string(),
+        };
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(1688457055);
+        runtime
+            .run(set_pacman_verification_datetime(&container, time))
+            .await?;
+
+        let calls = runtime.calls();
+        let written = calls
+            .iter()
+            .find_map(|call| call.stdin.as_ref())
+            .context("gpg.conf was not written")?;
+        assert_eq!(
+            fake_runtime::untar(written)?,
+            [(
+                GPG_CONF_FILENAME.to_string(),
+                b"no-greeting\nfaked-system-time 1688457055\n".to_vec()
+            )]
+        );
         Ok(())
     }
 }
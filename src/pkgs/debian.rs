@@ -1,11 +1,17 @@
 use crate::errors::*;
-use crate::pkgs::Pkg;
-use std::io::BufReader;
-use std::io::Read;
+use crate::limits;
+use crate::pkgs::{FileList, Pkg};
+use flate2::read::GzDecoder;
+use md5::{Digest, Md5};
+use std::collections::HashMap;
+use std::io::{self, BufReader, Read};
 
 pub fn parse_control(control: &str) -> Result<Pkg> {
     let mut name = None;
     let mut version = None;
+    let mut arch = None;
+    let mut depends = Vec::new();
+    let mut provides = Vec::new();
 
     for line in control.lines() {
         if let Some(value) = line.strip_prefix("Package: ") {
@@ -15,23 +21,58 @@ pub fn parse_control(control: &str) -> Result<Pkg> {
         if let Some(value) = line.strip_prefix("Version: ") {
             version = Some(value.to_string());
         }
+
+        if let Some(value) = line.strip_prefix("Architecture: ") {
+            arch = Some(value.to_string());
+        }
+
+        for field in ["Pre-Depends: ", "Depends: "] {
+            if let Some(value) = line.strip_prefix(field) {
+                depends.extend(value.split(',').map(|dep| dep.trim().to_string()));
+            }
+        }
+
+        if let Some(value) = line.strip_prefix("Provides: ") {
+            provides.extend(value.split(',').map(|dep| dep.trim().to_string()));
+        }
     }
 
     Ok(Pkg {
         name: name.context("Failed to find package name in deb control data")?,
         version: version.context("Failed to find package version in deb control data")?,
+        arch,
+        depends,
+        provides,
     })
 }
 
 pub fn parse_control_tar<R: Read>(filename: &[u8], reader: R) -> Result<Pkg> {
-    let mut buf = Vec::new();
-    let mut reader = BufReader::new(reader);
-    match filename {
-        b"control.tar.xz" => lzma_rs::xz_decompress(&mut reader, &mut buf)?,
-        _ => bail!("Unsupported compression for control.tar: {filename:?}"),
+    parse_control_data(&decompress_control_tar(filename, reader)?)
+}
+
+/// Find the control file in a decompressed control.tar and parse it
+fn parse_control_data(buf: &[u8]) -> Result<Pkg> {
+    parse_control(&read_control(buf)?)
+}
+
+/// The `Replaces:` and `Multi-Arch: same` fields of the control data
+fn parse_file_relations(control: &str) -> (Vec<String>, bool) {
+    let mut replaces = Vec::new();
+    let mut multi_arch_same = false;
+    for line in control.lines() {
+        if let Some(value) = line.strip_prefix("Replaces: ") {
+            replaces.extend(value.split(',').map(|dep| dep.trim().to_string()));
+        }
+        if line.strip_prefix("Multi-Arch: ") == Some("same") {
+            multi_arch_same = true;
+        }
     }
+    (replaces, multi_arch_same)
+}
 
-    let mut tar = tar::Archive::new(&buf[..]);
+/// Read the control file from a decompressed control.tar
+fn read_control(buf: &[u8]) -> Result<String> {
+    let mut tar = tar::Archive::new(buf);
     for entry in tar.entries()? {
         let mut entry = entry?;
         let path = entry.path()?;
@@ -39,29 +80,216 @@ pub fn parse_control_tar<R: Read>(filename: &[u8], reader: R) -> Result<Pkg> {
             .to_str()
             .with_context(|| anyhow!("Package contains paths with invalid encoding: {:?}", path))?;
 
-        if filename == "./control" {
+        if filename.strip_prefix("./").unwrap_or(filename) == "control" {
             let mut buf = String::new();
             entry.read_to_string(&mut buf)?;
-            return parse_control(&buf);
+            return Ok(buf);
         }
     }
 
     bail!("Failed to find control data in control.tar")
 }
 
+fn decompress_control_tar<R: Read>(filename: &[u8], reader: R) -> Result<Vec<u8>> {
+    let limits = limits::PACKAGE;
+    let mut buf = limits.wrap(Vec::new());
+    let mut reader = BufReader::new(reader);
+    match filename {
+        b"control.tar.xz" => lzma_rs::xz_decompress(&mut reader, &mut buf)?,
+        b"control.tar.gz" => {
+            io::copy(&mut GzDecoder::new(reader), &mut buf)?;
+        }
+        b"control.tar.zst" => {
+            io::copy(&mut ruzstd::StreamingDecoder::new(reader)?, &mut buf)?;
+        }
+        b"control.tar" => {
+            io::copy(&mut reader, &mut buf)?;
+        }
+        _ => bail!("Unsupported compression for control.tar: {filename:?}"),
+    }
+    Ok(buf.into_inner())
+}
+
+/// Find control.tar and decompress it, the archive is left at the following entry
+fn read_control_tar<R: Read>(archive: &mut ar::Archive<R>) -> Result<Vec<u8>> {
+    while let Some(entry) = archive.next_entry() {
+        let mut entry = entry?;
+        let filename = entry.header().identifier().to_owned();
+        if filename.starts_with(b"control.tar") {
+            return decompress_control_tar(&filename, &mut entry);
+        }
+    }
+
+    bail!("Failed to find control data")
+}
+
 pub fn parse<R: Read>(reader: R) -> Result<Pkg> {
+    let control = read_control_tar(&mut ar::Archive::new(reader))?;
+    parse_control_data(&control)
+}
+
+/// Parse the metadata and verify the contents of a package, in a single pass
+/// over the archive (control.tar comes before data.tar)
+pub fn inspect<R: Read + Send>(reader: R) -> Result<Pkg> {
     let mut archive = ar::Archive::new(reader);
+    let control = read_control_tar(&mut archive)?;
+    let pkg = parse_control_data(&control).context("Failed to parse data as debian package")?;
+    verify_data_tar(archive, &read_control_files(&control)?)
+        .context("Failed to verify debian package contents against md5sums")?;
+    Ok(pkg)
+}
+
+/// Call `f` with a reader for the decompressed data.tar
+fn with_data_tar<R: Read + Send, T, F: FnOnce(&mut dyn Read) -> Result<T>>(
+    mut archive: ar::Archive<R>,
+    f: F,
+) -> Result<T> {
     while let Some(entry) = archive.next_entry() {
+        let entry = entry?;
+        let filename = entry.header().identifier().to_owned();
+        let reader = BufReader::new(entry);
+        let limits = limits::PACKAGE;
+        match &filename[..] {
+            b"data.tar" => return f(&mut limits.wrap(reader)),
+            b"data.tar.gz" => return f(&mut limits.wrap(GzDecoder::new(reader))),
+            b"data.tar.xz" => return super::with_xz_reader(reader, |r| f(&mut limits.wrap(r))),
+            b"data.tar.zst" => return f(&mut limits.wrap(ruzstd::StreamingDecoder::new(reader)?)),
+            name if name.starts_with(b"data.tar") => {
+                bail!("Unsupported compression for data.tar: {name:?}")
+            }
+            _ => continue,
+        }
+    }
+
+    bail!("Failed to find data.tar in deb")
+}
+
+pub fn list_files<R: Read + Send>(reader: R) -> Result<FileList> {
+    let mut archive = ar::Archive::new(reader);
+    let control = read_control(&read_control_tar(&mut archive)?)?;
+    let (replaces, multi_arch_same) = parse_file_relations(&control);
+    let files = with_data_tar(archive, |data| super::list_tar_files(data, false))?;
+    Ok(FileList {
+        files,
+        replaces,
+        multi_arch_same,
+    })
+}
+
+/// The `md5sums` and `conffiles` of a package, paths are without leading slash
+#[derive(Debug, Default, PartialEq)]
+pub struct ControlFiles {
+    pub md5sums: Option<Vec<(String, String)>>,
+    pub conffiles: Vec<String>,
+}
+
+pub fn parse_md5sums(buf: &str) -> Result<Vec<(String, String)>> {
+    buf.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (md5, path) = line
+                .split_once("  ")
+                .with_context(|| anyhow!("Invalid line in md5sums: {line:?}"))?;
+            if md5.len() != 32 || !md5.bytes().all(|b| b.is_ascii_hexdigit()) {
+                bail!("Invalid md5 in md5sums: {line:?}");
+            }
+            Ok((path.to_string(), md5.to_ascii_lowercase()))
+        })
+        .collect()
+}
+
+/// Read the md5sums and conffiles from a decompressed control.tar
+fn read_control_files(buf: &[u8]) -> Result<ControlFiles> {
+    let mut files = ControlFiles::default();
+    let mut tar = tar::Archive::new(buf);
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        match path.strip_prefix("./").unwrap_or(&path) {
+            "md5sums" => {
+                let mut buf = String::new();
+                entry.read_to_string(&mut buf)?;
+                files.md5sums = Some(parse_md5sums(&buf)?);
+            }
+            "conffiles" => {
+                let mut buf = String::new();
+                entry.read_to_string(&mut buf)?;
+                files.conffiles = buf
+                    .lines()
+                    .filter_map(|line| line.strip_prefix('/'))
+                    .map(String::from)
+                    .collect();
+            }
+            _ => (),
+        }
+    }
+    Ok(files)
+}
+
+/// Hash all regular files in data.tar, hardlinks get the hash of their target
+fn hash_data_tar(data: &mut dyn Read) -> Result<HashMap<String, String>> {
+    let mut hashes = HashMap::new();
+    let mut links = Vec::new();
+    let mut tar = tar::Archive::new(data);
+    for (idx, entry) in tar.entries()?.enumerate() {
+        limits::PACKAGE.check_entry(idx)?;
         let mut entry = entry?;
-        let filename = entry.header().identifier();
-        if !filename.starts_with(b"control.tar") {
-            continue;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let path = path.strip_prefix("./").unwrap_or(&path).to_string();
+        match entry.header().entry_type() {
+            tar::EntryType::Regular => {
+                let mut md5 = Md5::new();
+                std::io::copy(&mut entry, &mut md5)?;
+                hashes.insert(path, hex::encode(md5.finalize()));
+            }
+            tar::EntryType::Link => {
+                if let Some(target) = entry.link_name()? {
+                    let target = target.to_string_lossy().into_owned();
+                    let target = target.strip_prefix("./").unwrap_or(&target).to_string();
+                    links.push((path, target));
+                }
+            }
+            _ => (),
         }
-        let filename = filename.to_owned();
-        return parse_control_tar(&filename, &mut entry);
     }
+    for (path, target) in links {
+        if let Some(md5) = hashes.get(&target).cloned() {
+            hashes.insert(path, md5);
+        }
+    }
+    Ok(hashes)
+}
 
-    bail!("Failed to find control data")
+/// Verify the files in data.tar against the md5sums and conffiles in
+/// control.tar, packages without md5sums are accepted
+pub fn verify_contents<R: Read + Send>(deb: R) -> Result<()> {
+    let mut archive = ar::Archive::new(deb);
+    let control = read_control_tar(&mut archive)?;
+    verify_data_tar(archive, &read_control_files(&control)?)
+}
+
+/// Hash the remaining data.tar of the archive and compare it with the control files
+fn verify_data_tar<R: Read + Send>(archive: ar::Archive<R>, control: &ControlFiles) -> Result<()> {
+    let Some(md5sums) = &control.md5sums else {
+        debug!("Package has no md5sums, skipping verification of contents");
+        return Ok(());
+    };
+
+    let hashes = with_data_tar(archive, hash_data_tar)?;
+    for (path, expected) in md5sums {
+        let Some(md5) = hashes.get(path) else {
+            bail!("File listed in md5sums is missing from data.tar: {path:?}");
+        };
+        if md5 != expected {
+            bail!("Mismatch of md5 for {path:?}: expected={expected:?}, found={md5:?}");
+        }
+    }
+    for path in &control.conffiles {
+        if !hashes.contains_key(path) {
+            bail!("File listed in conffiles is missing from data.tar: {path:?}");
+        }
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -77,11 +305,33 @@ mod tests {
             Pkg {
                 name: "binutils-common".to_string(),
                 version: "2.40-2".to_string(),
+                arch: Some("amd64".to_string()),
+                depends: vec![],
+                provides: vec![],
             }
         );
         Ok(())
     }
 
+    #[test]
+    fn test_parse_file_relations() {
+        let data = "Package: binutils-common\nVersion: 2.40-2\nReplaces: binutils (<< 2.38.50.20220527-2), binutils-multiarch (<< 2.38.50.20220527-2)\nMulti-Arch: same\n";
+        assert_eq!(
+            parse_file_relations(data),
+            (
+                vec![
+                    "binutils (<< 2.38.50.20220527-2)".to_string(),
+                    "binutils-multiarch (<< 2.38.50.20220527-2)".to_string(),
+                ],
+                true
+            )
+        );
+        assert_eq!(
+            parse_file_relations("Package: gcc\nMulti-Arch: foreign\n"),
+            (vec![], false)
+        );
+    }
+
     #[test]
     fn test_parse_deb() -> Result<()> {
         let tar = {
@@ -155,9 +405,105 @@ mod tests {
             Pkg {
                 name: "binutils-common".to_string(),
                 version: "2.40-2".to_string(),
+                arch: Some("amd64".to_string()),
+                depends: vec![],
+                provides: vec![],
             }
         );
 
         Ok(())
     }
+
+    fn tar(files: &[(&str, &[u8])]) -> Result<Vec<u8>> {
+        let mut tar = tar::Builder::new(Vec::new());
+        for (path, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, path, *data)?;
+        }
+        Ok(tar.into_inner()?)
+    }
+
+    fn deb(md5sums: &str, data: &[(&str, &[u8])]) -> Result<Vec<u8>> {
+        let control = tar(&[
+            ("md5sums", md5sums.as_bytes()),
+            ("conffiles", b"/etc/hello.conf\n"),
+        ])?;
+        let data = tar(data)?;
+        let mut ar = ar::Builder::new(Vec::new());
+        for (name, buf) in [("control.tar", &control), ("data.tar", &data)] {
+            let header = ar::Header::new(name.as_bytes().to_vec(), buf.len() as u64);
+            ar.append(&header, &buf[..])?;
+        }
+        Ok(ar.into_inner()?)
+    }
+
+    #[test]
+    fn test_parse_md5sums() -> Result<()> {
+        let md5sums = parse_md5sums("b1946ac92492d2347c6235b4d2611184  usr/bin/hello\n")?;
+        assert_eq!(
+            md5sums,
+            [(
+                "usr/bin/hello".to_string(),
+                "b1946ac92492d2347c6235b4d2611184".to_string()
+            )]
+        );
+        assert!(parse_md5sums("b1946ac92492d2347c6235b4d2611184 usr/bin/hello\n").is_err());
+        assert!(parse_md5sums("xyz  usr/bin/hello\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_contents() -> Result<()> {
+        // md5("hello\n")
+        let md5sums = "b1946ac92492d2347c6235b4d2611184  usr/bin/hello\n";
+        let conf: (&str, &[u8]) = ("etc/hello.conf", b"greeting=1\n");
+
+        verify_contents(&deb(md5sums, &[("usr/bin/hello", b"hello\n"), conf])?[..])?;
+
+        // modified file
+        let modified = deb(md5sums, &[("usr/bin/hello", b"evil\n"), conf])?;
+        assert!(verify_contents(&modified[..]).is_err());
+
+        // missing file
+        let missing = deb(md5sums, &[conf])?;
+        assert!(verify_contents(&missing[..]).is_err());
+
+        // missing conffile
+        let missing = deb(md5sums, &[("usr/bin/hello", b"hello\n")])?;
+        assert!(verify_contents(&missing[..]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_inspect_xz_data() -> Result<()> {
+        let control = tar(&[
+            (
+                "control",
+                b"Package: hello\nVersion: 1.0-1\nArchitecture: all\n",
+            ),
+            (
+                "md5sums",
+                b"b1946ac92492d2347c6235b4d2611184  usr/bin/hello\n",
+            ),
+        ])?;
+        let xz_deb = |data: &[u8]| -> Result<Vec<u8>> {
+            let mut compressed = Vec::new();
+            lzma_rs::xz_compress(&mut &tar(&[("usr/bin/hello", data)])?[..], &mut compressed)?;
+            let mut ar = ar::Builder::new(Vec::new());
+            for (name, buf) in [("control.tar", &control), ("data.tar.xz", &compressed)] {
+                let header = ar::Header::new(name.as_bytes().to_vec(), buf.len() as u64);
+                ar.append(&header, &buf[..])?;
+            }
+            Ok(ar.into_inner()?)
+        };
+
+        let pkg = inspect(&xz_deb(b"hello\n")?[..])?;
+        assert_eq!(pkg.name, "hello");
+        assert_eq!(pkg.version, "1.0-1");
+        assert!(inspect(&xz_deb(b"evil\n")?[..]).is_err());
+        Ok(())
+    }
 }
@@ -1,9 +1,319 @@
 pub mod alpine;
 pub mod archlinux;
 pub mod debian;
+pub mod openwrt;
 
-#[derive(Debug, PartialEq)]
+use crate::errors::*;
+use crate::limits;
+use crate::manifest;
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, Read};
+use std::sync::mpsc;
+use std::thread;
+
+/// Number of decompressed chunks that may be buffered between the xz decoder and the reader
+const XZ_CHUNKS: usize = 16;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Pkg {
     pub name: String,
     pub version: String,
+    pub arch: Option<String>,
+    /// The declared dependencies, as written in the package metadata
+    pub depends: Vec<String>,
+    /// Virtual packages provided by this package, as written in the package metadata
+    pub provides: Vec<String>,
+}
+
+/// The name a package system uses for the architecture repro-env runs on
+pub fn native_arch(system: &str) -> Option<&'static str> {
+    let arch = match (system, std::env::consts::ARCH) {
+        ("debian" | "snap", "x86_64") => "amd64",
+        ("debian" | "snap", "aarch64") => "arm64",
+        ("debian" | "snap", "x86") => "i386",
+        ("debian" | "snap", "arm") => "armhf",
+        ("debian" | "snap", "powerpc64") => "ppc64el",
+        ("alpine", "arm") => "armv7",
+        ("alpine", "powerpc64") => "ppc64le",
+        ("alpine" | "archlinux" | "debian" | "snap", arch) => arch,
+        _ => return None,
+    };
+    Some(arch)
+}
+
+/// Check if a package of this architecture can be installed on the architecture repro-env runs on
+pub fn is_native_arch(system: &str, arch: &str) -> bool {
+    if matches!(arch, "all" | "any" | "noarch") {
+        return true;
+    }
+    native_arch(system).is_none_or(|native| native == arch)
+}
+
+/// Parse the embedded metadata of a package, debian packages are also checked against their md5sums
+pub fn inspect<R: Read + Send>(system: &str, pkg: R) -> Result<Pkg> {
+    let pkg = match system {
+        "alpine" => alpine::parse(pkg).context("Failed to parse data as alpine package")?,
+        "archlinux" => {
+            archlinux::parse(pkg).context("Failed to parse data as archlinux package")?
+        }
+        "debian" => debian::inspect(pkg)?,
+        "openwrt" => openwrt::parse(pkg).context("Failed to parse data as openwrt package")?,
+        system => bail!("Unknown package system: {system:?}"),
+    };
+    Ok(pkg)
+}
+
+/// The files of a package, with the metadata that's needed to decide if two
+/// packages installing the same file is a conflict
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FileList {
+    pub files: Vec<String>,
+    /// Packages this one may overwrite files of (debian `Replaces:`)
+    #[serde(default)]
+    pub replaces: Vec<String>,
+    /// Debian `Multi-Arch: same`, the same files are shared by each architecture of the package
+    #[serde(default)]
+    pub multi_arch_same: bool,
+}
+
+/// List the regular files (and symlinks) in the data section of a package archive
+pub fn list_files<R: Read + Send>(system: &str, pkg: R) -> Result<FileList> {
+    let mut list = match system {
+        "alpine" => FileList {
+            files: alpine::list_files(pkg)?,
+            ..Default::default()
+        },
+        "archlinux" => FileList {
+            files: archlinux::list_files(pkg)?,
+            ..Default::default()
+        },
+        "debian" => debian::list_files(pkg)?,
+        "openwrt" => FileList {
+            files: openwrt::list_files(pkg)?,
+            ..Default::default()
+        },
+        system => bail!("Unknown package system: {system:?}"),
+    };
+    list.files.sort();
+    list.files.dedup();
+    Ok(list)
+}
+
+/// The package names a dependency (or provides entry) refers to, without
+/// version constraints. Debian (and opkg) alternatives (`a | b`) return all names.
+pub fn dependency_names<'a>(system: &str, dep: &'a str) -> Vec<&'a str> {
+    match system {
+        "debian" | "openwrt" => dep
+            .split('|')
+            .map(|alt| {
+                let alt = alt.trim();
+                let alt = alt.split_once(' ').map_or(alt, |(name, _)| name);
+                alt.split_once(':').map_or(alt, |(name, _)| name)
+            })
+            .collect(),
+        "conda" => vec![manifest::conda_spec_name(dep)],
+        // alpine conflicts start with `!`
+        _ if dep.starts_with('!') => vec![],
+        _ => {
+            let end = dep.find(['<', '>', '=', '~']).unwrap_or(dep.len());
+            vec![&dep[..end]]
+        }
+    }
+}
+
+struct ChunkWriter<'a> {
+    tx: &'a mpsc::SyncSender<io::Result<Vec<u8>>>,
+}
+
+impl io::Write for ChunkWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tx
+            .send(Ok(buf.to_vec()))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Reader has been dropped"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct ChunkReader {
+    rx: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.chunk.len() {
+            match self.rx.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                // the decoder is done
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// lzma-rs can only decompress xz into a writer, run the decoder on a separate
+/// thread and pass its output to `f` in chunks, so it's never fully in memory
+pub fn with_xz_reader<R: BufRead + Send, T, F: FnOnce(&mut dyn Read) -> Result<T>>(
+    mut reader: R,
+    f: F,
+) -> Result<T> {
+    let (tx, rx) = mpsc::sync_channel(XZ_CHUNKS);
+    thread::scope(|s| {
+        s.spawn(move || {
+            let mut writer = io::BufWriter::with_capacity(64 * 1024, ChunkWriter { tx: &tx });
+            let result = lzma_rs::xz_decompress(&mut reader, &mut writer)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+                .and_then(|_| {
+                    writer
+                        .into_inner()
+                        .map(|_| ())
+                        .map_err(|err| err.into_error())
+                });
+            if let Err(err) = result {
+                // fails if the reader has already been dropped, nobody is interested anymore
+                tx.send(Err(err)).ok();
+            }
+        });
+        let mut reader = ChunkReader {
+            rx,
+            chunk: Vec::new(),
+            pos: 0,
+        };
+        f(&mut reader)
+    })
+}
+
+/// Collect the paths of all non-directory entries, metadata files at the top-level are skipped
+fn list_tar_files<R: Read>(reader: R, skip_metadata: bool) -> Result<Vec<String>> {
+    let mut tar = tar::Archive::new(reader);
+    let mut files = Vec::new();
+    for (idx, entry) in tar.entries()?.enumerate() {
+        limits::PACKAGE.check_entry(idx)?;
+        let entry = entry?;
+        if entry.header().entry_type().is_dir() {
+            continue;
+        }
+        let path = entry.path()?;
+        let path = path
+            .to_str()
+            .with_context(|| anyhow!("Package contains paths with invalid encoding: {path:?}"))?;
+        let path = path.strip_prefix("./").unwrap_or(path);
+        if skip_metadata && path.starts_with('.') && !path.contains('/') {
+            continue;
+        }
+        files.push(format!("/{path}"));
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dependency_names() {
+        assert_eq!(
+            dependency_names("archlinux", "gcc-libs=13.1.1-1"),
+            ["gcc-libs"]
+        );
+        assert_eq!(
+            dependency_names("archlinux", "binutils>=2.28"),
+            ["binutils"]
+        );
+        assert_eq!(
+            dependency_names("alpine", "so:libmpfr.so.6=6.2.0"),
+            ["so:libmpfr.so.6"]
+        );
+        assert!(dependency_names("alpine", "!musl-dev").is_empty());
+        assert_eq!(dependency_names("debian", "libc6 (>= 2.34)"), ["libc6"]);
+        assert_eq!(
+            dependency_names("debian", "python3:any | python3-minimal (>= 3.11)"),
+            ["python3", "python3-minimal"]
+        );
+        assert_eq!(
+            dependency_names("conda", "__glibc >=2.17,<3.0.a0"),
+            ["__glibc"]
+        );
+    }
+
+    #[test]
+    fn test_is_native_arch() {
+        assert!(is_native_arch("debian", "all"));
+        assert!(is_native_arch("archlinux", "any"));
+        assert!(is_native_arch("gentoo", "sparc"));
+        if cfg!(target_arch = "x86_64") {
+            assert!(is_native_arch("debian", "amd64"));
+            assert!(is_native_arch("alpine", "x86_64"));
+            assert!(!is_native_arch("debian", "arm64"));
+            assert!(!is_native_arch("archlinux", "aarch64"));
+        }
+    }
+
+    #[test]
+    fn test_list_tar_files() -> Result<()> {
+        let tar = {
+            let mut tar = tar::Builder::new(Vec::new());
+            for (path, entry_type) in [
+                (".PKGINFO", tar::EntryType::Regular),
+                ("usr/", tar::EntryType::Directory),
+                ("usr/bin/", tar::EntryType::Directory),
+                ("usr/bin/gcc", tar::EntryType::Regular),
+                ("usr/bin/cc", tar::EntryType::Symlink),
+            ] {
+                let mut header = tar::Header::new_gnu();
+                header.set_path(path)?;
+                header.set_entry_type(entry_type);
+                header.set_size(0);
+                header.set_cksum();
+                tar.append(&header, &[][..])?;
+            }
+            tar.into_inner()?
+        };
+
+        let files = list_tar_files(&tar[..], true)?;
+        assert_eq!(files, vec!["/usr/bin/gcc", "/usr/bin/cc"]);
+
+        let files = list_tar_files(&tar[..], false)?;
+        assert_eq!(files, vec!["/.PKGINFO", "/usr/bin/gcc", "/usr/bin/cc"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_xz_reader() -> Result<()> {
+        let data = (0..200_000u32)
+            .flat_map(|i| i.to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut xz = Vec::new();
+        lzma_rs::xz_compress(&mut &data[..], &mut xz)?;
+
+        let read_all = |r: &mut dyn Read| -> Result<Vec<u8>> {
+            let mut buf = Vec::new();
+            r.read_to_end(&mut buf)?;
+            Ok(buf)
+        };
+        assert_eq!(with_xz_reader(&xz[..], read_all)?, data);
+
+        // stopping early doesn't block the decoder
+        let first = with_xz_reader(&xz[..], |r| {
+            let mut buf = [0; 4];
+            r.read_exact(&mut buf)?;
+            Ok(buf)
+        })?;
+        assert_eq!(first, [0, 0, 0, 0]);
+
+        assert!(with_xz_reader(&xz[..xz.len() / 2], read_all).is_err());
+        Ok(())
+    }
 }
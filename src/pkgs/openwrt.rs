@@ -0,0 +1,96 @@
+use crate::errors::*;
+use crate::limits;
+use crate::pkgs::{debian, Pkg};
+use flate2::read::GzDecoder;
+use std::io::{BufReader, Read};
+
+/// ipks are a gzip compressed tar of debian-binary, control.tar.gz and
+/// data.tar.gz, call `f` with each member until it returns something
+fn find_member<R: Read, T, F: FnMut(&str, &mut dyn Read) -> Result<Option<T>>>(
+    reader: R,
+    mut f: F,
+) -> Result<Option<T>> {
+    let limits = limits::PACKAGE;
+    let mut tar = tar::Archive::new(limits.wrap(GzDecoder::new(BufReader::new(reader))));
+    for (idx, entry) in tar.entries()?.enumerate() {
+        limits.check_entry(idx)?;
+        let mut entry = entry?;
+        let path = entry.path()?;
+        let name = path
+            .to_str()
+            .with_context(|| anyhow!("Package contains paths with invalid encoding: {path:?}"))?;
+        let name = name.strip_prefix("./").unwrap_or(name).to_string();
+        if let Some(value) = f(&name, &mut entry)? {
+            return Ok(Some(value));
+        }
+    }
+    Ok(None)
+}
+
+pub fn parse<R: Read>(reader: R) -> Result<Pkg> {
+    find_member(reader, |name, member| {
+        if name != "control.tar.gz" {
+            return Ok(None);
+        }
+        debian::parse_control_tar(b"control.tar.gz", member).map(Some)
+    })?
+    .context("Failed to find control.tar.gz in ipk")
+}
+
+pub fn list_files<R: Read>(reader: R) -> Result<Vec<String>> {
+    find_member(reader, |name, member| {
+        if name != "data.tar.gz" {
+            return Ok(None);
+        }
+        super::list_tar_files(GzDecoder::new(member), false).map(Some)
+    })?
+    .context("Failed to find data.tar.gz in ipk")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn tar_gz(files: &[(&str, &[u8])]) -> Result<Vec<u8>> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (path, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, *data)?;
+        }
+        let mut encoder = builder.into_inner()?;
+        encoder.flush()?;
+        Ok(encoder.finish()?)
+    }
+
+    #[test]
+    fn test_parse_ipk() -> Result<()> {
+        let control = tar_gz(&[(
+            "./control",
+            b"Package: luci-base\nVersion: git-24.086.45142-09d5a38\nDepends: libc, lua, luci-lib-base, rpcd\nSource: feeds/luci/modules/luci-base\nArchitecture: x86_64\n",
+        )])?;
+        let data = tar_gz(&[("./www/luci-static/resources/luci.js", b"'use strict';\n")])?;
+        let ipk = tar_gz(&[
+            ("./debian-binary", b"2.0\n"),
+            ("./data.tar.gz", &data),
+            ("./control.tar.gz", &control),
+        ])?;
+
+        let pkg = parse(&ipk[..])?;
+        assert_eq!(pkg.name, "luci-base");
+        assert_eq!(pkg.version, "git-24.086.45142-09d5a38");
+        assert_eq!(pkg.arch.as_deref(), Some("x86_64"));
+        assert_eq!(pkg.depends, ["libc", "lua", "luci-lib-base", "rpcd"]);
+
+        assert_eq!(
+            list_files(&ipk[..])?,
+            ["/www/luci-static/resources/luci.js"]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,198 @@
+//! Make sure the image can run on this machine before creating a container,
+//! foreign architectures need a binfmt_misc handler (usually qemu-user-static)
+use crate::errors::*;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+const BINFMT_MISC: &str = "/proc/sys/fs/binfmt_misc";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Platform {
+    pub os: String,
+    pub arch: String,
+    pub variant: Option<String>,
+}
+
+impl FromStr for Platform {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split('/');
+        let (Some(os), Some(arch)) = (parts.next(), parts.next()) else {
+            bail!("Invalid platform, expected os/arch[/variant]: {s:?}");
+        };
+        let variant = parts.next().map(String::from);
+        if os.is_empty() || arch.is_empty() || parts.next().is_some() {
+            bail!("Invalid platform, expected os/arch[/variant]: {s:?}");
+        }
+        Ok(Platform {
+            os: os.to_string(),
+            arch: arch.to_string(),
+            variant,
+        })
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        write!(w, "{}/{}", self.os, self.arch)?;
+        if let Some(variant) = &self.variant {
+            write!(w, "/{variant}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Platform {
+    /// If an image of `image` satisfies this platform, a missing variant matches any
+    pub fn matches(&self, image: &Platform) -> bool {
+        self.os == image.os
+            && self.arch == image.arch
+            && (self.variant.is_none() || self.variant == image.variant)
+    }
+}
+
+/// Translate rust's architecture names to the ones used by OCI images
+pub fn oci_arch(arch: &str) -> &str {
+    match arch {
+        "x86_64" => "amd64",
+        "x86" => "386",
+        "aarch64" => "arm64",
+        "powerpc64" => "ppc64le",
+        "loongarch64" => "loong64",
+        other => other,
+    }
+}
+
+/// The name qemu uses for an OCI architecture, as found in binfmt_misc entries
+fn qemu_arch(arch: &str) -> &str {
+    match arch {
+        "amd64" => "x86_64",
+        "386" => "i386",
+        "arm64" => "aarch64",
+        "loong64" => "loongarch64",
+        other => other,
+    }
+}
+
+pub fn host() -> Platform {
+    Platform {
+        os: env::consts::OS.to_string(),
+        arch: oci_arch(env::consts::ARCH).to_string(),
+        variant: None,
+    }
+}
+
+/// The names of the enabled binfmt_misc handlers, like `qemu-aarch64`
+pub fn binfmt_handlers() -> Vec<String> {
+    let Ok(dir) = fs::read_dir(BINFMT_MISC) else {
+        return vec![];
+    };
+    dir.filter_map(|entry| entry.ok())
+        .filter(|entry| is_enabled(&entry.path()))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name != "status" && name != "register")
+        .collect()
+}
+
+fn is_enabled(path: &Path) -> bool {
+    fs::read_to_string(path)
+        .map(|buf| buf.lines().next() == Some("enabled"))
+        .unwrap_or(false)
+}
+
+/// Check the platform of the image against the requested one (or the host)
+pub fn check(
+    image: &Platform,
+    requested: Option<&Platform>,
+    host: &Platform,
+    binfmt_handlers: &[String],
+) -> Result<()> {
+    if let Some(requested) = requested {
+        if !requested.matches(image) {
+            bail!("Image is {image}, but {requested} was requested with --platform");
+        }
+    }
+
+    if image.os != host.os {
+        bail!(
+            "Image is {image}, but containers on this machine run on {}",
+            host.os
+        );
+    }
+    if image.arch != host.arch {
+        let qemu = qemu_arch(&image.arch);
+        let handler = binfmt_handlers.iter().find(|name| {
+            name.strip_prefix("qemu-")
+                .is_some_and(|name| name == qemu || name.strip_suffix("-static") == Some(qemu))
+        });
+        match handler {
+            Some(handler) => {
+                info!("Image is {image}, running it with binfmt_misc handler {handler:?}")
+            }
+            None => bail!(
+                "Image is {image} but no binfmt handler for {qemu} was found in {BINFMT_MISC} (install qemu-user-static to run foreign architectures)"
+            ),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn platform(s: &str) -> Platform {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_platform() {
+        assert_eq!(
+            platform("linux/arm64/v8"),
+            Platform {
+                os: "linux".to_string(),
+                arch: "arm64".to_string(),
+                variant: Some("v8".to_string()),
+            }
+        );
+        assert_eq!(platform("linux/amd64").to_string(), "linux/amd64");
+        assert!("linux".parse::<Platform>().is_err());
+        assert!("linux/".parse::<Platform>().is_err());
+        assert!("linux/arm/v7/x".parse::<Platform>().is_err());
+    }
+
+    #[test]
+    fn test_check_platform() {
+        let host = platform("linux/amd64");
+        let handlers = ["qemu-aarch64".to_string(), "qemu-riscv64".to_string()];
+
+        assert!(check(&host, None, &host, &[]).is_ok());
+        assert!(check(&platform("linux/arm64/v8"), None, &host, &handlers).is_ok());
+
+        let err = check(&platform("linux/arm64"), None, &host, &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Image is linux/arm64 but no binfmt handler for aarch64 was found in /proc/sys/fs/binfmt_misc (install qemu-user-static to run foreign architectures)"
+        );
+        assert!(check(&platform("linux/s390x"), None, &host, &handlers).is_err());
+        assert!(check(&platform("windows/amd64"), None, &host, &handlers).is_err());
+
+        let requested = platform("linux/arm64");
+        let err = check(&host, Some(&requested), &host, &handlers).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Image is linux/amd64, but linux/arm64 was requested with --platform"
+        );
+        assert!(check(
+            &platform("linux/arm64/v8"),
+            Some(&requested),
+            &host,
+            &handlers
+        )
+        .is_ok());
+    }
+}
@@ -0,0 +1,143 @@
+use crate::args;
+use crate::build;
+use crate::consistency;
+use crate::container::{self, Container, Mount};
+use crate::errors::*;
+use crate::haskell;
+use crate::install;
+use crate::java;
+use crate::local::LocalConfig;
+use crate::paths;
+use crate::perl;
+use crate::php;
+use crate::refs;
+use crate::ruby;
+use crate::state::{self, ContainerRecord};
+use crate::summary;
+use crate::texlive;
+use crate::utils;
+use std::env;
+
+pub async fn prepare(prepare: &args::Prepare) -> Result<()> {
+    let _cache_lock = utils::lock_shared(paths::cache_lock()?).await?;
+
+    let (manifest, lockfile_path, mut lockfile) = args::load_files(
+        prepare.file.as_deref(),
+        prepare.variant.as_deref(),
+        !prepare.no_search_parents,
+    )
+    .await?;
+    LocalConfig::load_for_lockfile(&lockfile_path)?;
+    build::check_manifest(manifest.as_ref(), &lockfile, prepare.frozen)?;
+    lockfile.select_profile(prepare.profile.as_deref())?;
+    let arch = consistency::check_packages(&lockfile.packages)?;
+
+    let lockfile_digest = lockfile.digest();
+    if let Err(err) = refs::record(&lockfile_path, &lockfile.packages, &lockfile.all_files()).await
+    {
+        warn!("Failed to record package references of lockfile: {err:#}");
+    }
+
+    if let Some(id) = container::find_prepared(&lockfile_digest).await? {
+        info!("Found prepared container for this lockfile: {id:?}");
+        println!("{id}");
+        return Ok(());
+    }
+
+    // ignore packages that are already present in the container
+    let dependencies = lockfile
+        .packages
+        .into_iter()
+        .filter(|p| !p.installed)
+        .collect::<Vec<_>>();
+
+    build::check_userns(&dependencies).await?;
+
+    // the same mount `repro-env build` uses by default
+    let pwd = env::current_dir()?;
+    let project = state::project_dir(&lockfile_path)?;
+    let (root, _) = build::source_root(prepare.file.as_deref(), &pwd, &project);
+    let mut mounts = vec![Mount::new(&root, "/build")];
+    if prepare.check_conflicts {
+        build::check_file_conflicts(&dependencies, &prepare.download).await?;
+    }
+    let staging_dir = paths::staging_dir(prepare.staging_dir.as_deref())?;
+    let extra = build::stage_dependencies(
+        dependencies,
+        lockfile.keys,
+        lockfile.apk_keys,
+        lockfile.install_order,
+        &staging_dir,
+        &mut mounts,
+        &prepare.download,
+    )
+    .await?;
+    build::stage_files(lockfile.files, &mut mounts).await?;
+    build::stage_git(lockfile.git, &mut mounts).await?;
+    build::stage_toolchains(lockfile.toolchains, &mut mounts).await?;
+    java::stage(lockfile.maven, &mut mounts).await?;
+    ruby::stage(lockfile.gems, &mut mounts).await?;
+    php::stage(lockfile.composer, &mut mounts).await?;
+    perl::stage(lockfile.cpan, &mut mounts).await?;
+    haskell::stage(lockfile.haskell, &mut mounts).await?;
+    texlive::stage(lockfile.texlive, &mut mounts).await?;
+
+    // the umask of [build.environment] can't be changed after the container has been created
+    let podman_args = manifest
+        .as_ref()
+        .and_then(|m| m.build.environment.as_ref())
+        .map(|e| format!("--umask={}", e.umask()))
+        .into_iter()
+        .collect::<Vec<_>>();
+    container::verify_init(&lockfile.container)?;
+    let image = container::prepare_image(&lockfile.container, &project).await?;
+    build::check_platform(&image, None, arch.as_deref()).await?;
+    let container = Container::create(
+        &image,
+        container::Config {
+            mounts: &mounts,
+            expose_fuse: false,
+            lockfile_digest: Some(&lockfile_digest),
+            prepared: true,
+            platform: None,
+            podman_args: &podman_args,
+            read_only_root: false,
+            init: lockfile.container.init,
+        },
+    )
+    .await?;
+
+    let result = match &extra {
+        Some((_, install)) => install::run(&container, install, prepare.allow_untrusted).await,
+        None => Ok(()),
+    };
+    if let Err(err) = result {
+        if let Err(err) = container.kill().await {
+            warn!("Failed to kill container {:?}: {:#}", container.id, err);
+        }
+        return Err(err);
+    }
+
+    // the container keeps using /extra after we exit, `gc` removes the directory with the container
+    if let Some((dir, _)) = extra {
+        let path = dir.persist(&container.id)?;
+        debug!("Persisted staging directory for container: {path:?}");
+    }
+
+    state::record(&project, |state| {
+        state.add_container(ContainerRecord {
+            id: container.id.clone(),
+            lockfile_digest: lockfile_digest.clone(),
+            created: state::now(),
+            kept: true,
+        })
+    })
+    .await;
+
+    info!(
+        "Container is ready, `repro-env build` is going to use it: {:?}",
+        container.id
+    );
+    println!("{}", container.id);
+    summary::print(false)
+}
@@ -0,0 +1,158 @@
+use crate::errors::*;
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+/// How often progress is logged if stderr is not a terminal
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{secs}s")
+    }
+}
+
+/// Reports how many items of a long running phase are done, with an estimate
+/// for the remaining time
+#[derive(Debug)]
+pub struct Progress {
+    label: &'static str,
+    total: usize,
+    done: usize,
+    bytes: u64,
+    total_bytes: Option<u64>,
+    started: Instant,
+    last_log: Option<Instant>,
+    quiet: bool,
+    tty: bool,
+}
+
+impl Progress {
+    pub fn new(label: &'static str, total: usize, quiet: bool) -> Self {
+        Progress {
+            label,
+            total,
+            done: 0,
+            bytes: 0,
+            total_bytes: None,
+            started: Instant::now(),
+            last_log: None,
+            quiet,
+            tty: io::stderr().is_terminal(),
+        }
+    }
+
+    /// Show how much of a known total has been downloaded
+    pub fn with_total_bytes(mut self, total_bytes: u64) -> Self {
+        self.total_bytes = Some(total_bytes);
+        self
+    }
+
+    pub fn add_bytes(&mut self, bytes: u64) {
+        self.bytes += bytes;
+    }
+
+    pub fn inc(&mut self) {
+        self.done += 1;
+        self.render();
+    }
+
+    pub fn format(&self, elapsed: Duration) -> String {
+        let mut line = format!(
+            "{} {}/{}, downloaded {}",
+            self.label,
+            self.done,
+            self.total,
+            format_bytes(self.bytes)
+        );
+        if let Some(total_bytes) = self.total_bytes {
+            line.push_str(&format!(" of {}", format_bytes(total_bytes)));
+        }
+        if self.done > 0 && self.done < self.total {
+            let remaining = elapsed.mul_f64((self.total - self.done) as f64 / self.done as f64);
+            line.push_str(&format!(", ETA {}", format_duration(remaining)));
+        }
+        line
+    }
+
+    fn render(&mut self) {
+        if self.quiet {
+            return;
+        }
+        let line = self.format(self.started.elapsed());
+        if self.tty {
+            let mut stderr = io::stderr();
+            let _ = write!(stderr, "\r{line}\x1b[K");
+            if self.done >= self.total {
+                let _ = writeln!(stderr);
+            }
+            let _ = stderr.flush();
+        } else if self.done >= self.total
+            || self.last_log.is_none_or(|t| t.elapsed() >= LOG_INTERVAL)
+        {
+            info!("{line}");
+            self.last_log = Some(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(35 * 1024 * 1024), "35.0 MiB");
+    }
+
+    #[test]
+    fn test_format_progress() {
+        let mut progress = Progress::new("Resolved", 4, true);
+        assert_eq!(
+            progress.format(Duration::ZERO),
+            "Resolved 0/4, downloaded 0 B"
+        );
+        progress.inc();
+        progress.add_bytes(2048);
+        assert_eq!(
+            progress.format(Duration::from_secs(30)),
+            "Resolved 1/4, downloaded 2.0 KiB, ETA 1m30s"
+        );
+        progress.done = 4;
+        assert_eq!(
+            progress.format(Duration::from_secs(120)),
+            "Resolved 4/4, downloaded 2.0 KiB"
+        );
+    }
+
+    #[test]
+    fn test_format_progress_total_bytes() {
+        let mut progress = Progress::new("Fetched", 2, true).with_total_bytes(3 * 1024 * 1024);
+        progress.inc();
+        progress.add_bytes(1024 * 1024);
+        assert_eq!(
+            progress.format(Duration::from_secs(10)),
+            "Fetched 1/2, downloaded 1.0 MiB of 3.0 MiB, ETA 10s"
+        );
+    }
+}
@@ -0,0 +1,38 @@
+use crate::args;
+use crate::container::{self, LABEL_LOCKFILE_DIGEST, LABEL_PROJECT, LABEL_VERSION};
+use crate::errors::*;
+use crate::paths;
+use crate::staging;
+
+pub async fn ps(_ps: &args::Ps) -> Result<()> {
+    for c in container::list_containers().await? {
+        let id = c.id.get(..12).unwrap_or(&c.id);
+        println!(
+            "container {id} {} state={} project={} digest={} version={}",
+            c.image,
+            c.state,
+            c.label(LABEL_PROJECT).unwrap_or("-"),
+            c.label(LABEL_LOCKFILE_DIGEST).unwrap_or("-"),
+            c.label(LABEL_VERSION).unwrap_or("-"),
+        );
+    }
+
+    for dir in staging::list(&paths::staging_dir(None)?)? {
+        let label = |key| dir.labels.get(key).map(String::as_str).unwrap_or("-");
+        let state = if dir.stale {
+            "stale"
+        } else if dir.labels.contains_key(staging::LABEL_CONTAINER) {
+            "kept"
+        } else {
+            "in-use"
+        };
+        println!(
+            "staging {:?} state={state} project={} version={}",
+            dir.path,
+            label(LABEL_PROJECT),
+            label(LABEL_VERSION),
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,264 @@
+//! Package urls (purl) of locked packages, the identifiers that vulnerability
+//! databases and other SCA tools use, e.g. `pkg:deb/debian/curl@7.88.1-10?arch=amd64`
+use crate::lockfile::{Lockfile, PackageLock};
+
+/// Debian codenames and their release numbers, as used in `distro=debian-12`
+const DEBIAN_RELEASES: &[(&str, &str)] = &[
+    ("buster", "10"),
+    ("bullseye", "11"),
+    ("bookworm", "12"),
+    ("trixie", "13"),
+    ("forky", "14"),
+];
+
+/// The purl type and namespace of a package system
+fn type_namespace(system: &str) -> Option<(&'static str, &'static str)> {
+    match system {
+        "alpine" => Some(("apk", "alpine")),
+        "archlinux" => Some(("alpm", "arch")),
+        "debian" => Some(("deb", "debian")),
+        _ => None,
+    }
+}
+
+fn filename(pkg: &PackageLock) -> Option<&str> {
+    let (_, filename) = pkg.url.rsplit_once('/')?;
+    Some(filename)
+}
+
+/// The architecture of the package, as it's named in its filename or url
+pub fn arch(pkg: &PackageLock) -> Option<String> {
+    let arch = match pkg.system.as_str() {
+        // binutils_2.40-2_amd64.deb
+        "debian" => filename(pkg)?.strip_suffix(".deb")?.rsplit_once('_')?.1,
+        // .../v3.18/main/x86_64/binutils-2.40-r7.apk
+        "alpine" => {
+            let (repo, _) = pkg.url.rsplit_once('/')?;
+            repo.rsplit_once('/')?.1
+        }
+        // binutils-2.40-6-x86_64.pkg.tar.zst
+        "archlinux" => {
+            let (name, _) = filename(pkg)?.split_once(".pkg.tar")?;
+            name.rsplit_once('-')?.1
+        }
+        _ => return None,
+    };
+    (!arch.is_empty()).then(|| arch.to_string())
+}
+
+/// The release of alpine, e.g. `alpine-3.18` for a package from `.../v3.18/main/...`
+fn distro(pkg: &PackageLock) -> Option<String> {
+    if pkg.system != "alpine" {
+        return None;
+    }
+    pkg.url
+        .split('/')
+        .find_map(|segment| segment.strip_prefix('v'))
+        .filter(|version| version.starts_with(|c: char| c.is_ascii_digit()))
+        .map(|version| format!("alpine-{version}"))
+}
+
+/// The source package of a debian package, from its directory in the pool,
+/// e.g. `binutils` for `.../pool/main/b/binutils/libbinutils_2.40-2_amd64.deb`
+pub fn debian_source(pkg: &PackageLock) -> Option<&str> {
+    let mut segments = pkg.url.split('/').skip_while(|segment| *segment != "pool");
+    let source = segments.nth(3)?;
+    // the filename follows the source directory
+    segments.next()?;
+    (!source.is_empty()).then_some(source)
+}
+
+/// The debian release the lockfile was resolved for, e.g. `debian-12`. It's
+/// detected from the package indexes that were recorded during resolution.
+pub fn debian_distro(lockfile: &Lockfile) -> Option<String> {
+    let meta = lockfile.meta.as_ref()?;
+    let codename = meta.indexes.keys().find_map(|name| {
+        let (_, suite) = name.rsplit_once("_dists_")?;
+        (!suite.contains('-')).then_some(suite)
+    })?;
+    let release = DEBIAN_RELEASES
+        .iter()
+        .find(|(name, _)| *name == codename)
+        .map(|(_, number)| *number)
+        .unwrap_or(codename);
+    Some(format!("debian-{release}"))
+}
+
+/// Percent-encode everything except unreserved characters
+fn encode(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out
+}
+
+/// The purl of a locked package, None if the package system has no purl type
+pub fn purl(pkg: &PackageLock) -> Option<String> {
+    let (ty, namespace) = type_namespace(&pkg.system)?;
+    let mut purl = format!(
+        "pkg:{ty}/{namespace}/{}@{}",
+        encode(&pkg.name),
+        encode(&pkg.version)
+    );
+
+    // qualifiers are sorted by key
+    let qualifiers = [("arch", arch(pkg)), ("distro", distro(pkg))]
+        .into_iter()
+        .filter_map(|(key, value)| Some(format!("{key}={}", encode(&value?))))
+        .collect::<Vec<_>>();
+    if !qualifiers.is_empty() {
+        purl.push('?');
+        purl.push_str(&qualifiers.join("&"));
+    }
+    Some(purl)
+}
+
+/// The purl to look up advisories with. Debian advisories are filed against
+/// the source package and the release, so the purl of a debian package uses
+/// the name of its source package and a `distro` qualifier.
+pub fn advisory_purl(pkg: &PackageLock, distro: Option<&str>) -> Option<String> {
+    if pkg.system != "debian" {
+        return purl(pkg);
+    }
+    let name = debian_source(pkg).unwrap_or(&pkg.name);
+    let mut purl = format!("pkg:deb/debian/{}@{}", encode(name), encode(&pkg.version));
+    let qualifiers = [("arch", arch(pkg)), ("distro", distro.map(String::from))]
+        .into_iter()
+        .filter_map(|(key, value)| Some(format!("{key}={}", encode(&value?))))
+        .collect::<Vec<_>>();
+    if !qualifiers.is_empty() {
+        purl.push('?');
+        purl.push_str(&qualifiers.join("&"));
+    }
+    Some(purl)
+}
+
+/// The purl of a container image that is pinned by digest, e.g.
+/// `pkg:oci/debian@sha256%3A...?repository_url=docker.io/library/debian`
+pub fn oci(image: &str) -> Option<String> {
+    let (repo, digest) = image.split_once('@')?;
+    let name = repo.rsplit('/').next()?.to_lowercase();
+    Some(format!(
+        "pkg:oci/{}@{}?repository_url={}",
+        encode(&name),
+        encode(digest),
+        encode(repo).replace("%2F", "/")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::*;
+
+    fn pkg(system: &str, version: &str, url: &str) -> PackageLock {
+        PackageLock {
+            name: "binutils".to_string(),
+            version: version.to_string(),
+            system: system.to_string(),
+            url: url.to_string(),
+            sha256: "00".repeat(32),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_purl_debian() {
+        let pkg = pkg("debian", "1:2.40-2+deb12u1", "https://snapshot.debian.org/archive/debian/20230115T211934Z/pool/main/b/binutils/binutils_2.40-2%2Bdeb12u1_amd64.deb");
+        assert_eq!(
+            purl(&pkg).as_deref(),
+            Some("pkg:deb/debian/binutils@1%3A2.40-2%2Bdeb12u1?arch=amd64")
+        );
+    }
+
+    #[test]
+    fn test_advisory_purl_debian() {
+        let mut pkg = pkg("debian", "2.40-2+deb12u1", "https://snapshot.debian.org/archive/debian/20230115T211934Z/pool/main/b/binutils/libbinutils_2.40-2%2Bdeb12u1_amd64.deb");
+        pkg.name = "libbinutils".to_string();
+        assert_eq!(debian_source(&pkg), Some("binutils"));
+        assert_eq!(
+            advisory_purl(&pkg, Some("debian-12")).as_deref(),
+            Some("pkg:deb/debian/binutils@2.40-2%2Bdeb12u1?arch=amd64&distro=debian-12")
+        );
+
+        // not in a pool, fall back to the binary package
+        let pkg = PackageLock {
+            url: "https://example.com/libbinutils_2.40-2_amd64.deb".to_string(),
+            ..pkg
+        };
+        assert_eq!(debian_source(&pkg), None);
+        assert_eq!(
+            advisory_purl(&pkg, None).as_deref(),
+            Some("pkg:deb/debian/libbinutils@2.40-2%2Bdeb12u1?arch=amd64")
+        );
+    }
+
+    #[test]
+    fn test_debian_distro() -> Result<()> {
+        let mut lockfile = Lockfile::deserialize(
+            r#"[container]
+image = "debian@sha256:00"
+
+[meta]
+repro_env_version = "0.4.3"
+resolved_at = "2024-01-01T00:00:00Z"
+manifest_sha256 = "00"
+image = "debian:bookworm"
+
+[meta.indexes]
+"deb.debian.org_debian-security_dists_bookworm-security" = "2024-01-01T00:00:00Z"
+"deb.debian.org_debian_dists_bookworm" = "2024-01-01T00:00:00Z"
+"#,
+        )?;
+        assert_eq!(debian_distro(&lockfile).as_deref(), Some("debian-12"));
+        lockfile.meta = None;
+        assert_eq!(debian_distro(&lockfile), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_purl_alpine() {
+        let pkg = pkg(
+            "alpine",
+            "2.40-r7",
+            "https://dl-cdn.alpinelinux.org/alpine/v3.18/main/x86_64/binutils-2.40-r7.apk",
+        );
+        assert_eq!(
+            purl(&pkg).as_deref(),
+            Some("pkg:apk/alpine/binutils@2.40-r7?arch=x86_64&distro=alpine-3.18")
+        );
+    }
+
+    #[test]
+    fn test_purl_archlinux() {
+        let pkg = pkg(
+            "archlinux",
+            "2.40-6",
+            "https://archive.archlinux.org/packages/b/binutils/binutils-2.40-6-x86_64.pkg.tar.zst",
+        );
+        assert_eq!(
+            purl(&pkg).as_deref(),
+            Some("pkg:alpm/arch/binutils@2.40-6?arch=x86_64")
+        );
+    }
+
+    #[test]
+    fn test_purl_unknown() {
+        let pkg = pkg("gentoo", "2.40", "https://example.com/binutils-2.40.tbz2");
+        assert_eq!(purl(&pkg), None);
+    }
+
+    #[test]
+    fn test_purl_oci() {
+        assert_eq!(
+            oci("docker.io/library/debian@sha256:3d868b5eb908155f3784317b3dda2941df87bbbbaa4608f84881de66d9bb297b").as_deref(),
+            Some("pkg:oci/debian@sha256%3A3d868b5eb908155f3784317b3dda2941df87bbbbaa4608f84881de66d9bb297b?repository_url=docker.io/library/debian")
+        );
+        assert_eq!(oci("debian:bookworm"), None);
+    }
+}
@@ -0,0 +1,262 @@
+//! Ask public rebuilderd instances if the locked packages have been
+//! independently reproduced from source
+use crate::errors::*;
+use crate::http;
+use crate::lockfile::PackageLock;
+use crate::purl;
+use futures_util::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The rebuilders that are used for a package system unless `--rebuilder` is given
+const DEFAULT_REBUILDERS: &[(&str, &str)] = &[
+    ("archlinux", "https://reproducible.archlinux.org"),
+    ("debian", "https://reproduce.debian.net"),
+];
+const CONCURRENT_LOOKUPS: usize = 8;
+
+/// A package release as listed by `/api/v0/pkgs/list`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PkgRelease {
+    pub name: String,
+    pub version: String,
+    pub status: String,
+    pub architecture: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// The rebuilder produced a bit-for-bit identical package
+    Reproduced,
+    /// The rebuilder produced a different package
+    Unreproducible,
+    /// The rebuilder didn't build this exact version (yet)
+    Unknown,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Reproduced => "reproduced",
+            Status::Unreproducible => "unreproducible",
+            Status::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct CrossCheck {
+    pub name: String,
+    pub version: String,
+    pub system: String,
+    pub status: Status,
+    pub rebuilder: String,
+}
+
+/// Parse `SYSTEM=URL` of `--rebuilder`
+pub fn parse_rebuilder(s: &str) -> Result<(String, String)> {
+    let (system, url) = s
+        .split_once('=')
+        .context("Rebuilder needs to be in the format SYSTEM=URL")?;
+    if !url.starts_with("https://") && !url.starts_with("http://") {
+        bail!("Rebuilder url needs to be http(s): {url:?}");
+    }
+    Ok((system.to_string(), url.trim_end_matches('/').to_string()))
+}
+
+/// The rebuilders to query for each package system, `overrides` take precedence over the defaults
+pub fn rebuilders(overrides: &[(String, String)]) -> BTreeMap<String, String> {
+    let mut rebuilders = DEFAULT_REBUILDERS
+        .iter()
+        .map(|(system, url)| (system.to_string(), url.to_string()))
+        .collect::<BTreeMap<_, _>>();
+    rebuilders.extend(overrides.iter().cloned());
+    rebuilders
+}
+
+/// The status of the exact version (and architecture, if known) of the package
+pub fn match_status(releases: &[PkgRelease], pkg: &PackageLock) -> Status {
+    let arch = purl::arch(pkg);
+    releases
+        .iter()
+        .filter(|release| release.name == pkg.name && release.version == pkg.version)
+        .filter(|release| {
+            arch.as_ref()
+                .is_none_or(|arch| release.architecture == *arch)
+        })
+        .map(|release| match release.status.as_str() {
+            "GOOD" => Status::Reproduced,
+            "BAD" => Status::Unreproducible,
+            _ => Status::Unknown,
+        })
+        .min()
+        .unwrap_or(Status::Unknown)
+}
+
+async fn lookup(client: &http::Client, rebuilder: &str, pkg: &PackageLock) -> Result<Status> {
+    let url = format!(
+        "{rebuilder}/api/v0/pkgs/list?name={}",
+        urlencoding::encode(&pkg.name)
+    );
+    debug!("Querying rebuilder for {:?}: {url:?}", pkg.name);
+    let response = client
+        .cancellable(async {
+            client
+                .get(&url)
+                .send()
+                .await
+                .context("Failed to send http request")
+        })
+        .await?
+        .error_for_status()
+        .with_context(|| anyhow!("Received http error from rebuilder {rebuilder:?}"))?;
+    let buf = response.bytes().await.context("Failed to read http body")?;
+    let releases = serde_json::from_slice::<Vec<PkgRelease>>(&buf)
+        .with_context(|| anyhow!("Failed to parse response of rebuilder {rebuilder:?}"))?;
+    Ok(match_status(&releases, pkg))
+}
+
+/// Look up each package on the rebuilder of its package system, packages of
+/// systems without a rebuilder are skipped
+pub async fn cross_check(
+    client: &http::Client,
+    packages: &[PackageLock],
+    rebuilders: &BTreeMap<String, String>,
+) -> Result<Vec<CrossCheck>> {
+    let queryable = packages
+        .iter()
+        .filter_map(|pkg| Some((pkg, rebuilders.get(&pkg.system)?)))
+        .collect::<Vec<_>>();
+    let skipped = packages.len() - queryable.len();
+    if skipped > 0 {
+        info!("Skipping {skipped} packages of systems without a known rebuilder");
+    }
+    info!("Querying rebuilders for {} packages...", queryable.len());
+
+    let mut results = stream::iter(queryable)
+        .map(|(pkg, rebuilder)| async move {
+            let status = lookup(client, rebuilder, pkg).await?;
+            Ok::<_, Error>(CrossCheck {
+                name: pkg.name.clone(),
+                version: pkg.version.clone(),
+                system: pkg.system.clone(),
+                status,
+                rebuilder: rebuilder.clone(),
+            })
+        })
+        .buffer_unordered(CONCURRENT_LOOKUPS)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+    results.sort_by(|a, b| (a.status, &a.name).cmp(&(b.status, &b.name)));
+    Ok(results)
+}
+
+pub fn format_text(results: &[CrossCheck]) -> String {
+    let mut out = String::new();
+    for result in results {
+        out.push_str(&format!(
+            "{:<14}  {} {} ({})\n",
+            result.status.as_str(),
+            result.name,
+            result.version,
+            result.system
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pkg(name: &str, version: &str, url: &str) -> PackageLock {
+        PackageLock {
+            name: name.to_string(),
+            version: version.to_string(),
+            system: "archlinux".to_string(),
+            url: url.to_string(),
+            sha256: "0".repeat(64),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_match_status() -> Result<()> {
+        let releases = serde_json::from_str::<Vec<PkgRelease>>(
+            r#"[
+                {"name":"curl","version":"8.5.0-1","status":"BAD","distro":"archlinux","suite":"core","architecture":"x86_64","artifact_url":"https://example.com/curl-8.5.0-1-x86_64.pkg.tar.zst","build_id":1,"built_at":null,"has_diffoscope":false,"has_attestation":false,"next_retry":null},
+                {"name":"curl","version":"8.6.0-1","status":"GOOD","distro":"archlinux","suite":"core","architecture":"x86_64","artifact_url":"https://example.com/curl-8.6.0-1-x86_64.pkg.tar.zst","build_id":2,"built_at":null,"has_diffoscope":false,"has_attestation":false,"next_retry":null},
+                {"name":"curl","version":"8.7.0-1","status":"UNKWN","distro":"archlinux","suite":"core","architecture":"x86_64","artifact_url":"https://example.com/curl-8.7.0-1-x86_64.pkg.tar.zst","build_id":null,"built_at":null,"has_diffoscope":false,"has_attestation":false,"next_retry":null}
+            ]"#,
+        )?;
+        let url = |version| {
+            format!(
+                "https://archive.archlinux.org/packages/c/curl/curl-{version}-x86_64.pkg.tar.zst"
+            )
+        };
+        assert_eq!(
+            match_status(&releases, &pkg("curl", "8.6.0-1", &url("8.6.0-1"))),
+            Status::Reproduced
+        );
+        assert_eq!(
+            match_status(&releases, &pkg("curl", "8.5.0-1", &url("8.5.0-1"))),
+            Status::Unreproducible
+        );
+        assert_eq!(
+            match_status(&releases, &pkg("curl", "8.7.0-1", &url("8.7.0-1"))),
+            Status::Unknown
+        );
+        assert_eq!(
+            match_status(&releases, &pkg("curl", "8.8.0-1", &url("8.8.0-1"))),
+            Status::Unknown
+        );
+        // a different architecture was reproduced
+        assert_eq!(
+            match_status(
+                &releases,
+                &pkg(
+                    "curl",
+                    "8.6.0-1",
+                    "https://archive.archlinux.org/packages/c/curl/curl-8.6.0-1-aarch64.pkg.tar.zst"
+                )
+            ),
+            Status::Unknown
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebuilders() -> Result<()> {
+        let overrides = vec![parse_rebuilder("debian=https://rebuilder.example.com/")?];
+        let rebuilders = rebuilders(&overrides);
+        assert_eq!(
+            rebuilders.get("debian").map(String::as_str),
+            Some("https://rebuilder.example.com")
+        );
+        assert_eq!(
+            rebuilders.get("archlinux").map(String::as_str),
+            Some("https://reproducible.archlinux.org")
+        );
+        assert!(parse_rebuilder("debian").is_err());
+        assert!(parse_rebuilder("debian=ftp://example.com").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_text() {
+        let results = vec![CrossCheck {
+            name: "curl".to_string(),
+            version: "8.6.0-1".to_string(),
+            system: "archlinux".to_string(),
+            status: Status::Reproduced,
+            rebuilder: "https://reproducible.archlinux.org".to_string(),
+        }];
+        assert_eq!(
+            format_text(&results),
+            "reproduced      curl 8.6.0-1 (archlinux)\n"
+        );
+    }
+}
@@ -0,0 +1,221 @@
+//! Remember which lockfiles on this machine reference which packages in the
+//! cache, so `gc` can prune packages without breaking other projects
+use crate::errors::*;
+use crate::lockfile::{FileLock, Lockfile, PackageLock};
+use crate::paths;
+use crate::utils;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+const INDEX_FILENAME: &str = "refs.json";
+const LOCK_FILENAME: &str = "refs.lock";
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RefIndex {
+    /// Absolute path of a lockfile => sha256 of the packages it references
+    pub lockfiles: BTreeMap<PathBuf, BTreeSet<String>>,
+}
+
+impl RefIndex {
+    pub fn deserialize(buf: &str) -> Result<Self> {
+        let index = serde_json::from_str(buf)?;
+        Ok(index)
+    }
+
+    fn read(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(buf) => Self::deserialize(&buf)
+                .with_context(|| anyhow!("Failed to parse package reference index: {path:?}")),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        let buf = serde_json::to_string_pretty(self)?;
+        utils::atomic_write(path, buf.as_bytes())
+    }
+
+    pub fn insert(&mut self, lockfile: PathBuf, packages: &[PackageLock], files: &[FileLock]) {
+        let sha256 = packages
+            .iter()
+            .filter(|p| !p.installed && p.has_package_file())
+            .map(|p| p.sha256.clone())
+            .chain(files.iter().map(|f| f.sha256.clone()))
+            .collect();
+        self.lockfiles.insert(lockfile, sha256);
+    }
+
+    /// The lockfiles that reference a package
+    pub fn referencing(&self, sha256: &str) -> Vec<&Path> {
+        self.lockfiles
+            .iter()
+            .filter(|(_, pkgs)| pkgs.contains(sha256))
+            .map(|(path, _)| path.as_path())
+            .collect()
+    }
+
+    /// All packages that are referenced by at least one lockfile
+    pub fn referenced(&self) -> BTreeSet<String> {
+        self.lockfiles.values().flatten().cloned().collect()
+    }
+
+    /// Re-read all known lockfiles, forgetting the ones that have been deleted
+    pub fn refresh(&mut self) {
+        self.lockfiles.retain(|path, pkgs| {
+            let buf = match fs::read_to_string(path) {
+                Ok(buf) => buf,
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    debug!("Lockfile has been removed, forgetting references: {path:?}");
+                    return false;
+                }
+                Err(err) => {
+                    warn!("Failed to read lockfile, keeping references: {path:?}: {err:#}");
+                    return true;
+                }
+            };
+            match Lockfile::deserialize(&buf) {
+                Ok(lockfile) => {
+                    *pkgs = lockfile
+                        .all_packages()
+                        .filter(|p| !p.installed && p.has_package_file())
+                        .map(|p| p.sha256.clone())
+                        .chain(lockfile.files.iter().map(|f| f.sha256.clone()))
+                        .collect();
+                }
+                Err(err) => {
+                    warn!("Failed to parse lockfile, keeping references: {path:?}: {err:#}")
+                }
+            }
+            true
+        });
+    }
+}
+
+fn open_lock(dir: &Path) -> Result<fs::File> {
+    fs::create_dir_all(dir)
+        .with_context(|| anyhow!("Failed to create cache directory: {dir:?}"))?;
+    let path = dir.join(LOCK_FILENAME);
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| anyhow!("Failed to open lock: {path:?}"))?;
+    Ok(file)
+}
+
+/// Read the index without modifying it
+pub async fn load() -> Result<RefIndex> {
+    let dir = paths::cache_dir()?;
+    let _lock = utils::lock_shared(open_lock(&dir)?).await?;
+    RefIndex::read(&dir.join(INDEX_FILENAME))
+}
+
+/// Read the index and write it back after `f` has modified it
+pub async fn update<T, F: FnOnce(&mut RefIndex) -> Result<T>>(f: F) -> Result<T> {
+    let dir = paths::cache_dir()?;
+    let _lock = utils::lock_exclusive(open_lock(&dir)?).await?;
+
+    let path = dir.join(INDEX_FILENAME);
+    let mut index = RefIndex::read(&path)?;
+    let ret = f(&mut index)?;
+    index.write(&path)?;
+    Ok(ret)
+}
+
+/// Record that a lockfile references its packages and files
+pub async fn record(lockfile: &Path, packages: &[PackageLock], files: &[FileLock]) -> Result<()> {
+    let lockfile = std::path::absolute(lockfile)?;
+    if lockfile.to_str().is_none() {
+        // the index is json, which can't represent these paths
+        warn!("Can't record package references of lockfile with a non-UTF-8 path, `gc` may remove its packages: {lockfile:?}");
+        return Ok(());
+    }
+    update(|index| {
+        index.insert(lockfile, packages, files);
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pkg(sha256: &str, installed: bool) -> PackageLock {
+        PackageLock {
+            name: "binutils".to_string(),
+            version: "2.40-2".to_string(),
+            system: "debian".to_string(),
+            url: "https://snapshot.debian.org/archive/debian/20230115T211934Z/pool/main/b/binutils/binutils_2.40-2_amd64.deb".to_string(),
+            sha256: sha256.to_string(),
+            installed,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_references() -> Result<()> {
+        let mut index = RefIndex::default();
+        index.insert(
+            PathBuf::from("/src/a/repro-env.lock"),
+            &[pkg("aaaa", false), pkg("bbbb", false), pkg("eeee", true)],
+            &[],
+        );
+        index.insert(
+            PathBuf::from("/src/b/repro-env.lock"),
+            &[pkg("bbbb", false)],
+            &[FileLock {
+                name: "firmware.bin".to_string(),
+                url: "https://example.com/firmware.bin".to_string(),
+                sha256: "cccc".to_string(),
+                size: 1024,
+                signature: None,
+            }],
+        );
+
+        assert_eq!(
+            index.referencing("aaaa"),
+            [Path::new("/src/a/repro-env.lock")]
+        );
+        assert_eq!(
+            index.referencing("bbbb"),
+            [
+                Path::new("/src/a/repro-env.lock"),
+                Path::new("/src/b/repro-env.lock")
+            ]
+        );
+        assert!(index.referencing("eeee").is_empty());
+        assert_eq!(
+            index.referenced().into_iter().collect::<Vec<_>>(),
+            ["aaaa", "bbbb", "cccc"]
+        );
+
+        let buf = serde_json::to_string(&index)?;
+        assert_eq!(RefIndex::deserialize(&buf)?, index);
+        Ok(())
+    }
+
+    #[test]
+    fn test_refresh_forgets_removed_lockfiles() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("repro-env.lock");
+        std::fs::write(
+            &path,
+            "[container]\nimage = \"debian@sha256:3d868b5eb908155f3784317b3dda2941df87bbbbaa4608f84881de66d9bb297b\"\n",
+        )?;
+
+        let mut index = RefIndex::default();
+        index.insert(path.clone(), &[pkg("aaaa", false)], &[]);
+        index.insert(dir.path().join("gone.lock"), &[pkg("bbbb", false)], &[]);
+        index.refresh();
+
+        assert_eq!(index.lockfiles.len(), 1);
+        assert!(index.lockfiles[&path].is_empty());
+        Ok(())
+    }
+}
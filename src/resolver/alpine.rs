@@ -2,17 +2,28 @@ use crate::args;
 use crate::container::{self, Container};
 use crate::errors::*;
 use crate::http;
-use crate::lockfile::{ContainerLock, PackageLock};
-use crate::manifest::PackagesManifest;
+use crate::limits;
+use crate::lockfile::{ApkKeyLock, ContainerLock, PackageLock};
+use crate::manifest::{PackagesManifest, Solver};
 use crate::paths;
+use crate::pkgs;
+use crate::resolver::diagnose::{self, Candidate};
+use crate::resolver::parsers;
+use crate::resolver::provenance::{self, Provenance};
+use crate::summary;
 use crate::utils;
+
 use data_encoding::BASE64;
 use flate2::bufread::GzDecoder;
+use rsa::pkcs8::DecodePublicKey;
+use rsa::{Pkcs1v15Sign, RsaPublicKey};
 use sha1::Sha1;
 use sha2::{Digest, Sha256};
 use std::collections::{HashMap, HashSet};
-use std::io::{BufRead, BufReader, Read};
-use std::rc::Rc;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read};
+use std::path::Path;
+use std::sync::Arc;
+use time::OffsetDateTime;
 use tokio::fs;
 
 pub fn decode_apk_checksum(checksum: &str) -> Result<Vec<u8>> {
@@ -27,8 +38,10 @@ pub fn decode_apk_checksum(checksum: &str) -> Result<Vec<u8>> {
 
 #[derive(Debug, Default)]
 pub struct DatabaseCache {
-    repos: HashMap<String, Rc<String>>,
+    repos: HashMap<String, Arc<String>>,
     pkgs: HashMap<String, CacheEntry>,
+    /// Repository url => when its APKINDEX was generated (unix epoch)
+    index_times: Vec<(Arc<String>, u64)>,
 }
 
 #[derive(Debug)]
@@ -37,8 +50,10 @@ pub struct CacheEntry {
     version: String,
     arch: String,
     provides: Vec<String>,
+    depends: Vec<String>,
     checksum: String,
-    repo_url: Rc<String>,
+    size: Option<u64>,
+    repo_url: Arc<String>,
 }
 
 pub struct CacheEntryDraft {
@@ -46,8 +61,10 @@ pub struct CacheEntryDraft {
     pub version: Option<String>,
     pub arch: Option<String>,
     pub provides: Vec<String>,
+    pub depends: Vec<String>,
     pub checksum: Option<String>,
-    pub repo_url: Rc<String>,
+    pub size: Option<u64>,
+    pub repo_url: Arc<String>,
 }
 
 impl TryFrom<CacheEntryDraft> for CacheEntry {
@@ -59,26 +76,42 @@ impl TryFrom<CacheEntryDraft> for CacheEntry {
             version: draft.version.context("Missing version field")?,
             arch: draft.arch.context("Missing arch field")?,
             provides: draft.provides,
+            depends: draft.depends,
             checksum: draft.checksum.context("Missing checksum field")?,
+            size: draft.size,
             repo_url: draft.repo_url,
         })
     }
 }
 
 impl CacheEntryDraft {
-    pub fn new(repo_url: Rc<String>) -> Self {
+    pub fn new(repo_url: Arc<String>) -> Self {
         CacheEntryDraft {
             name: None,
             version: None,
             arch: None,
             provides: vec![],
+            depends: vec![],
             checksum: None,
+            size: None,
             repo_url,
         }
     }
 }
 
 impl DatabaseCache {
+    /// All package names and provides, used to suggest alternatives for typos
+    pub fn candidates(&self) -> Vec<Candidate<'_>> {
+        let mut candidates = Vec::new();
+        for pkg in self.pkgs.values() {
+            candidates.push(Candidate::package(&pkg.name));
+            for provides in &pkg.provides {
+                candidates.push(Candidate::provided(provides, &pkg.name));
+            }
+        }
+        candidates
+    }
+
     pub fn get(&self, id: &str) -> Result<&CacheEntry> {
         let entry = self
             .pkgs
@@ -87,7 +120,7 @@ impl DatabaseCache {
         Ok(entry)
     }
 
-    pub fn read_apkindex_text<R: Read>(&mut self, r: R, repo_url: &Rc<String>) -> Result<()> {
+    pub fn read_apkindex_text<R: Read>(&mut self, r: R, repo_url: &Arc<String>) -> Result<()> {
         let reader = BufReader::new(r);
         let mut draft = CacheEntryDraft::new(repo_url.clone());
         for line in reader.lines() {
@@ -114,6 +147,13 @@ impl DatabaseCache {
                         let checksum = decode_apk_checksum(value)?;
                         draft.checksum = Some(hex::encode(checksum));
                     }
+                    "S" => {
+                        trace!("Package size: {value:?}");
+                        let size = value
+                            .parse()
+                            .with_context(|| anyhow!("Invalid package size: {value:?}"))?;
+                        draft.size = Some(size);
+                    }
                     "A" => {
                         trace!("Package architecture: {value:?}");
                         draft.arch = Some(value.to_string());
@@ -125,6 +165,12 @@ impl DatabaseCache {
                             draft.provides.push(name.to_string());
                         }
                     }
+                    "D" => {
+                        trace!("Package dependencies: {value:?}");
+                        draft
+                            .depends
+                            .extend(value.split(' ').filter(|d| !d.is_empty()).map(String::from));
+                    }
                     _ => trace!("Ignoring APKINDEX value key={key:?}, value={value:?}"),
                 }
             } else {
@@ -134,18 +180,21 @@ impl DatabaseCache {
         Ok(())
     }
 
-    pub fn read_apkindex_container<R: Read>(&mut self, r: R, repo_url: &Rc<String>) -> Result<()> {
+    pub fn read_apkindex_container<R: Read>(&mut self, r: R, repo_url: &Arc<String>) -> Result<()> {
         let mut r = BufReader::new(r);
-        utils::read_gzip_to_end(&mut r).context("Failed to strip signature")?;
+        pkgs::alpine::read_section(&mut r, limits::INDEX).context("Failed to strip signature")?;
 
-        let gz = GzDecoder::new(r);
+        let gz = limits::INDEX.wrap(GzDecoder::new(r));
         let mut tar = tar::Archive::new(gz);
 
-        for entry in tar.entries()? {
+        for (idx, entry) in tar.entries()?.enumerate() {
+            limits::INDEX.check_entry(idx)?;
             let entry = entry?;
             if entry.header().entry_type() == tar::EntryType::Regular {
                 let path = entry.path()?;
                 if path.to_str() == Some("APKINDEX") {
+                    let mtime = entry.header().mtime()?;
+                    self.index_times.push((repo_url.clone(), mtime));
                     self.read_apkindex_text(entry, repo_url)?;
                 }
             }
@@ -182,7 +231,7 @@ impl DatabaseCache {
         let hash = hasher.finalize();
         let sha1 = hex::encode(&hash[..4]);
         self.repos
-            .insert(format!("APKINDEX.{sha1}.tar.gz"), Rc::new(repo));
+            .insert(format!("APKINDEX.{sha1}.tar.gz"), Arc::new(repo));
     }
 
     pub fn init_repos_from_container(&mut self, buf: &[u8]) -> Result<()> {
@@ -202,32 +251,127 @@ impl DatabaseCache {
     }
 }
 
-pub fn calculate_checksum_for_apk(apk: &[u8]) -> Result<Vec<u8>> {
-    // the first gzip has no end-of-stream marker, only read one file from tar
-    let remaining = {
-        let gz = GzDecoder::new(apk);
-        let mut tar = tar::Archive::new(gz);
-        tar.entries()?.next();
-        tar.into_inner().into_inner()
-    };
+/// Split an apk into the decompressed signature section and the (compressed) control section
+/// Remembers the bytes that have been consumed from the inner reader
+struct Recorder<R> {
+    inner: R,
+    consumed: Vec<u8>,
+}
 
-    // this is slightly chaotic, there's some over-read by GzDecoder that we need to correct
-    let sig = apk.len() - remaining.len() + 8;
+impl<R: BufRead> Read for Recorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = buf.len().min(available.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
 
-    // locate the start of the 3rd gzip stream
-    let mut r = &apk[sig..];
-    utils::read_gzip_to_end(&mut r)?;
-    let content = r.len();
+impl<R: BufRead> BufRead for Recorder<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
 
-    // cut at the location of the 2nd gzip stream
-    let control_data = &apk[sig..(apk.len() - content)];
+    fn consume(&mut self, amt: usize) {
+        // the buffer is already filled, this doesn't read
+        if let Ok(buf) = self.inner.fill_buf() {
+            self.consumed.extend_from_slice(&buf[..amt.min(buf.len())]);
+        }
+        self.inner.consume(amt);
+    }
+}
+
+/// Returns the decompressed signature section and the raw control section,
+/// the data section is not read
+fn split_apk<R: Read>(apk: R) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut r = BufReader::new(apk);
+    let signature = pkgs::alpine::read_section(&mut r, limits::PACKAGE)
+        .context("Failed to read signature section")?;
+    let mut r = Recorder {
+        inner: r,
+        consumed: Vec::new(),
+    };
+    pkgs::alpine::read_section(&mut r, limits::PACKAGE)
+        .context("Failed to read control section")?;
+    Ok((signature, r.consumed))
+}
+
+pub fn calculate_checksum_for_apk(apk: &[u8]) -> Result<Vec<u8>> {
+    let (_, control_data) = split_apk(apk)?;
 
     let mut sha1 = Sha1::new();
-    sha1.update(control_data);
+    sha1.update(&control_data);
     let sha1 = sha1.finalize();
     Ok(sha1.to_vec())
 }
 
+/// Verify the signature of an apk with the given keys, returns the name of the key that was used
+pub fn verify_signature<R: Read>(apk: R, keys: &[ApkKeyLock]) -> Result<&str> {
+    let (signature, control_data) = split_apk(apk)?;
+
+    // the signature section is a tar without end-of-archive marker, only read the first entry
+    let mut tar = tar::Archive::new(&signature[..]);
+    let mut entry = tar
+        .entries()?
+        .next()
+        .context("Package has no signature")??;
+    let path = entry.path()?;
+    let filename = path.to_str().context("Signature filename is not utf-8")?;
+    let (hashed, key_name) = if let Some(name) = filename.strip_prefix(".SIGN.RSA256.") {
+        let hashed = Sha256::digest(&control_data).to_vec();
+        (hashed, name.to_string())
+    } else if let Some(name) = filename.strip_prefix(".SIGN.RSA.") {
+        let hashed = Sha1::digest(&control_data).to_vec();
+        (hashed, name.to_string())
+    } else {
+        bail!("Unsupported signature in package: {filename:?}");
+    };
+    let mut sig = Vec::new();
+    entry.read_to_end(&mut sig)?;
+
+    let key = keys
+        .iter()
+        .find(|key| key.name == key_name)
+        .with_context(|| anyhow!("Package is signed by unknown key: {key_name:?}"))?;
+    let public_key = RsaPublicKey::from_public_key_pem(&key.key)
+        .with_context(|| anyhow!("Failed to parse apk key: {:?}", key.name))?;
+    let scheme = if hashed.len() == 32 {
+        Pkcs1v15Sign::new::<Sha256>()
+    } else {
+        Pkcs1v15Sign::new::<Sha1>()
+    };
+    public_key
+        .verify(scheme, &hashed, &sig)
+        .with_context(|| anyhow!("Invalid signature by key: {key_name:?}"))?;
+    Ok(&key.name)
+}
+
+/// Read the keys apk trusts from a tar of /etc/apk/keys
+pub fn read_apk_keys(buf: &[u8]) -> Result<Vec<ApkKeyLock>> {
+    let mut tar = tar::Archive::new(buf);
+    let mut keys = Vec::new();
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        let path = entry.path()?;
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context("Failed to detect filename")?
+            .to_string();
+        let mut key = String::new();
+        entry
+            .read_to_string(&mut key)
+            .with_context(|| anyhow!("Failed to read apk key: {name:?}"))?;
+        keys.push(ApkKeyLock { name, key });
+    }
+    keys.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(keys)
+}
+
 pub async fn detect_installed(container: &Container) -> Result<HashSet<String>> {
     let buf = container
         .exec(
@@ -239,32 +383,14 @@ pub async fn detect_installed(container: &Container) -> Result<HashSet<String>>
         )
         .await?;
     let buf = String::from_utf8(buf).context("Failed to decode apk output as utf8")?;
-
-    let installed = buf.lines().map(String::from).collect();
-    Ok(installed)
+    Ok(parsers::apk_info(&buf))
 }
 
-pub async fn resolve_dependencies(
+/// Upgrade and install in the container, then diff the installed packages
+async fn install_and_diff(
     container: &Container,
     manifest: &PackagesManifest,
-    dependencies: &mut Vec<PackageLock>,
-) -> Result<()> {
-    info!("Syncing package datatabase...");
-    container
-        .exec(&["apk", "update"], container::Exec::default())
-        .await?;
-
-    let mut dbs = DatabaseCache::default();
-    {
-        // we only need these files briefly, declare them in a small scope so they get free'd early
-        let repos = container.tar("/etc/apk/repositories").await?;
-        dbs.init_repos_from_container(&repos)?;
-
-        let tar = container.tar("/var/cache/apk").await?;
-        dbs.import_from_container(&tar)?;
-    }
-
-    info!("Resolving dependencies...");
+) -> Result<Vec<String>> {
     let initial_packages = detect_installed(container).await?;
 
     // upgrade and install
@@ -276,25 +402,232 @@ pub async fn resolve_dependencies(
     for dep in &manifest.dependencies {
         cmd.push(dep.as_str());
     }
-    container.exec(&cmd, container::Exec::default()).await?;
+    container
+        .exec(
+            &cmd,
+            container::Exec {
+                capture_stderr: true,
+                ..Default::default()
+            },
+        )
+        .await?;
 
     // detect dependencies
     let packages_afterwards = detect_installed(container).await?;
-    let new_packages = packages_afterwards.difference(&initial_packages);
+    Ok(parsers::apk_info_diff(
+        &initial_packages,
+        &packages_afterwards,
+    ))
+}
+
+/// The name and version of a `name-version-rN` package identifier
+fn split_apk_id(id: &str) -> Option<(&str, &str)> {
+    let (rest, release) = id.rsplit_once('-')?;
+    let (name, _) = rest.rsplit_once('-')?;
+    if !release.starts_with('r') {
+        return None;
+    }
+    Some((name, &id[name.len() + 1..]))
+}
+
+/// Check the packages `apk --simulate` picked, together with the installed
+/// ones they don't replace, against the dependencies (`D:`) and provides
+/// (`p:`) of the APKINDEX. Returns the unsatisfied dependencies as (package, dependency).
+fn unsatisfied_dependencies(
+    dbs: &DatabaseCache,
+    installed: &HashSet<String>,
+    new: &HashMap<String, String>,
+) -> Vec<(String, String)> {
+    let ids = installed
+        .iter()
+        .filter(|id| split_apk_id(id).is_none_or(|(name, _)| !new.contains_key(name)))
+        .cloned()
+        .chain(
+            new.iter()
+                .map(|(name, version)| format!("{name}-{version}")),
+        )
+        .collect::<Vec<_>>();
+
+    let mut provided = HashSet::new();
+    let mut entries = Vec::new();
+    for id in &ids {
+        if let Some(pkg) = dbs.pkgs.get(id) {
+            provided.insert(pkg.name.as_str());
+            provided.extend(pkg.provides.iter().map(String::as_str));
+            entries.push(pkg);
+        } else if let Some((name, _)) = split_apk_id(id) {
+            // not from a configured repository, its dependencies are unknown
+            provided.insert(name);
+        }
+    }
+
+    let mut unsatisfied = Vec::new();
+    for pkg in entries {
+        for dep in &pkg.depends {
+            let names = pkgs::dependency_names("alpine", dep);
+            if names.iter().any(|name| !provided.contains(name)) {
+                unsatisfied.push((pkg.name.clone(), dep.clone()));
+            }
+        }
+    }
+    unsatisfied.sort();
+    unsatisfied
+}
+
+/// Ask apk what it would upgrade and install, without modifying the container.
+/// If the result doesn't satisfy the dependencies in the APKINDEX, the packages
+/// are installed instead.
+async fn simulate(
+    container: &Container,
+    manifest: &PackagesManifest,
+    dbs: &DatabaseCache,
+) -> Result<Vec<String>> {
+    let installed = detect_installed(container).await?;
+    let mut cmds = vec![vec!["apk", "upgrade", "--simulate"]];
+    let mut add = vec!["apk", "add", "--simulate", "--"];
+    for dep in &manifest.dependencies {
+        add.push(dep.as_str());
+    }
+    cmds.push(add);
+
+    let mut pkgs = HashMap::new();
+    for cmd in cmds {
+        let buf = container
+            .exec(
+                &cmd,
+                container::Exec {
+                    capture_stdout: true,
+                    capture_stderr: true,
+                    ..Default::default()
+                },
+            )
+            .await?;
+        let buf = String::from_utf8(buf).context("Failed to decode apk output as utf8")?;
+        for (name, version) in parsers::apk_simulate(&buf)? {
+            pkgs.insert(name, version);
+        }
+    }
+
+    let unsatisfied = unsatisfied_dependencies(dbs, &installed, &pkgs);
+    if !unsatisfied.is_empty() {
+        for (pkg, dep) in &unsatisfied {
+            warn!("Dependency {dep:?} of {pkg:?} is missing from the result of apk --simulate");
+        }
+        info!("Resolving dependencies by installing the packages instead...");
+        return install_and_diff(container, manifest).await;
+    }
+
+    let pkgs = pkgs
+        .into_iter()
+        .map(|(name, version)| format!("{name}-{version}"))
+        .collect();
+    Ok(pkgs)
+}
+
+/// The url of a package on a dated snapshot mirror, the path of the repository is appended to `snapshot`
+pub fn snapshot_url(snapshot: &str, repo_url: &str, index_time: u64, file: &str) -> Result<String> {
+    let time = i64::try_from(index_time)
+        .ok()
+        .and_then(|t| OffsetDateTime::from_unix_timestamp(t).ok())
+        .with_context(|| anyhow!("Invalid index timestamp: {index_time}"))?;
+    let date = format!(
+        "{:04}{:02}{:02}",
+        time.year(),
+        u8::from(time.month()),
+        time.day()
+    );
+    let timestamp = format!(
+        "{date}T{:02}{:02}{:02}Z",
+        time.hour(),
+        time.minute(),
+        time.second()
+    );
+    let snapshot = snapshot
+        .replace("{date}", &date)
+        .replace("{timestamp}", &timestamp);
+
+    let repo = repo_url
+        .parse::<reqwest::Url>()
+        .with_context(|| anyhow!("Failed to parse repository url: {repo_url:?}"))?;
+    let path = repo.path().trim_matches('/');
+    Ok(format!("{}/{path}/{file}", snapshot.trim_end_matches('/')))
+}
+
+pub async fn resolve_dependencies(
+    container: &Container,
+    manifest: &PackagesManifest,
+    dependencies: &mut Vec<PackageLock>,
+    apk_keys: &mut Vec<ApkKeyLock>,
+    provenance: &mut Provenance,
+) -> Result<()> {
+    info!("Syncing package datatabase...");
+    container
+        .exec(&["apk", "update"], container::Exec::default())
+        .await?;
+    provenance
+        .detect_tool(
+            container,
+            "apk",
+            &["apk", "--version"],
+            provenance::parse_apk_version,
+        )
+        .await;
+    provenance
+        .record_sources(container, &["/etc/apk/repositories"])
+        .await?;
+
+    // the archives are moved into the worker, so they get free'd early
+    let repos = container.tar("/etc/apk/repositories").await?;
+    let cache = container.tar("/var/cache/apk").await?;
+    let keys = container.tar("/etc/apk/keys").await?;
+    let (dbs, keys) = utils::spawn_blocking(move |_| {
+        let mut dbs = DatabaseCache::default();
+        dbs.init_repos_from_container(&repos)?;
+        dbs.import_from_container(&cache)?;
+        Ok((dbs, read_apk_keys(&keys)?))
+    })
+    .await?;
+    for (repo_url, mtime) in &dbs.index_times {
+        provenance.record_index(repo_url, *mtime as i64)?;
+    }
+    *apk_keys = keys;
+
+    info!("Resolving dependencies...");
+    let new_packages = match manifest.solver {
+        Solver::Simulate => simulate(container, manifest, &dbs).await,
+        _ => install_and_diff(container, manifest).await,
+    };
+    let new_packages = match new_packages {
+        Ok(new_packages) => new_packages,
+        Err(err) => {
+            return Err(diagnose::explain(container, "alpine", err, &dbs.candidates()).await)
+        }
+    };
 
     info!("Calculating package checksums...");
     let client = http::Client::new()?;
     let alpine_cache_dir = paths::alpine_cache_dir()?;
-    for pkg_identifier in new_packages {
+    for pkg_identifier in &new_packages {
         let pkg = dbs.get(pkg_identifier)?;
         debug!("Detected dependency: {pkg:?}");
 
-        let url = format!(
-            "{}/{}/{}-{}.apk",
-            pkg.repo_url, pkg.arch, pkg.name, pkg.version
-        );
+        let file = format!("{}/{}-{}.apk", pkg.arch, pkg.name, pkg.version);
+        let url = format!("{}/{file}", pkg.repo_url);
+        // the live repository only has the latest version of each package
+        let mut fallback_urls = Vec::new();
+        if let Some(snapshot) = &manifest.snapshot {
+            let index_time = dbs
+                .index_times
+                .iter()
+                .find(|(repo_url, _)| *repo_url == pkg.repo_url)
+                .map(|(_, mtime)| *mtime)
+                .with_context(|| anyhow!("Missing index time of repository: {:?}", pkg.repo_url))?;
+            fallback_urls.push(snapshot_url(snapshot, &pkg.repo_url, index_time, &file)?);
+        }
 
-        let sha256 = if let Some(sha256) = alpine_cache_dir.sha1_read_link(&pkg.checksum).await? {
+        let cached = alpine_cache_dir.sha1_read_link(&pkg.checksum).await?;
+        summary::record_cache(cached.is_some());
+        let sha256 = if let Some(sha256) = cached {
             sha256
         } else {
             let mut buf = Vec::new();
@@ -305,13 +638,10 @@ pub async fn resolve_dependencies(
                 .with_context(|| anyhow!("Failed to download package from url: {:?}", url))?;
 
             let mut sha256 = Sha256::new();
-            while let Some(chunk) = response
-                .chunk()
-                .await
-                .context("Failed to read from download stream")?
-            {
+            while let Some(chunk) = client.chunk(&mut response).await? {
                 buf.extend(&chunk);
                 sha256.update(&chunk);
+                summary::record_download(chunk.len() as u64);
             }
 
             let sha256 = hex::encode(sha256.finalize());
@@ -333,9 +663,14 @@ pub async fn resolve_dependencies(
                 anyhow!("Failed to create parent directories for file: {sha1_path:?}")
             })?;
 
-            fs::symlink(sha256_path, sha1_path)
-                .await
-                .context("Failed to create sha1 symlink")?;
+            match fs::symlink(sha256_path, &sha1_path).await {
+                Ok(()) => (),
+                // another process created the same link in the meantime
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                    debug!("Symlink was created concurrently: {sha1_path:?}");
+                }
+                Err(err) => return Err(err).context("Failed to create sha1 symlink"),
+            }
 
             sha256
         };
@@ -353,10 +688,14 @@ pub async fn resolve_dependencies(
             version: pkg.version.to_string(),
             system: "alpine".to_string(),
             url,
+            fallback_urls,
             provides,
             sha256,
-            signature: None,
+            commit: None,
+            signatures: vec![],
             installed: false,
+            depends: vec![],
+            size: pkg.size,
         });
     }
 
@@ -367,19 +706,29 @@ pub async fn resolve(
     update: &args::Update,
     manifest: &PackagesManifest,
     container: &ContainerLock,
+    project: &Path,
     dependencies: &mut Vec<PackageLock>,
+    apk_keys: &mut Vec<ApkKeyLock>,
+    provenance: &mut Provenance,
 ) -> Result<()> {
+    let image = container::prepare_image(container, project).await?;
     let container = Container::create(
-        &container.image,
+        &image,
         container::Config {
             mounts: &[],
             expose_fuse: false,
+            lockfile_digest: None,
+            prepared: false,
+            platform: None,
+            podman_args: &[],
+            read_only_root: false,
+            init: container.init,
         },
     )
     .await?;
     container
         .run(
-            resolve_dependencies(&container, manifest, dependencies),
+            resolve_dependencies(&container, manifest, dependencies, apk_keys, provenance),
             update.keep,
         )
         .await
@@ -388,6 +737,89 @@ pub async fn resolve(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fake_runtime::{self, FakeRuntime};
+
+    #[test]
+    fn test_snapshot_url() -> Result<()> {
+        let repo = "https://dl-cdn.alpinelinux.org/alpine/v3.18/main";
+        let file = "x86_64/gcc-12.2.1_git20220924-r10.apk";
+        assert_eq!(
+            snapshot_url("https://snapshots.example.com/{date}/", repo, 1689802432, file)?,
+            "https://snapshots.example.com/20230719/alpine/v3.18/main/x86_64/gcc-12.2.1_git20220924-r10.apk"
+        );
+        assert_eq!(
+            snapshot_url("https://snapshots.example.com/{timestamp}", repo, 1689802432, file)?,
+            "https://snapshots.example.com/20230719T213352Z/alpine/v3.18/main/x86_64/gcc-12.2.1_git20220924-r10.apk"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_apk_id() {
+        assert_eq!(
+            split_apk_id("ca-certificates-bundle-20230506-r0"),
+            Some(("ca-certificates-bundle", "20230506-r0"))
+        );
+        assert_eq!(split_apk_id("musl-1.2.4-r1"), Some(("musl", "1.2.4-r1")));
+        assert_eq!(split_apk_id("musl"), None);
+    }
+
+    #[test]
+    fn test_unsatisfied_dependencies() -> Result<()> {
+        let repo_url = Arc::new("https://dl-cdn.alpinelinux.org/alpine/v3.18/main".to_string());
+        let mut dbs = DatabaseCache::default();
+        dbs.read_apkindex_text(
+            &b"C:Q1tzdkdKq7f7RT5T3gq9KS+m7v9Zc=
+P:musl
+V:1.2.4-r1
+A:x86_64
+p:so:libc.musl-x86_64.so.1=1
+
+C:Q1tzdkdKq7f7RT5T3gq9KS+m7v9Zc=
+P:libgcc
+V:12.2.1_git20220924-r10
+A:x86_64
+D:musl>=1.2 so:libc.musl-x86_64.so.1
+p:so:libgcc_s.so.1=1
+
+C:Q1tzdkdKq7f7RT5T3gq9KS+m7v9Zc=
+P:gcc
+V:12.2.1_git20220924-r10
+A:x86_64
+D:so:libgcc_s.so.1 !gcc-doc binutils
+
+"[..],
+            &repo_url,
+        )?;
+
+        let installed = HashSet::from(["musl-1.2.4-r0".to_string()]);
+        let new = |pkgs: &[(&str, &str)]| {
+            pkgs.iter()
+                .map(|(name, version)| (name.to_string(), version.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+
+        // the installed musl isn't in the index anymore, it still provides its name
+        let pkgs = new(&[("libgcc", "12.2.1_git20220924-r10")]);
+        assert_eq!(
+            unsatisfied_dependencies(&dbs, &installed, &pkgs),
+            [("libgcc".to_string(), "so:libc.musl-x86_64.so.1".to_string())]
+        );
+
+        let pkgs = new(&[("musl", "1.2.4-r1"), ("libgcc", "12.2.1_git20220924-r10")]);
+        assert!(unsatisfied_dependencies(&dbs, &installed, &pkgs).is_empty());
+
+        let pkgs = new(&[
+            ("musl", "1.2.4-r1"),
+            ("libgcc", "12.2.1_git20220924-r10"),
+            ("gcc", "12.2.1_git20220924-r10"),
+        ]);
+        assert_eq!(
+            unsatisfied_dependencies(&dbs, &installed, &pkgs),
+            [("gcc".to_string(), "binutils".to_string())]
+        );
+        Ok(())
+    }
 
     #[test]
     fn test_checksum_from_apk() -> Result<()> {
@@ -396,4 +828,83 @@ mod tests {
         assert_eq!(checksum, calculated);
         Ok(())
     }
+
+    #[test]
+    fn test_verify_signature() -> Result<()> {
+        use flate2::write::GzEncoder;
+        use rsa::pkcs8::{EncodePublicKey, LineEnding};
+        use rsa::RsaPrivateKey;
+        use std::io::Write;
+
+        let private_key = RsaPrivateKey::new(&mut rsa::rand_core::OsRng, 1024)?;
+        let keys = vec![ApkKeyLock {
+            name: "test.rsa.pub".to_string(),
+            key: private_key
+                .to_public_key()
+                .to_public_key_pem(LineEnding::LF)?,
+        }];
+
+        // re-sign the control and data section of a real package
+        let (_, control_data) = split_apk(crate::test_data::ALPINE_APK_EXAMPLE)?;
+        let mut rest = crate::test_data::ALPINE_APK_EXAMPLE;
+        pkgs::alpine::read_section(&mut rest, limits::PACKAGE)?;
+        let hashed = Sha256::digest(&control_data);
+        let sig = private_key.sign(Pkcs1v15Sign::new::<Sha256>(), &hashed)?;
+
+        let sign = |name: &str, sig: &[u8]| -> Result<Vec<u8>> {
+            let mut tar = tar::Builder::new(Vec::new());
+            let mut header = tar::Header::new_gnu();
+            header.set_size(sig.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, sig)?;
+            // like abuild, strip the end-of-archive marker
+            let tar = tar.into_inner()?;
+            let tar = &tar[..tar.len() - 1024];
+
+            let mut gz = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            gz.write_all(tar)?;
+            let mut apk = gz.finish()?;
+            apk.extend(rest);
+            Ok(apk)
+        };
+
+        let apk = sign(".SIGN.RSA256.test.rsa.pub", &sig)?;
+        assert_eq!(verify_signature(&apk[..], &keys)?, "test.rsa.pub");
+
+        let apk = sign(".SIGN.RSA256.other.rsa.pub", &sig)?;
+        assert!(verify_signature(&apk[..], &keys).is_err());
+
+        let apk = sign(".SIGN.RSA256.test.rsa.pub", &[0; 128])?;
+        assert!(verify_signature(&apk[..], &keys).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_install_and_diff() -> Result<()> {
+        let runtime = FakeRuntime::new();
+        runtime.reply(&["apk", "info", "-v"], "musl-1.2.4-r1\n");
+        runtime.reply(
+            &["apk", "info", "-v"],
+            "musl-1.2.4-r1\nlibgcc-12.2.1_git20220924-r10\n",
+        );
+        let manifest: PackagesManifest =
+            toml::from_str("system = \"alpine\"\ndependencies = [\"libgcc\"]\n")?;
+        let container = Container {
+            id: fake_runtime::CONTAINER_ID.to_string(),
+        };
+        let new = runtime.run(install_and_diff(&container, &manifest)).await?;
+
+        assert_eq!(new, ["libgcc-12.2.1_git20220924-r10"]);
+        assert_eq!(
+            runtime.execs(),
+            [
+                "apk info -v",
+                "apk upgrade",
+                "apk add -- libgcc",
+                "apk info -v"
+            ]
+        );
+        Ok(())
+    }
 }
@@ -1,11 +1,21 @@
 use crate::args;
 use crate::container::{self, Container};
 use crate::errors::*;
-use crate::lockfile::{ContainerLock, PackageLock};
-use crate::manifest::PackagesManifest;
-use flate2::read::GzDecoder;
+use crate::http;
+use crate::limits;
+use crate::lockfile::{ContainerLock, PackageLock, SignatureLock};
+use crate::manifest::{PackagesManifest, Solver};
+use crate::pkgs;
+use crate::resolver::archlinux_solver::{self, PacmanPackage};
+use crate::resolver::diagnose;
+use crate::resolver::image_files::ImageFiles;
+use crate::resolver::pacman_conf::PacmanConf;
+use crate::resolver::parsers;
+use crate::resolver::provenance::{self, Provenance};
+use crate::utils;
 use std::collections::{HashMap, HashSet};
 use std::io::Read;
+use std::path::Path;
 
 #[derive(Debug, Default, PartialEq)]
 pub struct Package {
@@ -58,9 +68,16 @@ impl Package {
         self.single_value("%NAME%")
     }
 
-    pub fn archive_url(&self) -> Result<String> {
+    /// The filename as url path segment, the `:` of an epoch and the `+` of
+    /// names like `libsigc++` are percent-encoded
+    fn url_filename(&self) -> Result<String> {
         let filename = self.single_value("%FILENAME%")?;
-        let pkgname = self.name()?;
+        Ok(urlencoding::encode(filename).into_owned())
+    }
+
+    pub fn archive_url(&self) -> Result<String> {
+        let filename = self.url_filename()?;
+        let pkgname = urlencoding::encode(self.name()?);
         let idx = pkgname
             .chars()
             .next()
@@ -70,6 +87,12 @@ impl Package {
         ))
     }
 
+    /// The url of the package on a mirror, `server` is a `Server` of the repository the package is in
+    pub fn mirror_url(&self, server: &str) -> Result<String> {
+        let filename = self.url_filename()?;
+        Ok(format!("{}/{filename}", server.trim_end_matches('/')))
+    }
+
     pub fn sha256(&self) -> Result<&str> {
         self.single_value("%SHA256SUM%")
     }
@@ -77,6 +100,44 @@ impl Package {
     pub fn signature(&self) -> Result<&str> {
         self.single_value("%PGPSIG%")
     }
+
+    pub fn size(&self) -> Result<Option<u64>> {
+        if !self.values.contains_key("%CSIZE%") {
+            return Ok(None);
+        }
+        let size = self.single_value("%CSIZE%")?;
+        let size = size
+            .parse()
+            .with_context(|| anyhow!("Invalid package size: {size:?}"))?;
+        Ok(Some(size))
+    }
+}
+
+/// Read all `desc` files from a pacman database tar
+pub fn read_db<R: Read>(r: R) -> Result<Vec<Package>> {
+    let mut tar = tar::Archive::new(r);
+
+    let mut pkgs = Vec::new();
+    for (idx, entry) in tar.entries()?.enumerate() {
+        limits::INDEX.check_entry(idx)?;
+        let mut entry = entry?;
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        if entry.path()?.file_name().and_then(|n| n.to_str()) != Some("desc") {
+            continue;
+        }
+
+        let mut buf = String::new();
+        trace!("Reading package from archive: {:?}", entry.path());
+        entry
+            .read_to_string(&mut buf)
+            .context("Failed to read database entry")?;
+
+        let pkg = Package::parse(&buf).context("Failed to parse database entry as package")?;
+        pkgs.push(pkg);
+    }
+    Ok(pkgs)
 }
 
 #[derive(Debug, Default)]
@@ -91,29 +152,35 @@ impl DatabaseCache {
     }
 
     pub fn import_repo(&mut self, repo: &str, buf: &[u8]) -> Result<()> {
-        let d = GzDecoder::new(buf);
-        let mut tar = tar::Archive::new(d);
-
-        for entry in tar.entries()? {
-            let mut entry = entry?;
-            if entry.header().entry_type() == tar::EntryType::Regular {
-                let mut buf = String::new();
-                trace!("Reading package from archive: {:?}", entry.path());
-                entry
-                    .read_to_string(&mut buf)
-                    .context("Failed to read database entry")?;
-
-                let pkg =
-                    Package::parse(&buf).context("Failed to parse database entry as package")?;
-
-                self.packages.insert(pkg.name()?.to_string(), pkg);
-            }
+        for pkg in read_db(pkgs::archlinux::decompress(buf, limits::INDEX)?)? {
+            self.packages.insert(pkg.name()?.to_string(), pkg);
         }
 
         self.imported_repositories.insert(repo.to_string());
         Ok(())
     }
 
+    /// The `-debug` packages of the resolved packages, they are named after the
+    /// pkgbase and need to have the same version
+    pub fn debug_packages(&self, resolved: &[(String, String, String)]) -> Result<Vec<String>> {
+        let mut debug = Vec::new();
+        for (_, name, version) in resolved {
+            if name.ends_with("-debug") {
+                continue;
+            }
+            let pkg = self.get_package(name)?;
+            let base = pkg.single_value("%BASE%").unwrap_or(name);
+            let name = format!("{base}-debug");
+            let Some(pkg) = self.packages.get(&name) else {
+                continue;
+            };
+            if pkg.single_value("%VERSION%")? == version && !debug.contains(&name) {
+                debug.push(name);
+            }
+        }
+        Ok(debug)
+    }
+
     pub fn get_package(&self, name: &str) -> Result<&Package> {
         self.packages
             .get(name)
@@ -121,77 +188,442 @@ impl DatabaseCache {
     }
 }
 
-pub async fn resolve_dependencies(
-    container: &Container,
+/// The repositories with the debug symbol packages of the official repositories
+const DEBUG_REPOS: &[&str] = &["core-debug", "extra-debug"];
+/// Regular mirrors usually don't carry the debug repositories
+const DEBUG_MIRROR: &str = "https://geo.mirror.pkgbuild.com/$repo/os/$arch";
+
+/// Append the debug repositories to a pacman.conf, unless they are already configured
+pub fn with_debug_repos(conf: &str) -> String {
+    let mut conf = conf.to_string();
+    for repo in DEBUG_REPOS {
+        let section = format!("[{repo}]");
+        if conf.lines().any(|line| line.trim() == section) {
+            continue;
+        }
+        if !conf.ends_with('\n') {
+            conf.push('\n');
+        }
+        conf.push_str(&format!("\n{section}\nServer = {DEBUG_MIRROR}\n"));
+    }
+    conf
+}
+
+/// Read the sync databases of all configured repositories, in order
+async fn read_sync_dbs(container: &Container) -> Result<Vec<(String, Vec<u8>)>> {
+    let buf = container
+        .exec(
+            &["pacman-conf", "--repo-list"],
+            container::Exec {
+                capture_stdout: true,
+                ..Default::default()
+            },
+        )
+        .await?;
+    let buf = String::from_utf8(buf).context("Failed to decode pacman-conf output as utf8")?;
+
+    let mut databases = Vec::new();
+    for repo in buf.lines() {
+        debug!("Reading sync database: {repo:?}");
+        let buf = container
+            .cat(&format!("/var/lib/pacman/sync/{repo}.db"))
+            .await?;
+        databases.push((repo.to_string(), buf));
+    }
+    Ok(databases)
+}
+
+fn index_sync_dbs(databases: &[(String, Vec<u8>)]) -> Result<archlinux_solver::Index> {
+    let mut index = archlinux_solver::Index::default();
+    for (repo, buf) in databases {
+        for pkg in read_db(pkgs::archlinux::decompress(&buf[..], limits::INDEX)?)? {
+            index.add(PacmanPackage::from_desc(repo, &pkg)?);
+        }
+    }
+    Ok(index)
+}
+
+/// Resolve the dependencies with the builtin solver from the sync databases
+fn solve_builtin(
     manifest: &PackagesManifest,
-    dependencies: &mut Vec<PackageLock>,
-) -> Result<()> {
-    info!("Syncing package datatabase...");
-    container
-        .exec(&["pacman", "-Sy"], container::Exec::default())
+    index: &archlinux_solver::Index,
+    local: &[Package],
+    distro: Option<&str>,
+) -> Result<Vec<(String, String, String)>> {
+    let installed = local
+        .iter()
+        .map(|pkg| PacmanPackage::from_desc("local", pkg))
+        .collect::<Result<Vec<_>>>()?;
+    let requested = manifest
+        .dependencies
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    let options = archlinux_solver::SolveOptions {
+        assume_installed: manifest
+            .assume_installed
+            .iter()
+            .map(|s| archlinux_solver::Dependency::parse(s))
+            .collect::<Result<_>>()?,
+        ignore_groups: manifest.ignore_groups.clone(),
+    };
+    let pkgs = archlinux_solver::solve(index, installed, &requested, &options)
+        .map_err(|err| diagnose::explain_in(distro, "archlinux", err, &index.candidates()))?;
+    Ok(pkgs
+        .iter()
+        .map(|pkg| (pkg.repo.clone(), pkg.name.clone(), pkg.version.clone()))
+        .collect())
+}
+
+/// Record the modification time of the sync databases, pacman sets it to the
+/// time the mirror last modified them
+async fn record_sync_dbs(container: &Container, provenance: &mut Provenance) -> Result<()> {
+    let buf = container
+        .exec(
+            &["sh", "-c", "stat -c '%Y %n' /var/lib/pacman/sync/*.db"],
+            container::Exec {
+                capture_stdout: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .context("Failed to read modification time of sync databases")?;
+    let buf = String::from_utf8(buf).context("Failed to decode stat output as utf8")?;
+    for (filename, epoch) in provenance::parse_stat_mtimes(&buf)? {
+        let repo = filename.strip_suffix(".db").unwrap_or(filename);
+        provenance.record_index(repo, epoch)?;
+    }
+    Ok(())
+}
+
+/// The first http mirror of a repository, as configured in the container
+async fn repo_mirror(container: &Container, repo: &str) -> Result<Option<String>> {
+    let buf = container
+        .exec(
+            &["pacman-conf", "--repo", repo, "Server"],
+            container::Exec {
+                capture_stdout: true,
+                ..Default::default()
+            },
+        )
         .await?;
+    let buf = String::from_utf8(buf).context("Failed to decode pacman-conf output as utf8")?;
+    Ok(parse_servers(&buf).map(String::from))
+}
 
-    info!("Resolving dependencies...");
+/// pacman-conf prints one server per line, with `$repo` and `$arch` already substituted
+pub fn parse_servers(buf: &str) -> Option<&str> {
+    buf.lines()
+        .map(str::trim)
+        .find(|line| line.starts_with("https://") || line.starts_with("http://"))
+}
+
+/// Resolve the dependencies with pacman, returns the repository, name and version of each package
+async fn solve(
+    container: &Container,
+    manifest: &PackagesManifest,
+) -> Result<Vec<(String, String, String)>> {
     let mut cmd = vec![
         "pacman",
         "-Sup",
         "--noconfirm",
         "--print-format",
         "%r %n %v",
-        "--",
-    ];
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect::<Vec<_>>();
+    for dep in &manifest.assume_installed {
+        cmd.push(format!("--assume-installed={dep}"));
+    }
+    for group in &manifest.ignore_groups {
+        cmd.push(format!("--ignoregroup={group}"));
+    }
+    cmd.push("--".to_string());
     for dep in &manifest.dependencies {
-        cmd.push(dep.as_str());
+        cmd.push(dep.to_string());
     }
-    let buf = container
+    let buf = match container
         .exec(
             &cmd,
             container::Exec {
                 capture_stdout: true,
+                capture_stderr: true,
                 ..Default::default()
             },
         )
-        .await?;
+        .await
+    {
+        Ok(buf) => buf,
+        Err(err) => {
+            // only used for suggestions, so this may fail
+            let index = match read_sync_dbs(container).await {
+                Ok(databases) => utils::spawn_blocking(move |_| index_sync_dbs(&databases)).await,
+                Err(err) => Err(err),
+            };
+            let index = index.unwrap_or_else(|err| {
+                debug!("Failed to read sync databases: {err:#}");
+                Default::default()
+            });
+            return Err(diagnose::explain(container, "archlinux", err, &index.candidates()).await);
+        }
+    };
     let buf = String::from_utf8(buf).context("Failed to decode pacman output as utf8")?;
+    parsers::pacman_print_format(&buf)
+}
+
+/// Import the sync database of a repository into `dbs`, unless it already is
+async fn import_repo(container: &Container, dbs: &mut DatabaseCache, repo: &str) -> Result<()> {
+    if dbs.has_repo(repo) {
+        return Ok(());
+    }
+    let buf = container
+        .cat(&format!("/var/lib/pacman/sync/{repo}.db"))
+        .await?;
+    let mut imported = std::mem::take(dbs);
+    let repo = repo.to_string();
+    *dbs = utils::spawn_blocking(move |_| {
+        imported.import_repo(&repo, &buf)?;
+        Ok(imported)
+    })
+    .await?;
+    Ok(())
+}
+
+pub async fn resolve_dependencies(
+    container: &Container,
+    manifest: &PackagesManifest,
+    dependencies: &mut Vec<PackageLock>,
+    provenance: &mut Provenance,
+) -> Result<()> {
+    if manifest.debug_symbols {
+        let conf = container.cat("/etc/pacman.conf").await?;
+        let conf = String::from_utf8(conf).context("Failed to decode pacman.conf as utf8")?;
+        debug!("Adding repositories for debug symbols to pacman.conf");
+        container
+            .write_file("/etc/", "pacman.conf", with_debug_repos(&conf).as_bytes())
+            .await?;
+    }
 
+    info!("Syncing package datatabase...");
+    container
+        .exec(&["pacman", "-Sy"], container::Exec::default())
+        .await?;
+    provenance
+        .detect_tool(
+            container,
+            "pacman",
+            &["pacman", "--version"],
+            provenance::parse_pacman_version,
+        )
+        .await;
+    record_sync_dbs(container, provenance).await?;
+    provenance
+        .record_sources(container, &["/etc/pacman.conf", "/etc/pacman.d/mirrorlist"])
+        .await?;
+
+    info!("Resolving dependencies...");
     let mut dbs = DatabaseCache::default();
-    for line in buf.lines() {
-        let mut line = line.split(' ');
-        let repo = line.next().context("Missing repo in pacman output")?;
-        let name = line.next().context("Missing pkg name in pacman output")?;
-        let version = line.next().context("Missing version in pacman output")?;
-        if let Some(trailing) = line.next() {
-            bail!("Trailing data in pacman output: {trailing:?}");
+    let mut resolved = solve(container, manifest).await?;
+    if manifest.debug_symbols {
+        let repos = resolved
+            .iter()
+            .map(|(repo, _, _)| repo.clone())
+            .chain(DEBUG_REPOS.iter().map(|repo| repo.to_string()))
+            .collect::<Vec<_>>();
+        for repo in &repos {
+            import_repo(container, &mut dbs, repo).await?;
+        }
+        let debug = dbs.debug_packages(&resolved)?;
+        info!("Adding {} debug symbol packages...", debug.len());
+        if !debug.is_empty() {
+            let mut manifest = manifest.clone();
+            manifest.dependencies.extend(debug);
+            resolved = solve(container, &manifest).await?;
         }
+    }
 
+    let mut mirrors = HashMap::new();
+    for (repo, name, version) in &resolved {
         debug!("Detected dependency name={name:?} version={version:?} repo={repo:?}");
-        if !dbs.has_repo(repo) {
-            let buf = container
-                .cat(&format!("/var/lib/pacman/sync/{repo}.db"))
-                .await?;
-            dbs.import_repo(repo, &buf)?;
+        import_repo(container, &mut dbs, repo).await?;
+        if !mirrors.contains_key(repo) {
+            // newly released packages may not be on archive.archlinux.org yet
+            let mirror = match repo_mirror(container, repo).await {
+                Ok(mirror) => mirror,
+                Err(err) => {
+                    warn!("Failed to find mirror for repository {repo:?}: {err:#}");
+                    None
+                }
+            };
+            mirrors.insert(repo.clone(), mirror);
         }
 
         let pkg = dbs.get_package(name)?;
+        dependencies.push(lock_package(manifest, pkg, mirrors[repo].as_deref())?);
+    }
+
+    Ok(())
+}
 
-        // record provides if it mentions a dependency
-        let mut provides = Vec::new();
-        for value in pkg.values.get("%PROVIDES%").into_iter().flatten() {
-            if manifest.dependencies.contains(value) {
-                provides.push(value.to_string());
+/// The lockfile entry of a resolved package, `mirror` is the server of its
+/// repository that's used as fallback
+fn lock_package(
+    manifest: &PackagesManifest,
+    pkg: &Package,
+    mirror: Option<&str>,
+) -> Result<PackageLock> {
+    // record provides if it mentions a dependency
+    let mut provides = Vec::new();
+    for value in pkg.values.get("%PROVIDES%").into_iter().flatten() {
+        if manifest.dependencies.contains(value) {
+            provides.push(value.to_string());
+        }
+    }
+
+    Ok(PackageLock {
+        name: pkg.name()?.to_string(),
+        version: pkg.single_value("%VERSION%")?.to_string(),
+        system: "archlinux".to_string(),
+        url: pkg.archive_url()?,
+        fallback_urls: mirror
+            .map(|server| pkg.mirror_url(server))
+            .transpose()?
+            .into_iter()
+            .collect(),
+        provides,
+        sha256: pkg.sha256()?.to_string(),
+        commit: None,
+        signatures: vec![SignatureLock::openpgp(pkg.signature()?)],
+        installed: false,
+        depends: vec![],
+        size: pkg.size()?,
+    })
+}
+
+/// Download the sync database of a repository on the host, the servers are tried in order
+async fn fetch_sync_db(
+    client: &http::Client,
+    repo: &str,
+    servers: &[String],
+    provenance: &mut Provenance,
+) -> Result<Vec<u8>> {
+    let mut last_err = None;
+    for server in servers {
+        let url = format!("{server}/{repo}.db");
+        match client.fetch_modified(&url).await {
+            Ok((buf, last_modified)) => {
+                if let Some(value) = last_modified {
+                    match provenance::parse_http_date(&value) {
+                        Ok(epoch) => provenance.record_index(repo, epoch)?,
+                        Err(err) => warn!("Failed to record age of sync database: {err:#}"),
+                    }
+                }
+                return Ok(buf.to_vec());
+            }
+            Err(err) => {
+                warn!("Failed to download sync database from {url:?}: {err:#}");
+                last_err = Some(err);
             }
         }
+    }
+    Err(last_err.unwrap_or_else(|| anyhow!("Repository has no servers configured")))
+        .with_context(|| anyhow!("Failed to download sync database of {repo:?}"))
+}
 
-        dependencies.push(PackageLock {
-            name: name.to_string(),
-            version: version.to_string(),
-            system: "archlinux".to_string(),
-            url: pkg.archive_url()?,
-            provides,
-            sha256: pkg.sha256()?.to_string(),
-            signature: Some(pkg.signature()?.to_string()),
-            installed: false,
-        });
+/// Resolve the dependencies with the builtin solver. pacman.conf and the local
+/// database are read from the layers of the image and the sync databases are
+/// downloaded on the host, no container is involved.
+pub async fn resolve_builtin(
+    image: &str,
+    manifest: &PackagesManifest,
+    dependencies: &mut Vec<PackageLock>,
+    provenance: &mut Provenance,
+) -> Result<()> {
+    info!("Reading pacman configuration from image...");
+    let files = ImageFiles::read(
+        image,
+        &[
+            "/etc/os-release",
+            "/usr/lib/os-release",
+            "/etc/pacman.conf",
+            "/etc/pacman.d",
+            "/var/lib/pacman/local",
+        ],
+    )
+    .await?;
+    let mut conf = files.text("/etc/pacman.conf")?;
+    if manifest.debug_symbols {
+        debug!("Adding repositories for debug symbols to pacman.conf");
+        conf = with_debug_repos(&conf);
+    }
+    let parsed = PacmanConf::parse(&files, &conf)?;
+    provenance
+        .sources
+        .insert("/etc/pacman.conf".to_string(), conf.clone());
+    let included = parsed.paths[1..]
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    provenance.record_image_sources(&files, &included)?;
+
+    let local = files.tar("/var/lib/pacman/local")?;
+    let local = utils::spawn_blocking(move |_| read_db(&local[..])).await?;
+    let arch = match parsed.architecture.as_deref() {
+        Some(arch) if arch != "auto" => arch.to_string(),
+        _ => local
+            .iter()
+            .find(|pkg| pkg.name().ok() == Some("pacman"))
+            .context("Failed to detect architecture, pacman is not installed in image")?
+            .single_value("%ARCH%")?
+            .to_string(),
+    };
+
+    info!("Downloading sync databases...");
+    let client = http::Client::new()?;
+    let mut databases = Vec::new();
+    let mut mirrors = HashMap::new();
+    for repo in &parsed.repos {
+        let servers = repo.urls(&arch);
+        let buf = fetch_sync_db(&client, &repo.name, &servers, provenance).await?;
+        databases.push((repo.name.clone(), buf));
+        let mirror = servers
+            .into_iter()
+            .find(|server| server.starts_with("https://") || server.starts_with("http://"));
+        mirrors.insert(repo.name.clone(), mirror);
+    }
+
+    let (index, dbs, local) = utils::spawn_blocking(move |_| {
+        let index = index_sync_dbs(&databases)?;
+        // import in reverse, so packages in earlier repositories take precedence
+        let mut dbs = DatabaseCache::default();
+        for (repo, buf) in databases.iter().rev() {
+            dbs.import_repo(repo, buf)?;
+        }
+        Ok((index, dbs, local))
+    })
+    .await?;
+
+    info!("Resolving dependencies...");
+    let distro = files
+        .file("/etc/os-release")?
+        .and_then(|buf| diagnose::parse_pretty_name(&String::from_utf8_lossy(buf)));
+    let mut resolved = solve_builtin(manifest, &index, &local, distro.as_deref())?;
+    if manifest.debug_symbols {
+        let debug = dbs.debug_packages(&resolved)?;
+        info!("Adding {} debug symbol packages...", debug.len());
+        if !debug.is_empty() {
+            let mut manifest = manifest.clone();
+            manifest.dependencies.extend(debug);
+            resolved = solve_builtin(&manifest, &index, &local, distro.as_deref())?;
+        }
+    }
+
+    for (repo, name, version) in &resolved {
+        debug!("Detected dependency name={name:?} version={version:?} repo={repo:?}");
+        let pkg = dbs.get_package(name)?;
+        let mirror = mirrors.get(repo).and_then(Option::as_deref);
+        dependencies.push(lock_package(manifest, pkg, mirror)?);
     }
 
     Ok(())
@@ -201,19 +633,31 @@ pub async fn resolve(
     update: &args::Update,
     manifest: &PackagesManifest,
     container: &ContainerLock,
+    project: &Path,
     dependencies: &mut Vec<PackageLock>,
+    provenance: &mut Provenance,
 ) -> Result<()> {
+    let image = container::prepare_image(container, project).await?;
+    if manifest.solver == Solver::Builtin {
+        return resolve_builtin(&image, manifest, dependencies, provenance).await;
+    }
     let container = Container::create(
-        &container.image,
+        &image,
         container::Config {
             mounts: &[],
             expose_fuse: false,
+            lockfile_digest: None,
+            prepared: false,
+            platform: None,
+            podman_args: &[],
+            read_only_root: false,
+            init: container.init,
         },
     )
     .await?;
     container
         .run(
-            resolve_dependencies(&container, manifest, dependencies),
+            resolve_dependencies(&container, manifest, dependencies, provenance),
             update.keep,
         )
         .await
@@ -293,6 +737,10 @@ ninja
             pkg.archive_url()?,
             "https://archive.archlinux.org/packages/z/zstd/zstd-1.5.5-1-x86_64.pkg.tar.zst"
         );
+        assert_eq!(
+            pkg.mirror_url("https://geo.mirror.pkgbuild.com/core/os/x86_64/")?,
+            "https://geo.mirror.pkgbuild.com/core/os/x86_64/zstd-1.5.5-1-x86_64.pkg.tar.zst"
+        );
         assert_eq!(
             pkg.sha256()?,
             "1891970afabc725e72c6a9bb2c127d906c1d3cc70309336fbe87adbd460c05b8"
@@ -332,6 +780,39 @@ ninja
         Ok(())
     }
 
+    #[test]
+    fn test_urls_with_epoch() -> Result<()> {
+        let mut pkg = Package::default();
+        pkg.add_values("%FILENAME%", &["rust-1:1.70.0-1-x86_64.pkg.tar.zst"]);
+        pkg.add_values("%NAME%", &["rust"]);
+        assert_eq!(
+            pkg.archive_url()?,
+            "https://archive.archlinux.org/packages/r/rust/rust-1%3A1.70.0-1-x86_64.pkg.tar.zst"
+        );
+        assert_eq!(
+            pkg.mirror_url("https://geo.mirror.pkgbuild.com/extra/os/x86_64")?,
+            "https://geo.mirror.pkgbuild.com/extra/os/x86_64/rust-1%3A1.70.0-1-x86_64.pkg.tar.zst"
+        );
+
+        let mut pkg = Package::default();
+        pkg.add_values("%FILENAME%", &["libsigc++-2.12.1-1-x86_64.pkg.tar.zst"]);
+        pkg.add_values("%NAME%", &["libsigc++"]);
+        assert_eq!(
+            pkg.archive_url()?,
+            "https://archive.archlinux.org/packages/l/libsigc%2B%2B/libsigc%2B%2B-2.12.1-1-x86_64.pkg.tar.zst"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_servers() {
+        assert_eq!(
+            parse_servers("file:///srv/repo\nhttps://geo.mirror.pkgbuild.com/core/os/x86_64\nhttps://mirror.example.com/core/os/x86_64\n"),
+            Some("https://geo.mirror.pkgbuild.com/core/os/x86_64")
+        );
+        assert_eq!(parse_servers(""), None);
+    }
+
     #[test]
     fn test_database_cache_import() -> Result<()> {
         let mut db = DatabaseCache::default();
@@ -443,6 +924,13 @@ procps-ng
         db.import_repo("core", &data)?;
         assert!(db.has_repo("core"));
 
+        // some mirrors and custom repositories serve uncompressed databases
+        let mut plain = Vec::new();
+        flate2::read::GzDecoder::new(&data[..]).read_to_end(&mut plain)?;
+        let mut plain_db = DatabaseCache::default();
+        plain_db.import_repo("custom", &plain)?;
+        assert_eq!(plain_db.get_package("rust")?, db.get_package("rust")?);
+
         let pkg = db.get_package("rust")?;
         let mut expected = Package::default();
         expected.add_values("%FILENAME%", &["rust-1:1.70.0-1-x86_64.pkg.tar.zst"]);
@@ -504,4 +992,50 @@ procps-ng
 
         Ok(())
     }
+
+    #[test]
+    fn test_with_debug_repos() {
+        let conf = "[core]\nInclude = /etc/pacman.d/mirrorlist\n";
+        let expected = "[core]
+Include = /etc/pacman.d/mirrorlist
+
+[core-debug]
+Server = https://geo.mirror.pkgbuild.com/$repo/os/$arch
+
+[extra-debug]
+Server = https://geo.mirror.pkgbuild.com/$repo/os/$arch
+";
+        assert_eq!(with_debug_repos(conf), expected);
+        assert_eq!(with_debug_repos(expected), expected);
+    }
+
+    #[test]
+    fn test_debug_packages() -> Result<()> {
+        let mut dbs = DatabaseCache::default();
+        for (name, base, version) in [
+            ("zstd", "zstd", "1.5.5-1"),
+            ("zstd-debug", "zstd", "1.5.5-1"),
+            ("gcc-libs", "gcc", "13.2.1-3"),
+            ("gcc-debug", "gcc", "13.2.1-3"),
+            ("openssl", "openssl", "3.1.4-1"),
+            ("openssl-debug", "openssl", "3.1.3-1"),
+            ("tzdata", "tzdata", "2023c-1"),
+        ] {
+            let mut pkg = Package::default();
+            pkg.add_values("%NAME%", &[name]);
+            pkg.add_values("%BASE%", &[base]);
+            pkg.add_values("%VERSION%", &[version]);
+            dbs.packages.insert(name.to_string(), pkg);
+        }
+        let resolved = [
+            ("core", "zstd", "1.5.5-1"),
+            ("core", "gcc-libs", "13.2.1-3"),
+            ("core", "openssl", "3.1.4-1"),
+            ("core", "tzdata", "2023c-1"),
+            ("core-debug", "zstd-debug", "1.5.5-1"),
+        ]
+        .map(|(repo, name, version)| (repo.to_string(), name.to_string(), version.to_string()));
+        assert_eq!(dbs.debug_packages(&resolved)?, ["zstd-debug", "gcc-debug"]);
+        Ok(())
+    }
 }
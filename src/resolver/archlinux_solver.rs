@@ -0,0 +1,482 @@
+//! Experimental dependency solver that resolves pacman dependencies from the
+//! sync databases directly, instead of asking pacman inside of the container.
+
+use crate::errors::*;
+use crate::resolver::archlinux::Package;
+use crate::resolver::diagnose::{Candidate, UnknownPackage};
+use crate::version::{self, Relation};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dependency {
+    pub name: String,
+    pub constraint: Option<(Relation, String)>,
+}
+
+impl Dependency {
+    /// Parse a pacman dependency like `glibc>=2.38` or a provide like `libzstd.so=1-64`
+    pub fn parse(s: &str) -> Result<Self> {
+        // optdepends and depends can have a description
+        let s = s.split_once(": ").map(|(s, _)| s).unwrap_or(s);
+        let (name, constraint) = version::split_constraint(s)?;
+        Ok(Dependency {
+            name: name.to_string(),
+            constraint: constraint.map(|(relation, version)| (relation, version.to_string())),
+        })
+    }
+
+    pub fn matches_version(&self, version: Option<&str>) -> bool {
+        match (&self.constraint, version) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some((relation, expected)), Some(version)) => {
+                relation.matches(version::pacman_cmp(version, expected))
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PacmanPackage {
+    pub repo: String,
+    pub name: String,
+    pub version: String,
+    pub provides: Vec<Dependency>,
+    pub depends: Vec<Dependency>,
+    pub conflicts: Vec<Dependency>,
+    pub replaces: Vec<Dependency>,
+    pub groups: Vec<String>,
+}
+
+impl PacmanPackage {
+    pub fn from_desc(repo: &str, pkg: &Package) -> Result<Self> {
+        let list = |key| -> Result<Vec<Dependency>> {
+            pkg.values
+                .get(key)
+                .into_iter()
+                .flatten()
+                .map(|s| Dependency::parse(s))
+                .collect()
+        };
+        Ok(PacmanPackage {
+            repo: repo.to_string(),
+            name: pkg.name()?.to_string(),
+            version: pkg.single_value("%VERSION%")?.to_string(),
+            provides: list("%PROVIDES%")?,
+            depends: list("%DEPENDS%")?,
+            conflicts: list("%CONFLICTS%")?,
+            replaces: list("%REPLACES%")?,
+            groups: pkg.values.get("%GROUPS%").cloned().unwrap_or_default(),
+        })
+    }
+
+    /// Check if this package satisfies a dependency, either by name or with `provides`
+    fn satisfies(&self, dep: &Dependency) -> bool {
+        if self.name == dep.name && dep.matches_version(Some(&self.version)) {
+            return true;
+        }
+        self.provides
+            .iter()
+            .filter(|p| p.name == dep.name)
+            .any(|p| {
+                // unversioned provides can't satisfy versioned dependencies
+                let version = p.constraint.as_ref().map(|(_, v)| v.as_str());
+                dep.matches_version(version)
+            })
+    }
+}
+
+/// The sync databases, in the order they are configured in pacman.conf
+#[derive(Debug, Default)]
+pub struct Index {
+    pkgs: Vec<Arc<PacmanPackage>>,
+    by_name: HashMap<String, Arc<PacmanPackage>>,
+}
+
+impl Index {
+    pub fn add(&mut self, pkg: PacmanPackage) {
+        let pkg = Arc::new(pkg);
+        // packages in earlier repositories take precedence
+        self.by_name
+            .entry(pkg.name.clone())
+            .or_insert_with(|| pkg.clone());
+        self.pkgs.push(pkg);
+    }
+
+    /// Find a package by name, or the first package providing the dependency
+    /// All package names and provides, used to suggest alternatives for typos
+    pub fn candidates(&self) -> Vec<Candidate<'_>> {
+        let mut candidates = Vec::new();
+        for pkg in &self.pkgs {
+            candidates.push(Candidate::package(&pkg.name));
+            for provides in &pkg.provides {
+                candidates.push(Candidate::provided(&provides.name, &pkg.name));
+            }
+        }
+        candidates
+    }
+
+    fn find(&self, dep: &Dependency) -> Option<&Arc<PacmanPackage>> {
+        if let Some(pkg) = self.by_name.get(&dep.name) {
+            if dep.matches_version(Some(&pkg.version)) {
+                return Some(pkg);
+            }
+        }
+        self.pkgs
+            .iter()
+            .find(|pkg| pkg.name != dep.name && pkg.satisfies(dep))
+    }
+}
+
+/// Like pacman's `--assume-installed` and `--ignoregroup`
+#[derive(Debug, Default)]
+pub struct SolveOptions {
+    pub assume_installed: Vec<Dependency>,
+    pub ignore_groups: Vec<String>,
+}
+
+impl SolveOptions {
+    fn is_ignored(&self, pkg: &PacmanPackage) -> bool {
+        pkg.groups.iter().any(|g| self.ignore_groups.contains(g))
+    }
+}
+
+struct Solver<'a> {
+    index: &'a Index,
+    options: &'a SolveOptions,
+    installed: BTreeMap<String, Arc<PacmanPackage>>,
+    selected: BTreeMap<String, Arc<PacmanPackage>>,
+    queue: VecDeque<Arc<PacmanPackage>>,
+}
+
+impl Solver<'_> {
+    fn select(&mut self, pkg: &Arc<PacmanPackage>) -> Result<()> {
+        if let Some(existing) = self.selected.get(&pkg.name) {
+            if existing.version != pkg.version {
+                bail!(
+                    "Conflicting versions selected for {:?}: {:?} and {:?}",
+                    pkg.name,
+                    existing.version,
+                    pkg.version
+                );
+            }
+            return Ok(());
+        }
+        trace!("Selecting package: {:?} {:?}", pkg.name, pkg.version);
+        self.selected.insert(pkg.name.clone(), pkg.clone());
+        self.queue.push_back(pkg.clone());
+        Ok(())
+    }
+
+    /// The packages that are going to be present after the transaction
+    fn resulting(&self) -> impl Iterator<Item = &Arc<PacmanPackage>> {
+        self.selected.values().chain(
+            self.installed
+                .iter()
+                .filter(|(name, _)| !self.selected.contains_key(*name))
+                .map(|(_, pkg)| pkg),
+        )
+    }
+
+    fn is_satisfied(&self, dep: &Dependency) -> bool {
+        let assumed = self.options.assume_installed.iter().any(|a| {
+            let version = a.constraint.as_ref().map(|(_, v)| v.as_str());
+            a.name == dep.name && dep.matches_version(version)
+        });
+        assumed || self.resulting().any(|pkg| pkg.satisfies(dep))
+    }
+
+    fn run(&mut self) -> Result<()> {
+        while let Some(pkg) = self.queue.pop_front() {
+            for dep in &pkg.depends {
+                if self.is_satisfied(dep) {
+                    continue;
+                }
+                let found = self.index.find(dep).with_context(|| {
+                    anyhow!("Unsatisfiable dependency of {:?}: {:?}", pkg.name, dep.name)
+                })?;
+                if self.options.is_ignored(found) {
+                    bail!(
+                        "Dependency of {:?} is in an ignored group: {:?}",
+                        pkg.name,
+                        found.name
+                    );
+                }
+                self.select(&found.clone())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_conflicts(&self) -> Result<()> {
+        for pkg in self.selected.values() {
+            for conflict in &pkg.conflicts {
+                if let Some(other) = self
+                    .resulting()
+                    .find(|other| other.name != pkg.name && other.satisfies(conflict))
+                {
+                    bail!(
+                        "Package {:?} conflicts with {:?} {:?}",
+                        pkg.name,
+                        other.name,
+                        other.version
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Upgrade all installed packages (`-Su`) and install the requested ones,
+/// returns the packages that need to be downloaded
+pub fn solve(
+    index: &Index,
+    installed: Vec<PacmanPackage>,
+    requested: &[&str],
+    options: &SolveOptions,
+) -> Result<Vec<Arc<PacmanPackage>>> {
+    let mut solver = Solver {
+        index,
+        options,
+        installed: installed
+            .into_iter()
+            .map(|pkg| (pkg.name.clone(), Arc::new(pkg)))
+            .collect(),
+        selected: BTreeMap::new(),
+        queue: VecDeque::new(),
+    };
+
+    // packages that got replaced by a different package in the sync database
+    for pkg in &index.pkgs {
+        // ignore packages that are shadowed by an earlier repository
+        if !Arc::ptr_eq(&index.by_name[&pkg.name], pkg) {
+            continue;
+        }
+        let replaced = solver
+            .installed
+            .iter()
+            .filter(|(name, _)| *name != &pkg.name)
+            .filter(|(_, installed)| pkg.replaces.iter().any(|r| installed.satisfies(r)))
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        if !replaced.is_empty() {
+            debug!("Package {:?} replaces {:?}", pkg.name, replaced);
+            for name in replaced {
+                solver.installed.remove(&name);
+            }
+            solver.select(pkg)?;
+        }
+    }
+
+    let installed = solver.installed.values().cloned().collect::<Vec<_>>();
+    for pkg in installed {
+        if let Some(candidate) = index.by_name.get(&pkg.name) {
+            if version::pacman_cmp(&candidate.version, &pkg.version) == Ordering::Greater {
+                if options.is_ignored(candidate) {
+                    debug!(
+                        "Not upgrading package in ignored group: {:?}",
+                        candidate.name
+                    );
+                    continue;
+                }
+                solver.select(candidate)?;
+            }
+        }
+    }
+
+    for request in requested {
+        let dep = Dependency::parse(request)?;
+        let pkg = index
+            .find(&dep)
+            .ok_or_else(|| UnknownPackage(request.to_string()))?
+            .clone();
+        // like `pacman -S`, reinstalling an up-to-date package is a no-op
+        if solver
+            .installed
+            .get(&pkg.name)
+            .is_some_and(|installed| installed.version == pkg.version)
+        {
+            continue;
+        }
+        solver.select(&pkg)?;
+    }
+
+    solver.run()?;
+    solver.check_conflicts()?;
+
+    Ok(solver.selected.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pkg(repo: &str, name: &str, version: &str, fields: &[(&str, &[&str])]) -> PacmanPackage {
+        let mut desc = Package::default();
+        desc.add_values("%NAME%", &[name]);
+        desc.add_values("%VERSION%", &[version]);
+        for (key, values) in fields {
+            desc.add_values(key, values);
+        }
+        PacmanPackage::from_desc(repo, &desc).unwrap()
+    }
+
+    fn index() -> Index {
+        let mut index = Index::default();
+        index.add(pkg("core", "glibc", "2.38-3", &[]));
+        index.add(pkg(
+            "core",
+            "gcc-libs",
+            "13.2.1-3",
+            &[("%DEPENDS%", &["glibc>=2.27"])],
+        ));
+        index.add(pkg(
+            "core",
+            "zstd",
+            "1.5.5-1",
+            &[
+                ("%PROVIDES%", &["libzstd.so=1-64"]),
+                ("%DEPENDS%", &["glibc", "gcc-libs"]),
+            ],
+        ));
+        index.add(pkg(
+            "extra",
+            "rust",
+            "1:1.73.0-1",
+            &[
+                ("%PROVIDES%", &["cargo", "rustfmt"]),
+                ("%REPLACES%", &["cargo"]),
+                ("%CONFLICTS%", &["cargo"]),
+                ("%DEPENDS%", &["gcc-libs", "libzstd.so=1-64"]),
+            ],
+        ));
+        index.add(pkg("extra", "glibc", "2.39-1", &[]));
+        index.add(pkg(
+            "extra",
+            "bash",
+            "5.2.015-5",
+            &[("%CONFLICTS%", &["zsh"])],
+        ));
+        index
+    }
+
+    fn names(pkgs: Vec<Arc<PacmanPackage>>) -> Vec<(String, String)> {
+        pkgs.iter()
+            .map(|p| (p.name.clone(), p.version.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_dependency() -> Result<()> {
+        assert_eq!(
+            Dependency::parse("glibc>=2.27")?,
+            Dependency {
+                name: "glibc".to_string(),
+                constraint: Some((Relation::GreaterOrEqual, "2.27".to_string())),
+            }
+        );
+        assert_eq!(
+            Dependency::parse("gdb: rust-gdb script")?,
+            Dependency {
+                name: "gdb".to_string(),
+                constraint: None,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_solve() -> Result<()> {
+        let index = index();
+        let installed = vec![pkg("core", "glibc", "2.38-1", &[])];
+        assert_eq!(
+            names(solve(
+                &index,
+                installed,
+                &["rust"],
+                &SolveOptions::default()
+            )?),
+            vec![
+                ("gcc-libs".to_string(), "13.2.1-3".to_string()),
+                // the core repo takes precedence over extra
+                ("glibc".to_string(), "2.38-3".to_string()),
+                ("rust".to_string(), "1:1.73.0-1".to_string()),
+                ("zstd".to_string(), "1.5.5-1".to_string()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_solve_replaces() -> Result<()> {
+        let index = index();
+        let installed = vec![
+            pkg("core", "glibc", "2.38-3", &[]),
+            pkg("core", "gcc-libs", "13.2.1-3", &[]),
+            pkg(
+                "core",
+                "zstd",
+                "1.5.5-1",
+                &[("%PROVIDES%", &["libzstd.so=1-64"])],
+            ),
+            pkg("extra", "cargo", "1.69.0-1", &[]),
+        ];
+        assert_eq!(
+            names(solve(&index, installed, &[], &SolveOptions::default())?),
+            vec![("rust".to_string(), "1:1.73.0-1".to_string())]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_solve_errors() {
+        let index = index();
+        let options = SolveOptions::default();
+        assert!(solve(&index, vec![], &["does-not-exist"], &options).is_err());
+        let installed = vec![pkg("extra", "zsh", "5.9-4", &[])];
+        assert!(solve(&index, installed, &["bash"], &options).is_err());
+    }
+
+    #[test]
+    fn test_solve_options() -> Result<()> {
+        let mut index = index();
+        index.add(pkg(
+            "extra",
+            "texlive-bin",
+            "2023.66594-3",
+            &[("%GROUPS%", &["texlive"]), ("%DEPENDS%", &["glibc"])],
+        ));
+        index.add(pkg(
+            "extra",
+            "texlive-core",
+            "2023.66594-3",
+            &[("%DEPENDS%", &["texlive-bin"])],
+        ));
+
+        let options = SolveOptions {
+            assume_installed: vec![Dependency::parse("gcc-libs=13.2.1-3")?],
+            ignore_groups: vec!["texlive".to_string()],
+        };
+        let installed = vec![
+            pkg("core", "glibc", "2.38-3", &[]),
+            pkg(
+                "extra",
+                "texlive-bin",
+                "2023.1-1",
+                &[("%GROUPS%", &["texlive"])],
+            ),
+        ];
+        // gcc-libs is assumed to be installed and texlive-bin isn't upgraded
+        assert_eq!(
+            names(solve(&index, installed, &["zstd"], &options)?),
+            vec![("zstd".to_string(), "1.5.5-1".to_string())]
+        );
+
+        // pulling in an ignored package as dependency fails
+        assert!(solve(&index, vec![], &["texlive-core"], &options).is_err());
+        Ok(())
+    }
+}
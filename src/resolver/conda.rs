@@ -0,0 +1,256 @@
+//! Resolve conda environments with micromamba in the container, the solution
+//! is taken from a dry run so nothing is installed during `update`
+use crate::args;
+use crate::container::{self, Container};
+use crate::errors::*;
+use crate::lockfile::{ContainerLock, PackageLock};
+use crate::manifest::PackagesManifest;
+use crate::resolver::provenance::{self, Provenance};
+use serde::Deserialize;
+use std::path::Path;
+use tokio::fs;
+
+pub const DEFAULT_CHANNEL: &str = "conda-forge";
+/// The name of the environment that's resolved, it's never created
+const ENV_NAME: &str = "repro-env";
+
+#[derive(Debug, Deserialize)]
+pub struct DryRun {
+    pub actions: Actions,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Actions {
+    #[serde(default, rename = "LINK")]
+    pub link: Vec<Record>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Record {
+    pub name: String,
+    pub version: String,
+    pub url: String,
+    pub sha256: Option<String>,
+    pub size: Option<u64>,
+    #[serde(default)]
+    pub depends: Vec<String>,
+}
+
+/// The packages micromamba would link into the environment
+pub fn parse_dry_run(buf: &[u8]) -> Result<Vec<PackageLock>> {
+    let dry_run = serde_json::from_slice::<DryRun>(buf)
+        .context("Failed to parse json output of micromamba")?;
+    let mut packages = Vec::new();
+    for record in dry_run.actions.link {
+        let sha256 = record.sha256.with_context(|| {
+            anyhow!(
+                "Conda package {:?} has no sha256 in the repodata of its channel",
+                record.name
+            )
+        })?;
+        packages.push(PackageLock {
+            name: record.name,
+            version: record.version,
+            system: "conda".to_string(),
+            url: record.url,
+            fallback_urls: vec![],
+            provides: vec![],
+            sha256,
+            commit: None,
+            signatures: vec![],
+            installed: false,
+            depends: record.depends,
+            size: record.size,
+        });
+    }
+    Ok(packages)
+}
+
+/// The arguments of the dry run, an environment.yml is read from /tmp in the container
+pub fn dry_run_args(manifest: &PackagesManifest) -> Vec<String> {
+    let mut args = [
+        "micromamba",
+        "create",
+        "--dry-run",
+        "--json",
+        "--yes",
+        "--name",
+        ENV_NAME,
+    ]
+    .map(String::from)
+    .to_vec();
+    if manifest.environment.is_some() {
+        args.extend(["--file".to_string(), "/tmp/environment.yml".to_string()]);
+    }
+    let channels = if manifest.channels.is_empty() && manifest.environment.is_none() {
+        vec![DEFAULT_CHANNEL.to_string()]
+    } else {
+        manifest.channels.clone()
+    };
+    if !channels.is_empty() {
+        args.push("--override-channels".to_string());
+    }
+    for channel in channels {
+        args.extend(["--channel".to_string(), channel]);
+    }
+    args.extend(manifest.dependencies.iter().cloned());
+    args
+}
+
+pub async fn resolve_dependencies(
+    container: &Container,
+    manifest: &PackagesManifest,
+    environment: Option<&[u8]>,
+    dependencies: &mut Vec<PackageLock>,
+    provenance: &mut Provenance,
+) -> Result<()> {
+    provenance
+        .detect_tool(
+            container,
+            "micromamba",
+            &["micromamba", "--version"],
+            provenance::parse_micromamba_version,
+        )
+        .await;
+    if let Some(environment) = environment {
+        container
+            .write_file("/tmp/", "environment.yml", environment)
+            .await?;
+    }
+
+    info!("Resolving conda environment...");
+    let buf = container
+        .exec(
+            &dry_run_args(manifest),
+            container::Exec {
+                capture_stdout: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .context("Failed to resolve conda environment, does the image contain micromamba?")?;
+    for package in parse_dry_run(&buf)? {
+        debug!(
+            "Detected conda package: {:?} {:?}",
+            package.name, package.version
+        );
+        dependencies.push(package);
+    }
+    Ok(())
+}
+
+pub async fn resolve(
+    update: &args::Update,
+    manifest: &PackagesManifest,
+    container: &ContainerLock,
+    project: &Path,
+    dependencies: &mut Vec<PackageLock>,
+    provenance: &mut Provenance,
+) -> Result<()> {
+    if manifest.dependencies.is_empty() && manifest.environment.is_none() {
+        bail!("Conda needs dependencies or an environment to resolve");
+    }
+    if let Some(spec) = manifest.dependencies.iter().find(|d| d.starts_with('-')) {
+        bail!("Invalid conda dependency: {spec:?}");
+    }
+    let environment = if let Some(path) = &manifest.environment {
+        let buf = fs::read(path)
+            .await
+            .with_context(|| anyhow!("Failed to read conda environment: {path:?}"))?;
+        Some(buf)
+    } else {
+        None
+    };
+
+    let image = container::prepare_image(container, project).await?;
+    let container = Container::create(
+        &image,
+        container::Config {
+            mounts: &[],
+            expose_fuse: false,
+            lockfile_digest: None,
+            prepared: false,
+            platform: None,
+            podman_args: &[],
+            read_only_root: false,
+            init: container.init,
+        },
+    )
+    .await?;
+    container
+        .run(
+            resolve_dependencies(
+                &container,
+                manifest,
+                environment.as_deref(),
+                dependencies,
+                provenance,
+            ),
+            update.keep,
+        )
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dry_run() -> Result<()> {
+        let packages = parse_dry_run(
+            br#"{
+    "actions": {
+        "FETCH": [],
+        "LINK": [
+            {
+                "build": "h4bc722e_0",
+                "build_number": 0,
+                "channel": "conda-forge",
+                "depends": ["__glibc >=2.17,<3.0.a0", "libgcc-ng >=12"],
+                "fn": "bzip2-1.0.8-h4bc722e_7.conda",
+                "md5": "62ee74e96c5ebb0af99386de58cf9553",
+                "name": "bzip2",
+                "sha256": "5ced96500d945fb286c9c838e54fa759aa04a7129c59800f0846b4335cee770d",
+                "size": 252783,
+                "subdir": "linux-64",
+                "url": "https://conda.anaconda.org/conda-forge/linux-64/bzip2-1.0.8-h4bc722e_7.conda",
+                "version": "1.0.8"
+            }
+        ],
+        "PREFIX": "/root/.local/share/mamba/envs/repro-env"
+    },
+    "dry_run": true,
+    "prefix": "/root/.local/share/mamba/envs/repro-env",
+    "success": true
+}"#,
+        )?;
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].name, "bzip2");
+        assert_eq!(packages[0].version, "1.0.8");
+        assert_eq!(packages[0].size, Some(252783));
+        assert_eq!(packages[0].depends.len(), 2);
+
+        let missing = br#"{"actions": {"LINK": [{"name": "old", "version": "1.0", "url": "https://conda.anaconda.org/conda-forge/noarch/old-1.0-0.tar.bz2"}]}}"#;
+        assert!(parse_dry_run(missing).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run_args() -> Result<()> {
+        let manifest = toml::from_str::<PackagesManifest>(
+            "system = \"conda\"\ndependencies = [\"numpy>=1.26\"]\n",
+        )?;
+        assert_eq!(
+            dry_run_args(&manifest).join(" "),
+            "micromamba create --dry-run --json --yes --name repro-env --override-channels --channel conda-forge numpy>=1.26"
+        );
+        let manifest = toml::from_str::<PackagesManifest>(
+            "system = \"conda\"\nenvironment = \"environment.yml\"\n",
+        )?;
+        assert_eq!(
+            dry_run_args(&manifest).join(" "),
+            "micromamba create --dry-run --json --yes --name repro-env --file /tmp/environment.yml"
+        );
+        Ok(())
+    }
+}
@@ -2,11 +2,47 @@ use crate::args;
 use crate::container;
 use crate::container::ImageRef;
 use crate::errors::*;
-use crate::lockfile::ContainerLock;
-use crate::manifest::Manifest;
+use crate::lockfile::{ContainerLock, ContainerfileLock};
+use crate::manifest::{Init, Manifest};
+use crate::utils;
+use std::path::Path;
 
-pub async fn resolve(args: &args::Update, manifest: &Manifest) -> Result<ContainerLock> {
+/// Pin the image of the manifest, a containerfile or image archive is relative to `project`
+pub async fn resolve(
+    args: &args::Update,
+    manifest: &Manifest,
+    project: &Path,
+) -> Result<ContainerLock> {
     let image = manifest.container.image.clone();
+    let init = manifest.container.init;
+    if manifest.container.pin_init && init != Init::Catatonit {
+        bail!("pin_init is only supported with the catatonit init");
+    }
+    let init_sha256 = if manifest.container.pin_init {
+        let sha256 = container::init_sha256()?;
+        info!(
+            "Pinning init {:?} to sha256 {sha256:?}",
+            container::CATATONIT
+        );
+        Some(sha256)
+    } else {
+        None
+    };
+
+    if let Some((path, _)) = container::project_archive(&image, project) {
+        if manifest.container.containerfile.is_some() {
+            bail!("A containerfile can't be used with an image archive as base");
+        }
+        let sha256 = utils::sha256_file(&path)?;
+        info!("Resolved image archive {path:?} to sha256 {sha256:?}");
+        return Ok(ContainerLock {
+            image,
+            sha256: Some(sha256),
+            init_sha256,
+            init,
+            containerfile: None,
+        });
+    }
 
     if !args.no_pull {
         container::pull(&image).await?;
@@ -19,7 +55,25 @@ pub async fn resolve(args: &args::Update, manifest: &Manifest) -> Result<Contain
     let pinned_image = image_ref.to_string();
     info!("Resolved image reference {:?} to {:?}", image, pinned_image);
 
+    let containerfile = if let Some(path) = &manifest.container.containerfile {
+        let sha256 = utils::sha256_file(&project.join(path))?;
+        info!("Building image from containerfile {path:?}...");
+        let image_id = container::build_image(&pinned_image, project, path).await?;
+        info!("Built image from containerfile {path:?} as {image_id:?}");
+        Some(ContainerfileLock {
+            path: path.clone(),
+            sha256,
+            image_id,
+        })
+    } else {
+        None
+    };
+
     Ok(ContainerLock {
         image: pinned_image,
+        sha256: None,
+        init_sha256,
+        init,
+        containerfile,
     })
 }
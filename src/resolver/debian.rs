@@ -1,17 +1,33 @@
 use crate::args;
-use crate::container::{self, Container};
+use crate::container::{self, Container, Mount};
+use crate::credentials::Credentials;
 use crate::errors::*;
 use crate::http;
+use crate::limits;
 use crate::lockfile::{ContainerLock, PackageLock};
-use crate::manifest::PackagesManifest;
+use crate::manifest::{self, PackagesManifest, Solver};
 use crate::paths;
+use crate::pkgs;
+use crate::progress::Progress;
+use crate::resolver::debian_release::{self, VerifiedRelease};
+use crate::resolver::debian_solver;
+use crate::resolver::debian_sources;
+use crate::resolver::diagnose::{self, Candidate};
+use crate::resolver::image_files::ImageFiles;
+use crate::resolver::parsers;
+use crate::resolver::provenance::{self, Provenance};
+use crate::resolver::snapshot::Snapshot;
+use crate::utils;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use sequoia_openpgp::Cert;
 use serde::Deserialize;
-use sha1::Sha1;
-use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::prelude::*;
-use std::io::Lines;
-use tokio::fs;
+use std::io::{BufReader, Lines};
+use std::iter;
+use std::path::Path;
+use std::sync::Arc;
+use tempfile::NamedTempFile;
 
 #[derive(Debug, Deserialize)]
 pub struct JsonSnapshotInfo {
@@ -31,7 +47,11 @@ pub struct JsonSnapshotPkg {
 pub struct PkgEntry {
     name: String,
     version: String,
+    /// The Filename of the package, relative to the root of the repository
+    path: String,
     provides: Vec<String>,
+    /// The names mentioned in Depends and Pre-Depends, including alternatives
+    depends: Vec<String>,
     sha256: String,
 }
 
@@ -41,9 +61,16 @@ pub struct PkgDatabase {
 }
 
 impl PkgDatabase {
-    pub fn import_lz4<R: Read>(&mut self, reader: R) -> Result<()> {
-        let rdr = lz4_flex::frame::FrameDecoder::new(reader);
-        self.import_lines_stream(rdr.lines())
+    /// All package names and provides, used to suggest alternatives for typos
+    pub fn candidates(&self) -> Vec<Candidate<'_>> {
+        let mut candidates = Vec::new();
+        for pkg in self.pkgs.values() {
+            candidates.push(Candidate::package(&pkg.name));
+            for provides in &pkg.provides {
+                candidates.push(Candidate::provided(provides, &pkg.name));
+            }
+        }
+        candidates
     }
 
     pub fn import_lines_stream<R: BufRead>(&mut self, mut lines: Lines<R>) -> Result<()> {
@@ -54,8 +81,9 @@ impl PkgDatabase {
                 bail!("Unexpected line in database (expected `Package: `): {line:?}")
             };
             let mut version = None;
-            let mut filename = None;
+            let mut path = None;
             let mut provides = Vec::new();
+            let mut depends = Vec::new();
             let mut sha256 = None;
 
             for line in &mut lines {
@@ -67,29 +95,37 @@ impl PkgDatabase {
                 } else if let Some(value) = line.strip_prefix("Version: ") {
                     version = Some(value.to_string());
                 } else if let Some(value) = line.strip_prefix("Filename: ") {
-                    let value = value
-                        .rsplit_once('/')
-                        .map(|(_, filename)| filename)
-                        .unwrap_or(value);
-                    filename = Some(value.to_string());
+                    path = Some(value.to_string());
                 } else if let Some(value) = line.strip_prefix("Provides: ") {
                     for entry in value.split(", ") {
                         let (name, _) = entry.split_once(' ').unwrap_or((entry, ""));
                         provides.push(name.to_string());
                     }
+                } else if let Some(value) = line
+                    .strip_prefix("Depends: ")
+                    .or_else(|| line.strip_prefix("Pre-Depends: "))
+                {
+                    for entry in value.split([',', '|']) {
+                        let entry = entry.trim();
+                        let end = entry.find([' ', ':', '(']).unwrap_or(entry.len());
+                        depends.push(entry[..end].to_string());
+                    }
                 } else if let Some(value) = line.strip_prefix("SHA256: ") {
                     sha256 = Some(value.to_string());
                 }
             }
 
-            let filename = filename.context("Package database entry is missing filename")?;
+            let path = path.context("Package database entry is missing filename")?;
+            let filename = path.rsplit('/').next().unwrap_or(&path).to_string();
             let new = PkgEntry {
                 name: name.to_string(),
                 version: version.context("Package database entry is missing version")?,
+                path,
                 provides,
+                depends,
                 sha256: sha256.context("Package database entry is missing sha256")?,
             };
-            let old = self.pkgs.insert(filename.to_string(), new.clone());
+            let old = self.pkgs.insert(filename.clone(), new.clone());
 
             if let Some(old) = old {
                 // it's only a problem if they differ
@@ -106,24 +142,61 @@ impl PkgDatabase {
         let mut tar = tar::Archive::new(buf);
 
         let mut db = Self::default();
-        for entry in tar.entries()? {
+        for (idx, entry) in tar.entries()?.enumerate() {
+            limits::INDEX.check_entry(idx)?;
             let entry = entry?;
             let path = entry
                 .header()
                 .path()
-                .context("Filename was not valid utf-8")?;
-            let Some(extension) = path.extension() else {
+                .context("Filename was not valid utf-8")?
+                .into_owned();
+            if path.components().any(|c| c.as_os_str() == "partial") {
+                continue;
+            }
+            let Some(filename) = path.file_name().and_then(|f| f.to_str()) else {
                 continue;
             };
 
-            if extension.to_str() == Some("lz4") {
-                db.import_lz4(entry)?;
+            if debian_release::packages_list(filename)?.is_some() {
+                let mut compressed = Vec::new();
+                limits::INDEX.wrap(entry).read_to_end(&mut compressed)?;
+                let rdr = pkgs::archlinux::decompress(&compressed[..], limits::INDEX)?;
+                db.import_lines_stream(BufReader::new(rdr).lines())?;
             }
         }
 
         Ok(db)
     }
 
+    /// The debug symbol packages of the same version as the given packages,
+    /// automatic `-dbgsym` packages are preferred over `-dbg`
+    pub fn debug_packages<'a, I: IntoIterator<Item = (&'a str, &'a str)>>(
+        &self,
+        packages: I,
+    ) -> Vec<String> {
+        let available = self
+            .pkgs
+            .values()
+            .map(|pkg| (pkg.name.as_str(), pkg.version.as_str()))
+            .collect::<HashSet<_>>();
+        let mut debug = Vec::new();
+        for (name, version) in packages {
+            if name.ends_with("-dbgsym") || name.ends_with("-dbg") {
+                continue;
+            }
+            let found = ["-dbgsym", "-dbg"]
+                .iter()
+                .map(|suffix| format!("{name}{suffix}"))
+                .find(|debug| available.contains(&(debug.as_str(), version)));
+            if let Some(found) = found {
+                if !debug.contains(&found) {
+                    debug.push(found);
+                }
+            }
+        }
+        debug
+    }
+
     pub fn find_by_filename(&self, filename: &str) -> Result<&PkgEntry> {
         let entry = self
             .pkgs
@@ -132,130 +205,616 @@ impl PkgDatabase {
         Ok(entry)
     }
 
-    pub fn find_by_apt_output(&self, line: &str) -> Result<(String, &PkgEntry)> {
-        let mut line = line.split(' ');
-        let url = line.next().context("Missing url in apt output")?;
-        let filename = line.next().context("Missing filename in apt output")?;
-        let _size = line.next().context("Missing size in apt output")?;
-        let _md5sum = line.next().context("Missing md5sum in apt output")?;
-
-        if let Some(trailing) = line.next() {
-            bail!("Trailing data in apt output: {trailing:?}");
-        }
+    fn find_by_sha256(&self, sha256: &str) -> Option<&PkgEntry> {
+        self.pkgs.values().find(|pkg| pkg.sha256 == sha256)
+    }
 
-        let url = url.strip_prefix('\'').unwrap_or(url);
-        let url = url.strip_suffix('\'').unwrap_or(url);
+    /// Mirrors and redirectors may encode the url differently than the Packages
+    /// index, if the filename doesn't match the package is looked up by checksum
+    pub fn find_by_apt_output(&self, line: &str) -> Result<(String, &PkgEntry)> {
+        let uri = parsers::apt_print_uri(line)?;
+        let (url, filename) = (uri.url, uri.filename);
         debug!("Detected dependency filename={filename:?} url={url:?}");
 
-        let package = {
-            let url = url
-                .parse::<reqwest::Url>()
-                .context("Failed to parse as url")?;
-            let filename = url
-                .path_segments()
-                .context("Failed to get path from url")?
-                .last()
-                .context("Failed to get filename from url")?;
-            let filename =
-                urlencoding::decode(filename).context("Failed to url decode filename")?;
-            self.find_by_filename(&filename).with_context(|| {
-                anyhow!("Failed to find package database entry for file: {filename:?}")
-            })?
-        };
+        let basename = url.rsplit('/').next().unwrap_or(url);
+        let basename = urlencoding::decode(basename).context("Failed to url decode filename")?;
+        let filename = urlencoding::decode(filename).context("Failed to url decode filename")?;
+        let package = self
+            .pkgs
+            .get(basename.as_ref())
+            .or_else(|| self.pkgs.get(filename.as_ref()))
+            .or_else(|| self.find_by_sha256(uri.sha256()?))
+            .with_context(|| {
+                anyhow!("Failed to find package database entry for file: {basename:?}")
+            })?;
 
         Ok((url.to_string(), package))
     }
 }
 
-pub async fn resolve_dependencies(
+/// Directories that apt reads trusted keys from
+pub const KEYRING_DIRS: &[&str] = &["/usr/share/keyrings", "/etc/apt/trusted.gpg.d"];
+/// The keyring of debian-archive-keyring, used by the apt sources written for `security`/`updates`
+const DEBIAN_KEYRING: &str = "/usr/share/keyrings/debian-archive-keyring.gpg";
+
+/// apt's mirror method prints urls like `mirror+file:/etc/apt/mirrors/debian.list/pool/main/...`,
+/// returns the location of the mirror list if `url` is one of them
+pub fn mirror_list_url(url: &str, path: &str) -> Result<Option<String>> {
+    let Some(url) = url.strip_prefix("mirror+") else {
+        return Ok(None);
+    };
+    let url = urlencoding::decode(url).context("Failed to url decode mirror url")?;
+    let list = url
+        .strip_suffix(path)
+        .and_then(|url| url.strip_suffix('/'))
+        .with_context(|| anyhow!("Mirror url doesn't end with {path:?}: {url:?}"))?;
+    Ok(Some(list.to_string()))
+}
+
+/// The first mirror of an apt mirror list, entries may be followed by tab-separated metadata
+pub fn parse_mirror_list(buf: &str) -> Option<&str> {
+    buf.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .find_map(|line| line.split_whitespace().next())
+        .map(|url| url.trim_end_matches('/'))
+}
+
+/// Replace the urls of apt's mirror method with a url of the first mirror in the list
+async fn resolve_mirror_urls(
     container: &Container,
-    manifest: &PackagesManifest,
-    dependencies: &mut Vec<PackageLock>,
+    client: &http::Client,
+    resolved: &mut [(String, &PkgEntry)],
 ) -> Result<()> {
-    info!("Update package datatabase...");
-    container
-        .exec(&["apt-get", "update"], container::Exec::default())
-        .await?;
+    let mut mirrors = HashMap::new();
+    for (url, package) in resolved {
+        let Some(list) = mirror_list_url(url, &package.path)? else {
+            continue;
+        };
+        if !mirrors.contains_key(&list) {
+            debug!("Reading apt mirror list: {list:?}");
+            let buf = if let Some(path) = list.strip_prefix("file:") {
+                container.cat(path).await?
+            } else {
+                client.fetch(&list).await?.to_vec()
+            };
+            let buf = String::from_utf8(buf).context("Failed to decode mirror list as utf8")?;
+            let mirror = parse_mirror_list(&buf)
+                .with_context(|| anyhow!("Mirror list is empty: {list:?}"))?;
+            mirrors.insert(list.clone(), mirror.to_string());
+        }
+        *url = format!("{}/{}", mirrors[&list], package.path);
+    }
+    Ok(())
+}
 
-    info!("Importing package database...");
-    let tar = container.tar("/var/lib/apt/lists").await?;
-    let db = PkgDatabase::import_tar(&tar)?;
+/// Select the snapshot.debian.org entry for a download and make sure it matches
+pub fn find_snapshot_pkg<'a>(
+    info: &'a JsonSnapshotInfo,
+    filename: &str,
+    size: usize,
+) -> Result<&'a JsonSnapshotPkg> {
+    // the lookup is by checksum, if the name differs any of the files has the same content
+    let pkg = info
+        .result
+        .iter()
+        .find(|pkg| pkg.name == filename)
+        .or_else(|| info.result.first())
+        .with_context(|| anyhow!("Could not find {filename:?} in any snapshots"))?;
+    if pkg.size != size as i64 {
+        bail!(
+            "Size of {filename:?} does not match snapshot.debian.org: expected={}, downloaded={size}",
+            pkg.size
+        );
+    }
+    Ok(pkg)
+}
 
-    info!("Resolving dependencies...");
-    let mut cmd = vec![
-        "apt-get",
-        "-qq",
-        "--print-uris",
-        "--no-install-recommends",
-        "upgrade",
-        "--",
-    ];
+/// The `VERSION_CODENAME` of an os-release file, e.g. `bookworm`
+pub fn parse_os_codename(os_release: &str) -> Option<&str> {
+    os_release.lines().find_map(|line| {
+        let value = line.strip_prefix("VERSION_CODENAME=")?;
+        let value = value.trim_matches('"');
+        (!value.is_empty()).then_some(value)
+    })
+}
+
+/// The deb822 apt sources of a release, with the security and updates suites if enabled.
+/// With a snapshot.debian.org timestamp the archive is used as it was at that time.
+pub fn apt_sources(
+    codename: &str,
+    security: bool,
+    updates: bool,
+    snapshot: Option<&str>,
+) -> String {
+    let (base, options) = match snapshot {
+        Some(timestamp) => (
+            format!("http://snapshot.debian.org/archive/{{}}/{timestamp}"),
+            "Check-Valid-Until: no\n",
+        ),
+        None => ("http://deb.debian.org/{}".to_string(), ""),
+    };
+    let uri = |archive: &str| base.replace("{}", archive);
+
+    let mut suites = vec![codename.to_string()];
+    if updates {
+        suites.push(format!("{codename}-updates"));
+    }
+    let mut sources = format!(
+        "Types: deb\nURIs: {}\nSuites: {}\nComponents: main\nSigned-By: {DEBIAN_KEYRING}\n{options}",
+        uri("debian"),
+        suites.join(" ")
+    );
+    if security {
+        sources.push_str(&format!(
+            "\nTypes: deb\nURIs: {}\nSuites: {codename}-security\nComponents: main\nSigned-By: {DEBIAN_KEYRING}\n{options}",
+            uri("debian-security")
+        ));
+    }
+    sources
+}
+
+/// The snapshot.debian.org timestamp to resolve a point release from, based on the
+/// line `Sat, 10 Feb 2024 - Debian 12.5 released` in the ChangeLog of the release.
+/// The end of the following day leaves time for the mirrors to be updated, the main
+/// suite doesn't change again until the next point release.
+pub fn point_release_snapshot(changelog: &str, version: &str) -> Result<String> {
+    let suffix = format!(" - Debian {version} released");
+    let date = changelog
+        .lines()
+        .find_map(|line| line.trim_end().strip_suffix(&suffix))
+        .with_context(|| anyhow!("Could not find point release {version:?} in ChangeLog"))?;
+    let format =
+        time::format_description::parse("[weekday repr:short], [day] [month repr:short] [year]")?;
+    let date = time::Date::parse(date.trim(), &format)
+        .with_context(|| anyhow!("Invalid date of point release {version:?}: {date:?}"))?;
+    let day = date
+        .next_day()
+        .with_context(|| anyhow!("Invalid date of point release {version:?}: {date}"))?;
+    Ok(format!(
+        "{:04}{:02}{:02}T235959Z",
+        day.year(),
+        u8::from(day.month()),
+        day.day()
+    ))
+}
+
+/// The deb822 apt sources of the automatic debug symbol packages of a release
+pub fn apt_debug_sources(codename: &str) -> String {
+    format!(
+        "Types: deb\nURIs: http://deb.debian.org/debian-debug\nSuites: {codename}-debug\nComponents: main\nSigned-By: {DEBIAN_KEYRING}\n"
+    )
+}
+
+/// Make sure the main archive is at the expected point release, the security
+/// and updates suites have no point release of their own. The sources are pinned
+/// to a snapshot of the point release, this guards against picking the wrong one.
+pub fn check_point_release(releases: &[VerifiedRelease], expected: &str) -> Result<()> {
+    let Some(version) = releases.iter().find_map(|release| {
+        let codename = release.codename.as_deref()?;
+        if ["-security", "-updates", "-debug"]
+            .iter()
+            .any(|suffix| codename.ends_with(suffix))
+        {
+            return None;
+        }
+        release.version.as_deref()
+    }) else {
+        bail!("Could not detect point release of the debian archive, expected {expected:?}");
+    };
+    if version != expected {
+        bail!("Debian archive is at point release {version:?} but {expected:?} was requested, the snapshot of the archive doesn't serve it");
+    }
+    Ok(())
+}
+
+/// Resolve the dependencies with apt, returns the urls of the packages and their database entries
+async fn solve<'a>(
+    container: &Container,
+    manifest: &PackagesManifest,
+    db: &'a PkgDatabase,
+) -> Result<Vec<(String, &'a PkgEntry)>> {
+    let mut cmd = vec!["apt-get", "-qq", "--print-uris"];
+    cmd.push(if manifest.install_recommends {
+        "--install-recommends"
+    } else {
+        "--no-install-recommends"
+    });
+    if manifest.install_suggests {
+        cmd.push("--install-suggests");
+    }
+    cmd.extend(["upgrade", "--"]);
     for dep in &manifest.dependencies {
         cmd.push(dep.as_str());
     }
-    let buf = container
+    let buf = match container
         .exec(
             &cmd,
             container::Exec {
                 capture_stdout: true,
+                capture_stderr: true,
                 ..Default::default()
             },
         )
-        .await?;
+        .await
+    {
+        Ok(buf) => buf,
+        Err(err) => {
+            return Err(diagnose::explain(container, "debian", err, &db.candidates()).await)
+        }
+    };
     let buf = String::from_utf8(buf).context("Failed to decode apt output as utf8")?;
+    buf.lines()
+        .map(|line| db.find_by_apt_output(line))
+        .collect()
+}
 
-    let client = http::Client::new()?;
-    let pkgs_cache_dir = paths::pkgs_cache_dir()?;
-    for line in buf.lines() {
-        let (url, package) = db.find_by_apt_output(line)?;
-
-        let path = pkgs_cache_dir.sha256_path(&package.sha256)?;
-        let buf = if path.exists() {
-            fs::read(path).await?
-        } else {
-            let buf = client.fetch(&url).await?.to_vec();
-
-            let mut hasher = Sha256::new();
-            hasher.update(&buf);
-            let result = hex::encode(hasher.finalize());
-
-            if result != package.sha256 {
-                bail!(
-                    "Mismatch of sha256 checksum, expected={}, downloaded={}",
-                    package.sha256,
-                    result
-                );
+/// Resolve the dependencies with the builtin solver from the package indexes
+fn solve_builtin<'a>(
+    manifest: &PackagesManifest,
+    db: &'a PkgDatabase,
+    index: &debian_solver::Index,
+    installed: &HashMap<String, debian_solver::InstalledPackage>,
+    distro: Option<&str>,
+) -> Result<Vec<(String, &'a PkgEntry)>> {
+    let requested = manifest
+        .dependencies
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    let weak = debian_solver::WeakDepends {
+        recommends: manifest.install_recommends,
+        suggests: manifest.install_suggests,
+    };
+    let pkgs = debian_solver::solve(index, installed, &requested, weak)
+        .map_err(|err| diagnose::explain_in(distro, "debian", err, &db.candidates()))?;
+    pkgs.iter()
+        .map(|pkg| {
+            let filename = pkg.filename.rsplit('/').next().unwrap_or(&pkg.filename);
+            let entry = db.find_by_filename(filename)?;
+            Ok((pkg.url(), entry))
+        })
+        .collect()
+}
+
+/// The snapshot.debian.org timestamp of the point release that's requested, if any
+async fn point_release_timestamp(
+    manifest: &PackagesManifest,
+    codename: &str,
+) -> Result<Option<String>> {
+    let Some(point_release) = &manifest.point_release else {
+        return Ok(None);
+    };
+    let url = format!("https://deb.debian.org/debian/dists/{codename}/ChangeLog");
+    let changelog = http::Client::new()?.fetch_cached(&url).await?;
+    let changelog = String::from_utf8_lossy(&changelog);
+    let timestamp = point_release_snapshot(&changelog, point_release)
+        .with_context(|| anyhow!("Failed to pin point release using {url:?}"))?;
+    info!("Resolving point release {point_release:?} from snapshot {timestamp:?}");
+    Ok(Some(timestamp))
+}
+
+/// The keys apt trusts in the image, they're read on the host from the image
+/// itself, before anything ran in the container that could change them
+pub async fn image_keyring(image: &str) -> Result<Vec<Cert>> {
+    let files = ImageFiles::read(image, &["/etc/apt", "/usr/share/keyrings"]).await?;
+    let mut certs = debian_sources::default_keyring(&files)?;
+    for source in debian_sources::image_sources(&files)? {
+        for cert in debian_sources::source_certs(&files, &source, &[])? {
+            if !certs.contains(&cert) {
+                certs.push(cert);
             }
+        }
+    }
+    if certs.is_empty() {
+        bail!("Could not find any apt keyring in image");
+    }
+    Ok(certs)
+}
 
-            buf
-        };
+pub async fn resolve_dependencies(
+    update: &args::Update,
+    container: &Container,
+    manifest: &PackagesManifest,
+    certs: Vec<Cert>,
+    dependencies: &mut Vec<PackageLock>,
+    provenance: &mut Provenance,
+) -> Result<()> {
+    if !manifest.preferences.is_empty() {
+        let preferences = manifest::apt_preferences(&manifest.preferences)?;
+        debug!("Writing apt preferences: {preferences:?}");
+        container
+            .write_file(
+                "/etc/apt/preferences.d/",
+                "repro-env",
+                preferences.as_bytes(),
+            )
+            .await?;
+    }
 
-        let mut hasher = Sha1::new();
-        hasher.update(&buf);
-        let sha1 = hex::encode(hasher.finalize());
+    let replace_sources = manifest.configures_suites() || manifest.point_release.is_some();
+    let os_release = if replace_sources || manifest.debug_symbols {
+        let os_release = container.cat("/etc/os-release").await?;
+        String::from_utf8(os_release).context("Failed to decode /etc/os-release as utf8")?
+    } else {
+        String::new()
+    };
+    let codename = || {
+        parse_os_codename(&os_release)
+            .context("Failed to detect debian codename from /etc/os-release")
+    };
 
-        let url = format!("https://snapshot.debian.org/mr/file/{sha1}/info");
-        let buf = client
-            .fetch(&url)
-            .await
-            .context("Failed to lookup pkg hash on snapshot.debian.org")?;
+    if replace_sources {
+        let codename = codename()?;
+        let snapshot = point_release_timestamp(manifest, codename).await?;
+        let sources = apt_sources(
+            codename,
+            manifest.security.unwrap_or(true),
+            manifest.updates.unwrap_or(true),
+            snapshot.as_deref(),
+        );
+        debug!("Replacing apt sources: {sources:?}");
+        container
+            .exec(
+                &[
+                    "sh",
+                    "-c",
+                    "rm -f /etc/apt/sources.list /etc/apt/sources.list.d/*",
+                ],
+                container::Exec::default(),
+            )
+            .await?;
+        container
+            .write_file(
+                "/etc/apt/sources.list.d/",
+                "repro-env.sources",
+                sources.as_bytes(),
+            )
+            .await?;
+    }
+    if manifest.debug_symbols {
+        let sources = apt_debug_sources(codename()?);
+        debug!("Adding apt sources for debug symbols: {sources:?}");
+        container
+            .write_file(
+                "/etc/apt/sources.list.d/",
+                "repro-env-debug.sources",
+                sources.as_bytes(),
+            )
+            .await?;
+    }
 
-        let info = serde_json::from_slice::<JsonSnapshotInfo>(&buf)
-            .context("Failed to decode snapshot.debian.org json response")?;
+    info!("Update package datatabase...");
+    container
+        .exec(&["apt-get", "update"], container::Exec::default())
+        .await?;
+    provenance
+        .detect_tool(
+            container,
+            "apt",
+            &["apt-get", "--version"],
+            provenance::parse_apt_version,
+        )
+        .await;
+    provenance
+        .record_sources(
+            container,
+            &["/etc/apt/sources.list", "/etc/apt/sources.list.d"],
+        )
+        .await?;
 
-        let pkg = info
-            .result
-            .first()
-            .context("Could not find package in any snapshots")?;
+    info!("Importing package database...");
+    let tar = container.tar("/var/lib/apt/lists").await?;
 
-        let archive_name = &pkg.archive_name;
-        let first_seen = &pkg.first_seen;
-        let path = &pkg.path;
-        let name = &pkg.name;
+    info!("Verifying package database signatures...");
+    let (releases, db) = utils::spawn_blocking(move |_| {
+        let releases = debian_release::verify_lists(&tar, &certs)?;
+        let db = PkgDatabase::import_tar(&tar)?;
+        Ok((releases, db))
+    })
+    .await?;
+    for release in &releases {
+        if let Some(date) = release.date {
+            provenance.record_index(&release.name, date)?;
+        }
+    }
+    if let Some(point_release) = &manifest.point_release {
+        check_point_release(&releases, point_release)?;
+    }
+
+    info!("Resolving dependencies...");
+    let mut resolved = solve(container, manifest, &db).await?;
+    if manifest.debug_symbols {
+        let debug = db.debug_packages(
+            resolved
+                .iter()
+                .map(|(_, pkg)| (pkg.name.as_str(), pkg.version.as_str())),
+        );
+        info!("Adding {} debug symbol packages...", debug.len());
+        if !debug.is_empty() {
+            let mut manifest = manifest.clone();
+            manifest.dependencies.extend(debug);
+            resolved = solve(container, &manifest, &db).await?;
+        }
+    }
 
-        let url =
-            format!("https://snapshot.debian.org/archive/{archive_name}/{first_seen}{path}/{name}");
+    let client = http::Client::new()?;
+    resolve_mirror_urls(container, &client, &mut resolved).await?;
+    lock_packages(update, &client, manifest, &resolved, dependencies).await
+}
 
+/// Resolve the dependencies with the builtin solver. The configuration is read
+/// from the layers of the image and the package indexes are downloaded and
+/// verified on the host, no container is involved.
+pub async fn resolve_builtin(
+    update: &args::Update,
+    image: &str,
+    manifest: &PackagesManifest,
+    dependencies: &mut Vec<PackageLock>,
+    provenance: &mut Provenance,
+) -> Result<()> {
+    info!("Reading apt configuration from image...");
+    let files = ImageFiles::read(
+        image,
+        &[
+            "/etc/os-release",
+            "/usr/lib/os-release",
+            "/etc/apt",
+            "/usr/share/keyrings",
+            "/var/lib/dpkg/status",
+        ],
+    )
+    .await?;
+    let os_release = files.text("/etc/os-release")?;
+    let codename = || {
+        parse_os_codename(&os_release)
+            .context("Failed to detect debian codename from /etc/os-release")
+    };
+    let status = files.text("/var/lib/dpkg/status")?;
+    let arch = debian_solver::dpkg_architecture(&status)
+        .context("Failed to detect architecture of dpkg in image")?;
+
+    let mut sources = if manifest.configures_suites() || manifest.point_release.is_some() {
+        let codename = codename()?;
+        let snapshot = point_release_timestamp(manifest, codename).await?;
+        let sources = apt_sources(
+            codename,
+            manifest.security.unwrap_or(true),
+            manifest.updates.unwrap_or(true),
+            snapshot.as_deref(),
+        );
+        debug!("Replacing apt sources: {sources:?}");
+        provenance.sources.insert(
+            "/etc/apt/sources.list.d/repro-env.sources".to_string(),
+            sources.clone(),
+        );
+        debian_sources::parse_deb822(&sources)?
+    } else {
+        provenance.record_image_sources(
+            &files,
+            &["/etc/apt/sources.list", "/etc/apt/sources.list.d"],
+        )?;
+        debian_sources::image_sources(&files)?
+    };
+    if manifest.debug_symbols {
+        let debug = apt_debug_sources(codename()?);
+        debug!("Adding apt sources for debug symbols: {debug:?}");
+        provenance.sources.insert(
+            "/etc/apt/sources.list.d/repro-env-debug.sources".to_string(),
+            debug.clone(),
+        );
+        sources.extend(debian_sources::parse_deb822(&debug)?);
+    }
+    if sources.is_empty() {
+        bail!("Could not find any apt sources in image");
+    }
+
+    info!("Downloading package database...");
+    let client = http::Client::new()?;
+    let keyring = debian_sources::default_keyring(&files)?;
+    let mut releases = Vec::new();
+    let mut db = PkgDatabase::default();
+    let mut index = debian_solver::Index::default();
+    for source in &sources {
+        let certs = debian_sources::source_certs(&files, source, &keyring)?;
+        let fetched = debian_sources::fetch(&client, &files, source, &arch, certs).await?;
+        let base_url = Arc::new(fetched.base_url);
+        for text in &fetched.indexes {
+            db.import_lines_stream(BufReader::new(text.as_bytes()).lines())?;
+            index.import_text(text, &base_url)?;
+        }
+        releases.push(fetched.release);
+    }
+    for release in &releases {
+        if let Some(date) = release.date {
+            provenance.record_index(&release.name, date)?;
+        }
+    }
+    if let Some(point_release) = &manifest.point_release {
+        check_point_release(&releases, point_release)?;
+    }
+
+    info!("Resolving dependencies...");
+    let installed = debian_solver::parse_dpkg_status(&status)?;
+    let distro = diagnose::parse_pretty_name(&os_release);
+    let mut resolved = solve_builtin(manifest, &db, &index, &installed, distro.as_deref())?;
+    if manifest.debug_symbols {
+        let debug = db.debug_packages(
+            resolved
+                .iter()
+                .map(|(_, pkg)| (pkg.name.as_str(), pkg.version.as_str())),
+        );
+        info!("Adding {} debug symbol packages...", debug.len());
+        if !debug.is_empty() {
+            let mut manifest = manifest.clone();
+            manifest.dependencies.extend(debug);
+            resolved = solve_builtin(&manifest, &db, &index, &installed, distro.as_deref())?;
+        }
+    }
+
+    lock_packages(update, &client, manifest, &resolved, dependencies).await
+}
+
+/// Look up the resolved packages on snapshot.debian.org and add them to the lockfile
+async fn lock_packages(
+    update: &args::Update,
+    client: &http::Client,
+    manifest: &PackagesManifest,
+    resolved: &[(String, &PkgEntry)],
+    dependencies: &mut Vec<PackageLock>,
+) -> Result<()> {
+    let pkgs_cache_dir = paths::pkgs_cache_dir()?;
+    let snapshot = Snapshot::new(client, &pkgs_cache_dir, paths::snapshot_cache_dir()?);
+    let first = dependencies.len();
+    let mut providers = HashMap::new();
+    for (_, package) in resolved {
+        for name in iter::once(&package.name).chain(&package.provides) {
+            providers.insert(name.as_str(), package.name.as_str());
+        }
+    }
+    info!(
+        "Looking up {} packages on snapshot.debian.org...",
+        resolved.len()
+    );
+    let resumed = resolved
+        .iter()
+        .filter(|(_, package)| snapshot.is_cached(&package.sha256))
+        .count();
+    if resumed > 0 {
+        info!(
+            "Resuming, {resumed} of {} packages have already been looked up",
+            resolved.len()
+        );
+    }
+    let mut progress = Progress::new("Resolved", resolved.len(), update.quiet);
+    let mut lookups = Vec::with_capacity(resolved.len());
+    lookups.resize(resolved.len(), None);
+    let mut pending = resolved.iter().enumerate();
+    let mut in_flight = FuturesUnordered::new();
+    loop {
+        while in_flight.len() < snapshot.concurrency.current() {
+            let Some((idx, (url, package))) = pending.next() else {
+                break;
+            };
+            let snapshot = &snapshot;
+            in_flight.push(async move {
+                let filename = package.path.rsplit('/').next().unwrap_or(&package.path);
+                let lookup = snapshot.lookup(url, filename, &package.sha256).await;
+                (idx, lookup)
+            });
+        }
+        // lookups that already finished are cached, even if this one failed
+        let Some((idx, lookup)) = in_flight.next().await else {
+            break;
+        };
+        let (lookup, downloaded) = lookup?;
+        progress.add_bytes(downloaded);
+        progress.inc();
+        lookups[idx] = Some(lookup);
+    }
+
+    for ((_, package), lookup) in resolved.iter().zip(lookups) {
+        let lookup = lookup.context("Snapshot lookup did not finish")?;
         // record provides if it mentions a dependency
         let mut provides = Vec::new();
         for value in &package.provides {
@@ -268,34 +827,103 @@ pub async fn resolve_dependencies(
             name: package.name.to_string(),
             version: package.version.to_string(),
             system: "debian".to_string(),
-            url,
+            url: lookup.url,
+            fallback_urls: vec![],
             provides,
             sha256: package.sha256.to_string(),
-            signature: None,
+            commit: None,
+            signatures: vec![],
             installed: false,
+            depends: vec![],
+            size: Some(lookup.size),
         });
     }
 
+    // record which of the locked packages need to be installed first
+    for (pkg, (_, package)) in dependencies[first..].iter_mut().zip(resolved) {
+        let mut depends = package
+            .depends
+            .iter()
+            .filter_map(|name| providers.get(name.as_str()))
+            .filter(|name| **name != package.name)
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        depends.sort();
+        depends.dedup();
+        pkg.depends = depends;
+    }
+
     Ok(())
 }
 
+/// Where the mirror credentials are mounted for apt
+const APT_AUTH_CONF: &str = "/etc/apt/auth.conf.d/repro-env.conf";
+
+/// Write the credentials into a private file that's mounted into the container,
+/// so they're not part of the podman arguments
+fn write_auth_conf(credentials: &Credentials) -> Result<NamedTempFile> {
+    let mut file = tempfile::Builder::new()
+        .prefix("repro-env-")
+        .suffix(".conf")
+        .tempfile()
+        .context("Failed to create apt auth.conf")?;
+    file.write_all(credentials.apt_auth_conf()?.as_bytes())
+        .context("Failed to write apt auth.conf")?;
+    file.flush()?;
+    Ok(file)
+}
+
 pub async fn resolve(
     update: &args::Update,
     manifest: &PackagesManifest,
     container: &ContainerLock,
+    project: &Path,
     dependencies: &mut Vec<PackageLock>,
+    provenance: &mut Provenance,
 ) -> Result<()> {
+    let image = container::prepare_image(container, project).await?;
+    if manifest.solver == Solver::Builtin {
+        return resolve_builtin(update, &image, manifest, dependencies, provenance).await;
+    }
+
+    let certs = image_keyring(&image).await?;
+
+    // apt in the container needs the mirror credentials too
+    let credentials = Credentials::load()?;
+    let auth_conf = if !credentials.mirrors.is_empty() {
+        Some(write_auth_conf(&credentials)?)
+    } else {
+        None
+    };
+    let mounts = auth_conf
+        .iter()
+        .map(|file| Mount::read_only(file.path(), APT_AUTH_CONF))
+        .collect::<Vec<_>>();
+
     let container = Container::create(
-        &container.image,
+        &image,
         container::Config {
-            mounts: &[],
+            mounts: &mounts,
             expose_fuse: false,
+            lockfile_digest: None,
+            prepared: false,
+            platform: None,
+            podman_args: &[],
+            read_only_root: false,
+            init: container.init,
         },
     )
     .await?;
     container
         .run(
-            resolve_dependencies(&container, manifest, dependencies),
+            resolve_dependencies(
+                update,
+                &container,
+                manifest,
+                certs,
+                dependencies,
+                provenance,
+            ),
             update.keep,
         )
         .await
@@ -373,7 +1001,18 @@ SHA256: 26dd439266153e38d3e6fbe0fe2dbbb41f20994afa688faa71f38427348589ed
                 PkgEntry {
                     name: "binutils-aarch64-linux-gnu".to_string(),
                     version: "2.40-2".to_string(),
+                    path: "pool/main/b/binutils/binutils-aarch64-linux-gnu_2.40-2_amd64.deb"
+                        .to_string(),
                     provides: vec![],
+                    depends: vec![
+                        "binutils-common".to_string(),
+                        "libbinutils".to_string(),
+                        "libc6".to_string(),
+                        "libgcc-s1".to_string(),
+                        "libjansson4".to_string(),
+                        "libzstd1".to_string(),
+                        "zlib1g".to_string(),
+                    ],
                     sha256: "3d6f64a7a4ed6d73719f8fa2e85fd896f58ff7f211a6683942ba93de690aaa66"
                         .to_string(),
                 },
@@ -383,7 +1022,16 @@ SHA256: 26dd439266153e38d3e6fbe0fe2dbbb41f20994afa688faa71f38427348589ed
                 PkgEntry {
                     name: "rustc".to_string(),
                     version: "1.63.0+dfsg1-2".to_string(),
+                    path: "pool/main/r/rustc/rustc_1.63.0+dfsg1-2_amd64.deb".to_string(),
                     provides: vec![],
+                    depends: vec![
+                        "libc6".to_string(),
+                        "libgcc-s1".to_string(),
+                        "libstd-rust-dev".to_string(),
+                        "gcc".to_string(),
+                        "libc-dev".to_string(),
+                        "binutils".to_string(),
+                    ],
                     sha256: "26dd439266153e38d3e6fbe0fe2dbbb41f20994afa688faa71f38427348589ed"
                         .to_string(),
                 },
@@ -403,7 +1051,9 @@ SHA256: 26dd439266153e38d3e6fbe0fe2dbbb41f20994afa688faa71f38427348589ed
             PkgEntry {
                 name: "rustc".to_string(),
                 version: "1.63.0+dfsg1-2".to_string(),
+                path: "pool/main/r/rustc/rustc_1.63.0+dfsg1-2_amd64.deb".to_string(),
                 provides: vec![],
+                depends: vec![],
                 sha256: "26dd439266153e38d3e6fbe0fe2dbbb41f20994afa688faa71f38427348589ed"
                     .to_string(),
             },
@@ -418,7 +1068,9 @@ SHA256: 26dd439266153e38d3e6fbe0fe2dbbb41f20994afa688faa71f38427348589ed
                 &PkgEntry {
                     name: "rustc".to_string(),
                     version: "1.63.0+dfsg1-2".to_string(),
+                    path: "pool/main/r/rustc/rustc_1.63.0+dfsg1-2_amd64.deb".to_string(),
                     provides: vec![],
+                    depends: vec![],
                     sha256: "26dd439266153e38d3e6fbe0fe2dbbb41f20994afa688faa71f38427348589ed"
                         .to_string(),
                 }
@@ -428,6 +1080,18 @@ SHA256: 26dd439266153e38d3e6fbe0fe2dbbb41f20994afa688faa71f38427348589ed
         let result = db.find_by_apt_output("'http://deb.debian.org/debian/pool/main/n/non-existant/non-existant_1.2.3_amd64.deb' non-existant_1.2.3_amd64.deb 2612712 MD5Sum:5eaa6969388c512a206377bf813ab531");
         assert!(result.is_err());
 
+        // a redirector that uses its own naming, matched by checksum
+        let (url, pkg) = db.find_by_apt_output("'http://mirror.example.com/by-hash/26dd4392' rustc_1.63.0+dfsg1-2_amd64.deb 2612712 SHA256:26dd439266153e38d3e6fbe0fe2dbbb41f20994afa688faa71f38427348589ed")?;
+        assert_eq!(url, "http://mirror.example.com/by-hash/26dd4392");
+        assert_eq!(pkg.name, "rustc");
+        let (_, pkg) = db.find_by_apt_output("'http://mirror.example.com/by-hash/26dd4392' unknown.deb 2612712 SHA256:26dd439266153e38d3e6fbe0fe2dbbb41f20994afa688faa71f38427348589ed")?;
+        assert_eq!(pkg.name, "rustc");
+        assert!(db
+            .find_by_apt_output(
+                "'http://mirror.example.com/by-hash/00' unknown.deb 2612712 SHA256:00"
+            )
+            .is_err());
+
         Ok(())
     }
 
@@ -461,6 +1125,8 @@ SHA256: 2bb1befee1b89f0462b74d519be9b8c94c038d7f8a074d050d62985f47ec4164
                 PkgEntry {
                     name: "librust-repro-env-dev".to_string(),
                     version: "0.3.2-1".to_string(),
+                    path: "pool/main/r/rust-repro-env/librust-repro-env-dev_0.3.2-1_amd64.deb"
+                        .to_string(),
                     provides: vec![
                         "librust-repro-env+default-dev".to_string(),
                         "librust-repro-env-0+default-dev".to_string(),
@@ -470,6 +1136,46 @@ SHA256: 2bb1befee1b89f0462b74d519be9b8c94c038d7f8a074d050d62985f47ec4164
                         "librust-repro-env-0.3.2+default-dev".to_string(),
                         "librust-repro-env-0.3.2-dev".to_string(),
                     ],
+                    depends: vec![
+                        "librust-anyhow-1+default-dev".to_string(),
+                        "librust-ar-0.9+default-dev".to_string(),
+                        "librust-bytes-1+default-dev".to_string(),
+                        "librust-clap-4+default-dev".to_string(),
+                        "librust-clap-4+derive-dev".to_string(),
+                        "librust-clap-complete-4+default-dev".to_string(),
+                        "librust-clone-file-0.1+default-dev".to_string(),
+                        "librust-data-encoding-2+default-dev".to_string(),
+                        "librust-dirs-5+default-dev".to_string(),
+                        "librust-env-logger-0.10+default-dev".to_string(),
+                        "librust-fd-lock-3+default-dev".to_string(),
+                        "librust-flate2-1+default-dev".to_string(),
+                        "librust-hex-0.4+default-dev".to_string(),
+                        "librust-log-0.4+default-dev".to_string(),
+                        "librust-lz4-flex-0.11+default-dev".to_string(),
+                        "librust-lzma-rs-0.3+default-dev".to_string(),
+                        "librust-memchr-2+default-dev".to_string(),
+                        "librust-nix-0.26+sched-dev".to_string(),
+                        "librust-peekread-0.1+default-dev".to_string(),
+                        "librust-reqwest-0.11+rustls-tls-native-roots-dev".to_string(),
+                        "librust-reqwest-0.11+stream-dev".to_string(),
+                        "librust-reqwest-0.11+tokio-socks-dev".to_string(),
+                        "librust-ruzstd-0.4+default-dev".to_string(),
+                        "librust-serde-1+default-dev".to_string(),
+                        "librust-serde-1+derive-dev".to_string(),
+                        "librust-serde-json-1+default-dev".to_string(),
+                        "librust-sha1-0.10+default-dev".to_string(),
+                        "librust-sha2-0.10+default-dev".to_string(),
+                        "librust-tar-0.4+default-dev".to_string(),
+                        "librust-tempfile-3+default-dev".to_string(),
+                        "librust-tokio-1+default-dev".to_string(),
+                        "librust-tokio-1+fs-dev".to_string(),
+                        "librust-tokio-1+macros-dev".to_string(),
+                        "librust-tokio-1+process-dev".to_string(),
+                        "librust-tokio-1+rt-multi-thread-dev".to_string(),
+                        "librust-tokio-1+signal-dev".to_string(),
+                        "librust-toml-0.7+default-dev".to_string(),
+                        "librust-urlencoding-2+default-dev".to_string(),
+                    ],
                     sha256: "2bb1befee1b89f0462b74d519be9b8c94c038d7f8a074d050d62985f47ec4164"
                         .to_string(),
                 },
@@ -479,4 +1185,211 @@ SHA256: 2bb1befee1b89f0462b74d519be9b8c94c038d7f8a074d050d62985f47ec4164
         assert_eq!(db, PkgDatabase { pkgs });
         Ok(())
     }
+
+    #[test]
+    fn test_find_snapshot_pkg() -> Result<()> {
+        let info = serde_json::from_str::<JsonSnapshotInfo>(
+            r#"{"_comment":"foo","result":[{"archive_name":"debian","first_seen":"20230115T211934Z","name":"binutils-common_2.40-2_amd64.deb","path":"/pool/main/b/binutils","size":2487304}]}"#,
+        )?;
+        let pkg = find_snapshot_pkg(&info, "binutils-common_2.40-2_amd64.deb", 2487304)?;
+        assert_eq!(pkg.first_seen, "20230115T211934Z");
+        assert!(find_snapshot_pkg(&info, "binutils-common_2.40-2_amd64.deb", 1337).is_err());
+
+        // the content is the same, even if the name isn't
+        let pkg = find_snapshot_pkg(&info, "binutils-common_2.40-2_amd64%2b.deb", 2487304)?;
+        assert_eq!(pkg.name, "binutils-common_2.40-2_amd64.deb");
+
+        let info = JsonSnapshotInfo { result: vec![] };
+        assert!(find_snapshot_pkg(&info, "binutils-common_2.40-2_amd64.deb", 2487304).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_mirror_urls() -> Result<()> {
+        let path = "pool/main/r/rustc/rustc_1.63.0+dfsg1-2_amd64.deb";
+        assert_eq!(
+            mirror_list_url(
+                "mirror+file:/etc/apt/mirrors/debian.list/pool/main/r/rustc/rustc_1.63.0%2bdfsg1-2_amd64.deb",
+                path
+            )?,
+            Some("file:/etc/apt/mirrors/debian.list".to_string())
+        );
+        assert_eq!(
+            mirror_list_url(
+                "mirror+https://example.com/mirrors.txt/pool/main/r/rustc/rustc_1.63.0+dfsg1-2_amd64.deb",
+                path
+            )?,
+            Some("https://example.com/mirrors.txt".to_string())
+        );
+        assert_eq!(
+            mirror_list_url(
+                "http://deb.debian.org/debian/pool/main/r/rustc/rustc_1.63.0%2bdfsg1-2_amd64.deb",
+                path
+            )?,
+            None
+        );
+        assert!(
+            mirror_list_url("mirror+file:/etc/apt/mirrors/debian.list/other.deb", path).is_err()
+        );
+
+        let list = "# primary\n\nhttps://mirror.example.com/debian/\tpriority:1\nhttps://deb.debian.org/debian\n";
+        assert_eq!(
+            parse_mirror_list(list),
+            Some("https://mirror.example.com/debian")
+        );
+        assert_eq!(parse_mirror_list("# nothing\n"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_os_codename() {
+        let os_release = "PRETTY_NAME=\"Debian GNU/Linux 12 (bookworm)\"\nVERSION_ID=\"12\"\nVERSION_CODENAME=bookworm\nID=debian\n";
+        assert_eq!(parse_os_codename(os_release), Some("bookworm"));
+        assert_eq!(parse_os_codename("ID=debian\nVERSION_CODENAME=\n"), None);
+    }
+
+    #[test]
+    fn test_apt_sources() {
+        assert_eq!(
+            apt_sources("bookworm", true, true, None),
+            "Types: deb
+URIs: http://deb.debian.org/debian
+Suites: bookworm bookworm-updates
+Components: main
+Signed-By: /usr/share/keyrings/debian-archive-keyring.gpg
+
+Types: deb
+URIs: http://deb.debian.org/debian-security
+Suites: bookworm-security
+Components: main
+Signed-By: /usr/share/keyrings/debian-archive-keyring.gpg
+"
+        );
+        assert_eq!(
+            apt_sources("bookworm", false, false, None),
+            "Types: deb
+URIs: http://deb.debian.org/debian
+Suites: bookworm
+Components: main
+Signed-By: /usr/share/keyrings/debian-archive-keyring.gpg
+"
+        );
+        assert_eq!(
+            apt_sources("bookworm", true, false, Some("20240211T235959Z")),
+            "Types: deb
+URIs: http://snapshot.debian.org/archive/debian/20240211T235959Z
+Suites: bookworm
+Components: main
+Signed-By: /usr/share/keyrings/debian-archive-keyring.gpg
+Check-Valid-Until: no
+
+Types: deb
+URIs: http://snapshot.debian.org/archive/debian-security/20240211T235959Z
+Suites: bookworm-security
+Components: main
+Signed-By: /usr/share/keyrings/debian-archive-keyring.gpg
+Check-Valid-Until: no
+"
+        );
+    }
+
+    #[test]
+    fn test_point_release_snapshot() -> Result<()> {
+        let changelog = "=========================================
+Sat, 10 Feb 2024 - Debian 12.5 released
+=========================================
+Changes:
+ base-files (12.4+deb12u5) bookworm; urgency=medium
+
+=========================================
+Sat, 09 Dec 2023 - Debian 12.4 released
+=========================================
+";
+        assert_eq!(
+            point_release_snapshot(changelog, "12.5")?,
+            "20240211T235959Z"
+        );
+        assert_eq!(
+            point_release_snapshot(changelog, "12.4")?,
+            "20231210T235959Z"
+        );
+        assert!(point_release_snapshot(changelog, "12.6").is_err());
+        assert!(point_release_snapshot(changelog, "12").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_point_release() {
+        let release = |name: &str, codename: &str, version: Option<&str>| VerifiedRelease {
+            name: name.to_string(),
+            date: None,
+            codename: Some(codename.to_string()),
+            version: version.map(String::from),
+        };
+        let releases = vec![
+            release(
+                "deb.debian.org_debian_dists_bookworm",
+                "bookworm",
+                Some("12.5"),
+            ),
+            release(
+                "deb.debian.org_debian-security_dists_bookworm-security",
+                "bookworm-security",
+                Some("12"),
+            ),
+            release(
+                "deb.debian.org_debian_dists_bookworm-updates",
+                "bookworm-updates",
+                None,
+            ),
+        ];
+        assert!(check_point_release(&releases, "12.5").is_ok());
+        assert!(check_point_release(&releases, "12.4").is_err());
+        assert!(check_point_release(&releases[1..], "12.5").is_err());
+    }
+
+    #[test]
+    fn test_apt_debug_sources() {
+        assert_eq!(
+            apt_debug_sources("bookworm"),
+            "Types: deb
+URIs: http://deb.debian.org/debian-debug
+Suites: bookworm-debug
+Components: main
+Signed-By: /usr/share/keyrings/debian-archive-keyring.gpg
+"
+        );
+    }
+
+    #[test]
+    fn test_debug_packages() {
+        let mut db = PkgDatabase::default();
+        for (name, version) in [
+            ("zstd", "1.5.4+dfsg2-5"),
+            ("zstd-dbgsym", "1.5.4+dfsg2-5"),
+            ("libc6", "2.36-9"),
+            ("libc6-dbg", "2.36-9"),
+            ("libssl3", "3.0.11-1"),
+            ("libssl3-dbgsym", "3.0.9-1"),
+        ] {
+            db.pkgs.insert(
+                format!("{name}_{version}_amd64.deb"),
+                PkgEntry {
+                    name: name.to_string(),
+                    version: version.to_string(),
+                    path: format!("pool/main/{name}_{version}_amd64.deb"),
+                    provides: vec![],
+                    depends: vec![],
+                    sha256: String::new(),
+                },
+            );
+        }
+        let debug = db.debug_packages([
+            ("zstd", "1.5.4+dfsg2-5"),
+            ("zstd-dbgsym", "1.5.4+dfsg2-5"),
+            ("libc6", "2.36-9"),
+            ("libssl3", "3.0.11-1"),
+        ]);
+        assert_eq!(debug, ["zstd-dbgsym", "libc6-dbg"]);
+    }
 }
@@ -0,0 +1,391 @@
+//! Verify apt package indexes against the signed InRelease files, so the
+//! sha256 pins we record can be traced back to the archive signing keys
+//! instead of trusting whatever is in /var/lib/apt/lists.
+
+use crate::errors::*;
+use crate::limits;
+use crate::pgp::TrustedCerts;
+use crate::pkgs;
+use sequoia_openpgp::cert::CertParser;
+use sequoia_openpgp::parse::stream::VerifierBuilder;
+use sequoia_openpgp::parse::Parse;
+use sequoia_openpgp::policy::StandardPolicy;
+use sequoia_openpgp::Cert;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Read;
+use time::format_description::well_known;
+use time::OffsetDateTime;
+
+/// The compressions apt may store the lists in /var/lib/apt/lists with
+const LIST_COMPRESSIONS: &[&str] = &["lz4", "gz", "xz", "zst"];
+
+/// Load all certificates from a single keyring file (binary or armored)
+pub fn read_certs(data: &[u8], path: &str) -> Result<Vec<Cert>> {
+    let mut certs = Vec::new();
+    for cert in CertParser::from_bytes(data)? {
+        match cert {
+            Ok(cert) => certs.push(cert),
+            Err(err) => debug!("Skipping invalid certificate in {path:?}: {err:#}"),
+        }
+    }
+    Ok(certs)
+}
+
+/// Verify a clearsigned InRelease file and return the signed text
+pub fn verify_clearsigned(buf: &[u8], certs: &[Cert]) -> Result<String> {
+    let policy = StandardPolicy::new();
+    let mut verifier =
+        VerifierBuilder::from_bytes(buf)?.with_policy(&policy, None, TrustedCerts { certs })?;
+    let mut text = String::new();
+    verifier.read_to_string(&mut text)?;
+    Ok(text)
+}
+
+/// Parse the `SHA256:` section of a Release file into path => (sha256, size)
+pub fn parse_release(text: &str) -> Result<HashMap<String, (String, u64)>> {
+    let mut hashes = HashMap::new();
+    let mut in_section = false;
+    for line in text.lines() {
+        if let Some(entry) = line.strip_prefix(' ') {
+            if !in_section {
+                continue;
+            }
+            let mut fields = entry.split_whitespace();
+            let (Some(sha256), Some(size), Some(path), None) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                bail!("Invalid line in Release file: {line:?}");
+            };
+            let size = size
+                .parse()
+                .with_context(|| anyhow!("Invalid size in Release file: {line:?}"))?;
+            hashes.insert(path.to_string(), (sha256.to_string(), size));
+        } else {
+            in_section = line == "SHA256:";
+        }
+    }
+    Ok(hashes)
+}
+
+/// Parse the `Date:` field of a Release file into a unix epoch
+pub fn parse_release_date(text: &str) -> Result<Option<i64>> {
+    let Some(date) = text.lines().find_map(|line| line.strip_prefix("Date: ")) else {
+        return Ok(None);
+    };
+    // apt writes the timezone as `UTC`, which is not valid rfc2822
+    let normalized = match date.strip_suffix(" UTC") {
+        Some(date) => format!("{date} +0000"),
+        None => date.to_string(),
+    };
+    let date = OffsetDateTime::parse(&normalized, &well_known::Rfc2822)
+        .with_context(|| anyhow!("Invalid date in Release file: {date:?}"))?;
+    Ok(Some(date.unix_timestamp()))
+}
+
+/// The value of a single-line field of a Release file, e.g. `Suite` or `Version`
+pub fn parse_release_field<'a>(text: &'a str, field: &str) -> Option<&'a str> {
+    text.lines()
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| line.strip_prefix(field)?.strip_prefix(": "))
+}
+
+/// If a file of /var/lib/apt/lists is a Packages index, its name without
+/// compression extension. A Packages index with an unknown extension is an
+/// error, it couldn't be verified.
+pub fn packages_list(filename: &str) -> Result<Option<&str>> {
+    if filename.ends_with("_Packages") {
+        return Ok(Some(filename));
+    }
+    let Some((name, ext)) = filename.rsplit_once('.') else {
+        return Ok(None);
+    };
+    if !name.ends_with("_Packages") {
+        return Ok(None);
+    }
+    if !LIST_COMPRESSIONS.contains(&ext) {
+        bail!("Package index has an unknown compression and can't be verified: {filename:?}");
+    }
+    Ok(Some(name))
+}
+
+/// Check a downloaded index against its entry in a verified Release file
+pub fn check_index(name: &str, data: &[u8], expected: &str, size: u64) -> Result<()> {
+    let sha256 = hex::encode(Sha256::digest(data));
+    if sha256 != expected || data.len() as u64 != size {
+        bail!(
+            "Package index {name:?} does not match InRelease: expected={expected:?}, found={sha256:?}"
+        );
+    }
+    debug!("Verified package index {name:?}");
+    Ok(())
+}
+
+/// Pick the Packages index of a component and architecture from a Release
+/// file, preferring compressed variants. Returns the path relative to the
+/// `dists/<suite>` directory, its sha256 and its size.
+pub fn packages_index<'a>(
+    hashes: &'a HashMap<String, (String, u64)>,
+    component: &str,
+    arch: &str,
+) -> Option<(String, &'a str, u64)> {
+    ["xz", "gz", "zst", "lz4", ""].iter().find_map(|ext| {
+        let mut path = format!("{component}/binary-{arch}/Packages");
+        if !ext.is_empty() {
+            path.push('.');
+            path.push_str(ext);
+        }
+        let (sha256, size) = hashes.get(&path)?;
+        Some((path, sha256.as_str(), *size))
+    })
+}
+
+/// A release whose InRelease signature has been verified
+#[derive(Debug, PartialEq)]
+pub struct VerifiedRelease {
+    /// The filename prefix in /var/lib/apt/lists, e.g. `deb.debian.org_debian_dists_bookworm`
+    pub name: String,
+    /// When the release was generated, according to its signed `Date:` field
+    pub date: Option<i64>,
+    pub codename: Option<String>,
+    /// The point release, e.g. `12.5`
+    pub version: Option<String>,
+}
+
+/// Check every package index in a tar of /var/lib/apt/lists against the
+/// verified InRelease file of its repository, no matter how apt compressed
+/// it. Returns the verified releases, sorted by name.
+pub fn verify_lists(buf: &[u8], certs: &[Cert]) -> Result<Vec<VerifiedRelease>> {
+    let mut releases = HashMap::new();
+    let mut indexes = Vec::new();
+    let mut verified = Vec::new();
+
+    let mut tar = tar::Archive::new(buf);
+    for (idx, entry) in tar.entries()?.enumerate() {
+        limits::INDEX.check_entry(idx)?;
+        let mut entry = entry?;
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        let path = entry.path()?.into_owned();
+        // downloads that didn't finish, apt doesn't use them
+        if path.components().any(|c| c.as_os_str() == "partial") {
+            continue;
+        }
+        let Some(filename) = path.file_name().and_then(|f| f.to_str()) else {
+            continue;
+        };
+        if let Some(prefix) = filename.strip_suffix("_InRelease") {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            let text = verify_clearsigned(&data, certs)
+                .with_context(|| anyhow!("Failed to verify signature of {filename:?}"))?;
+            debug!("Verified signature of {filename:?}");
+            verified.push(VerifiedRelease {
+                name: prefix.to_string(),
+                date: parse_release_date(&text)?,
+                codename: parse_release_field(&text, "Codename").map(String::from),
+                version: parse_release_field(&text, "Version").map(String::from),
+            });
+            releases.insert(prefix.to_string(), parse_release(&text)?);
+        } else if filename.ends_with("_Release") || filename.ends_with("_Release.gpg") {
+            bail!("Repository without InRelease file is not supported: {filename:?}");
+        } else if let Some(name) = packages_list(filename)? {
+            let mut compressed = Vec::new();
+            limits::INDEX.wrap(entry).read_to_end(&mut compressed)?;
+            let mut data = Vec::new();
+            pkgs::archlinux::decompress(&compressed[..], limits::INDEX)?.read_to_end(&mut data)?;
+            indexes.push((name.to_string(), data));
+        }
+    }
+
+    for (name, data) in indexes {
+        let (prefix, hashes) = releases
+            .iter()
+            .find(|(prefix, _)| name.starts_with(&format!("{prefix}_")))
+            .with_context(|| anyhow!("Found no signed InRelease file for {name:?}"))?;
+        let path = name[prefix.len() + 1..].replace('_', "/");
+        let (expected, size) = hashes
+            .get(&path)
+            .with_context(|| anyhow!("Package index is not listed in InRelease: {path:?}"))?;
+
+        check_index(&name, &data, expected, *size)?;
+    }
+
+    verified.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(verified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sequoia_openpgp::cert::CertBuilder;
+    use sequoia_openpgp::serialize::stream::{Message, Signer};
+    use sequoia_openpgp::serialize::SerializeInto;
+    use std::io::Write;
+
+    fn clearsign(cert: &Cert, text: &str) -> Result<Vec<u8>> {
+        let policy = StandardPolicy::new();
+        let keypair = cert
+            .keys()
+            .unencrypted_secret()
+            .with_policy(&policy, None)
+            .for_signing()
+            .next()
+            .context("No signing key")?
+            .key()
+            .clone()
+            .into_keypair()?;
+        let mut out = Vec::new();
+        let message = Message::new(&mut out);
+        let mut signer = Signer::new(message, keypair).cleartext().build()?;
+        signer.write_all(text.as_bytes())?;
+        signer.finalize()?;
+        Ok(out)
+    }
+
+    fn tar(files: &[(&str, &[u8])]) -> Result<Vec<u8>> {
+        let mut tar = tar::Builder::new(Vec::new());
+        for (path, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, path, *data)?;
+        }
+        Ok(tar.into_inner()?)
+    }
+
+    fn lz4(data: &[u8]) -> Result<Vec<u8>> {
+        let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+        encoder.write_all(data)?;
+        Ok(encoder.finish()?)
+    }
+
+    #[test]
+    fn test_parse_release() -> Result<()> {
+        let hashes = parse_release(
+            "Origin: Debian
+Suite: stable
+MD5Sum:
+ 0ba5a9ecbf2a5ba6d6a7e0e7d7e0e7d7 1234 main/binary-amd64/Packages
+SHA256:
+ 4f3b3f4c6d5e9a1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f60718293 1234 main/binary-amd64/Packages
+ 5a4b3c2d1e0f9a8b7c6d5e4f3a2b1c0d9e8f7a6b5c4d3e2f1a0b9c8d7e6f5a4b 567 main/i18n/Translation-en
+",
+        )?;
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(
+            hashes["main/binary-amd64/Packages"],
+            (
+                "4f3b3f4c6d5e9a1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f60718293".to_string(),
+                1234
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_release_date() -> Result<()> {
+        let date = parse_release_date("Origin: Debian\nDate: Sat, 10 Jun 2023 09:26:13 UTC\n")?;
+        assert_eq!(date, Some(1686389173));
+        assert_eq!(parse_release_date("Origin: Debian\n")?, None);
+        assert!(parse_release_date("Date: yesterday\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_release_field() {
+        let text =
+            "Origin: Debian\nSuite: stable-security\nCodename: bookworm-security\n\nVersion: 12\n";
+        assert_eq!(
+            parse_release_field(text, "Codename"),
+            Some("bookworm-security")
+        );
+        assert_eq!(parse_release_field(text, "Suite"), Some("stable-security"));
+        assert_eq!(parse_release_field(text, "Version"), None);
+        assert_eq!(parse_release_field(text, "Origi"), None);
+    }
+
+    #[test]
+    fn test_verify_lists() -> Result<()> {
+        let (cert, _) = CertBuilder::general_purpose(None, Some("archive")).generate()?;
+        let (other, _) = CertBuilder::general_purpose(None, Some("attacker")).generate()?;
+        let certs = read_certs(&cert.to_vec()?, "debian-archive-keyring.gpg")?;
+
+        let packages = b"Package: rustc\nVersion: 1.63.0+dfsg1-2\n\n";
+        let release = format!(
+            "Suite: stable\nCodename: bookworm\nVersion: 12.5\nDate: Sat, 10 Jun 2023 09:26:13 UTC\nSHA256:\n {} {} main/binary-amd64/Packages\n",
+            hex::encode(Sha256::digest(packages)),
+            packages.len()
+        );
+        let in_release = clearsign(&cert, &release)?;
+        let index = "deb.debian.org_debian_dists_stable_main_binary-amd64_Packages.lz4";
+
+        let lists = tar(&[
+            ("deb.debian.org_debian_dists_stable_InRelease", &in_release),
+            (index, &lz4(packages)?),
+        ])?;
+        assert_eq!(
+            verify_lists(&lists, &certs)?,
+            [VerifiedRelease {
+                name: "deb.debian.org_debian_dists_stable".to_string(),
+                date: Some(1686389173),
+                codename: Some("bookworm".to_string()),
+                version: Some("12.5".to_string()),
+            }]
+        );
+
+        // modified package index
+        let lists = tar(&[
+            ("deb.debian.org_debian_dists_stable_InRelease", &in_release),
+            (index, &lz4(b"Package: evil\n\n")?),
+        ])?;
+        assert!(verify_lists(&lists, &certs).is_err());
+
+        // signed by an untrusted key
+        let lists = tar(&[
+            (
+                "deb.debian.org_debian_dists_stable_InRelease",
+                &clearsign(&other, &release)?,
+            ),
+            (index, &lz4(packages)?),
+        ])?;
+        assert!(verify_lists(&lists, &certs).is_err());
+
+        // no InRelease at all
+        let lists = tar(&[(index, &lz4(packages)?)])?;
+        assert!(verify_lists(&lists, &certs).is_err());
+
+        // uncompressed lists are verified too
+        let plain = "deb.debian.org_debian_dists_stable_main_binary-amd64_Packages";
+        let lists = tar(&[
+            ("deb.debian.org_debian_dists_stable_InRelease", &in_release),
+            (plain, b"Package: evil\n\n"),
+        ])?;
+        assert!(verify_lists(&lists, &certs).is_err());
+
+        // a compression we can't read
+        let lists = tar(&[
+            ("deb.debian.org_debian_dists_stable_InRelease", &in_release),
+            (&format!("{plain}.bz2"), b"BZh"),
+        ])?;
+        assert!(verify_lists(&lists, &certs).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_packages_list() -> Result<()> {
+        let plain = "deb.debian.org_debian_dists_stable_main_binary-amd64_Packages";
+        assert_eq!(packages_list(plain)?, Some(plain));
+        assert_eq!(packages_list(&format!("{plain}.lz4"))?, Some(plain));
+        assert_eq!(packages_list(&format!("{plain}.xz"))?, Some(plain));
+        assert!(packages_list(&format!("{plain}.bz2")).is_err());
+        assert_eq!(
+            packages_list("deb.debian.org_debian_dists_stable_main_i18n_Translation-en.lz4")?,
+            None
+        );
+        assert_eq!(packages_list("lock")?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,656 @@
+//! Experimental dependency solver that resolves debian dependencies from the
+//! Packages indexes directly, instead of asking apt inside of the container.
+
+use crate::errors::*;
+use crate::resolver::diagnose::UnknownPackage;
+use crate::version::{self, Relation};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
+
+/// A paragraph of a deb822 document, like a package of a Packages index
+#[derive(Debug, Default, PartialEq)]
+pub struct Paragraph<'a> {
+    fields: Vec<(&'a str, Cow<'a, str>)>,
+}
+
+impl Paragraph<'_> {
+    /// The value of a field, continuation lines are joined with newlines
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_ref())
+    }
+}
+
+/// Split a deb822 document into paragraphs, separated by blank lines. Lines that
+/// start with whitespace continue the value of the previous field, comments are skipped.
+pub fn paragraphs(text: &str) -> Vec<Paragraph<'_>> {
+    let mut paragraphs = Vec::new();
+    let mut current = Paragraph::default();
+    for line in text.lines() {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.trim().is_empty() {
+            if !current.fields.is_empty() {
+                paragraphs.push(std::mem::take(&mut current));
+            }
+        } else if line.starts_with('#') {
+            continue;
+        } else if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = current.fields.last_mut() {
+                let value = value.to_mut();
+                value.push('\n');
+                // a single `.` is an empty line of a multiline value
+                let line = line.trim();
+                value.push_str(if line == "." { "" } else { line });
+            }
+        } else if let Some((key, value)) = line.split_once(':') {
+            current
+                .fields
+                .push((key.trim(), Cow::Borrowed(value.trim())));
+        }
+    }
+    if !current.fields.is_empty() {
+        paragraphs.push(current);
+    }
+    paragraphs
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dependency {
+    pub name: String,
+    pub constraint: Option<(Relation, String)>,
+}
+
+impl Dependency {
+    pub fn parse(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (name, constraint) = match s.split_once('(') {
+            Some((name, constraint)) => {
+                let constraint = constraint
+                    .trim()
+                    .strip_suffix(')')
+                    .with_context(|| anyhow!("Missing closing parenthesis: {s:?}"))?;
+                let idx = constraint
+                    .find(|c: char| !"<=>".contains(c))
+                    .with_context(|| anyhow!("Missing version in dependency: {s:?}"))?;
+                let (relation, version) = constraint.split_at(idx);
+                (
+                    name.trim(),
+                    Some((relation.parse()?, version.trim().to_string())),
+                )
+            }
+            None => (s, None),
+        };
+        // architecture qualifiers like `python3:any` are not relevant for a single architecture
+        let (name, _arch) = name.split_once(':').unwrap_or((name, ""));
+        Ok(Dependency {
+            name: name.to_string(),
+            constraint,
+        })
+    }
+
+    /// Parse a list of dependencies, each entry may have alternatives (`a | b`)
+    pub fn parse_list(s: &str) -> Result<Vec<Vec<Self>>> {
+        s.split(',')
+            .filter(|group| !group.trim().is_empty())
+            .map(|group| group.split('|').map(Self::parse).collect())
+            .collect()
+    }
+
+    pub fn matches_version(&self, version: Option<&str>) -> bool {
+        match (&self.constraint, version) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some((relation, expected)), Some(version)) => {
+                relation.matches(version::dpkg_cmp(version, expected))
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct DebPackage {
+    pub name: String,
+    pub version: String,
+    pub filename: String,
+    pub base_url: Arc<String>,
+    pub provides: Vec<Dependency>,
+    pub depends: Vec<Vec<Dependency>>,
+    pub recommends: Vec<Vec<Dependency>>,
+    pub suggests: Vec<Vec<Dependency>>,
+    pub conflicts: Vec<Dependency>,
+}
+
+/// Which weak dependencies should be installed too, like apt's
+/// `--install-recommends` and `--install-suggests`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WeakDepends {
+    pub recommends: bool,
+    pub suggests: bool,
+}
+
+impl DebPackage {
+    pub fn url(&self) -> String {
+        format!("{}/{}", self.base_url, self.filename)
+    }
+
+    fn provides(&self, dep: &Dependency) -> bool {
+        self.provides
+            .iter()
+            .filter(|p| p.name == dep.name)
+            .any(|p| {
+                let version = p.constraint.as_ref().map(|(_, v)| v.as_str());
+                dep.matches_version(version)
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstalledPackage {
+    pub version: String,
+    pub provides: Vec<Dependency>,
+}
+
+/// Packages that are installed in the container, parsed from /var/lib/dpkg/status
+pub fn parse_dpkg_status(text: &str) -> Result<HashMap<String, InstalledPackage>> {
+    let mut installed = HashMap::new();
+    for paragraph in paragraphs(text) {
+        let get = |key| paragraph.get(key);
+        let Some(name) = get("Package") else { continue };
+        if get("Status").map(|s| s.ends_with(" installed")) != Some(true) {
+            continue;
+        }
+        let version = get("Version").context("Installed package is missing version")?;
+        let provides = get("Provides")
+            .map(|p| p.split(',').map(Dependency::parse).collect())
+            .transpose()?
+            .unwrap_or_default();
+        installed.insert(
+            name.to_string(),
+            InstalledPackage {
+                version: version.to_string(),
+                provides,
+            },
+        );
+    }
+    Ok(installed)
+}
+
+/// The architecture of dpkg, the package indexes of this architecture are used
+pub fn dpkg_architecture(status: &str) -> Option<String> {
+    paragraphs(status)
+        .iter()
+        .find(|paragraph| paragraph.get("Package") == Some("dpkg"))
+        .and_then(|paragraph| paragraph.get("Architecture"))
+        .map(String::from)
+}
+
+#[derive(Debug, Default)]
+pub struct Index {
+    pkgs: HashMap<String, Vec<Arc<DebPackage>>>,
+    providers: HashMap<String, Vec<Arc<DebPackage>>>,
+}
+
+impl Index {
+    pub fn import_text(&mut self, text: &str, base_url: &Arc<String>) -> Result<()> {
+        for paragraph in paragraphs(text) {
+            let get = |key| paragraph.get(key);
+            let list = |key| -> Result<Vec<Vec<Dependency>>> {
+                get(key).map(Dependency::parse_list).unwrap_or(Ok(vec![]))
+            };
+
+            let name = get("Package").context("Package database entry is missing name")?;
+            let mut depends = list("Pre-Depends")?;
+            depends.extend(list("Depends")?);
+            let mut conflicts = Vec::new();
+            for key in ["Conflicts", "Breaks"] {
+                conflicts.extend(list(key)?.into_iter().flatten());
+            }
+
+            let pkg = Arc::new(DebPackage {
+                name: name.to_string(),
+                version: get("Version")
+                    .context("Package database entry is missing version")?
+                    .to_string(),
+                filename: get("Filename")
+                    .context("Package database entry is missing filename")?
+                    .to_string(),
+                base_url: base_url.clone(),
+                provides: list("Provides")?.into_iter().flatten().collect(),
+                depends,
+                recommends: list("Recommends")?,
+                suggests: list("Suggests")?,
+                conflicts,
+            });
+
+            for provides in &pkg.provides {
+                self.providers
+                    .entry(provides.name.clone())
+                    .or_default()
+                    .push(pkg.clone());
+            }
+            self.pkgs.entry(pkg.name.clone()).or_default().push(pkg);
+        }
+        Ok(())
+    }
+
+    /// The highest version of a real package that matches the dependency
+    fn candidate(&self, dep: &Dependency) -> Option<&Arc<DebPackage>> {
+        self.pkgs
+            .get(&dep.name)?
+            .iter()
+            .filter(|pkg| dep.matches_version(Some(&pkg.version)))
+            .max_by(|a, b| version::dpkg_cmp(&a.version, &b.version))
+    }
+
+    /// All packages that can satisfy the dependency through `Provides:`
+    fn providers(&self, dep: &Dependency) -> Vec<&Arc<DebPackage>> {
+        let mut providers: Vec<_> = self
+            .providers
+            .get(&dep.name)
+            .map(|list| list.iter().filter(|pkg| pkg.provides(dep)).collect())
+            .unwrap_or_default();
+        providers.sort_by(|a, b| {
+            a.name
+                .cmp(&b.name)
+                .then_with(|| version::dpkg_cmp(&b.version, &a.version))
+        });
+        providers
+    }
+}
+
+struct Solver<'a> {
+    index: &'a Index,
+    installed: &'a HashMap<String, InstalledPackage>,
+    selected: BTreeMap<String, Arc<DebPackage>>,
+    queue: VecDeque<Arc<DebPackage>>,
+    weak: WeakDepends,
+}
+
+impl Solver<'_> {
+    fn select(&mut self, pkg: &Arc<DebPackage>) -> Result<()> {
+        if let Some(existing) = self.selected.get(&pkg.name) {
+            if existing.version != pkg.version {
+                bail!(
+                    "Conflicting versions selected for {:?}: {:?} and {:?}",
+                    pkg.name,
+                    existing.version,
+                    pkg.version
+                );
+            }
+            return Ok(());
+        }
+        trace!("Selecting package: {:?} {:?}", pkg.name, pkg.version);
+        self.selected.insert(pkg.name.clone(), pkg.clone());
+        self.queue.push_back(pkg.clone());
+        Ok(())
+    }
+
+    /// Check if the dependency is satisfied by the selected packages, or by
+    /// installed packages that are not getting replaced
+    fn is_satisfied(&self, dep: &Dependency) -> bool {
+        let real = if let Some(pkg) = self.selected.get(&dep.name) {
+            dep.matches_version(Some(&pkg.version))
+        } else if let Some(pkg) = self.installed.get(&dep.name) {
+            dep.matches_version(Some(&pkg.version))
+        } else {
+            false
+        };
+
+        real || self.selected.values().any(|pkg| pkg.provides(dep))
+            || self
+                .installed
+                .iter()
+                .filter(|(name, _)| !self.selected.contains_key(*name))
+                .flat_map(|(_, pkg)| &pkg.provides)
+                .filter(|p| p.name == dep.name)
+                .any(|p| dep.matches_version(p.constraint.as_ref().map(|(_, v)| v.as_str())))
+    }
+
+    fn find(&self, dep: &Dependency) -> Option<Arc<DebPackage>> {
+        if let Some(pkg) = self.index.candidate(dep) {
+            return Some(pkg.clone());
+        }
+        self.index.providers(dep).first().map(|pkg| (*pkg).clone())
+    }
+
+    fn run(&mut self) -> Result<()> {
+        while let Some(pkg) = self.queue.pop_front() {
+            for group in &pkg.depends {
+                if group.iter().any(|dep| self.is_satisfied(dep)) {
+                    continue;
+                }
+                let Some(found) = group.iter().find_map(|dep| self.find(dep)) else {
+                    bail!(
+                        "Unsatisfiable dependency of {:?}: {:?}",
+                        pkg.name,
+                        group.iter().map(|d| &d.name).collect::<Vec<_>>()
+                    );
+                };
+                self.select(&found)?;
+            }
+
+            // like apt, weak dependencies are skipped if they can't be installed
+            let mut weak = Vec::new();
+            if self.weak.recommends {
+                weak.extend(&pkg.recommends);
+            }
+            if self.weak.suggests {
+                weak.extend(&pkg.suggests);
+            }
+            for group in weak {
+                if group.iter().any(|dep| self.is_satisfied(dep)) {
+                    continue;
+                }
+                if let Some(found) = group.iter().find_map(|dep| self.find(dep)) {
+                    self.select(&found)?;
+                } else {
+                    debug!(
+                        "Skipping unavailable weak dependency of {:?}: {:?}",
+                        pkg.name,
+                        group.iter().map(|d| &d.name).collect::<Vec<_>>()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_conflicts(&self) -> Result<()> {
+        for pkg in self.selected.values() {
+            for conflict in &pkg.conflicts {
+                // packages are allowed to conflict with what they provide
+                if pkg.provides.iter().any(|p| p.name == conflict.name) {
+                    continue;
+                }
+                let version = if let Some(other) = self.selected.get(&conflict.name) {
+                    Some(&other.version)
+                } else {
+                    self.installed.get(&conflict.name).map(|p| &p.version)
+                };
+                if let Some(version) = version {
+                    if conflict.name != pkg.name && conflict.matches_version(Some(version)) {
+                        bail!(
+                            "Package {:?} conflicts with {:?} {:?}",
+                            pkg.name,
+                            conflict.name,
+                            version
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Upgrade all installed packages and install the requested ones (`name` or
+/// `name=version`), returns the packages that need to be downloaded
+pub fn solve(
+    index: &Index,
+    installed: &HashMap<String, InstalledPackage>,
+    requested: &[&str],
+    weak: WeakDepends,
+) -> Result<Vec<Arc<DebPackage>>> {
+    let mut solver = Solver {
+        index,
+        installed,
+        selected: BTreeMap::new(),
+        queue: VecDeque::new(),
+        weak,
+    };
+
+    let mut names = installed.keys().collect::<Vec<_>>();
+    names.sort();
+    for name in names {
+        let dep = Dependency {
+            name: name.to_string(),
+            constraint: None,
+        };
+        if let Some(candidate) = index.candidate(&dep) {
+            if version::dpkg_cmp(&candidate.version, &installed[name].version) == Ordering::Greater
+            {
+                solver.select(candidate)?;
+            }
+        }
+    }
+
+    for request in requested {
+        let dep = match request.split_once('=') {
+            Some((name, version)) => Dependency {
+                name: name.to_string(),
+                constraint: Some((Relation::Equal, version.to_string())),
+            },
+            None => Dependency {
+                name: request.to_string(),
+                constraint: None,
+            },
+        };
+        if solver.is_satisfied(&dep) && dep.constraint.is_none() {
+            continue;
+        }
+        let pkg = solver
+            .find(&dep)
+            .ok_or_else(|| UnknownPackage(request.to_string()))?;
+        solver.select(&pkg)?;
+    }
+
+    solver.run()?;
+    solver.check_conflicts()?;
+
+    Ok(solver.selected.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACKAGES: &str = "Package: gcc
+Version: 4:12.2.0-3
+Depends: cpp (= 4:12.2.0-3), gcc-12 (>= 12.2.0-1~)
+Filename: pool/main/g/gcc-defaults/gcc_12.2.0-3_amd64.deb
+
+Package: cpp
+Version: 4:12.2.0-3
+Depends: cpp-12 (>= 12.2.0-1~)
+Filename: pool/main/g/gcc-defaults/cpp_12.2.0-3_amd64.deb
+
+Package: gcc-12
+Version: 12.2.0-14
+Depends: cpp-12 (= 12.2.0-14), libc6 (>= 2.34), binutils
+Recommends: libc6-dev | libc-dev
+Suggests: gcc-12-doc, gcc-12-locales
+Filename: pool/main/g/gcc-12/gcc-12_12.2.0-14_amd64.deb
+
+Package: libc6-dev
+Version: 2.36-9+deb12u1
+Depends: libc6 (= 2.36-9+deb12u1)
+Filename: pool/main/g/glibc/libc6-dev_2.36-9+deb12u1_amd64.deb
+
+Package: gcc-12-doc
+Version: 12.2.0-1
+Filename: pool/non-free/g/gcc-12-doc/gcc-12-doc_12.2.0-1_all.deb
+
+Package: cpp-12
+Version: 12.2.0-14
+Filename: pool/main/g/gcc-12/cpp-12_12.2.0-14_amd64.deb
+
+Package: libc6
+Version: 2.36-9+deb12u1
+Filename: pool/main/g/glibc/libc6_2.36-9+deb12u1_amd64.deb
+
+Package: binutils-gold
+Version: 2.40-2
+Provides: binutils
+Filename: pool/main/b/binutils/binutils-gold_2.40-2_amd64.deb
+
+Package: mawk
+Version: 1.3.4.20200120-3.1
+Provides: awk
+Filename: pool/main/m/mawk/mawk_1.3.4.20200120-3.1_amd64.deb
+
+Package: gawk
+Version: 1:5.2.1-2
+Conflicts: mawk
+Filename: pool/main/g/gawk/gawk_5.2.1-2_amd64.deb
+";
+
+    const STATUS: &str = "Package: libc6
+Status: install ok installed
+Version: 2.36-9
+
+Package: mawk
+Status: install ok installed
+Version: 1.3.4.20200120-3.1
+Provides: awk
+
+Package: removed
+Status: deinstall ok config-files
+Version: 1.0
+";
+
+    fn solve_names(requested: &[&str]) -> Result<Vec<(String, String)>> {
+        solve_names_weak(requested, WeakDepends::default())
+    }
+
+    fn solve_names_weak(requested: &[&str], weak: WeakDepends) -> Result<Vec<(String, String)>> {
+        let mut index = Index::default();
+        index.import_text(
+            PACKAGES,
+            &Arc::new("http://deb.debian.org/debian".to_string()),
+        )?;
+        let installed = parse_dpkg_status(STATUS)?;
+        let pkgs = solve(&index, &installed, requested, weak)?;
+        Ok(pkgs
+            .iter()
+            .map(|p| (p.name.clone(), p.version.clone()))
+            .collect())
+    }
+
+    #[test]
+    fn test_parse_dependency() -> Result<()> {
+        assert_eq!(
+            Dependency::parse_list("libc6 (>= 2.34), python3:any | python3-minimal (<< 3.12)")?,
+            vec![
+                vec![Dependency {
+                    name: "libc6".to_string(),
+                    constraint: Some((Relation::GreaterOrEqual, "2.34".to_string())),
+                }],
+                vec![
+                    Dependency {
+                        name: "python3".to_string(),
+                        constraint: None,
+                    },
+                    Dependency {
+                        name: "python3-minimal".to_string(),
+                        constraint: Some((Relation::Lower, "3.12".to_string())),
+                    },
+                ],
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_paragraphs() {
+        let paragraphs = paragraphs(
+            "Package: dpkg
+Architecture: amd64
+Depends: libc6 (>= 2.34),
+ tar (>= 1.28-1)
+Description: Debian package management system
+ This package provides the low-level infrastructure.
+ .
+ Second paragraph.
+
+# comment
+
+Package: tar
+",
+        );
+        assert_eq!(paragraphs.len(), 2);
+        assert_eq!(
+            paragraphs[0].get("Depends"),
+            Some("libc6 (>= 2.34),\ntar (>= 1.28-1)")
+        );
+        assert_eq!(
+            paragraphs[0].get("description"),
+            Some("Debian package management system\nThis package provides the low-level infrastructure.\n\nSecond paragraph.")
+        );
+        assert_eq!(paragraphs[1].get("Package"), Some("tar"));
+        assert_eq!(
+            dpkg_architecture(
+                "Package: tar\nArchitecture: arm64\n\nPackage: dpkg\nArchitecture: amd64\n"
+            )
+            .as_deref(),
+            Some("amd64")
+        );
+    }
+
+    #[test]
+    fn test_folded_depends() -> Result<()> {
+        let mut index = Index::default();
+        index.import_text(
+            "Package: a\nVersion: 1\nDepends: b,\n c (>= 2)\nFilename: pool/a.deb\n\nPackage: b\nVersion: 1\nFilename: pool/b.deb\n\nPackage: c\nVersion: 2\nFilename: pool/c.deb\n",
+            &Arc::new("https://deb.debian.org/debian".to_string()),
+        )?;
+        let pkgs = solve(&index, &HashMap::new(), &["a"], WeakDepends::default())?;
+        assert_eq!(
+            pkgs.iter().map(|p| p.url()).collect::<Vec<_>>(),
+            [
+                "https://deb.debian.org/debian/pool/a.deb",
+                "https://deb.debian.org/debian/pool/b.deb",
+                "https://deb.debian.org/debian/pool/c.deb",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_solve() -> Result<()> {
+        assert_eq!(
+            solve_names(&["gcc"])?,
+            vec![
+                ("binutils-gold".to_string(), "2.40-2".to_string()),
+                ("cpp".to_string(), "4:12.2.0-3".to_string()),
+                ("cpp-12".to_string(), "12.2.0-14".to_string()),
+                ("gcc".to_string(), "4:12.2.0-3".to_string()),
+                ("gcc-12".to_string(), "12.2.0-14".to_string()),
+                ("libc6".to_string(), "2.36-9+deb12u1".to_string()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_solve_weak_depends() -> Result<()> {
+        let recommends = WeakDepends {
+            recommends: true,
+            suggests: false,
+        };
+        let names = solve_names_weak(&["gcc-12"], recommends)?;
+        assert!(names.iter().any(|(name, _)| name == "libc6-dev"));
+        assert!(!names.iter().any(|(name, _)| name == "gcc-12-doc"));
+
+        // gcc-12-locales is not available and skipped
+        let all = WeakDepends {
+            recommends: true,
+            suggests: true,
+        };
+        let names = solve_names_weak(&["gcc-12"], all)?;
+        assert!(names.iter().any(|(name, _)| name == "libc6-dev"));
+        assert!(names.iter().any(|(name, _)| name == "gcc-12-doc"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_solve_errors() {
+        assert!(solve_names(&["does-not-exist"]).is_err());
+        assert!(solve_names(&["gcc=1.0"]).is_err());
+        // conflicts with the installed mawk
+        assert!(solve_names(&["gawk"]).is_err());
+    }
+}
@@ -0,0 +1,436 @@
+//! Translate resolution failures of apt, pacman and apk into actionable errors
+use crate::container::{CommandError, Container};
+use crate::errors::*;
+use std::fmt::{self, Write};
+
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    /// The package is not known to any repository
+    NotFound(String),
+    /// The name is known, but there's nothing that could be installed
+    NoCandidate(String),
+    /// The dependencies can't be satisfied, with the lines explaining why
+    Unsatisfiable(Vec<String>),
+}
+
+/// A package name that can be suggested, either a real package or a name that's provided by one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Candidate<'a> {
+    pub name: &'a str,
+    pub provided_by: Option<&'a str>,
+}
+
+impl<'a> Candidate<'a> {
+    pub fn package(name: &'a str) -> Self {
+        Candidate {
+            name,
+            provided_by: None,
+        }
+    }
+
+    pub fn provided(name: &'a str, provided_by: &'a str) -> Self {
+        Candidate {
+            name,
+            provided_by: Some(provided_by),
+        }
+    }
+}
+
+impl fmt::Display for Candidate<'_> {
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        write!(w, "{:?}", self.name)?;
+        if let Some(provided_by) = self.provided_by {
+            write!(w, " (provided by {provided_by:?})")?;
+        }
+        Ok(())
+    }
+}
+
+/// A requested package the builtin solvers couldn't find
+#[derive(Debug)]
+pub struct UnknownPackage(pub String);
+
+impl fmt::Display for UnknownPackage {
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        write!(w, "Unable to locate package: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownPackage {}
+
+/// `E: Unable to locate package foo`
+pub fn parse_apt(stderr: &str) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut broken = Vec::new();
+    for line in stderr.lines() {
+        if let Some(name) = line.strip_prefix("E: Unable to locate package ") {
+            problems.push(Problem::NotFound(name.trim().to_string()));
+        } else if let Some(name) = line
+            .strip_prefix("E: Package '")
+            .and_then(|rest| rest.strip_suffix("' has no installation candidate"))
+        {
+            problems.push(Problem::NoCandidate(name.to_string()));
+        } else if line.contains(" : Depends: ")
+            || line.contains(" : Breaks: ")
+            || line.contains(" : Conflicts: ")
+            || (line.starts_with("  ") && !broken.is_empty())
+        {
+            broken.push(line.trim().to_string());
+        }
+    }
+    if !broken.is_empty() || stderr.contains("E: Unable to correct problems") {
+        problems.push(Problem::Unsatisfiable(broken));
+    }
+    problems
+}
+
+/// `error: target not found: foo`
+pub fn parse_pacman(stderr: &str) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut conflicts = Vec::new();
+    for line in stderr.lines() {
+        if let Some(name) = line.strip_prefix("error: target not found: ") {
+            problems.push(Problem::NotFound(name.trim().to_string()));
+        } else if let Some(msg) = line.strip_prefix(":: ") {
+            if msg.contains(" are in conflict") || msg.starts_with("unable to satisfy dependency") {
+                conflicts.push(msg.trim().to_string());
+            }
+        }
+    }
+    if !conflicts.is_empty() {
+        problems.push(Problem::Unsatisfiable(conflicts));
+    }
+    problems
+}
+
+/// ```text
+/// ERROR: unable to select packages:
+///   foo (no such package):
+///     required by: world[foo]
+/// ```
+pub fn parse_apk(stderr: &str) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut conflicts = Vec::new();
+    let mut in_select = false;
+    for line in stderr.lines() {
+        if line.starts_with("ERROR: unable to select packages") {
+            in_select = true;
+        } else if !line.starts_with(' ') {
+            in_select = false;
+        } else if in_select {
+            if let Some(name) = line.trim().strip_suffix(" (no such package):") {
+                problems.push(Problem::NotFound(name.to_string()));
+            } else if line.contains("conflicts:") || line.contains("breaks:") {
+                conflicts.push(line.trim().to_string());
+            }
+        }
+    }
+    if !conflicts.is_empty() {
+        problems.push(Problem::Unsatisfiable(conflicts));
+    }
+    problems
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur.push((prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+/// Find the known package name that's most likely meant by `name`, real
+/// packages are preferred over provided names
+pub fn suggest<'a>(name: &str, candidates: &[Candidate<'a>]) -> Option<Candidate<'a>> {
+    let prefix = format!("{name}-");
+    candidates
+        .iter()
+        .filter(|candidate| candidate.name != name)
+        .filter_map(|candidate| {
+            let virtual_ = candidate.provided_by.is_some();
+            if candidate.name.starts_with(&prefix) {
+                // prefer the shortest one, e.g. foo-dev over foo-dev-doc
+                Some((0, candidate.name.len(), virtual_, candidate.name, candidate))
+            } else {
+                let distance = levenshtein(name, candidate.name);
+                (distance <= 2).then_some((1, distance, virtual_, candidate.name, candidate))
+            }
+        })
+        .min_by(|a, b| (a.0, a.1, a.2, a.3).cmp(&(b.0, b.1, b.2, b.3)))
+        .map(|(_, _, _, _, candidate)| *candidate)
+}
+
+/// The packages that provide `name`
+pub fn providers<'a>(name: &str, candidates: &[Candidate<'a>]) -> Vec<&'a str> {
+    let mut providers = candidates
+        .iter()
+        .filter(|candidate| candidate.name == name)
+        .filter_map(|candidate| candidate.provided_by)
+        .collect::<Vec<_>>();
+    providers.sort();
+    providers.dedup();
+    providers
+}
+
+fn quoted_list(names: &[&str]) -> String {
+    names
+        .iter()
+        .map(|name| format!("{name:?}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The human readable name of the distribution in the container, e.g. `Debian GNU/Linux 12 (bookworm)`
+async fn pretty_name(container: &Container) -> Option<String> {
+    let buf = container.cat("/etc/os-release").await.ok()?;
+    let buf = String::from_utf8(buf).ok()?;
+    parse_pretty_name(&buf)
+}
+
+/// The `PRETTY_NAME` of an os-release file
+pub fn parse_pretty_name(os_release: &str) -> Option<String> {
+    os_release
+        .lines()
+        .find_map(|line| line.strip_prefix("PRETTY_NAME="))
+        .map(|name| name.trim_matches('"').to_string())
+}
+
+/// Turn the failure of a package manager into an error that says what went
+/// wrong, errors that aren't recognized are returned as-is
+pub async fn explain(
+    container: &Container,
+    system: &str,
+    err: Error,
+    candidates: &[Candidate<'_>],
+) -> Error {
+    let distro = pretty_name(container).await;
+    explain_in(distro.as_deref(), system, err, candidates)
+}
+
+/// Like [`explain`], for resolutions that don't use a container. `distro` is
+/// the human readable name of the distribution, if known.
+pub fn explain_in(
+    distro: Option<&str>,
+    system: &str,
+    err: Error,
+    candidates: &[Candidate<'_>],
+) -> Error {
+    let problems =
+        if let Some(unknown) = err.chain().find_map(|e| e.downcast_ref::<UnknownPackage>()) {
+            vec![Problem::NotFound(unknown.0.clone())]
+        } else if let Some(cmd) = err.chain().find_map(|e| e.downcast_ref::<CommandError>()) {
+            match system {
+                "alpine" => parse_apk(&cmd.stderr),
+                "archlinux" => parse_pacman(&cmd.stderr),
+                "debian" => parse_apt(&cmd.stderr),
+                _ => vec![],
+            }
+        } else {
+            vec![]
+        };
+    if problems.is_empty() {
+        return err;
+    }
+    debug!("Package manager failed to resolve: {err:#}");
+
+    let distro = distro.unwrap_or(system);
+    let mut msg = String::new();
+    for problem in problems {
+        if !msg.is_empty() {
+            msg.push('\n');
+        }
+        match problem {
+            Problem::NotFound(name) => {
+                write!(msg, "Package {name:?} not found in {distro}").ok();
+                let providers = providers(&name, candidates);
+                if !providers.is_empty() {
+                    write!(msg, ", it's provided by {}", quoted_list(&providers)).ok();
+                } else if let Some(suggestion) = suggest(&name, candidates) {
+                    write!(msg, ", did you mean {suggestion}?").ok();
+                }
+            }
+            Problem::NoCandidate(name) => {
+                write!(
+                    msg,
+                    "Package {name:?} has no installation candidate in {distro}"
+                )
+                .ok();
+                let providers = providers(&name, candidates);
+                if providers.is_empty() {
+                    write!(
+                        msg,
+                        ", it may be a virtual package or only referenced by other packages"
+                    )
+                    .ok();
+                } else {
+                    write!(
+                        msg,
+                        ", it's a virtual package provided by {}",
+                        quoted_list(&providers)
+                    )
+                    .ok();
+                }
+            }
+            Problem::Unsatisfiable(reasons) => {
+                write!(msg, "Dependencies can't be satisfied in {distro}").ok();
+                for reason in reasons {
+                    write!(msg, "\n  {reason}").ok();
+                }
+            }
+        }
+    }
+    anyhow!(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_runtime::{self, FakeRuntime};
+
+    #[test]
+    fn test_parse_apt() {
+        let problems = parse_apt(
+            "E: Unable to locate package foo\nE: Package 'bar' has no installation candidate\n",
+        );
+        assert_eq!(
+            problems,
+            [
+                Problem::NotFound("foo".to_string()),
+                Problem::NoCandidate("bar".to_string()),
+            ]
+        );
+
+        let problems = parse_apt(
+            "Some packages could not be installed.
+The following packages have unmet dependencies:
+ libfoo-dev : Depends: libfoo1 (= 2.0-1) but 1.0-1 is to be installed
+              Depends: libbar-dev but it is not going to be installed
+E: Unable to correct problems, you have held broken packages.
+",
+        );
+        assert_eq!(
+            problems,
+            [Problem::Unsatisfiable(vec![
+                "libfoo-dev : Depends: libfoo1 (= 2.0-1) but 1.0-1 is to be installed".to_string(),
+                "Depends: libbar-dev but it is not going to be installed".to_string(),
+            ])]
+        );
+    }
+
+    #[test]
+    fn test_parse_pacman() {
+        let problems = parse_pacman("error: target not found: rustt\n");
+        assert_eq!(problems, [Problem::NotFound("rustt".to_string())]);
+
+        let problems = parse_pacman(
+            "looking for conflicting packages...\n:: iptables-nft-1:1.8.9-1 and iptables-1:1.8.9-1 are in conflict\nerror: unresolvable package conflicts detected\n",
+        );
+        assert_eq!(
+            problems,
+            [Problem::Unsatisfiable(vec![
+                "iptables-nft-1:1.8.9-1 and iptables-1:1.8.9-1 are in conflict".to_string()
+            ])]
+        );
+    }
+
+    #[test]
+    fn test_parse_apk() {
+        let problems = parse_apk(
+            "ERROR: unable to select packages:\n  curll (no such package):\n    required by: world[curll]\n",
+        );
+        assert_eq!(problems, [Problem::NotFound("curll".to_string())]);
+    }
+
+    #[test]
+    fn test_suggest() {
+        let candidates = [
+            Candidate::package("libssl-dev"),
+            Candidate::package("libssl-doc"),
+            Candidate::package("libssl3"),
+            Candidate::package("curl"),
+            Candidate::package("libcurl4"),
+            Candidate::provided("libcurl", "libcurl4"),
+            Candidate::provided("mail-transport-agent", "exim4-daemon-light"),
+            Candidate::provided("mail-transport-agent", "postfix"),
+        ];
+        assert_eq!(
+            suggest("libssl", &candidates),
+            Some(Candidate::package("libssl-dev"))
+        );
+        assert_eq!(
+            suggest("crul", &candidates),
+            Some(Candidate::package("curl"))
+        );
+        assert_eq!(
+            suggest("libcrul", &candidates),
+            Some(Candidate::provided("libcurl", "libcurl4"))
+        );
+        assert_eq!(suggest("python3", &candidates), None);
+        assert_eq!(
+            providers("mail-transport-agent", &candidates),
+            ["exim4-daemon-light", "postfix"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_explain() -> Result<()> {
+        let runtime = FakeRuntime::new();
+        runtime.fail_with(&["apt-get"], "E: Unable to locate package crul\n");
+        runtime.reply(
+            &[&format!("{}:/etc/os-release", fake_runtime::CONTAINER_ID)],
+            fake_runtime::tar_file(
+                "os-release",
+                b"PRETTY_NAME=\"Debian GNU/Linux 12 (bookworm)\"\nNAME=\"Debian GNU/Linux\"\n",
+            ),
+        );
+        let container = Container {
+            id: fake_runtime::CONTAINER_ID.to_string(),
+        };
+        let err = runtime
+            .run(async {
+                let err = container
+                    .exec(&["apt-get", "install", "crul"], Default::default())
+                    .await
+                    .unwrap_err();
+                let candidates = [Candidate::package("curl"), Candidate::package("wget")];
+                explain(&container, "debian", err, &candidates).await
+            })
+            .await;
+        assert_eq!(
+            err.to_string(),
+            "Package \"crul\" not found in Debian GNU/Linux 12 (bookworm), did you mean \"curl\"?"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_explain_unknown_package() -> Result<()> {
+        let runtime = FakeRuntime::new();
+        let container = Container {
+            id: fake_runtime::CONTAINER_ID.to_string(),
+        };
+        let candidates = [
+            Candidate::package("postfix"),
+            Candidate::provided("mail-transport-agent", "postfix"),
+        ];
+        let err = runtime
+            .run(explain(
+                &container,
+                "debian",
+                UnknownPackage("mail-transport-agent".to_string()).into(),
+                &candidates,
+            ))
+            .await;
+        assert_eq!(
+            err.to_string(),
+            "Package \"mail-transport-agent\" not found in debian, it's provided by \"postfix\""
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+use crate::args;
+use crate::container::{self, Container};
+use crate::errors::*;
+use crate::install::flatpak::{self, DEFAULT_REMOTE};
+use crate::lockfile::{ContainerLock, PackageLock};
+use crate::manifest::PackagesManifest;
+use crate::resolver::provenance::{self, Provenance};
+use std::path::Path;
+
+/// The ref (without `runtime/`) and commit from the output of `flatpak remote-info`
+pub fn parse_remote_info(buf: &str) -> Result<(String, String)> {
+    let mut flatpak_ref = None;
+    let mut commit = None;
+    for line in buf.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        match key.trim() {
+            "Ref" => flatpak_ref = Some(value.trim()),
+            "Commit" => commit = Some(value.trim()),
+            _ => (),
+        }
+    }
+    let flatpak_ref = flatpak_ref.context("Missing ref in flatpak remote-info output")?;
+    let commit = commit.context("Missing commit in flatpak remote-info output")?;
+
+    let name = flatpak_ref
+        .strip_prefix("runtime/")
+        .with_context(|| anyhow!("Only runtimes are supported, not {flatpak_ref:?}"))?;
+    if commit.len() != 64 || !commit.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("Unexpected flatpak commit: {commit:?}");
+    }
+    Ok((name.to_string(), commit.to_string()))
+}
+
+pub async fn resolve_dependencies(
+    container: &Container,
+    manifest: &PackagesManifest,
+    dependencies: &mut Vec<PackageLock>,
+    provenance: &mut Provenance,
+) -> Result<()> {
+    let remote = manifest.remote.as_deref().unwrap_or(DEFAULT_REMOTE);
+    info!("Adding flatpak remote {remote:?}...");
+    container
+        .exec(&flatpak::remote_add(remote), container::Exec::default())
+        .await?;
+    provenance
+        .detect_tool(
+            container,
+            "flatpak",
+            &["flatpak", "--version"],
+            provenance::parse_flatpak_version,
+        )
+        .await;
+
+    info!("Resolving runtimes...");
+    let remote_name = flatpak::remote_name(remote);
+    for dependency in &manifest.dependencies {
+        let buf = container
+            .exec(
+                &[
+                    "flatpak",
+                    "remote-info",
+                    "--system",
+                    "--",
+                    &remote_name,
+                    dependency,
+                ],
+                container::Exec {
+                    capture_stdout: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .with_context(|| anyhow!("Failed to resolve flatpak runtime {dependency:?}"))?;
+        let buf = String::from_utf8(buf).context("Failed to decode flatpak output as utf8")?;
+        let (name, commit) = parse_remote_info(&buf)?;
+        debug!("Detected runtime: {name:?} => {commit:?}");
+
+        dependencies.push(PackageLock {
+            name,
+            version: commit.clone(),
+            system: "flatpak".to_string(),
+            url: remote.to_string(),
+            fallback_urls: vec![],
+            provides: vec![dependency.clone()],
+            sha256: String::new(),
+            commit: Some(commit),
+            signatures: vec![],
+            installed: false,
+            depends: vec![],
+            size: None,
+        });
+    }
+
+    Ok(())
+}
+
+pub async fn resolve(
+    update: &args::Update,
+    manifest: &PackagesManifest,
+    container: &ContainerLock,
+    project: &Path,
+    dependencies: &mut Vec<PackageLock>,
+    provenance: &mut Provenance,
+) -> Result<()> {
+    let image = container::prepare_image(container, project).await?;
+    let container = Container::create(
+        &image,
+        container::Config {
+            mounts: &[],
+            expose_fuse: false,
+            lockfile_digest: None,
+            prepared: false,
+            platform: None,
+            podman_args: &[],
+            read_only_root: false,
+            init: container.init,
+        },
+    )
+    .await?;
+    container
+        .run(
+            resolve_dependencies(&container, manifest, dependencies, provenance),
+            update.keep,
+        )
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_remote_info() -> Result<()> {
+        let commit = "d7a6c48a3b3de6ea0a9b1dbd8e5e9b0a2f58e37f5b2b0c7d9ae1a5e2f0c14f2d";
+        let buf = format!(
+            "
+        Freedesktop SDK - Runtime for building applications
+
+          ID: org.freedesktop.Sdk
+         Ref: runtime/org.freedesktop.Sdk/x86_64/23.08
+        Arch: x86_64
+      Branch: 23.08
+     Version: 23.08.24
+     License: GPL-2.0 LGPL-2.0
+ Collection: org.flathub.Stable
+    Download: 244.1 MB
+   Installed: 932.5 MB
+
+      Commit: {commit}
+      Parent: 5e1ed7e5a94b2a61b0c3c2f3b5a7e6c0d0dd9f0a6fa1e37dd8a4b9ff1d06a1c4
+     Subject: Export org.freedesktop.Sdk
+        Date: 2024-08-28 12:33:08 +0000
+"
+        );
+        let (name, parsed) = parse_remote_info(&buf)?;
+        assert_eq!(name, "org.freedesktop.Sdk/x86_64/23.08");
+        assert_eq!(parsed, commit);
+
+        let app = buf.replace("runtime/", "app/");
+        assert!(parse_remote_info(&app).is_err());
+        Ok(())
+    }
+}
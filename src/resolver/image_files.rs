@@ -0,0 +1,442 @@
+//! Read files from the layers of a container image on the host, so resolvers
+//! can use the configuration of the image without creating a container.
+
+use crate::container::{self, ExecConfig};
+use crate::errors::*;
+use crate::limits;
+use crate::pkgs;
+use crate::utils;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path};
+
+/// Symlinks that are followed when resolving a path, like `MAXSYMLINKS` of Linux
+const MAX_SYMLINKS: usize = 40;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Entry {
+    File(Vec<u8>),
+    Symlink(String),
+    Dir,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ArchiveManifest {
+    layers: Vec<String>,
+}
+
+/// The files of an image below some prefixes, with the layers applied in order
+#[derive(Debug, Default)]
+pub struct ImageFiles {
+    entries: BTreeMap<String, Entry>,
+}
+
+/// Turn a path of a layer into an absolute path without `.` and `..`
+fn normalize(path: &Path) -> String {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy().into_owned()),
+            Component::ParentDir => {
+                parts.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => (),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+fn is_below(path: &str, dir: &str) -> bool {
+    dir == "/"
+        || path
+            .strip_prefix(dir)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+impl ImageFiles {
+    /// Export the image with `podman image save` and read the files below `prefixes`,
+    /// symlinks are always recorded so paths can be resolved like in a container
+    pub async fn read(image: &str, prefixes: &[&str]) -> Result<Self> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("image.tar");
+        debug!("Exporting image {image:?} to read its files");
+        container::podman(
+            [
+                OsStr::new("image"),
+                OsStr::new("save"),
+                OsStr::new("--format=docker-archive"),
+                OsStr::new("--output"),
+                path.as_os_str(),
+                OsStr::new("--"),
+                OsStr::new(image),
+            ],
+            &ExecConfig::default(),
+        )
+        .await
+        .with_context(|| anyhow!("Failed to export image: {image:?}"))?;
+
+        let prefixes = prefixes.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        utils::spawn_blocking(move |_| {
+            let file = File::open(&path)
+                .with_context(|| anyhow!("Failed to open exported image: {path:?}"))?;
+            let prefixes = prefixes.iter().map(String::as_str).collect::<Vec<_>>();
+            Self::from_archive(file, &prefixes)
+        })
+        .await
+    }
+
+    /// Read a `docker-archive` tar, its layers are tars of their own
+    pub fn from_archive<R: Read + Seek>(mut reader: R, prefixes: &[&str]) -> Result<Self> {
+        let mut manifest = None;
+        let mut layers = BTreeMap::new();
+        {
+            let mut tar = tar::Archive::new(&mut reader);
+            for (idx, entry) in tar.entries()?.enumerate() {
+                limits::INDEX.check_entry(idx)?;
+                let mut entry = entry?;
+                let path = entry.path()?.to_string_lossy().into_owned();
+                if path == "manifest.json" {
+                    let mut buf = Vec::new();
+                    entry.read_to_end(&mut buf)?;
+                    let mut list = serde_json::from_slice::<Vec<ArchiveManifest>>(&buf)
+                        .context("Failed to parse manifest.json of image archive")?;
+                    if list.len() != 1 {
+                        bail!(
+                            "Expected exactly one image in archive, found {}",
+                            list.len()
+                        );
+                    }
+                    manifest = list.pop();
+                } else {
+                    layers.insert(path, (entry.raw_file_position(), entry.size()));
+                }
+            }
+        }
+        let manifest = manifest.context("Image archive is missing manifest.json")?;
+
+        let mut files = Self::default();
+        for layer in &manifest.layers {
+            let (position, size) = *layers
+                .get(layer)
+                .with_context(|| anyhow!("Image archive is missing layer: {layer:?}"))?;
+            trace!("Reading image layer: {layer:?}");
+            reader.seek(SeekFrom::Start(position))?;
+            let data = pkgs::archlinux::decompress((&mut reader).take(size), limits::INDEX)?;
+            files
+                .apply_layer(data, prefixes)
+                .with_context(|| anyhow!("Failed to read image layer: {layer:?}"))?;
+        }
+        Ok(files)
+    }
+
+    /// Apply the changes of a layer, including whiteouts of the files of lower layers
+    fn apply_layer<R: Read>(&mut self, reader: R, prefixes: &[&str]) -> Result<()> {
+        let wanted = |path: &str| prefixes.iter().any(|prefix| is_below(path, prefix));
+        let mut added = HashSet::new();
+        let mut tar = tar::Archive::new(reader);
+        for (idx, entry) in tar.entries()?.enumerate() {
+            limits::INDEX.check_entry(idx)?;
+            let mut entry = entry?;
+            let path = normalize(&entry.path()?);
+            let (parent, name) = path.rsplit_once('/').unwrap_or(("", &path));
+            let parent = if parent.is_empty() { "/" } else { parent };
+
+            if name == ".wh..wh..opq" {
+                // the directory replaces the one of the lower layers
+                self.remove_below(parent, |p| added.contains(p));
+                continue;
+            }
+            if let Some(name) = name.strip_prefix(".wh.") {
+                let removed = format!("{}/{name}", parent.trim_end_matches('/'));
+                self.entries.remove(&removed);
+                self.remove_below(&removed, |_| false);
+                continue;
+            }
+
+            let value = match entry.header().entry_type() {
+                tar::EntryType::Directory => Entry::Dir,
+                tar::EntryType::Symlink => {
+                    let target = entry
+                        .link_name()?
+                        .context("Symlink in image layer has no target")?;
+                    Entry::Symlink(target.to_string_lossy().into_owned())
+                }
+                tar::EntryType::Link if wanted(&path) => {
+                    let target = entry
+                        .link_name()?
+                        .context("Hardlink in image layer has no target")?;
+                    match self.entries.get(&normalize(&target)) {
+                        Some(Entry::File(data)) => Entry::File(data.clone()),
+                        _ => continue,
+                    }
+                }
+                tar::EntryType::Regular | tar::EntryType::Continuous if wanted(&path) => {
+                    let mut data = Vec::new();
+                    entry.read_to_end(&mut data)?;
+                    Entry::File(data)
+                }
+                _ => continue,
+            };
+            if value != Entry::Dir {
+                // a file or symlink replaces a directory of a lower layer
+                self.remove_below(&path, |_| false);
+            }
+            added.insert(path.clone());
+            self.entries.insert(path, value);
+        }
+        Ok(())
+    }
+
+    /// Remove the entries below `dir` (but not `dir` itself), unless `keep` returns true
+    fn remove_below<F: Fn(&str) -> bool>(&mut self, dir: &str, keep: F) {
+        let prefix = format!("{}/", dir.trim_end_matches('/'));
+        let below = self
+            .entries
+            .range(prefix.clone()..)
+            .map(|(path, _)| path)
+            .take_while(|path| path.starts_with(&prefix))
+            .filter(|path| !keep(path))
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in below {
+            self.entries.remove(&path);
+        }
+    }
+
+    /// Resolve symlinks in all components of an absolute path
+    fn resolve(&self, path: &str) -> Result<String> {
+        let mut pending = path.to_string();
+        let mut followed = 0;
+        'restart: loop {
+            let mut resolved = String::new();
+            let components = pending
+                .split('/')
+                .filter(|c| !c.is_empty())
+                .collect::<Vec<_>>();
+            for (idx, component) in components.iter().enumerate() {
+                match *component {
+                    "." => continue,
+                    ".." => {
+                        let end = resolved.rfind('/').unwrap_or(0);
+                        resolved.truncate(end);
+                        continue;
+                    }
+                    _ => (),
+                }
+                let current = format!("{resolved}/{component}");
+                if let Some(Entry::Symlink(target)) = self.entries.get(&current) {
+                    followed += 1;
+                    if followed > MAX_SYMLINKS {
+                        bail!("Too many levels of symbolic links in image: {path:?}");
+                    }
+                    let rest = components[idx + 1..].join("/");
+                    let base = if target.starts_with('/') {
+                        String::new()
+                    } else {
+                        resolved.clone()
+                    };
+                    pending = format!("{base}/{target}/{rest}");
+                    continue 'restart;
+                }
+                resolved = current;
+            }
+            return Ok(if resolved.is_empty() {
+                "/".to_string()
+            } else {
+                resolved
+            });
+        }
+    }
+
+    /// The content of a regular file, symlinks are followed
+    pub fn file(&self, path: &str) -> Result<Option<&[u8]>> {
+        let resolved = self.resolve(path)?;
+        match self.entries.get(&resolved) {
+            Some(Entry::File(data)) => Ok(Some(data)),
+            Some(_) => bail!("Path in image is not a regular file: {path:?}"),
+            None => Ok(None),
+        }
+    }
+
+    /// If the path is a directory, symlinks are followed
+    pub fn is_dir(&self, path: &str) -> Result<bool> {
+        let resolved = self.resolve(path)?;
+        Ok(matches!(self.entries.get(&resolved), Some(Entry::Dir)) || resolved == "/")
+    }
+
+    /// Like [`ImageFiles::file`], but the file needs to exist and be valid utf-8
+    pub fn text(&self, path: &str) -> Result<String> {
+        let data = self
+            .file(path)?
+            .with_context(|| anyhow!("File does not exist in image: {path:?}"))?;
+        String::from_utf8(data.to_vec()).with_context(|| anyhow!("File is not utf-8: {path:?}"))
+    }
+
+    /// The regular files below a directory as (path, content), sorted by path.
+    /// The paths are relative to the resolved directory, prefixed with `dir`.
+    pub fn files_in(&self, dir: &str) -> Result<Vec<(String, &[u8])>> {
+        let resolved = self.resolve(dir)?;
+        let dir = dir.trim_end_matches('/');
+        Ok(self
+            .entries
+            .iter()
+            .filter(|(path, _)| is_below(path, &resolved) && **path != resolved)
+            .filter_map(|(path, entry)| match entry {
+                Entry::File(data) => Some((
+                    format!("{dir}{}", &path[resolved.trim_end_matches('/').len()..]),
+                    &data[..],
+                )),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// The regular files below a directory as tar, like `podman cp` creates it
+    /// from a container (the entries start with the name of the directory)
+    pub fn tar(&self, dir: &str) -> Result<Vec<u8>> {
+        let dir = dir.trim_end_matches('/');
+        let (parent, _) = dir.rsplit_once('/').unwrap_or(("", dir));
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, data) in self.files_in(dir)? {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            let name = path[parent.len()..].trim_start_matches('/');
+            builder.append_data(&mut header, name, data)?;
+        }
+        builder.into_inner().map_err(io::Error::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    enum Item<'a> {
+        File(&'a str, &'a [u8]),
+        Symlink(&'a str, &'a str),
+        Dir(&'a str),
+    }
+
+    fn tar(items: &[Item]) -> Result<Vec<u8>> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for item in items {
+            let mut header = tar::Header::new_gnu();
+            match item {
+                Item::File(path, data) => {
+                    header.set_size(data.len() as u64);
+                    header.set_mode(0o644);
+                    header.set_cksum();
+                    builder.append_data(&mut header, path, *data)?;
+                }
+                Item::Symlink(path, target) => {
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    header.set_size(0);
+                    builder.append_link(&mut header, path, target)?;
+                }
+                Item::Dir(path) => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_size(0);
+                    header.set_mode(0o755);
+                    header.set_cksum();
+                    builder.append_data(&mut header, path, &[][..])?;
+                }
+            }
+        }
+        Ok(builder.into_inner()?)
+    }
+
+    fn archive(layers: &[Vec<u8>]) -> Result<Vec<u8>> {
+        let names = (0..layers.len())
+            .map(|idx| format!("{idx:064x}.tar"))
+            .collect::<Vec<_>>();
+        let manifest = serde_json::json!([{
+            "Config": "config.json",
+            "RepoTags": [],
+            "Layers": names,
+        }])
+        .to_string();
+        let mut items = names
+            .iter()
+            .zip(layers)
+            .map(|(name, layer)| Item::File(name.as_str(), layer.as_slice()))
+            .collect::<Vec<_>>();
+        items.push(Item::File("manifest.json", manifest.as_bytes()));
+        tar(&items)
+    }
+
+    #[test]
+    fn test_read_layers() -> Result<()> {
+        let base = tar(&[
+            Item::Dir("etc/"),
+            Item::Symlink("etc/os-release", "../usr/lib/os-release"),
+            Item::File("usr/lib/os-release", b"ID=debian\n"),
+            Item::File(
+                "etc/apt/sources.list",
+                b"deb http://deb.debian.org/debian bookworm main\n",
+            ),
+            Item::File(
+                "etc/apt/sources.list.d/old.list",
+                b"deb http://example.com/ old main\n",
+            ),
+            Item::File("etc/apt/trusted.gpg.d/old.gpg", b"old"),
+            Item::File("usr/share/doc/ignored", b"ignored"),
+            Item::Symlink("lib", "usr/lib"),
+        ])?;
+        let update = tar(&[
+            Item::File("etc/apt/sources.list.d/.wh.old.list", b""),
+            Item::File("etc/apt/trusted.gpg.d/.wh..wh..opq", b""),
+            Item::File("etc/apt/trusted.gpg.d/new.gpg", b"new"),
+            Item::File(
+                "usr/lib/os-release",
+                b"ID=debian\nVERSION_CODENAME=bookworm\n",
+            ),
+        ])?;
+        let files = ImageFiles::from_archive(
+            Cursor::new(archive(&[base, update])?),
+            &["/etc", "/usr/lib/os-release"],
+        )?;
+
+        assert_eq!(
+            files.text("/etc/os-release")?,
+            "ID=debian\nVERSION_CODENAME=bookworm\n"
+        );
+        assert_eq!(
+            files.text("/lib/os-release")?,
+            "ID=debian\nVERSION_CODENAME=bookworm\n"
+        );
+        assert_eq!(files.file("/usr/share/doc/ignored")?, None);
+        assert_eq!(files.file("/etc/apt/sources.list.d/old.list")?, None);
+        assert_eq!(
+            files
+                .files_in("/etc/apt/trusted.gpg.d")?
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect::<Vec<_>>(),
+            ["/etc/apt/trusted.gpg.d/new.gpg"]
+        );
+
+        let tar = files.tar("/etc/apt")?;
+        let mut names = tar::Archive::new(&tar[..])
+            .entries()?
+            .map(|entry| Ok(entry?.path()?.to_string_lossy().into_owned()))
+            .collect::<Result<Vec<_>>>()?;
+        names.sort();
+        assert_eq!(names, ["apt/sources.list", "apt/trusted.gpg.d/new.gpg"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_symlink_loop() -> Result<()> {
+        let layer = tar(&[Item::Symlink("a", "b"), Item::Symlink("b", "a")])?;
+        let files = ImageFiles::from_archive(Cursor::new(archive(&[layer])?), &["/"])?;
+        assert!(files.file("/a").is_err());
+        Ok(())
+    }
+}
@@ -1,37 +1,300 @@
 pub mod alpine;
 pub mod archlinux;
+pub mod archlinux_solver;
+pub mod conda;
 pub mod container;
 pub mod debian;
+pub mod debian_release;
+pub mod debian_solver;
+pub mod debian_sources;
+pub mod diagnose;
+pub mod flatpak;
+pub mod image_files;
+pub mod openwrt;
+pub mod pacman_conf;
+pub mod parsers;
+pub mod provenance;
+pub mod snap;
+pub mod snapshot;
 
 use crate::args;
 use crate::errors::*;
-use crate::lockfile::Lockfile;
-use crate::manifest::Manifest;
+use crate::lockfile::{ApkKeyLock, ContainerLock, Lockfile, PackageLock, ProfileLock};
+use crate::manifest::{Manifest, PackagesManifest, Solver};
+use crate::resolver::provenance::Provenance;
+use crate::version;
+use std::collections::BTreeMap;
+use std::path::Path;
 
-pub async fn resolve(args: &args::Update, manifest: &Manifest) -> Result<Lockfile> {
-    let container = container::resolve(args, manifest).await?;
+/// Resolve the manifest into a lockfile, also returns how the pins were computed.
+/// Paths of the manifest are relative to `project`, the directory of the manifest.
+pub async fn resolve(
+    args: &args::Update,
+    manifest: &Manifest,
+    project: &Path,
+) -> Result<(Lockfile, Provenance)> {
+    let container = container::resolve(args, manifest, project).await?;
 
     let mut dependencies = Vec::new();
+    let mut apk_keys = Vec::new();
+    let mut provenance = Provenance::default();
+    let mut profiles = BTreeMap::new();
+    let mut install_order = Vec::new();
     if let Some(packages) = &manifest.packages {
-        match packages.system.as_str() {
-            "alpine" => alpine::resolve(args, packages, &container, &mut dependencies).await?,
-            "archlinux" => {
-                archlinux::resolve(args, packages, &container, &mut dependencies).await?
+        if packages.solver == Solver::Simulate && packages.system != "alpine" {
+            bail!("The simulate solver is only supported for alpine");
+        }
+        if packages.solver == Solver::Builtin
+            && !["archlinux", "debian"].contains(&packages.system.as_str())
+        {
+            bail!("The builtin solver is only supported for archlinux and debian");
+        }
+        if (packages.install_recommends || packages.install_suggests) && packages.system != "debian"
+        {
+            bail!("Installing recommended or suggested packages is only supported for debian");
+        }
+        if (!packages.assume_installed.is_empty() || !packages.ignore_groups.is_empty())
+            && packages.system != "archlinux"
+        {
+            bail!("assume_installed and ignore_groups are only supported for archlinux");
+        }
+        if (packages.configures_suites() || packages.point_release.is_some())
+            && packages.system != "debian"
+        {
+            bail!("security, updates and point_release are only supported for debian");
+        }
+        if packages.debug_symbols && !["archlinux", "debian"].contains(&packages.system.as_str()) {
+            bail!("Debug symbols are only supported for archlinux and debian");
+        }
+        if packages.snapshot.is_some() && packages.system != "alpine" {
+            bail!("Snapshot mirrors are only supported for alpine");
+        }
+        if packages.remote.is_some() && packages.system != "flatpak" {
+            bail!("Remotes are only supported for flatpak");
+        }
+        if !packages.feeds.is_empty() && packages.system != "openwrt" {
+            bail!("Feeds are only supported for openwrt");
+        }
+        if (packages.environment.is_some() || !packages.channels.is_empty())
+            && packages.system != "conda"
+        {
+            bail!("environment and channels are only supported for conda");
+        }
+        if packages.faketime
+            && ["conda", "flatpak", "openwrt", "snap"].contains(&packages.system.as_str())
+        {
+            bail!("faketime is not supported for {}", packages.system);
+        }
+        if !packages.install_order.is_empty()
+            && !["alpine", "archlinux", "debian"].contains(&packages.system.as_str())
+        {
+            bail!("install_order is only supported for alpine, archlinux and debian");
+        }
+        if !packages.preferences.is_empty() {
+            if packages.system != "debian" {
+                bail!("apt preferences are only supported for debian");
+            }
+            if packages.solver == Solver::Builtin {
+                bail!("apt preferences are not supported by the builtin solver");
+            }
+        }
+
+        let packages = &PackagesManifest {
+            dependencies: packages.all_dependencies(),
+            ..packages.clone()
+        };
+        resolve_packages(
+            args,
+            packages,
+            &container,
+            project,
+            &mut dependencies,
+            &mut apk_keys,
+            &mut provenance,
+        )
+        .await?;
+
+        // each profile is resolved together with the environment in the same
+        // container, only the packages that are added on top are recorded
+        for (name, profile) in &manifest.profiles {
+            info!("Resolving profile {name:?}...");
+            let mut packages = packages.clone();
+            packages
+                .dependencies
+                .extend(profile.dependencies.iter().cloned());
+            let mut resolved = Vec::new();
+            let mut profile_keys = Vec::new();
+            resolve_packages(
+                args,
+                &packages,
+                &container,
+                project,
+                &mut resolved,
+                &mut profile_keys,
+                &mut Provenance::default(),
+            )
+            .await?;
+            let mut added = profile_packages(&dependencies, resolved)
+                .with_context(|| anyhow!("Failed to resolve profile {name:?}"))?;
+            sort_packages(&mut added);
+            profiles.insert(name.clone(), ProfileLock { packages: added });
+            for key in profile_keys {
+                if !apk_keys.contains(&key) {
+                    apk_keys.push(key);
+                }
+            }
+        }
+
+        for name in &packages.install_order {
+            let resolved = dependencies
+                .iter()
+                .chain(profiles.values().flat_map(|p: &ProfileLock| &p.packages))
+                .any(|pkg| pkg.name == *name);
+            if !resolved {
+                warn!("Package in install_order is not part of the environment: {name:?}");
             }
-            "debian" => debian::resolve(args, packages, &container, &mut dependencies).await?,
-            system => bail!("Unknown package system: {system:?}"),
         }
+        install_order.clone_from(&packages.install_order);
     }
 
-    dependencies.sort_by(|a, b| {
+    sort_packages(&mut dependencies);
+
+    let lockfile = Lockfile {
+        min_repro_env_version: None,
+        install_order,
+        container,
+        meta: None,
+        policy: BTreeMap::new(),
+        keys: vec![],
+        apk_keys,
+        packages: dependencies,
+        profiles,
+        files: vec![],
+        git: vec![],
+        toolchains: vec![],
+        maven: vec![],
+        gems: vec![],
+        composer: vec![],
+        cpan: vec![],
+        haskell: None,
+        texlive: None,
+    };
+    Ok((lockfile, provenance))
+}
+
+async fn resolve_packages(
+    args: &args::Update,
+    packages: &PackagesManifest,
+    container: &ContainerLock,
+    project: &Path,
+    dependencies: &mut Vec<PackageLock>,
+    apk_keys: &mut Vec<ApkKeyLock>,
+    provenance: &mut Provenance,
+) -> Result<()> {
+    match packages.system.as_str() {
+        "alpine" => {
+            alpine::resolve(
+                args,
+                packages,
+                container,
+                project,
+                dependencies,
+                apk_keys,
+                provenance,
+            )
+            .await
+        }
+        "archlinux" => {
+            archlinux::resolve(args, packages, container, project, dependencies, provenance).await
+        }
+        "conda" => {
+            conda::resolve(args, packages, container, project, dependencies, provenance).await
+        }
+        "debian" => {
+            debian::resolve(args, packages, container, project, dependencies, provenance).await
+        }
+        "flatpak" => {
+            flatpak::resolve(args, packages, container, project, dependencies, provenance).await
+        }
+        "openwrt" => openwrt::resolve(packages, dependencies).await,
+        "snap" => snap::resolve(packages, dependencies).await,
+        system => bail!("Unknown package system: {system:?}"),
+    }
+}
+
+/// The packages of a profile that are not already part of the environment.
+/// The profile can't replace a package of the environment with a different build.
+fn profile_packages(
+    environment: &[PackageLock],
+    resolved: Vec<PackageLock>,
+) -> Result<Vec<PackageLock>> {
+    let mut added = Vec::new();
+    for package in resolved {
+        if let Some(existing) = environment
+            .iter()
+            .find(|p| p.name == package.name && p.system == package.system)
+        {
+            if existing.sha256 != package.sha256 {
+                bail!(
+                    "Profile needs {} {}, but the environment has {}",
+                    package.name,
+                    package.version,
+                    existing.version
+                );
+            }
+        } else {
+            added.push(package);
+        }
+    }
+    Ok(added)
+}
+
+fn sort_packages(packages: &mut [PackageLock]) {
+    packages.sort_by(|a, b| {
         a.name
             .cmp(&b.name)
-            .then(a.version.cmp(&b.version))
+            .then_with(|| version::compare(&a.system, &a.version, &b.version))
             .then(a.system.cmp(&b.system))
     });
+}
 
-    Ok(Lockfile {
-        container,
-        packages: dependencies,
-    })
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pkg(name: &str, version: &str, sha256: &str) -> PackageLock {
+        PackageLock {
+            name: name.to_string(),
+            version: version.to_string(),
+            system: "debian".to_string(),
+            url: format!("https://example.com/{name}_{version}.deb"),
+            sha256: sha256.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_profile_packages() -> Result<()> {
+        let environment = [
+            pkg("binutils", "2.40-2", "aa"),
+            pkg("gcc", "12.2.0-14", "bb"),
+        ];
+        let added = profile_packages(
+            &environment,
+            vec![
+                pkg("binutils", "2.40-2", "aa"),
+                pkg("binutils-dbgsym", "2.40-2", "cc"),
+                pkg("gcc", "12.2.0-14", "bb"),
+            ],
+        )?;
+        assert_eq!(added, [pkg("binutils-dbgsym", "2.40-2", "cc")]);
+
+        let err =
+            profile_packages(&environment, vec![pkg("binutils", "2.41-1", "dd")]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Profile needs binutils 2.41-1, but the environment has 2.40-2"
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,274 @@
+//! Resolve opkg packages from the `Packages.gz` index of each feed, the
+//! packages are meant for the SDK or ImageBuilder, not the container itself
+use crate::errors::*;
+use crate::http;
+use crate::limits;
+use crate::lockfile::PackageLock;
+use crate::manifest::PackagesManifest;
+use crate::pkgs;
+use crate::resolver::diagnose::UnknownPackage;
+use flate2::read::GzDecoder;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Read;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedPackage {
+    pub name: String,
+    pub version: String,
+    pub depends: Vec<String>,
+    pub provides: Vec<String>,
+    pub filename: String,
+    pub size: Option<u64>,
+    pub sha256: String,
+    pub feed: usize,
+}
+
+/// The packages of all feeds, earlier feeds take precedence
+#[derive(Debug, Default)]
+pub struct Index {
+    feeds: Vec<String>,
+    pkgs: HashMap<String, FeedPackage>,
+    provides: HashMap<String, String>,
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|entry| entry.trim().to_string())
+        .filter(|entry| !entry.is_empty())
+        .collect()
+}
+
+impl Index {
+    /// Parse the (decompressed) `Packages` index of a feed
+    pub fn import(&mut self, feed: &str, buf: &str) -> Result<()> {
+        let feed_idx = self.feeds.len();
+        self.feeds.push(feed.trim_end_matches('/').to_string());
+
+        for paragraph in buf.split("\n\n").filter(|p| !p.trim().is_empty()) {
+            let mut fields = HashMap::new();
+            for line in paragraph.lines() {
+                if let Some((key, value)) = line.split_once(": ") {
+                    fields.insert(key, value.trim());
+                }
+            }
+            let field = |key| {
+                fields
+                    .get(key)
+                    .map(|value| value.to_string())
+                    .with_context(|| {
+                        anyhow!("Package index entry is missing {key:?}: {paragraph:?}")
+                    })
+            };
+            let size = match fields.get("Size") {
+                Some(size) => Some(
+                    size.parse()
+                        .with_context(|| anyhow!("Invalid package size: {size:?}"))?,
+                ),
+                None => None,
+            };
+            let pkg = FeedPackage {
+                name: field("Package")?,
+                version: field("Version")?,
+                depends: fields
+                    .get("Depends")
+                    .map(|v| split_list(v))
+                    .unwrap_or_default(),
+                provides: fields
+                    .get("Provides")
+                    .map(|v| split_list(v))
+                    .unwrap_or_default(),
+                filename: field("Filename")?,
+                size,
+                sha256: field("SHA256sum")?,
+                feed: feed_idx,
+            };
+            if self.pkgs.contains_key(&pkg.name) {
+                continue;
+            }
+            for provide in &pkg.provides {
+                for name in pkgs::dependency_names("openwrt", provide) {
+                    self.provides
+                        .entry(name.to_string())
+                        .or_insert_with(|| pkg.name.clone());
+                }
+            }
+            self.pkgs.insert(pkg.name.clone(), pkg);
+        }
+        Ok(())
+    }
+
+    /// The package for a name, or the one that provides it
+    fn lookup(&self, name: &str) -> Option<&FeedPackage> {
+        self.pkgs
+            .get(name)
+            .or_else(|| self.pkgs.get(self.provides.get(name)?))
+    }
+
+    /// The requested packages and everything they depend on, for alternatives
+    /// (`a | b`) the first one that's in a feed is used
+    pub fn resolve(&self, requested: &[String]) -> Result<Vec<&FeedPackage>> {
+        let mut queue = requested.iter().cloned().collect::<VecDeque<_>>();
+        let mut seen = HashSet::new();
+        let mut resolved = Vec::new();
+        while let Some(dep) = queue.pop_front() {
+            let names = pkgs::dependency_names("openwrt", &dep);
+            let pkg = names
+                .iter()
+                .find_map(|name| self.lookup(name))
+                .ok_or_else(|| UnknownPackage(dep.clone()))?;
+            if !seen.insert(&pkg.name) {
+                continue;
+            }
+            queue.extend(pkg.depends.iter().cloned());
+            resolved.push(pkg);
+        }
+        Ok(resolved)
+    }
+
+    pub fn url(&self, pkg: &FeedPackage) -> String {
+        format!("{}/{}", self.feeds[pkg.feed], pkg.filename)
+    }
+}
+
+pub async fn resolve(
+    manifest: &PackagesManifest,
+    dependencies: &mut Vec<PackageLock>,
+) -> Result<()> {
+    if manifest.feeds.is_empty() {
+        bail!("The openwrt system needs at least one feed");
+    }
+    let client = http::Client::new()?;
+
+    info!("Downloading package indexes...");
+    let mut index = Index::default();
+    for feed in &manifest.feeds {
+        let url = format!("{}/Packages.gz", feed.trim_end_matches('/'));
+        let buf = client.fetch_cached(&url).await?;
+        let mut packages = String::new();
+        limits::INDEX
+            .wrap(GzDecoder::new(&buf[..]))
+            .read_to_string(&mut packages)
+            .with_context(|| anyhow!("Failed to decompress package index: {url:?}"))?;
+        index
+            .import(feed, &packages)
+            .with_context(|| anyhow!("Failed to parse package index: {url:?}"))?;
+    }
+
+    info!("Resolving dependencies...");
+    let requested = manifest.dependencies.iter().cloned().collect::<Vec<_>>();
+    for pkg in index.resolve(&requested)? {
+        debug!("Detected dependency: {pkg:?}");
+        dependencies.push(PackageLock {
+            name: pkg.name.clone(),
+            version: pkg.version.clone(),
+            system: "openwrt".to_string(),
+            url: index.url(pkg),
+            fallback_urls: vec![],
+            provides: pkg.provides.clone(),
+            sha256: pkg.sha256.clone(),
+            commit: None,
+            signatures: vec![],
+            installed: false,
+            depends: pkg
+                .depends
+                .iter()
+                .flat_map(|dep| pkgs::dependency_names("openwrt", dep))
+                .map(String::from)
+                .collect(),
+            size: pkg.size,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "Package: libc
+Version: 1.2.4-4
+Depends: libgcc1
+License: MIT
+Section: libs
+Architecture: x86_64
+Installed-Size: 296960
+Filename: libc_1.2.4-4_x86_64.ipk
+Size: 297706
+SHA256sum: 1dc4bd8b2bbfa9a4b2b8bb9ac51e2d3c0c5c1e4a6fa5a7f1bba1e0b1a4a3b2c1
+Description:  C library
+
+Package: libgcc1
+Version: 12.3.0-4
+License: GPL-3.0-with-GCC-exception
+Architecture: x86_64
+Filename: libgcc1_12.3.0-4_x86_64.ipk
+Size: 28390
+SHA256sum: 7c5e1f3a2b8d4e6f9a0b1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0d1e2f
+Description:  GCC support library
+
+Package: libustream-mbedtls20201210
+Version: 2023.02.28~498f6e26-1
+Depends: libc
+Provides: libustream-mbedtls
+ABIVersion: 20201210
+Architecture: x86_64
+Filename: libustream-mbedtls20201210_2023.02.28~498f6e26-1_x86_64.ipk
+Size: 7912
+SHA256sum: 3e6d4a1f8b2c9e7d5a0f1b3c6e8d2a4f7b9c1e3d5f7a9b2c4e6f8a0b1c3d5e7f
+Description:  ustream SSL Library
+";
+
+    const PACKAGES: &str = "Package: curl
+Version: 8.7.1-r1
+Depends: libc, libcurl4 (>= 8.7.1), libustream-openssl | libustream-mbedtls
+Architecture: x86_64
+Filename: curl_8.7.1-r1_x86_64.ipk
+Size: 66270
+SHA256sum: 9f8e7d6c5b4a39281706f5e4d3c2b1a09f8e7d6c5b4a39281706f5e4d3c2b1a0
+Description:  A client-side URL transfer utility
+
+Package: libcurl4
+Version: 8.7.1-r1
+Depends: libc
+Architecture: x86_64
+Filename: libcurl4_8.7.1-r1_x86_64.ipk
+SHA256sum: 0a1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f60718293a4b5c6d7e8f9
+Description:  A client-side URL transfer library
+";
+
+    #[test]
+    fn test_resolve() -> Result<()> {
+        let mut index = Index::default();
+        index.import(
+            "https://downloads.openwrt.org/releases/23.05.3/targets/x86/64/packages/",
+            BASE,
+        )?;
+        index.import(
+            "https://downloads.openwrt.org/releases/23.05.3/packages/x86_64/packages",
+            PACKAGES,
+        )?;
+
+        let resolved = index.resolve(&["curl".to_string()])?;
+        let names = resolved.iter().map(|p| p.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "curl",
+                "libc",
+                "libcurl4",
+                "libustream-mbedtls20201210",
+                "libgcc1"
+            ]
+        );
+        assert_eq!(
+            index.url(resolved[3]),
+            "https://downloads.openwrt.org/releases/23.05.3/targets/x86/64/packages/libustream-mbedtls20201210_2023.02.28~498f6e26-1_x86_64.ipk"
+        );
+        assert_eq!(resolved[2].size, None);
+
+        let err = index.resolve(&["wget".to_string()]).unwrap_err();
+        assert!(err.downcast_ref::<UnknownPackage>().is_some());
+        Ok(())
+    }
+}
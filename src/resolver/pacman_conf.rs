@@ -0,0 +1,182 @@
+//! Read the repositories configured in the pacman.conf of an image, for the
+//! builtin solver that downloads the sync databases on the host.
+
+use crate::errors::*;
+use crate::resolver::image_files::ImageFiles;
+
+/// Includes can nest, this guards against loops
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+/// A repository section of pacman.conf
+#[derive(Debug, Clone, PartialEq)]
+pub struct Repo {
+    pub name: String,
+    /// The servers in order, `$repo` and `$arch` are not substituted yet
+    pub servers: Vec<String>,
+}
+
+impl Repo {
+    /// The servers with `$repo` and `$arch` substituted
+    pub fn urls(&self, arch: &str) -> Vec<String> {
+        self.servers
+            .iter()
+            .map(|server| {
+                server
+                    .replace("$repo", &self.name)
+                    .replace("$arch", arch)
+                    .trim_end_matches('/')
+                    .to_string()
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct PacmanConf {
+    /// The `Architecture` of the `[options]` section, `auto` if unset
+    pub architecture: Option<String>,
+    pub repos: Vec<Repo>,
+    /// The paths of all files that were read, starting with pacman.conf
+    pub paths: Vec<String>,
+}
+
+impl PacmanConf {
+    /// Parse pacman.conf, files that are included are read from the image
+    pub fn parse(files: &ImageFiles, conf: &str) -> Result<Self> {
+        let mut parsed = PacmanConf {
+            paths: vec!["/etc/pacman.conf".to_string()],
+            ..Default::default()
+        };
+        let mut section = None;
+        parsed.parse_text(files, conf, &mut section, 0)?;
+        Ok(parsed)
+    }
+
+    fn parse_text(
+        &mut self,
+        files: &ImageFiles,
+        text: &str,
+        section: &mut Option<String>,
+        depth: usize,
+    ) -> Result<()> {
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                if name != "options" {
+                    self.repos.push(Repo {
+                        name: name.to_string(),
+                        servers: vec![],
+                    });
+                }
+                *section = Some(name.to_string());
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+            match (section.as_deref(), key) {
+                (Some("options"), "Architecture") => {
+                    let arch = value.split_whitespace().next().unwrap_or(value);
+                    self.architecture = Some(arch.to_string());
+                }
+                (Some(_), "Include") => {
+                    if depth >= MAX_INCLUDE_DEPTH {
+                        bail!("Too many nested includes in pacman.conf: {value:?}");
+                    }
+                    for (path, data) in glob(files, value)? {
+                        let text = String::from_utf8(data.to_vec())
+                            .with_context(|| anyhow!("Failed to decode {path:?} as utf8"))?;
+                        self.paths.push(path);
+                        self.parse_text(files, &text, section, depth + 1)?;
+                    }
+                }
+                (Some(name), "Server") if name != "options" => {
+                    if let Some(repo) = self.repos.last_mut() {
+                        repo.servers.push(value.to_string());
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The files matching an Include, a `*` is supported in the last component
+fn glob<'a>(files: &'a ImageFiles, pattern: &str) -> Result<Vec<(String, &'a [u8])>> {
+    let Some((dir, name)) = pattern.rsplit_once('/') else {
+        bail!("Include in pacman.conf is not an absolute path: {pattern:?}");
+    };
+    let Some((prefix, suffix)) = name.split_once('*') else {
+        let data = files
+            .file(pattern)?
+            .with_context(|| anyhow!("Included file does not exist in image: {pattern:?}"))?;
+        return Ok(vec![(pattern.to_string(), data)]);
+    };
+    Ok(files
+        .files_in(dir)?
+        .into_iter()
+        .filter(|(path, _)| {
+            let name = &path[dir.len() + 1..];
+            !name.contains('/')
+                && name.len() >= prefix.len() + suffix.len()
+                && name.starts_with(prefix)
+                && name.ends_with(suffix)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pacman_conf() -> Result<()> {
+        let files = ImageFiles::default();
+        let conf = PacmanConf::parse(
+            &files,
+            "[options]
+HoldPkg = pacman glibc
+Architecture = auto
+
+#[testing]
+#Include = /etc/pacman.d/mirrorlist
+
+[core]
+Server = https://geo.mirror.pkgbuild.com/$repo/os/$arch/
+Server = https://mirror.example.com/$repo/os/$arch
+
+[extra]
+SigLevel = Optional
+Server = https://geo.mirror.pkgbuild.com/$repo/os/$arch
+",
+        )?;
+        assert_eq!(conf.architecture.as_deref(), Some("auto"));
+        assert_eq!(
+            conf.repos
+                .iter()
+                .map(|repo| repo.name.as_str())
+                .collect::<Vec<_>>(),
+            ["core", "extra"]
+        );
+        assert_eq!(
+            conf.repos[0].urls("x86_64"),
+            [
+                "https://geo.mirror.pkgbuild.com/core/os/x86_64",
+                "https://mirror.example.com/core/os/x86_64",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_include() {
+        let files = ImageFiles::default();
+        let conf = "[core]\nInclude = /etc/pacman.d/mirrorlist\n";
+        assert!(PacmanConf::parse(&files, conf).is_err());
+    }
+}
@@ -0,0 +1,266 @@
+//! Parsers for the output of the package managers that are run in the
+//! container during `repro-env update`. They don't depend on a container, so
+//! the output of different releases can be tested (and fuzzed) directly.
+use crate::errors::*;
+use std::collections::HashSet;
+
+/// A line of `apt-get --print-uris`
+#[derive(Debug, PartialEq)]
+pub struct AptUri<'a> {
+    /// The url, as printed by apt (percent-encoded)
+    pub url: &'a str,
+    pub filename: &'a str,
+    pub size: u64,
+    /// The checksum with its type, e.g. `SHA256:26dd...`, older releases print `MD5Sum:`
+    pub checksum: &'a str,
+}
+
+impl AptUri<'_> {
+    /// The checksum if it's a sha256
+    pub fn sha256(&self) -> Option<&str> {
+        self.checksum.strip_prefix("SHA256:")
+    }
+}
+
+/// Parse a line of `apt-get --print-uris`, e.g.
+/// `'http://deb.debian.org/debian/pool/main/r/rustc/rustc_1.63.0%2bdfsg1-2_amd64.deb' rustc_1.63.0+dfsg1-2_amd64.deb 2612712 SHA256:26dd...`
+pub fn apt_print_uri(line: &str) -> Result<AptUri<'_>> {
+    let mut parts = line.split(' ');
+    let url = parts.next().context("Missing url in apt output")?;
+    let filename = parts.next().context("Missing filename in apt output")?;
+    let size = parts.next().context("Missing size in apt output")?;
+    let checksum = parts.next().context("Missing checksum in apt output")?;
+
+    if let Some(trailing) = parts.next() {
+        bail!("Trailing data in apt output: {trailing:?}");
+    }
+
+    let url = url.strip_prefix('\'').unwrap_or(url);
+    let url = url.strip_suffix('\'').unwrap_or(url);
+    if url.is_empty() {
+        bail!("Empty url in apt output: {line:?}");
+    }
+    let size = size
+        .parse()
+        .with_context(|| anyhow!("Invalid size in apt output: {size:?}"))?;
+
+    Ok(AptUri {
+        url,
+        filename,
+        size,
+        checksum,
+    })
+}
+
+/// Parse the output of `apt-get --print-uris`, one package per line
+pub fn apt_print_uris(buf: &str) -> Result<Vec<AptUri<'_>>> {
+    buf.lines()
+        .filter(|line| !line.is_empty())
+        .map(apt_print_uri)
+        .collect()
+}
+
+/// Parse the output of `pacman -Sup --print-format '%r %n %v'` into repository, name and version
+pub fn pacman_print_format(buf: &str) -> Result<Vec<(String, String, String)>> {
+    let mut resolved = Vec::new();
+    for line in buf.lines() {
+        if line.is_empty() {
+            continue;
+        }
+        let mut line = line.split(' ');
+        let repo = line.next().context("Missing repo in pacman output")?;
+        let name = line.next().context("Missing pkg name in pacman output")?;
+        let version = line.next().context("Missing version in pacman output")?;
+        if let Some(trailing) = line.next() {
+            bail!("Trailing data in pacman output: {trailing:?}");
+        }
+        if repo.is_empty() || name.is_empty() || version.is_empty() {
+            bail!("Empty field in pacman output: {:?}", [repo, name, version]);
+        }
+        resolved.push((repo.to_string(), name.to_string(), version.to_string()));
+    }
+    Ok(resolved)
+}
+
+/// Parse the output of `apk info -v` into the `name-version` of each installed package
+pub fn apk_info(buf: &str) -> HashSet<String> {
+    buf.lines()
+        .filter(|line| !line.is_empty() && !line.starts_with("WARNING:"))
+        .map(String::from)
+        .collect()
+}
+
+/// The packages of `apk info -v` that are only installed afterwards, sorted
+pub fn apk_info_diff(before: &HashSet<String>, after: &HashSet<String>) -> Vec<String> {
+    let mut new = after.difference(before).cloned().collect::<Vec<_>>();
+    new.sort();
+    new
+}
+
+/// Parse the output of `apk --simulate` into the (name, version) of packages that would be installed
+pub fn apk_simulate(buf: &str) -> Result<Vec<(String, String)>> {
+    let mut pkgs = Vec::new();
+    for line in buf.lines() {
+        // (1/5) Installing musl (1.2.4-r1)
+        // (2/5) Upgrading busybox (1.36.1-r0 -> 1.36.1-r2)
+        let Some((_, line)) = line.split_once(") ") else {
+            continue;
+        };
+        let Some((action, line)) = line.split_once(' ') else {
+            continue;
+        };
+        if !matches!(
+            action,
+            "Installing" | "Upgrading" | "Downgrading" | "Replacing"
+        ) {
+            continue;
+        }
+        let (name, version) = line
+            .split_once(" (")
+            .with_context(|| anyhow!("Failed to parse apk output: {line:?}"))?;
+        let version = version
+            .strip_suffix(')')
+            .with_context(|| anyhow!("Failed to parse apk output: {line:?}"))?;
+        let version = version
+            .rsplit_once(" -> ")
+            .map(|(_, new)| new)
+            .unwrap_or(version);
+        pkgs.push((name.to_string(), version.to_string()));
+    }
+    Ok(pkgs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apt_print_uris_bookworm() -> Result<()> {
+        let uris = apt_print_uris("'http://deb.debian.org/debian/pool/main/r/rustc/rustc_1.63.0%2bdfsg1-2_amd64.deb' rustc_1.63.0+dfsg1-2_amd64.deb 2612712 SHA256:26dd439266153e38d3e6fbe0fe2dbbb41f20994afa688faa71f38427348589ed
+'http://deb.debian.org/debian/pool/main/b/binutils/binutils_2.40-2_amd64.deb' binutils_2.40-2_amd64.deb 2935908 SHA256:83c3e20b53e1fbd84d764c3ba27d26a0376e361ae5d7fb37120196934dd87424
+")?;
+        assert_eq!(
+            uris,
+            [
+                AptUri {
+                    url: "http://deb.debian.org/debian/pool/main/r/rustc/rustc_1.63.0%2bdfsg1-2_amd64.deb",
+                    filename: "rustc_1.63.0+dfsg1-2_amd64.deb",
+                    size: 2612712,
+                    checksum: "SHA256:26dd439266153e38d3e6fbe0fe2dbbb41f20994afa688faa71f38427348589ed",
+                },
+                AptUri {
+                    url: "http://deb.debian.org/debian/pool/main/b/binutils/binutils_2.40-2_amd64.deb",
+                    filename: "binutils_2.40-2_amd64.deb",
+                    size: 2935908,
+                    checksum: "SHA256:83c3e20b53e1fbd84d764c3ba27d26a0376e361ae5d7fb37120196934dd87424",
+                },
+            ]
+        );
+        assert_eq!(
+            uris[0].sha256(),
+            Some("26dd439266153e38d3e6fbe0fe2dbbb41f20994afa688faa71f38427348589ed")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_apt_print_uris_buster() -> Result<()> {
+        // apt 1.8 prints the MD5Sum and epochs encoded as %3a
+        let uris = apt_print_uris("'http://deb.debian.org/debian/pool/main/b/binutils/binutils_2.31.1-16_amd64.deb' binutils_2.31.1-16_amd64.deb 1855032 MD5Sum:5eaa6969388c512a206377bf813ab531
+'http://deb.debian.org/debian/pool/main/l/lvm2/dmsetup_2%3a1.02.155-3_amd64.deb' dmsetup_2%3a1.02.155-3_amd64.deb 91792 MD5Sum:0b6b3f8bd5b6c0d5f35c2a4f2d70d5e6
+")?;
+        assert_eq!(uris.len(), 2);
+        assert_eq!(uris[0].sha256(), None);
+        assert_eq!(uris[1].filename, "dmsetup_2%3a1.02.155-3_amd64.deb");
+        Ok(())
+    }
+
+    #[test]
+    fn test_apt_print_uri_invalid() {
+        assert!(apt_print_uri("'http://deb.debian.org/x.deb' x.deb 123").is_err());
+        assert!(apt_print_uri("'http://deb.debian.org/x.deb' x.deb 123 SHA256:00 extra").is_err());
+        assert!(apt_print_uri("'http://deb.debian.org/x.deb' x.deb big SHA256:00").is_err());
+        assert!(apt_print_uri("'' x.deb 123 SHA256:00").is_err());
+        assert!(apt_print_uri("").is_err());
+    }
+
+    #[test]
+    fn test_pacman_print_format() -> Result<()> {
+        let resolved = pacman_print_format(
+            "core gcc-libs 13.2.1-3
+extra rust 1:1.74.0-1
+extra libsigc++ 2.12.1-1
+",
+        )?;
+        assert_eq!(
+            resolved,
+            [
+                ("core", "gcc-libs", "13.2.1-3"),
+                ("extra", "rust", "1:1.74.0-1"),
+                ("extra", "libsigc++", "2.12.1-1"),
+            ]
+            .map(|(repo, name, version)| (
+                repo.to_string(),
+                name.to_string(),
+                version.to_string()
+            ))
+        );
+        assert_eq!(pacman_print_format("")?, []);
+        assert!(pacman_print_format("core gcc-libs\n").is_err());
+        assert!(pacman_print_format("core gcc-libs 13.2.1-3 x86_64\n").is_err());
+        assert!(pacman_print_format("core  13.2.1-3\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_apk_info_diff() {
+        // apk 2.14 (alpine 3.18)
+        let before = apk_info("musl-1.2.4-r1\nbusybox-1.36.1-r2\n");
+        // apk warns about repositories it can't read
+        let after = apk_info("WARNING: opening /var/cache/apk: No such file or directory\nmusl-1.2.4-r1\nbusybox-1.36.1-r2\nlibgcc-12.2.1_git20220924-r10\ngcc-12.2.1_git20220924-r10\n\n");
+        assert_eq!(
+            apk_info_diff(&before, &after),
+            [
+                "gcc-12.2.1_git20220924-r10",
+                "libgcc-12.2.1_git20220924-r10"
+            ]
+        );
+        assert_eq!(apk_info_diff(&after, &before), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_apk_simulate() -> Result<()> {
+        let pkgs = apk_simulate(
+            "(1/3) Upgrading busybox (1.36.1-r0 -> 1.36.1-r2)
+(2/3) Installing libgcc (12.2.1_git20220924-r10)
+(3/3) Installing gcc (12.2.1_git20220924-r10)
+OK: 123 MiB in 20 packages
+",
+        )?;
+        assert_eq!(
+            pkgs,
+            vec![
+                ("busybox".to_string(), "1.36.1-r2".to_string()),
+                ("libgcc".to_string(), "12.2.1_git20220924-r10".to_string()),
+                ("gcc".to_string(), "12.2.1_git20220924-r10".to_string()),
+            ]
+        );
+        // apk 3 (alpine 3.22) prints the same lines, with "Executing" steps in between
+        let pkgs = apk_simulate(
+            "(1/2) Installing ca-certificates (20250619-r0)
+Executing ca-certificates-20250619-r0.trigger
+(2/2) Replacing libcrypto3 (3.5.1-r0)
+OK: 9 MiB in 17 packages
+",
+        )?;
+        assert_eq!(
+            pkgs,
+            vec![
+                ("ca-certificates".to_string(), "20250619-r0".to_string()),
+                ("libcrypto3".to_string(), "3.5.1-r0".to_string()),
+            ]
+        );
+        assert!(apk_simulate("(1/1) Installing musl\n").is_err());
+        Ok(())
+    }
+}
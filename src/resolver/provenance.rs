@@ -0,0 +1,268 @@
+//! Record how pins were computed: the versions of the package manager in the
+//! resolution container, how old the package indexes were and which
+//! repositories were configured
+use crate::container::{self, Container};
+use crate::errors::*;
+use crate::resolver::image_files::ImageFiles;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+use time::format_description::well_known;
+use time::OffsetDateTime;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Provenance {
+    /// Name of the tool => version
+    pub tools: BTreeMap<String, String>,
+    /// Name of the package index => when it was generated (rfc3339)
+    pub indexes: BTreeMap<String, String>,
+    /// Path of a repository configuration file => its content
+    pub sources: BTreeMap<String, String>,
+}
+
+impl Provenance {
+    /// Run `cmd` in the container and record the version it reports. This is
+    /// informational, so failures are only logged.
+    pub async fn detect_tool(
+        &mut self,
+        container: &Container,
+        name: &str,
+        cmd: &[&str],
+        parse: fn(&str) -> Option<&str>,
+    ) {
+        let version = container
+            .exec(
+                cmd,
+                container::Exec {
+                    capture_stdout: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .and_then(|buf| String::from_utf8(buf).context("Version output is invalid utf-8"));
+        match version {
+            Ok(out) => match parse(&out) {
+                Some(version) => {
+                    debug!("Detected {name} version: {version:?}");
+                    self.tools.insert(name.to_string(), version.to_string());
+                }
+                None => warn!("Failed to parse {name} version from output: {out:?}"),
+            },
+            Err(err) => warn!("Failed to detect {name} version: {err:#}"),
+        }
+    }
+
+    /// Record the modification time (unix epoch) of a package index
+    pub fn record_index(&mut self, name: &str, epoch: i64) -> Result<()> {
+        let time = OffsetDateTime::from_unix_timestamp(epoch)?.format(&well_known::Rfc3339)?;
+        self.indexes.insert(name.to_string(), time);
+        Ok(())
+    }
+
+    /// Record the repository configuration in the container, `paths` can be
+    /// files or directories. Paths that don't exist in the image are skipped.
+    pub async fn record_sources(&mut self, container: &Container, paths: &[&str]) -> Result<()> {
+        for path in paths {
+            let buf = match container.tar(path).await {
+                Ok(buf) => buf,
+                Err(err) => {
+                    debug!("Failed to read repository configuration {path:?}: {err:#}");
+                    continue;
+                }
+            };
+            for (path, content) in read_sources_tar(path, &buf)? {
+                debug!("Recording repository configuration {path:?}");
+                self.sources.insert(path, content);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Provenance {
+    /// Record the repository configuration of an image that was read without
+    /// a container, like [`Provenance::record_sources`]
+    pub fn record_image_sources(&mut self, files: &ImageFiles, paths: &[&str]) -> Result<()> {
+        for path in paths {
+            let entries = if files.is_dir(path)? {
+                files.files_in(path)?
+            } else if let Some(data) = files.file(path)? {
+                vec![(path.to_string(), data)]
+            } else {
+                debug!("Repository configuration does not exist in image: {path:?}");
+                continue;
+            };
+            for (path, data) in entries {
+                let Ok(content) = String::from_utf8(data.to_vec()) else {
+                    debug!("Skipping repository configuration that isn't utf8: {path:?}");
+                    continue;
+                };
+                debug!("Recording repository configuration {path:?}");
+                self.sources.insert(path, content);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read the text files of a tar that was created from `path` in the container,
+/// returns the absolute path of each file with its content
+pub fn read_sources_tar(path: &str, buf: &[u8]) -> Result<Vec<(String, String)>> {
+    let parent = Path::new(path).parent().unwrap_or(Path::new("/"));
+    let mut sources = Vec::new();
+    let mut tar = tar::Archive::new(buf);
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        let path = parent.join(entry.path()?);
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        let Ok(content) = String::from_utf8(data) else {
+            debug!("Skipping repository configuration that isn't utf8: {path:?}");
+            continue;
+        };
+        let path = path
+            .to_str()
+            .with_context(|| anyhow!("Path is not valid utf8: {path:?}"))?;
+        sources.push((path.to_string(), content));
+    }
+    Ok(sources)
+}
+
+/// `apk-tools 2.14.0, compiled for x86_64.`
+pub fn parse_apk_version(out: &str) -> Option<&str> {
+    let version = out
+        .strip_prefix("apk-tools ")?
+        .split([',', ' ', '\n'])
+        .next()?;
+    (!version.is_empty()).then_some(version)
+}
+
+/// ` .--.                  Pacman v6.0.2 - libalpm v13.0.2`
+pub fn parse_pacman_version(out: &str) -> Option<&str> {
+    let (_, version) = out.split_once("Pacman v")?;
+    version.split_whitespace().next()
+}
+
+/// `apt 2.6.1 (amd64)`
+pub fn parse_apt_version(out: &str) -> Option<&str> {
+    let mut fields = out.lines().next()?.split_whitespace();
+    if fields.next()? != "apt" {
+        return None;
+    }
+    fields.next()
+}
+
+/// `Flatpak 1.14.4`
+pub fn parse_flatpak_version(out: &str) -> Option<&str> {
+    out.strip_prefix("Flatpak ")?.split_whitespace().next()
+}
+
+/// `1.5.8`, newer releases print only the version too
+pub fn parse_micromamba_version(out: &str) -> Option<&str> {
+    out.lines().next()?.split_whitespace().next()
+}
+
+/// Parse the output of `stat -c '%Y %n'` into (filename, epoch)
+pub fn parse_stat_mtimes(out: &str) -> Result<Vec<(&str, i64)>> {
+    out.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (epoch, path) = line
+                .split_once(' ')
+                .with_context(|| anyhow!("Invalid stat output: {line:?}"))?;
+            let epoch = epoch
+                .parse()
+                .with_context(|| anyhow!("Invalid timestamp in stat output: {line:?}"))?;
+            let filename = path.rsplit('/').next().unwrap_or(path);
+            Ok((filename, epoch))
+        })
+        .collect()
+}
+
+/// Parse a `Last-Modified` http header into a unix epoch,
+/// e.g. `Tue, 04 Jul 2023 07:50:54 GMT`
+pub fn parse_http_date(value: &str) -> Result<i64> {
+    // rfc2822 doesn't allow the timezone as `GMT`
+    let normalized = match value.strip_suffix(" GMT") {
+        Some(date) => format!("{date} +0000"),
+        None => value.to_string(),
+    };
+    let date = OffsetDateTime::parse(&normalized, &well_known::Rfc2822)
+        .with_context(|| anyhow!("Invalid http date: {value:?}"))?;
+    Ok(date.unix_timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tool_versions() {
+        assert_eq!(
+            parse_apk_version("apk-tools 2.14.0, compiled for x86_64.\n"),
+            Some("2.14.0")
+        );
+        assert_eq!(
+            parse_pacman_version(
+                "\n .--.                  Pacman v6.0.2 - libalpm v13.0.2\n/ _.-' .-.  .-.  .-.   Copyright (C) 2006-2021 Pacman Development Team\n"
+            ),
+            Some("6.0.2")
+        );
+        assert_eq!(parse_apt_version("apt 2.6.1 (amd64)\n"), Some("2.6.1"));
+        assert_eq!(parse_apt_version("dpkg 1.21.22\n"), None);
+        assert_eq!(parse_apk_version(""), None);
+        assert_eq!(parse_flatpak_version("Flatpak 1.14.4\n"), Some("1.14.4"));
+    }
+
+    #[test]
+    fn test_parse_stat_mtimes() -> Result<()> {
+        let mtimes =
+            parse_stat_mtimes("1688457054 /var/lib/pacman/sync/core.db\n1688457000 extra.db\n")?;
+        assert_eq!(mtimes, [("core.db", 1688457054), ("extra.db", 1688457000)]);
+        assert!(parse_stat_mtimes("yesterday core.db\n").is_err());
+
+        let mut provenance = Provenance::default();
+        provenance.record_index("core", 1688457054)?;
+        assert_eq!(provenance.indexes["core"], "2023-07-04T07:50:54Z");
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_http_date() -> Result<()> {
+        assert_eq!(
+            parse_http_date("Tue, 04 Jul 2023 07:50:54 GMT")?,
+            1688457054
+        );
+        assert!(parse_http_date("yesterday").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_sources_tar() -> Result<()> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, data) in [
+            ("sources.list.d/debian.sources", &b"Types: deb\n"[..]),
+            ("sources.list.d/binary.gpg", &b"\xff\x00"[..]),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, data)?;
+        }
+        let buf = builder.into_inner()?;
+
+        let sources = read_sources_tar("/etc/apt/sources.list.d", &buf)?;
+        assert_eq!(
+            sources,
+            [(
+                "/etc/apt/sources.list.d/debian.sources".to_string(),
+                "Types: deb\n".to_string()
+            )]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+//! Resolve snaps with the api of the snap store, snapcraft would otherwise pick
+//! whatever revision a channel points to at build time
+use crate::errors::*;
+use crate::fetch;
+use crate::http;
+use crate::lockfile::PackageLock;
+use crate::manifest::PackagesManifest;
+use crate::pkgs;
+use serde::Deserialize;
+
+const STORE_API: &str = "https://api.snapcraft.io/v2/snaps/info";
+const RISKS: &[&str] = &["stable", "candidate", "beta", "edge"];
+
+#[derive(Debug, Deserialize)]
+pub struct SnapInfo {
+    #[serde(rename = "channel-map")]
+    pub channel_map: Vec<ChannelMapEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChannelMapEntry {
+    pub channel: Channel,
+    pub revision: u64,
+    pub version: String,
+    pub download: Download,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Channel {
+    pub architecture: String,
+    pub track: String,
+    pub risk: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Download {
+    pub url: String,
+}
+
+/// Split a dependency in the format of snapcraft's `build-snaps`, like `core22`,
+/// `go/1.21/stable` or `snapcraft/edge`, into name, track and risk
+pub fn parse_dependency(dep: &str) -> Result<(&str, &str, &str)> {
+    let mut parts = dep.split('/');
+    let name = parts.next().filter(|name| !name.is_empty());
+    let name = name.with_context(|| anyhow!("Snap dependency has no name: {dep:?}"))?;
+    let (track, risk) = match (parts.next(), parts.next(), parts.next()) {
+        (None, _, _) => ("latest", "stable"),
+        (Some(risk), None, _) if RISKS.contains(&risk) => ("latest", risk),
+        (Some(track), None, _) => (track, "stable"),
+        (Some(track), Some(risk), None) if RISKS.contains(&risk) => (track, risk),
+        _ => bail!("Invalid snap channel, expected name/track/risk: {dep:?}"),
+    };
+    Ok((name, track, risk))
+}
+
+/// The entry of the channel map for this channel and architecture
+pub fn select<'a>(
+    info: &'a SnapInfo,
+    track: &str,
+    risk: &str,
+    arch: &str,
+) -> Option<&'a ChannelMapEntry> {
+    info.channel_map.iter().find(|entry| {
+        entry.channel.track == track
+            && entry.channel.risk == risk
+            && entry.channel.architecture == arch
+    })
+}
+
+async fn lookup(client: &http::Client, name: &str, arch: &str) -> Result<SnapInfo> {
+    let url = format!(
+        "{STORE_API}/{}?architecture={arch}&fields=revision,version,download",
+        urlencoding::encode(name)
+    );
+    debug!("Querying snap store: {url:?}");
+    let response = client
+        .cancellable(async {
+            client
+                .get(&url)
+                .header("Snap-Device-Series", "16")
+                .send()
+                .await
+                .context("Failed to send http request")
+        })
+        .await?
+        .error_for_status()
+        .with_context(|| anyhow!("Received http error from snap store for {name:?}"))?;
+    let buf = response.bytes().await.context("Failed to read http body")?;
+    serde_json::from_slice(&buf).context("Failed to parse response of snap store")
+}
+
+pub async fn resolve(
+    manifest: &PackagesManifest,
+    dependencies: &mut Vec<PackageLock>,
+) -> Result<()> {
+    let arch = pkgs::native_arch("snap").context("Unsupported architecture for snaps")?;
+    let client = http::Client::new()?;
+
+    info!("Resolving snaps...");
+    for dep in &manifest.dependencies {
+        let (name, track, risk) = parse_dependency(dep)?;
+        let info = lookup(&client, name, arch).await?;
+        let entry = select(&info, track, risk, arch).with_context(|| {
+            anyhow!("Snap {name:?} has no revision in channel {track}/{risk} for {arch}")
+        })?;
+        debug!(
+            "Detected snap {name:?}: revision={}, version={:?}",
+            entry.revision, entry.version
+        );
+
+        // the store only publishes sha3-384, pin the download by sha256 like the other packages
+        let filename = format!("{name}_{}.snap", entry.revision);
+        let pin = fetch::pin_file(&client, &filename, &entry.download.url, None)
+            .await
+            .with_context(|| anyhow!("Failed to download snap {name:?}"))?;
+
+        dependencies.push(PackageLock {
+            name: name.to_string(),
+            version: entry.version.clone(),
+            system: "snap".to_string(),
+            url: entry.download.url.clone(),
+            fallback_urls: vec![],
+            provides: vec![dep.clone()],
+            sha256: pin.sha256,
+            commit: None,
+            signatures: vec![],
+            installed: false,
+            depends: vec![],
+            size: Some(pin.size),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dependency() -> Result<()> {
+        assert_eq!(parse_dependency("core22")?, ("core22", "latest", "stable"));
+        assert_eq!(
+            parse_dependency("go/1.21/stable")?,
+            ("go", "1.21", "stable")
+        );
+        assert_eq!(parse_dependency("go/1.21")?, ("go", "1.21", "stable"));
+        assert_eq!(
+            parse_dependency("snapcraft/edge")?,
+            ("snapcraft", "latest", "edge")
+        );
+        assert!(parse_dependency("go/1.21/nightly").is_err());
+        assert!(parse_dependency("/stable").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_select() -> Result<()> {
+        let info = serde_json::from_str::<SnapInfo>(
+            r#"{
+                "channel-map": [
+                    {"channel":{"architecture":"amd64","name":"stable","released-at":"2024-01-22T13:40:26.437811+00:00","risk":"stable","track":"latest"},"download":{"deltas":[],"sha3-384":"","size":77262848,"url":"https://api.snapcraft.io/api/v1/snaps/download/amcUKQILKXHHTlmSa7NMdnXSx02dNeeT_1122.snap"},"revision":1122,"type":"base","version":"20240111"},
+                    {"channel":{"architecture":"amd64","name":"edge","released-at":"2024-02-01T09:12:00.000000+00:00","risk":"edge","track":"latest"},"download":{"deltas":[],"sha3-384":"","size":77266944,"url":"https://api.snapcraft.io/api/v1/snaps/download/amcUKQILKXHHTlmSa7NMdnXSx02dNeeT_1139.snap"},"revision":1139,"type":"base","version":"20240201"},
+                    {"channel":{"architecture":"arm64","name":"stable","released-at":"2024-01-22T13:40:26.437811+00:00","risk":"stable","track":"latest"},"download":{"deltas":[],"sha3-384":"","size":71213056,"url":"https://api.snapcraft.io/api/v1/snaps/download/amcUKQILKXHHTlmSa7NMdnXSx02dNeeT_1123.snap"},"revision":1123,"type":"base","version":"20240111"}
+                ],
+                "name": "core22",
+                "snap-id": "amcUKQILKXHHTlmSa7NMdnXSx02dNeeT"
+            }"#,
+        )?;
+        let entry = select(&info, "latest", "stable", "amd64").context("Missing entry")?;
+        assert_eq!(entry.revision, 1122);
+        assert_eq!(entry.version, "20240111");
+        let entry = select(&info, "latest", "edge", "amd64").context("Missing entry")?;
+        assert_eq!(entry.revision, 1139);
+        assert!(select(&info, "latest", "beta", "amd64").is_none());
+        Ok(())
+    }
+}
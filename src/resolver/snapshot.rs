@@ -0,0 +1,326 @@
+//! Lookups on snapshot.debian.org, which aggressively rate-limits. Requests
+//! that receive HTTP 429 are retried after the delay requested by the server,
+//! the number of concurrent lookups adapts to the rate limit and finished
+//! lookups are cached, so an interrupted update resumes where it left off.
+use crate::errors::*;
+use crate::hash;
+use crate::http;
+use crate::paths::PkgsCacheDir;
+use crate::resolver::debian::{self, JsonSnapshotInfo};
+use crate::summary;
+use crate::utils;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::Digest;
+use std::cmp;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+use tokio::{fs, time};
+
+/// The most lookups that run at the same time
+pub const MAX_CONCURRENCY: usize = 8;
+/// Give up on a request after it was rate-limited this many times
+const MAX_ATTEMPTS: u32 = 8;
+/// Don't wait longer than this, even if the server asks for it
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+#[derive(Debug, PartialEq)]
+struct Limit {
+    current: usize,
+    successes: usize,
+}
+
+/// How many lookups may run concurrently, halved when we get rate-limited
+/// and slowly increased again after enough requests succeeded
+#[derive(Debug)]
+pub struct Concurrency {
+    max: usize,
+    limit: Mutex<Limit>,
+}
+
+impl Concurrency {
+    pub fn new(max: usize) -> Self {
+        Concurrency {
+            max,
+            limit: Mutex::new(Limit {
+                current: max,
+                successes: 0,
+            }),
+        }
+    }
+
+    pub fn current(&self) -> usize {
+        self.limit
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .current
+    }
+
+    pub fn success(&self) {
+        let mut limit = self.limit.lock().unwrap_or_else(PoisonError::into_inner);
+        limit.successes += 1;
+        if limit.successes >= limit.current {
+            limit.current = cmp::min(limit.current + 1, self.max);
+            limit.successes = 0;
+        }
+    }
+
+    pub fn rate_limited(&self) {
+        let mut limit = self.limit.lock().unwrap_or_else(PoisonError::into_inner);
+        limit.current = cmp::max(limit.current / 2, 1);
+        limit.successes = 0;
+    }
+}
+
+/// Retry `f` while the server responds with HTTP 429
+pub async fn with_backoff<T, F, Fut>(concurrency: &Concurrency, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        let err = match f().await {
+            Ok(value) => {
+                concurrency.success();
+                return Ok(value);
+            }
+            Err(err) => err,
+        };
+        let Some(limited) = err.downcast_ref::<http::RateLimited>() else {
+            return Err(err);
+        };
+        if attempt >= MAX_ATTEMPTS {
+            return Err(err);
+        }
+        concurrency.rate_limited();
+        let delay = limited
+            .retry_after
+            .unwrap_or_else(|| Duration::from_secs(2u64.pow(attempt)))
+            .min(MAX_RETRY_AFTER);
+        warn!(
+            "Rate limit exceeded, retrying in {}s with {} concurrent requests (attempt {attempt}/{MAX_ATTEMPTS})",
+            delay.as_secs(),
+            concurrency.current()
+        );
+        utils::shutdown()
+            .run(async {
+                time::sleep(delay).await;
+                Ok(())
+            })
+            .await?;
+        attempt += 1;
+    }
+}
+
+/// The result of a lookup, cached by the sha256 of the package
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Lookup {
+    pub url: String,
+    pub size: u64,
+}
+
+pub struct Snapshot<'a> {
+    pub client: &'a http::Client,
+    pub pkgs_cache_dir: &'a PkgsCacheDir,
+    pub cache_dir: PathBuf,
+    pub concurrency: Concurrency,
+}
+
+impl<'a> Snapshot<'a> {
+    pub fn new(
+        client: &'a http::Client,
+        pkgs_cache_dir: &'a PkgsCacheDir,
+        cache_dir: PathBuf,
+    ) -> Self {
+        Snapshot {
+            client,
+            pkgs_cache_dir,
+            cache_dir,
+            concurrency: Concurrency::new(MAX_CONCURRENCY),
+        }
+    }
+
+    fn cache_path(&self, sha256: &str) -> Result<PathBuf> {
+        if sha256.is_empty() || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+            bail!("Invalid sha256 checksum: {sha256:?}");
+        }
+        Ok(self.cache_dir.join(sha256))
+    }
+
+    /// If this package has been looked up before, e.g. by an update that failed halfway
+    pub fn is_cached(&self, sha256: &str) -> bool {
+        self.cache_path(sha256).is_ok_and(|path| path.exists())
+    }
+
+    /// Find the snapshot.debian.org url of the package with this sha256,
+    /// `url` is used to download it if it's not in the package cache yet.
+    /// Returns the lookup and the number of bytes downloaded.
+    pub async fn lookup(&self, url: &str, filename: &str, sha256: &str) -> Result<(Lookup, u64)> {
+        let cache_path = self.cache_path(sha256)?;
+        match read_cache(&cache_path).await {
+            Ok(Some(lookup)) => {
+                debug!(
+                    "Using cached snapshot lookup for {filename:?}: {:?}",
+                    lookup.url
+                );
+                summary::record_cache(true);
+                return Ok((lookup, 0));
+            }
+            Ok(None) => (),
+            Err(err) => debug!("Ignoring invalid snapshot cache entry {cache_path:?}: {err:#}"),
+        }
+
+        let path = self.pkgs_cache_dir.sha256_path(sha256)?;
+        summary::record_cache(path.exists());
+        let (buf, downloaded) = if path.exists() {
+            (fs::read(path).await?, 0)
+        } else {
+            let buf = with_backoff(&self.concurrency, || self.client.fetch(url))
+                .await?
+                .to_vec();
+
+            let result = hash::sha256(&buf);
+            if result != sha256 {
+                bail!("Mismatch of sha256 checksum, expected={sha256}, downloaded={result}");
+            }
+
+            let len = buf.len() as u64;
+            (buf, len)
+        };
+
+        let sha1 = hex::encode(Sha1::digest(&buf));
+        let info_url = format!("https://snapshot.debian.org/mr/file/{sha1}/info");
+        let info = with_backoff(&self.concurrency, || self.client.fetch_cached(&info_url))
+            .await
+            .context("Failed to lookup pkg hash on snapshot.debian.org")?;
+
+        let info = serde_json::from_slice::<JsonSnapshotInfo>(&info)
+            .context("Failed to decode snapshot.debian.org json response")?;
+        let pkg = debian::find_snapshot_pkg(&info, filename, buf.len())?;
+
+        let archive_name = &pkg.archive_name;
+        let first_seen = &pkg.first_seen;
+        let path = &pkg.path;
+        let name = &pkg.name;
+        let lookup = Lookup {
+            url: format!(
+                "https://snapshot.debian.org/archive/{archive_name}/{first_seen}{path}/{name}"
+            ),
+            size: buf.len() as u64,
+        };
+
+        if let Err(err) = write_cache(&cache_path, &lookup) {
+            warn!("Failed to write snapshot cache entry {cache_path:?}: {err:#}");
+        }
+        Ok((lookup, downloaded))
+    }
+}
+
+async fn read_cache(path: &Path) -> Result<Option<Lookup>> {
+    match fs::read(path).await {
+        Ok(buf) => Ok(Some(serde_json::from_slice(&buf)?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn write_cache(path: &Path, lookup: &Lookup) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    utils::atomic_write(path, &serde_json::to_vec(lookup)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrency() {
+        let concurrency = Concurrency::new(8);
+        assert_eq!(concurrency.current(), 8);
+        concurrency.rate_limited();
+        assert_eq!(concurrency.current(), 4);
+        concurrency.rate_limited();
+        concurrency.rate_limited();
+        concurrency.rate_limited();
+        assert_eq!(concurrency.current(), 1);
+
+        // grows by one after as many successes as the current limit
+        concurrency.success();
+        assert_eq!(concurrency.current(), 2);
+        concurrency.success();
+        assert_eq!(concurrency.current(), 2);
+        concurrency.success();
+        assert_eq!(concurrency.current(), 3);
+        for _ in 0..100 {
+            concurrency.success();
+        }
+        assert_eq!(concurrency.current(), 8);
+    }
+
+    #[tokio::test]
+    async fn test_backoff() -> Result<()> {
+        let concurrency = Concurrency::new(8);
+        let mut calls = 0;
+        let value = with_backoff(&concurrency, || {
+            calls += 1;
+            let result = if calls < 3 {
+                Err(http::RateLimited {
+                    url: "https://snapshot.debian.org/mr/file/00/info".to_string(),
+                    retry_after: Some(Duration::ZERO),
+                }
+                .into())
+            } else {
+                Ok(calls)
+            };
+            async move { result }
+        })
+        .await?;
+        assert_eq!(value, 3);
+        assert_eq!(concurrency.current(), 2);
+
+        // other errors are not retried
+        let mut calls = 0;
+        let result = with_backoff(&concurrency, || {
+            calls += 1;
+            async { Err::<(), _>(anyhow!("Received http error")) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lookup_cache() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let client = http::Client::new()?;
+        let pkgs_cache_dir = crate::paths::pkgs_cache_dir()?;
+        let snapshot = Snapshot::new(&client, &pkgs_cache_dir, dir.path().join("snapshot"));
+
+        let sha256 = "b65fd16001578e10b602e577a8031cbfffc1164caf47ed9ba00c60d804519430";
+        let lookup = Lookup {
+            url: "https://snapshot.debian.org/archive/debian/20230115T211934Z/pool/main/b/binutils/binutils_2.40-2_amd64.deb".to_string(),
+            size: 1234,
+        };
+        assert!(!snapshot.is_cached(sha256));
+        write_cache(&snapshot.cache_path(sha256)?, &lookup)?;
+        assert!(snapshot.is_cached(sha256));
+        // no network access is needed
+        let cached = snapshot
+            .lookup(
+                "http://127.0.0.1:1/binutils.deb",
+                "binutils_2.40-2_amd64.deb",
+                sha256,
+            )
+            .await?;
+        assert_eq!(cached, (lookup, 0));
+
+        assert!(snapshot.cache_path("../../etc/passwd").is_err());
+        Ok(())
+    }
+}
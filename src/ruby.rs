@@ -0,0 +1,282 @@
+//! The `[ruby]` section. The gems of `Gemfile.lock` are pinned by sha256 and
+//! mounted as bundler cache, so `bundle install --local` works without network.
+use crate::container::Mount;
+use crate::errors::*;
+use crate::fetch;
+use crate::http;
+use crate::lockfile::{GemLock, Lockfile};
+use crate::manifest::{self, RubyManifest};
+use crate::paths;
+use crate::summary;
+use crate::utils;
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::fs;
+
+/// Where the gems are mounted during builds
+pub const GEM_CACHE: &str = "/extra/gems";
+
+/// A gem of a `GEM` section in `Gemfile.lock`
+#[derive(Debug, PartialEq)]
+pub struct Gem {
+    pub remote: String,
+    pub name: String,
+    pub version: String,
+    pub platform: Option<String>,
+}
+
+impl Gem {
+    /// The archive on the gem server, like `https://rubygems.org/gems/rake-13.1.0.gem`
+    pub fn url(&self) -> String {
+        let filename = match &self.platform {
+            Some(platform) => format!("{}-{}-{platform}.gem", self.name, self.version),
+            None => format!("{}-{}.gem", self.name, self.version),
+        };
+        format!("{}/gems/{filename}", self.remote.trim_end_matches('/'))
+    }
+
+    /// Gems without precompiled extensions work everywhere, the others only
+    /// on the architecture repro-env runs on
+    pub fn is_native(&self) -> bool {
+        let Some(platform) = &self.platform else {
+            return true;
+        };
+        platform
+            .strip_prefix(std::env::consts::ARCH)
+            .is_some_and(|rest| rest == "-linux" || rest.starts_with("-linux-"))
+    }
+}
+
+/// Split `1.16.2-x86_64-linux` into version and platform, gem versions can't contain a `-`
+fn split_platform(version: &str) -> (&str, Option<&str>) {
+    match version.split_once('-') {
+        Some((version, "ruby")) => (version, None),
+        Some((version, platform)) => (version, Some(platform)),
+        None => (version, None),
+    }
+}
+
+/// Parse a `name (version)` line of a specs or checksums list
+fn parse_spec(line: &str) -> Option<(&str, &str)> {
+    let (name, rest) = line.split_once(" (")?;
+    let (version, _) = rest.split_once(')')?;
+    Some((name, version))
+}
+
+/// The gems and the sha256 checksums (written by bundler 2.5 and later) of a `Gemfile.lock`
+pub fn parse_gemfile_lock(buf: &str) -> Result<(Vec<Gem>, HashMap<String, String>)> {
+    let mut gems = Vec::new();
+    let mut checksums = HashMap::new();
+    let mut section = "";
+    let mut remote = None;
+
+    for line in buf.lines() {
+        if line.is_empty() {
+            continue;
+        }
+        if !line.starts_with(' ') {
+            section = line;
+            remote = None;
+            if section == "GIT" {
+                warn!("Gems from git repositories in Gemfile.lock are not pinned, use [[git]] instead");
+            }
+            continue;
+        }
+
+        match section {
+            "GEM" => {
+                if let Some(url) = line.strip_prefix("  remote: ") {
+                    remote = Some(url.to_string());
+                } else if let Some(spec) = line.strip_prefix("    ") {
+                    // dependencies of a gem are indented even further
+                    if spec.starts_with(' ') {
+                        continue;
+                    }
+                    let (name, version) = parse_spec(spec)
+                        .with_context(|| anyhow!("Invalid gem in Gemfile.lock: {spec:?}"))?;
+                    let remote = remote
+                        .clone()
+                        .with_context(|| anyhow!("Gem {name:?} has no remote in Gemfile.lock"))?;
+                    let (version, platform) = split_platform(version);
+                    gems.push(Gem {
+                        remote,
+                        name: name.to_string(),
+                        version: version.to_string(),
+                        platform: platform.map(String::from),
+                    });
+                }
+            }
+            "CHECKSUMS" => {
+                let line = line.trim_start();
+                let Some((spec, checksum)) = line.rsplit_once(' ') else {
+                    continue;
+                };
+                let Some(sha256) = checksum.strip_prefix("sha256=") else {
+                    continue;
+                };
+                let (name, version) = parse_spec(spec)
+                    .with_context(|| anyhow!("Invalid checksum in Gemfile.lock: {line:?}"))?;
+                checksums.insert(format!("{name}-{version}"), sha256.to_string());
+            }
+            _ => (),
+        }
+    }
+
+    Ok((gems, checksums))
+}
+
+/// Pin the gems of the `Gemfile.lock` of `[ruby]`
+pub async fn pin(ruby: &RubyManifest, manifest_dir: &Path, lockfile: &mut Lockfile) -> Result<()> {
+    let path = manifest_dir.join(&ruby.gemfile_lock);
+    let buf = fs::read_to_string(&path)
+        .await
+        .with_context(|| anyhow!("Failed to read gem lockfile: {path:?}"))?;
+    let (gems, checksums) =
+        parse_gemfile_lock(&buf).with_context(|| anyhow!("Failed to parse {path:?}"))?;
+
+    let _phase = summary::phase("download");
+    let client = http::Client::new()?;
+    for gem in gems {
+        if !gem.is_native() {
+            debug!(
+                "Skipping gem {:?} for platform {:?}",
+                gem.name, gem.platform
+            );
+            continue;
+        }
+        let mut lock = GemLock {
+            name: gem.name.clone(),
+            version: gem.version.clone(),
+            platform: gem.platform.clone(),
+            url: gem.url(),
+            sha256: String::new(),
+            size: 0,
+        };
+        let filename = lock.filename();
+        if !manifest::valid_file_name(&filename) {
+            bail!("Invalid name for gem in Gemfile.lock: {filename:?}");
+        }
+        let key = filename.trim_end_matches(".gem");
+        let expected = checksums.get(key).map(String::as_str);
+        let pin = fetch::pin_file(&client, &filename, &lock.url, expected)
+            .await
+            .with_context(|| anyhow!("Failed to pin gem {filename:?}"))?;
+        debug!("Pinned gem {filename:?} with sha256 {:?}", pin.sha256);
+        lock.sha256 = pin.sha256;
+        lock.size = pin.size;
+        lockfile.gems.push(lock);
+    }
+    Ok(())
+}
+
+/// Download and verify the `[[gem]]` pins of the lockfile, each of them is
+/// mounted read-only into /extra/gems/
+pub async fn stage(gems: Vec<GemLock>, mounts: &mut Vec<Mount>) -> Result<()> {
+    if gems.is_empty() {
+        return Ok(());
+    }
+    let files = gems.iter().map(GemLock::file).collect::<Vec<_>>();
+    for file in &files {
+        if !manifest::valid_file_name(&file.name) {
+            bail!("Invalid filename for gem in lockfile: {:?}", file.name);
+        }
+    }
+
+    {
+        let _phase = summary::phase("download");
+        fetch::download_files(&files).await?;
+    }
+    let files = {
+        let _phase = summary::phase("verify");
+        utils::spawn_blocking(move |_| {
+            fetch::verify_files(&files)?;
+            Ok(files)
+        })
+        .await?
+    };
+
+    let pkgs_cache_dir = paths::pkgs_cache_dir()?;
+    for file in files {
+        let source = pkgs_cache_dir.sha256_path(&file.sha256)?;
+        mounts.push(Mount::read_only(
+            source,
+            format!("{GEM_CACHE}/{}", file.name),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_gemfile_lock() -> Result<()> {
+        let (gems, checksums) = parse_gemfile_lock(
+            "GIT
+  remote: https://github.com/rails/rails.git
+  revision: 0123456789abcdef0123456789abcdef01234567
+  specs:
+    rails (7.2.0.alpha)
+
+GEM
+  remote: https://rubygems.org/
+  specs:
+    mini_portile2 (2.8.5)
+    nokogiri (1.16.2)
+      mini_portile2 (~> 2.8.2)
+      racc (~> 1.4)
+    nokogiri (1.16.2-x86_64-linux)
+      racc (~> 1.4)
+    racc (1.7.3)
+
+PLATFORMS
+  ruby
+  x86_64-linux
+
+DEPENDENCIES
+  nokogiri
+
+CHECKSUMS
+  mini_portile2 (2.8.5) sha256=7a37db8ae758086c3c3ac3a59c036704d331e965d5e106635e4a42d6e66089ce
+  nokogiri (1.16.2-x86_64-linux) sha256=7ab4da7dc5af4a1e1fbc5a82ad9e8bb4b7e3a5aea4c0a19f53aaa3d2c2a2fc9e
+  rails (7.2.0.alpha)
+
+BUNDLED WITH
+   2.5.6
+",
+        )?;
+        assert_eq!(
+            gems.iter()
+                .map(|g| (g.name.as_str(), g.version.as_str(), g.platform.as_deref()))
+                .collect::<Vec<_>>(),
+            [
+                ("mini_portile2", "2.8.5", None),
+                ("nokogiri", "1.16.2", None),
+                ("nokogiri", "1.16.2", Some("x86_64-linux")),
+                ("racc", "1.7.3", None),
+            ]
+        );
+        assert_eq!(
+            gems[2].url(),
+            "https://rubygems.org/gems/nokogiri-1.16.2-x86_64-linux.gem"
+        );
+        assert_eq!(
+            checksums.get("mini_portile2-2.8.5").map(String::as_str),
+            Some("7a37db8ae758086c3c3ac3a59c036704d331e965d5e106635e4a42d6e66089ce")
+        );
+        assert!(checksums.contains_key("nokogiri-1.16.2-x86_64-linux"));
+        assert_eq!(checksums.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_platform() {
+        assert_eq!(split_platform("13.1.0"), ("13.1.0", None));
+        assert_eq!(split_platform("1.6.3-ruby"), ("1.6.3", None));
+        assert_eq!(
+            split_platform("1.16.2-aarch64-linux-musl"),
+            ("1.16.2", Some("aarch64-linux-musl"))
+        );
+    }
+}
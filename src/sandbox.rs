@@ -0,0 +1,118 @@
+//! Parse untrusted package data in a child process with reduced privileges.
+//! The child runs in its own user and network namespace, can't open any new
+//! files and is killed if it spends too much cpu time.
+use crate::args;
+use crate::errors::*;
+use crate::limits;
+use crate::pkgs::{self, FileList, Pkg};
+use nix::sched::CloneFlags;
+use nix::sys::resource::{self, Resource};
+use serde::de::DeserializeOwned;
+use std::io::{self, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+
+/// Set `REPRO_ENV_NO_SANDBOX=1` to parse packages in-process
+pub fn is_enabled() -> bool {
+    if cfg!(test) {
+        // the test binary can't act as the child process
+        return false;
+    }
+    !std::env::var("REPRO_ENV_NO_SANDBOX")
+        .map(|x| x != "0")
+        .unwrap_or(false)
+}
+
+/// Parse and verify the embedded metadata of a package
+pub fn inspect<R: Read + Send>(system: &str, pkg: R) -> Result<Pkg> {
+    if !is_enabled() {
+        return pkgs::inspect(system, pkg);
+    }
+    run(&[system], pkg)
+}
+
+/// List the files that are installed by a package
+pub fn list_files<R: Read + Send>(system: &str, pkg: R) -> Result<FileList> {
+    if !is_enabled() {
+        return pkgs::list_files(system, pkg);
+    }
+    run(&[system, "--list-files"], pkg)
+}
+
+fn run<T: DeserializeOwned, R: Read + Send>(args: &[&str], mut input: R) -> Result<T> {
+    let exe = std::env::current_exe().context("Failed to detect path of repro-env executable")?;
+    let mut cmd = Command::new(exe);
+    cmd.arg("inspect-package")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // SAFETY: unshare is a single syscall and doesn't allocate
+    unsafe {
+        cmd.pre_exec(|| {
+            let flags =
+                CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNET | CloneFlags::CLONE_NEWIPC;
+            nix::sched::unshare(flags).map_err(io::Error::from)
+        });
+    }
+
+    debug!("Spawning sandboxed package parser: {cmd:?}");
+    let mut child = cmd.spawn().context(
+        "Failed to start sandboxed package parser (set REPRO_ENV_NO_SANDBOX=1 to parse in-process)",
+    )?;
+    let mut stdin = child.stdin.take().context("Failed to get stdin of child")?;
+    let output = std::thread::scope(|s| {
+        // if the child exits early, the error is reported through its exit status
+        s.spawn(move || io::copy(&mut input, &mut stdin));
+        child.wait_with_output()
+    })?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!(
+            "Sandboxed package parser failed ({}): {}",
+            output.status,
+            stderr.trim()
+        );
+    }
+    serde_json::from_slice(&output.stdout).context("Failed to decode output of package parser")
+}
+
+/// Prevent the child from opening files or sockets, writing to disk and running forever
+fn restrict() -> Result<()> {
+    resource::setrlimit(Resource::RLIMIT_NOFILE, 0, 0)?;
+    resource::setrlimit(Resource::RLIMIT_FSIZE, 0, 0)?;
+    let cpu = limits::PACKAGE.timeout.as_secs();
+    resource::setrlimit(Resource::RLIMIT_CPU, cpu, cpu)?;
+    Ok(())
+}
+
+/// Entrypoint of the child process, reads the package from stdin and writes json to stdout
+pub fn child(args: &args::InspectPackage) -> Result<()> {
+    restrict().context("Failed to restrict package parser")?;
+
+    let json = if args.list_files {
+        serde_json::to_vec(&pkgs::list_files(&args.system, io::stdin())?)?
+    } else {
+        serde_json::to_vec(&pkgs::inspect(&args.system, io::stdin())?)?
+    };
+    io::stdout().write_all(&json)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_parse_child_args() {
+        let args =
+            args::Args::parse_from(["repro-env", "inspect-package", "debian", "--list-files"]);
+        let Some(args::SubCommand::InspectPackage(inspect)) = args.subcommand else {
+            panic!("Unexpected subcommand: {:?}", args.subcommand);
+        };
+        assert_eq!(inspect.system, "debian");
+        assert!(inspect.list_files);
+    }
+}
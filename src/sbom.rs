@@ -0,0 +1,383 @@
+//! Generate a CycloneDX SBOM of the environment. If the base image has a
+//! CycloneDX SBOM attached in its registry (OCI referrers), its components are
+//! merged with the locked packages.
+use crate::args;
+use crate::container::{self, ImageRef};
+use crate::errors::*;
+use crate::freshness::{self, Repository};
+use crate::http;
+use crate::lockfile::{Lockfile, PackageLock};
+use crate::purl;
+use reqwest::header::ACCEPT;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::path::Path;
+
+const INDEX_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+const MANIFEST_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const IN_TOTO_TYPE: &str = "application/vnd.in-toto+json";
+const CYCLONEDX_PREDICATE: &str = "https://cyclonedx.org/bom";
+
+#[derive(Debug, Deserialize)]
+struct ImageIndex {
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageManifest {
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    pub digest: String,
+    #[serde(default)]
+    pub artifact_type: Option<String>,
+    #[serde(default)]
+    pub annotations: serde_json::Map<String, Value>,
+}
+
+impl Descriptor {
+    /// If this referrer is a CycloneDX SBOM, either plain or as in-toto attestation
+    pub fn is_cyclonedx(&self) -> bool {
+        match self.artifact_type.as_deref() {
+            Some(ty) if ty.starts_with("application/vnd.cyclonedx") => ty.ends_with("json"),
+            Some(IN_TOTO_TYPE) => {
+                self.annotations.get("in-toto.io/predicate-type")
+                    == Some(&Value::from(CYCLONEDX_PREDICATE))
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The components of a CycloneDX document, the document may be wrapped in an in-toto statement
+pub fn extract_components(buf: &[u8]) -> Result<Vec<Value>> {
+    let document = serde_json::from_slice::<Value>(buf).context("Failed to parse image SBOM")?;
+    let bom = match document.get("predicate") {
+        Some(predicate) => predicate,
+        None => &document,
+    };
+    if bom.get("bomFormat").and_then(Value::as_str) != Some("CycloneDX") {
+        bail!("Image SBOM is not a CycloneDX document");
+    }
+    match bom.get("components") {
+        Some(Value::Array(components)) => Ok(components.clone()),
+        Some(_) => bail!("Components of image SBOM are not a list"),
+        None => Ok(vec![]),
+    }
+}
+
+fn blob_sha256_matches(buf: &[u8], digest: &str) -> bool {
+    digest.strip_prefix("sha256:") == Some(&hex::encode(Sha256::digest(buf)))
+}
+
+pub async fn fetch_json<T: for<'de> Deserialize<'de>>(
+    client: &http::Client,
+    url: &str,
+    accept: &str,
+) -> Result<Option<T>> {
+    let response = freshness::send(client, |client| client.get(url).header(ACCEPT, accept)).await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let response = response.error_for_status().context("Received http error")?;
+    let buf = response.bytes().await.context("Failed to read http body")?;
+    let value = serde_json::from_slice(&buf)
+        .with_context(|| anyhow!("Failed to parse registry response: {url:?}"))?;
+    Ok(Some(value))
+}
+
+pub async fn fetch_blob(client: &http::Client, repo: &Repository, digest: &str) -> Result<Vec<u8>> {
+    let url = format!("https://{}/v2/{}/blobs/{digest}", repo.host, repo.name);
+    let response = freshness::send(client, |client| client.get(&url))
+        .await?
+        .error_for_status()
+        .context("Received http error")?;
+    let buf = response.bytes().await.context("Failed to read http body")?;
+    if !blob_sha256_matches(&buf, digest) {
+        bail!("Blob downloaded from registry doesn't match digest {digest:?}");
+    }
+    Ok(buf.to_vec())
+}
+
+/// Look up the SBOMs attached to an image with the OCI referrers api, returns
+/// the components of all CycloneDX SBOMs that were found
+pub async fn image_components(client: &http::Client, image: &str) -> Result<Vec<Value>> {
+    let image_ref = image.parse::<ImageRef>()?;
+    let digest = image_ref
+        .digest
+        .as_deref()
+        .with_context(|| anyhow!("Image in lockfile is not pinned by digest: {image:?}"))?;
+    let repo = Repository::parse(&image_ref.repo);
+
+    let url = format!("https://{}/v2/{}/referrers/{digest}", repo.host, repo.name);
+    debug!("Requesting referrers of image: {url:?}");
+    let Some(index) = fetch_json::<ImageIndex>(client, &url, INDEX_TYPE).await? else {
+        info!("Registry doesn't list referrers of {image:?}, no image SBOM to merge");
+        return Ok(vec![]);
+    };
+
+    let mut components = Vec::new();
+    for referrer in index.manifests.iter().filter(|d| d.is_cyclonedx()) {
+        info!("Fetching image SBOM {:?}...", referrer.digest);
+        let url = format!(
+            "https://{}/v2/{}/manifests/{}",
+            repo.host, repo.name, referrer.digest
+        );
+        let manifest = fetch_json::<ImageManifest>(client, &url, MANIFEST_TYPE)
+            .await?
+            .with_context(|| anyhow!("Referrer of image not found: {:?}", referrer.digest))?;
+        for layer in &manifest.layers {
+            let buf = fetch_blob(client, &repo, &layer.digest).await?;
+            components.extend(extract_components(&buf)?);
+        }
+    }
+    if components.is_empty() {
+        info!("No CycloneDX SBOM is attached to {image:?}");
+    }
+    Ok(components)
+}
+
+/// Compare purls without qualifiers, e.g. `distro=debian-12` added by image scanners
+fn purl_key(purl: &str) -> &str {
+    purl.split(['?', '#']).next().unwrap_or_default()
+}
+
+fn package_component(pkg: &PackageLock) -> Value {
+    let purl = pkg.purl();
+    let mut component = json!({
+        "type": "library",
+        "bom-ref": purl.clone().unwrap_or_else(|| format!("{}@{}", pkg.name, pkg.version)),
+        "name": pkg.name,
+        "version": pkg.version,
+    });
+    // flatpak runtimes are pinned by OSTree commit, not by the sha256 of a file
+    if !pkg.sha256.is_empty() {
+        component["hashes"] = json!([{"alg": "SHA-256", "content": pkg.sha256}]);
+    }
+    if let Some(purl) = purl {
+        component["purl"] = Value::from(purl);
+    }
+    component
+}
+
+/// Build the SBOM of a lockfile, with the components of the image SBOM that
+/// aren't locked packages themselves
+pub fn generate(lockfile: &Lockfile, image_components: Vec<Value>) -> Value {
+    let packages = lockfile
+        .packages
+        .iter()
+        .map(package_component)
+        .collect::<Vec<_>>();
+    let locked = packages
+        .iter()
+        .flat_map(|c| [c.get("purl"), c.get("bom-ref")])
+        .flatten()
+        .filter_map(Value::as_str)
+        .map(purl_key)
+        .collect::<BTreeSet<_>>();
+
+    let mut components = image_components
+        .iter()
+        .filter(|c| {
+            ![c.get("purl"), c.get("bom-ref")]
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+                .any(|id| locked.contains(purl_key(id)))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    components.extend(packages.iter().cloned());
+
+    let image = &lockfile.container.image;
+    let mut metadata = json!({
+        "tools": {"components": [{
+            "type": "application",
+            "name": "repro-env",
+            "version": env!("CARGO_PKG_VERSION"),
+        }]},
+        "component": {
+            "type": "container",
+            "bom-ref": image,
+            "name": image.split_once('@').map(|(repo, _)| repo).unwrap_or(image),
+        },
+    });
+    if let Some((_, digest)) = image.split_once('@') {
+        metadata["component"]["version"] = Value::from(digest);
+    }
+    if let Some(purl) = purl::oci(image) {
+        metadata["component"]["purl"] = Value::from(purl);
+    }
+
+    json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "version": 1,
+        "metadata": metadata,
+        "components": components,
+    })
+}
+
+/// Generate the SBOM of a lockfile, merged with the SBOM of the base image
+/// unless `no_image_sbom` is set. A missing image SBOM is not an error.
+pub async fn document(lockfile: &Lockfile, no_image_sbom: bool) -> Result<Value> {
+    let image = &lockfile.container.image;
+    let image_components = if no_image_sbom {
+        vec![]
+    } else if container::archive_path(image).is_some() {
+        debug!("Image is loaded from an archive, skipping image SBOM lookup");
+        vec![]
+    } else {
+        let client = http::Client::new()?;
+        match image_components(&client, image).await {
+            Ok(components) => components,
+            Err(err) => {
+                warn!("Failed to fetch SBOM of base image: {err:#}");
+                vec![]
+            }
+        }
+    };
+    if !image_components.is_empty() {
+        info!(
+            "Merging {} components from the SBOM of the base image",
+            image_components.len()
+        );
+    }
+    Ok(generate(lockfile, image_components))
+}
+
+pub async fn sbom(sbom: &args::Sbom) -> Result<()> {
+    let path = sbom.file.as_deref().unwrap_or(Path::new("repro-env.lock"));
+    let lockfile = Lockfile::read_from_file(path).await?;
+    let document = document(&lockfile, sbom.no_image_sbom).await?;
+    println!("{}", serde_json::to_string_pretty(&document)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lockfile::ContainerLock;
+    use crate::manifest::Init;
+    use std::collections::BTreeMap;
+
+    fn lockfile() -> Lockfile {
+        Lockfile {
+            min_repro_env_version: None,
+            install_order: vec![],
+            container: ContainerLock {
+                image: "docker.io/library/debian@sha256:3d868b5eb908155f3784317b3dda2941df87bbbbaa4608f84881de66d9bb297b".to_string(),
+                sha256: None,
+                init_sha256: None,
+                init: Init::Catatonit,
+                containerfile: None,
+            },
+            meta: None,
+            policy: BTreeMap::new(),
+            keys: vec![],
+            apk_keys: vec![],
+            packages: vec![PackageLock {
+                name: "curl".to_string(),
+                version: "7.88.1-10".to_string(),
+                system: "debian".to_string(),
+                url: "https://snapshot.debian.org/archive/debian/20230612T000000Z/pool/main/c/curl/curl_7.88.1-10_amd64.deb".to_string(),
+                sha256: "aa".repeat(32),
+                ..Default::default()
+            }],
+            profiles: BTreeMap::new(),
+            files: vec![],
+            git: vec![],
+            toolchains: vec![],
+            maven: vec![],
+            gems: vec![],
+            composer: vec![],
+            cpan: vec![],
+            haskell: None,
+            texlive: None,
+        }
+    }
+
+    #[test]
+    fn test_is_cyclonedx() -> Result<()> {
+        let index = serde_json::from_str::<ImageIndex>(
+            r#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.index.v1+json","manifests":[
+  {"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"sha256:aa","size":1,"artifactType":"application/vnd.cyclonedx+json"},
+  {"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"sha256:bb","size":1,"artifactType":"application/spdx+json"},
+  {"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"sha256:cc","size":1,"artifactType":"application/vnd.in-toto+json","annotations":{"in-toto.io/predicate-type":"https://cyclonedx.org/bom"}},
+  {"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"sha256:dd","size":1,"artifactType":"application/vnd.in-toto+json","annotations":{"in-toto.io/predicate-type":"https://slsa.dev/provenance/v1"}}
+]}"#,
+        )?;
+        let digests = index
+            .manifests
+            .iter()
+            .filter(|d| d.is_cyclonedx())
+            .map(|d| d.digest.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(digests, ["sha256:aa", "sha256:cc"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_components() -> Result<()> {
+        let bom = br#"{"bomFormat":"CycloneDX","specVersion":"1.5","components":[{"type":"library","name":"libc6","version":"2.36-9"}]}"#;
+        assert_eq!(
+            extract_components(bom)?,
+            [json!({"type":"library","name":"libc6","version":"2.36-9"})]
+        );
+
+        let statement = br#"{"_type":"https://in-toto.io/Statement/v1","predicateType":"https://cyclonedx.org/bom","predicate":{"bomFormat":"CycloneDX","components":[{"name":"libc6"}]}}"#;
+        assert_eq!(extract_components(statement)?, [json!({"name":"libc6"})]);
+
+        assert!(extract_components(br#"{"spdxVersion":"SPDX-2.3"}"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_blob_sha256_matches() {
+        let digest = "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        assert!(blob_sha256_matches(b"hello", digest));
+        assert!(!blob_sha256_matches(b"world", digest));
+        assert!(!blob_sha256_matches(b"hello", "sha512:00"));
+    }
+
+    #[test]
+    fn test_generate() {
+        let image_components = vec![
+            json!({"type":"library","bom-ref":"pkg:deb/debian/libc6@2.36-9?arch=amd64&distro=debian-12","name":"libc6","version":"2.36-9","purl":"pkg:deb/debian/libc6@2.36-9?arch=amd64&distro=debian-12"}),
+            json!({"type":"library","bom-ref":"pkg:deb/debian/curl@7.88.1-10?distro=debian-12","name":"curl","version":"7.88.1-10","purl":"pkg:deb/debian/curl@7.88.1-10?distro=debian-12"}),
+        ];
+        let document = generate(&lockfile(), image_components);
+        assert_eq!(document["bomFormat"], "CycloneDX");
+        assert_eq!(
+            document["metadata"]["component"]["purl"],
+            "pkg:oci/debian@sha256%3A3d868b5eb908155f3784317b3dda2941df87bbbbaa4608f84881de66d9bb297b?repository_url=docker.io/library/debian"
+        );
+        assert_eq!(
+            document["metadata"]["component"]["name"],
+            "docker.io/library/debian"
+        );
+
+        // the locked curl replaces the one from the image SBOM
+        let components = document["components"].as_array().unwrap();
+        let purls = components
+            .iter()
+            .map(|c| c["purl"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            purls,
+            [
+                "pkg:deb/debian/libc6@2.36-9?arch=amd64&distro=debian-12",
+                "pkg:deb/debian/curl@7.88.1-10?arch=amd64",
+            ]
+        );
+        assert_eq!(components[1]["hashes"][0]["content"], "aa".repeat(32));
+    }
+}
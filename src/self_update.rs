@@ -0,0 +1,144 @@
+//! Replace the running binary with a signed release, for users who installed
+//! the static binary outside of a package manager
+use crate::args::SelfUpdate;
+use crate::errors::*;
+use crate::http;
+use crate::pgp;
+use crate::utils;
+use sequoia_openpgp::cert::CertParser;
+use sequoia_openpgp::parse::Parse;
+use sequoia_openpgp::Cert;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::env;
+
+const LATEST_RELEASE_URL: &str = "https://api.github.com/repos/kpcyrd/repro-env/releases/latest";
+const DOWNLOAD_URL: &str = "https://github.com/kpcyrd/repro-env/releases/download";
+
+/// Armored OpenPGP certificates that release binaries are signed with
+const RELEASE_KEYS: &str = include_str!("../contrib/release-keys.asc");
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+}
+
+/// The name of the release binary for this architecture
+pub fn asset_name(arch: &str) -> String {
+    format!("repro-env-{arch}-unknown-linux-musl")
+}
+
+pub fn parse_release_keys(keys: &str) -> Result<Vec<Cert>> {
+    if keys.trim().is_empty() {
+        bail!("No release signing keys have been configured");
+    }
+    let certs = CertParser::from_bytes(keys.as_bytes())?
+        .collect::<sequoia_openpgp::Result<Vec<_>>>()
+        .context("Failed to parse release signing keys")?;
+    if certs.is_empty() {
+        bail!("No release signing keys have been configured");
+    }
+    Ok(certs)
+}
+
+fn release_keys() -> Result<Vec<Cert>> {
+    parse_release_keys(RELEASE_KEYS)
+        .context("This build of repro-env has no usable release signing keys, update it with the tool it was installed with")
+}
+
+/// Compare two release versions, pre-releases sort before the release
+pub fn compare_versions(a: &str, b: &str) -> Result<Ordering> {
+    let parse =
+        |v| semver::Version::parse(v).with_context(|| anyhow!("Invalid release version: {v:?}"));
+    Ok(parse(a)?.cmp(&parse(b)?))
+}
+
+async fn latest_version(client: &http::Client) -> Result<String> {
+    let buf = client.fetch(LATEST_RELEASE_URL).await?;
+    let release =
+        serde_json::from_slice::<Release>(&buf).context("Failed to parse release information")?;
+    Ok(release.tag_name)
+}
+
+pub async fn self_update(update: &SelfUpdate) -> Result<()> {
+    // fail before doing any network requests if we can't verify the download
+    let certs = release_keys()?;
+    let client = http::Client::new()?;
+
+    let version = match &update.version {
+        Some(version) => version.clone(),
+        None => latest_version(&client).await?,
+    };
+    let version = version.strip_prefix('v').unwrap_or(&version);
+    let current = env!("CARGO_PKG_VERSION");
+
+    match compare_versions(version, current)? {
+        Ordering::Equal if !update.force => {
+            info!("repro-env {current} is already up-to-date");
+            return Ok(());
+        }
+        Ordering::Less if update.version.is_none() && !update.force => {
+            info!("repro-env {current} is newer than the latest release ({version}), not updating");
+            return Ok(());
+        }
+        _ => (),
+    }
+
+    if update.check {
+        println!("{current} -> {version}");
+        return Ok(());
+    }
+
+    let url = format!(
+        "{DOWNLOAD_URL}/v{version}/{}",
+        asset_name(env::consts::ARCH)
+    );
+    let binary = client.fetch(&url).await?;
+    let sig = client.fetch(&format!("{url}.asc")).await?;
+    pgp::verify_detached(&binary, &sig, &certs)
+        .with_context(|| anyhow!("Failed to verify signature of release binary: {url:?}"))?;
+    info!(
+        "Verified signature of release binary (sha256:{})",
+        hex::encode(Sha256::digest(&binary))
+    );
+
+    let exe = env::current_exe().context("Failed to locate the running executable")?;
+    utils::atomic_write(&exe, &binary)
+        .with_context(|| anyhow!("Failed to replace executable: {exe:?}"))?;
+    info!("Updated {exe:?} from repro-env {current} to {version}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sequoia_openpgp::serialize::SerializeInto;
+
+    #[test]
+    fn test_asset_name() {
+        assert_eq!(asset_name("x86_64"), "repro-env-x86_64-unknown-linux-musl");
+    }
+
+    #[test]
+    fn test_parse_release_keys() -> Result<()> {
+        let (cert, _) = sequoia_openpgp::cert::CertBuilder::general_purpose(None, Some("release"))
+            .generate()?;
+        let armored = String::from_utf8(cert.armored().to_vec()?)?;
+        let certs = parse_release_keys(&armored)?;
+        assert_eq!(certs.len(), 1);
+        assert_eq!(certs[0].fingerprint(), cert.fingerprint());
+
+        assert!(parse_release_keys("").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare_versions() -> Result<()> {
+        assert_eq!(compare_versions("1.2.0-rc1", "1.2.0")?, Ordering::Less);
+        assert_eq!(compare_versions("1.10.0", "1.9.1")?, Ordering::Greater);
+        assert_eq!(compare_versions("0.4.3", "0.4.3")?, Ordering::Equal);
+        assert!(compare_versions("latest", "0.4.3").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+//! Sign the artifacts of a successful build as configured in `[build.sign]`,
+//! the signatures are written next to the artifacts
+use crate::errors::*;
+use crate::manifest::{SignManifest, SignMethod};
+use crate::pgp;
+use crate::utils;
+use sequoia_openpgp::parse::Parse;
+use sequoia_openpgp::Cert;
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::process;
+
+/// The password of an encrypted OpenPGP signing key
+pub const PASSWORD_ENV: &str = "REPRO_ENV_SIGNING_PASSWORD";
+
+/// The path of the signature of an artifact, e.g. `hello.tar.gz.asc`
+pub fn signature_path(artifact: &Path, method: SignMethod) -> PathBuf {
+    let mut path = artifact.as_os_str().to_owned();
+    path.push(".");
+    path.push(method.extension());
+    PathBuf::from(path)
+}
+
+/// The command that signs an artifact with an external tool, None for methods that are built-in
+pub fn sign_command(
+    method: SignMethod,
+    key: Option<&Path>,
+    artifact: &Path,
+    sig: &Path,
+) -> Option<Vec<OsString>> {
+    let mut cmd = Vec::<OsString>::new();
+    match method {
+        SignMethod::Openpgp => return None,
+        SignMethod::Minisign => {
+            cmd.extend(["minisign".into(), "-S".into()]);
+            if let Some(key) = key {
+                cmd.extend(["-s".into(), key.into()]);
+            }
+            cmd.extend(["-m".into(), artifact.into(), "-x".into(), sig.into()]);
+        }
+        SignMethod::Cosign => {
+            cmd.extend(["cosign".into(), "sign-blob".into(), "--yes".into()]);
+            if let Some(key) = key {
+                cmd.extend(["--key".into(), key.into()]);
+            }
+            cmd.extend(["--bundle".into(), sig.into(), artifact.into()]);
+        }
+    }
+    Some(cmd)
+}
+
+async fn sign_openpgp(key: &Path, artifact: &Path, sig: &Path) -> Result<()> {
+    let buf = fs::read(key)
+        .await
+        .with_context(|| anyhow!("Failed to read signing key: {key:?}"))?;
+    let cert = Cert::from_bytes(&buf)
+        .with_context(|| anyhow!("Failed to parse OpenPGP secret key: {key:?}"))?;
+    let password = env::var(PASSWORD_ENV).ok();
+    let data = fs::read(artifact)
+        .await
+        .with_context(|| anyhow!("Failed to read artifact: {artifact:?}"))?;
+    let signature = pgp::sign_detached(&cert, password.as_deref(), &data)
+        .with_context(|| anyhow!("Failed to sign {artifact:?}"))?;
+    utils::atomic_write(sig, &signature)
+}
+
+async fn run(cmd: &[OsString]) -> Result<()> {
+    let Some((bin, args)) = cmd.split_first() else {
+        bail!("Command is empty");
+    };
+    debug!("Spawning child process: {cmd:?}");
+    // stdin is inherited, the tool may ask for a password
+    let status = process::Command::new(bin)
+        .args(args)
+        .status()
+        .await
+        .with_context(|| anyhow!("Failed to execute {bin:?}"))?;
+    if !status.success() {
+        bail!("Command {cmd:?} exited with {status}");
+    }
+    Ok(())
+}
+
+/// Sign every declared artifact, paths are relative to `dir`
+pub async fn sign_artifacts(sign: &SignManifest, dir: &Path) -> Result<()> {
+    let key = sign.key.as_ref().map(|key| dir.join(key));
+    for artifact in &sign.artifacts {
+        let artifact = dir.join(artifact);
+        if !artifact.is_file() {
+            bail!("Artifact to sign doesn't exist or is not a file: {artifact:?}");
+        }
+        let sig = signature_path(&artifact, sign.method);
+        info!(
+            "Signing {artifact:?} with {}: {sig:?}",
+            sign.method.as_str()
+        );
+        match sign_command(sign.method, key.as_deref(), &artifact, &sig) {
+            Some(cmd) => run(&cmd).await?,
+            None => {
+                let key = key
+                    .as_deref()
+                    .context("Signing with openpgp needs a key in [build.sign]")?;
+                sign_openpgp(key, &artifact, &sig).await?
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sequoia_openpgp::cert::CertBuilder;
+    use sequoia_openpgp::serialize::SerializeInto;
+
+    #[test]
+    fn test_signature_path() {
+        let path = Path::new("target/release/hello.tar.gz");
+        assert_eq!(
+            signature_path(path, SignMethod::Openpgp),
+            Path::new("target/release/hello.tar.gz.asc")
+        );
+        assert_eq!(
+            signature_path(path, SignMethod::Cosign),
+            Path::new("target/release/hello.tar.gz.sigstore.json")
+        );
+    }
+
+    #[test]
+    fn test_sign_command() {
+        let artifact = Path::new("out/hello");
+        let sig = signature_path(artifact, SignMethod::Minisign);
+        assert_eq!(
+            sign_command(
+                SignMethod::Minisign,
+                Some(Path::new("minisign.key")),
+                artifact,
+                &sig
+            ),
+            Some(
+                [
+                    "minisign",
+                    "-S",
+                    "-s",
+                    "minisign.key",
+                    "-m",
+                    "out/hello",
+                    "-x",
+                    "out/hello.minisig"
+                ]
+                .map(OsString::from)
+                .to_vec()
+            )
+        );
+        let sig = signature_path(artifact, SignMethod::Cosign);
+        assert_eq!(
+            sign_command(SignMethod::Cosign, None, artifact, &sig),
+            Some(
+                [
+                    "cosign",
+                    "sign-blob",
+                    "--yes",
+                    "--bundle",
+                    "out/hello.sigstore.json",
+                    "out/hello"
+                ]
+                .map(OsString::from)
+                .to_vec()
+            )
+        );
+        assert_eq!(
+            sign_command(SignMethod::Openpgp, None, artifact, &sig),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sign_artifacts_openpgp() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (cert, _) = CertBuilder::general_purpose(None, Some("release")).generate()?;
+        std::fs::write(
+            dir.path().join("release.key"),
+            cert.as_tsk().armored().to_vec()?,
+        )?;
+        std::fs::write(dir.path().join("hello"), b"hello world")?;
+
+        let sign = SignManifest {
+            method: SignMethod::Openpgp,
+            key: Some(PathBuf::from("release.key")),
+            artifacts: vec![PathBuf::from("hello")],
+        };
+        sign_artifacts(&sign, dir.path()).await?;
+        let sig = std::fs::read(dir.path().join("hello.asc"))?;
+        pgp::verify_detached(b"hello world", &sig, &[cert])?;
+
+        let sign = SignManifest {
+            artifacts: vec![PathBuf::from("missing")],
+            ..sign
+        };
+        assert!(sign_artifacts(&sign, dir.path()).await.is_err());
+        Ok(())
+    }
+}
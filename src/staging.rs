@@ -0,0 +1,428 @@
+use crate::container;
+use crate::errors::*;
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use std::collections::{HashMap, HashSet};
+use std::fs::FileTimes;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tempfile::TempDir;
+
+pub const PREFIX: &str = "env.";
+/// Every file and directory in the staging directory gets this modification time
+pub const MTIME: SystemTime = SystemTime::UNIX_EPOCH;
+
+fn lock_path(dir: &Path) -> PathBuf {
+    let mut path = dir.as_os_str().to_owned();
+    path.push(".lock");
+    PathBuf::from(path)
+}
+
+/// The container a staging directory has been handed over to with `persist`
+pub const LABEL_CONTAINER: &str = "io.repro-env.container";
+
+/// The temporary directory that is mounted to /extra. A lock is held on a
+/// sibling file for as long as the directory is in use, if the process gets
+/// killed the lock is released and `cleanup_stale` can remove the directory.
+#[derive(Debug)]
+pub struct StagingDir {
+    dir: Option<TempDir>,
+    lock_path: PathBuf,
+    lock: Flock<File>,
+}
+
+impl StagingDir {
+    /// Create `env.{name}.{n}` in `path`, with the lowest `n` that's not taken
+    /// yet, so the same content is staged in a directory with the same name.
+    /// The lock file is locked before the directory is created, so there's
+    /// never a directory of a running process without a locked lock file.
+    pub fn create(path: &Path, name: &str) -> Result<Self> {
+        fs::create_dir_all(path)
+            .with_context(|| anyhow!("Failed to create directory: {path:?}"))?;
+        let mut n = 0;
+        let (dir, lock_path, mut lock) = loop {
+            let prefix = format!("{PREFIX}{name}.{n}");
+            let dir = path.join(&prefix);
+            // don't add a lock file to a directory that is persisted or left behind
+            if dir.exists() {
+                n += 1;
+                continue;
+            }
+            let lock_path = lock_path(&dir);
+            let Some(lock) = try_lock(&lock_path, true)
+                .with_context(|| anyhow!("Failed to lock staging directory: {lock_path:?}"))?
+            else {
+                n += 1;
+                continue;
+            };
+            let result = tempfile::Builder::new()
+                .prefix(&prefix)
+                .rand_bytes(0)
+                .tempdir_in(path);
+            match result {
+                Ok(dir) => break (dir, lock_path, lock),
+                // persisted for a container, or left behind without a lock file
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => n += 1,
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| anyhow!("Failed to create staging directory in {path:?}"))
+                }
+            }
+        };
+
+        // record who created this directory, the same way containers are labeled
+        lock.set_len(0)?;
+        for (key, value) in container::labels(None) {
+            writeln!(lock, "{key}={value}")?;
+        }
+
+        Ok(StagingDir {
+            dir: Some(dir),
+            lock_path,
+            lock,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir
+            .as_ref()
+            .expect("Staging directory was already removed")
+            .path()
+    }
+
+    /// Keep the directory around for a container that outlives this process,
+    /// it's removed by `cleanup_orphaned` once the container doesn't exist anymore
+    pub fn persist(mut self, container: &str) -> Result<PathBuf> {
+        writeln!(self.lock, "{LABEL_CONTAINER}={container}")
+            .with_context(|| anyhow!("Failed to write lock file: {:?}", self.lock_path))?;
+        let dir = self
+            .dir
+            .take()
+            .expect("Staging directory was already removed");
+        Ok(dir.into_path())
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        let Some(dir) = self.dir.take() else {
+            // persisted, the lock file records which container uses the directory
+            return;
+        };
+        let path = dir.path().to_owned();
+        if let Err(err) = dir.close() {
+            warn!("Failed to remove staging directory {path:?}: {err:#}");
+        }
+        if let Err(err) = fs::remove_file(&self.lock_path) {
+            warn!("Failed to remove lock file {:?}: {err:#}", self.lock_path);
+        }
+    }
+}
+
+/// Lock the lock file without blocking, `None` if somebody else holds the
+/// lock or (unless `create` is set) there's no lock file
+fn try_lock(lock_path: &Path, create: bool) -> Result<Option<Flock<File>>> {
+    loop {
+        let file = match OpenOptions::new()
+            .read(true)
+            .write(create)
+            .create(create)
+            .truncate(false)
+            .open(lock_path)
+        {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound && !create => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let lock = match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+            Ok(lock) => lock,
+            Err((_, Errno::EWOULDBLOCK)) => return Ok(None),
+            Err((_, errno)) => return Err(errno.into()),
+        };
+        // the file may have been removed by `cleanup_stale` before it got locked
+        match fs::metadata(lock_path) {
+            Ok(metadata) if metadata.ino() == lock.metadata()?.ino() => return Ok(Some(lock)),
+            Ok(_) => continue,
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Lock a staging directory that isn't used anymore. Directories without lock
+/// file are skipped, they are either not set up by repro-env or the lock file
+/// of a running process is about to be removed together with the directory.
+fn lock_stale(dir: &Path) -> Result<Option<Flock<File>>> {
+    let Some(lock) = try_lock(&lock_path(dir), false)? else {
+        return Ok(None);
+    };
+    // persisted for a container, removed by `cleanup_orphaned` instead
+    if read_labels(dir).contains_key(LABEL_CONTAINER) {
+        return Ok(None);
+    }
+    Ok(Some(lock))
+}
+
+fn is_stale(dir: &Path) -> Result<bool> {
+    Ok(lock_stale(dir)?.is_some())
+}
+
+fn read_labels(dir: &Path) -> HashMap<String, String> {
+    let Ok(buf) = fs::read_to_string(lock_path(dir)) else {
+        return HashMap::new();
+    };
+    buf.lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+fn staging_dirs(path: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut dirs = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else { continue };
+        if name.starts_with(PREFIX) && entry.file_type()?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+#[derive(Debug)]
+pub struct StagingInfo {
+    pub path: PathBuf,
+    pub stale: bool,
+    pub labels: HashMap<String, String>,
+}
+
+/// List all staging directories in `path`, including the ones that are still in use
+pub fn list(path: &Path) -> Result<Vec<StagingInfo>> {
+    staging_dirs(path)?
+        .into_iter()
+        .map(|path| {
+            Ok(StagingInfo {
+                stale: is_stale(&path)?,
+                labels: read_labels(&path),
+                path,
+            })
+        })
+        .collect()
+}
+
+/// Give everything in the staging directory fixed permissions (0755 for
+/// directories, 0644 for files) and modification times, so tools that scan
+/// /extra don't see anything that depends on the host or when it was staged
+pub fn normalize(path: &Path) -> Result<()> {
+    let mut entries = fs::read_dir(path)
+        .with_context(|| anyhow!("Failed to read directory: {path:?}"))?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    entries.sort();
+    for entry in entries {
+        let file_type = fs::symlink_metadata(&entry)?.file_type();
+        if file_type.is_dir() {
+            normalize(&entry)?;
+        } else if file_type.is_file() {
+            set_fixed_metadata(&entry, 0o644)?;
+        }
+    }
+    set_fixed_metadata(path, 0o755)
+}
+
+fn set_fixed_metadata(path: &Path, mode: u32) -> Result<()> {
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .with_context(|| anyhow!("Failed to set permissions of {path:?}"))?;
+    let times = FileTimes::new().set_accessed(MTIME).set_modified(MTIME);
+    File::open(path)
+        .and_then(|file| file.set_times(times))
+        .with_context(|| anyhow!("Failed to set modification time of {path:?}"))?;
+    Ok(())
+}
+
+fn remove(dir: &Path) -> Result<()> {
+    fs::remove_dir_all(dir).with_context(|| anyhow!("Failed to remove directory: {dir:?}"))?;
+    let lock_path = lock_path(dir);
+    if let Err(err) = fs::remove_file(&lock_path) {
+        if err.kind() != ErrorKind::NotFound {
+            return Err(err).context("Failed to remove lock file");
+        }
+    }
+    Ok(())
+}
+
+/// Remove staging directories left behind by processes that didn't get to
+/// clean up after themselves, returns the number of removed directories
+pub fn cleanup_stale(path: &Path) -> Result<usize> {
+    let mut removed = 0;
+    for dir in staging_dirs(path)? {
+        // the lock is held while removing, so the name can't be taken again in between
+        let Some(_lock) = lock_stale(&dir)? else {
+            trace!("Staging directory is still in use: {dir:?}");
+            continue;
+        };
+
+        info!("Removing stale staging directory: {dir:?}");
+        remove(&dir)?;
+        removed += 1;
+    }
+
+    Ok(removed)
+}
+
+/// Remove the persisted staging directories of containers that don't exist
+/// anymore, returns the number of removed directories
+pub fn cleanup_orphaned(path: &Path, containers: &HashSet<&str>) -> Result<usize> {
+    let mut removed = 0;
+    for dir in staging_dirs(path)? {
+        let Some(container) = read_labels(&dir).remove(LABEL_CONTAINER) else {
+            continue;
+        };
+        if containers.contains(container.as_str()) {
+            trace!("Staging directory is used by container {container:?}: {dir:?}");
+            continue;
+        }
+
+        info!("Removing staging directory of removed container {container:?}: {dir:?}");
+        remove(&dir)?;
+        removed += 1;
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_path() {
+        assert_eq!(
+            lock_path(Path::new("/cache/repro-env/env.AbCdEf")),
+            Path::new("/cache/repro-env/env.AbCdEf.lock")
+        );
+    }
+
+    #[test]
+    fn test_stale_detection() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let staging = dir.path().join("env.foo");
+        fs::create_dir(&staging)?;
+
+        // no lock file, not set up by repro-env
+        assert!(!is_stale(&staging)?);
+        assert_eq!(cleanup_stale(dir.path())?, 0);
+
+        let file = File::create(lock_path(&staging))?;
+        let lock = Flock::lock(file, FlockArg::LockExclusiveNonblock).unwrap();
+        assert!(!is_stale(&staging)?);
+
+        drop(lock);
+        assert!(is_stale(&staging)?);
+
+        fs::write(
+            lock_path(&staging),
+            "io.repro-env.version=0.4.1\nio.repro-env.project=/home/user/project\n",
+        )?;
+        let labels = read_labels(&staging);
+        assert_eq!(
+            labels.get(container::LABEL_PROJECT).map(String::as_str),
+            Some("/home/user/project")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_in_dir() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let parent = dir.path().join("staging");
+
+        let staging = StagingDir::create(&parent, "context")?;
+        assert_eq!(staging.path(), parent.join("env.context.0"));
+        let dirs = list(&parent)?;
+        assert_eq!(dirs.len(), 1);
+        assert_eq!(dirs[0].path, staging.path());
+        assert!(!dirs[0].stale);
+        assert_eq!(cleanup_stale(&parent)?, 0);
+
+        // the name is taken as long as the directory exists
+        let second = StagingDir::create(&parent, "context")?;
+        assert_eq!(second.path(), parent.join("env.context.1"));
+        drop(second);
+
+        drop(staging);
+        assert!(list(&parent)?.is_empty());
+
+        // an unlocked lock file without directory is reused, a directory
+        // without lock file is left alone
+        fs::write(
+            parent.join("env.context.0.lock"),
+            "io.repro-env.version=0.4.1\n",
+        )?;
+        fs::create_dir(parent.join("env.context.1"))?;
+        let staging = StagingDir::create(&parent, "context")?;
+        assert_eq!(staging.path(), parent.join("env.context.0"));
+        let second = StagingDir::create(&parent, "context")?;
+        assert_eq!(second.path(), parent.join("env.context.2"));
+        assert_eq!(cleanup_stale(&parent)?, 0);
+        assert!(parent.join("env.context.1").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_persist() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let parent = dir.path().join("staging");
+
+        let staging = StagingDir::create(&parent, "context")?;
+        let path = staging.persist("aaaa")?;
+        assert!(path.exists());
+
+        // the lock is released, but the directory belongs to the container now
+        assert!(!is_stale(&path)?);
+        assert_eq!(cleanup_stale(&parent)?, 0);
+        assert_eq!(cleanup_orphaned(&parent, &HashSet::from(["aaaa"]))?, 0);
+        assert!(path.exists());
+
+        assert_eq!(cleanup_orphaned(&parent, &HashSet::new())?, 1);
+        assert!(!path.exists());
+        assert!(list(&parent)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path();
+        fs::write(path.join("b.pkg"), "b")?;
+        fs::set_permissions(path.join("b.pkg"), fs::Permissions::from_mode(0o600))?;
+        fs::create_dir(path.join("keys"))?;
+        fs::write(path.join("keys/a.asc"), "a")?;
+        fs::set_permissions(path.join("keys/a.asc"), fs::Permissions::from_mode(0o755))?;
+
+        normalize(path)?;
+        for (entry, mode) in [
+            (path.to_path_buf(), 0o755),
+            (path.join("keys"), 0o755),
+            (path.join("b.pkg"), 0o644),
+            (path.join("keys/a.asc"), 0o644),
+        ] {
+            let metadata = fs::metadata(&entry)?;
+            assert_eq!(metadata.permissions().mode() & 0o7777, mode, "{entry:?}");
+            assert_eq!(metadata.modified()?, MTIME, "{entry:?}");
+        }
+        Ok(())
+    }
+}
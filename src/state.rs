@@ -0,0 +1,262 @@
+//! Per-project state in `.repro-env/state.json` next to the lockfile, it
+//! remembers past builds and the containers that were created for them
+use crate::errors::*;
+use crate::summary::PackageSource;
+use crate::utils;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use time::format_description::well_known;
+use time::OffsetDateTime;
+
+pub const DIR: &str = ".repro-env";
+const STATE_FILENAME: &str = "state.json";
+const LOCK_FILENAME: &str = "state.lock";
+/// Number of builds that are kept in the history
+const HISTORY_LEN: usize = 20;
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct State {
+    /// Past builds, oldest first
+    #[serde(default)]
+    pub builds: Vec<BuildRecord>,
+    /// Containers that have been created for this project and may still exist
+    #[serde(default)]
+    pub containers: Vec<ContainerRecord>,
+    /// VEX documents that are used by `audit`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vex: Vec<VexRecord>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BuildRecord {
+    /// Unix timestamp of when the build was started
+    pub started: u64,
+    pub lockfile_digest: String,
+    pub cmd: Vec<String>,
+    pub success: bool,
+    pub container: Option<String>,
+    /// Whether each package was taken from the cache or downloaded
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub packages: Vec<PackageSource>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContainerRecord {
+    pub id: String,
+    pub lockfile_digest: String,
+    /// Unix timestamp of when the container was created
+    pub created: u64,
+    /// Created by `prepare` or `build --keep`, meant to be used with `exec`
+    pub kept: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VexRecord {
+    pub path: PathBuf,
+    /// The sha256 of the document when it was attached
+    pub sha256: String,
+}
+
+impl State {
+    pub fn deserialize(buf: &str) -> Result<Self> {
+        let state = serde_json::from_str(buf)?;
+        Ok(state)
+    }
+
+    fn read(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(buf) => Self::deserialize(&buf)
+                .with_context(|| anyhow!("Failed to parse project state: {path:?}")),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        let buf = serde_json::to_string_pretty(self)?;
+        utils::atomic_write(path, buf.as_bytes())
+    }
+
+    pub fn last_build(&self) -> Option<&BuildRecord> {
+        self.builds.last()
+    }
+
+    pub fn record_build(&mut self, build: BuildRecord) {
+        self.builds.push(build);
+        if self.builds.len() > HISTORY_LEN {
+            self.builds.drain(..self.builds.len() - HISTORY_LEN);
+        }
+    }
+
+    pub fn add_container(&mut self, container: ContainerRecord) {
+        self.remove_container(&container.id);
+        self.containers.push(container);
+    }
+
+    pub fn remove_container(&mut self, id: &str) {
+        self.containers.retain(|c| c.id != id);
+    }
+
+    pub fn attach_vex(&mut self, vex: VexRecord) {
+        self.detach_vex(&vex.path);
+        self.vex.push(vex);
+    }
+
+    /// Returns false if the document wasn't attached
+    pub fn detach_vex(&mut self, path: &Path) -> bool {
+        let len = self.vex.len();
+        self.vex.retain(|v| v.path != path);
+        self.vex.len() != len
+    }
+}
+
+/// The project directory is the one that contains the lockfile
+pub fn project_dir(lockfile: &Path) -> Result<PathBuf> {
+    let lockfile = std::path::absolute(lockfile)?;
+    let dir = lockfile
+        .parent()
+        .with_context(|| anyhow!("Lockfile has no parent directory: {lockfile:?}"))?;
+    Ok(dir.to_path_buf())
+}
+
+fn open_lock(dir: &Path) -> Result<fs::File> {
+    let path = dir.join(LOCK_FILENAME);
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| anyhow!("Failed to open lock: {path:?}"))?;
+    Ok(file)
+}
+
+/// Read the state of a project, a missing state file is treated as empty
+pub fn load(project: &Path) -> Result<State> {
+    State::read(&project.join(DIR).join(STATE_FILENAME))
+}
+
+/// Read the state and write it back after `f` has modified it
+pub async fn update<T, F: FnOnce(&mut State) -> T>(project: &Path, f: F) -> Result<T> {
+    let dir = project.join(DIR);
+    if !dir.exists() {
+        fs::create_dir(&dir)
+            .with_context(|| anyhow!("Failed to create state directory: {dir:?}"))?;
+        // the directory is machine specific and shouldn't be committed
+        fs::write(dir.join(".gitignore"), "*\n")?;
+    }
+    let _lock = utils::lock_exclusive(open_lock(&dir)?).await?;
+
+    let path = dir.join(STATE_FILENAME);
+    let mut state = State::read(&path)?;
+    let ret = f(&mut state);
+    state.write(&path)?;
+    Ok(ret)
+}
+
+/// Like [`update`] but only logs errors, the state is not essential for a build
+pub async fn record<F: FnOnce(&mut State)>(project: &Path, f: F) {
+    if let Err(err) = update(project, f).await {
+        warn!("Failed to update project state: {err:#}");
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn format_timestamp(timestamp: u64) -> String {
+    i64::try_from(timestamp)
+        .ok()
+        .and_then(|t| OffsetDateTime::from_unix_timestamp(t).ok())
+        .and_then(|t| t.format(&well_known::Rfc3339).ok())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(started: u64) -> BuildRecord {
+        BuildRecord {
+            started,
+            lockfile_digest: "sha256:00".to_string(),
+            cmd: vec!["make".to_string()],
+            success: true,
+            container: None,
+            packages: vec![],
+        }
+    }
+
+    fn container(id: &str) -> ContainerRecord {
+        ContainerRecord {
+            id: id.to_string(),
+            lockfile_digest: "sha256:00".to_string(),
+            created: 0,
+            kept: false,
+        }
+    }
+
+    #[test]
+    fn test_build_history() {
+        let mut state = State::default();
+        for i in 0..25 {
+            state.record_build(build(i));
+        }
+        assert_eq!(state.builds.len(), HISTORY_LEN);
+        assert_eq!(state.builds[0].started, 5);
+        assert_eq!(state.last_build().map(|b| b.started), Some(24));
+    }
+
+    #[test]
+    fn test_containers() {
+        let mut state = State::default();
+        state.add_container(container("a"));
+        state.add_container(container("b"));
+        state.add_container(container("a"));
+        assert_eq!(state.containers, [container("b"), container("a")]);
+
+        state.remove_container("b");
+        assert_eq!(state.containers, [container("a")]);
+    }
+
+    #[test]
+    fn test_vex() {
+        let vex = |path: &str, sha256: &str| VexRecord {
+            path: PathBuf::from(path),
+            sha256: sha256.to_string(),
+        };
+        let mut state = State::default();
+        state.attach_vex(vex("/src/a.vex.json", "00"));
+        state.attach_vex(vex("/src/b.vex.json", "11"));
+        state.attach_vex(vex("/src/a.vex.json", "22"));
+        assert_eq!(
+            state.vex,
+            [vex("/src/b.vex.json", "11"), vex("/src/a.vex.json", "22")]
+        );
+
+        assert!(state.detach_vex(Path::new("/src/b.vex.json")));
+        assert!(!state.detach_vex(Path::new("/src/b.vex.json")));
+        assert_eq!(state.vex, [vex("/src/a.vex.json", "22")]);
+    }
+
+    #[tokio::test]
+    async fn test_update_roundtrip() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        update(dir.path(), |state| state.record_build(build(1700000000))).await?;
+        assert!(dir.path().join(DIR).join(".gitignore").exists());
+
+        let state = load(dir.path())?;
+        assert_eq!(state.builds, [build(1700000000)]);
+        assert_eq!(
+            format_timestamp(state.builds[0].started),
+            "2023-11-14T22:13:20Z"
+        );
+        Ok(())
+    }
+}
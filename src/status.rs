@@ -0,0 +1,246 @@
+use crate::args;
+use crate::container::{self, Container};
+use crate::errors::*;
+use crate::lockfile::Lockfile;
+use crate::paths;
+use crate::state::{self, BuildRecord, ContainerRecord, State};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub project: PathBuf,
+    pub lockfile_digest: String,
+    pub last_build: Option<BuildRecord>,
+    pub containers: Vec<ContainerStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ContainerStatus {
+    #[serde(flatten)]
+    pub record: ContainerRecord,
+    /// As reported by podman, `removed` if the container doesn't exist anymore
+    pub state: String,
+}
+
+impl Status {
+    pub fn format_text(&self) -> String {
+        let mut out = String::new();
+        out.push_str(&format!("project:    {}\n", self.project.display()));
+        out.push_str(&format!("lockfile:   {}\n", self.lockfile_digest));
+
+        if let Some(build) = &self.last_build {
+            let result = if build.success { "success" } else { "failed" };
+            out.push_str(&format!(
+                "last build: {} ({result}): {}\n",
+                state::format_timestamp(build.started),
+                build.cmd.join(" ")
+            ));
+            if build.lockfile_digest != self.lockfile_digest {
+                out.push_str("            lockfile has changed since then\n");
+            }
+        } else {
+            out.push_str("last build: never\n");
+        }
+
+        if !self.containers.is_empty() {
+            out.push_str("containers:\n");
+            for c in &self.containers {
+                let id = c.record.id.get(..12).unwrap_or(&c.record.id);
+                let kept = if c.record.kept { " kept" } else { "" };
+                let outdated = if c.record.lockfile_digest != self.lockfile_digest {
+                    " (outdated lockfile)"
+                } else {
+                    ""
+                };
+                out.push_str(&format!(
+                    "  {id} {} created={}{kept}{outdated}\n",
+                    c.state,
+                    state::format_timestamp(c.record.created)
+                ));
+            }
+        }
+        out
+    }
+}
+
+/// Find the lockfile and project directory, the lockfile is loaded to get the current digest
+async fn load_project(file: Option<&Path>) -> Result<(PathBuf, String)> {
+    let path = match file {
+        Some(path) => path.to_path_buf(),
+        None => paths::find_project_file("repro-env.lock", true)?,
+    };
+    let lockfile = Lockfile::read_from_file(&path).await?;
+    Ok((state::project_dir(&path)?, lockfile.digest()))
+}
+
+/// The state of each container according to podman
+async fn container_states() -> Result<HashMap<String, String>> {
+    let list = container::list_containers().await?;
+    Ok(list.into_iter().map(|c| (c.id, c.state)).collect())
+}
+
+pub async fn status(status: &args::Status) -> Result<()> {
+    let (project, lockfile_digest) = load_project(status.file.as_deref()).await?;
+    let mut state = state::load(&project)?;
+
+    let known = match container_states().await {
+        Ok(known) => Some(known),
+        Err(err) => {
+            warn!("Failed to list containers: {err:#}");
+            None
+        }
+    };
+    let containers = state
+        .containers
+        .drain(..)
+        .map(|record| {
+            let state = match &known {
+                Some(known) => known
+                    .get(&record.id)
+                    .cloned()
+                    .unwrap_or_else(|| "removed".to_string()),
+                None => "unknown".to_string(),
+            };
+            ContainerStatus { record, state }
+        })
+        .collect();
+
+    let report = Status {
+        project,
+        lockfile_digest,
+        last_build: state.builds.pop(),
+        containers,
+    };
+    if status.json {
+        let json = serde_json::to_string_pretty(&report)?;
+        println!("{json}");
+    } else {
+        print!("{}", report.format_text());
+    }
+    Ok(())
+}
+
+/// Pick the newest kept container that is running, prefer the ones set up for the current lockfile
+fn select_container<'a>(
+    state: &'a State,
+    running: &HashSet<&str>,
+    lockfile_digest: &str,
+) -> Option<&'a ContainerRecord> {
+    state
+        .containers
+        .iter()
+        .filter(|c| c.kept && running.contains(c.id.as_str()))
+        .max_by_key(|c| (c.lockfile_digest == lockfile_digest, c.created))
+}
+
+pub async fn exec(exec: &args::Exec) -> Result<()> {
+    let (project, lockfile_digest) = load_project(exec.file.as_deref()).await?;
+    let state = state::load(&project)?;
+
+    let states = container_states().await?;
+    let running = states
+        .iter()
+        .filter(|(_, state)| *state == "running")
+        .map(|(id, _)| id.as_str())
+        .collect::<HashSet<_>>();
+    let Some(record) = select_container(&state, &running, &lockfile_digest) else {
+        bail!("No running container for this project, use `repro-env prepare` or `repro-env build --keep` first");
+    };
+    if record.lockfile_digest != lockfile_digest {
+        warn!(
+            "Container {:?} has been set up for a different version of the lockfile",
+            record.id
+        );
+    }
+
+    let cmd = if exec.cmd.is_empty() {
+        vec!["sh".to_string()]
+    } else {
+        exec.cmd.clone()
+    };
+    info!("Executing in container {:?}: {cmd:?}", record.id);
+    let container = Container {
+        id: record.id.clone(),
+    };
+    container
+        .exec(
+            &cmd,
+            container::Exec {
+                cwd: Some("/build"),
+                interactive: true,
+                ..Default::default()
+            },
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn container(id: &str, digest: &str, created: u64, kept: bool) -> ContainerRecord {
+        ContainerRecord {
+            id: id.to_string(),
+            lockfile_digest: digest.to_string(),
+            created,
+            kept,
+        }
+    }
+
+    #[test]
+    fn test_select_container() {
+        let state = State {
+            builds: vec![],
+            containers: vec![
+                container("old", "sha256:aa", 1, true),
+                container("current", "sha256:bb", 2, true),
+                container("newer", "sha256:aa", 3, true),
+                container("build", "sha256:bb", 4, false),
+                container("stopped", "sha256:bb", 5, true),
+            ],
+            vex: vec![],
+        };
+        let running = HashSet::from(["old", "current", "newer", "build"]);
+
+        let c = select_container(&state, &running, "sha256:bb");
+        assert_eq!(c.map(|c| c.id.as_str()), Some("current"));
+        let c = select_container(&state, &running, "sha256:cc");
+        assert_eq!(c.map(|c| c.id.as_str()), Some("newer"));
+        let c = select_container(&state, &HashSet::from(["build"]), "sha256:bb");
+        assert_eq!(c, None);
+    }
+
+    #[test]
+    fn test_format_status() {
+        let status = Status {
+            project: PathBuf::from("/home/user/project"),
+            lockfile_digest: "sha256:bb".to_string(),
+            last_build: Some(BuildRecord {
+                started: 1700000000,
+                lockfile_digest: "sha256:aa".to_string(),
+                cmd: vec!["make".to_string(), "test".to_string()],
+                success: false,
+                container: None,
+                packages: vec![],
+            }),
+            containers: vec![ContainerStatus {
+                record: container("0123456789abcdef", "sha256:aa", 1700000000, true),
+                state: "running".to_string(),
+            }],
+        };
+        assert_eq!(
+            status.format_text(),
+            "\
+project:    /home/user/project
+lockfile:   sha256:bb
+last build: 2023-11-14T22:13:20Z (failed): make test
+            lockfile has changed since then
+containers:
+  0123456789ab running created=2023-11-14T22:13:20Z kept (outdated lockfile)
+"
+        );
+    }
+}
@@ -0,0 +1,456 @@
+//! Run the `[[build.steps]]` of the manifest with `build --steps`. A step that
+//! declares inputs and outputs is skipped if neither its inputs nor the
+//! environment changed, its outputs are restored from the cache instead.
+use crate::container::{self, CommandError, Container};
+use crate::context;
+use crate::errors::*;
+use crate::jobs::{self, Job, JobCommand, JobResult};
+use crate::manifest::StepManifest;
+use crate::paths;
+use crate::utils;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+#[derive(Debug)]
+pub struct Steps<'a> {
+    pub steps: &'a [StepManifest],
+    /// The directory mounted as build directory, inputs and outputs are relative to it
+    pub src: &'a Path,
+    /// Where the outputs are cached, see [`crate::paths::steps_cache_dir`]
+    pub cache_dir: PathBuf,
+    pub lockfile_digest: &'a str,
+    /// If false, every step is executed (but the outputs are still cached)
+    pub use_cache: bool,
+}
+
+impl<'a> Steps<'a> {
+    pub fn new(
+        steps: &'a [StepManifest],
+        src: &'a Path,
+        lockfile_digest: &'a str,
+        use_cache: bool,
+    ) -> Result<Self> {
+        Ok(Steps {
+            steps,
+            src,
+            cache_dir: paths::steps_cache_dir()?,
+            lockfile_digest,
+            use_cache,
+        })
+    }
+}
+
+/// The files matching the input globs of the step (but not its outputs), sorted and relative to `src`
+pub fn input_files(src: &Path, step: &StepManifest) -> Result<Vec<PathBuf>> {
+    let mut overrides = ignore::overrides::OverrideBuilder::new(src);
+    for glob in &step.inputs {
+        overrides.add(glob).with_context(|| {
+            anyhow!("Invalid input glob of build step {:?}: {glob:?}", step.name)
+        })?;
+    }
+    for output in &step.outputs {
+        overrides.add(&format!("!/{output}"))?;
+        overrides.add(&format!("!/{output}/**"))?;
+    }
+    let overrides = overrides.build()?;
+
+    let mut walk = ignore::WalkBuilder::new(src);
+    walk.standard_filters(false).overrides(overrides);
+    let mut files = Vec::new();
+    for entry in walk.build() {
+        let entry = entry.context("Failed to walk build directory")?;
+        if entry.file_type().is_some_and(|t| t.is_file()) {
+            files.push(entry.path().strip_prefix(src)?.to_path_buf());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Identifies the outputs of a step by everything that may influence them
+pub fn cache_key(
+    src: &Path,
+    step: &StepManifest,
+    lockfile_digest: &str,
+    env: &[String],
+) -> Result<String> {
+    let mut lines = vec![
+        format!("environment {lockfile_digest}"),
+        format!("cmd {:?}", step.cmd),
+        format!("env {env:?}"),
+        format!("outputs {:?}", step.outputs),
+    ];
+    for file in input_files(src, step)? {
+        let sha256 = utils::sha256_file(&src.join(&file))?;
+        lines.push(format!("input {file:?} {sha256}"));
+    }
+
+    let mut hasher = Sha256::new();
+    for line in lines {
+        hasher.update(line.as_bytes());
+        hasher.update(b"\n");
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Copy a file or a directory with all of its content
+fn copy_tree(src: &Path, dest: &Path) -> Result<()> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut walk = ignore::WalkBuilder::new(src);
+    walk.standard_filters(false);
+    for entry in walk.build() {
+        let entry = entry.context("Failed to walk directory")?;
+        let path = entry.path();
+        let target = dest.join(path.strip_prefix(src)?);
+        let file_type = entry.file_type().context("Failed to detect file type")?;
+        context::copy_entry(path, &target, file_type)
+            .with_context(|| anyhow!("Failed to copy {path:?} to {target:?}"))?;
+    }
+    Ok(())
+}
+
+fn remove_path(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path)?,
+        Ok(_) => fs::remove_file(path)?,
+        Err(_) => (),
+    }
+    Ok(())
+}
+
+/// Copy the outputs of the step into the cache, they are moved into place once complete
+pub fn store(src: &Path, step: &StepManifest, dir: &Path) -> Result<()> {
+    let parent = dir.parent().context("Cache directory has no parent")?;
+    fs::create_dir_all(parent)
+        .with_context(|| anyhow!("Failed to create cache directory: {parent:?}"))?;
+    let tmp = tempfile::tempdir_in(parent)?;
+    for output in &step.outputs {
+        let path = src.join(output);
+        if fs::symlink_metadata(&path).is_err() {
+            bail!(
+                "Build step {:?} did not create output {output:?}",
+                step.name
+            );
+        }
+        copy_tree(&path, &tmp.path().join(output))?;
+    }
+
+    let tmp = tmp.into_path();
+    if let Err(err) = fs::rename(&tmp, dir) {
+        fs::remove_dir_all(&tmp)?;
+        // a concurrent build may have stored the same outputs already
+        if !dir.is_dir() {
+            return Err(err).with_context(|| anyhow!("Failed to move outputs into {dir:?}"));
+        }
+    }
+    Ok(())
+}
+
+/// Replace the outputs in `src` with the ones from the cache
+pub fn restore(dir: &Path, src: &Path, step: &StepManifest) -> Result<()> {
+    for output in &step.outputs {
+        let target = src.join(output);
+        remove_path(&target).with_context(|| anyhow!("Failed to remove {target:?}"))?;
+        copy_tree(&dir.join(output), &target)?;
+    }
+    Ok(())
+}
+
+enum Cache {
+    /// The step doesn't declare inputs and outputs
+    Disabled,
+    Restored,
+    /// The step needs to run, its outputs are stored in this directory afterwards
+    Miss(PathBuf),
+}
+
+/// Restore the outputs of the step from the cache, if possible
+fn check_cache(steps: &Steps, step: &StepManifest, env: &[String]) -> Result<Cache> {
+    if !step.is_cached() {
+        return Ok(Cache::Disabled);
+    }
+    let key = cache_key(steps.src, step, steps.lockfile_digest, env)?;
+    let dir = steps.cache_dir.join(key);
+    if steps.use_cache && dir.is_dir() {
+        info!(
+            "Inputs of step {:?} didn't change, restoring outputs from cache",
+            step.name
+        );
+        restore(&dir, steps.src, step)?;
+        return Ok(Cache::Restored);
+    }
+    Ok(Cache::Miss(dir))
+}
+
+/// Run a step, its output is only captured if other steps may run at the same time
+async fn exec_step(
+    container: &Container,
+    step: &StepManifest,
+    capture: bool,
+    workdir: &str,
+    env: &[String],
+    env_file: Option<&Path>,
+) -> Result<JobResult> {
+    if capture {
+        let job = Job {
+            name: step.name.clone(),
+            cmd: JobCommand::Args(step.cmd.clone()),
+            env: vec![],
+        };
+        return jobs::run_job(container, &job, workdir, env, env_file).await;
+    }
+
+    info!("Running step {:?}...", step.name);
+    let start = Instant::now();
+    let result = container
+        .exec(
+            step.cmd.clone(),
+            container::Exec {
+                cwd: Some(workdir),
+                env,
+                env_file,
+                ..Default::default()
+            },
+        )
+        .await;
+    let code = match result {
+        Ok(_) => Some(0),
+        Err(err) => err.downcast::<CommandError>()?.code,
+    };
+    Ok(JobResult {
+        name: step.name.clone(),
+        code,
+        log: vec![],
+        elapsed: start.elapsed(),
+    })
+}
+
+/// Execute the steps once the steps they need are done, with at most
+/// `parallel` at the same time. After a step failed no new steps are started.
+pub async fn run(
+    container: &Container,
+    steps: &Steps<'_>,
+    parallel: usize,
+    workdir: &str,
+    env: &[String],
+    env_file: Option<&Path>,
+) -> Result<()> {
+    let capture = parallel > 1;
+    let mut pending = steps.steps.iter().collect::<Vec<_>>();
+    let mut done = HashSet::new();
+    let mut running = FuturesUnordered::new();
+    let mut results = Vec::new();
+    loop {
+        while results.iter().all(JobResult::success) && running.len() < parallel.max(1) {
+            let Some(idx) = pending
+                .iter()
+                .position(|step| step.needs.iter().all(|need| done.contains(need)))
+            else {
+                break;
+            };
+            let step = pending.remove(idx);
+            let cache = match check_cache(steps, step, env)? {
+                Cache::Disabled => None,
+                Cache::Restored => {
+                    done.insert(&step.name);
+                    continue;
+                }
+                Cache::Miss(dir) => Some(dir),
+            };
+            running.push(async move {
+                let result = exec_step(container, step, capture, workdir, env, env_file).await;
+                (step, cache, result)
+            });
+        }
+
+        let Some((step, cache, result)) = running.next().await else {
+            break;
+        };
+        let result = result?;
+        if capture {
+            jobs::print_log(&result);
+        }
+        if result.success() {
+            if let Some(dir) = &cache {
+                debug!("Caching outputs of step {:?} in {dir:?}", step.name);
+                store(steps.src, step, dir)?;
+            }
+            done.insert(&step.name);
+        }
+        results.push(result);
+    }
+
+    if capture && results.len() > 1 {
+        eprint!("{}", jobs::format_summary(&results));
+    }
+    if let Some(failed) = results.iter().find(|r| !r.success()) {
+        let mut msg = format!("Build step {:?} failed", failed.name);
+        if !pending.is_empty() {
+            let names = pending.iter().map(|s| s.name.as_str()).collect::<Vec<_>>();
+            msg.push_str(&format!(", not started: {}", names.join(", ")));
+        }
+        bail!("{msg}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_runtime::{FakeRuntime, CONTAINER_ID};
+
+    fn step(inputs: &[&str], outputs: &[&str]) -> StepManifest {
+        StepManifest {
+            name: "compile".to_string(),
+            cmd: vec!["make".to_string()],
+            inputs: inputs.iter().map(|s| s.to_string()).collect(),
+            outputs: outputs.iter().map(|s| s.to_string()).collect(),
+            needs: vec![],
+        }
+    }
+
+    #[test]
+    fn test_cache_key() -> Result<()> {
+        let src = tempfile::tempdir()?;
+        let src = src.path();
+        fs::create_dir_all(src.join("src"))?;
+        fs::create_dir_all(src.join("out"))?;
+        fs::write(src.join("src/main.c"), "int main() {}\n")?;
+        fs::write(src.join("README.md"), "hello\n")?;
+        fs::write(src.join("out/main.o"), "\x7fELF")?;
+
+        let step = step(&["src/**", "*.o"], &["out"]);
+        assert_eq!(input_files(src, &step)?, [PathBuf::from("src/main.c")]);
+
+        let key = cache_key(src, &step, "sha256:00", &[])?;
+        fs::write(src.join("README.md"), "changed\n")?;
+        fs::write(src.join("out/main.o"), "changed")?;
+        assert_eq!(cache_key(src, &step, "sha256:00", &[])?, key);
+        assert_ne!(cache_key(src, &step, "sha256:11", &[])?, key);
+        assert_ne!(
+            cache_key(src, &step, "sha256:00", &["CFLAGS=-O2".to_string()])?,
+            key
+        );
+        fs::write(src.join("src/main.c"), "int main() { return 1; }\n")?;
+        assert_ne!(cache_key(src, &step, "sha256:00", &[])?, key);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_cached_step() -> Result<()> {
+        let src = tempfile::tempdir()?;
+        let src = src.path();
+        let cache = tempfile::tempdir()?;
+        fs::write(src.join("main.c"), "int main() {}\n")?;
+        // the fake container doesn't create outputs, pretend it did
+        fs::create_dir_all(src.join("out/bin"))?;
+        fs::write(src.join("out/bin/hello"), "\x7fELF")?;
+
+        let steps = [step(&["*.c"], &["out"])];
+        let steps = Steps {
+            steps: &steps,
+            src,
+            cache_dir: cache.path().join("steps"),
+            lockfile_digest: "sha256:00",
+            use_cache: true,
+        };
+        let container = Container {
+            id: CONTAINER_ID.to_string(),
+        };
+
+        let runtime = FakeRuntime::new();
+        runtime
+            .run(run(&container, &steps, 1, "/build", &[], None))
+            .await?;
+        assert_eq!(runtime.execs().len(), 1);
+
+        fs::remove_dir_all(src.join("out"))?;
+        let runtime = FakeRuntime::new();
+        runtime
+            .run(run(&container, &steps, 1, "/build", &[], None))
+            .await?;
+        assert!(runtime.execs().is_empty());
+        assert_eq!(fs::read(src.join("out/bin/hello"))?, b"\x7fELF");
+
+        fs::write(src.join("main.c"), "int main() { return 1; }\n")?;
+        let runtime = FakeRuntime::new();
+        runtime
+            .run(run(&container, &steps, 1, "/build", &[], None))
+            .await?;
+        assert_eq!(runtime.execs().len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_parallel_steps() -> Result<()> {
+        let src = tempfile::tempdir()?;
+        let step = |name: &str, needs: &[&str]| StepManifest {
+            name: name.to_string(),
+            cmd: vec!["make".to_string(), name.to_string()],
+            inputs: vec![],
+            outputs: vec![],
+            needs: needs.iter().map(|s| s.to_string()).collect(),
+        };
+        let steps = [
+            step("compile", &[]),
+            step("test", &["compile"]),
+            step("lint", &[]),
+        ];
+        let steps = Steps {
+            steps: &steps,
+            src: src.path(),
+            cache_dir: src.path().join("cache"),
+            lockfile_digest: "sha256:00",
+            use_cache: true,
+        };
+        let container = Container {
+            id: CONTAINER_ID.to_string(),
+        };
+
+        let runtime = FakeRuntime::new();
+        runtime.reply(&["make", "lint"], "no warnings\n");
+        runtime
+            .run(run(&container, &steps, 2, "/build", &[], None))
+            .await?;
+        let execs = runtime.execs();
+        assert_eq!(execs.len(), 3);
+        // the independent steps are started first
+        assert!(execs[2].ends_with("make test"));
+
+        let runtime = FakeRuntime::new();
+        runtime.fail(&["make", "compile"]);
+        let err = runtime
+            .run(run(&container, &steps, 2, "/build", &[], None))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Build step \"compile\" failed, not started: test"
+        );
+        assert!(!runtime.execs().iter().any(|cmd| cmd.ends_with("make test")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_output() -> Result<()> {
+        let src = tempfile::tempdir()?;
+        let cache = tempfile::tempdir()?;
+        let err = store(
+            src.path(),
+            &step(&["*.c"], &["out"]),
+            &cache.path().join("00"),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Build step \"compile\" did not create output \"out\""
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,343 @@
+//! Counters and timings that are printed at the end of update, fetch and build
+use crate::errors::*;
+use crate::progress::{format_bytes, format_duration};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+static BYTES_DOWNLOADED: AtomicU64 = AtomicU64::new(0);
+static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+static PACKAGES_INSTALLED: AtomicU64 = AtomicU64::new(0);
+static PODMAN_INVOCATIONS: AtomicU64 = AtomicU64::new(0);
+static PODMAN_NANOS: AtomicU64 = AtomicU64::new(0);
+static PHASES: Mutex<Vec<(&'static str, Duration)>> = Mutex::new(Vec::new());
+/// The phases that haven't finished yet, the last one is the innermost
+static ACTIVE_PHASES: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+static COMMANDS: Mutex<Vec<CommandTiming>> = Mutex::new(Vec::new());
+static PACKAGE_SOURCES: Mutex<Vec<PackageSource>> = Mutex::new(Vec::new());
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+/// Remember when the command was started, for the total time in the summary
+pub fn init() {
+    STARTED.get_or_init(Instant::now);
+}
+
+pub fn record_download(bytes: u64) {
+    BYTES_DOWNLOADED.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn record_cache(hit: bool) {
+    if hit {
+        CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+    } else {
+        CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn record_installed(count: usize) {
+    PACKAGES_INSTALLED.fetch_add(count as u64, Ordering::Relaxed);
+}
+
+pub fn record_podman(elapsed: Duration) {
+    PODMAN_INVOCATIONS.fetch_add(1, Ordering::Relaxed);
+    PODMAN_NANOS.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Record where the file of a package came from
+pub fn record_package_source(source: PackageSource) {
+    if let Ok(mut sources) = PACKAGE_SOURCES.lock() {
+        sources.push(source);
+    }
+}
+
+/// The packages recorded with `record_package_source`, in order
+pub fn package_sources() -> Vec<PackageSource> {
+    PACKAGE_SOURCES
+        .lock()
+        .map(|sources| sources.clone())
+        .unwrap_or_default()
+}
+
+/// Record a command that was executed in a container, it's attributed to the
+/// phase it was executed in
+pub fn record_command(cmd: Vec<String>, elapsed: Duration, exit_code: Option<i32>) {
+    let phase = ACTIVE_PHASES
+        .lock()
+        .ok()
+        .and_then(|phases| phases.last().copied());
+    if let Ok(mut commands) = COMMANDS.lock() {
+        commands.push(CommandTiming {
+            phase,
+            cmd,
+            seconds: elapsed.as_secs_f64(),
+            exit_code,
+        });
+    }
+}
+
+/// Measures a phase of the command until it's dropped
+pub struct Phase {
+    name: &'static str,
+    started: Instant,
+}
+
+impl Drop for Phase {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        if let Ok(mut phases) = PHASES.lock() {
+            phases.push((self.name, elapsed));
+        }
+        if let Ok(mut active) = ACTIVE_PHASES.lock() {
+            if let Some(idx) = active.iter().rposition(|name| *name == self.name) {
+                active.remove(idx);
+            }
+        }
+    }
+}
+
+pub fn phase(name: &'static str) -> Phase {
+    if let Ok(mut active) = ACTIVE_PHASES.lock() {
+        active.push(name);
+    }
+    Phase {
+        name,
+        started: Instant::now(),
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct PhaseTiming {
+    pub name: &'static str,
+    pub seconds: f64,
+}
+
+/// A command that was executed in a container
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CommandTiming {
+    /// Not set if it was executed outside of any phase
+    pub phase: Option<&'static str>,
+    pub cmd: Vec<String>,
+    pub seconds: f64,
+    /// Not set if podman was killed by a signal or couldn't be started
+    pub exit_code: Option<i32>,
+}
+
+impl CommandTiming {
+    pub fn format_text(&self) -> String {
+        let mut cmd = self.cmd.join(" ");
+        if cmd.chars().count() > MAX_COMMAND_WIDTH {
+            cmd = cmd.chars().take(MAX_COMMAND_WIDTH - 3).collect();
+            cmd.push_str("...");
+        }
+        let exit_code = self
+            .exit_code
+            .map(|code| code.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        format!(
+            "{} {} (exit code {exit_code}): {cmd}",
+            self.phase.unwrap_or("-"),
+            format_duration(Duration::from_secs_f64(self.seconds)),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Origin {
+    /// The file was already in the package cache
+    Cache,
+    /// The file was downloaded during this run
+    Download,
+}
+
+/// Where the file of a package came from, for the provenance of a build
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PackageSource {
+    pub name: String,
+    pub version: String,
+    pub sha256: String,
+    pub origin: Origin,
+    /// The url it was downloaded from, this may be one of the fallback urls
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+/// Commands are cut off after this many characters in the text summary
+const MAX_COMMAND_WIDTH: usize = 80;
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Summary {
+    pub total_seconds: f64,
+    pub phases: Vec<PhaseTiming>,
+    /// The commands executed in containers, in order
+    pub commands: Vec<CommandTiming>,
+    /// Whether each package was taken from the cache or downloaded
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub packages: Vec<PackageSource>,
+    pub bytes_downloaded: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub packages_installed: u64,
+    pub podman_invocations: u64,
+    pub podman_seconds: f64,
+}
+
+impl Summary {
+    pub fn collect() -> Self {
+        let total = STARTED.get().map(|t| t.elapsed()).unwrap_or_default();
+        let phases = PHASES
+            .lock()
+            .map(|phases| {
+                phases
+                    .iter()
+                    .map(|(name, duration)| PhaseTiming {
+                        name,
+                        seconds: duration.as_secs_f64(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let commands = COMMANDS
+            .lock()
+            .map(|commands| commands.clone())
+            .unwrap_or_default();
+        Summary {
+            total_seconds: total.as_secs_f64(),
+            phases,
+            commands,
+            packages: package_sources(),
+            bytes_downloaded: BYTES_DOWNLOADED.load(Ordering::Relaxed),
+            cache_hits: CACHE_HITS.load(Ordering::Relaxed),
+            cache_misses: CACHE_MISSES.load(Ordering::Relaxed),
+            packages_installed: PACKAGES_INSTALLED.load(Ordering::Relaxed),
+            podman_invocations: PODMAN_INVOCATIONS.load(Ordering::Relaxed),
+            podman_seconds: Duration::from_nanos(PODMAN_NANOS.load(Ordering::Relaxed))
+                .as_secs_f64(),
+        }
+    }
+
+    pub fn cache_hit_rate(&self) -> Option<f64> {
+        let total = self.cache_hits + self.cache_misses;
+        (total > 0).then(|| self.cache_hits as f64 / total as f64)
+    }
+
+    pub fn format_text(&self) -> String {
+        let mut out = format!(
+            "finished in {}",
+            format_duration(Duration::from_secs_f64(self.total_seconds))
+        );
+        for phase in &self.phases {
+            out.push_str(&format!(
+                ", {} {}",
+                phase.name,
+                format_duration(Duration::from_secs_f64(phase.seconds))
+            ));
+        }
+        out.push_str(&format!(
+            "; downloaded {}",
+            format_bytes(self.bytes_downloaded)
+        ));
+        if let Some(rate) = self.cache_hit_rate() {
+            out.push_str(&format!(
+                ", cache hit rate {:.0}% ({}/{})",
+                rate * 100.0,
+                self.cache_hits,
+                self.cache_hits + self.cache_misses
+            ));
+        }
+        if self.packages_installed > 0 {
+            out.push_str(&format!(", installed {} packages", self.packages_installed));
+        }
+        out
+    }
+}
+
+/// Print the summary to stderr, or as json to stdout
+pub fn print(json: bool) -> Result<()> {
+    let summary = Summary::collect();
+    if json {
+        println!("{}", serde_json::to_string(&summary)?);
+    } else {
+        info!("Summary: {}", summary.format_text());
+        for command in &summary.commands {
+            info!("Executed in container: {}", command.format_text());
+        }
+    }
+    Ok(())
+}
+
+/// Write the summary to a local file for --profile, nothing is sent anywhere
+pub fn write_profile(path: &Path) -> Result<()> {
+    let mut json = serde_json::to_string_pretty(&Summary::collect())?;
+    json.push('\n');
+    std::fs::write(path, json).with_context(|| anyhow!("Failed to write profile: {path:?}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_summary() {
+        let mut summary = Summary {
+            total_seconds: 95.2,
+            phases: vec![
+                PhaseTiming {
+                    name: "resolve",
+                    seconds: 80.5,
+                },
+                PhaseTiming {
+                    name: "download",
+                    seconds: 3.1,
+                },
+            ],
+            bytes_downloaded: 3 * 1024 * 1024,
+            cache_hits: 3,
+            cache_misses: 1,
+            packages_installed: 4,
+            commands: vec![],
+            packages: vec![],
+            podman_invocations: 12,
+            podman_seconds: 81.0,
+        };
+        assert_eq!(
+            summary.format_text(),
+            "finished in 1m35s, resolve 1m20s, download 3s; downloaded 3.0 MiB, cache hit rate 75% (3/4), installed 4 packages"
+        );
+
+        summary.cache_hits = 0;
+        summary.cache_misses = 0;
+        summary.packages_installed = 0;
+        assert_eq!(summary.cache_hit_rate(), None);
+        assert_eq!(
+            summary.format_text(),
+            "finished in 1m35s, resolve 1m20s, download 3s; downloaded 3.0 MiB"
+        );
+    }
+
+    #[test]
+    fn test_format_command() {
+        let mut command = CommandTiming {
+            phase: Some("install"),
+            cmd: vec!["apt-get".to_string(), "install".to_string()],
+            seconds: 12.0,
+            exit_code: Some(0),
+        };
+        assert_eq!(
+            command.format_text(),
+            "install 12s (exit code 0): apt-get install"
+        );
+
+        command.phase = None;
+        command.exit_code = None;
+        command.cmd = vec!["sh".to_string(), "-c".to_string(), "x".repeat(100)];
+        let text = command.format_text();
+        assert!(text.starts_with("- 12s (exit code -): sh -c xxx"));
+        assert!(text.ends_with("x..."));
+        assert_eq!(text.len(), "- 12s (exit code -): ".len() + 80);
+    }
+}
@@ -0,0 +1,146 @@
+//! Describe what this build of repro-env supports, so wrappers and editors
+//! don't need to hardcode it
+#[cfg(feature = "cli")]
+use crate::args;
+#[cfg(feature = "cli")]
+use crate::errors::*;
+use crate::manifest::Solver;
+use serde::Serialize;
+use std::env;
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Systems {
+    pub package_systems: Vec<PackageSystem>,
+    pub container_runtimes: Vec<ContainerRuntime>,
+    /// Packages are always resolved for the architecture repro-env runs on
+    pub architectures: Vec<&'static str>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct PackageSystem {
+    pub name: &'static str,
+    pub solvers: Vec<Solver>,
+    /// Keys of the `[packages]` section this system understands
+    pub manifest_options: Vec<&'static str>,
+    pub capabilities: Vec<&'static str>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ContainerRuntime {
+    pub name: &'static str,
+    pub capabilities: Vec<&'static str>,
+}
+
+pub fn package_systems() -> Vec<PackageSystem> {
+    vec![
+        PackageSystem {
+            name: "alpine",
+            solvers: vec![Solver::Native, Solver::Simulate],
+            manifest_options: vec!["system", "dependencies", "solver"],
+            capabilities: vec!["signature-verification", "apk-key-pinning"],
+        },
+        PackageSystem {
+            name: "archlinux",
+            solvers: vec![Solver::Native, Solver::Builtin],
+            manifest_options: vec![
+                "system",
+                "dependencies",
+                "solver",
+                "assume_installed",
+                "ignore_groups",
+                "keys",
+            ],
+            capabilities: vec!["archive-snapshots", "keyring-pinning"],
+        },
+        PackageSystem {
+            name: "conda",
+            solvers: vec![Solver::Native],
+            manifest_options: vec!["system", "dependencies", "environment", "channels"],
+            capabilities: vec!["experimental"],
+        },
+        PackageSystem {
+            name: "debian",
+            solvers: vec![Solver::Native, Solver::Builtin],
+            manifest_options: vec![
+                "system",
+                "dependencies",
+                "solver",
+                "install_recommends",
+                "install_suggests",
+                "preference",
+            ],
+            capabilities: vec!["archive-snapshots", "release-verification"],
+        },
+        PackageSystem {
+            name: "flatpak",
+            solvers: vec![Solver::Native],
+            manifest_options: vec!["system", "dependencies", "remote"],
+            capabilities: vec!["commit-pinning"],
+        },
+        PackageSystem {
+            name: "openwrt",
+            solvers: vec![Solver::Native],
+            manifest_options: vec!["system", "dependencies", "feeds"],
+            capabilities: vec!["index-checksums"],
+        },
+        PackageSystem {
+            name: "snap",
+            solvers: vec![Solver::Native],
+            manifest_options: vec!["system", "dependencies"],
+            capabilities: vec!["experimental"],
+        },
+    ]
+}
+
+pub fn list() -> Systems {
+    Systems {
+        package_systems: package_systems(),
+        container_runtimes: vec![ContainerRuntime {
+            name: "podman",
+            capabilities: vec!["image-archives", "rootless"],
+        }],
+        architectures: vec![env::consts::ARCH],
+    }
+}
+
+#[cfg(feature = "cli")]
+pub fn run(_list: &args::ListSystems) -> Result<()> {
+    let json = serde_json::to_string_pretty(&list())?;
+    println!("{json}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::Result;
+
+    #[test]
+    fn test_list_systems() -> Result<()> {
+        let systems = list();
+        let names = systems
+            .package_systems
+            .iter()
+            .map(|s| s.name)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "alpine",
+                "archlinux",
+                "conda",
+                "debian",
+                "flatpak",
+                "openwrt",
+                "snap"
+            ]
+        );
+
+        let json = serde_json::to_value(&systems)?;
+        assert_eq!(
+            json["package_systems"][0]["solvers"],
+            serde_json::json!(["native", "simulate"])
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,295 @@
+//! The `[texlive]` section. The packages are resolved from the `texlive.tlpdb`
+//! of a daily snapshot of tlnet on texlive.info, both are pinned and mounted
+//! as a local repository for tlmgr, so LaTeX packages install without network.
+use crate::container::Mount;
+use crate::errors::*;
+use crate::fetch;
+use crate::http;
+use crate::lockfile::{Lockfile, TexliveLock};
+use crate::manifest::{self, TexliveManifest};
+use crate::paths;
+use crate::summary;
+use crate::utils;
+use sha2::{Digest, Sha512};
+use std::collections::{BTreeSet, HashMap};
+use tokio::fs;
+
+const TLNET_ARCHIVE: &str = "https://texlive.info/tlnet-archive";
+/// The package database of a tlnet repository
+pub const TLPDB: &str = "texlive.tlpdb";
+/// Where the local repository is mounted during builds
+pub const TEXLIVE_DIR: &str = "/extra/texlive";
+
+/// A package of `texlive.tlpdb`
+#[derive(Debug, Default, PartialEq)]
+pub struct TlPackage {
+    pub depends: Vec<String>,
+    /// The sha512 of the container in `archive/`, packages without one only group others
+    pub container_checksum: Option<String>,
+}
+
+/// The tlnet snapshot of a date, `2024-06-01` is at `2024/06/01/tlnet`
+pub fn repository_url(date: &str) -> Result<String> {
+    if !manifest::valid_date(date) {
+        bail!("Invalid date for tlnet snapshot: {date:?}");
+    }
+    Ok(format!("{TLNET_ARCHIVE}/{}/tlnet", date.replace('-', "/")))
+}
+
+/// Parse the records of `texlive.tlpdb`, they are separated by empty lines
+pub fn parse_tlpdb(buf: &str) -> Result<HashMap<String, TlPackage>> {
+    let mut packages = HashMap::new();
+    let mut current: Option<(String, TlPackage)> = None;
+    for line in buf.lines() {
+        if line.is_empty() {
+            if let Some((name, package)) = current.take() {
+                packages.insert(name, package);
+            }
+            continue;
+        }
+        let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+        match (key, &mut current) {
+            ("name", _) => {
+                if let Some((name, package)) = current.take() {
+                    packages.insert(name, package);
+                }
+                current = Some((value.to_string(), TlPackage::default()));
+            }
+            ("depend", Some((_, package))) => package.depends.push(value.to_string()),
+            ("containerchecksum", Some((_, package))) => {
+                package.container_checksum = Some(value.to_string());
+            }
+            (_, None) if !line.starts_with(' ') => {
+                bail!("Unexpected line outside of package in texlive.tlpdb: {line:?}")
+            }
+            _ => (),
+        }
+    }
+    if let Some((name, package)) = current {
+        packages.insert(name, package);
+    }
+    Ok(packages)
+}
+
+/// The requested packages and everything they depend on that has a container,
+/// `.ARCH` dependencies are the binaries for `platform` (if there are any)
+pub fn resolve_packages<'a>(
+    tlpdb: &'a HashMap<String, TlPackage>,
+    requested: &[String],
+    platform: &str,
+) -> Result<BTreeSet<&'a str>> {
+    let mut queue = Vec::new();
+    for name in requested {
+        let (name, _) = tlpdb
+            .get_key_value(name)
+            .with_context(|| anyhow!("TeX Live package not found in texlive.tlpdb: {name:?}"))?;
+        queue.push(name.as_str());
+    }
+
+    let mut seen = BTreeSet::new();
+    while let Some(name) = queue.pop() {
+        if !seen.insert(name) {
+            continue;
+        }
+        for depend in &tlpdb[name].depends {
+            let depend = match depend.strip_suffix(".ARCH") {
+                Some(depend) => format!("{depend}.{platform}"),
+                None => depend.clone(),
+            };
+            if let Some((depend, _)) = tlpdb.get_key_value(&depend) {
+                queue.push(depend);
+            } else {
+                debug!("Skipping dependency of {name:?} that's not in texlive.tlpdb: {depend:?}");
+            }
+        }
+    }
+
+    Ok(seen
+        .into_iter()
+        .filter(|name| tlpdb[*name].container_checksum.is_some())
+        .collect())
+}
+
+/// Pin the package database and the containers of the packages of `[texlive]`
+pub async fn pin(texlive: &TexliveManifest, lockfile: &mut Lockfile) -> Result<()> {
+    let _phase = summary::phase("download");
+    let client = http::Client::new()?;
+    let pkgs_cache_dir = paths::pkgs_cache_dir()?;
+    let repository = repository_url(&texlive.date)?;
+
+    let url = format!("{repository}/tlpkg/{TLPDB}");
+    let tlpdb_pin = fetch::pin_file(&client, TLPDB, &url, None)
+        .await
+        .with_context(|| anyhow!("Failed to pin texlive.tlpdb of {:?}", texlive.date))?;
+    let buf = fs::read_to_string(pkgs_cache_dir.sha256_path(&tlpdb_pin.sha256)?)
+        .await
+        .context("Failed to read texlive.tlpdb from cache")?;
+    let tlpdb = parse_tlpdb(&buf)?;
+    let packages = resolve_packages(&tlpdb, &texlive.packages, &texlive.platform)?;
+
+    let mut files = vec![tlpdb_pin];
+    for name in packages {
+        let filename = format!("{name}.tar.xz");
+        if !manifest::valid_file_name(&filename) {
+            bail!("Invalid name for TeX Live package: {name:?}");
+        }
+        let url = format!("{repository}/archive/{filename}");
+        let pin = fetch::pin_file(&client, &filename, &url, None)
+            .await
+            .with_context(|| anyhow!("Failed to pin TeX Live package {name:?}"))?;
+
+        // tlmgr checks this too, but the lockfile shouldn't pin a broken container
+        let expected = tlpdb[name]
+            .container_checksum
+            .as_deref()
+            .unwrap_or_default();
+        let buf = fs::read(pkgs_cache_dir.sha256_path(&pin.sha256)?).await?;
+        let sha512 = hex::encode(Sha512::digest(&buf));
+        if sha512 != expected {
+            bail!("Mismatch of sha512 for TeX Live package {name:?}, expected={expected:?}, downloaded={sha512:?}");
+        }
+        debug!(
+            "Pinned TeX Live package {name:?} with sha256 {:?}",
+            pin.sha256
+        );
+        files.push(pin);
+    }
+
+    lockfile.texlive = Some(TexliveLock {
+        date: texlive.date.clone(),
+        packages: texlive.packages.clone(),
+        files,
+    });
+    Ok(())
+}
+
+/// Download and verify the files of the tlnet snapshot, they are mounted
+/// read-only as local repository into /extra/texlive/
+pub async fn stage(texlive: Option<TexliveLock>, mounts: &mut Vec<Mount>) -> Result<()> {
+    let Some(texlive) = texlive else {
+        return Ok(());
+    };
+    let files = texlive.files;
+    for file in &files {
+        if !manifest::valid_file_name(&file.name) {
+            bail!(
+                "Invalid filename for TeX Live package in lockfile: {:?}",
+                file.name
+            );
+        }
+    }
+
+    {
+        let _phase = summary::phase("download");
+        fetch::download_files(&files).await?;
+    }
+    let files = {
+        let _phase = summary::phase("verify");
+        utils::spawn_blocking(move |_| {
+            fetch::verify_files(&files)?;
+            Ok(files)
+        })
+        .await?
+    };
+
+    let pkgs_cache_dir = paths::pkgs_cache_dir()?;
+    for file in files {
+        let source = pkgs_cache_dir.sha256_path(&file.sha256)?;
+        let dir = if file.name == TLPDB {
+            "tlpkg"
+        } else {
+            "archive"
+        };
+        mounts.push(Mount::read_only(
+            source,
+            format!("{TEXLIVE_DIR}/{dir}/{}", file.name),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TLPDB_EXAMPLE: &str = "name 00texlive.config
+category ConfigFile
+depend minrelease/2016
+depend release/2024
+
+name amsmath
+category Package
+revision 72779
+containersize 23456
+containerchecksum 0b5d6e2a
+doccontainersize 1234567
+depend tools
+runfiles size=34
+ texmf-dist/tex/latex/amsmath/amsmath.sty
+
+name collection-basic
+category Collection
+revision 69939
+depend amsmath
+depend kpathsea
+depend hyphen-base
+
+name kpathsea
+category TLCore
+revision 70000
+containerchecksum 1c2d3e4f
+depend kpathsea.ARCH
+
+name kpathsea.x86_64-linux
+category TLCore
+revision 70001
+containerchecksum 5a6b7c8d
+binfiles arch=x86_64-linux size=84
+ bin/x86_64-linux/kpsewhich
+
+name tools
+category Package
+containerchecksum 9e8f7a6b
+";
+
+    #[test]
+    fn test_parse_tlpdb() -> Result<()> {
+        let tlpdb = parse_tlpdb(TLPDB_EXAMPLE)?;
+        assert_eq!(tlpdb.len(), 6);
+        assert_eq!(
+            tlpdb["amsmath"],
+            TlPackage {
+                depends: vec!["tools".to_string()],
+                container_checksum: Some("0b5d6e2a".to_string()),
+            }
+        );
+        assert_eq!(tlpdb["collection-basic"].container_checksum, None);
+        assert!(parse_tlpdb("revision 1\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_packages() -> Result<()> {
+        let tlpdb = parse_tlpdb(TLPDB_EXAMPLE)?;
+        let packages = resolve_packages(&tlpdb, &["collection-basic".to_string()], "x86_64-linux")?;
+        assert_eq!(
+            packages.into_iter().collect::<Vec<_>>(),
+            ["amsmath", "kpathsea", "kpathsea.x86_64-linux", "tools"]
+        );
+        let packages = resolve_packages(&tlpdb, &["kpathsea".to_string()], "aarch64-linux")?;
+        assert_eq!(packages.into_iter().collect::<Vec<_>>(), ["kpathsea"]);
+        assert!(resolve_packages(&tlpdb, &["biblatex".to_string()], "x86_64-linux").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_repository_url() -> Result<()> {
+        assert_eq!(
+            repository_url("2024-06-01")?,
+            "https://texlive.info/tlnet-archive/2024/06/01/tlnet"
+        );
+        assert!(repository_url("2024-6-1").is_err());
+        assert!(repository_url("../2024-06-01").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+//! Prebuilt cross-toolchains of `[[toolchain.external]]`. The tarball is pinned
+//! like a `[[files]]` entry and extracted once into the cache, builds mount the
+//! extracted directory read-only at the path of the manifest.
+use crate::errors::*;
+use crate::limits;
+use crate::lockfile::ToolchainLock;
+use crate::paths;
+use crate::pkgs;
+use crate::utils;
+use flate2::bufread::GzDecoder;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+fn unpack_tar<R: Read>(reader: R, dest: &Path) -> Result<()> {
+    let limits = limits::PACKAGE;
+    let mut tar = tar::Archive::new(limits.wrap(reader));
+    tar.set_preserve_permissions(true);
+    for (idx, entry) in tar.entries()?.enumerate() {
+        limits.check_entry(idx)?;
+        // unpack_in refuses paths (and symlinks in parents) that would leave dest
+        entry?.unpack_in(dest)?;
+    }
+    Ok(())
+}
+
+/// Extract a tarball, the compression is detected from its magic bytes
+pub fn unpack<R: BufRead + Send>(mut reader: R, dest: &Path) -> Result<()> {
+    let magic = reader.fill_buf()?;
+    if magic.starts_with(&[0x1F, 0x8B]) {
+        unpack_tar(GzDecoder::new(reader), dest)
+    } else if magic.starts_with(b"\xFD7zXZ\x00") {
+        pkgs::with_xz_reader(reader, |r| unpack_tar(r, dest))
+    } else if magic.starts_with(&[0x28, 0xB5, 0x2F, 0xFD]) {
+        unpack_tar(ruzstd::StreamingDecoder::new(reader)?, dest)
+    } else if magic.starts_with(b"BZh") {
+        bail!("Toolchains compressed with bzip2 are not supported, use the .tar.xz release")
+    } else {
+        unpack_tar(reader, dest)
+    }
+}
+
+/// Descend into the leading directories, each of them needs to be the only entry of its parent
+pub fn strip_components(dir: &Path, n: u32) -> Result<PathBuf> {
+    let mut dir = dir.to_path_buf();
+    for _ in 0..n {
+        let entries = fs::read_dir(&dir)
+            .with_context(|| anyhow!("Failed to read directory: {dir:?}"))?
+            .collect::<std::io::Result<Vec<_>>>()?;
+        match &entries[..] {
+            [entry] if entry.file_type()?.is_dir() => dir = entry.path(),
+            _ => bail!(
+                "Can't strip leading directory, {dir:?} doesn't contain exactly one directory"
+            ),
+        }
+    }
+    Ok(dir)
+}
+
+/// Extract the (already verified) tarball into the cache, unless this was
+/// done before. Returns the directory that's mounted into the container.
+pub async fn extract(toolchain: &ToolchainLock) -> Result<PathBuf> {
+    let dir = paths::toolchains_cache_dir()?;
+    let path = dir.join(&toolchain.sha256);
+    if !path.exists() {
+        let tarball = paths::pkgs_cache_dir()?.sha256_path(&toolchain.sha256)?;
+        let temp = dir.join(format!("{}.tmp-{}", toolchain.sha256, std::process::id()));
+        info!("Extracting toolchain {:?}...", toolchain.name);
+        let target = path.clone();
+        utils::spawn_blocking(move |cancel| {
+            if temp.exists() {
+                fs::remove_dir_all(&temp)?;
+            }
+            fs::create_dir_all(&temp)
+                .with_context(|| anyhow!("Failed to create directory: {temp:?}"))?;
+            let file = File::open(&tarball)
+                .with_context(|| anyhow!("Failed to open toolchain from cache: {tarball:?}"))?;
+            cancel.scope(|| unpack(BufReader::new(file), &temp))?;
+            fs::rename(&temp, &target)
+                .with_context(|| anyhow!("Failed to move toolchain into cache: {target:?}"))?;
+            Ok(())
+        })
+        .await
+        .with_context(|| anyhow!("Failed to extract toolchain {:?}", toolchain.name))?;
+    }
+    strip_components(&path, toolchain.strip_components)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    #[test]
+    fn test_unpack_strip_components() -> Result<()> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (path, data) in [
+            (
+                "armv7-eabihf--glibc--stable/bin/arm-linux-gcc",
+                &b"\x7fELF"[..],
+            ),
+            (
+                "armv7-eabihf--glibc--stable/README.txt",
+                &b"Bootlin toolchain\n"[..],
+            ),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o755);
+            header.set_cksum();
+            builder.append_data(&mut header, path, data)?;
+        }
+        let tarball = builder.into_inner()?.finish()?;
+
+        let dir = tempfile::tempdir()?;
+        unpack(&tarball[..], dir.path())?;
+        let root = strip_components(dir.path(), 1)?;
+        assert_eq!(root, dir.path().join("armv7-eabihf--glibc--stable"));
+        assert_eq!(fs::read(root.join("bin/arm-linux-gcc"))?, b"\x7fELF");
+        assert!(strip_components(dir.path(), 2).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+//! Record every container runtime invocation to a jsonl file for --trace-runtime
+use crate::container::CommandError;
+use crate::errors::*;
+use serde::Serialize;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+/// Captured output is cut off after this many bytes
+pub const MAX_OUTPUT: usize = 4096;
+
+static TRACE: OnceLock<Mutex<File>> = OnceLock::new();
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Invocation {
+    pub args: Vec<String>,
+    pub seconds: f64,
+    /// Not set if podman was killed by a signal or couldn't be started
+    pub exit_code: Option<i32>,
+    /// Only set if the output was captured
+    pub stdout: String,
+    /// Only set if the command failed and stderr was captured
+    pub stderr: String,
+    /// Set if podman couldn't be executed at all
+    pub error: Option<String>,
+}
+
+impl Invocation {
+    pub fn new(args: &[OsString], elapsed: Duration, result: &Result<Vec<u8>>) -> Self {
+        let mut invocation = Invocation {
+            args: args
+                .iter()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            seconds: elapsed.as_secs_f64(),
+            exit_code: None,
+            stdout: String::new(),
+            stderr: String::new(),
+            error: None,
+        };
+        match result {
+            Ok(stdout) => {
+                invocation.exit_code = Some(0);
+                invocation.stdout = truncate(stdout);
+            }
+            Err(err) => match err.downcast_ref::<CommandError>() {
+                Some(err) => {
+                    invocation.exit_code = err.code;
+                    invocation.stdout = truncate(&err.stdout);
+                    invocation.stderr = truncate(err.stderr.as_bytes());
+                }
+                None => invocation.error = Some(format!("{err:#}")),
+            },
+        }
+        invocation
+    }
+}
+
+fn truncate(buf: &[u8]) -> String {
+    if buf.len() <= MAX_OUTPUT {
+        String::from_utf8_lossy(buf).into_owned()
+    } else {
+        let mut out = String::from_utf8_lossy(&buf[..MAX_OUTPUT]).into_owned();
+        out.push_str(&format!("... ({} bytes truncated)", buf.len() - MAX_OUTPUT));
+        out
+    }
+}
+
+/// Start writing invocations to `path`, an existing file is replaced
+pub fn init(path: &Path) -> Result<()> {
+    let file =
+        File::create(path).with_context(|| anyhow!("Failed to create trace file: {path:?}"))?;
+    TRACE
+        .set(Mutex::new(file))
+        .map_err(|_| anyhow!("Runtime trace has already been initialized"))?;
+    Ok(())
+}
+
+/// Append the invocation to the trace file, if --trace-runtime is used
+pub fn record(args: &[OsString], elapsed: Duration, result: &Result<Vec<u8>>) {
+    let Some(file) = TRACE.get() else {
+        return;
+    };
+    let invocation = Invocation::new(args, elapsed, result);
+    let mut line = match serde_json::to_string(&invocation) {
+        Ok(line) => line,
+        Err(err) => {
+            warn!("Failed to serialize runtime trace: {err:#}");
+            return;
+        }
+    };
+    line.push('\n');
+    if let Ok(mut file) = file.lock() {
+        if let Err(err) = file.write_all(line.as_bytes()) {
+            warn!("Failed to write runtime trace: {err:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_success() {
+        let args = vec![OsString::from("image"), OsString::from("exists")];
+        let invocation = Invocation::new(&args, Duration::from_millis(1500), &Ok(b"ok\n".to_vec()));
+        assert_eq!(
+            serde_json::to_string(&invocation).unwrap(),
+            r#"{"args":["image","exists"],"seconds":1.5,"exit_code":0,"stdout":"ok\n","stderr":"","error":null}"#
+        );
+    }
+
+    #[test]
+    fn test_trace_failure() {
+        let args = vec![OsString::from("pull"), OsString::from("rust")];
+        let err = CommandError {
+            args: args.clone(),
+            code: Some(125),
+            stderr: "x".repeat(MAX_OUTPUT + 10),
+            stdout: Vec::new(),
+        };
+        let invocation = Invocation::new(&args, Duration::from_secs(2), &Err(err.into()));
+        assert_eq!(invocation.exit_code, Some(125));
+        assert_eq!(invocation.error, None);
+        assert_eq!(
+            invocation.stderr,
+            format!("{}... (10 bytes truncated)", "x".repeat(MAX_OUTPUT))
+        );
+
+        let invocation = Invocation::new(
+            &args,
+            Duration::ZERO,
+            &Err(anyhow!("Failed to execute podman binary")),
+        );
+        assert_eq!(invocation.exit_code, None);
+        assert_eq!(
+            invocation.error.as_deref(),
+            Some("Failed to execute podman binary")
+        );
+    }
+}
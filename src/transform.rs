@@ -0,0 +1,226 @@
+//! Rewrite the resolved lockfile before it's written, for example to use an
+//! internal mirror. Commands are configured with `[[lockfile.transform]]`,
+//! library users can implement [`Transform`] instead.
+use crate::errors::*;
+use crate::lockfile::Lockfile;
+use crate::manifest::TransformManifest;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process;
+
+pub trait Transform {
+    /// Recorded in the [meta] section of the lockfile
+    fn name(&self) -> &str;
+
+    fn apply<'a>(
+        &'a self,
+        lockfile: Lockfile,
+    ) -> Pin<Box<dyn Future<Output = Result<Lockfile>> + Send + 'a>>;
+}
+
+/// Pipe the lockfile through a command on the host
+#[derive(Debug, Clone, PartialEq)]
+pub struct Command {
+    pub name: String,
+    pub cmd: Vec<String>,
+    pub cwd: PathBuf,
+}
+
+impl Command {
+    pub fn new(transform: &TransformManifest, cwd: &Path) -> Self {
+        let cwd = if cwd.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            cwd
+        };
+        Command {
+            name: transform.name.clone(),
+            cmd: transform.cmd.clone(),
+            cwd: cwd.to_path_buf(),
+        }
+    }
+
+    async fn run(&self, lockfile: &Lockfile) -> Result<Lockfile> {
+        let Some((bin, args)) = self.cmd.split_first() else {
+            bail!("Command is empty");
+        };
+        let buf = lockfile.serialize()?;
+
+        debug!("Spawning child process: {:?}", self.cmd);
+        let mut child = process::Command::new(bin)
+            .args(args)
+            .current_dir(&self.cwd)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| anyhow!("Failed to execute {bin:?}"))?;
+
+        // write and read concurrently, the command may start printing before all input is read
+        let mut stdin = child.stdin.take().context("Failed to open stdin")?;
+        let (written, out) = tokio::join!(
+            async move { stdin.write_all(buf.as_bytes()).await },
+            child.wait_with_output()
+        );
+        let out = out?;
+        if !out.status.success() {
+            bail!("Command {:?} exited with {}", self.cmd, out.status);
+        }
+        // the command doesn't need to read all of its input
+        match written {
+            Err(err) if err.kind() != ErrorKind::BrokenPipe => {
+                return Err(err).context("Failed to write lockfile to stdin");
+            }
+            _ => (),
+        }
+
+        let buf = String::from_utf8(out.stdout).context("Output is not valid utf8")?;
+        Lockfile::deserialize(&buf).context("Failed to parse lockfile from output")
+    }
+}
+
+impl Transform for Command {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn apply<'a>(
+        &'a self,
+        lockfile: Lockfile,
+    ) -> Pin<Box<dyn Future<Output = Result<Lockfile>> + Send + 'a>> {
+        Box::pin(async move { self.run(&lockfile).await })
+    }
+}
+
+/// The commands of the `[[lockfile.transform]]` entries of a manifest
+pub fn from_manifest(transforms: &[TransformManifest], manifest_dir: &Path) -> Vec<Command> {
+    transforms
+        .iter()
+        .map(|transform| Command::new(transform, manifest_dir))
+        .collect()
+}
+
+/// Apply the transforms in order, each of them is recorded in [meta] (if enabled)
+pub async fn apply(mut lockfile: Lockfile, transforms: &[&dyn Transform]) -> Result<Lockfile> {
+    for transform in transforms {
+        let name = transform.name();
+        info!("Applying lockfile transform: {name:?}");
+        // [meta] describes how the lockfile was generated, transforms can't change it
+        let meta = lockfile.meta.clone();
+        lockfile = transform
+            .apply(lockfile)
+            .await
+            .with_context(|| anyhow!("Failed to apply lockfile transform: {name:?}"))?;
+        lockfile.meta = meta;
+        if let Some(meta) = &mut lockfile.meta {
+            meta.transforms.push(name.to_string());
+        }
+    }
+    Ok(lockfile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    const LOCKFILE: &str = r#"[container]
+image = "docker.io/library/archlinux@sha256:6568d3f1f278827a4a7d8537f80c2ae36982829a0c6bccff4cec081774025472"
+
+[meta]
+repro_env_version = "0.4.1"
+resolved_at = "2023-07-20T10:08:19Z"
+manifest_sha256 = "00"
+image = "docker.io/library/archlinux"
+
+[[package]]
+name = "binutils"
+version = "2.40-6"
+system = "archlinux"
+url = "https://archive.archlinux.org/packages/b/binutils/binutils-2.40-6-x86_64.pkg.tar.zst"
+sha256 = "b65fd16001578e10b602e577a8031cbfffc1164caf47ed9ba00c60d804519430"
+"#;
+
+    /// Like a library user would implement it
+    struct Policy;
+
+    impl Transform for Policy {
+        fn name(&self) -> &str {
+            "policy"
+        }
+
+        fn apply<'a>(
+            &'a self,
+            mut lockfile: Lockfile,
+        ) -> Pin<Box<dyn Future<Output = Result<Lockfile>> + Send + 'a>> {
+            Box::pin(async move {
+                lockfile
+                    .policy
+                    .insert("owner".to_string(), "build-team".into());
+                lockfile.meta = None;
+                Ok(lockfile)
+            })
+        }
+    }
+
+    fn command(name: &str, cmd: &[&str]) -> Command {
+        Command {
+            name: name.to_string(),
+            cmd: cmd.iter().map(|s| s.to_string()).collect(),
+            cwd: PathBuf::from("."),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apply_transforms() -> Result<()> {
+        let lockfile = Lockfile::deserialize(LOCKFILE)?;
+        let mirror = command(
+            "mirror",
+            &[
+                "sed",
+                "s|https://archive.archlinux.org/|https://mirror.example.com/archlinux/|",
+            ],
+        );
+        let lockfile = apply(lockfile, &[&mirror, &Policy]).await?;
+
+        assert_eq!(
+            lockfile.packages[0].url,
+            "https://mirror.example.com/archlinux/packages/b/binutils/binutils-2.40-6-x86_64.pkg.tar.zst"
+        );
+        assert_eq!(
+            lockfile.policy,
+            BTreeMap::from([("owner".to_string(), "build-team".into())])
+        );
+        let meta = lockfile.meta.as_ref().context("Missing [meta]")?;
+        assert_eq!(meta.transforms, ["mirror", "policy"]);
+
+        let toml = lockfile.serialize()?;
+        assert!(toml.contains("transforms = [\n    \"mirror\",\n    \"policy\",\n]"));
+        assert!(toml.contains("[policy]\nowner = \"build-team\"\n"));
+        assert_eq!(Lockfile::deserialize(&toml)?, lockfile);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failing_transform() -> Result<()> {
+        let lockfile = Lockfile::deserialize(LOCKFILE)?;
+        let err = apply(lockfile.clone(), &[&command("fail", &["false"])])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "Failed to apply lockfile transform: \"fail\": Command [\"false\"] exited with exit status: 1"
+        );
+
+        let err = apply(lockfile, &[&command("garbage", &["echo", "nope"])])
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").starts_with(
+            "Failed to apply lockfile transform: \"garbage\": Failed to parse lockfile from output"
+        ));
+        Ok(())
+    }
+}
@@ -1,25 +1,382 @@
 use crate::args;
 use crate::container;
+use crate::diff;
 use crate::errors::*;
-use crate::manifest::Manifest;
+use crate::fetch;
+use crate::git;
+use crate::haskell;
+use crate::http;
+use crate::java;
+use crate::lockfile::{FileLock, FileSignatureLock, KeyLock, Lockfile, MetaLock, ToolchainLock};
+use crate::manifest::{self, FileManifest, Manifest};
+use crate::paths;
+use crate::perl;
+use crate::pgp;
+use crate::php;
 use crate::resolver;
+use crate::ruby;
+use crate::summary;
+use crate::texlive;
+use crate::transform::{self, Transform};
+use crate::utils;
+use data_encoding::BASE64;
+use sequoia_openpgp::parse::Parse;
+use sequoia_openpgp::serialize::SerializeInto;
+use sequoia_openpgp::Cert;
+use std::io::{self, IsTerminal};
 use std::path::Path;
 use tokio::fs;
 
+/// Pin the OpenPGP certificates listed in the manifest, paths are relative to the manifest
+async fn record_keys(
+    manifest: &Manifest,
+    manifest_dir: &Path,
+    lockfile: &mut Lockfile,
+) -> Result<()> {
+    let Some(packages) = &manifest.packages else {
+        return Ok(());
+    };
+    if !packages.keys.is_empty() && packages.system != "archlinux" {
+        bail!("Pinning keys is only supported for archlinux");
+    }
+    for path in &packages.keys {
+        let path = manifest_dir.join(path);
+        let buf = fs::read(&path)
+            .await
+            .with_context(|| anyhow!("Failed to read OpenPGP certificate: {path:?}"))?;
+        let (fingerprint, cert) = pgp::normalize_cert(&buf)
+            .with_context(|| anyhow!("Failed to load OpenPGP certificate: {path:?}"))?;
+        debug!("Pinning OpenPGP certificate: {fingerprint:?}");
+        lockfile.keys.push(KeyLock { fingerprint, cert });
+    }
+    lockfile
+        .keys
+        .sort_by(|a, b| a.fingerprint.cmp(&b.fingerprint));
+    lockfile
+        .keys
+        .dedup_by(|a, b| a.fingerprint == b.fingerprint);
+    Ok(())
+}
+
+/// Download the detached signature of a `[[files]]` entry and pin it with the
+/// certificate that made it, it needs to be one of the keys of the entry
+async fn pin_signature(
+    client: &http::Client,
+    file: &FileManifest,
+    url: &str,
+    pin: &FileLock,
+    manifest_dir: &Path,
+) -> Result<FileSignatureLock> {
+    let mut certs = Vec::new();
+    for path in &file.keys {
+        let path = manifest_dir.join(path);
+        let buf = fs::read(&path)
+            .await
+            .with_context(|| anyhow!("Failed to read OpenPGP certificate: {path:?}"))?;
+        let cert = Cert::from_bytes(&buf)
+            .with_context(|| anyhow!("Failed to load OpenPGP certificate: {path:?}"))?;
+        certs.push(cert);
+    }
+    let sig = client
+        .fetch(url)
+        .await
+        .with_context(|| anyhow!("Failed to download signature: {url:?}"))?;
+    let path = paths::pkgs_cache_dir()?.sha256_path(&pin.sha256)?;
+    let data = fs::read(&path).await?;
+    let cert = pgp::find_signer(&data, &sig, &certs)?;
+    let (fingerprint, cert) = pgp::normalize_cert(&cert.to_vec()?)?;
+    debug!("Verified signature of {:?} by {fingerprint:?}", pin.name);
+    Ok(FileSignatureLock {
+        url: url.to_string(),
+        value: BASE64.encode(&sig),
+        fingerprint,
+        cert,
+    })
+}
+
+/// Download the `[[files]]` of the manifest and pin them by their sha256
+async fn pin_files(
+    manifest: &Manifest,
+    manifest_dir: &Path,
+    lockfile: &mut Lockfile,
+) -> Result<()> {
+    if manifest.files.is_empty() {
+        return Ok(());
+    }
+    let _phase = summary::phase("download");
+    let client = http::Client::new()?;
+    for file in &manifest.files {
+        let name = file
+            .name()
+            .with_context(|| anyhow!("Failed to determine filename of url: {:?}", file.url))?;
+        let mut pin = fetch::pin_file(&client, name, &file.url, file.sha256.as_deref())
+            .await
+            .with_context(|| anyhow!("Failed to pin file {name:?}"))?;
+        if let Some(url) = &file.signature {
+            let signature = pin_signature(&client, file, url, &pin, manifest_dir)
+                .await
+                .with_context(|| anyhow!("Failed to verify signature of file {name:?}"))?;
+            pin.signature = Some(signature);
+        }
+        debug!("Pinned file {name:?} with sha256 {:?}", pin.sha256);
+        lockfile.files.push(pin);
+    }
+    Ok(())
+}
+
+/// Download the tarballs of `[[toolchain.external]]` and pin them by their sha256
+async fn pin_toolchains(manifest: &Manifest, lockfile: &mut Lockfile) -> Result<()> {
+    if manifest.toolchain.is_empty() {
+        return Ok(());
+    }
+    let _phase = summary::phase("download");
+    let client = http::Client::new()?;
+    for toolchain in &manifest.toolchain.external {
+        let name = toolchain
+            .name()
+            .with_context(|| anyhow!("Failed to determine filename of url: {:?}", toolchain.url))?;
+        let pin = fetch::pin_file(&client, name, &toolchain.url, toolchain.sha256.as_deref())
+            .await
+            .with_context(|| anyhow!("Failed to pin toolchain {name:?}"))?;
+        debug!("Pinned toolchain {name:?} with sha256 {:?}", pin.sha256);
+        lockfile.toolchains.push(ToolchainLock {
+            name: pin.name,
+            url: pin.url,
+            sha256: pin.sha256,
+            size: pin.size,
+            path: toolchain.path.clone(),
+            strip_components: toolchain.strip_components,
+        });
+    }
+    Ok(())
+}
+
+/// Resolve the `[[git]]` repositories of the manifest to the commits their revs point to
+async fn pin_git(manifest: &Manifest, lockfile: &mut Lockfile) -> Result<()> {
+    for entry in &manifest.git {
+        let pin = git::resolve(entry)
+            .await
+            .with_context(|| anyhow!("Failed to pin git repository {:?}", entry.url))?;
+        lockfile.git.push(pin);
+    }
+    Ok(())
+}
+
+/// The lockfile that's about to be replaced, if there is one
+async fn previous_lockfile(path: &Path) -> Option<Lockfile> {
+    if !path.exists() {
+        return None;
+    }
+    match Lockfile::read_from_file(path).await {
+        Ok(old) => Some(old),
+        Err(err) => {
+            debug!("Failed to read previous lockfile: {err:#}");
+            None
+        }
+    }
+}
+
 pub async fn update(update: &args::Update) -> Result<()> {
-    container::test_for_unprivileged_userns_clone().await?;
+    let manifest_path = manifest::locate(&update.manifest);
+    let manifest_path = manifest_path.as_path();
+    let lockfile_path = update.lockfile.as_path();
 
-    let manifest_path = Path::new("repro-env.toml");
-    let lockfile_path = Path::new("repro-env.lock");
+    // prevent concurrent updates of the same lockfile
+    let file = std::fs::File::open(manifest_path)
+        .with_context(|| anyhow!("Failed to open dependency manifest: {manifest_path:?}"))?;
+    let _manifest_lock = utils::lock_exclusive(file).await?;
+
+    let _cache_lock = utils::lock_shared(paths::cache_lock()?).await?;
 
     let manifest = Manifest::read_from_file(manifest_path).await?;
 
-    let lockfile = resolver::resolve(update, &manifest).await?;
+    // the default environment and every [matrix] variant, unless one was selected
+    let mut environments = Vec::new();
+    if let Some(variant) = &update.variant {
+        environments.push((
+            manifest.variant(variant)?,
+            manifest::variant_lockfile_path(lockfile_path, variant),
+        ));
+    } else {
+        for variant in manifest.matrix.keys() {
+            environments.push((
+                manifest.variant(variant)?,
+                manifest::variant_lockfile_path(lockfile_path, variant),
+            ));
+        }
+        environments.insert(0, (manifest, lockfile_path.to_path_buf()));
+    }
+    if update.interactive && !io::stdin().is_terminal() {
+        bail!("--interactive needs a terminal to ask on");
+    }
+    if update.write_stdout && environments.len() > 1 {
+        bail!("Manifest has a [matrix], use --variant to select which lockfile to print");
+    }
+    if update.print && environments.len() > 1 {
+        bail!("Manifest has a [matrix], use --variant to select which image to print");
+    }
+
+    if update.print {
+        let (manifest, _) = &environments[0];
+        let manifest_dir = manifest::manifest_dir(manifest_path);
+        let container = resolver::container::resolve(update, manifest, manifest_dir).await?;
+        println!("{}", container.image);
+        return Ok(());
+    }
+
+    // resolving packages needs a container, the image alone only needs the registry
+    if environments
+        .iter()
+        .any(|(manifest, _)| manifest.packages.is_some())
+    {
+        container::test_for_unprivileged_userns_clone().await?;
+    }
+
+    let announce = environments.len() > 1 || update.variant.is_some();
+    for (manifest, lockfile_path) in environments {
+        if announce {
+            info!("Resolving lockfile: {lockfile_path:?}");
+        }
+        update_lockfile(update, &manifest, manifest_path, &lockfile_path).await?;
+    }
+
+    if update.write_stdout {
+        return Ok(());
+    }
+    summary::print(update.json)
+}
+
+/// Show the changes and ask about every package bump, rejected packages keep
+/// the version of the previous lockfile
+fn review_changes(previous: &Lockfile, lockfile: &mut Lockfile) -> Result<()> {
+    let changes = diff::changes(previous, lockfile);
+    if changes.is_empty() {
+        return Ok(());
+    }
+    eprint!("{}", changes.format_text());
+
+    let mut rejected = Vec::new();
+    let bumps = changes
+        .upgraded
+        .iter()
+        .map(|pkg| ("upgrade", pkg))
+        .chain(changes.downgraded.iter().map(|pkg| ("downgrade", pkg)));
+    for (change, pkg) in bumps {
+        let question = format!(
+            "Accept {change} of {} {} -> {}?",
+            pkg.label(),
+            pkg.old,
+            pkg.new
+        );
+        if !utils::confirm(&question)? {
+            diff::keep_previous(lockfile, previous, pkg.profile.as_deref(), &pkg.name)?;
+            rejected.push(format!("{} {}", pkg.label(), pkg.old));
+        }
+    }
+    if !rejected.is_empty() {
+        warn!(
+            "Kept {} at the previous version, this combination was never resolved together: {}",
+            rejected.len(),
+            rejected.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Resolve one environment of the manifest and write it to `lockfile_path`
+async fn update_lockfile(
+    update: &args::Update,
+    manifest: &Manifest,
+    manifest_path: &Path,
+    lockfile_path: &Path,
+) -> Result<()> {
+    let manifest_dir = manifest::manifest_dir(manifest_path);
+    let (mut lockfile, provenance) = {
+        let _phase = summary::phase("resolve");
+        // the environment.yml of conda is relative to the manifest, not the working directory
+        let mut manifest = manifest.clone();
+        if let Some(environment) = manifest
+            .packages
+            .as_mut()
+            .and_then(|packages| packages.environment.as_mut())
+        {
+            *environment = manifest_dir
+                .join(&*environment)
+                .to_string_lossy()
+                .into_owned();
+        }
+        resolver::resolve(update, &manifest, manifest_dir).await?
+    };
+    record_keys(manifest, manifest_dir, &mut lockfile).await?;
+    pin_files(manifest, manifest_dir, &mut lockfile).await?;
+    pin_git(manifest, &mut lockfile).await?;
+    pin_toolchains(manifest, &mut lockfile).await?;
+    if let Some(java) = &manifest.java {
+        java::pin(update, java, manifest_dir, &mut lockfile).await?;
+    }
+    if let Some(ruby) = &manifest.ruby {
+        ruby::pin(ruby, manifest_dir, &mut lockfile).await?;
+    }
+    if let Some(php) = &manifest.php {
+        php::pin(php, manifest_dir, &mut lockfile).await?;
+    }
+    if let Some(perl) = &manifest.perl {
+        perl::pin(perl, manifest_dir, &mut lockfile).await?;
+    }
+    if let Some(haskell) = &manifest.haskell {
+        haskell::pin(haskell, &mut lockfile).await?;
+    }
+    if let Some(texlive) = &manifest.texlive {
+        texlive::pin(texlive, &mut lockfile).await?;
+    }
+    if manifest.lockfile.meta {
+        let buf = fs::read(manifest_path).await?;
+        lockfile.meta = Some(MetaLock::new(manifest, &buf, provenance)?);
+    }
+    if !manifest.lockfile.transforms.is_empty() {
+        let commands = transform::from_manifest(&manifest.lockfile.transforms, manifest_dir);
+        let transforms = commands
+            .iter()
+            .map(|cmd| cmd as &dyn Transform)
+            .collect::<Vec<_>>();
+        lockfile = transform::apply(lockfile, &transforms).await?;
+    }
     trace!("Resolved manifest into lockfile: {lockfile:?}");
 
-    debug!("Updating dependency lockfile: {lockfile_path:?}");
-    let buf = lockfile.serialize()?;
-    fs::write(lockfile_path, buf).await?;
+    let previous = previous_lockfile(lockfile_path).await;
+    if update.deny_downgrades {
+        if let Some(previous) = &previous {
+            diff::changes(previous, &lockfile)
+                .deny_downgrades(&update.allow_downgrade)
+                .with_context(|| anyhow!("Failed to update lockfile: {lockfile_path:?}"))?;
+        }
+    }
 
+    if update.write_stdout {
+        print!("{}", lockfile.serialize()?);
+        return Ok(());
+    }
+
+    if update.interactive {
+        if let Some(previous) = &previous {
+            review_changes(previous, &mut lockfile)?;
+        }
+    }
+
+    debug!("Updating dependency lockfile: {lockfile_path:?}");
+    lockfile.write_to_file(lockfile_path)?;
+    if let Some(previous) = &previous {
+        eprint!("{}", diff::changes(previous, &lockfile).format_text());
+    }
+    match update.report {
+        Some(args::ReportFormat::Markdown) => {
+            let path = lockfile_path.to_string_lossy();
+            println!(
+                "{}",
+                diff::markdown_report(&path, previous.as_ref(), &lockfile)
+            );
+        }
+        None => (),
+    }
     Ok(())
 }
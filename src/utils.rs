@@ -1,10 +1,321 @@
 use crate::errors::*;
-use flate2::bufread::GzDecoder;
-use std::io::{BufRead, Read};
-
-pub fn read_gzip_to_end<R: BufRead>(reader: &mut R) -> Result<Vec<u8>> {
-    let mut buf = Vec::new();
-    let mut gz = GzDecoder::new(reader);
-    gz.read_to_end(&mut buf)?;
-    Ok(buf)
+use crate::hash;
+use crate::limits::Cancel;
+use crate::lockfile::PackageLock;
+use crate::progress::format_bytes;
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use nix::sys::statvfs;
+use std::fs::{File, Permissions};
+use std::future::Future;
+use std::io::{self, IsTerminal, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Once;
+use tokio::signal::unix::{self, SignalKind};
+use tokio::sync::Notify;
+
+static SHUTDOWN: Shutdown = Shutdown::new();
+static INTERRUPT_LISTENER: Once = Once::new();
+/// Number of places that currently handle ctrl-c themselves
+static INTERRUPT_HANDLERS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path).with_context(|| anyhow!("Failed to open file: {path:?}"))?;
+    let mut hasher = hash::Sha256::new();
+    io::copy(&mut file, &mut hasher).with_context(|| anyhow!("Failed to read file: {path:?}"))?;
+    Ok(hasher.finalize())
+}
+
+/// Write to a temporary file in the same directory and rename it into place,
+/// concurrent readers either see the old or the new content, never a mix
+pub fn atomic_write(path: &Path, buf: &[u8]) -> Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    // keep the permissions of the file we replace, tempfile defaults to 0600
+    let permissions = match std::fs::metadata(path) {
+        Ok(metadata) => metadata.permissions(),
+        Err(_) => Permissions::from_mode(0o644),
+    };
+    let mut file = tempfile::Builder::new()
+        .permissions(permissions)
+        .tempfile_in(parent)
+        .with_context(|| anyhow!("Failed to create temporary file in {parent:?}"))?;
+    file.write_all(buf)?;
+    file.as_file().sync_all()?;
+    file.persist(path)
+        .with_context(|| anyhow!("Failed to move temporary file to {path:?}"))?;
+    Ok(())
+}
+
+/// Free space on the filesystem of this path, as available to unprivileged users
+pub fn available_space(path: &Path) -> Result<u64> {
+    let stat = statvfs::statvfs(path)
+        .with_context(|| anyhow!("Failed to query free disk space: {path:?}"))?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+/// Fail early with an actionable error instead of running into ENOSPC halfway through
+pub fn check_free_space(path: &Path, required: u64) -> Result<()> {
+    let available = available_space(path)?;
+    debug!(
+        "Free disk space in {path:?}: required={}, available={}",
+        format_bytes(required),
+        format_bytes(available)
+    );
+    if required > available {
+        bail!(
+            "Not enough free disk space in {path:?}: {} needed, but only {} available. Free up some space (for example with `repro-env gc`) or set REPRO_ENV_CACHE/REPRO_ENV_HOME to a larger filesystem",
+            format_bytes(required),
+            format_bytes(available)
+        );
+    }
+    Ok(())
+}
+
+/// Sum up the recorded sizes, packages from older lockfiles may not have one
+pub fn total_size<'a, I: IntoIterator<Item = &'a PackageLock>>(packages: I) -> u64 {
+    packages.into_iter().filter_map(|p| p.size).sum()
+}
+
+pub async fn lock_shared(file: File) -> Result<Flock<File>> {
+    lock(file, FlockArg::LockSharedNonblock, FlockArg::LockShared)
+        .await
+        .context("Failed to acquire shared lock")
+}
+
+pub async fn lock_exclusive(file: File) -> Result<Flock<File>> {
+    lock(
+        file,
+        FlockArg::LockExclusiveNonblock,
+        FlockArg::LockExclusive,
+    )
+    .await
+    .context("Failed to acquire exclusive lock")
+}
+
+/// Waiting for another process may take a whole build, this happens on a
+/// blocking thread so the runtime can keep going
+async fn lock(file: File, nonblock: FlockArg, blocking: FlockArg) -> Result<Flock<File>> {
+    match Flock::lock(file, nonblock) {
+        Ok(lock) => Ok(lock),
+        Err((file, Errno::EWOULDBLOCK)) => {
+            info!("Waiting for lock held by another repro-env process...");
+            spawn_blocking(move |_cancel| {
+                Flock::lock(file, blocking).map_err(|(_, errno)| errno.into())
+            })
+            .await
+        }
+        Err((_, errno)) => Err(errno.into()),
+    }
+}
+
+/// Ask a yes/no question on the terminal, anything but yes is a no. Without
+/// a terminal to ask on the answer is always no.
+pub fn confirm(question: &str) -> Result<bool> {
+    if !io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    eprint!("{question} [y/N] ");
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .context("Failed to read answer from stdin")?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Set once repro-env is shutting down, either because of ctrl-c or because a
+/// container is torn down after a signal. Nothing is restarted afterwards.
+#[derive(Debug)]
+pub struct Shutdown {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl Shutdown {
+    pub const fn new() -> Self {
+        Shutdown {
+            cancelled: AtomicBool::new(false),
+            notify: Notify::const_new(),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once `cancel` was called, immediately if that already happened
+    pub async fn cancelled(&self) {
+        loop {
+            let notified = self.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Drive `fut` until it completes, or abort it as soon as we're shutting down.
+    /// ctrl-c is handled while this is running.
+    pub async fn run<T, F: Future<Output = Result<T>>>(&self, fut: F) -> Result<T> {
+        listen_for_interrupts();
+        let _guard = handle_interrupts();
+        tokio::select! {
+            biased;
+            _ = self.cancelled() => bail!("Shutting down, operation was aborted"),
+            result = fut => result,
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The shutdown state of the process, used to abort in-flight downloads and blocking work
+pub fn shutdown() -> &'static Shutdown {
+    &SHUTDOWN
+}
+
+/// Counts as handling ctrl-c while it's alive, see [`handle_interrupts`]
+pub struct InterruptGuard(());
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        INTERRUPT_HANDLERS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Tell the ctrl-c listener of the worker pool that the caller handles ctrl-c
+/// itself for as long as the guard is alive. Without any handler, ctrl-c exits
+/// the process like the default signal handler would.
+pub fn handle_interrupts() -> InterruptGuard {
+    INTERRUPT_HANDLERS.fetch_add(1, Ordering::SeqCst);
+    InterruptGuard(())
+}
+
+/// Once a ctrl-c listener is registered the default signal handler is gone for
+/// the rest of the process, so there's only one that is shared by everything
+fn listen_for_interrupts() {
+    INTERRUPT_LISTENER.call_once(|| match unix::signal(SignalKind::interrupt()) {
+        Ok(mut sigint) => {
+            tokio::spawn(async move {
+                while sigint.recv().await.is_some() {
+                    if INTERRUPT_HANDLERS.load(Ordering::SeqCst) == 0 {
+                        std::process::exit(130);
+                    }
+                    SHUTDOWN.cancel();
+                }
+            });
+        }
+        Err(err) => warn!("Failed to listen for ctrl-c: {err:#}"),
+    });
+}
+
+/// Run blocking work like decompressing and parsing archives on the worker
+/// pool, so it doesn't stall the async runtime. On ctrl-c the archives that
+/// are read with [`Cancel::scope`] stop at their next read and this returns
+/// immediately. Work that uses rayon needs to enter the scope on each thread.
+pub async fn spawn_blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Cancel) -> Result<T> + Send + 'static,
+{
+    listen_for_interrupts();
+    let _guard = handle_interrupts();
+
+    let cancel = Cancel::default();
+    let token = cancel.clone();
+    let task = tokio::task::spawn_blocking(move || token.scope(|| f(&token)));
+    tokio::select! {
+        result = task => result.context("Blocking task failed")?,
+        _ = SHUTDOWN.cancelled() => {
+            cancel.cancel();
+            bail!("Ctrl-c received")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_atomic_write() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("repro-env.lock");
+        atomic_write(&path, b"foo")?;
+        atomic_write(&path, b"bar")?;
+        assert_eq!(std::fs::read(&path)?, b"bar");
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o644);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_free_space() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        check_free_space(dir.path(), 0)?;
+        assert!(check_free_space(dir.path(), u64::MAX).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spawn_blocking() -> Result<()> {
+        let buf = vec![1u8, 2, 3];
+        let (sum, buf) = spawn_blocking(move |cancel| {
+            assert!(!cancel.is_cancelled());
+            Ok((buf.iter().map(|b| *b as u32).sum::<u32>(), buf))
+        })
+        .await?;
+        assert_eq!(sum, 6);
+        assert_eq!(buf, [1, 2, 3]);
+
+        let err = spawn_blocking(|_| -> Result<()> { bail!("Invalid archive") }).await;
+        assert_eq!(err.unwrap_err().to_string(), "Invalid archive");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lock_waits_for_other_holder() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(".lock");
+        let held = lock_exclusive(File::create(&path)?).await?;
+
+        let waiting = tokio::spawn(lock_shared(File::open(&path)?));
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        drop(held);
+        waiting.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() -> Result<()> {
+        static SHUTDOWN: Shutdown = Shutdown::new();
+        assert_eq!(SHUTDOWN.run(async { Ok(1) }).await?, 1);
+
+        let download = tokio::spawn(SHUTDOWN.run(std::future::pending::<Result<()>>()));
+        tokio::task::yield_now().await;
+        SHUTDOWN.cancel();
+        let err = download.await?.unwrap_err();
+        assert_eq!(err.to_string(), "Shutting down, operation was aborted");
+
+        // anything started afterwards is aborted right away
+        assert!(SHUTDOWN.run(async { Ok(()) }).await.is_err());
+        Ok(())
+    }
 }
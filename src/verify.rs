@@ -0,0 +1,83 @@
+//! Check the cached packages and files of a lockfile again, and optionally
+//! how many of the packages have been independently reproduced
+use crate::args;
+use crate::errors::*;
+use crate::fetch;
+use crate::http;
+use crate::lockfile::Lockfile;
+use crate::paths;
+use crate::rebuilderd::{self, Status};
+use crate::utils;
+use std::path::Path;
+
+pub async fn verify(verify: &args::Verify) -> Result<()> {
+    let path = verify
+        .file
+        .as_deref()
+        .unwrap_or(Path::new("repro-env.lock"));
+    let lockfile = Lockfile::read_from_file(path).await?;
+
+    {
+        let _cache_lock = utils::lock_shared(paths::cache_lock()?).await?;
+
+        let pkgs_cache_dir = paths::pkgs_cache_dir()?;
+        let dependencies = lockfile
+            .packages
+            .iter()
+            .filter(|p| !p.installed && p.has_package_file())
+            .cloned()
+            .collect::<Vec<_>>();
+        let files = lockfile.all_files();
+        let mut missing = Vec::new();
+        for package in &dependencies {
+            if !pkgs_cache_dir.sha256_path(&package.sha256)?.exists() {
+                missing.push(format!("package {:?} {:?}", package.name, package.version));
+            }
+        }
+        for file in &files {
+            if !pkgs_cache_dir.sha256_path(&file.sha256)?.exists() {
+                missing.push(format!("file {:?}", file.name));
+            }
+        }
+        if !missing.is_empty() {
+            bail!(
+                "Missing from cache, run `repro-env fetch` first:\n  {}",
+                missing.join("\n  ")
+            );
+        }
+
+        let count = dependencies.len() + files.len();
+        let apk_keys = lockfile.apk_keys.clone();
+        utils::spawn_blocking(move |cancel| {
+            fetch::verify_dependencies(&dependencies, apk_keys, cancel)?;
+            fetch::verify_files(&files)
+        })
+        .await?;
+        info!("Verified {count} packages and files in cache");
+    }
+
+    if verify.cross_check {
+        let client = http::Client::new()?;
+        let rebuilders = rebuilderd::rebuilders(&verify.rebuilder);
+        let results = rebuilderd::cross_check(&client, &lockfile.packages, &rebuilders).await?;
+        if verify.json {
+            println!("{}", serde_json::to_string(&results)?);
+        } else {
+            print!("{}", rebuilderd::format_text(&results));
+        }
+
+        let reproduced = results
+            .iter()
+            .filter(|r| r.status == Status::Reproduced)
+            .count();
+        let unreproducible = results
+            .iter()
+            .filter(|r| r.status == Status::Unreproducible)
+            .count();
+        info!(
+            "{reproduced} of {} packages have been independently reproduced, {unreproducible} failed to reproduce",
+            results.len()
+        );
+    }
+    Ok(())
+}
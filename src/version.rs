@@ -0,0 +1,450 @@
+use crate::errors::*;
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+/// The relation of a versioned dependency, like `libc6 (>= 2.36)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Relation {
+    Lower,
+    LowerOrEqual,
+    Equal,
+    GreaterOrEqual,
+    Greater,
+}
+
+impl FromStr for Relation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "<<" => Relation::Lower,
+            // `<` and `>` are deprecated in debian and mean `<=`/`>=`
+            "<=" | "<" => Relation::LowerOrEqual,
+            "=" => Relation::Equal,
+            ">=" | ">" => Relation::GreaterOrEqual,
+            ">>" => Relation::Greater,
+            _ => bail!("Unknown version relation: {s:?}"),
+        })
+    }
+}
+
+impl Relation {
+    pub fn matches(&self, ordering: Ordering) -> bool {
+        match self {
+            Relation::Lower => ordering == Ordering::Less,
+            Relation::LowerOrEqual => ordering != Ordering::Greater,
+            Relation::Equal => ordering == Ordering::Equal,
+            Relation::GreaterOrEqual => ordering != Ordering::Less,
+            Relation::Greater => ordering == Ordering::Greater,
+        }
+    }
+}
+
+/// The sort weight of a character in a debian version, `~` sorts even before the end of the string
+fn dpkg_order(c: Option<&u8>) -> i32 {
+    match c {
+        None => 0,
+        Some(c) if c.is_ascii_digit() => 0,
+        Some(c) if c.is_ascii_alphabetic() => *c as i32,
+        Some(b'~') => -1,
+        Some(c) => *c as i32 + 256,
+    }
+}
+
+/// Port of `verrevcmp` from dpkg
+fn dpkg_verrevcmp(a: &str, b: &str) -> Ordering {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (mut i, mut j) = (0, 0);
+
+    while i < a.len() || j < b.len() {
+        while (i < a.len() && !a[i].is_ascii_digit()) || (j < b.len() && !b[j].is_ascii_digit()) {
+            let ac = dpkg_order(a.get(i));
+            let bc = dpkg_order(b.get(j));
+            if ac != bc {
+                return ac.cmp(&bc);
+            }
+            i += 1;
+            j += 1;
+        }
+
+        while a.get(i) == Some(&b'0') {
+            i += 1;
+        }
+        while b.get(j) == Some(&b'0') {
+            j += 1;
+        }
+
+        let mut first_diff = Ordering::Equal;
+        while i < a.len() && a[i].is_ascii_digit() && j < b.len() && b[j].is_ascii_digit() {
+            if first_diff == Ordering::Equal {
+                first_diff = a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+
+        if a.get(i).is_some_and(u8::is_ascii_digit) {
+            return Ordering::Greater;
+        }
+        if b.get(j).is_some_and(u8::is_ascii_digit) {
+            return Ordering::Less;
+        }
+        if first_diff != Ordering::Equal {
+            return first_diff;
+        }
+    }
+
+    Ordering::Equal
+}
+
+/// Split a debian version into epoch, upstream version and revision
+fn dpkg_split(version: &str) -> (u64, &str, &str) {
+    let (epoch, rest) = match version.split_once(':') {
+        Some((epoch, rest)) => (epoch.parse().unwrap_or(0), rest),
+        None => (0, version),
+    };
+    let (upstream, revision) = rest.rsplit_once('-').unwrap_or((rest, ""));
+    (epoch, upstream, revision)
+}
+
+/// Compare two versions the way dpkg does
+pub fn dpkg_cmp(a: &str, b: &str) -> Ordering {
+    let (a_epoch, a_upstream, a_revision) = dpkg_split(a);
+    let (b_epoch, b_upstream, b_revision) = dpkg_split(b);
+    a_epoch
+        .cmp(&b_epoch)
+        .then_with(|| dpkg_verrevcmp(a_upstream, b_upstream))
+        .then_with(|| dpkg_verrevcmp(a_revision, b_revision))
+}
+
+/// Port of `rpmvercmp` from libalpm
+fn rpmvercmp(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (mut i, mut j) = (0, 0);
+
+    while i < a.len() && j < b.len() {
+        let (sep_a, sep_b) = (i, j);
+        while i < a.len() && !a[i].is_ascii_alphanumeric() {
+            i += 1;
+        }
+        while j < b.len() && !b[j].is_ascii_alphanumeric() {
+            j += 1;
+        }
+        if i >= a.len() || j >= b.len() {
+            break;
+        }
+        // the version with fewer separators is older
+        if i - sep_a != j - sep_b {
+            return (i - sep_a).cmp(&(j - sep_b));
+        }
+
+        let (start_a, start_b) = (i, j);
+        let is_num = a[i].is_ascii_digit();
+        let segment = if is_num {
+            u8::is_ascii_digit
+        } else {
+            u8::is_ascii_alphabetic
+        };
+        while i < a.len() && segment(&a[i]) {
+            i += 1;
+        }
+        while j < b.len() && segment(&b[j]) {
+            j += 1;
+        }
+
+        let mut seg_a = &a[start_a..i];
+        let mut seg_b = &b[start_b..j];
+        // numeric segments are always newer than alpha segments
+        if seg_b.is_empty() {
+            return if is_num {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            };
+        }
+
+        if is_num {
+            while seg_a.first() == Some(&b'0') {
+                seg_a = &seg_a[1..];
+            }
+            while seg_b.first() == Some(&b'0') {
+                seg_b = &seg_b[1..];
+            }
+            let ord = seg_a.len().cmp(&seg_b.len());
+            if ord != Ordering::Equal {
+                return ord;
+            }
+        }
+
+        let ord = seg_a.cmp(seg_b);
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+
+    match (a.get(i), b.get(j)) {
+        (None, None) => Ordering::Equal,
+        // a remaining alpha string never beats an empty string
+        (None, Some(c)) if !c.is_ascii_alphabetic() => Ordering::Less,
+        (Some(c), _) if c.is_ascii_alphabetic() => Ordering::Less,
+        _ => Ordering::Greater,
+    }
+}
+
+/// Split a pacman version into epoch, version and pkgrel
+fn pacman_split(version: &str) -> (&str, &str, Option<&str>) {
+    let (epoch, rest) = match version.split_once(':') {
+        Some((epoch, rest)) if epoch.bytes().all(|c| c.is_ascii_digit()) => (epoch, rest),
+        _ => ("0", version),
+    };
+    match rest.rsplit_once('-') {
+        Some((version, rel)) => (epoch, version, Some(rel)),
+        None => (epoch, rest, None),
+    }
+}
+
+/// Compare two versions the way pacman does (`vercmp`)
+pub fn pacman_cmp(a: &str, b: &str) -> Ordering {
+    let (a_epoch, a_version, a_rel) = pacman_split(a);
+    let (b_epoch, b_version, b_rel) = pacman_split(b);
+    rpmvercmp(a_epoch, b_epoch)
+        .then_with(|| rpmvercmp(a_version, b_version))
+        .then_with(|| match (a_rel, b_rel) {
+            (Some(a_rel), Some(b_rel)) => rpmvercmp(a_rel, b_rel),
+            _ => Ordering::Equal,
+        })
+}
+
+/// The sort rank of an apk version suffix, pre-releases sort before the plain version
+fn apk_suffix_rank(suffix: &str) -> Option<i32> {
+    Some(match suffix {
+        "alpha" => -4,
+        "beta" => -3,
+        "pre" => -2,
+        "rc" => -1,
+        "cvs" => 1,
+        "svn" => 2,
+        "git" => 3,
+        "hg" => 4,
+        "p" => 5,
+        _ => return None,
+    })
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct ApkVersion {
+    numbers: Vec<u64>,
+    letter: Option<char>,
+    suffixes: Vec<(i32, u64)>,
+    revision: u64,
+}
+
+impl ApkVersion {
+    fn parse(version: &str) -> Option<Self> {
+        // a trailing commit hash (`~abcdef`) is not relevant for sorting
+        let version = version.split_once('~').map(|(v, _)| v).unwrap_or(version);
+        let (version, revision) = match version.rsplit_once("-r") {
+            Some((version, revision)) => (version, revision.parse().ok()?),
+            None => (version, 0),
+        };
+
+        let mut parts = version.split('_');
+        let mut v = ApkVersion {
+            revision,
+            ..Default::default()
+        };
+        let numbers = parts.next()?;
+        let numbers = match numbers.chars().last() {
+            Some(c) if c.is_ascii_lowercase() => {
+                v.letter = Some(c);
+                &numbers[..numbers.len() - 1]
+            }
+            _ => numbers,
+        };
+        for number in numbers.split('.') {
+            v.numbers.push(number.parse().ok()?);
+        }
+
+        for suffix in parts {
+            let idx = suffix
+                .find(|c: char| c.is_ascii_digit())
+                .unwrap_or(suffix.len());
+            let (name, number) = suffix.split_at(idx);
+            let number = if number.is_empty() {
+                0
+            } else {
+                number.parse().ok()?
+            };
+            v.suffixes.push((apk_suffix_rank(name)?, number));
+        }
+
+        Some(v)
+    }
+}
+
+/// Compare two versions the way apk does
+pub fn apk_cmp(a: &str, b: &str) -> Ordering {
+    let (Some(va), Some(vb)) = (ApkVersion::parse(a), ApkVersion::parse(b)) else {
+        // invalid versions can't be sorted meaningfully, stay deterministic
+        return a.cmp(b);
+    };
+
+    // a missing suffix sorts between pre-releases and post-releases
+    let suffix_ord = || {
+        for idx in 0..va.suffixes.len().max(vb.suffixes.len()) {
+            let ord = va
+                .suffixes
+                .get(idx)
+                .unwrap_or(&(0, 0))
+                .cmp(vb.suffixes.get(idx).unwrap_or(&(0, 0)));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+        }
+        Ordering::Equal
+    };
+
+    va.numbers
+        .cmp(&vb.numbers)
+        .then_with(|| va.letter.cmp(&vb.letter))
+        .then_with(suffix_ord)
+        .then_with(|| va.revision.cmp(&vb.revision))
+}
+
+/// Compare two versions with the rules of the given package system
+pub fn compare(system: &str, a: &str, b: &str) -> Ordering {
+    match system {
+        "alpine" => apk_cmp(a, b),
+        "archlinux" => pacman_cmp(a, b),
+        // opkg compares versions the same way as dpkg
+        "debian" | "openwrt" => dpkg_cmp(a, b),
+        _ => a.cmp(b),
+    }
+}
+
+/// Split a dependency like `gcc>=13` or `gcc=13.2.1` into name and constraint,
+/// `<` and `>` are strict in this syntax
+pub fn split_constraint(s: &str) -> Result<(&str, Option<(Relation, &str)>)> {
+    let Some(idx) = s.find(['<', '>', '=']) else {
+        return Ok((s, None));
+    };
+    let (name, rest) = s.split_at(idx);
+    let (relation, version) = if let Some(version) = rest.strip_prefix(">=") {
+        (Relation::GreaterOrEqual, version)
+    } else if let Some(version) = rest.strip_prefix("<=") {
+        (Relation::LowerOrEqual, version)
+    } else if let Some(version) = rest.strip_prefix('>') {
+        (Relation::Greater, version)
+    } else if let Some(version) = rest.strip_prefix('<') {
+        (Relation::Lower, version)
+    } else if let Some(version) = rest.strip_prefix('=') {
+        (Relation::Equal, version)
+    } else {
+        bail!("Failed to parse version constraint: {s:?}");
+    };
+    Ok((name, Some((relation, version))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dpkg_cmp() {
+        for (a, b, expected) in [
+            ("1.0", "1.0", Ordering::Equal),
+            ("1.0-1", "1.0-2", Ordering::Less),
+            ("1.10", "1.9", Ordering::Greater),
+            ("1.0~rc1", "1.0", Ordering::Less),
+            ("1.0~rc1", "1.0~rc1~", Ordering::Greater),
+            ("1:0.9", "2.0", Ordering::Greater),
+            ("2.40-2", "2.40-2+b1", Ordering::Less),
+            ("1.0a", "1.0+", Ordering::Less),
+            ("1.001", "1.1", Ordering::Equal),
+            ("0:1.0", "1.0", Ordering::Equal),
+            ("1.63.0+dfsg1-2", "1.63.0+dfsg1-1", Ordering::Greater),
+        ] {
+            assert_eq!(dpkg_cmp(a, b), expected, "{a:?} <=> {b:?}");
+            assert_eq!(dpkg_cmp(b, a), expected.reverse(), "{b:?} <=> {a:?}");
+        }
+    }
+
+    #[test]
+    fn test_pacman_cmp() {
+        for (a, b, expected) in [
+            ("1.0", "1.0", Ordering::Equal),
+            ("1.0-1", "1.0-2", Ordering::Less),
+            ("1.0", "1.0-2", Ordering::Equal),
+            ("1.10", "1.9", Ordering::Greater),
+            ("1.0a", "1.0", Ordering::Less),
+            ("1.0alpha", "1.0", Ordering::Less),
+            ("1.0", "1.0.1", Ordering::Less),
+            ("1.0.a", "1.0.1", Ordering::Less),
+            ("1:1.0", "2.0", Ordering::Greater),
+            ("1:1.70.0-1", "1:1.69.0-2", Ordering::Greater),
+            ("2.40-6", "2.40-6.1", Ordering::Less),
+            ("20230704-1", "20230704-1", Ordering::Equal),
+            ("1.001", "1.1", Ordering::Equal),
+        ] {
+            assert_eq!(pacman_cmp(a, b), expected, "{a:?} <=> {b:?}");
+            assert_eq!(pacman_cmp(b, a), expected.reverse(), "{b:?} <=> {a:?}");
+        }
+    }
+
+    #[test]
+    fn test_apk_cmp() {
+        for (a, b, expected) in [
+            ("1.0", "1.0", Ordering::Equal),
+            ("1.0-r1", "1.0-r2", Ordering::Less),
+            ("1.10", "1.9", Ordering::Greater),
+            ("1.0_rc1", "1.0", Ordering::Less),
+            ("1.0_alpha2", "1.0_beta1", Ordering::Less),
+            ("1.0_p1", "1.0", Ordering::Greater),
+            ("1.0a", "1.0", Ordering::Greater),
+            ("1.0", "1.0.1", Ordering::Less),
+            (
+                "12.2.1_git20220924-r10",
+                "12.2.1_git20220924-r4",
+                Ordering::Greater,
+            ),
+            ("1.36.1-r2", "1.36.1-r2", Ordering::Equal),
+        ] {
+            assert_eq!(apk_cmp(a, b), expected, "{a:?} <=> {b:?}");
+            assert_eq!(apk_cmp(b, a), expected.reverse(), "{b:?} <=> {a:?}");
+        }
+    }
+
+    #[test]
+    fn test_compare_by_system() {
+        // lexicographic sorting would get all of these wrong
+        assert_eq!(compare("debian", "2.40-10", "2.40-9"), Ordering::Greater);
+        assert_eq!(compare("archlinux", "1:1.0-1", "2.0-1"), Ordering::Greater);
+        assert_eq!(compare("alpine", "1.0_rc1-r0", "1.0-r0"), Ordering::Less);
+    }
+
+    #[test]
+    fn test_split_constraint() -> Result<()> {
+        assert_eq!(split_constraint("gcc")?, ("gcc", None));
+        assert_eq!(
+            split_constraint("gcc>=13")?,
+            ("gcc", Some((Relation::GreaterOrEqual, "13")))
+        );
+        assert_eq!(
+            split_constraint("rust=1:1.73.0-1")?,
+            ("rust", Some((Relation::Equal, "1:1.73.0-1")))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_relation() -> Result<()> {
+        assert!(">=".parse::<Relation>()?.matches(dpkg_cmp("2.37", "2.36")));
+        assert!(!"<<".parse::<Relation>()?.matches(dpkg_cmp("2.36", "2.36")));
+        assert!("=".parse::<Relation>()?.matches(dpkg_cmp("2.36", "2.36")));
+        assert!("~=".parse::<Relation>().is_err());
+        Ok(())
+    }
+}
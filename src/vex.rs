@@ -0,0 +1,203 @@
+//! VEX documents that mark vulnerabilities as not affecting a package, used by
+//! `audit` to suppress findings. CycloneDX and OpenVEX json are supported.
+use crate::errors::*;
+use serde::Deserialize;
+use std::path::Path;
+
+/// A vulnerability that doesn't affect a package
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub vulnerability: String,
+    /// The purls of the packages, without a version they match every version
+    pub products: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Document {
+    CycloneDx { vulnerabilities: Vec<CycloneDxVuln> },
+    OpenVex { statements: Vec<OpenVexStatement> },
+}
+
+#[derive(Debug, Deserialize)]
+struct CycloneDxVuln {
+    id: String,
+    #[serde(default)]
+    analysis: Option<CycloneDxAnalysis>,
+    #[serde(default)]
+    affects: Vec<CycloneDxAffects>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CycloneDxAnalysis {
+    state: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CycloneDxAffects {
+    #[serde(rename = "ref")]
+    reference: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenVexStatement {
+    vulnerability: OpenVexVulnerability,
+    #[serde(default)]
+    products: Vec<OpenVexProduct>,
+    status: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenVexVulnerability {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenVexProduct {
+    #[serde(rename = "@id")]
+    id: String,
+}
+
+/// Parse a VEX document, only the statements that say a package is not affected are kept
+pub fn parse(buf: &[u8]) -> Result<Vec<Statement>> {
+    let document = serde_json::from_slice::<Document>(buf)
+        .context("Failed to parse VEX document, expected CycloneDX or OpenVEX json")?;
+    let statements = match document {
+        Document::CycloneDx { vulnerabilities } => vulnerabilities
+            .into_iter()
+            .filter(|vuln| {
+                let state = vuln.analysis.as_ref().and_then(|a| a.state.as_deref());
+                matches!(state, Some("not_affected" | "false_positive" | "resolved"))
+            })
+            .map(|vuln| Statement {
+                vulnerability: vuln.id,
+                products: vuln.affects.into_iter().map(|a| a.reference).collect(),
+            })
+            .collect(),
+        Document::OpenVex { statements } => statements
+            .into_iter()
+            .filter(|s| matches!(s.status.as_str(), "not_affected" | "fixed"))
+            .map(|s| Statement {
+                vulnerability: s.vulnerability.name,
+                products: s.products.into_iter().map(|p| p.id).collect(),
+            })
+            .collect(),
+    };
+    Ok(statements)
+}
+
+pub fn read_from_file(path: &Path) -> Result<Vec<Statement>> {
+    let buf =
+        std::fs::read(path).with_context(|| anyhow!("Failed to read VEX document: {path:?}"))?;
+    parse(&buf).with_context(|| anyhow!("Invalid VEX document: {path:?}"))
+}
+
+/// Compare purls without qualifiers, a product without version matches any version
+fn purl_matches(product: &str, purl: &str) -> bool {
+    let strip = |purl: &str| {
+        purl.split(['?', '#'])
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    };
+    let product = strip(product);
+    let purl = strip(purl);
+    if product.contains('@') {
+        product == purl
+    } else {
+        purl.split_once('@').map(|(name, _)| name) == Some(product.as_str())
+    }
+}
+
+impl Statement {
+    /// If this statement covers a vulnerability (by id or alias) of a package
+    pub fn matches(&self, ids: &[&str], purl: &str) -> bool {
+        ids.contains(&self.vulnerability.as_str())
+            && self.products.iter().any(|p| purl_matches(p, purl))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cyclonedx() -> Result<()> {
+        let statements = parse(
+            br#"{
+  "bomFormat": "CycloneDX",
+  "specVersion": "1.5",
+  "vulnerabilities": [
+    {
+      "id": "CVE-2023-38545",
+      "analysis": { "state": "not_affected", "justification": "code_not_reachable" },
+      "affects": [{ "ref": "pkg:deb/debian/curl@7.88.1-10?arch=amd64" }]
+    },
+    {
+      "id": "CVE-2023-38546",
+      "analysis": { "state": "exploitable" },
+      "affects": [{ "ref": "pkg:deb/debian/curl@7.88.1-10?arch=amd64" }]
+    }
+  ]
+}"#,
+        )?;
+        assert_eq!(
+            statements,
+            [Statement {
+                vulnerability: "CVE-2023-38545".to_string(),
+                products: vec!["pkg:deb/debian/curl@7.88.1-10?arch=amd64".to_string()],
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_openvex() -> Result<()> {
+        let statements = parse(
+            br#"{
+  "@context": "https://openvex.dev/ns/v0.2.0",
+  "statements": [
+    {
+      "vulnerability": { "name": "CVE-2023-38545" },
+      "products": [{ "@id": "pkg:deb/debian/curl" }],
+      "status": "not_affected",
+      "justification": "vulnerable_code_not_in_execute_path"
+    },
+    {
+      "vulnerability": { "name": "CVE-2023-38546" },
+      "products": [{ "@id": "pkg:deb/debian/curl" }],
+      "status": "affected"
+    }
+  ]
+}"#,
+        )?;
+        assert_eq!(
+            statements,
+            [Statement {
+                vulnerability: "CVE-2023-38545".to_string(),
+                products: vec!["pkg:deb/debian/curl".to_string()],
+            }]
+        );
+        assert!(parse(b"{}").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_matches() {
+        let statement = Statement {
+            vulnerability: "CVE-2023-38545".to_string(),
+            products: vec!["pkg:deb/debian/curl@7.88.1-10".to_string()],
+        };
+        let purl = "pkg:deb/debian/curl@7.88.1-10?arch=amd64";
+        assert!(statement.matches(&["DSA-5510-1", "CVE-2023-38545"], purl));
+        assert!(!statement.matches(&["CVE-2023-38546"], purl));
+        assert!(!statement.matches(&["CVE-2023-38545"], "pkg:deb/debian/curl@7.88.1-11"));
+
+        let statement = Statement {
+            vulnerability: "CVE-2023-38545".to_string(),
+            products: vec!["pkg:deb/debian/curl".to_string()],
+        };
+        assert!(statement.matches(&["CVE-2023-38545"], "pkg:deb/debian/curl@7.88.1-11"));
+        assert!(!statement.matches(&["CVE-2023-38545"], "pkg:deb/debian/curlie@1.0"));
+    }
+}
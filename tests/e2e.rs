@@ -0,0 +1,192 @@
+//! End-to-end tests that run the `repro-env` binary with podman against the
+//! projects in examples/. They need podman and network access, so they're
+//! only built with `cargo test --features e2e`.
+//!
+//! The recorded lockfiles of the examples are built and the artifacts are
+//! compared with the checksums that are also verified by CI. The `update_*`
+//! tests resolve the manifests again and build with the new lockfile, with
+//! `REPRO_ENV_E2E_RECORD=1` the new lockfile is written back to examples/.
+#![cfg(feature = "e2e")]
+
+use repro_env::errors::*;
+use repro_env::lockfile::Lockfile;
+use repro_env::manifest::Manifest;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::TempDir;
+
+const BIN: &str = env!("CARGO_BIN_EXE_repro-env");
+
+fn example(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("examples")
+        .join(name)
+}
+
+/// Copy an example into a temporary directory, so the build output and a new
+/// lockfile don't end up in the source tree
+fn copy_example(name: &str) -> Result<TempDir> {
+    fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            let dest = to.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                copy_dir(&entry.path(), &dest)?;
+            } else {
+                fs::copy(entry.path(), dest)?;
+            }
+        }
+        Ok(())
+    }
+
+    let dir = tempfile::tempdir()?;
+    copy_dir(&example(name), dir.path())?;
+    Ok(dir)
+}
+
+fn repro_env(dir: &Path, args: &[&str]) -> Result<()> {
+    let status = Command::new(BIN)
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .status()
+        .with_context(|| anyhow!("Failed to execute {BIN:?}"))?;
+    if !status.success() {
+        bail!("repro-env {args:?} failed: {status}");
+    }
+    Ok(())
+}
+
+fn sha256(path: &Path) -> Result<String> {
+    let buf = fs::read(path).with_context(|| anyhow!("Failed to read artifact: {path:?}"))?;
+    Ok(hex::encode(Sha256::digest(buf)))
+}
+
+fn read_lockfile(dir: &Path) -> Result<Lockfile> {
+    let buf = fs::read_to_string(dir.join("repro-env.lock"))?;
+    Lockfile::deserialize(&buf)
+}
+
+/// Fetch and build an example with its recorded lockfile, the artifact needs to match
+fn build_recorded(name: &str, cmd: &[&str], output: &str, expected: &str) -> Result<()> {
+    let dir = copy_example(name)?;
+    repro_env(dir.path(), &["fetch"])?;
+    let mut args = vec!["build", "--"];
+    args.extend(cmd);
+    repro_env(dir.path(), &args)?;
+    assert_eq!(sha256(&dir.path().join(output))?, expected);
+    Ok(())
+}
+
+/// Resolve an example again, then build with the new lockfile
+fn update_and_build(name: &str, cmd: &[&str], output: &str) -> Result<()> {
+    let dir = copy_example(name)?;
+    let recorded = read_lockfile(dir.path())?;
+    repro_env(dir.path(), &["update"])?;
+    let updated = read_lockfile(dir.path())?;
+
+    let buf = fs::read_to_string(dir.path().join("repro-env.toml"))?;
+    let manifest = Manifest::deserialize(&buf)?;
+    if let Some(packages) = &manifest.packages {
+        for pkg in &updated.packages {
+            assert_eq!(pkg.system, packages.system, "{:?}", pkg.name);
+        }
+        // dependencies may be provided by a package of another name, only the
+        // ones that were pinned by name before need to be pinned again
+        for dep in &packages.dependencies {
+            let was_pinned = recorded.packages.iter().any(|pkg| pkg.name == *dep);
+            let is_pinned = updated.packages.iter().any(|pkg| pkg.name == *dep);
+            assert!(
+                !was_pinned || is_pinned,
+                "Dependency is not pinned: {dep:?}"
+            );
+        }
+    }
+    assert!(updated.container.image.contains("@sha256:"));
+
+    repro_env(dir.path(), &["fetch"])?;
+    let mut args = vec!["build", "--"];
+    args.extend(cmd);
+    repro_env(dir.path(), &args)?;
+    assert!(dir.path().join(output).exists());
+
+    if env::var_os("REPRO_ENV_E2E_RECORD").is_some() {
+        fs::copy(
+            dir.path().join("repro-env.lock"),
+            example(name).join("repro-env.lock"),
+        )?;
+    }
+    Ok(())
+}
+
+#[test]
+fn build_archlinux() -> Result<()> {
+    build_recorded(
+        "archlinux",
+        &["gcc", "-static", "-o", "hello", "hello.c"],
+        "hello",
+        "04e03fd681793db603feb66a4c8e8df3858f1e31372dc017d7a0cf255faf84c5",
+    )
+}
+
+#[test]
+fn build_debian() -> Result<()> {
+    build_recorded(
+        "debian",
+        &["gcc", "-static", "-o", "hello", "hello.c"],
+        "hello",
+        "2b24cfa838189c3aa6fa2440afe8508654830b6d3bad85d9b31958ae5c3fb429",
+    )
+}
+
+#[test]
+fn build_golang() -> Result<()> {
+    build_recorded(
+        "golang",
+        &["go", "build", "."],
+        "hello",
+        "dd41bdb93af4fb798f6b079368d6ee50a6907044c292ba6d2c98420ca8f012bf",
+    )
+}
+
+#[test]
+fn build_rust() -> Result<()> {
+    build_recorded(
+        "rust",
+        &["cargo", "build", "--release"],
+        "target/release/rust",
+        "ea8997342bef06680784c9ea6ef7e22f7ba089e98927e870905503d0bf39acb1",
+    )
+}
+
+#[test]
+fn update_alpine() -> Result<()> {
+    // the recorded packages are removed from the mirrors eventually, only a fresh resolve builds
+    update_and_build(
+        "alpine",
+        &["gcc", "-static", "-o", "hello", "hello.c"],
+        "hello",
+    )
+}
+
+#[test]
+fn update_archlinux() -> Result<()> {
+    update_and_build(
+        "archlinux",
+        &["gcc", "-static", "-o", "hello", "hello.c"],
+        "hello",
+    )
+}
+
+#[test]
+fn update_debian() -> Result<()> {
+    update_and_build(
+        "debian",
+        &["gcc", "-static", "-o", "hello", "hello.c"],
+        "hello",
+    )
+}